@@ -149,6 +149,151 @@ enum AranyaError
 typedef uint32_t AranyaError;
 #endif // __cplusplus
 
+/**
+ * A detailed error code for an extended error.
+ *
+ * Unlike [`AranyaError`](@ref AranyaError), it distinguishes between the AFC errors
+ * that callers are likely to handle. Codes are never
+ * renumbered, but new codes may be added, so callers should
+ * treat unknown codes like `::ARANYA_ERROR_CODE_OTHER`.
+ *
+ * See [`aranya_error_code`](@ref aranya_error_code).
+ */
+enum AranyaErrorCode
+#ifdef __cplusplus
+  : uint32_t
+#endif // __cplusplus
+ {
+    /**
+     * The extended error does not hold an error.
+     */
+    ARANYA_ERROR_CODE_NONE = 0,
+    /**
+     * An error without a more specific code.
+     */
+    ARANYA_ERROR_CODE_OTHER = 1,
+    /**
+     * Internal bug discovered.
+     */
+    ARANYA_ERROR_CODE_BUG = 2,
+    /**
+     * Timed out.
+     */
+    ARANYA_ERROR_CODE_TIMEOUT = 3,
+    /**
+     * Logging initialization failure.
+     */
+    ARANYA_ERROR_CODE_LOG_INIT = 4,
+    /**
+     * Invalid argument.
+     */
+    ARANYA_ERROR_CODE_INVALID_ARGUMENT = 5,
+    /**
+     * Buffer is too small.
+     */
+    ARANYA_ERROR_CODE_BUFFER_TOO_SMALL = 6,
+    /**
+     * Invalid UTF-8.
+     */
+    ARANYA_ERROR_CODE_INVALID_UTF8 = 7,
+    /**
+     * Invalid address.
+     */
+    ARANYA_ERROR_CODE_INVALID_ADDR = 8,
+    /**
+     * The channel has no metadata entry with the key.
+     */
+    ARANYA_ERROR_CODE_MISSING_METADATA = 9,
+    /**
+     * Error connecting to daemon.
+     */
+    ARANYA_ERROR_CODE_CONNECTING = 10,
+    /**
+     * Could not send request to daemon.
+     */
+    ARANYA_ERROR_CODE_RPC = 11,
+    /**
+     * Daemon reported error.
+     */
+    ARANYA_ERROR_CODE_DAEMON = 12,
+    /**
+     * Tokio runtime error.
+     */
+    ARANYA_ERROR_CODE_RUNTIME = 13,
+    /**
+     * An AFC error without a more specific code.
+     */
+    ARANYA_ERROR_CODE_AFC_OTHER = 100,
+    /**
+     * The AFC channel does not exist.
+     */
+    ARANYA_ERROR_CODE_AFC_CHANNEL_NOT_FOUND = 101,
+    /**
+     * The AFC channel was revoked.
+     */
+    ARANYA_ERROR_CODE_AFC_CHANNEL_REVOKED = 102,
+    /**
+     * The AFC channel has reached its last sequence number.
+     */
+    ARANYA_ERROR_CODE_AFC_END_OF_CHANNEL = 103,
+    /**
+     * The message is too large.
+     */
+    ARANYA_ERROR_CODE_AFC_MSG_TOO_LARGE = 104,
+    /**
+     * The AFC channel's rate limit was exceeded.
+     */
+    ARANYA_ERROR_CODE_AFC_RATE_LIMITED = 105,
+    /**
+     * The AFC channel's retry queue is full.
+     */
+    ARANYA_ERROR_CODE_AFC_RETRY_QUEUE_FULL = 106,
+    /**
+     * Sending over the AFC channel would block.
+     */
+    ARANYA_ERROR_CODE_AFC_WOULD_BLOCK = 107,
+    /**
+     * The router is backing off from the peer after failing
+     * to connect.
+     */
+    ARANYA_ERROR_CODE_AFC_BACKOFF = 108,
+    /**
+     * Looking up the peer's address failed.
+     */
+    ARANYA_ERROR_CODE_AFC_DNS_LOOKUP = 109,
+    /**
+     * Connecting to the peer failed.
+     */
+    ARANYA_ERROR_CODE_AFC_CONNECT = 110,
+    /**
+     * Reading from or writing to the peer failed.
+     */
+    ARANYA_ERROR_CODE_AFC_STREAM_IO = 111,
+    /**
+     * Encrypting or decrypting a message failed.
+     */
+    ARANYA_ERROR_CODE_AFC_CRYPTO = 112,
+    /**
+     * The peer uses an unsupported AFC version.
+     */
+    ARANYA_ERROR_CODE_AFC_VERSION_MISMATCH = 113,
+    /**
+     * The daemon's shared memory cannot be used.
+     */
+    ARANYA_ERROR_CODE_AFC_SHM = 114,
+    /**
+     * The router is out of capacity.
+     */
+    ARANYA_ERROR_CODE_AFC_CAPACITY = 115,
+    /**
+     * The AFC channel expired.
+     */
+    ARANYA_ERROR_CODE_AFC_CHANNEL_EXPIRED = 116,
+};
+#ifndef __cplusplus
+typedef uint32_t AranyaErrorCode;
+#endif // __cplusplus
+
 /**
  * An enum containing team roles defined in the Aranya policy.
  */
@@ -178,6 +323,33 @@ enum AranyaRole
 typedef uint8_t AranyaRole;
 #endif // __cplusplus
 
+/**
+ * The transport used to send data over an Aranya Fast Channels
+ * (AFC) channel.
+ */
+enum AranyaAfcTransport
+#ifdef __cplusplus
+  : uint8_t
+#endif // __cplusplus
+ {
+    /**
+     * TCP.
+     */
+    ARANYA_AFC_TRANSPORT_TCP,
+    /**
+     * UDP.
+     */
+    ARANYA_AFC_TRANSPORT_UDP,
+    /**
+     * A transport that this version of the C API does not
+     * know about.
+     */
+    ARANYA_AFC_TRANSPORT_OTHER,
+};
+#ifndef __cplusplus
+typedef uint8_t AranyaAfcTransport;
+#endif // __cplusplus
+
 /**
  * Extended error information.
  */
@@ -190,6 +362,26 @@ typedef struct ARANYA_ALIGNED(8) AranyaExtError {
     uint8_t __for_size_only[80];
 } AranyaExtError;
 
+/**
+ * The optional capabilities of this build of the library.
+ *
+ * See [`aranya_features`](@ref aranya_features).
+ */
+typedef struct AranyaFeatures {
+    /**
+     * AFC streams can be wrapped in TLS.
+     */
+    bool tls;
+    /**
+     * Router metrics are recorded.
+     */
+    bool metrics;
+    /**
+     * The network used by AFC can be simulated.
+     */
+    bool sim;
+} AranyaFeatures;
+
 /**
  * A handle to an Aranya Client.
  */
@@ -333,6 +525,11 @@ typedef struct sockaddr_storage AranyaSocketAddr;
 
 /**
  * Aranya Fast Channels (AFC) message info.
+ *
+ * Its layout is stable, so its fields can be read directly.
+ * Bindings that cannot read C structs can use the accessors
+ * instead: [`aranya_msg_afc_id`](@ref aranya_msg_afc_id), [`aranya_msg_label`](@ref aranya_msg_label), [`aranya_msg_seq`](@ref aranya_msg_seq), and
+ * [`aranya_msg_peer_addr`](@ref aranya_msg_peer_addr).
  */
 typedef struct AranyaAfcMsgInfo {
     /**
@@ -355,6 +552,92 @@ typedef struct AranyaAfcMsgInfo {
     AranyaSocketAddr addr;
 } AranyaAfcMsgInfo;
 
+/**
+ * Receives Aranya Fast Channels (AFC) data.
+ *
+ * `data` and `info` are only valid until the callback
+ * returns.
+ *
+ * See [`aranya_afc_set_data_callback`](@ref aranya_afc_set_data_callback).
+ */
+typedef void (*AranyaAfcDataCallback)(void *user_data,
+                                      const uint8_t *data,
+                                      size_t data_len,
+                                      const struct AranyaAfcMsgInfo *info);
+
+/**
+ * The effective limits of a client.
+ */
+typedef struct AranyaLimits {
+    /**
+     * The maximum size in bytes of an encoded message.
+     */
+    uint32_t max_msg_size;
+    /**
+     * The size in bytes of the AFC header prepended to each
+     * ciphertext.
+     */
+    size_t header_size;
+    /**
+     * The number of bytes that sealing adds to the plaintext,
+     * excluding the header.
+     */
+    size_t seal_overhead;
+    /**
+     * The maximum number of channels.
+     */
+    size_t max_chans;
+    /**
+     * The largest amount of data that can always be sent in one
+     * message over TCP.
+     */
+    size_t max_data_size;
+    /**
+     * The largest amount of data that can always be sent in one
+     * message over UDP.
+     */
+    size_t max_datagram_data_size;
+} AranyaLimits;
+
+/**
+ * Information about an open Aranya Fast Channels (AFC) channel.
+ */
+typedef struct AranyaAfcChannelInfo {
+    /**
+     * Uniquely (globally) identifies the channel.
+     */
+    struct AranyaChannelId channel;
+    /**
+     * The label applied to the channel.
+     */
+    AranyaLabel label;
+    /**
+     * How data is sent over the channel.
+     */
+    AranyaAfcTransport transport;
+    /**
+     * The number of messages waiting to be resent after a
+     * transient failure.
+     */
+    size_t queued;
+    /**
+     * The number of plaintext bytes sent.
+     */
+    uint64_t bytes_sent;
+    /**
+     * The number of plaintext bytes received.
+     */
+    uint64_t bytes_received;
+    /**
+     * The number of messages encrypted and sent.
+     */
+    uint64_t msgs_sealed;
+    /**
+     * The number of messages received and decrypted.
+     */
+    uint64_t msgs_opened;
+} AranyaAfcChannelInfo;
+
 #ifdef __cplusplus
 extern "C" {
 #endif // __cplusplus
@@ -457,6 +740,51 @@ AranyaError aranya_ext_error_msg_ext(const struct AranyaExtError *err,
                                      size_t *msg_len,
                                      struct AranyaExtError *__ext_err);
 
+/**
+ * Returns the extended error's detailed error code.
+ *
+ * It is `::ARANYA_ERROR_CODE_NONE` if the extended error does
+ * not hold an error, e.g., because the last call succeeded.
+ *
+ * @param err the extended error [`AranyaExtError`](@ref AranyaExtError).
+ *
+ * @relates AranyaExtError.
+ */
+AranyaErrorCode aranya_error_code(const struct AranyaExtError *err);
+
+/**
+ * Enables or disables strict mode.
+ *
+ * In strict mode, anomalies that are otherwise logged and
+ * ignored are reported as errors, e.g., so that certification
+ * runs can assert that none occur:
+ * - Clients initialized afterwards report duplicate AFC
+ *   channel IDs and streams as `::ARANYA_ERROR_AFC`.
+ * - [`aranya_ext_error_msg`](@ref aranya_ext_error_msg) returns `::ARANYA_ERROR_INVALID_ARGUMENT`
+ *   for an extended error that does not hold an error instead
+ *   of copying an empty message.
+ *
+ * @param strict whether to enable strict mode.
+ */
+AranyaError aranya_set_strict_mode(bool strict);
+
+/**
+ * Enables or disables strict mode.
+ *
+ * In strict mode, anomalies that are otherwise logged and
+ * ignored are reported as errors, e.g., so that certification
+ * runs can assert that none occur:
+ * - Clients initialized afterwards report duplicate AFC
+ *   channel IDs and streams as `::ARANYA_ERROR_AFC`.
+ * - [`aranya_ext_error_msg`](@ref aranya_ext_error_msg) returns `::ARANYA_ERROR_INVALID_ARGUMENT`
+ *   for an extended error that does not hold an error instead
+ *   of copying an empty message.
+ *
+ * @param strict whether to enable strict mode.
+ */
+AranyaError aranya_set_strict_mode_ext(bool strict,
+                                       struct AranyaExtError *__ext_err);
+
 /**
  * Initializes logging.
  *
@@ -473,6 +801,15 @@ AranyaError aranya_init_logging(void);
  */
 AranyaError aranya_init_logging_ext(struct AranyaExtError *__ext_err);
 
+/**
+ * Returns the optional capabilities of this build of the
+ * library.
+ *
+ * Use them to adapt at runtime, e.g., to only configure TLS
+ * when it is supported.
+ */
+AranyaFeatures aranya_features(void);
+
 /**
  * Releases any resources associated with `ptr`.
  *
@@ -1113,6 +1450,8 @@ AranyaError aranya_create_bidi_channel_ext(struct AranyaClient *client,
 /**
  * Delete an Aranya Fast Channel (AFC).
  *
+ * Deprecated: use [`aranya_afc_delete_channel`](@ref aranya_afc_delete_channel).
+ *
  * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
  * @param chan the AFC channel ID [`AranyaChannelId`](@ref AranyaChannelId) of the channel to delete.
  *
@@ -1124,6 +1463,8 @@ AranyaError aranya_delete_channel(struct AranyaClient *client,
 /**
  * Delete an Aranya Fast Channel (AFC).
  *
+ * Deprecated: use [`aranya_afc_delete_channel`](@ref aranya_afc_delete_channel).
+ *
  * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
  * @param chan the AFC channel ID [`AranyaChannelId`](@ref AranyaChannelId) of the channel to delete.
  *
@@ -1138,6 +1479,10 @@ AranyaError aranya_delete_channel_ext(struct AranyaClient *client,
  *
  * If the operation times out, this will return an `::ARANYA_ERROR_TIMEOUT`.
  *
+ * This is not needed while a data callback is registered,
+ * since the callback's messages are received without polling.
+ * See [`aranya_afc_set_data_callback`](@ref aranya_afc_set_data_callback).
+ *
  * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
  * @param timeout how long to wait before timing out the poll operation [`AranyaDuration`](@ref AranyaDuration).
  *
@@ -1151,6 +1496,10 @@ AranyaError aranya_poll_data(struct AranyaClient *client,
  *
  * If the operation times out, this will return an `::ARANYA_ERROR_TIMEOUT`.
  *
+ * This is not needed while a data callback is registered,
+ * since the callback's messages are received without polling.
+ * See [`aranya_afc_set_data_callback`](@ref aranya_afc_set_data_callback).
+ *
  * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
  * @param timeout how long to wait before timing out the poll operation [`AranyaDuration`](@ref AranyaDuration).
  *
@@ -1191,6 +1540,35 @@ AranyaError aranya_send_data_ext(struct AranyaClient *client,
                                  size_t data_len,
                                  struct AranyaExtError *__ext_err);
 
+/**
+ * Gets the client's effective limits.
+ *
+ * Use them to size buffers passed to [`aranya_recv_data`](@ref aranya_recv_data) and to
+ * validate data passed to [`aranya_send_data`](@ref aranya_send_data).
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param __output the client's limits [`AranyaLimits`](@ref AranyaLimits).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_limits(struct AranyaClient *client,
+                          struct AranyaLimits *__output);
+
+/**
+ * Gets the client's effective limits.
+ *
+ * Use them to size buffers passed to [`aranya_recv_data`](@ref aranya_recv_data) and to
+ * validate data passed to [`aranya_send_data`](@ref aranya_send_data).
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param __output the client's limits [`AranyaLimits`](@ref AranyaLimits).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_limits_ext(struct AranyaClient *client,
+                              struct AranyaLimits *__output,
+                              struct AranyaExtError *__ext_err);
+
 /**
  * Receive Aranya Fast Channels (AFC) data.
  *
@@ -1226,6 +1604,479 @@ AranyaError aranya_recv_data_ext(struct AranyaClient *client,
                                  bool *__output,
                                  struct AranyaExtError *__ext_err);
 
+/**
+ * Returns the ID of the channel that a received message was
+ * sent over.
+ *
+ * @param info information about the message [`AranyaAfcMsgInfo`](@ref AranyaAfcMsgInfo).
+ *
+ * @relates AranyaAfcMsgInfo.
+ */
+struct AranyaChannelId aranya_msg_afc_id(const struct AranyaAfcMsgInfo *info);
+
+/**
+ * Returns the label of the channel that a received message was
+ * sent over.
+ *
+ * @param info information about the message [`AranyaAfcMsgInfo`](@ref AranyaAfcMsgInfo).
+ *
+ * @relates AranyaAfcMsgInfo.
+ */
+AranyaLabel aranya_msg_label(const struct AranyaAfcMsgInfo *info);
+
+/**
+ * Returns the sequence number of a received message.
+ *
+ * @param info information about the message [`AranyaAfcMsgInfo`](@ref AranyaAfcMsgInfo).
+ *
+ * @relates AranyaAfcMsgInfo.
+ */
+uint64_t aranya_msg_seq(const struct AranyaAfcMsgInfo *info);
+
+/**
+ * Returns the network socket address of the peer that sent
+ * a received message.
+ *
+ * @param info information about the message [`AranyaAfcMsgInfo`](@ref AranyaAfcMsgInfo).
+ *
+ * @relates AranyaAfcMsgInfo.
+ */
+AranyaSocketAddr aranya_msg_peer_addr(const struct AranyaAfcMsgInfo *info);
+
+/**
+ * Registers a callback for incoming Aranya Fast Channels (AFC)
+ * data, replacing any previous callback.
+ *
+ * While a callback is registered, the client's runtime polls
+ * for data in the background, and received messages are passed
+ * to the callback instead of being read with [`aranya_recv_data`](@ref aranya_recv_data).
+ * [`aranya_poll_data`](@ref aranya_poll_data) does not need to be called. The callback is
+ * invoked:
+ * - on one of the runtime's threads, as soon as data arrives,
+ * - once per message, in the order that [`aranya_recv_data`](@ref aranya_recv_data) would
+ *   have returned them,
+ * - never concurrently with itself or other calls on the
+ *   client, which wait for it to return.
+ *
+ * The callback must not call any function with `client`. It
+ * must return quickly, since the client cannot send or receive
+ * data while it runs. `user_data` must be safe to use from
+ * other threads.
+ *
+ * Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if `callback` is
+ * NULL.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param callback the callback [`AranyaAfcDataCallback`](@ref AranyaAfcDataCallback).
+ * @param user_data passed to each invocation of `callback`.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_set_data_callback(struct AranyaClient *client,
+                                         AranyaAfcDataCallback callback,
+                                         void *user_data);
+
+/**
+ * Registers a callback for incoming Aranya Fast Channels (AFC)
+ * data, replacing any previous callback.
+ *
+ * While a callback is registered, the client's runtime polls
+ * for data in the background, and received messages are passed
+ * to the callback instead of being read with [`aranya_recv_data`](@ref aranya_recv_data).
+ * [`aranya_poll_data`](@ref aranya_poll_data) does not need to be called. The callback is
+ * invoked:
+ * - on one of the runtime's threads, as soon as data arrives,
+ * - once per message, in the order that [`aranya_recv_data`](@ref aranya_recv_data) would
+ *   have returned them,
+ * - never concurrently with itself or other calls on the
+ *   client, which wait for it to return.
+ *
+ * The callback must not call any function with `client`. It
+ * must return quickly, since the client cannot send or receive
+ * data while it runs. `user_data` must be safe to use from
+ * other threads.
+ *
+ * Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if `callback` is
+ * NULL.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param callback the callback [`AranyaAfcDataCallback`](@ref AranyaAfcDataCallback).
+ * @param user_data passed to each invocation of `callback`.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_set_data_callback_ext(struct AranyaClient *client,
+                                             AranyaAfcDataCallback callback,
+                                             void *user_data,
+                                             struct AranyaExtError *__ext_err);
+
+/**
+ * Unregisters the callback set with [`aranya_afc_set_data_callback`](@ref aranya_afc_set_data_callback).
+ *
+ * Messages are read with [`aranya_recv_data`](@ref aranya_recv_data) again, after polling
+ * with [`aranya_poll_data`](@ref aranya_poll_data). Once this returns, the callback is no
+ * longer invoked.
+ *
+ * This must not be called from the callback.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_clear_data_callback(struct AranyaClient *client);
+
+/**
+ * Unregisters the callback set with [`aranya_afc_set_data_callback`](@ref aranya_afc_set_data_callback).
+ *
+ * Messages are read with [`aranya_recv_data`](@ref aranya_recv_data) again, after polling
+ * with [`aranya_poll_data`](@ref aranya_poll_data). Once this returns, the callback is no
+ * longer invoked.
+ *
+ * This must not be called from the callback.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_clear_data_callback_ext(struct AranyaClient *client,
+                                               struct AranyaExtError *__ext_err);
+
+/**
+ * Joins a team by syncing it with `peers`.
+ *
+ * This is [`aranya_add_sync_peer`](@ref aranya_add_sync_peer) for each peer, which is how
+ * a device that was added to a team joins it: the team's graph
+ * is fetched from the peers by the first sync.
+ *
+ * If adding a sync peer fails, the peers that were already
+ * added are removed and the error is returned, so the call can
+ * be retried.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param team the team's ID [`AranyaTeamId`](@ref AranyaTeamId).
+ * @param peers the peers' Aranya network addresses [`AranyaAddr`](@ref AranyaAddr).
+ * @param peers_len the number of peers.
+ * @param interval the time [`AranyaDuration`](@ref AranyaDuration) to wait between syncs with each peer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_setup_and_join_team(struct AranyaClient *client,
+                                       const struct AranyaTeamId *team,
+                                       const AranyaAddr *peers,
+                                       size_t peers_len,
+                                       AranyaDuration interval);
+
+/**
+ * Joins a team by syncing it with `peers`.
+ *
+ * This is [`aranya_add_sync_peer`](@ref aranya_add_sync_peer) for each peer, which is how
+ * a device that was added to a team joins it: the team's graph
+ * is fetched from the peers by the first sync.
+ *
+ * If adding a sync peer fails, the peers that were already
+ * added are removed and the error is returned, so the call can
+ * be retried.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param team the team's ID [`AranyaTeamId`](@ref AranyaTeamId).
+ * @param peers the peers' Aranya network addresses [`AranyaAddr`](@ref AranyaAddr).
+ * @param peers_len the number of peers.
+ * @param interval the time [`AranyaDuration`](@ref AranyaDuration) to wait between syncs with each peer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_setup_and_join_team_ext(struct AranyaClient *client,
+                                           const struct AranyaTeamId *team,
+                                           const AranyaAddr *peers,
+                                           size_t peers_len,
+                                           AranyaDuration interval,
+                                           struct AranyaExtError *__ext_err);
+
+/**
+ * Creates an Aranya Fast Channel (AFC) and sends data over it.
+ *
+ * This is [`aranya_create_bidi_channel`](@ref aranya_create_bidi_channel) followed by [`aranya_send_data`](@ref aranya_send_data).
+ * If sending the data fails, the channel is deleted and the
+ * error is returned, so no channel is leaked.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param team the team's ID [`AranyaTeamId`](@ref AranyaTeamId).
+ * @param peer the peer's network identifier [`AranyaNetIdentifier`](@ref AranyaNetIdentifier).
+ * @param label the AFC channel label [`AranyaLabel`](@ref AranyaLabel) to create the channel with.
+ * @param data raw bytes of data to send.
+ * @param data_len length of data to send.
+ * @param __output the channel's ID [`AranyaChannelId`](@ref AranyaChannelId)
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_open_channel_and_send(struct AranyaClient *client,
+                                         const struct AranyaTeamId *team,
+                                         AranyaNetIdentifier peer,
+                                         AranyaLabel label,
+                                         const uint8_t *data,
+                                         size_t data_len,
+                                         struct AranyaChannelId *__output);
+
+/**
+ * Creates an Aranya Fast Channel (AFC) and sends data over it.
+ *
+ * This is [`aranya_create_bidi_channel`](@ref aranya_create_bidi_channel) followed by [`aranya_send_data`](@ref aranya_send_data).
+ * If sending the data fails, the channel is deleted and the
+ * error is returned, so no channel is leaked.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param team the team's ID [`AranyaTeamId`](@ref AranyaTeamId).
+ * @param peer the peer's network identifier [`AranyaNetIdentifier`](@ref AranyaNetIdentifier).
+ * @param label the AFC channel label [`AranyaLabel`](@ref AranyaLabel) to create the channel with.
+ * @param data raw bytes of data to send.
+ * @param data_len length of data to send.
+ * @param __output the channel's ID [`AranyaChannelId`](@ref AranyaChannelId)
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_open_channel_and_send_ext(struct AranyaClient *client,
+                                             const struct AranyaTeamId *team,
+                                             AranyaNetIdentifier peer,
+                                             AranyaLabel label,
+                                             const uint8_t *data,
+                                             size_t data_len,
+                                             struct AranyaChannelId *__output,
+                                             struct AranyaExtError *__ext_err);
+
+/**
+ * Lists the open Aranya Fast Channels (AFC) channels.
+ *
+ * If `channels_len` is large enough to fit every channel, it
+ * copies the channels' IDs into `channels` and updates
+ * `channels_len` with the number of channels.
+ *
+ * Otherwise, it updates `channels_len` with the number of
+ * channels and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param channels buffer to copy the channel IDs [`AranyaChannelId`](@ref AranyaChannelId) into.
+ * @param channels_len length of the buffer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_list_channels(struct AranyaClient *client,
+                                     struct AranyaChannelId *channels,
+                                     size_t *channels_len);
+
+/**
+ * Lists the open Aranya Fast Channels (AFC) channels.
+ *
+ * If `channels_len` is large enough to fit every channel, it
+ * copies the channels' IDs into `channels` and updates
+ * `channels_len` with the number of channels.
+ *
+ * Otherwise, it updates `channels_len` with the number of
+ * channels and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param channels buffer to copy the channel IDs [`AranyaChannelId`](@ref AranyaChannelId) into.
+ * @param channels_len length of the buffer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_list_channels_ext(struct AranyaClient *client,
+                                         struct AranyaChannelId *channels,
+                                         size_t *channels_len,
+                                         struct AranyaExtError *__ext_err);
+
+/**
+ * Gets information about an open Aranya Fast Channels (AFC)
+ * channel.
+ *
+ * Returns `::ARANYA_ERROR_AFC` if the channel is not open.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param __output information about the channel [`AranyaAfcChannelInfo`](@ref AranyaAfcChannelInfo).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_channel_info(struct AranyaClient *client,
+                                    struct AranyaChannelId chan,
+                                    struct AranyaAfcChannelInfo *__output);
+
+/**
+ * Gets information about an open Aranya Fast Channels (AFC)
+ * channel.
+ *
+ * Returns `::ARANYA_ERROR_AFC` if the channel is not open.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param __output information about the channel [`AranyaAfcChannelInfo`](@ref AranyaAfcChannelInfo).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_channel_info_ext(struct AranyaClient *client,
+                                        struct AranyaChannelId chan,
+                                        struct AranyaAfcChannelInfo *__output,
+                                        struct AranyaExtError *__ext_err);
+
+/**
+ * Deletes an Aranya Fast Channels (AFC) channel.
+ *
+ * The channel is removed from the daemon and the client, so
+ * its ID is no longer listed by [`aranya_afc_list_channels`](@ref aranya_afc_list_channels).
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_delete_channel(struct AranyaClient *client,
+                                      struct AranyaChannelId chan);
+
+/**
+ * Deletes an Aranya Fast Channels (AFC) channel.
+ *
+ * The channel is removed from the daemon and the client, so
+ * its ID is no longer listed by [`aranya_afc_list_channels`](@ref aranya_afc_list_channels).
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_delete_channel_ext(struct AranyaClient *client,
+                                          struct AranyaChannelId chan,
+                                          struct AranyaExtError *__ext_err);
+
+/**
+ * Sets the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel to `value`, replacing its previous value.
+ *
+ * The metadata is not interpreted by Aranya and lives as long
+ * as the channel.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ * @param value the entry's value, a null-terminated UTF-8 string.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_set_channel_metadata(struct AranyaClient *client,
+                                            struct AranyaChannelId chan,
+                                            const char *key,
+                                            const char *value);
+
+/**
+ * Sets the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel to `value`, replacing its previous value.
+ *
+ * The metadata is not interpreted by Aranya and lives as long
+ * as the channel.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ * @param value the entry's value, a null-terminated UTF-8 string.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_set_channel_metadata_ext(struct AranyaClient *client,
+                                                struct AranyaChannelId chan,
+                                                const char *key,
+                                                const char *value,
+                                                struct AranyaExtError *__ext_err);
+
+/**
+ * Copies the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel into `value`.
+ *
+ * If `value_len` is large enough to fit the entire value,
+ * including the trailing null byte, it updates `value_len`
+ * with the length of the value and copies the value into
+ * `value`.
+ *
+ * Otherwise, it updates `value_len` with the length of the
+ * value and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+ *
+ * Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if the channel
+ * has no entry `key`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ * @param value buffer to copy the entry's value into.
+ * @param value_len length of the buffer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_get_channel_metadata(struct AranyaClient *client,
+                                            struct AranyaChannelId chan,
+                                            const char *key,
+                                            char *value,
+                                            size_t *value_len);
+
+/**
+ * Copies the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel into `value`.
+ *
+ * If `value_len` is large enough to fit the entire value,
+ * including the trailing null byte, it updates `value_len`
+ * with the length of the value and copies the value into
+ * `value`.
+ *
+ * Otherwise, it updates `value_len` with the length of the
+ * value and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+ *
+ * Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if the channel
+ * has no entry `key`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ * @param value buffer to copy the entry's value into.
+ * @param value_len length of the buffer.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_get_channel_metadata_ext(struct AranyaClient *client,
+                                                struct AranyaChannelId chan,
+                                                const char *key,
+                                                char *value,
+                                                size_t *value_len,
+                                                struct AranyaExtError *__ext_err);
+
+/**
+ * Removes the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel.
+ *
+ * It is not an error if the channel has no entry `key`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_remove_channel_metadata(struct AranyaClient *client,
+                                               struct AranyaChannelId chan,
+                                               const char *key);
+
+/**
+ * Removes the metadata entry `key` of an Aranya Fast Channels
+ * (AFC) channel.
+ *
+ * It is not an error if the channel has no entry `key`.
+ *
+ * @param client the Aranya Client [`AranyaClient`](@ref AranyaClient).
+ * @param chan the AFC channel's ID [`AranyaChannelId`](@ref AranyaChannelId).
+ * @param key the entry's key, a null-terminated UTF-8 string.
+ *
+ * @relates AranyaClient.
+ */
+AranyaError aranya_afc_remove_channel_metadata_ext(struct AranyaClient *client,
+                                                   struct AranyaChannelId chan,
+                                                   const char *key,
+                                                   struct AranyaExtError *__ext_err);
+
 #ifdef __cplusplus
 }  // extern "C"
 #endif  // __cplusplus
@@ -1,7 +1,13 @@
-use core::{ffi::c_char, ops::DerefMut, ptr, slice};
+use core::{
+    ffi::{c_char, c_void},
+    ops::DerefMut,
+    ptr, slice,
+    sync::atomic::Ordering,
+};
 use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
 
-use aranya_capi_core::{prelude::*, ErrorCode, InvalidArg};
+use aranya_capi_core::{prelude::*, ErrorCode as _, InvalidArg};
+use aranya_util::ShortId;
 use libc;
 use tracing::debug;
 
@@ -10,7 +16,7 @@ use crate::imp;
 /// An error code.
 ///
 /// For extended error information, see [`ExtError`].
-#[derive(Copy, Clone, Debug, Eq, PartialEq, ErrorCode)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, aranya_capi_core::ErrorCode)]
 #[repr(u32)]
 pub enum Error {
     /// Success.
@@ -77,12 +83,17 @@ impl From<&imp::Error> for Error {
             imp::Error::Utf8(_) => Self::InvalidUtf8,
             imp::Error::Addr(_) => Self::InvalidAddr,
             imp::Error::BufferTooSmall => Self::BufferTooSmall,
-            imp::Error::Client(err) => match err {
+            imp::Error::MissingMetadata(_) => Self::InvalidArgument,
+            imp::Error::NullCallback => Self::InvalidArgument,
+            imp::Error::EmptyExtError => Self::InvalidArgument,
+            imp::Error::Client(err) => match err.inner() {
                 aranya_client::Error::Connecting(_) => Self::Connecting,
                 aranya_client::Error::Rpc(_) => Self::Rpc,
                 aranya_client::Error::Daemon(_) => Self::Daemon,
                 aranya_client::Error::Afc(_) => Self::Afc,
                 aranya_client::Error::Bug(_) => Self::Bug,
+                // `inner` strips the context.
+                aranya_client::Error::Context { .. } => Self::Bug,
             },
             imp::Error::Runtime(_) => Self::Runtime,
         }
@@ -101,6 +112,140 @@ impl From<&InvalidArg<'static>> for Error {
     }
 }
 
+/// A detailed error code for an extended error.
+///
+/// Unlike [`Error`], it distinguishes between the AFC errors
+/// that callers are likely to handle. Codes are never
+/// renumbered, but new codes may be added, so callers should
+/// treat unknown codes like `::ARANYA_ERROR_CODE_OTHER`.
+///
+/// See [`error_code`].
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// The extended error does not hold an error.
+    None = 0,
+    /// An error without a more specific code.
+    Other = 1,
+    /// Internal bug discovered.
+    Bug = 2,
+    /// Timed out.
+    Timeout = 3,
+    /// Logging initialization failure.
+    LogInit = 4,
+    /// Invalid argument.
+    InvalidArgument = 5,
+    /// Buffer is too small.
+    BufferTooSmall = 6,
+    /// Invalid UTF-8.
+    InvalidUtf8 = 7,
+    /// Invalid address.
+    InvalidAddr = 8,
+    /// The channel has no metadata entry with the key.
+    MissingMetadata = 9,
+    /// Error connecting to daemon.
+    Connecting = 10,
+    /// Could not send request to daemon.
+    Rpc = 11,
+    /// Daemon reported error.
+    Daemon = 12,
+    /// Tokio runtime error.
+    Runtime = 13,
+
+    /// An AFC error without a more specific code.
+    AfcOther = 100,
+    /// The AFC channel does not exist.
+    AfcChannelNotFound = 101,
+    /// The AFC channel was revoked.
+    AfcChannelRevoked = 102,
+    /// The AFC channel has reached its last sequence number.
+    AfcEndOfChannel = 103,
+    /// The message is too large.
+    AfcMsgTooLarge = 104,
+    /// The AFC channel's rate limit was exceeded.
+    AfcRateLimited = 105,
+    /// The AFC channel's retry queue is full.
+    AfcRetryQueueFull = 106,
+    /// Sending over the AFC channel would block.
+    AfcWouldBlock = 107,
+    /// The router is backing off from the peer after failing
+    /// to connect.
+    AfcBackoff = 108,
+    /// Looking up the peer's address failed.
+    AfcDnsLookup = 109,
+    /// Connecting to the peer failed.
+    AfcConnect = 110,
+    /// Reading from or writing to the peer failed.
+    AfcStreamIo = 111,
+    /// Encrypting or decrypting a message failed.
+    AfcCrypto = 112,
+    /// The peer uses an unsupported AFC version.
+    AfcVersionMismatch = 113,
+    /// The daemon's shared memory cannot be used.
+    AfcShm = 114,
+    /// The router is out of capacity.
+    AfcCapacity = 115,
+    /// The AFC channel expired.
+    AfcChannelExpired = 116,
+}
+
+impl From<&imp::Error> for ErrorCode {
+    fn from(err: &imp::Error) -> Self {
+        use aranya_client::AfcError;
+
+        match err {
+            imp::Error::Bug(_) => Self::Bug,
+            imp::Error::Timeout(_) => Self::Timeout,
+            imp::Error::LogInit(_) => Self::LogInit,
+            imp::Error::InvalidArg(_) => Self::InvalidArgument,
+            imp::Error::Utf8(_) => Self::InvalidUtf8,
+            imp::Error::Addr(_) => Self::InvalidAddr,
+            imp::Error::BufferTooSmall => Self::BufferTooSmall,
+            imp::Error::MissingMetadata(_) => Self::MissingMetadata,
+            imp::Error::NullCallback => Self::InvalidArgument,
+            imp::Error::EmptyExtError => Self::InvalidArgument,
+            imp::Error::Client(err) => match err.inner() {
+                aranya_client::Error::Connecting(_) => Self::Connecting,
+                aranya_client::Error::Rpc(_) => Self::Rpc,
+                aranya_client::Error::Daemon(_) => Self::Daemon,
+                aranya_client::Error::Bug(_) => Self::Bug,
+                // `inner` strips the context.
+                aranya_client::Error::Context { .. } => Self::Bug,
+                aranya_client::Error::Afc(err) => match err {
+                    AfcError::Bug(_) => Self::Bug,
+                    AfcError::ChannelNotFound(_) => Self::AfcChannelNotFound,
+                    AfcError::ChannelRevoked(_) => Self::AfcChannelRevoked,
+                    AfcError::EndOfChannel => Self::AfcEndOfChannel,
+                    AfcError::MsgTooLarge { .. } | AfcError::DatagramTooLarge { .. } => {
+                        Self::AfcMsgTooLarge
+                    }
+                    AfcError::RateLimited(_) | AfcError::PeerRateLimited(_) => Self::AfcRateLimited,
+                    AfcError::RetryQueueFull(_) => Self::AfcRetryQueueFull,
+                    AfcError::WouldBlock(_) => Self::AfcWouldBlock,
+                    AfcError::Backoff { .. } => Self::AfcBackoff,
+                    AfcError::DnsLookup(_) => Self::AfcDnsLookup,
+                    AfcError::StreamConnect(_) | AfcError::Tls(_) => Self::AfcConnect,
+                    AfcError::StreamRead(_)
+                    | AfcError::StreamWrite(_)
+                    | AfcError::StreamShutdown(_)
+                    | AfcError::ReadData(_)
+                    | AfcError::Udp(_) => Self::AfcStreamIo,
+                    AfcError::Encryption(_) | AfcError::Decryption(_) => Self::AfcCrypto,
+                    AfcError::VersionMismatch { .. } => Self::AfcVersionMismatch,
+                    AfcError::ShmPathParse(_)
+                    | AfcError::ShmLayoutMismatch(_)
+                    | AfcError::ShmReadState(_) => Self::AfcShm,
+                    AfcError::Capacity(_) => Self::AfcCapacity,
+                    AfcError::ChannelExpired(_) => Self::AfcChannelExpired,
+                    AfcError::RequestTimeout(_) | AfcError::WindowExhausted(_) => Self::Timeout,
+                    _ => Self::AfcOther,
+                },
+            },
+            imp::Error::Runtime(_) => Self::Runtime,
+        }
+    }
+}
+
 /// Returns a human-readable error message for an [`Error`].
 ///
 /// The resulting pointer must NOT be freed.
@@ -147,6 +292,36 @@ pub fn ext_error_msg(
     err.copy_msg(msg, msg_len)
 }
 
+/// Returns the extended error's detailed error code.
+///
+/// It is `::ARANYA_ERROR_CODE_NONE` if the extended error does
+/// not hold an error, e.g., because the last call succeeded.
+///
+/// @param err the extended error [`ExtError`].
+///
+/// @relates AranyaExtError.
+#[aranya_capi_core::no_ext_error]
+pub fn error_code(err: &ExtError) -> ErrorCode {
+    err.err().map_or(ErrorCode::None, ErrorCode::from)
+}
+
+/// Enables or disables strict mode.
+///
+/// In strict mode, anomalies that are otherwise logged and
+/// ignored are reported as errors, e.g., so that certification
+/// runs can assert that none occur:
+/// - Clients initialized afterwards report duplicate AFC
+///   channel IDs and streams as `::ARANYA_ERROR_AFC`.
+/// - [`ext_error_msg`] returns `::ARANYA_ERROR_INVALID_ARGUMENT`
+///   for an extended error that does not hold an error instead
+///   of copying an empty message.
+///
+/// @param strict whether to enable strict mode.
+pub fn set_strict_mode(strict: bool) -> Result<(), imp::Error> {
+    imp::STRICT.store(strict, Ordering::Relaxed);
+    Ok(())
+}
+
 /// Initializes logging.
 ///
 /// Assumes the `ARANYA_CAPI` environment variable has been set to the desired tracing log level.
@@ -160,6 +335,40 @@ pub fn init_logging() -> Result<(), imp::Error> {
     Ok(())
 }
 
+/// The optional capabilities of this build of the library.
+///
+/// See [`features`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Features {
+    /// AFC streams can be wrapped in TLS.
+    pub tls: bool,
+    /// Router metrics are recorded.
+    pub metrics: bool,
+    /// The network used by AFC can be simulated.
+    pub sim: bool,
+}
+
+impl From<aranya_client::Features> for Features {
+    fn from(value: aranya_client::Features) -> Self {
+        Self {
+            tls: value.tls,
+            metrics: value.metrics,
+            sim: value.sim,
+        }
+    }
+}
+
+/// Returns the optional capabilities of this build of the
+/// library.
+///
+/// Use them to adapt at runtime, e.g., to only configure TLS
+/// when it is supported.
+#[aranya_capi_core::no_ext_error]
+pub fn features() -> Features {
+    aranya_client::features().into()
+}
+
 /// A handle to an Aranya Client.
 #[aranya_capi_core::derive(Cleanup)]
 #[aranya_capi_core::opaque(size = 2656, align = 16)]
@@ -208,6 +417,30 @@ impl From<Role> for aranya_daemon_api::Role {
     }
 }
 
+/// The transport used to send data over an Aranya Fast Channels
+/// (AFC) channel.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+pub enum AfcTransport {
+    /// TCP.
+    Tcp,
+    /// UDP.
+    Udp,
+    /// A transport that this version of the C API does not
+    /// know about.
+    Other,
+}
+
+impl From<aranya_client::Transport> for AfcTransport {
+    fn from(value: aranya_client::Transport) -> Self {
+        match value {
+            aranya_client::Transport::Tcp => Self::Tcp,
+            aranya_client::Transport::Udp => Self::Udp,
+            _ => Self::Other,
+        }
+    }
+}
+
 /// A network socket address for an Aranya client.
 ///
 /// E.g. "localhost:8080", "127.0.0.1:8080"
@@ -375,21 +608,19 @@ pub unsafe fn client_init(
         // SAFETY: Caller must ensure pointer is a valid C String.
         unsafe { std::ffi::CStr::from_ptr(config.afc.addr) }
         .to_str()?;
+    let cfg = aranya_client::RouterConfig {
+        strict: imp::STRICT.load(Ordering::Relaxed),
+        ..Default::default()
+    };
     let rt = tokio::runtime::Runtime::new().map_err(imp::Error::Runtime)?;
-    let inner = rt.block_on(aranya_client::Client::connect(
+    let inner = rt.block_on(aranya_client::Client::connect_with_config(
         daemon_sock,
         afc_shm_path,
         config.afc.max_channels,
         afc_addr,
+        cfg,
     ))?;
-    Safe::init(
-        client,
-        imp::Client {
-            rt,
-            inner,
-            msg: None,
-        },
-    );
+    Safe::init(client, imp::Client::new(rt, inner));
     Ok(())
 }
 
@@ -401,7 +632,7 @@ pub unsafe fn client_init(
 /// @relates AranyaClient.
 pub fn get_key_bundle(client: &mut Client) -> Result<KeyBundle, imp::Error> {
     let client = client.deref_mut();
-    let keys = client.rt.block_on(client.inner.get_key_bundle())?;
+    let keys = client.rt.block_on(client.inner.lock().get_key_bundle())?;
     Ok(KeyBundle::from_underlying(keys))
 }
 
@@ -413,7 +644,7 @@ pub fn get_key_bundle(client: &mut Client) -> Result<KeyBundle, imp::Error> {
 /// @relates AranyaClient.
 pub fn get_device_id(client: &mut Client) -> Result<DeviceId, imp::Error> {
     let client = client.deref_mut();
-    let id = client.rt.block_on(client.inner.get_device_id())?;
+    let id = client.rt.block_on(client.inner.lock().get_device_id())?;
     Ok(DeviceId(id))
 }
 
@@ -425,7 +656,7 @@ pub fn get_device_id(client: &mut Client) -> Result<DeviceId, imp::Error> {
 /// @relates AranyaClient.
 pub fn create_team(client: &mut Client) -> Result<TeamId, imp::Error> {
     let client = client.deref_mut();
-    let id = client.rt.block_on(client.inner.create_team())?;
+    let id = client.rt.block_on(client.inner.lock().create_team())?;
     Ok(TeamId(id))
 }
 
@@ -437,7 +668,7 @@ pub fn create_team(client: &mut Client) -> Result<TeamId, imp::Error> {
 /// @relates AranyaClient.
 pub fn add_team(client: &mut Client, team: &TeamId) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client.rt.block_on(client.inner.add_team(team.0))?;
+    client.rt.block_on(client.inner.lock().add_team(team.0))?;
     Ok(())
 }
 
@@ -449,7 +680,9 @@ pub fn add_team(client: &mut Client, team: &TeamId) -> Result<(), imp::Error> {
 /// @relates AranyaClient.
 pub fn remove_team(client: &mut Client, team: &TeamId) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client.rt.block_on(client.inner.remove_team(team.0))?;
+    client
+        .rt
+        .block_on(client.inner.lock().remove_team(team.0))?;
     Ok(())
 }
 
@@ -477,6 +710,7 @@ pub unsafe fn add_sync_peer(
     client.rt.block_on(
         client
             .inner
+            .lock()
             .team(team.0)
             .add_sync_peer(addr, interval.into()),
     )?;
@@ -500,7 +734,7 @@ pub unsafe fn remove_sync_peer(
     let addr = unsafe { addr.as_underlying() }?;
     client
         .rt
-        .block_on(client.inner.team(team.0).remove_sync_peer(addr))?;
+        .block_on(client.inner.lock().team(team.0).remove_sync_peer(addr))?;
     Ok(())
 }
 
@@ -512,7 +746,9 @@ pub unsafe fn remove_sync_peer(
 /// @relates AranyaClient.
 pub fn close_team(client: &mut Client, team: &TeamId) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client.rt.block_on(client.inner.team(team.0).close_team())?;
+    client
+        .rt
+        .block_on(client.inner.lock().team(team.0).close_team())?;
     Ok(())
 }
 
@@ -536,7 +772,7 @@ pub unsafe fn add_device_to_team(
         unsafe { keys.as_underlying() };
     client
         .rt
-        .block_on(client.inner.team(team.0).add_device_to_team(keys))?;
+        .block_on(client.inner.lock().team(team.0).add_device_to_team(keys))?;
     Ok(())
 }
 
@@ -555,9 +791,13 @@ pub fn remove_device_from_team(
     device: &DeviceId,
 ) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client
-        .rt
-        .block_on(client.inner.team(team.0).remove_device_from_team(device.0))?;
+    client.rt.block_on(
+        client
+            .inner
+            .lock()
+            .team(team.0)
+            .remove_device_from_team(device.0),
+    )?;
     Ok(())
 }
 
@@ -580,9 +820,13 @@ pub fn assign_role(
     role: Role,
 ) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client
-        .rt
-        .block_on(client.inner.team(team.0).assign_role(device.0, role.into()))?;
+    client.rt.block_on(
+        client
+            .inner
+            .lock()
+            .team(team.0)
+            .assign_role(device.0, role.into()),
+    )?;
     Ok(())
 }
 
@@ -603,9 +847,13 @@ pub fn revoke_role(
     role: Role,
 ) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client
-        .rt
-        .block_on(client.inner.team(team.0).revoke_role(device.0, role.into()))?;
+    client.rt.block_on(
+        client
+            .inner
+            .lock()
+            .team(team.0)
+            .revoke_role(device.0, role.into()),
+    )?;
     Ok(())
 }
 
@@ -635,6 +883,7 @@ pub unsafe fn assign_net_identifier(
     client.rt.block_on(
         client
             .inner
+            .lock()
             .team(team.0)
             .assign_net_identifier(device.0, net_identifier),
     )?;
@@ -663,6 +912,7 @@ pub unsafe fn remove_net_identifier(
     client.rt.block_on(
         client
             .inner
+            .lock()
             .team(team.0)
             .remove_net_identifier(device.0, net_identifier),
     )?;
@@ -682,7 +932,7 @@ pub fn create_label(client: &mut Client, team: &TeamId, label: Label) -> Result<
     let client = client.deref_mut();
     client
         .rt
-        .block_on(client.inner.team(team.0).create_label(label.into()))?;
+        .block_on(client.inner.lock().team(team.0).create_label(label.into()))?;
     Ok(())
 }
 
@@ -699,7 +949,7 @@ pub fn delete_label(client: &mut Client, team: &TeamId, label: Label) -> Result<
     let client = client.deref_mut();
     client
         .rt
-        .block_on(client.inner.team(team.0).delete_label(label.into()))?;
+        .block_on(client.inner.lock().team(team.0).delete_label(label.into()))?;
     Ok(())
 }
 
@@ -723,6 +973,7 @@ pub fn assign_label(
     client.rt.block_on(
         client
             .inner
+            .lock()
             .team(team.0)
             .assign_label(device.0, label.into()),
     )?;
@@ -749,6 +1000,7 @@ pub fn revoke_label(
     client.rt.block_on(
         client
             .inner
+            .lock()
             .team(team.0)
             .revoke_label(device.0, label.into()),
     )?;
@@ -778,21 +1030,124 @@ pub unsafe fn create_bidi_channel(
     let client = client.deref_mut();
     // SAFETY: Caller must ensure `peer` is a valid C String.
     let peer = unsafe { peer.as_underlying() }?;
-    let id = client
-        .rt
-        .block_on(client.inner.create_bidi_channel(team.0, peer, label.into()))?;
+    let id = client.rt.block_on(client.inner.lock().create_bidi_channel(
+        team.0,
+        peer,
+        label.into(),
+    ))?;
     Ok(ChannelId(id))
 }
 
 /// Delete an Aranya Fast Channel (AFC).
 ///
+/// Deprecated: use [`afc_delete_channel`].
+///
 /// @param client the Aranya Client [`Client`].
 /// @param chan the AFC channel ID [`ChannelId`] of the channel to delete.
 ///
 /// @relates AranyaClient.
 pub fn delete_channel(client: &mut Client, chan: ChannelId) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client.rt.block_on(client.inner.delete_channel(chan.0))?;
+    client
+        .rt
+        .block_on(client.inner.lock().delete_channel(chan.0))?;
+    Ok(())
+}
+
+/// Sets the metadata entry `key` of an Aranya Fast Channels
+/// (AFC) channel to `value`, replacing its previous value.
+///
+/// The metadata is not interpreted by Aranya and lives as long
+/// as the channel.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param chan the AFC channel's ID [`ChannelId`].
+/// @param key the entry's key, a null-terminated UTF-8 string.
+/// @param value the entry's value, a null-terminated UTF-8 string.
+///
+/// @relates AranyaClient.
+pub unsafe fn afc_set_channel_metadata(
+    client: &mut Client,
+    chan: ChannelId,
+    key: *const c_char,
+    value: *const c_char,
+) -> Result<(), imp::Error> {
+    let client = client.deref_mut();
+    // SAFETY: Caller must ensure `key` and `value` are valid C
+    // Strings.
+    let (key, value) = unsafe {
+        (
+            core::ffi::CStr::from_ptr(key).to_str()?,
+            core::ffi::CStr::from_ptr(value).to_str()?,
+        )
+    };
+    client
+        .inner
+        .lock()
+        .set_channel_metadata(chan.0, key, value)?;
+    Ok(())
+}
+
+/// Copies the metadata entry `key` of an Aranya Fast Channels
+/// (AFC) channel into `value`.
+///
+/// If `value_len` is large enough to fit the entire value,
+/// including the trailing null byte, it updates `value_len`
+/// with the length of the value and copies the value into
+/// `value`.
+///
+/// Otherwise, it updates `value_len` with the length of the
+/// value and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+///
+/// Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if the channel
+/// has no entry `key`.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param chan the AFC channel's ID [`ChannelId`].
+/// @param key the entry's key, a null-terminated UTF-8 string.
+/// @param value buffer to copy the entry's value into.
+/// @param value_len length of the buffer.
+///
+/// @relates AranyaClient.
+pub unsafe fn afc_get_channel_metadata(
+    client: &mut Client,
+    chan: ChannelId,
+    key: *const c_char,
+    value: &mut MaybeUninit<c_char>,
+    value_len: &mut usize,
+) -> Result<(), imp::Error> {
+    let value = aranya_capi_core::try_as_mut_slice!(value, *value_len);
+    let client = client.deref_mut();
+    // SAFETY: Caller must ensure `key` is a valid C String.
+    let key = unsafe { core::ffi::CStr::from_ptr(key) }.to_str()?;
+    let v = client
+        .inner
+        .lock()
+        .channel_metadata(chan.0)?
+        .get(key)
+        .ok_or_else(|| imp::Error::MissingMetadata(key.to_owned()))?;
+    aranya_capi_core::write_c_str(value, v, value_len).map_err(Into::into)
+}
+
+/// Removes the metadata entry `key` of an Aranya Fast Channels
+/// (AFC) channel.
+///
+/// It is not an error if the channel has no entry `key`.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param chan the AFC channel's ID [`ChannelId`].
+/// @param key the entry's key, a null-terminated UTF-8 string.
+///
+/// @relates AranyaClient.
+pub unsafe fn afc_remove_channel_metadata(
+    client: &mut Client,
+    chan: ChannelId,
+    key: *const c_char,
+) -> Result<(), imp::Error> {
+    let client = client.deref_mut();
+    // SAFETY: Caller must ensure `key` is a valid C String.
+    let key = unsafe { core::ffi::CStr::from_ptr(key) }.to_str()?;
+    client.inner.lock().remove_channel_metadata(chan.0, key)?;
     Ok(())
 }
 
@@ -800,15 +1155,20 @@ pub fn delete_channel(client: &mut Client, chan: ChannelId) -> Result<(), imp::E
 ///
 /// If the operation times out, this will return an `::ARANYA_ERROR_TIMEOUT`.
 ///
+/// This is not needed while a data callback is registered,
+/// since the callback's messages are received without polling.
+/// See [`afc_set_data_callback`].
+///
 /// @param client the Aranya Client [`Client`].
 /// @param timeout how long to wait before timing out the poll operation [`Duration`].
 ///
 /// @relates AranyaClient.
 pub fn poll_data(client: &mut Client, timeout: Duration) -> Result<(), imp::Error> {
     let client = client.deref_mut();
+    let mut inner = client.inner.lock();
     client.rt.block_on(async {
-        let data = tokio::time::timeout(timeout.into(), client.inner.poll_data()).await??;
-        client.inner.handle_data(data).await?;
+        let data = tokio::time::timeout(timeout.into(), inner.poll_data()).await??;
+        inner.handle_data(data).await?;
         Ok(())
     })
 }
@@ -823,13 +1183,20 @@ pub fn poll_data(client: &mut Client, timeout: Duration) -> Result<(), imp::Erro
 /// @relates AranyaClient.
 pub fn send_data(client: &mut Client, chan: ChannelId, data: &[u8]) -> Result<(), imp::Error> {
     let client = client.deref_mut();
-    client.rt.block_on(client.inner.send_data(chan.0, data))?;
+    client
+        .rt
+        .block_on(client.inner.lock().send_data(chan.0, data))?;
     Ok(())
 }
 
 /// Aranya Fast Channels (AFC) message info.
+///
+/// Its layout is stable, so its fields can be read directly.
+/// Bindings that cannot read C structs can use the accessors
+/// instead: [`msg_afc_id`], [`msg_label`], [`msg_seq`], and
+/// [`msg_peer_addr`].
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct AfcMsgInfo {
     /// Uniquely (globally) identifies the channel.
     pub channel: ChannelId,
@@ -843,6 +1210,104 @@ pub struct AfcMsgInfo {
     pub addr: SocketAddr,
 }
 
+impl From<&aranya_client::AfcMsg> for AfcMsgInfo {
+    fn from(msg: &aranya_client::AfcMsg) -> Self {
+        Self {
+            channel: ChannelId(msg.channel),
+            label: msg.label.into(),
+            seq: msg.seq.to_u64(),
+            addr: msg.addr.into(),
+        }
+    }
+}
+
+/// The effective limits of a client.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// The maximum size in bytes of an encoded message.
+    pub max_msg_size: u32,
+    /// The size in bytes of the AFC header prepended to each
+    /// ciphertext.
+    pub header_size: usize,
+    /// The number of bytes that sealing adds to the plaintext,
+    /// excluding the header.
+    pub seal_overhead: usize,
+    /// The maximum number of channels.
+    pub max_chans: usize,
+    /// The largest amount of data that can always be sent in one
+    /// message over TCP.
+    pub max_data_size: usize,
+    /// The largest amount of data that can always be sent in one
+    /// message over UDP.
+    pub max_datagram_data_size: usize,
+}
+
+impl From<aranya_client::Limits> for Limits {
+    fn from(value: aranya_client::Limits) -> Self {
+        Self {
+            max_msg_size: value.max_msg_size,
+            header_size: value.header_size,
+            seal_overhead: value.seal_overhead,
+            max_chans: value.max_chans,
+            max_data_size: value.max_data_size,
+            max_datagram_data_size: value.max_datagram_data_size,
+        }
+    }
+}
+
+/// Information about an open Aranya Fast Channels (AFC) channel.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct AfcChannelInfo {
+    /// Uniquely (globally) identifies the channel.
+    pub channel: ChannelId,
+    /// The label applied to the channel.
+    pub label: Label,
+    /// How data is sent over the channel.
+    pub transport: AfcTransport,
+    /// The number of messages waiting to be resent after a
+    /// transient failure.
+    pub queued: usize,
+    /// The number of plaintext bytes sent.
+    pub bytes_sent: u64,
+    /// The number of plaintext bytes received.
+    pub bytes_received: u64,
+    /// The number of messages encrypted and sent.
+    pub msgs_sealed: u64,
+    /// The number of messages received and decrypted.
+    pub msgs_opened: u64,
+}
+
+impl From<aranya_client::ChannelSummary> for AfcChannelInfo {
+    fn from(value: aranya_client::ChannelSummary) -> Self {
+        Self {
+            channel: ChannelId(value.id),
+            label: value.label.into(),
+            transport: value.transport.into(),
+            queued: value.queued,
+            bytes_sent: value.stats.bytes_sent,
+            bytes_received: value.stats.bytes_received,
+            msgs_sealed: value.stats.msgs_sealed,
+            msgs_opened: value.stats.msgs_opened,
+        }
+    }
+}
+
+/// Gets the client's effective limits.
+///
+/// Use them to size buffers passed to [`recv_data`] and to
+/// validate data passed to [`send_data`].
+///
+/// @param client the Aranya Client [`Client`].
+/// @param __output the client's limits [`Limits`].
+///
+/// @relates AranyaClient.
+pub fn limits(client: &mut Client) -> Result<Limits, imp::Error> {
+    let client = client.deref_mut();
+    Ok(client.inner.lock().limits().into())
+}
+
 /// Network socket address.
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug)]
@@ -901,7 +1366,7 @@ pub unsafe fn recv_data(
     let client = client.deref_mut();
 
     if client.msg.is_none() {
-        client.msg = client.inner.try_recv_data();
+        client.msg = client.inner.lock().try_recv_data();
     }
     let Some(msg) = &mut client.msg else {
         return Ok(false);
@@ -911,14 +1376,282 @@ pub unsafe fn recv_data(
     unsafe { buf.copy_to(|buf| buf.write_all(&msg.data)) }
         .map_err(|_| imp::Error::BufferTooSmall)?;
 
-    info.write(AfcMsgInfo {
-        channel: ChannelId(msg.channel),
-        label: msg.label.into(),
-        seq: msg.seq.to_u64(),
-        addr: msg.addr.into(),
-    });
+    info.write(AfcMsgInfo::from(&*msg));
 
     client.msg = None;
 
     Ok(true)
 }
+
+/// Returns the ID of the channel that a received message was
+/// sent over.
+///
+/// @param info information about the message [`AfcMsgInfo`].
+///
+/// @relates AranyaAfcMsgInfo.
+#[aranya_capi_core::no_ext_error]
+pub fn msg_afc_id(info: &AfcMsgInfo) -> ChannelId {
+    info.channel
+}
+
+/// Returns the label of the channel that a received message was
+/// sent over.
+///
+/// @param info information about the message [`AfcMsgInfo`].
+///
+/// @relates AranyaAfcMsgInfo.
+#[aranya_capi_core::no_ext_error]
+pub fn msg_label(info: &AfcMsgInfo) -> Label {
+    info.label
+}
+
+/// Returns the sequence number of a received message.
+///
+/// @param info information about the message [`AfcMsgInfo`].
+///
+/// @relates AranyaAfcMsgInfo.
+#[aranya_capi_core::no_ext_error]
+pub fn msg_seq(info: &AfcMsgInfo) -> u64 {
+    info.seq
+}
+
+/// Returns the network socket address of the peer that sent
+/// a received message.
+///
+/// @param info information about the message [`AfcMsgInfo`].
+///
+/// @relates AranyaAfcMsgInfo.
+#[aranya_capi_core::no_ext_error]
+pub fn msg_peer_addr(info: &AfcMsgInfo) -> SocketAddr {
+    info.addr
+}
+
+/// Receives Aranya Fast Channels (AFC) data.
+///
+/// `data` and `info` are only valid until the callback
+/// returns.
+///
+/// See [`afc_set_data_callback`].
+pub type AfcDataCallback = Option<
+    extern "C" fn(
+        user_data: *mut c_void,
+        data: *const u8,
+        data_len: usize,
+        info: *const AfcMsgInfo,
+    ),
+>;
+
+/// Registers a callback for incoming Aranya Fast Channels (AFC)
+/// data, replacing any previous callback.
+///
+/// While a callback is registered, the client's runtime polls
+/// for data in the background, and received messages are passed
+/// to the callback instead of being read with [`recv_data`].
+/// [`poll_data`] does not need to be called. The callback is
+/// invoked:
+/// - on one of the runtime's threads, as soon as data arrives,
+/// - once per message, in the order that [`recv_data`] would
+///   have returned them,
+/// - never concurrently with itself or other calls on the
+///   client, which wait for it to return.
+///
+/// The callback must not call any function with `client`. It
+/// must return quickly, since the client cannot send or receive
+/// data while it runs. `user_data` must be safe to use from
+/// other threads.
+///
+/// Returns `::ARANYA_ERROR_INVALID_ARGUMENT` if `callback` is
+/// NULL.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param callback the callback [`AfcDataCallback`].
+/// @param user_data passed to each invocation of `callback`.
+///
+/// @relates AranyaClient.
+pub fn afc_set_data_callback(
+    client: &mut Client,
+    callback: AfcDataCallback,
+    user_data: *mut c_void,
+) -> Result<(), imp::Error> {
+    let Some(callback) = callback else {
+        return Err(imp::Error::NullCallback);
+    };
+    let user_data = imp::UserData(user_data);
+    client.deref_mut().set_on_data(Box::new(move |msg| {
+        let info = AfcMsgInfo::from(msg);
+        callback(user_data.get(), msg.data.as_ptr(), msg.data.len(), &info);
+    }));
+    Ok(())
+}
+
+/// Unregisters the callback set with [`afc_set_data_callback`].
+///
+/// Messages are read with [`recv_data`] again, after polling
+/// with [`poll_data`]. Once this returns, the callback is no
+/// longer invoked.
+///
+/// This must not be called from the callback.
+///
+/// @param client the Aranya Client [`Client`].
+///
+/// @relates AranyaClient.
+pub fn afc_clear_data_callback(client: &mut Client) -> Result<(), imp::Error> {
+    client.deref_mut().clear_on_data();
+    Ok(())
+}
+
+/// Joins a team by syncing it with `peers`.
+///
+/// This is [`add_sync_peer`] for each peer, which is how
+/// a device that was added to a team joins it: the team's graph
+/// is fetched from the peers by the first sync.
+///
+/// If adding a sync peer fails, the peers that were already
+/// added are removed and the error is returned, so the call can
+/// be retried.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param team the team's ID [`TeamId`].
+/// @param peers the peers' Aranya network addresses [`Addr`].
+/// @param peers_len the number of peers.
+/// @param interval the time [`Duration`] to wait between syncs with each peer.
+///
+/// @relates AranyaClient.
+pub unsafe fn setup_and_join_team(
+    client: &mut Client,
+    team: &TeamId,
+    peers: &[Addr],
+    interval: Duration,
+) -> Result<(), imp::Error> {
+    let client = client.deref_mut();
+    let addrs = peers
+        .iter()
+        // SAFETY: Caller must ensure each `peer` is a valid C String.
+        .map(|peer| unsafe { peer.as_underlying() })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut inner = client.inner.lock();
+    client.rt.block_on(async {
+        for (i, addr) in addrs.iter().enumerate() {
+            let result = inner
+                .team(team.0)
+                .add_sync_peer(*addr, interval.into())
+                .await;
+            if let Err(err) = result {
+                for addr in addrs.iter().take(i) {
+                    if let Err(err) = inner.team(team.0).remove_sync_peer(*addr).await {
+                        debug!(%addr, %err, "unable to remove sync peer");
+                    }
+                }
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Creates an Aranya Fast Channel (AFC) and sends data over it.
+///
+/// This is [`create_bidi_channel`] followed by [`send_data`].
+/// If sending the data fails, the channel is deleted and the
+/// error is returned, so no channel is leaked.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param team the team's ID [`TeamId`].
+/// @param peer the peer's network identifier [`NetIdentifier`].
+/// @param label the AFC channel label [`Label`] to create the channel with.
+/// @param data raw bytes of data to send.
+/// @param data_len length of data to send.
+/// @param __output the channel's ID [`ChannelId`]
+///
+/// @relates AranyaClient.
+pub unsafe fn open_channel_and_send(
+    client: &mut Client,
+    team: &TeamId,
+    peer: NetIdentifier,
+    label: Label,
+    data: &[u8],
+) -> Result<ChannelId, imp::Error> {
+    let client = client.deref_mut();
+    // SAFETY: Caller must ensure `peer` is a valid C String.
+    let peer = unsafe { peer.as_underlying() }?;
+    let mut inner = client.inner.lock();
+    client.rt.block_on(async {
+        let id = inner
+            .create_bidi_channel(team.0, peer, label.into())
+            .await?;
+        if let Err(err) = inner.send_data(id, data).await {
+            if let Err(err) = inner.delete_channel(id).await {
+                debug!(afc_id = %ShortId::new("afc", id), %err, "unable to delete channel");
+            }
+            return Err(err.into());
+        }
+        Ok(ChannelId(id))
+    })
+}
+
+/// Lists the open Aranya Fast Channels (AFC) channels.
+///
+/// If `channels_len` is large enough to fit every channel, it
+/// copies the channels' IDs into `channels` and updates
+/// `channels_len` with the number of channels.
+///
+/// Otherwise, it updates `channels_len` with the number of
+/// channels and returns `::ARANYA_ERROR_BUFFER_TOO_SMALL`.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param channels buffer to copy the channel IDs [`ChannelId`] into.
+/// @param channels_len length of the buffer.
+///
+/// @relates AranyaClient.
+pub fn afc_list_channels(
+    client: &mut Client,
+    channels: &mut MaybeUninit<ChannelId>,
+    channels_len: &mut usize,
+) -> Result<(), imp::Error> {
+    let channels = aranya_capi_core::try_as_mut_slice!(channels, *channels_len);
+    let client = client.deref_mut();
+    let ids = client.inner.lock().list_channels();
+    *channels_len = ids.len();
+    if channels.len() < ids.len() {
+        return Err(imp::Error::BufferTooSmall);
+    }
+    for (dst, summary) in channels.iter_mut().zip(ids) {
+        dst.write(ChannelId(summary.id));
+    }
+    Ok(())
+}
+
+/// Gets information about an open Aranya Fast Channels (AFC)
+/// channel.
+///
+/// Returns `::ARANYA_ERROR_AFC` if the channel is not open.
+///
+/// @param client the Aranya Client [`Client`].
+/// @param chan the AFC channel's ID [`ChannelId`].
+/// @param __output information about the channel [`AfcChannelInfo`].
+///
+/// @relates AranyaClient.
+pub fn afc_channel_info(
+    client: &mut Client,
+    chan: ChannelId,
+) -> Result<AfcChannelInfo, imp::Error> {
+    let client = client.deref_mut();
+    Ok(client.inner.lock().channel_info(chan.0)?.into())
+}
+
+/// Deletes an Aranya Fast Channels (AFC) channel.
+///
+/// The channel is removed from the daemon and the client, so
+/// its ID is no longer listed by [`afc_list_channels`].
+///
+/// @param client the Aranya Client [`Client`].
+/// @param chan the AFC channel's ID [`ChannelId`].
+///
+/// @relates AranyaClient.
+pub fn afc_delete_channel(client: &mut Client, chan: ChannelId) -> Result<(), imp::Error> {
+    let client = client.deref_mut();
+    client
+        .rt
+        .block_on(client.inner.lock().delete_channel(chan.0))?;
+    Ok(())
+}
@@ -1,14 +1,176 @@
+use core::ffi::c_void;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
 use aranya_capi_core::safe::{TypeId, Typed};
 use aranya_client::AfcMsg;
+use tokio::{
+    sync::{Mutex, MutexGuard, Notify},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+/// Receives data instead of `recv_data`.
+///
+/// See `afc_set_data_callback`.
+pub type OnData = Box<dyn FnMut(&AfcMsg) + Send>;
+
+/// The `user_data` passed to the data callback.
+pub struct UserData(pub *mut c_void);
+
+// SAFETY: The caller of `afc_set_data_callback` must ensure
+// that `user_data` can be used from any thread.
+unsafe impl Send for UserData {}
+
+impl UserData {
+    /// Returns the pointer.
+    ///
+    /// Closures must use this instead of the field, so that
+    /// they capture the `Send` wrapper.
+    pub fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
 
 pub struct Client {
-    pub inner: aranya_client::Client,
+    pub inner: Arc<Inner>,
     pub rt: tokio::runtime::Runtime,
     /// Cached message in case the buffer provided to `recv_msg`
     /// is too small.
     pub msg: Option<AfcMsg>,
+    /// Invokes the data callback, if set.
+    driver: Option<Driver>,
+}
+
+impl Client {
+    pub fn new(rt: tokio::runtime::Runtime, inner: aranya_client::Client) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client: Mutex::new(inner),
+                waiting: AtomicUsize::new(0),
+                interrupt: Notify::new(),
+            }),
+            rt,
+            msg: None,
+            driver: None,
+        }
+    }
+
+    /// Invokes `on_data` for each received message from the
+    /// runtime, replacing the previous callback.
+    pub fn set_on_data(&mut self, on_data: OnData) {
+        self.clear_on_data();
+        let stop = Arc::new(AtomicBool::new(false));
+        let task = self.rt.spawn(drive(
+            Arc::clone(&self.inner),
+            Arc::clone(&stop),
+            self.msg.take(),
+            on_data,
+        ));
+        self.driver = Some(Driver { stop, task });
+    }
+
+    /// Stops invoking the data callback.
+    ///
+    /// Once this returns, the callback is no longer invoked.
+    pub fn clear_on_data(&mut self) {
+        let Some(driver) = self.driver.take() else {
+            return;
+        };
+        driver.stop.store(true, Ordering::SeqCst);
+        self.inner.interrupt.notify_one();
+        match self.rt.block_on(driver.task) {
+            Ok(pending) => self.msg = pending,
+            Err(err) => warn!(%err, "data callback failed"),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.clear_on_data();
+        // `inner` is dropped outside of `rt`, so close its
+        // streams while the runtime is still around.
+        self.rt.block_on(self.inner.lock().shutdown());
+    }
 }
 
 impl Typed for Client {
     const TYPE_ID: TypeId = TypeId::new(0xbbafb41c);
 }
+
+/// The underlying client, shared with the task that invokes
+/// the data callback.
+pub struct Inner {
+    client: Mutex<aranya_client::Client>,
+    /// The number of threads waiting in [`lock`][Self::lock].
+    waiting: AtomicUsize,
+    /// Wakes the task polling for data when a thread is
+    /// waiting for the client, or when it should stop.
+    interrupt: Notify,
+}
+
+impl Inner {
+    /// Locks the client, interrupting the task that polls for
+    /// data on behalf of the data callback.
+    ///
+    /// Must not be called from the runtime.
+    pub fn lock(&self) -> MutexGuard<'_, aranya_client::Client> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        self.interrupt.notify_one();
+        let client = self.client.blocking_lock();
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        client
+    }
+}
+
+/// The task that invokes the data callback.
+struct Driver {
+    stop: Arc<AtomicBool>,
+    /// Returns the message that it did not deliver, if any.
+    task: JoinHandle<Option<AfcMsg>>,
+}
+
+/// Polls for data and passes each received message to
+/// `on_data` until `stop` is set.
+///
+/// Polling is interrupted whenever a thread calls
+/// [`Inner::lock`], so the client is not held until data
+/// arrives.
+async fn drive(
+    inner: Arc<Inner>,
+    stop: Arc<AtomicBool>,
+    mut pending: Option<AfcMsg>,
+    mut on_data: OnData,
+) -> Option<AfcMsg> {
+    loop {
+        let mut client = inner.client.lock().await;
+        if stop.load(Ordering::SeqCst) {
+            return pending;
+        }
+        if inner.waiting.load(Ordering::SeqCst) > 0 {
+            // The mutex is fair, so once the waiting thread has
+            // queued up, it gets the client before we do.
+            drop(client);
+            tokio::task::yield_now().await;
+            continue;
+        }
+        while let Some(msg) = pending.take().or_else(|| client.try_recv_data()) {
+            on_data(&msg);
+        }
+        // `poll_data` is cancellation safe.
+        let data = tokio::select! {
+            result = client.poll_data() => result,
+            () = inner.interrupt.notified() => continue,
+        };
+        let result = match data {
+            Ok(data) => client.handle_data(data).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!(%err, "unable to poll for data");
+        }
+    }
+}
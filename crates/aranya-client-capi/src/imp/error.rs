@@ -1,4 +1,4 @@
-use core::{ffi::c_char, mem::MaybeUninit};
+use core::{ffi::c_char, mem::MaybeUninit, sync::atomic::Ordering};
 
 use aranya_buggy::Bug;
 use aranya_capi_core::{
@@ -26,6 +26,15 @@ pub enum Error {
     #[error("buffer too small")]
     BufferTooSmall,
 
+    #[error("extended error is empty")]
+    EmptyExtError,
+
+    #[error("channel has no metadata entry {0:?}")]
+    MissingMetadata(String),
+
+    #[error("data callback is null")]
+    NullCallback,
+
     #[error(transparent)]
     Utf8(#[from] core::str::Utf8Error),
 
@@ -60,11 +69,19 @@ impl ExtError {
         Self { err: Some(err) }
     }
 
+    /// Returns the error, if any.
+    pub fn err(&self) -> Option<&Error> {
+        self.err.as_ref()
+    }
+
     /// Copies the error message to `msg` as a null-terminated
     /// C string.
     pub fn copy_msg(&self, msg: &mut [MaybeUninit<c_char>], len: &mut usize) -> Result<(), Error> {
         if let Some(err) = &self.err {
             write_c_str(msg, err, len).map_err(Into::into)
+        } else if super::STRICT.load(Ordering::Relaxed) {
+            tracing::error!("empty extended error");
+            Err(Error::EmptyExtError)
         } else {
             warn!("empty extended error empty");
             write_c_str(msg, &"", len).map_err(Into::into)
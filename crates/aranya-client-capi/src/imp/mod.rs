@@ -1,5 +1,12 @@
+use core::sync::atomic::AtomicBool;
+
 pub mod client;
 pub mod error;
 
 pub use client::*;
 pub use error::*;
+
+/// Is strict mode enabled?
+///
+/// See [`set_strict_mode`][crate::api::set_strict_mode].
+pub static STRICT: AtomicBool = AtomicBool::new(false);
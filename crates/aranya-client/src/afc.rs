@@ -11,43 +11,168 @@
 //! - `len` is a 32-bit little endian integer that contains the
 //!   size in bytes of `msg`.
 //! - `msg`: A postcard-encoded [`Msg`].
+//!
+//! All integers in the header are little-endian regardless of
+//! the host's byte order. `msg` uses postcard's varint encoding,
+//! which is also independent of the host's byte order.
+//!
+//! # Transports
+//!
+//! Control messages and [`Hello`]s are always sent over TCP.
+//! Data messages are sent over TCP unless the channel was
+//! created with [`Transport::Udp`], in which case each data
+//! message is sent as a single UDP datagram containing
+//! `magic || len || msg`. The UDP socket is bound to the same
+//! address as the TCP listener.
+//!
+//! If TLS is enabled (see [`RouterConfig::tls`]), every TCP
+//! stream is wrapped in TLS and the wire format is sent inside
+//! the TLS stream.
+//!
+//! # Data Plaintext
+//!
+//! The plaintext sealed into a [`Msg::Data`] message is the
+//! application's data. Messages with extensions (like an
+//! idempotency token or a request ID) are sent as
+//! [`Msg::Enveloped`] instead, whose plaintext is a
+//! postcard-encoded [`Envelope`]: the list of extensions
+//! followed by the application's data. Extensions are encrypted
+//! and authenticated along with the data. Since extensions are
+//! opt-in, peers that predate them can read data sent without
+//! them.
+//!
+//! # Streams
+//!
+//! Payloads larger than a single message are sent as a stream
+//! of data messages, each carrying a [`Fragment`] extension.
+//! The receiver appends fragments in the order in which they
+//! are received, which is the order of their sequence numbers
+//! since older sequence numbers are rejected as replays.
+//! A fragment whose index does not follow the previous one
+//! (e.g., because a datagram was lost) drops the partial
+//! stream.
+//!
+//! # Versions
+//!
+//! Peers that predate [`Hello`] only speak [`Msg::Ctrl`] and
+//! [`Msg::Data`]. They decode a message from the start of each
+//! frame and ignore whatever follows it, so the peer that opens
+//! a stream appends its greeting to the first frame that it
+//! writes, and the other peer answers with its own. Until a peer
+//! has sent something else, it is only sent those two messages.
+//! The optional fields of [`Ctrl`] follow the ones that those
+//! peers decode. Opt-in features that add messages of their own
+//! (like keep-alive probes or flow control) need peers that
+//! speak them.
+//!
+//! Each peer greets the other with the newest version in
+//! [`SUPPORTED_VERSIONS`]. A peer that receives a version it
+//! also speaks adopts it, greeting again with it if it greeted
+//! with a newer one. A peer that receives a version it does not
+//! speak handles it per [`RouterConfig::version_mismatch`]; if
+//! it advertises its [`Versions`], the other peer greets again
+//! with the newest version they share. The agreed version is
+//! reported by [`StreamStats::negotiated`].
 
 use std::{
-    collections::btree_map::{self, BTreeMap},
-    ffi::c_int,
+    collections::{
+        btree_map::{self, BTreeMap},
+        BTreeSet, HashSet, VecDeque,
+    },
     fmt,
-    future::Future,
-    io::{self, IoSlice},
-    net::SocketAddr,
-    os::fd::AsRawFd,
+    future::{poll_fn, Future},
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    ops::Range,
     path::Path,
-    pin::Pin,
+    pin::{pin, Pin},
     str::FromStr,
-    task::{Context, Poll},
+    sync::Arc,
+    task::{ready, Context, Poll, Waker},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::anyhow;
+use aranya_base58::ToBase58;
 use aranya_buggy::{bug, Bug, BugExt};
-use aranya_crypto::{csprng::Random, default::Rng};
-use aranya_daemon_api::{AfcCtrl, AfcId, NetIdentifier, TeamId, CS};
+use aranya_crypto::{csprng::Random, default::Rng, Csprng};
+use aranya_daemon_api::{AfcCtrl, AfcId, DeviceId, NetIdentifier, ShmLayout, TeamId, CS};
 use aranya_fast_channels::{
     self as afc,
     shm::{Flag, InvalidPathError, Mode, ReadState},
-    AfcState, ChannelId, Client, Header, HeaderError, Label, Message, NodeId, Payload, Seq,
-    Version,
+    AfcState, ChannelId, Client, Header, HeaderError, Label, Message, Payload, Seq, Version,
+};
+use aranya_util::{
+    display::{FmtOr, ShortId, TryFmt},
+    util::ShmPathBuf,
 };
-use aranya_util::util::ShmPathBuf;
 use indexmap::{map, IndexMap};
 use serde::{Deserialize, Serialize};
+use tarpc::trace::TraceId;
 use tokio::{
-    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, Interest, ReadBuf},
     net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinSet,
+    time::{sleep, sleep_until, timeout, Instant},
 };
-use tracing::{debug, error, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
+
+pub(crate) use self::handoff::Handoff;
+use self::{
+    dns::DnsCache,
+    flow::Flow,
+    frame::{decode_frame, decode_msg, FrameBuf},
+    handoff::HandedChan,
+    keepalive::Probe,
+    limit::{RateLimiter, TokenBucket},
+    net::{bind_router, set_tcp_keepalive, unbound, Conn, Listeners, Net, Sockets},
+    persist::{SavedChan, Snapshot},
+    pool::{BufPool, RecvBuf},
+    replay::{Check, ReplayWindow},
+    resume::{Challenge, Resume, Ticket, Tickets, Token},
+    stream::Stream,
+};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConfig, AFC_ALPN};
+use crate::{
+    config::{
+        CapacityPolicy, ChannelExpiry, FlowControl, KeepAlive, QueueWatermarks, RateLimit,
+        RemovedChannelPolicy, RouterConfig, StreamTimeouts, VersionMismatchPolicy,
+    },
+    error::ErrorContext,
+    foreign::ForeignConn,
+    metrics,
+    reputation::{PeerReputation, Reputations},
+    resolve::SystemResolver,
+    rpc::{RequestId, Rpc},
+};
+
+mod dns;
+mod flow;
+mod frame;
+mod handoff;
+mod keepalive;
+mod limit;
+mod net;
+mod persist;
+mod pool;
+mod replay;
+mod resume;
+mod stream;
 
 /// An AFC error.
 #[derive(thiserror::Error, Debug)]
 pub enum AfcError {
+    /// The peer recently failed to connect, so we are waiting
+    /// before reconnecting.
+    #[error("backing off from {addr} for {retry_in:?}")]
+    Backoff {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// How long until the next connection attempt.
+        retry_in: Duration,
+    },
+
     /// Unable to bind a network addresss.
     #[error("unable to bind address: {0}")]
     Bind(io::Error),
@@ -56,10 +181,44 @@ pub enum AfcError {
     #[error("internal bug: {0}")]
     Bug(#[from] Bug),
 
+    /// A bound from [`MemoryBounds`][crate::MemoryBounds] or
+    /// a cap from [`ChannelLimits`][crate::ChannelLimits] was
+    /// reached.
+    #[error("out of capacity: {0}")]
+    Capacity(&'static str),
+
+    /// The channel expired, so it can no longer be used.
+    ///
+    /// See [`ChannelExpiry`][crate::ChannelExpiry].
+    #[error("channel expired: {0}")]
+    ChannelExpired(AfcId),
+
     /// The channel was not found.
     #[error("channel not found: {0}")]
     ChannelNotFound(AfcId),
 
+    /// The daemon removed the channel, e.g., because a policy
+    /// change revoked its label.
+    ///
+    /// See [`Client::reconcile_channels`][crate::Client::reconcile_channels].
+    #[error("channel revoked: {0}")]
+    ChannelRevoked(AfcId),
+
+    /// Unable to load or save the channel state.
+    ///
+    /// See [`RouterConfig::channel_state_path`].
+    #[error("unable to load or save channel state: {0}")]
+    ChannelState(io::Error),
+
+    /// The message does not fit in a single UDP datagram.
+    #[error("datagram too large: {got} > {max}")]
+    DatagramTooLarge {
+        /// The size of the datagram.
+        got: usize,
+        /// The maximum size of a datagram.
+        max: usize,
+    },
+
     /// AFC message decryption failure.
     #[error("decryption failure: {0}")]
     Decryption(afc::Error),
@@ -68,6 +227,20 @@ pub enum AfcError {
     #[error("DNS lookup failed: {0}")]
     DnsLookup(io::Error),
 
+    /// A channel with the same ID already exists.
+    ///
+    /// Only returned in strict mode. See
+    /// [`RouterConfig::strict`].
+    #[error("duplicate channel ID: {0}")]
+    DuplicateChannel(AfcId),
+
+    /// A stream with the same peer address already exists.
+    ///
+    /// Only returned in strict mode. See
+    /// [`RouterConfig::strict`].
+    #[error("duplicate stream: {0}")]
+    DuplicateStream(SocketAddr),
+
     /// AFC message encryption failure.
     #[error("encryption failure: {0}")]
     Encryption(afc::Error),
@@ -82,6 +255,13 @@ pub enum AfcError {
     #[error("end of channel reached")]
     EndOfChannel,
 
+    /// Unable to hand the router's state to a new process or to
+    /// take it over.
+    ///
+    /// See [`Client::hand_off`][crate::Client::hand_off].
+    #[error("unable to hand off router state: {0}")]
+    Handoff(io::Error),
+
     /// Invalid AFC header.
     #[error("invalid AFC header: {0}")]
     InvalidHeader(#[from] HeaderError),
@@ -107,6 +287,39 @@ pub enum AfcError {
     #[error("payload is too small to be ciphertext")]
     PayloadTooSmall,
 
+    /// The peer sent control messages too quickly.
+    ///
+    /// See [`RouterConfig::ctrl_rate_limit`].
+    #[error("control message rate limit exceeded for peer {0}")]
+    PeerRateLimited(IpAddr),
+
+    /// The channel's rate limit was exceeded.
+    ///
+    /// See [`RateLimit`][crate::RateLimit].
+    #[error("rate limit exceeded for channel {0}")]
+    RateLimited(AfcId),
+
+    /// Unable to read the data of a stream.
+    #[error("unable to read stream data: {0}")]
+    ReadData(io::Error),
+
+    /// Unable to load peer reputations.
+    #[error("unable to load peer reputations: {0}")]
+    Reputation(io::Error),
+
+    /// The response to a request over the channel did not
+    /// arrive in time.
+    ///
+    /// See [`Client::request`][crate::Client::request].
+    #[error("request over channel {0} timed out")]
+    RequestTimeout(AfcId),
+
+    /// The channel's retry queue is full.
+    ///
+    /// See [`RouterConfig::retry_queue_len`].
+    #[error("retry queue is full for channel {0}")]
+    RetryQueueFull(AfcId),
+
     /// Local address failure.
     #[error("unable to get local address: {0}")]
     RouterAddr(io::Error),
@@ -115,6 +328,14 @@ pub enum AfcError {
     #[error("serialization/deserialization error: {0}")]
     Serde(postcard::Error),
 
+    /// The daemon and client disagree on the layout of the
+    /// shared memory.
+    ///
+    /// This usually means that they were built for different
+    /// targets or with different options.
+    #[error("shared memory layout mismatch (daemon vs. client): {}", .0.join(", "))]
+    ShmLayoutMismatch(Vec<String>),
+
     /// Unable to parse shm path.
     #[error("unable to parse shared memory path: {0}")]
     ShmPathParse(InvalidPathError),
@@ -151,15 +372,57 @@ pub enum AfcError {
     #[error("stream not found: {0}")]
     StreamNotFound(SocketAddr),
 
+    /// The team's channels are handled by another router.
+    ///
+    /// See [`Client::isolate_team`][crate::Client::isolate_team].
+    #[error("team {0} is handled by another router")]
+    TeamNotRouted(TeamId),
+
+    /// The TLS handshake with a peer failed.
+    #[cfg(feature = "tls")]
+    #[error("TLS handshake failed: {0}")]
+    Tls(io::Error),
+
+    /// Unable to send or receive a UDP datagram.
+    #[error("UDP error: {0}")]
+    Udp(io::Error),
+
     /// AFC version mismatch.
     #[error("AFC version mismatch: got {actual:?}, expected {expected:?}")]
     VersionMismatch { expected: Version, actual: Version },
 
+    /// The channel's peer did not open its flow control window
+    /// in time.
+    ///
+    /// See [`FlowControl`].
+    #[error("flow control window exhausted for channel {0}")]
+    WindowExhausted(AfcId),
+
+    /// Sending data over the channel would have to wait.
+    ///
+    /// See [`Client::try_send_data`][crate::Client::try_send_data].
+    #[error("sending over channel {0} would block")]
+    WouldBlock(AfcId),
+
     /// Some other error.
     #[error("{0}")]
     Other(#[from] anyhow::Error),
 }
 
+impl AfcError {
+    /// Reports whether the error might go away if the operation
+    /// is retried later (e.g., a dropped TCP stream).
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Backoff { .. }
+                | Self::DnsLookup(_)
+                | Self::StreamConnect(_)
+                | Self::StreamWrite(_)
+        )
+    }
+}
+
 /// The most recent state from [`poll`][Afc::poll].
 #[derive(Clone, Debug)]
 pub(crate) enum State {
@@ -167,6 +430,81 @@ pub(crate) enum State {
     Accept(SocketAddr),
     /// We recieved an incoming message.
     Msg(SocketAddr),
+    /// The UDP socket might have a datagram.
+    Datagram,
+    /// Messages queued for retry are due to be resent.
+    Retry,
+    /// The stream with a peer was closed.
+    Disconnected(SocketAddr, DisconnectReason),
+    /// The channel table is due to be reconciled with the
+    /// daemon's.
+    Reconcile,
+    /// Keep-alive pings are due to be sent or have timed out.
+    KeepAlive,
+    /// Idle streams are due to be pinged or have not answered.
+    ///
+    /// See [`RouterConfig::stream_timeouts`].
+    Reap,
+    /// Channels are due to expire.
+    ///
+    /// See [`ChannelExpiry`].
+    Expire,
+    /// Flow control windows are due to be advertised.
+    ///
+    /// See [`FlowControl`].
+    Window,
+    /// The client's connection to the daemon broke and is due
+    /// to be reestablished.
+    ///
+    /// This comes from the client, not from the router.
+    DaemonLost,
+}
+
+/// How urgently a data message should be sent.
+///
+/// Messages are normally written in the order they are sent.
+/// When messages are queued for a channel (e.g., because its
+/// stream was dropped), high-priority messages are queued ahead
+/// of normal-priority ones and, when the queues are resent,
+/// high-priority messages of every channel are written before
+/// the normal-priority messages of any of them. This keeps,
+/// e.g., control-plane messages from waiting behind bulk data
+/// on the same stream.
+///
+/// A high-priority message only overtakes queued messages if
+/// the peer still accepts them afterwards, i.e., if they are
+/// within its [`RouterConfig::replay_window`], which is assumed
+/// to be the same as ours. Otherwise, it is queued in order.
+/// With the default window of zero, messages are never
+/// reordered.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Priority {
+    /// Bulk data.
+    #[default]
+    Normal,
+    /// Data that should not wait behind bulk data.
+    High,
+}
+
+/// The transport used to send data messages over a channel.
+///
+/// Control messages are always sent over TCP.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// Send data over a TCP stream.
+    ///
+    /// Messages are delivered reliably and in order.
+    #[default]
+    Tcp,
+    /// Send each message as a UDP datagram.
+    ///
+    /// Messages can be lost or arrive out of order. Messages
+    /// that arrive after a message with a higher sequence
+    /// number are dropped. Messages must fit in a single
+    /// datagram.
+    Udp,
 }
 
 /// AFC messages.
@@ -177,15 +515,78 @@ pub(crate) enum State {
 pub(crate) enum Msg {
     Ctrl(Ctrl),
     Data(Data),
+    Hello(Hello),
+    /// The peer is overloaded and closed the stream without
+    /// reading from it.
+    Busy,
+    /// The peer dropped data for a channel that it recently
+    /// removed.
+    Nack(Nack),
+    /// Asks the peer whether it is still reachable.
+    Ping(Ping),
+    /// Answers a [`Msg::Ping`].
+    Pong(Ping),
+    /// Asks the peer whether the stream is still open.
+    ///
+    /// See [`StreamTimeouts`].
+    StreamPing,
+    /// Answers a [`Msg::StreamPing`].
+    StreamPong,
+    /// The peer does not speak the version of our [`Hello`],
+    /// but keeps the stream open for a while so that we can
+    /// greet it again with one that it does.
+    ///
+    /// See [`VersionMismatchPolicy::Advertise`].
+    Versions(Versions),
+    /// The peer is about to close the stream.
+    Goodbye(Goodbye),
+    /// A ticket for resuming the stream if it breaks.
+    ///
+    /// See [`RouterConfig::session_resumption`].
+    Ticket(Ticket),
+    /// Answers a [`Msg::Challenge`] from a peer that issued us
+    /// a [`Msg::Ticket`].
+    Resume(Resume),
+    /// The peer deleted a channel.
+    Close(Close),
+    /// The peer's flow control window for a channel.
+    Window(Window),
+    /// Like [`Msg::Data`], but the plaintext is an
+    /// [`Envelope`].
+    ///
+    /// It is only sent when the message has extensions.
+    Enveloped(Data),
+    /// Sent after [`Hello`] to a peer that we issued a
+    /// [`Msg::Ticket`] to, so that it can resume a stream with
+    /// a [`Msg::Resume`].
+    Challenge(Challenge),
 }
 
 /// An AFC control message.
+///
+/// Fields after `cmd` are optional: peers that predate them
+/// ignore them and do not send them (see
+/// [`decode_frame`][frame::decode_frame]), so new ones must be
+/// added to the end.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Ctrl {
     pub version: Version,
     pub team_id: TeamId,
     /// Ephemeral command for AFC channel creation.
     pub cmd: AfcCtrl,
+    /// If set, the sender wants data sent over UDP to this
+    /// port at the sender's IP address.
+    pub udp_port: Option<u16>,
+    /// Correlates the control message with the daemon RPCs
+    /// that created and received it.
+    ///
+    /// It is sent in cleartext.
+    pub trace_id: Option<TraceId>,
+    /// If set, data sent over the channel carries a checksum of
+    /// its plaintext.
+    ///
+    /// See [`RouterConfig::self_check`].
+    pub self_check: bool,
 }
 
 /// An AFC data (ciphertext) message.
@@ -193,737 +594,6502 @@ pub(crate) struct Ctrl {
 pub(crate) struct Data {
     version: Version,
     afc_id: AfcId,
+    ciphertext: RecvBuf,
+    /// Whether the plaintext is an [`Envelope`], i.e., whether
+    /// it was received as [`Msg::Enveloped`].
+    #[serde(skip)]
+    enveloped: bool,
+}
+
+/// Tells the peer that we dropped its data because we recently
+/// removed the channel.
+///
+/// It is sent at most once per removed channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Nack {
+    version: Version,
+    pub afc_id: AfcId,
+}
+
+/// Tells the peer that we deleted a channel.
+///
+/// It is only accepted over the stream that the channel uses,
+/// and only if `ciphertext` is [`CLOSE_MARKER`] sealed with the
+/// channel's key, so that it cannot be forged by anyone who
+/// learns the channel's ID.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Close {
+    version: Version,
+    pub afc_id: AfcId,
+    /// `header || ciphertext`, like [`Data::ciphertext`].
     ciphertext: Vec<u8>,
 }
 
-/// The size in bytes of `magic || len`.
+/// The plaintext of [`Close::ciphertext`].
+const CLOSE_MARKER: &[u8] = b"\xffclose";
+
+/// Advertises how far the sender may send over a channel.
 ///
-/// See the wire format description.
-const WIRE_HEADER_SIZE: usize = 4 + 4;
+/// It is sent over the channel's transport. See
+/// [`FlowControl`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Window {
+    version: Version,
+    pub afc_id: AfcId,
+    /// The sequence number below which the sender may send.
+    limit: u64,
+}
 
-/// See the wire format description.
-const WIRE_MAGIC: &[u8; 4] = b"AFC\0";
+/// A keep-alive probe for a channel, or the answer to one.
+///
+/// It is sent over the channel's transport and answered with
+/// the same nonce.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Ping {
+    version: Version,
+    pub afc_id: AfcId,
+    nonce: u64,
+}
 
-/// The maximum allowed size of a [`Msg`].
+/// Sent by both peers when a TCP stream is opened.
 ///
-/// Helps prevent DoS attacks.
-// TODO(eric): make this configurable.
-const MAX_MSG_SIZE: u32 = 10 * 1024 * 1024;
+/// It identifies the device on each end of the stream so that
+/// duplicate streams between the same pair of devices can be
+/// resolved once the daemon verifies it. See
+/// [`Afc::handle_hello`] and [`Afc::verify_peer`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Hello {
+    version: Version,
+    device_id: DeviceId,
+    /// The port that the sender accepts streams on, if it does.
+    ///
+    /// It might not be the port that the sender's peers were
+    /// told, e.g., if it fell back to another one (see
+    /// [`RouterConfig::fallback_ports`]).
+    port: Option<u16>,
+}
 
-/// Sends and receives AFC messages.
-pub(crate) struct Afc<S> {
-    /// The underlying AFC client.
-    afc: Client<S>,
-    /// Listens for incoming connections from peers.
-    listener: TcpListener,
-    /// Open TCP connections.
-    // TODO(eric): prune unused/idle streams.
-    // TODO(eric): use different maps for streams we opened vs
-    // streams that peers opened.
-    streams: TcpStreams,
-    /// All open channels.
-    chans: BTreeMap<AfcId, Chan>,
-    /// Incrementing counter for unique [`NodeId`]s.
-    // TODO: move this counter into the daemon.
-    next_node_id: u32,
+/// The wire versions that the sender speaks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Versions {
+    pub supported: Vec<Version>,
 }
 
-impl<S: AfcState> Afc<S> {
-    /// Creates a new `Afc` listening for connections on `addr`.
-    pub async fn new<A>(afc: Client<S>, addr: A) -> Result<Self, AfcError>
-    where
-        A: ToSocketAddrs,
-    {
-        let listener = TcpListener::bind(addr).await.map_err(AfcError::Bind)?;
-        Ok(Self {
-            afc,
-            listener,
-            streams: TcpStreams::new(),
-            chans: BTreeMap::new(),
-            next_node_id: 0,
-        })
+/// Tells the peer why the sender is closing the stream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Goodbye {
+    pub reason: GoodbyeReason,
+}
+
+/// Why a peer closed the stream with us.
+///
+/// See [`AfcEvent::Goodbye`][crate::AfcEvent::Goodbye].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum GoodbyeReason {
+    /// The peer does not speak the version of our greeting.
+    ///
+    /// See [`VersionMismatchPolicy::Goodbye`].
+    UnsupportedVersion {
+        /// The versions that the peer speaks.
+        supported: Vec<Version>,
+    },
+    /// The peer is shutting down (see [`Client::shutdown`]).
+    ///
+    /// [`Client::shutdown`]: crate::Client::shutdown
+    Shutdown,
+    /// The peer has another stream with us and keeps that one
+    /// instead.
+    ///
+    /// Messages already sent over the stream are still
+    /// delivered, and channels move to the other stream.
+    Duplicate,
+}
+
+/// Why the stream with a peer was closed.
+///
+/// See [`AfcEvent::PeerDisconnected`][crate::AfcEvent::PeerDisconnected].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// The peer closed the stream.
+    Closed,
+    /// The peer reset the stream.
+    Reset,
+    /// The peer stopped answering: a ping or a write did not
+    /// complete in time, or it did not identify itself within
+    /// [`RouterConfig::handshake_timeout`].
+    ///
+    /// See [`StreamTimeouts`].
+    TimedOut,
+    /// The peer said goodbye.
+    ///
+    /// See [`AfcEvent::Goodbye`][crate::AfcEvent::Goodbye].
+    Goodbye(GoodbyeReason),
+    /// The peer is overloaded.
+    Busy,
+    /// The peer speaks another wire version.
+    ///
+    /// See [`RouterConfig::version_mismatch`].
+    VersionMismatch,
+    /// Reading from or writing to the stream failed.
+    Io(io::ErrorKind),
+}
+
+impl DisconnectReason {
+    /// Classifies a failure to read from or write to a stream.
+    fn from_io(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::UnexpectedEof => Self::Closed,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Self::Reset,
+            io::ErrorKind::TimedOut => Self::TimedOut,
+            kind => Self::Io(kind),
+        }
     }
 
-    /// Verifies that the router version is expected.
-    fn check_version(&self, version: Version) -> Result<(), AfcError> {
-        if version != Version::V1 {
-            error!(got = ?version, want = ?Version::V1, "AFC version mismatch");
-            Err(AfcError::VersionMismatch {
-                expected: Version::V1,
-                actual: version,
-            })
-        } else {
-            Ok(())
+    /// Classifies a failure to write to a stream.
+    fn from_write(err: &AfcError) -> Self {
+        match err {
+            AfcError::StreamWrite(err) => Self::from_io(err.kind()),
+            #[cfg(feature = "tls")]
+            AfcError::Tls(err) => Self::from_io(err.kind()),
+            _ => Self::Io(io::ErrorKind::Other),
         }
     }
+}
 
-    /// Polls the current AFC state.
-    #[instrument(skip_all)]
-    pub async fn poll(&mut self) -> Result<State, AfcError> {
-        #![allow(clippy::disallowed_macros)]
-        tokio::select! {
-            biased;
+/// Encodes a [`Goodbye`] frame, including the wire header.
+fn goodbye_frame(reason: GoodbyeReason) -> Result<Vec<u8>, AfcError> {
+    let mut buf = FrameBuf::default();
+    buf.encode(&Msg::Goodbye(Goodbye { reason }))?;
+    Ok(buf.finish()?.to_vec())
+}
 
-            // An existing stream has a message.
-            result = self.streams.next() => {
-                result.map(State::Msg).map_err(Into::into)
-            }
+/// The plaintext of a [`Data`] message.
+///
+/// It is decoded with [`decode_envelope`].
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    /// Optional extensions.
+    exts: &'a [Ext],
+    /// The application's data.
+    data: &'a [u8],
+}
 
-            // We have an incoming connection.
-            result = self.listener.accept() => {
-                result
-                    .map(|(stream, addr)| {
-                        debug!(%addr, "accepted incoming TCP stream");
-                        self.streams.insert(stream)?;
-                        Ok::<_, AfcError>(addr)
-                    })
-                    .map_err(AfcError::StreamAccept)?
-                    .map(State::Accept)
-                    .map_err(Into::into)
-            }
-        }
+/// Decodes an [`Envelope`] without allocating.
+///
+/// Returns the envelope's extensions and the range of `buf` that
+/// holds the application's data.
+fn decode_envelope(buf: &[u8]) -> Result<(Exts<'_>, Range<usize>), AfcError> {
+    let (n, exts) = postcard::take_from_bytes::<usize>(buf).map_err(AfcError::Serde)?;
+    // Skip over the extensions, checking that they are valid
+    // so that `Exts` does not have to.
+    let mut rest = exts;
+    for _ in 0..n {
+        (_, rest) = postcard::take_from_bytes::<Ext>(rest).map_err(AfcError::Serde)?;
     }
+    let (len, data) = postcard::take_from_bytes::<usize>(rest).map_err(AfcError::Serde)?;
+    if len > data.len() {
+        return Err(AfcError::Serde(postcard::Error::DeserializeUnexpectedEnd));
+    }
+    let start = buf.len() - data.len();
+    Ok((Exts { buf: exts, n }, start..start + len))
+}
 
-    /// Sends a control message to the peer at `net_id`.
-    // NB: Eliding `net_id` and `team_id` since
-    // `create_bidi_channel` (in client.rs) also adds those.
-    #[instrument(skip_all, fields(
-        %afc_id,
-        %chan_id,
-    ))]
-    pub async fn send_ctrl(
-        &mut self,
-        net_id: NetIdentifier,
-        cmd: AfcCtrl,
-        team_id: TeamId,
-        afc_id: AfcId,
-        chan_id: ChannelId,
-    ) -> Result<(), AfcError> {
-        debug!("sending control message");
+/// The extensions of an encoded [`Envelope`].
+#[derive(Clone, Debug)]
+struct Exts<'a> {
+    buf: &'a [u8],
+    /// The number of remaining extensions.
+    n: usize,
+}
 
-        // TODO(eric): Don't allocate here.
-        let data = postcard::to_allocvec(&Msg::Ctrl(Ctrl {
-            version: Version::V1,
-            team_id,
-            cmd,
-        }))
-        .map_err(AfcError::Serde)?;
-        debug!(len = data.len(), "encoded ctrl message");
+impl Iterator for Exts<'_> {
+    type Item = Ext;
 
-        let len = u32::try_from(data.len())
-            .assume("`data` should be < 2^32-1")?
-            .to_le_bytes();
+    fn next(&mut self) -> Option<Ext> {
+        self.n = self.n.checked_sub(1)?;
+        let (ext, rest) = postcard::take_from_bytes(self.buf).ok()?;
+        self.buf = rest;
+        Some(ext)
+    }
+}
 
-        let stream = {
-            // Try to find an open stream with this peer.
-            let addr = lookup_host(net_id.as_ref())
-                .await
-                .map_err(AfcError::DnsLookup)?
-                .find(|addr| {
-                    debug!(%addr, "resolved potential address");
-                    self.streams.contains(addr)
-                });
-            self.streams
-                .try_get_or_open((addr, net_id.as_ref()))
-                .await?
-        };
-        let addr = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
-        debug!(%addr, "connected to peer");
+/// An [`Envelope`] extension.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum Ext {
+    /// See [`IdempotencyToken`].
+    IdempotencyToken(IdempotencyToken),
+    /// A checksum of the application's data.
+    ///
+    /// Only sent on channels with self-check enabled. See
+    /// [`RouterConfig::self_check`].
+    Checksum(u64),
+    /// See [`Fragment`].
+    Fragment(Fragment),
+    /// When the message was sent, in microseconds since the
+    /// Unix epoch.
+    ///
+    /// Only sent if enabled. See
+    /// [`RouterConfig::send_timestamps`].
+    Timestamp(u64),
+    /// Marks the message as a request.
+    ///
+    /// See [`Client::request`][crate::Client::request].
+    Request(RequestId),
+    /// Marks the message as the response to a request.
+    ///
+    /// See [`Client::respond`][crate::Client::respond].
+    Response(RequestId),
+}
 
-        stream
-            .write_all_vectored(&mut [
-                IoSlice::new(WIRE_MAGIC),
-                IoSlice::new(&len),
-                IoSlice::new(&data),
-            ])
-            .await
-            .map_err(AfcError::StreamWrite)?;
-        stream.flush().await.map_err(AfcError::StreamWrite)?;
-        debug!("sent control message");
+/// Converts `t` for [`Ext::Timestamp`], saturating.
+fn to_unix_micros(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_micros()).unwrap_or(u64::MAX))
+}
 
-        // TODO(eric): This throws away `stream` if we already
-        // have a stream with this address.
-        self.add_channel(afc_id, net_id, team_id, chan_id, addr)
-            .await?;
+/// Converts an [`Ext::Timestamp`], saturating.
+fn from_unix_micros(micros: u64) -> SystemTime {
+    let since = Duration::from_micros(micros);
+    SystemTime::UNIX_EPOCH
+        .checked_add(since)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
 
-        Ok(())
-    }
+/// Identifies a fragment of a stream.
+///
+/// See the module-level docs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Fragment {
+    /// The fragment's position in the stream, starting at zero.
+    index: u32,
+    /// Is this the last fragment in the stream?
+    last: bool,
+}
 
-    /// Encrypts `plaintext` and sends it over the AFC channel.
-    // NB: Eliding `id` since send_data` (in client.rs) also adds
-    // it.
-    #[instrument(skip_all)]
-    pub async fn send_data(&mut self, id: AfcId, plaintext: &[u8]) -> Result<(), AfcError> {
-        debug!(pt_len = plaintext.len(), "sending data");
+/// The maximum size in bytes of the data in a stream fragment.
+///
+/// Fragments are also limited by the router's [`Limits`].
+const MAX_FRAGMENT_SIZE: usize = 64 * 1024;
 
-        let Chan {
-            net_id,
-            chan_id,
-            addr,
-            ..
-        } = self
-            .chans
-            .get(&id)
-            .ok_or_else(|| AfcError::ChannelNotFound(id))?;
-        debug!(%chan_id, %addr, "found channel");
+/// Computes the 64-bit FNV-1a hash of `data`.
+///
+/// It is not cryptographically secure: the AEAD already
+/// authenticates the plaintext. It only detects bugs that corrupt
+/// the plaintext before it is sealed or after it is opened.
+fn checksum(data: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    data.iter()
+        .fold(OFFSET, |h, &b| (h ^ u64::from(b)).wrapping_mul(PRIME))
+}
 
-        // TODO(eric): Don't allocate here. Use `IoSlice`
-        // instead.
-        let datagram = {
-            // We need enough space to write
-            //   header || ciphertext
-            let mut buf = vec![0u8; Header::PACKED_SIZE + plaintext.len() + Client::<S>::OVERHEAD];
-            let (header, ciphertext) = buf
-                .split_first_chunk_mut()
-                .assume("`buf.len()` >= `Header::PACKED_SIZE`")?;
-            debug!(%chan_id, "sealing message");
-            let hdr = self
-                .afc
-                .seal(*chan_id, ciphertext, plaintext)
-                .map_err(AfcError::Encryption)?;
-            debug!(%chan_id, "sealed message");
-            hdr.encode(header)?;
-            buf
-        };
-        debug!(len = datagram.len(), "created datagram");
+/// A caller-supplied token that identifies a logical message.
+///
+/// The receiver suppresses messages with the same token on the
+/// same channel, so an application can safely retry a send after
+/// an ambiguous failure. Only the most recent
+/// [`IDEMPOTENCY_WINDOW`] tokens per channel are remembered.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct IdempotencyToken([u8; 16]);
 
-        // TODO(eric): Don't allocate here.
-        let data = postcard::to_allocvec(&Msg::Data(Data {
-            version: Version::V1,
-            afc_id: id,
-            ciphertext: datagram,
-        }))
-        .map_err(AfcError::Serde)?;
-        debug!(len = data.len(), "encoded data message");
+impl IdempotencyToken {
+    /// Creates a token from its bytes.
+    pub const fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
 
-        let len = u32::try_from(data.len())
-            .assume("`data` should be < 2^32-1")?
-            .to_le_bytes();
+    /// Creates a random token.
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 16];
+        Rng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
 
-        let stream = self.streams.get_or_open((*addr, net_id.as_ref())).await?;
-        stream
-            .write_all_vectored(&mut [
-                IoSlice::new(WIRE_MAGIC),
-                IoSlice::new(&len),
-                IoSlice::new(&data),
-            ])
-            .await
-            .map_err(AfcError::StreamWrite)?;
-        stream.flush().await.map_err(AfcError::StreamWrite)?;
-        debug!(data_len = data.len(), "wrote msg to stream");
+    /// Returns the token's bytes.
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
 
-        Ok(())
+impl From<[u8; 16]> for IdempotencyToken {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
     }
+}
 
-    /// Reads a [`Msg`] from the stream.
-    #[instrument(skip_all, fields(%addr))]
-    pub async fn read_msg(&mut self, addr: SocketAddr) -> Result<Msg, AfcError> {
-        debug!("reading message from stream");
+impl fmt::Display for IdempotencyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_base58())
+    }
+}
 
-        let stream = self
-            .streams
-            .get_mut(&addr)
-            .ok_or_else(|| AfcError::StreamNotFound(addr))?;
+/// The number of recent [`IdempotencyToken`]s remembered per
+/// channel.
+pub const IDEMPOTENCY_WINDOW: usize = 1024;
 
-        stream.readable().await.map_err(AfcError::StreamRead)?;
+/// The wire versions that the router speaks, newest first.
+///
+/// Each stream uses the newest version that both peers speak.
+/// See [`StreamStats::negotiated`].
+pub const SUPPORTED_VERSIONS: &[Version] = &[Version::V1];
 
-        let mut buf = [[0u8; 4]; 2];
-        stream
-            .read_exact(buf.as_flattened_mut())
-            .await
-            .map_err(AfcError::StreamRead)?;
+/// Returns the newest version that we and a peer that speaks
+/// `theirs` both speak.
+fn common_version(theirs: &[Version]) -> Option<Version> {
+    SUPPORTED_VERSIONS
+        .iter()
+        .find(|v| theirs.contains(v))
+        .copied()
+}
 
-        let magic = buf[0];
-        if magic != *WIRE_MAGIC {
-            error!(got = ?magic, expected = ?WIRE_MAGIC, "invalid magic");
-            return Err(AfcError::InvalidMagic(u32::from_le_bytes(magic)));
+/// The result of [`Afc::open_data`].
+#[derive(Debug)]
+pub(crate) enum Opened {
+    /// The message should be delivered.
+    Msg {
+        data: Vec<u8>,
+        afc_id: AfcId,
+        label: Label,
+        seq: Seq,
+        sent_at: Option<SystemTime>,
+        rpc: Option<Rpc>,
+    },
+    /// The message is a fragment of a stream that is not yet
+    /// complete, so there is nothing to deliver.
+    Fragment { afc_id: AfcId, seq: Seq },
+    /// A partially received stream was dropped.
+    StreamDropped { afc_id: AfcId, seq: Seq },
+    /// The message's idempotency token was recently seen on the
+    /// same channel, so it should not be delivered.
+    Duplicate {
+        afc_id: AfcId,
+        token: IdempotencyToken,
+        seq: Seq,
+    },
+    /// The message's checksum did not match its plaintext.
+    ChecksumMismatch { afc_id: AfcId, seq: Seq },
+    /// The message's timestamp is too far in the future.
+    TimestampRejected {
+        afc_id: AfcId,
+        seq: Seq,
+        sent_at: SystemTime,
+    },
+    /// The channel was recently removed, so the message was
+    /// dropped.
+    ///
+    /// If `notify` is true, the peer should be sent a [`Nack`].
+    Removed { afc_id: AfcId, notify: bool },
+}
+
+/// The size in bytes of `magic || len`.
+///
+/// See the wire format description.
+const WIRE_HEADER_SIZE: usize = 4 + 4;
+
+/// See the wire format description.
+const WIRE_MAGIC: &[u8; 4] = b"AFC\0";
+
+/// How long to wait for an incoming stream to show whether it
+/// starts with [`WIRE_MAGIC`].
+///
+/// See [`RouterConfig::foreign_handler`]. The stream holds one of
+/// the [`RouterConfig::max_pending_handshakes`] slots meanwhile,
+/// so it is short: AFC peers greet us as soon as they connect.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The maximum size of a UDP datagram, including the wire
+/// header.
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// The header is exactly `magic || len`.
+const _: () = assert!(WIRE_HEADER_SIZE == WIRE_MAGIC.len() + (u32::BITS / 8) as usize);
+// `len` must fit in a `usize` on every platform we support.
+const _: () = assert!(usize::BITS >= u32::BITS);
+
+/// Encodes `magic || len`.
+///
+/// `len` is always encoded as little-endian.
+fn encode_wire_header(len: u32) -> [u8; WIRE_HEADER_SIZE] {
+    let mut buf = [0u8; WIRE_HEADER_SIZE];
+    let (magic, rest) = buf.split_at_mut(WIRE_MAGIC.len());
+    magic.copy_from_slice(WIRE_MAGIC);
+    rest.copy_from_slice(&len.to_le_bytes());
+    buf
+}
+
+/// Decodes `magic || len`, returning `len`.
+///
+/// It is an error if the magic is invalid or if `len` is larger
+/// than `max`.
+fn decode_wire_header(buf: &[u8; WIRE_HEADER_SIZE], max: u32) -> Result<u32, AfcError> {
+    let [m0, m1, m2, m3, l0, l1, l2, l3] = *buf;
+
+    let magic = [m0, m1, m2, m3];
+    if magic != *WIRE_MAGIC {
+        error!(got = ?magic, expected = ?WIRE_MAGIC, "invalid magic");
+        return Err(AfcError::InvalidMagic(u32::from_le_bytes(magic)));
+    }
+
+    let len = u32::from_le_bytes([l0, l1, l2, l3]);
+    check_msg_size(len.try_into().unwrap_or(usize::MAX), max)?;
+    Ok(len)
+}
+
+/// Decodes a datagram read into `buf`.
+///
+/// See [`decode_msg`].
+fn decode_datagram(buf: &mut Vec<u8>, max: u32) -> Result<Msg, AfcError> {
+    let (hdr, rest) = buf
+        .split_first_chunk::<WIRE_HEADER_SIZE>()
+        .ok_or(AfcError::PayloadTooSmall)?;
+    let len = decode_wire_header(hdr, max)?;
+    if usize::try_from(len).ok() != Some(rest.len()) {
+        return Err(AfcError::PayloadTooSmall);
+    }
+    decode_msg(buf, WIRE_HEADER_SIZE)
+}
+
+/// Checks that a message of `len` bytes is at most `max` bytes.
+fn check_msg_size(len: usize, max: u32) -> Result<(), AfcError> {
+    let max = usize::try_from(max).unwrap_or(usize::MAX);
+    if len > max {
+        error!(got = %len, expected = %max, "msg size too large");
+        return Err(AfcError::MsgTooLarge { got: len, max });
+    }
+    Ok(())
+}
+
+/// Reads from `reader` until `buf` is full or EOF is reached.
+///
+/// Returns the number of bytes read.
+async fn read_full<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<usize, AfcError> {
+    let mut n = 0;
+    while let Some(rest) = buf.get_mut(n..).filter(|rest| !rest.is_empty()) {
+        match reader.read(rest).await.map_err(AfcError::ReadData)? {
+            0 => break,
+            m => n += m,
+        }
+    }
+    Ok(n)
+}
+
+/// Binds a listener to `addr`.
+///
+/// If none of the addresses that `addr` resolves to can be
+/// bound, each port in `fallback_ports` is tried in order with
+/// the same IP addresses.
+async fn bind(addr: impl ToSocketAddrs, fallback_ports: &[u16]) -> Result<TcpListener, AfcError> {
+    let addrs = lookup_host(addr)
+        .await
+        .map_err(AfcError::Bind)?
+        .collect::<Vec<_>>();
+    let candidates = addrs
+        .iter()
+        .copied()
+        .chain(fallback_ports.iter().flat_map(|&port| {
+            addrs
+                .iter()
+                .map(move |addr| SocketAddr::new(addr.ip(), port))
+        }));
+    let mut last_err = None;
+    for addr in candidates {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(err) => {
+                warn!(%addr, %err, "unable to bind AFC router");
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(AfcError::Bind(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "address did not resolve to any addresses",
+        )
+    })))
+}
+
+/// Sends `msg` to `addr` as a single datagram using the wire
+/// format, encoding it into `buf`.
+///
+/// Returns the encoded size of `msg`.
+async fn send_datagram(
+    udp: &Sockets,
+    addr: SocketAddr,
+    buf: &mut FrameBuf,
+    msg: &Msg,
+) -> Result<usize, AfcError> {
+    buf.encode(msg)?;
+    let len = buf.msg_len();
+    send_frame(udp, addr, buf.finish()?).await?;
+    Ok(len)
+}
+
+/// Sends `frame` (`magic || len || msg`) to `addr` as a single
+/// datagram.
+async fn send_frame(udp: &Sockets, addr: SocketAddr, frame: &[u8]) -> Result<(), AfcError> {
+    check_datagram_size(frame)?;
+    udp.send_to(frame, addr).await.map_err(AfcError::Udp)?;
+    Ok(())
+}
+
+/// Like [`send_frame`], but fails with [`AfcError::WouldBlock`]
+/// instead of waiting for the socket to be writable.
+fn try_send_frame(
+    udp: &Sockets,
+    id: AfcId,
+    addr: SocketAddr,
+    frame: &[u8],
+) -> Result<(), AfcError> {
+    check_datagram_size(frame)?;
+    match udp.try_send_to(frame, addr) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(AfcError::WouldBlock(id)),
+        Err(err) => Err(AfcError::Udp(err)),
+    }
+}
+
+/// Checks that `frame` fits in a single datagram.
+fn check_datagram_size(frame: &[u8]) -> Result<(), AfcError> {
+    if frame.len() > MAX_DATAGRAM_SIZE {
+        return Err(AfcError::DatagramTooLarge {
+            got: frame.len(),
+            max: MAX_DATAGRAM_SIZE,
+        });
+    }
+    Ok(())
+}
+
+/// Writes `frame` (`magic || len || msg`) to the TCP stream
+/// used by a channel, opening the stream if needed.
+///
+/// `addr` is the channel's cached address. If the write fails,
+/// the stream is removed so that the next write reconnects.
+async fn write_chan(
+    streams: &mut TcpStreams,
+    net_id: &NetIdentifier,
+    addr: &mut Option<SocketAddr>,
+    frame: &[u8],
+) -> Result<(), AfcError> {
+    let timeouts = streams.timeouts;
+    let stream = match addr {
+        Some(addr) => streams.get_or_open((*addr, net_id.as_ref())).await?,
+        None => {
+            // The peer was updated (see
+            // `update_channel_peer`), so look it up again.
+            // Prefer an existing stream, if any.
+            let found = streams
+                .dns
+                .resolve(net_id.as_ref())
+                .await?
+                .iter()
+                .copied()
+                .find(|addr| streams.contains(addr));
+            let stream = streams.try_get_or_open((found, net_id.as_ref())).await?;
+            let peer = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
+            debug!(%peer, "resolved channel address");
+            *addr = Some(peer);
+            stream
+        }
+    };
+    let peer = stream.peer_addr().ok();
+    let result = match timeouts {
+        // A write that does not complete means that the peer
+        // stopped reading, so treat it like a failed one.
+        Some(t) => timeout(t.response_timeout, write_frame(stream, frame))
+            .await
+            .unwrap_or_else(|_| Err(AfcError::StreamWrite(io::ErrorKind::TimedOut.into()))),
+        None => write_frame(stream, frame).await,
+    };
+    if let (Err(err), Some(peer)) = (&result, peer) {
+        debug!(%peer, "removing broken stream");
+        streams.remove(&peer, DisconnectReason::from_write(err));
+    }
+    result
+}
+
+/// Tells the peer that we're overloaded, then closes `stream`.
+///
+/// This never waits: if the busy frame cannot be written
+/// immediately, the stream is closed without it. If `busy` is
+/// false (e.g., the peer expects a TLS handshake), the stream
+/// is closed without it as well.
+fn shed(stream: Conn, busy: bool) -> Result<(), AfcError> {
+    if !busy {
+        return Ok(());
+    }
+    let mut buf = FrameBuf::default();
+    buf.encode(&Msg::Busy)?;
+    if let Err(err) = stream.try_write(buf.finish()?) {
+        debug!(?err, "unable to write busy frame");
+    }
+    // Dropping `stream` closes it.
+    Ok(())
+}
+
+/// An incoming connection whose handshake has finished.
+enum Accepted {
+    /// An AFC stream.
+    Afc(Stream),
+    /// A stream for [`RouterConfig::foreign_handler`].
+    Foreign(ForeignConn),
+}
+
+/// Tells the plaintext `stream` apart by whether it starts with
+/// [`WIRE_MAGIC`].
+///
+/// Streams that are slow to show how they start are assumed to
+/// be AFC, like without a [`RouterConfig::foreign_handler`].
+async fn sniff(stream: TcpStream) -> Result<Accepted, AfcError> {
+    match timeout(SNIFF_TIMEOUT, starts_with_magic(&stream)).await {
+        Ok(Ok(true)) | Err(_) => Ok(Accepted::Afc(Stream::tcp(stream))),
+        Ok(Ok(false)) => Ok(Accepted::Foreign(ForeignConn::Tcp(stream))),
+        Ok(Err(err)) => Err(AfcError::StreamRead(err)),
+    }
+}
+
+/// Reports whether `stream` starts with [`WIRE_MAGIC`], without
+/// reading from it.
+async fn starts_with_magic(stream: &TcpStream) -> io::Result<bool> {
+    poll_fn(|cx| poll_magic(stream, cx)).await
+}
+
+/// See [`starts_with_magic`].
+fn poll_magic(stream: &TcpStream, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+    let mut buf = [0u8; WIRE_MAGIC.len()];
+    loop {
+        ready!(stream.poll_read_ready(cx))?;
+        // Peeking at part of the magic leaves the stream
+        // readable, so clear its readiness to wait for the rest
+        // instead of peeking again right away. `try_io` does not
+        // clear readiness that arrived while peeking.
+        let peeked = stream.try_io(Interest::READABLE, || {
+            let mut peeked = ReadBuf::new(&mut buf);
+            match stream.poll_peek(cx, &mut peeked) {
+                Poll::Ready(Ok(n))
+                    if n > 0 && n < WIRE_MAGIC.len() && WIRE_MAGIC.starts_with(peeked.filled()) =>
+                {
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
+                Poll::Ready(result) => result,
+                Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        });
+        match peeked {
+            Ok(0) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+            Ok(n) => return Poll::Ready(Ok(buf.get(..n) == Some(WIRE_MAGIC.as_slice()))),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+    }
+}
+
+/// Writes `msg` to `stream` using the wire format, encoding it
+/// into `buf`.
+///
+/// Returns the encoded size of `msg`.
+async fn write_msg(stream: &mut Stream, buf: &mut FrameBuf, msg: &Msg) -> Result<usize, AfcError> {
+    buf.encode(msg)?;
+    let len = buf.msg_len();
+    write_frame(stream, buf.finish()?).await?;
+    Ok(len)
+}
+
+/// Writes `frame` (`magic || len || msg`) to `stream`.
+///
+/// The peer never sees part of `frame`, even if the future is
+/// dropped (see [`Stream::write_frame`]).
+async fn write_frame(stream: &mut Stream, frame: &[u8]) -> Result<(), AfcError> {
+    stream
+        .write_frame(frame)
+        .await
+        .map_err(AfcError::StreamWrite)
+}
+
+/// The router's streams, taken by [`Afc::take_streams`] so that
+/// they can be closed before the rest of the router is torn
+/// down.
+#[derive(Debug)]
+pub(crate) struct ClosingStreams {
+    streams: Vec<(SocketAddr, Stream)>,
+    /// See [`RouterConfig::close_timeout`].
+    timeout: Duration,
+}
+
+impl ClosingStreams {
+    /// Reports whether closing the streams has to write
+    /// anything, i.e., a [`Goodbye`] or the rest of an
+    /// interrupted frame.
+    ///
+    /// Otherwise, dropping the streams closes them just as
+    /// well.
+    pub fn needs_flush(&self) -> bool {
+        self.streams.iter().any(|(_, stream)| stream.has_unsent())
+    }
+
+    /// Finishes interrupted frames, says goodbye, and shuts
+    /// down the streams, giving up on the remaining streams
+    /// after
+    /// [`RouterConfig::close_timeout`].
+    ///
+    /// A stream that is given up on is closed in the middle of
+    /// a frame, which the peer treats as an error instead of
+    /// waiting for the rest of the frame.
+    pub async fn close(mut self) {
+        let closing = poll_fn(|cx| {
+            self.streams
+                .retain_mut(|(addr, stream)| match stream.poll_close(cx) {
+                    Poll::Ready(Ok(())) => false,
+                    Poll::Ready(Err(err)) => {
+                        debug!(%addr, %err, "unable to close stream");
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+            if self.streams.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        if timeout(self.timeout, closing).await.is_err() {
+            warn!(n = self.streams.len(), "timed out closing streams");
+        }
+    }
+}
+
+/// AFC router statistics.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RouterStats {
+    /// The number of incoming streams that were accepted,
+    /// including streams that were later shed.
+    pub accepted: u64,
+    /// The number of incoming streams that were shed because
+    /// the router was overloaded.
+    pub shed: u64,
+    /// The number of busy frames received from overloaded
+    /// peers.
+    pub busy_received: u64,
+    /// The number of messages that were dropped because their
+    /// channel was recently removed.
+    pub removed_channel_drops: u64,
+    /// The number of UDP datagrams that were dropped because
+    /// they were malformed, stale, or for an unknown channel.
+    pub datagrams_dropped: u64,
+    /// The number of half-open streams that were closed.
+    ///
+    /// See [`RouterConfig::stream_timeouts`].
+    pub streams_reaped: u64,
+    /// The number of times the router yielded to other tasks
+    /// because it used up its budget.
+    ///
+    /// See [`RouterConfig::yield_budget`].
+    pub yields: u64,
+    /// The number of streams that peers resumed with a session
+    /// resumption ticket.
+    ///
+    /// See [`RouterConfig::session_resumption`].
+    pub resumptions: u64,
+    /// The number of incoming streams that were not AFC and
+    /// were handed to [`RouterConfig::foreign_handler`].
+    pub foreign: u64,
+    /// The number of currently open streams.
+    pub streams: usize,
+    /// The number of currently open incoming streams whose peer
+    /// has not yet identified itself.
+    pub pending_handshakes: usize,
+    /// The number of messages waiting to be resent, across all
+    /// streams.
+    pub queued: usize,
+    /// The number of streams that are currently congested.
+    ///
+    /// Always zero unless watermarks are set. See
+    /// [`RouterConfig::queue_watermarks`].
+    pub congested_streams: usize,
+}
+
+/// Statistics for the TCP stream with a single peer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct StreamStats {
+    /// The peer's address.
+    pub addr: SocketAddr,
+    /// The peer's device ID, once it has identified itself.
+    ///
+    /// Always `None` if the stream is not open.
+    pub peer: Option<DeviceId>,
+    /// Is the stream open?
+    ///
+    /// Messages can be queued for a stream that was dropped
+    /// until it is reopened.
+    pub open: bool,
+    /// The number of messages waiting to be written to the
+    /// stream.
+    pub queued: usize,
+    /// Whether the stream is congested.
+    ///
+    /// Always `false` unless watermarks are set. See
+    /// [`RouterConfig::queue_watermarks`].
+    pub congested: bool,
+    /// The wire version that the peer greeted us with, once it
+    /// has, even if we do not speak it.
+    ///
+    /// Always `None` if the stream is not open.
+    pub version: Option<Version>,
+    /// The wire version that both peers agreed to use, once they
+    /// have.
+    ///
+    /// Always `None` if the stream is not open or the peers do
+    /// not speak a common version.
+    pub negotiated: Option<Version>,
+}
+
+/// Statistics for the DNS lookups of a single host.
+///
+/// See [`Client::dns_stats`][crate::Client::dns_stats].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct DnsStats {
+    /// The `host:port` pair that was looked up.
+    pub host: String,
+    /// The number of lookups, excluding cache hits.
+    pub lookups: u64,
+    /// The number of lookups that failed.
+    pub failures: u64,
+    /// The number of times that a cached lookup was used.
+    pub cache_hits: u64,
+    /// How long the last lookup took.
+    pub last_latency: Duration,
+    /// When the last lookup finished.
+    pub last_lookup: SystemTime,
+    /// The addresses that the last successful lookup returned,
+    /// in the order that they are dialed.
+    pub last_resolved: Vec<SocketAddr>,
+    /// Why the last lookup failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// What the router resolves a [`NetIdentifier`] to and which
+/// address it would dial.
+///
+/// See [`Client::resolve_debug`][crate::Client::resolve_debug].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ResolveDebug {
+    /// The resolved identifier.
+    pub net_id: NetIdentifier,
+    /// Whether the addresses came from the DNS cache.
+    pub cached: bool,
+    /// The addresses that the identifier resolves to, in the
+    /// order that they are dialed.
+    ///
+    /// Empty if resolution failed.
+    pub addrs: Vec<SocketAddr>,
+    /// The addresses that are skipped because of their
+    /// reputation, and how long until they are tried again.
+    ///
+    /// See [`Client::peer_reputations`][crate::Client::peer_reputations].
+    pub backoff: Vec<(SocketAddr, Duration)>,
+    /// The address that would be dialed first, if any.
+    pub candidate: Option<SocketAddr>,
+    /// The address of an open stream with one of `addrs`, which
+    /// is used instead of dialing.
+    pub stream: Option<SocketAddr>,
+    /// Why resolution failed, if it did.
+    pub error: Option<String>,
+}
+
+/// A change to whether a stream is congested.
+///
+/// See [`QueueWatermarks`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct QueueChange {
+    /// The peer's address.
+    pub addr: SocketAddr,
+    /// The stream's queue depth.
+    pub queued: usize,
+    /// Whether the stream is now congested.
+    pub congested: bool,
+}
+
+/// An upper bound on the size of the encoding of a
+/// [`Msg::Enveloped`] and its [`Envelope`], excluding the ciphertext and plaintext.
+///
+/// It leaves room for every extension.
+const MAX_FRAMING_OVERHEAD: usize = 80;
+
+/// The effective limits of a client.
+///
+/// Use them to size buffers and validate payloads instead of
+/// hardcoding numbers that might change between versions.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Limits {
+    /// The maximum size in bytes of an encoded message. See
+    /// [`RouterConfig::max_msg_size`].
+    pub max_msg_size: u32,
+    /// The size in bytes of the AFC header prepended to each
+    /// ciphertext.
+    pub header_size: usize,
+    /// The number of bytes that sealing adds to the plaintext
+    /// (e.g., the authentication tag), excluding the header.
+    pub seal_overhead: usize,
+    /// The maximum number of channels.
+    pub max_chans: usize,
+    /// The largest amount of data that can always be sent in
+    /// one message over TCP.
+    pub max_data_size: usize,
+    /// The largest amount of data that can always be sent in
+    /// one message over UDP.
+    pub max_datagram_data_size: usize,
+    /// How long the router can keep a channel that the daemon
+    /// removed (e.g., because the peer was removed from the
+    /// team) before reconciling, if bounded.
+    ///
+    /// Sealing or opening data for such a channel fails
+    /// regardless. See [`RouterConfig::reconcile_interval`].
+    pub max_revocation_delay: Option<Duration>,
+}
+
+/// Statistics for a single AFC channel.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChannelStats {
+    /// The number of plaintext bytes sent.
+    pub bytes_sent: u64,
+    /// The number of plaintext bytes received.
+    pub bytes_received: u64,
+    /// The number of messages encrypted and sent.
+    pub msgs_sealed: u64,
+    /// The number of messages received and decrypted,
+    /// including duplicates.
+    pub msgs_opened: u64,
+    /// The sequence number of the last message received, if
+    /// any.
+    pub last_seq: Option<Seq>,
+    /// The number of messages rejected because they were
+    /// replayed or arrived too late for the replay window.
+    ///
+    /// See [`RouterConfig::replay_window`].
+    pub replays_rejected: u64,
+    /// The number of messages that arrived out of order, but
+    /// within the replay window.
+    pub reordered: u64,
+    /// The number of messages that were decrypted but whose
+    /// checksum did not match.
+    ///
+    /// Always zero unless self-check is enabled for the
+    /// channel. See [`RouterConfig::self_check`].
+    pub checksum_mismatches: u64,
+    /// The number of messages that were decrypted but whose
+    /// timestamp was too far in the future.
+    ///
+    /// See [`RouterConfig::max_clock_skew`].
+    pub timestamps_rejected: u64,
+    /// The number of messages that were decrypted but rejected
+    /// by the label's payload validator.
+    ///
+    /// See [`Client::set_payload_validator`][crate::Client::set_payload_validator].
+    pub payloads_rejected: u64,
+    /// When a message was last sent or received, if ever.
+    pub last_activity: Option<SystemTime>,
+    /// The number of messages whose sending was delayed by the
+    /// channel's rate limit.
+    ///
+    /// See [`RateLimit`][crate::RateLimit].
+    pub throttled: u64,
+    /// The number of messages dropped because they exceeded
+    /// the channel's rate limit.
+    pub rate_limited: u64,
+    /// Whether the peer answers keep-alive pings.
+    ///
+    /// Always [`Liveness::Alive`] unless keep-alive is enabled.
+    /// See [`RouterConfig::keepalive`].
+    pub liveness: Liveness,
+    /// The round-trip time of the last answered ping, if any.
+    pub rtt: Option<Duration>,
+    /// The smoothed round-trip time of answered pings, if any.
+    ///
+    /// Each ping moves it an eighth of the way toward the ping's
+    /// round-trip time, like TCP's smoothed RTT, so a single
+    /// slow ping does not swing it.
+    pub srtt: Option<Duration>,
+    /// The number of pings that were not answered in time.
+    pub pings_missed: u64,
+    /// The smoothed one-way latency of received messages, if
+    /// the peer sends timestamps.
+    ///
+    /// Each message's latency is when it was opened less its
+    /// timestamp, so the estimate is only as good as the
+    /// synchronization of both peers' clocks. Messages whose
+    /// timestamps are ahead of our clock count as no latency.
+    /// It is smoothed like [`srtt`][Self::srtt]. See
+    /// [`RouterConfig::send_timestamps`].
+    pub latency: Option<Duration>,
+}
+
+impl ChannelStats {
+    fn sent(&mut self, len: usize) {
+        self.bytes_sent = self
+            .bytes_sent
+            .saturating_add(u64::try_from(len).unwrap_or(u64::MAX));
+        self.msgs_sealed = self.msgs_sealed.saturating_add(1);
+        self.last_activity = Some(SystemTime::now());
+        metrics::msg_sent(len);
+    }
+
+    fn received(&mut self, len: usize, seq: Seq) {
+        self.bytes_received = self
+            .bytes_received
+            .saturating_add(u64::try_from(len).unwrap_or(u64::MAX));
+        self.msgs_opened = self.msgs_opened.saturating_add(1);
+        self.last_seq = Some(seq);
+        self.last_activity = Some(SystemTime::now());
+        metrics::msg_received(len);
+    }
+
+    /// Records the round-trip time of an answered ping.
+    fn ponged(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+        self.srtt = Some(smooth(self.srtt, rtt));
+        metrics::rtt(rtt);
+    }
+
+    /// Records the latency of a message sent at `sent_at`.
+    fn timestamped(&mut self, sent_at: SystemTime, now: SystemTime) {
+        let latency = now.duration_since(sent_at).unwrap_or_default();
+        self.latency = Some(smooth(self.latency, latency));
+        metrics::msg_latency(latency);
+    }
+}
+
+/// Moves the estimate `old` an eighth of the way toward
+/// `sample`.
+///
+/// The first sample is taken as is.
+fn smooth(old: Option<Duration>, sample: Duration) -> Duration {
+    match old {
+        Some(old) => old.saturating_sub(old / 8).saturating_add(sample / 8),
+        None => sample,
+    }
+}
+
+/// Whether a channel's peer is reachable.
+///
+/// See [`KeepAlive`][crate::KeepAlive].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Liveness {
+    /// The peer answered the last ping or recently sent data.
+    #[default]
+    Alive,
+    /// The peer missed at least one ping.
+    Degraded,
+    /// The peer missed
+    /// [`KeepAlive::max_missed`][crate::KeepAlive::max_missed]
+    /// pings in a row.
+    Unreachable,
+}
+
+/// Why a channel expired.
+///
+/// See [`ChannelExpiry`][crate::ChannelExpiry].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ExpiryReason {
+    /// The channel reached its
+    /// [`max_age`][crate::ChannelExpiry::max_age].
+    MaxAge,
+    /// The channel reached its
+    /// [`max_msgs`][crate::ChannelExpiry::max_msgs].
+    MaxMsgs,
+}
+
+/// A summary of an open AFC channel.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ChannelSummary {
+    /// The channel's ID.
+    pub id: AfcId,
+    /// The peer on the other side of the channel.
+    pub peer: NetIdentifier,
+    /// The address that `peer` resolved to, once data or
+    /// a control message has been sent to it.
+    ///
+    /// For UDP channels, this is the peer's datagram address.
+    pub peer_addr: Option<SocketAddr>,
+    /// The channel's label.
+    pub label: Label,
+    /// How data is sent over the channel.
+    pub transport: Transport,
+    /// Does data sent over the channel carry a checksum?
+    pub self_check: bool,
+    /// The number of messages waiting to be resent after
+    /// a transient failure.
+    pub queued: usize,
+    /// The channel's statistics.
+    pub stats: ChannelStats,
+    /// The metadata the application attached to the channel.
+    pub metadata: ChannelMetadata,
+}
+
+/// Application-defined metadata attached to a channel, e.g.,
+/// its purpose or priority.
+///
+/// The router does not interpret it. It lives as long as the
+/// channel and is not persisted across restarts.
+pub type ChannelMetadata = BTreeMap<String, String>;
+
+/// Sends and receives AFC messages.
+pub(crate) struct Afc<S> {
+    /// The underlying AFC client.
+    afc: Client<S>,
+    /// Listens for incoming connections from peers.
+    listener: Listeners,
+    /// Incoming connections whose handshake has not finished.
+    ///
+    /// Each handshake runs on its own task so that a peer that
+    /// stalls it cannot stall the router.
+    accepting: JoinSet<(SocketAddr, Result<Accepted, AfcError>)>,
+    /// Sends and receives data for UDP channels.
+    udp: Sockets,
+    /// Open TCP connections.
+    // TODO(eric): prune unused/idle streams.
+    streams: TcpStreams,
+    /// All open channels.
+    chans: BTreeMap<AfcId, Chan>,
+    /// Recently removed channels.
+    tombstones: BTreeMap<AfcId, Tombstone>,
+    /// The control message rate limit of each peer.
+    ///
+    /// See [`RouterConfig::ctrl_rate_limit`].
+    ctrl_limits: BTreeMap<IpAddr, TokenBucket>,
+    /// Router limits.
+    cfg: RouterConfig,
+    /// The maximum number of channels in shared memory.
+    max_chans: usize,
+    /// Router statistics.
+    stats: RouterStats,
+    /// Reused to encode outgoing frames.
+    frame: FrameBuf,
+    /// Reused to encode the plaintext of outgoing data
+    /// messages.
+    plaintext: Vec<u8>,
+    /// Reused to coalesce the frames of a batch of outgoing
+    /// data messages.
+    batch: Vec<u8>,
+    /// Buffers for incoming messages.
+    bufs: BufPool,
+    /// When the channel table is next due to be reconciled, if
+    /// ever.
+    next_reconcile: Option<Instant>,
+    /// Liveness changes that have not been reported yet.
+    liveness: Vec<(AfcId, Liveness)>,
+    /// Channels that expired and have not been reported yet,
+    /// and whether to delete them.
+    expired: Vec<(AfcId, ExpiryReason, bool)>,
+    /// The streams whose queue depth reached the high
+    /// watermark and has not yet fallen to the low one.
+    congested: BTreeSet<SocketAddr>,
+    /// The channels whose flow control window is due to be
+    /// advertised.
+    windows: BTreeSet<AfcId>,
+    /// How many more messages can be handled before the router
+    /// yields.
+    ///
+    /// See [`RouterConfig::yield_budget`].
+    budget: u32,
+}
+
+impl<S: AfcState> Afc<S> {
+    /// Creates a new `Afc` listening for connections on `addr`.
+    ///
+    /// `device_id` identifies this device to peers and
+    /// `max_chans` is the capacity of the shared memory.
+    ///
+    /// If `addr` cannot be bound (e.g., its port is already in
+    /// use), the router falls back to
+    /// [`RouterConfig::fallback_ports`]. Use
+    /// [`local_addr`][Self::local_addr] to find out which port
+    /// was actually bound.
+    ///
+    /// If `addr` is `None`, the router does not listen at all:
+    /// it can only reach peers over streams that it opens and
+    /// cannot send data over UDP.
+    pub async fn new<A>(
+        afc: Client<S>,
+        addr: Option<A>,
+        device_id: DeviceId,
+        max_chans: usize,
+        cfg: RouterConfig,
+    ) -> Result<Self, AfcError>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut cfg = cfg;
+        if cfg.max_msg_size < RouterConfig::MIN_MAX_MSG_SIZE {
+            warn!(
+                max_msg_size = cfg.max_msg_size,
+                min = RouterConfig::MIN_MAX_MSG_SIZE,
+                "`max_msg_size` is too small, using the minimum"
+            );
+            cfg.max_msg_size = RouterConfig::MIN_MAX_MSG_SIZE;
+        }
+        if cfg.replay_window > RouterConfig::MAX_REPLAY_WINDOW {
+            warn!(
+                replay_window = cfg.replay_window,
+                max = RouterConfig::MAX_REPLAY_WINDOW,
+                "`replay_window` is too large, using the maximum"
+            );
+            cfg.replay_window = RouterConfig::MAX_REPLAY_WINDOW;
+        }
+        for (label, opts) in &mut cfg.label_options {
+            if let Some(window) = &mut opts.replay_window {
+                if *window > RouterConfig::MAX_REPLAY_WINDOW {
+                    warn!(
+                        %label,
+                        replay_window = *window,
+                        max = RouterConfig::MAX_REPLAY_WINDOW,
+                        "label's `replay_window` is too large, using the maximum"
+                    );
+                    *window = RouterConfig::MAX_REPLAY_WINDOW;
+                }
+            }
+        }
+        if cfg.seq_reservation == 0 {
+            warn!("`seq_reservation` is zero, using one");
+            cfg.seq_reservation = 1;
+        }
+        if let Some(wm) = &mut cfg.queue_watermarks {
+            if wm.high == 0 {
+                warn!("`queue_watermarks.high` is zero, using one");
+                wm.high = 1;
+            }
+            if wm.low >= wm.high {
+                warn!(
+                    low = wm.low,
+                    high = wm.high,
+                    "`queue_watermarks.low` is not below `high`, lowering it"
+                );
+                wm.low = wm.high - 1;
+            }
+        }
+        let max_msg_size = usize::try_from(cfg.max_msg_size).unwrap_or(usize::MAX);
+        let (frame, plaintext, bufs) = match cfg.bounded {
+            Some(bounds) => {
+                if cfg.max_reconnect_attempts > 0 {
+                    debug!("resending data is disabled in bounded-memory mode");
+                    cfg.max_reconnect_attempts = 0;
+                }
+                (
+                    FrameBuf::with_capacity(max_msg_size),
+                    Vec::with_capacity(max_msg_size),
+                    // One buffer per queued message, plus one for
+                    // the ciphertext and one for the plaintext of
+                    // the message being opened.
+                    BufPool::bounded(
+                        WIRE_HEADER_SIZE + max_msg_size,
+                        bounds.max_queued_msgs.saturating_add(2),
+                    ),
+                )
+            }
+            None => (
+                FrameBuf::default(),
+                Vec::new(),
+                BufPool::new(WIRE_HEADER_SIZE + max_msg_size),
+            ),
+        };
+        let (listener, udp, net, port) = match addr {
+            Some(addr) => {
+                let (listener, udp, net) = bind_router(addr, &cfg).await?;
+                let local_addr = listener.local_addr().map_err(AfcError::RouterAddr)?;
+                info!(addr = %local_addr, "bound AFC router");
+                (listener, udp, net, Some(local_addr.port()))
+            }
+            None => {
+                info!("AFC router is not listening");
+                let (listener, udp, net) = unbound(&cfg)?;
+                (listener, udp, net, None)
+            }
+        };
+        let reputations = match &cfg.reputation_path {
+            Some(path) => {
+                Reputations::load(path, cfg.reconnect_backoff).map_err(AfcError::Reputation)?
+            }
+            None => Reputations::new(cfg.reconnect_backoff),
+        };
+        let mut chans = BTreeMap::new();
+        if let Some(path) = &cfg.channel_state_path {
+            if let Some(snap) = persist::load(path).map_err(AfcError::ChannelState)? {
+                for saved in snap.chans {
+                    let chan_id = ChannelId::new(saved.node_id, saved.label);
+                    let mut chan = Chan::new(
+                        &cfg,
+                        saved.team_id,
+                        saved.net_id,
+                        chan_id,
+                        None,
+                        saved.udp,
+                        saved.self_check,
+                    );
+                    if let Some(seq) = saved.reserved {
+                        // The reservation might have been used up
+                        // before the restart, so nothing up to it
+                        // can be trusted.
+                        chan.replay = ReplayWindow::after(chan.replay.size(), seq);
+                        chan.reserved = Some(seq);
+                    }
+                    chans.insert(saved.id, chan);
+                }
+                info!(?path, n = chans.len(), "restored AFC channels");
+            }
+        }
+        let next_reconcile = cfg.reconcile_interval.map(|d| Instant::now() + d);
+        Ok(Self {
+            afc,
+            listener,
+            accepting: JoinSet::new(),
+            udp,
+            streams: TcpStreams::new(
+                device_id,
+                net,
+                reputations,
+                DnsCache::new(
+                    cfg.resolver
+                        .clone()
+                        .unwrap_or_else(|| Arc::new(SystemResolver)),
+                    cfg.dns_ttl,
+                    cfg.dns_negative_ttl,
+                    cfg.max_dns_entries,
+                    cfg.addr_preference,
+                ),
+                cfg.stream_timeouts,
+                cfg.handshake_timeout,
+                port,
+                cfg.connect_attempt_delay,
+                cfg.strict,
+                #[cfg(feature = "tls")]
+                cfg.tls.clone(),
+            ),
+            chans,
+            tombstones: BTreeMap::new(),
+            ctrl_limits: BTreeMap::new(),
+            cfg,
+            max_chans,
+            stats: RouterStats::default(),
+            frame,
+            plaintext,
+            batch: Vec::new(),
+            bufs,
+            next_reconcile,
+            liveness: Vec::new(),
+            expired: Vec::new(),
+            congested: BTreeSet::new(),
+            windows: BTreeSet::new(),
+            budget: cfg.yield_budget,
+        })
+    }
+
+    /// Verifies that we speak `version`.
+    fn check_version(&self, version: Version) -> Result<(), AfcError> {
+        if SUPPORTED_VERSIONS.contains(&version) {
+            return Ok(());
+        }
+        let expected = SUPPORTED_VERSIONS
+            .first()
+            .copied()
+            .assume("we should speak a version")?;
+        error!(got = ?version, want = ?expected, "AFC version mismatch");
+        Err(AfcError::VersionMismatch {
+            expected,
+            actual: version,
+        })
+    }
+
+    /// Polls the current AFC state.
+    ///
+    /// This waits until either an existing stream has a message
+    /// or a peer opens a new stream. It is fine to call this
+    /// when there are no open streams or channels: the stream
+    /// set registers interest in new streams, so `poll` resumes
+    /// as soon as one is added.
+    ///
+    /// Incoming streams that exceed the [`RouterConfig`] limits
+    /// are shed without being returned.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future.
+    #[instrument(skip_all)]
+    pub async fn poll(&mut self) -> Result<State, AfcError> {
+        metrics::router(self.chans.len(), self.streams.streams.len());
+
+        let mut waited = false;
+        let result = {
+            let mut fut = pin!(self.poll_inner());
+            poll_fn(|cx| {
+                let poll = fut.as_mut().poll(cx);
+                waited |= poll.is_pending();
+                poll
+            })
+            .await
+        };
+        if waited {
+            // Other tasks could run while we waited.
+            self.budget = self.cfg.yield_budget;
+        }
+        result
+    }
+
+    /// Yields to other tasks if the router has handled
+    /// [`RouterConfig::yield_budget`] messages since it last
+    /// waited or yielded.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future.
+    pub async fn consume_budget(&mut self) {
+        if self.cfg.yield_budget == 0 {
+            return;
+        }
+        self.budget = self.budget.saturating_sub(1);
+        if self.budget == 0 {
+            debug!("yielding to other tasks");
+            self.stats.yields = self.stats.yields.saturating_add(1);
+            self.budget = self.cfg.yield_budget;
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// See [`poll`][Self::poll].
+    async fn poll_inner(&mut self) -> Result<State, AfcError> {
+        #![allow(clippy::disallowed_macros)]
+        loop {
+            let next_retry = self.next_retry();
+            let next_reconcile = self.next_reconcile;
+            let next_probe = self.next_probe();
+            let next_expiry = self.next_expiry();
+            let next_reap = self.streams.next_reap();
+            let next_flush = self.streams.rep.next_flush();
+            tokio::select! {
+                biased;
+
+                // Flow control windows are due to be advertised.
+                // They go first so that peers waiting for them
+                // are not held up by a busy stream.
+                () = std::future::ready(()), if !self.windows.is_empty() => {
+                    return Ok(State::Window);
+                }
+
+                // An existing stream has a message.
+                result = self.streams.next() => {
+                    return match result? {
+                        Next::Readable(addr) => Ok(State::Msg(addr)),
+                        Next::Closed(addr, reason) => Ok(State::Disconnected(addr, reason)),
+                    };
+                }
+
+                // We might have a datagram.
+                result = self.udp.readable() => {
+                    result.map_err(AfcError::Udp)?;
+                    return Ok(State::Datagram);
+                }
+
+                // Queued messages are due to be resent.
+                () = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                    return Ok(State::Retry);
+                }
+
+                // The channel table is due to be reconciled.
+                () = sleep_until(next_reconcile.unwrap_or_else(Instant::now)), if next_reconcile.is_some() => {
+                    self.next_reconcile = self.cfg.reconcile_interval.map(|d| Instant::now() + d);
+                    return Ok(State::Reconcile);
+                }
+
+                // Keep-alive pings are due or have timed out.
+                () = sleep_until(next_probe.unwrap_or_else(Instant::now)), if next_probe.is_some() => {
+                    return Ok(State::KeepAlive);
+                }
+
+                // Channels are due to expire.
+                () = sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                    return Ok(State::Expire);
+                }
+
+                // Idle streams are due to be pinged or reaped.
+                () = sleep_until(next_reap.unwrap_or_else(Instant::now)), if next_reap.is_some() => {
+                    return Ok(State::Reap);
+                }
+
+                // Changed peer reputations are due to be saved.
+                () = sleep_until(next_flush.unwrap_or_else(Instant::now)), if next_flush.is_some() => {
+                    self.streams.rep.flush().await;
+                    continue;
+                }
+
+                // We have an incoming connection.
+                result = self.listener.accept() => {
+                    let (conn, addr) = result.map_err(AfcError::StreamAccept)?;
+                    debug!(%addr, "accepted incoming TCP stream");
+                    self.stats.accepted = self.stats.accepted.saturating_add(1);
+
+                    // Streams are shed before they are told apart,
+                    // so a flood of foreign streams is shed too.
+                    if let Some(reason) = self.overloaded(addr) {
+                        warn!(%addr, reason, "shedding incoming TCP stream");
+                        self.stats.shed = self.stats.shed.saturating_add(1);
+                        // Only AFC peers understand the busy frame.
+                        let busy =
+                            !self.streams.is_tls() && self.cfg.foreign_handler.is_none();
+                        shed(conn, busy)?;
+                        continue;
+                    }
+                    self.start_accept(conn, addr);
+                    continue;
+                }
+
+                // An incoming connection finished its handshake.
+                Some(result) = self.accepting.join_next() => {
+                    let (addr, result) = match result {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn!(%err, "handshake task failed");
+                            continue;
+                        }
+                    };
+                    let stream = match self.streams.handshake_done(addr, result) {
+                        Ok(Accepted::Afc(stream)) => stream,
+                        Ok(Accepted::Foreign(conn)) => {
+                            self.hand_off_foreign(conn, addr);
+                            continue;
+                        }
+                        Err(err) => {
+                            warn!(%addr, %err, "dropping incoming TCP stream");
+                            continue;
+                        }
+                    };
+                    #[cfg(feature = "tls")]
+                    let Some(stream) = self.sniff_alpn(stream, addr) else {
+                        continue;
+                    };
+                    self.streams.insert(stream, false)?;
+                    return Ok(State::Accept(addr));
+                }
+            }
+        }
+    }
+
+    /// Starts the handshake of the connection accepted from
+    /// `addr` on its own task. See [`accepting`][Self::accepting].
+    ///
+    /// If [`RouterConfig::foreign_handler`] is set, plaintext
+    /// streams are first told apart by how they start (see
+    /// [`sniff`]).
+    fn start_accept(&mut self, conn: Conn, addr: SocketAddr) {
+        match conn {
+            Conn::Tcp(stream) if self.cfg.foreign_handler.is_some() && !self.streams.is_tls() => {
+                self.streams.set_keepalive(&stream, addr);
+                self.accepting
+                    .spawn(async move { (addr, sniff(stream).await) });
+            }
+            conn => {
+                let handshake = self.streams.handshake(conn, addr, false);
+                self.accepting
+                    .spawn(async move { (addr, handshake.await.map(Accepted::Afc)) });
+            }
+        }
+    }
+
+    /// Hands `conn` from `addr` to
+    /// [`RouterConfig::foreign_handler`].
+    fn hand_off_foreign(&mut self, conn: ForeignConn, addr: SocketAddr) {
+        let Some(handler) = &self.cfg.foreign_handler else {
+            return;
+        };
+        debug!(%addr, "handing off foreign stream");
+        self.stats.foreign = self.stats.foreign.saturating_add(1);
+        handler.handle(conn, addr);
+    }
+
+    /// Hands the TLS stream from `addr` to
+    /// [`RouterConfig::foreign_handler`] if its peer negotiated
+    /// an ALPN protocol other than [`AFC_ALPN`], returning it
+    /// otherwise.
+    #[cfg(feature = "tls")]
+    fn sniff_alpn(&mut self, stream: Stream, addr: SocketAddr) -> Option<Stream> {
+        if self.cfg.foreign_handler.is_none()
+            || stream.alpn_protocol().map_or(true, |p| p == AFC_ALPN)
+        {
+            return Some(stream);
+        }
+        self.hand_off_foreign(ForeignConn::Tls(stream.into_tls()?), addr);
+        None
+    }
+
+    /// Reports why an incoming stream from `addr` should be
+    /// shed, if at all.
+    ///
+    /// Suspicious peers are held to half of the usual limits.
+    fn overloaded(&self, addr: SocketAddr) -> Option<&'static str> {
+        let (max_pending, max_streams) = if self.streams.rep.is_suspicious(addr.ip()) {
+            (
+                self.cfg.max_pending_handshakes / 2,
+                self.cfg.max_streams / 2,
+            )
+        } else {
+            (self.cfg.max_pending_handshakes, self.cfg.max_streams)
+        };
+        let pending = self
+            .streams
+            .pending_handshakes()
+            .saturating_add(self.accepting.len());
+        if pending >= max_pending {
+            Some("too many pending handshakes")
+        } else if self.streams.streams.len() >= max_streams {
+            Some("too many streams")
+        } else {
+            None
+        }
+    }
+
+    /// Returns the reputation of the peer at `ip`.
+    pub fn reputation(&self, ip: IpAddr) -> Option<PeerReputation> {
+        self.streams.rep.get(ip)
+    }
+
+    /// Returns the reputations of all known peers.
+    pub fn reputations(&self) -> Vec<(IpAddr, PeerReputation)> {
+        self.streams.rep.iter().collect()
+    }
+
+    /// Forgets the reputation of the peer at `ip`, or of all
+    /// peers if `ip` is `None`.
+    pub fn reset_reputation(&mut self, ip: Option<IpAddr>) {
+        match ip {
+            Some(ip) => self.streams.rep.reset(ip),
+            None => self.streams.rep.reset_all(),
+        }
+    }
+
+    /// Forgets cached DNS lookups.
+    pub fn flush_dns_cache(&mut self) {
+        self.streams.dns.flush();
+    }
+
+    /// Returns the statistics of each host that was looked up.
+    pub fn dns_stats(&self) -> Vec<DnsStats> {
+        self.streams.dns.stats().cloned().collect()
+    }
+
+    /// Resolves `peer` like opening a stream with it would,
+    /// without dialing it.
+    ///
+    /// A lookup that is not cached is cached afterwards.
+    #[instrument(skip(self))]
+    pub async fn resolve_debug(&mut self, peer: &NetIdentifier) -> ResolveDebug {
+        let host = peer.0.as_str();
+        let cached = self.streams.dns.is_cached(host);
+        let (addrs, error) = match self.streams.dns.resolve(host).await {
+            Ok(addrs) => (addrs.to_vec(), None),
+            Err(err) => (Vec::new(), Some(err.to_string())),
+        };
+        let mut backoff = Vec::new();
+        let mut candidate = None;
+        for &addr in &addrs {
+            match self.streams.rep.retry_in(addr.ip()) {
+                Some(retry_in) => backoff.push((addr, retry_in)),
+                None => {
+                    candidate.get_or_insert(addr);
+                }
+            }
+        }
+        let stream = addrs
+            .iter()
+            .map(|&addr| self.streams.survivor(addr))
+            .find(|addr| self.streams.streams.contains_key(addr));
+        ResolveDebug {
+            net_id: peer.clone(),
+            cached,
+            addrs,
+            backoff,
+            candidate,
+            stream,
+            error,
+        }
+    }
+
+    /// Handles a busy frame received from the stream at `addr`.
+    ///
+    /// The peer has already closed the stream, so we remove it.
+    /// Channels that use the stream reconnect the next time
+    /// they send data.
+    #[instrument(skip_all, fields(%addr))]
+    pub fn handle_busy(&mut self, addr: SocketAddr) {
+        warn!("peer is overloaded");
+        self.stats.busy_received = self.stats.busy_received.saturating_add(1);
+        self.streams.remove(&addr, DisconnectReason::Busy);
+    }
+
+    /// Returns the router's statistics.
+    pub fn stats(&self) -> RouterStats {
+        RouterStats {
+            streams: self.streams.streams.len(),
+            pending_handshakes: self.streams.pending_handshakes(),
+            queued: self.chans.values().map(|chan| chan.queued()).sum(),
+            congested_streams: self.congested.len(),
+            ..self.stats
+        }
+    }
+
+    /// Takes the router's streams so that they can be closed
+    /// gracefully, e.g., when the client is dropped.
+    ///
+    /// A [`Goodbye`] is queued on each stream that we still
+    /// write to, unless its peer predates it (see
+    /// [`PeerStream::extended`]).
+    pub fn take_streams(&mut self) -> ClosingStreams {
+        let goodbye = goodbye_frame(GoodbyeReason::Shutdown)
+            .inspect_err(|err| warn!(%err, "unable to encode goodbye"))
+            .ok();
+        let streams = mem::take(&mut self.streams.streams)
+            .into_iter()
+            .map(|(addr, mut ps)| {
+                if let Some(frame) = goodbye.as_deref().filter(|_| !ps.draining && ps.extended) {
+                    ps.stream.queue_frame(frame);
+                }
+                (addr, ps.stream)
+            })
+            .collect();
+        ClosingStreams {
+            streams,
+            timeout: self.cfg.close_timeout,
+        }
+    }
+
+    /// Returns the statistics of each open stream and of each
+    /// stream that messages are waiting to be written to.
+    pub fn stream_stats(&self) -> Vec<StreamStats> {
+        let mut depths = self.queue_depths();
+        let mut stats = self
+            .streams
+            .streams
+            .iter()
+            .map(|(addr, s)| StreamStats {
+                addr: *addr,
+                peer: s.peer,
+                open: true,
+                queued: depths.remove(addr).unwrap_or(0),
+                congested: self.congested.contains(addr),
+                version: s.version,
+                negotiated: s.negotiated,
+            })
+            .collect::<Vec<_>>();
+        stats.extend(depths.into_iter().map(|(addr, queued)| StreamStats {
+            addr,
+            peer: None,
+            open: false,
+            queued,
+            congested: self.congested.contains(&addr),
+            version: None,
+            negotiated: None,
+        }));
+        stats
+    }
+
+    /// Returns the number of messages waiting to be written to
+    /// each stream that has any.
+    ///
+    /// Messages queued by channels whose peer has not been
+    /// resolved yet are not attributed to any stream.
+    fn queue_depths(&self) -> BTreeMap<SocketAddr, usize> {
+        let mut depths = BTreeMap::new();
+        for chan in self.chans.values() {
+            let n = chan.queued();
+            let Some(addr) = chan.addr.filter(|_| n > 0) else {
+                continue;
+            };
+            let depth: &mut usize = depths.entry(addr).or_default();
+            *depth = depth.saturating_add(n);
+        }
+        depths
+    }
+
+    /// Returns the streams that became congested or drained
+    /// since the last call.
+    ///
+    /// Always empty unless watermarks are set. See
+    /// [`RouterConfig::queue_watermarks`].
+    pub fn take_queue_changes(&mut self) -> Vec<QueueChange> {
+        let Some(QueueWatermarks { high, low }) = self.cfg.queue_watermarks else {
+            return Vec::new();
+        };
+        let depths = self.queue_depths();
+        let mut changes = Vec::new();
+        self.congested.retain(|addr| {
+            let queued = depths.get(addr).copied().unwrap_or(0);
+            if queued > low {
+                return true;
+            }
+            info!(%addr, queued, "stream drained");
+            changes.push(QueueChange {
+                addr: *addr,
+                queued,
+                congested: false,
+            });
+            false
+        });
+        for (addr, queued) in depths {
+            if queued >= high && self.congested.insert(addr) {
+                warn!(%addr, queued, "stream congested");
+                changes.push(QueueChange {
+                    addr,
+                    queued,
+                    congested: true,
+                });
+            }
+        }
+        changes
+    }
+
+    /// Sends a control message to the peer at `net_id`.
+    ///
+    /// If the peer `peer` already has a verified stream with us
+    /// (see [`verify_peer`][Self::verify_peer]), that stream is
+    /// used instead of resolving and dialing `net_id`.
+    // NB: Eliding `net_id` and `team_id` since
+    // `create_bidi_channel` (in client.rs) also adds those.
+    #[instrument(skip_all, fields(
+        afc_id = %ShortId::new("afc", afc_id),
+        %chan_id,
+        %trace_id,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_ctrl(
+        &mut self,
+        net_id: NetIdentifier,
+        peer: DeviceId,
+        cmd: AfcCtrl,
+        team_id: TeamId,
+        afc_id: AfcId,
+        chan_id: ChannelId,
+        trace_id: TraceId,
+        transport: Transport,
+    ) -> Result<(), AfcError> {
+        debug!(?transport, "sending control message");
+
+        let udp_port = match transport {
+            Transport::Tcp => None,
+            Transport::Udp => Some(self.local_addr()?.port()),
+        };
+        // The peer's UDP socket is bound to the port that it
+        // advertises with `net_id`, which is not the port of
+        // the stream if the peer opened it.
+        let peer_udp_port = match transport {
+            Transport::Tcp => None,
+            Transport::Udp => self
+                .streams
+                .dns
+                .resolve(net_id.as_ref())
+                .await?
+                .first()
+                .map(SocketAddr::port),
+        };
+
+        let stream = if let Some(addr) = self.streams.find_verified(peer) {
+            // Prefer a stream that the peer already proved it
+            // owns, even if we would dial a different address
+            // (e.g., the peer is behind a NAT).
+            debug!(%addr, "reusing verified stream");
+            self.streams
+                .get_mut(&addr)
+                .assume("verified stream should exist")?
+        } else {
+            // Try to find an open stream with this peer.
+            let addr = self
+                .streams
+                .dns
+                .resolve(net_id.as_ref())
+                .await?
+                .iter()
+                .copied()
+                .find(|addr| {
+                    debug!(%addr, "resolved potential address");
+                    self.streams.contains(addr)
+                });
+            self.streams
+                .try_get_or_open((addr, net_id.as_ref()))
+                .await?
+        };
+        let addr = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
+        debug!(%addr, "connected to peer");
+
+        let self_check = self.cfg.self_check;
+        let msg = Msg::Ctrl(Ctrl {
+            version: Version::V1,
+            team_id,
+            cmd,
+            udp_port,
+            trace_id: Some(trace_id),
+            self_check,
+        });
+        let len = write_msg(stream, &mut self.frame, &msg).await?;
+        debug!(len, "sent control message");
+
+        // TODO(eric): This throws away `stream` if we already
+        // have a stream with this address.
+        //
+        // Like the peer, use the advertised port with the IP
+        // address of the stream.
+        let udp = peer_udp_port.map(|port| SocketAddr::new(addr.ip(), port));
+        self.add_channel(afc_id, net_id, team_id, chan_id, addr, udp, self_check)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Encrypts `plaintext` and sends it over the AFC channel.
+    ///
+    /// `ext` (an idempotency token, request, or response) and
+    /// `fragment`, if any, are encrypted and authenticated along
+    /// with `plaintext`.
+    // NB: Eliding `id` since send_data` (in client.rs) also adds
+    // it.
+    #[instrument(skip_all)]
+    pub async fn send_data(
+        &mut self,
+        id: AfcId,
+        plaintext: &[u8],
+        ext: Option<Ext>,
+        fragment: Option<Fragment>,
+        priority: Option<Priority>,
+    ) -> Result<(), AfcError> {
+        debug!(
+            pt_len = plaintext.len(),
+            ?ext,
+            ?fragment,
+            ?priority,
+            "sending data"
+        );
+
+        let pt_len = plaintext.len();
+        if self.cfg.bounded.is_some() {
+            // Check the size up front so that encoding cannot
+            // outgrow the preallocated buffers.
+            let max = self.limits().max_data_size;
+            if pt_len > max {
+                return Err(AfcError::MsgTooLarge { got: pt_len, max });
+            }
+        }
+
+        // Preserve ordering: messages fed earlier are sent first.
+        self.flush(id).await?;
+
+        self.throttle(id, 1).await?;
+        let msg_len = self.seal_frame(id, plaintext, ext, fragment)?;
+        let frame = self.frame.finish()?;
+
+        let Chan {
+            net_id,
+            addr,
+            udp,
+            replay,
+            stats,
+            retry,
+            priority: default_priority,
+            ..
+        } = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        let priority = priority.unwrap_or(*default_priority);
+        let window = replay.size();
+
+        if let Some(udp) = udp {
+            send_frame(&self.udp, *udp, frame).await?;
+            debug!(data_len = msg_len, %udp, "sent datagram");
+            stats.sent(pt_len);
+            return Ok(());
+        }
+
+        // Preserve ordering: if earlier messages are waiting to
+        // be resent, this message must wait too, though
+        // a high-priority message may wait ahead of them.
+        if let Some(retry) = retry {
+            retry.push(id, frame.to_vec(), pt_len, priority, window, &self.cfg)?;
+            debug!(
+                queued = retry.queue.len(),
+                "queued msg behind pending retries"
+            );
+            return Ok(());
+        }
+
+        match write_chan(&mut self.streams, net_id, addr, frame).await {
+            Ok(()) => {
+                debug!(data_len = msg_len, "wrote msg to stream");
+                stats.sent(pt_len);
+            }
+            Err(err) if err.is_transient() && self.cfg.max_reconnect_attempts > 0 => {
+                let mut r = Retry::default();
+                r.push(id, frame.to_vec(), pt_len, priority, window, &self.cfg)?;
+                let retry_in = r.schedule(&self.cfg);
+                warn!(%err, ?retry_in, "unable to send data, will retry");
+                *retry = Some(r);
+            }
+            Err(err) => return Err(err),
+        }
+
+        Ok(())
+    }
+
+    /// Sends `plaintext` over every channel with `label`.
+    ///
+    /// The message is sealed for each channel. The messages for
+    /// channels whose streams are open are queued on the
+    /// streams, which are then written concurrently. The rest
+    /// are sent one at a time by [`send_data`][Self::send_data].
+    ///
+    /// Returns the result for each channel.
+    #[instrument(skip_all, fields(%label))]
+    pub async fn send_data_by_label(
+        &mut self,
+        label: Label,
+        plaintext: &[u8],
+    ) -> BTreeMap<AfcId, Result<(), AfcError>> {
+        let ids = self
+            .chans
+            .iter()
+            .filter(|(_, chan)| chan.chan_id.label() == label)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        debug!(n = ids.len(), "sending data by label");
+
+        let mut results = BTreeMap::new();
+        let mut slow = Vec::new();
+        // The channels whose frames were queued on each stream
+        // and, if they might have to be resent, the frames.
+        let mut queued = BTreeMap::<SocketAddr, Vec<(AfcId, Option<Vec<u8>>)>>::new();
+        for id in ids {
+            match self.queue_data(id, plaintext).await {
+                Ok(Some((addr, frame))) => queued.entry(addr).or_default().push((id, frame)),
+                Ok(None) => slow.push(id),
+                Err(err) => {
+                    results.insert(id, Err(err));
+                }
+            }
+        }
+
+        let addrs = queued.keys().copied().collect();
+        let failed = self.streams.write_queued(&addrs).await;
+        for (addr, frames) in queued {
+            let err = failed.get(&addr).copied();
+            for (id, frame) in frames {
+                let result = self.finish_queued(id, frame, plaintext.len(), err);
+                results.insert(id, result);
+            }
+        }
+
+        for id in slow {
+            let result = self.send_data(id, plaintext, None, None, None).await;
+            results.insert(id, result);
+        }
+        results
+    }
+
+    /// Seals `plaintext` for channel `id` and queues it on the
+    /// channel's stream.
+    ///
+    /// Returns the stream's address and, if it might have to be
+    /// resent, a copy of the frame. Returns `None` if the data
+    /// has to be sent by [`send_data`][Self::send_data] instead
+    /// because the channel uses UDP, its stream is not open, or
+    /// earlier messages are waiting to be resent or flushed.
+    async fn queue_data(
+        &mut self,
+        id: AfcId,
+        plaintext: &[u8],
+    ) -> Result<Option<(SocketAddr, Option<Vec<u8>>)>, AfcError> {
+        let chan = self
+            .chans
+            .get(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        let addr = match (chan.udp, &chan.retry, chan.addr) {
+            (None, None, Some(addr)) if self.streams.contains(&addr) && chan.fed.is_empty() => addr,
+            _ => return Ok(None),
+        };
+        if self.cfg.bounded.is_some() {
+            let max = self.limits().max_data_size;
+            if plaintext.len() > max {
+                return Err(AfcError::MsgTooLarge {
+                    got: plaintext.len(),
+                    max,
+                });
+            }
+        }
+
+        self.throttle(id, 1).await?;
+        self.seal_frame(id, plaintext, None, None)?;
+        let frame = self.frame.finish()?;
+        self.streams
+            .get_mut(&addr)
+            .assume("stream should still be open")?
+            .queue_frame(frame);
+        let copy = (self.cfg.max_reconnect_attempts > 0).then(|| frame.to_vec());
+        Ok(Some((addr, copy)))
+    }
+
+    /// Records the outcome of writing a frame queued by
+    /// [`queue_data`][Self::queue_data] for channel `id`.
+    ///
+    /// If the write failed with `err`, the frame is queued to be
+    /// resent if it was kept.
+    fn finish_queued(
+        &mut self,
+        id: AfcId,
+        frame: Option<Vec<u8>>,
+        pt_len: usize,
+        err: Option<io::ErrorKind>,
+    ) -> Result<(), AfcError> {
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        let Some(kind) = err else {
+            chan.stats.sent(pt_len);
+            return Ok(());
+        };
+        let err = AfcError::StreamWrite(kind.into());
+        let Some(frame) = frame else {
+            return Err(err);
+        };
+        let mut r = Retry::default();
+        r.push(
+            id,
+            frame,
+            pt_len,
+            chan.priority,
+            chan.replay.size(),
+            &self.cfg,
+        )?;
+        let retry_in = r.schedule(&self.cfg);
+        warn!(afc_id = %ShortId::new("afc", id), %err, ?retry_in, "unable to send data, will retry");
+        chan.retry = Some(r);
+        Ok(())
+    }
+
+    /// Like [`send_data`][Self::send_data], but fails with
+    /// [`AfcError::WouldBlock`] instead of waiting.
+    ///
+    /// It does not wait for the channel's rate limit, data
+    /// waiting to be resent, a stream to be opened, or the
+    /// socket to be writable.
+    #[instrument(skip_all)]
+    pub fn try_send_data(&mut self, id: AfcId, plaintext: &[u8]) -> Result<(), AfcError> {
+        debug!(pt_len = plaintext.len(), "trying to send data");
+
+        let pt_len = plaintext.len();
+        if self.cfg.bounded.is_some() {
+            let max = self.limits().max_data_size;
+            if pt_len > max {
+                return Err(AfcError::MsgTooLarge { got: pt_len, max });
+            }
+        }
+
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        // Data waiting to be resent or flushed has to be sent
+        // first.
+        if chan.retry.is_some() || !chan.fed.is_empty() {
+            return Err(AfcError::WouldBlock(id));
+        }
+        if let Some(limiter) = &mut chan.limiter {
+            if !limiter.try_send() {
+                return Err(AfcError::WouldBlock(id));
+            }
+        }
+        let udp = chan.udp;
+        let addr = chan.addr.map(|addr| self.streams.survivor(addr));
+        if udp.is_none() && !addr.is_some_and(|addr| self.streams.contains(&addr)) {
+            // Opening a stream would have to wait.
+            return Err(AfcError::WouldBlock(id));
+        }
+
+        let msg_len = self.seal_frame(id, plaintext, None, None)?;
+        let frame = self.frame.finish()?;
+        if let Some(udp) = udp {
+            try_send_frame(&self.udp, id, udp, frame)?;
+            debug!(data_len = msg_len, %udp, "sent datagram");
+        } else {
+            let addr = addr.assume("stream should exist")?;
+            let stream = self.streams.get_mut(&addr).assume("stream should exist")?;
+            match stream.try_write_frame(frame) {
+                Ok(()) => debug!(data_len = msg_len, "wrote msg to stream"),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(AfcError::WouldBlock(id));
+                }
+                Err(err) => {
+                    debug!(%addr, "removing broken stream");
+                    self.streams
+                        .remove(&addr, DisconnectReason::from_io(err.kind()));
+                    return Err(AfcError::StreamWrite(err));
+                }
+            }
+        }
+        if let Some(chan) = self.chans.get_mut(&id) {
+            chan.stats.sent(pt_len);
+        }
+        Ok(())
+    }
+
+    /// Encrypts each of `payloads` and sends them over the AFC
+    /// channel in order.
+    ///
+    /// Over TCP, the messages are written to the stream with
+    /// a single write and flushed once. Over UDP, each message
+    /// is still sent as its own datagram.
+    ///
+    /// Every payload is checked against
+    /// [`Limits::max_data_size`] before any of them are sent.
+    #[instrument(skip_all, fields(n = payloads.len()))]
+    pub async fn send_data_batch(&mut self, id: AfcId, payloads: &[&[u8]]) -> Result<(), AfcError> {
+        debug!("sending batch");
+
+        if !self.chans.contains_key(&id) {
+            return Err(chan_not_found(&self.tombstones, id));
+        }
+        let max = self.limits().max_data_size;
+        if let Some(got) = payloads.iter().map(|p| p.len()).find(|&len| len > max) {
+            return Err(AfcError::MsgTooLarge { got, max });
+        }
+        if payloads.is_empty() {
+            return Ok(());
+        }
+
+        // Preserve ordering: messages fed earlier are sent first.
+        self.flush(id).await?;
+        self.write_batch(id, payloads).await
+    }
+
+    /// Queues `plaintext` to be sent over the AFC channel by the
+    /// next [`flush`][Self::flush].
+    ///
+    /// The payload is checked against
+    /// [`Limits::max_data_size`] right away.
+    pub fn feed_data(&mut self, id: AfcId, plaintext: &[u8]) -> Result<(), AfcError> {
+        let max = self.limits().max_data_size;
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        if plaintext.len() > max {
+            return Err(AfcError::MsgTooLarge {
+                got: plaintext.len(),
+                max,
+            });
+        }
+        chan.fed.push(plaintext.to_vec());
+        debug!(fed = chan.fed.len(), "fed data");
+        Ok(())
+    }
+
+    /// Sends the messages fed to the AFC channel, like
+    /// [`send_data_batch`][Self::send_data_batch].
+    ///
+    /// The messages are taken from the channel before they are
+    /// sent, so they are dropped if sending them fails.
+    #[instrument(skip_all)]
+    pub async fn flush(&mut self, id: AfcId) -> Result<(), AfcError> {
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        if chan.fed.is_empty() {
+            return Ok(());
+        }
+        let fed = mem::take(&mut chan.fed);
+        debug!(n = fed.len(), "flushing fed data");
+        let payloads = fed.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        self.write_batch(id, &payloads).await
+    }
+
+    /// Encrypts each of `payloads`, which are not empty and have
+    /// already been checked against [`Limits::max_data_size`],
+    /// and sends them over the AFC channel in order.
+    async fn write_batch(&mut self, id: AfcId, payloads: &[&[u8]]) -> Result<(), AfcError> {
+        self.throttle(id, payloads.len()).await?;
+
+        // Where each frame is in `batch` and the size of its
+        // plaintext.
+        let mut frames: Vec<(Range<usize>, usize)> = Vec::with_capacity(payloads.len());
+        self.batch.clear();
+        for payload in payloads {
+            self.seal_frame(id, payload, None, None)?;
+            let frame = self.frame.finish()?;
+            let Chan { udp, stats, .. } = self
+                .chans
+                .get_mut(&id)
+                .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+            if let Some(udp) = udp {
+                send_frame(&self.udp, *udp, frame).await?;
+                stats.sent(payload.len());
+                continue;
+            }
+            let start = self.batch.len();
+            self.batch.extend_from_slice(frame);
+            frames.push((start..self.batch.len(), payload.len()));
+        }
+        if frames.is_empty() {
+            debug!("sent batch as datagrams");
+            return Ok(());
+        }
+
+        let Chan {
+            net_id,
+            addr,
+            replay,
+            stats,
+            retry,
+            priority,
+            ..
+        } = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        let (priority, window) = (*priority, replay.size());
+
+        // Preserve ordering: if earlier messages are waiting to
+        // be resent, these messages must wait too.
+        if let Some(retry) = retry {
+            for (range, pt_len) in frames {
+                let frame = self.batch.get(range).assume("frame is in `batch`")?;
+                retry.push(id, frame.to_vec(), pt_len, priority, window, &self.cfg)?;
+            }
+            debug!(
+                queued = retry.queue.len(),
+                "queued batch behind pending retries"
+            );
+            return Ok(());
+        }
+
+        match write_chan(&mut self.streams, net_id, addr, &self.batch).await {
+            Ok(()) => {
+                debug!(len = self.batch.len(), "wrote batch to stream");
+                for (_, pt_len) in frames {
+                    stats.sent(pt_len);
+                }
+            }
+            Err(err) if err.is_transient() && self.cfg.max_reconnect_attempts > 0 => {
+                let mut r = Retry::default();
+                for (range, pt_len) in frames {
+                    let frame = self.batch.get(range).assume("frame is in `batch`")?;
+                    r.push(id, frame.to_vec(), pt_len, priority, window, &self.cfg)?;
+                }
+                let retry_in = r.schedule(&self.cfg);
+                warn!(%err, ?retry_in, "unable to send batch, will retry");
+                *retry = Some(r);
+            }
+            Err(err) => return Err(err),
+        }
+
+        Ok(())
+    }
+
+    /// Takes `n` tokens from the channel's rate limiter, if
+    /// any, waiting until the last of them is available.
+    async fn throttle(&mut self, id: AfcId, n: usize) -> Result<(), AfcError> {
+        let Some(chan) = self.chans.get_mut(&id) else {
+            return Ok(());
+        };
+        let Some(limiter) = &mut chan.limiter else {
+            return Ok(());
+        };
+        let mut wait = Duration::ZERO;
+        for _ in 0..n {
+            match limiter.send() {
+                Some(w) => wait = wait.max(w),
+                None => {
+                    warn!("send rate limit exceeded");
+                    chan.stats.rate_limited = chan.stats.rate_limited.saturating_add(1);
+                    return Err(AfcError::RateLimited(id));
+                }
+            }
+        }
+        if !wait.is_zero() {
+            debug!(?wait, "throttling send");
+            chan.stats.throttled = chan.stats.throttled.saturating_add(1);
+            sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Encrypts `plaintext` into a data message for channel
+    /// `id`, leaving the finished frame in `self.frame`.
+    ///
+    /// `ext` is one of [`Ext::IdempotencyToken`],
+    /// [`Ext::Request`], or [`Ext::Response`], which are the
+    /// same size, so [`MAX_FRAMING_OVERHEAD`] holds.
+    ///
+    /// Returns the encoded size of the message.
+    fn seal_frame(
+        &mut self,
+        id: AfcId,
+        plaintext: &[u8],
+        ext: Option<Ext>,
+        fragment: Option<Fragment>,
+    ) -> Result<usize, AfcError> {
+        self.check_expiry(id)?;
+        let Chan {
+            chan_id,
+            addr,
+            self_check,
+            flow,
+            ..
+        } = self
+            .chans
+            .get(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        debug!(%chan_id, addr = %FmtOr(*addr, "unresolved"), "found channel");
+        if !flow.can_send() {
+            debug!(%chan_id, "flow control window exhausted");
+            return Err(AfcError::WindowExhausted(id));
+        }
+
+        let cksum = self_check.then(|| Ext::Checksum(checksum(plaintext)));
+        let sent_at = self
+            .cfg
+            .send_timestamps
+            .then(|| Ext::Timestamp(to_unix_micros(SystemTime::now())));
+        let mut exts = [Ext::Checksum(0); 4];
+        let mut n = 0;
+        for ext in [ext, cksum, fragment.map(Ext::Fragment), sent_at]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(slot) = exts.get_mut(n) {
+                *slot = ext;
+                n += 1;
+            }
+        }
+        let exts = exts.get(..n).assume("`n` <= `exts.len()`")?;
+        let enveloped = !exts.is_empty();
+
+        // Both the envelope and the frame are encoded into
+        // buffers that are reused across messages, so sending
+        // does not allocate once they are large enough.
+        self.plaintext.clear();
+        if enveloped {
+            self.plaintext = postcard::to_extend(
+                &Envelope {
+                    exts,
+                    data: plaintext,
+                },
+                mem::take(&mut self.plaintext),
+            )
+            .map_err(AfcError::Serde)?;
+        } else {
+            self.plaintext.extend_from_slice(plaintext);
+        }
+
+        let seq = {
+            // The ciphertext is
+            //   header || ciphertext
+            // and is sealed directly into the frame.
+            let len = Header::PACKED_SIZE + self.plaintext.len() + Client::<S>::OVERHEAD;
+            let (header, ciphertext) = self
+                .frame
+                .data(id, len, enveloped)?
+                .split_first_chunk_mut()
+                .assume("`buf.len()` >= `Header::PACKED_SIZE`")?;
+            debug!(%chan_id, "sealing message");
+            let result = metrics::timed(metrics::SEAL_LATENCY, || {
+                self.afc.seal(*chan_id, ciphertext, &self.plaintext)
+            });
+            // Don't leave the plaintext lying around.
+            self.plaintext.fill(0);
+            let hdr = result.map_err(AfcError::Encryption)?;
+            debug!(%chan_id, "sealed message");
+            hdr.encode(header)?;
+            hdr.seq
+        };
+        if let Some(chan) = self.chans.get_mut(&id) {
+            chan.flow.sent(seq.to_u64());
+        }
+        self.count_use(id);
+        let msg_len = self.frame.msg_len();
+        debug!(len = msg_len, "created msg");
+        check_msg_size(msg_len, self.cfg.max_msg_size)?;
+        Ok(msg_len)
+    }
+
+    /// Reads `reader` until EOF and sends its data over the AFC
+    /// channel as a stream of fragments.
+    ///
+    /// `progress` is called with the total number of bytes sent
+    /// after each fragment is sent.
+    ///
+    /// Returns the total number of bytes sent.
+    #[instrument(skip_all)]
+    pub async fn send_stream<R, F>(
+        &mut self,
+        id: AfcId,
+        mut reader: R,
+        mut progress: F,
+    ) -> Result<u64, AfcError>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64),
+    {
+        let chan = self
+            .chans
+            .get(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        let limits = self.limits();
+        let size = if chan.udp.is_some() {
+            limits.max_datagram_data_size
+        } else {
+            limits.max_data_size
+        }
+        .min(MAX_FRAGMENT_SIZE);
+        debug!(size, "sending stream");
+
+        // Read one fragment ahead so that we know which fragment
+        // is the last one.
+        let mut cur = vec![0u8; size];
+        let mut next = vec![0u8; size];
+        let mut n = read_full(&mut reader, &mut cur).await?;
+        let mut index = 0u32;
+        let mut sent = 0u64;
+        loop {
+            let m = if n == size {
+                read_full(&mut reader, &mut next).await?
+            } else {
+                0
+            };
+            let last = m == 0;
+            let data = cur.get(..n).assume("`n` <= `cur.len()`")?;
+            let fragment = Some(Fragment { index, last });
+            self.send_data(id, data, None, fragment, None).await?;
+            sent = sent.saturating_add(u64::try_from(n).unwrap_or(u64::MAX));
+            progress(sent);
+            if last {
+                debug!(sent, fragments = u64::from(index) + 1, "sent stream");
+                return Ok(sent);
+            }
+            index = index
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("stream has too many fragments"))?;
+            mem::swap(&mut cur, &mut next);
+            n = m;
+        }
+    }
+
+    /// Handles a [`Hello`] received from the stream at `addr`.
+    ///
+    /// The peer greets us with the newest version that it
+    /// speaks. If we speak it too, it becomes the stream's
+    /// negotiated version, and we answer with our own [`Hello`]
+    /// in it, greeting the peer again if we greeted it with
+    /// another one.
+    ///
+    /// The peer's device ID is only a hint until the daemon
+    /// verifies it (see [`verify_peer`][Self::verify_peer]), so
+    /// it never moves channels to the stream.
+    #[instrument(skip_all, fields(%addr, peer = %hello.device_id))]
+    pub async fn handle_hello(&mut self, addr: SocketAddr, hello: Hello) -> Result<(), AfcError> {
+        debug!("handling hello");
+
+        let new = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        new.version = Some(hello.version);
+        if let Err(err) = self.check_version(hello.version) {
+            return self.version_mismatch(addr, err).await;
+        }
+
+        let local = self.streams.device_id;
+        let port = self.streams.port;
+        let peer = hello.device_id;
+        let new = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .assume("stream should exist")?;
+        // The peer downgraded in time.
+        new.grace = None;
+        new.peer = Some(peer);
+        new.port = hello.port;
+        new.negotiated = Some(hello.version);
+        if new.greeting != hello.version {
+            debug!(version = ?hello.version, "downgrading to the peer's version");
+            new.greeting = hello.version;
+            new.greeted = false;
+        }
+        new.greet(local, port).await?;
+        self.challenge(addr, peer).await;
+        Ok(())
+    }
+
+    /// Sends `peer` a [`Challenge`] over the stream at `addr`
+    /// if we issued it a session resumption ticket and the peer
+    /// opened the stream.
+    async fn challenge(&mut self, addr: SocketAddr, peer: DeviceId) {
+        if !self.streams.tickets.is_issued_to(peer, Instant::now()) {
+            return;
+        }
+        let Some(stream) = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .filter(|s| !s.outbound && !s.draining && s.challenge.is_none())
+        else {
+            return;
+        };
+        let challenge = Challenge::new();
+        stream.challenge = Some(challenge.clone());
+        let msg = Msg::Challenge(challenge);
+        if let Err(err) = write_msg(&mut stream.stream, &mut self.frame, &msg).await {
+            warn!(%err, "unable to send session resumption challenge");
+        }
+    }
+
+    /// Resolves duplicate streams with the peer of the verified
+    /// stream at `addr`.
+    ///
+    /// If we already have a verified stream with the same peer,
+    /// both peers deterministically agree on which stream
+    /// survives (see [`keep_newer`]). Channels using the other
+    /// stream are moved to the survivor and the other stream is
+    /// drained: we stop writing to it, but keep reading from it
+    /// until the peer closes it so that messages in flight are
+    /// not lost.
+    async fn resolve_duplicate(&mut self, addr: SocketAddr) -> Result<(), AfcError> {
+        let local = self.streams.device_id;
+        let new = self
+            .streams
+            .streams
+            .get(&addr)
+            .assume("stream should exist")?;
+        let Some(peer) = new.peer.filter(|_| new.verified) else {
+            return Ok(());
+        };
+        let new_outbound = new.outbound;
+
+        if peer == local {
+            // We're talking to ourselves, so both streams are
+            // the same connection.
+            return Ok(());
+        }
+        let Some(old_addr) = self.streams.find_peer(peer, addr) else {
+            return Ok(());
+        };
+        let old_outbound = self
+            .streams
+            .streams
+            .get(&old_addr)
+            .assume("stream should exist")?
+            .outbound;
+
+        let (survivor, loser) = if keep_newer(&local, &peer, new_outbound, old_outbound) {
+            (addr, old_addr)
+        } else {
+            (old_addr, addr)
+        };
+        debug!(%survivor, %loser, "resolved duplicate stream");
+
+        for chan in self.chans.values_mut() {
+            if chan.addr == Some(loser) {
+                chan.addr = Some(survivor);
+            }
+        }
+
+        let loser = self
+            .streams
+            .streams
+            .get_mut(&loser)
+            .assume("stream should exist")?;
+        loser.draining = true;
+        // Tell the peer why before closing our write half, which
+        // tells the peer that we're done with the stream. Once
+        // it does the same, the stream is removed by
+        // `next_ready`.
+        let goodbye = Msg::Goodbye(Goodbye {
+            reason: GoodbyeReason::Duplicate,
+        });
+        if loser.extended {
+            if let Err(err) = write_msg(&mut loser.stream, &mut self.frame, &goodbye).await {
+                debug!(%err, "unable to say goodbye");
+            }
+        }
+        if let Err(err) = loser.stream.close().await {
+            warn!(?err, "unable to shutdown losing stream");
+        }
+
+        Ok(())
+    }
+
+    /// Handles a [`Hello`] from the stream at `addr` whose
+    /// version we do not speak, per
+    /// [`RouterConfig::version_mismatch`].
+    async fn version_mismatch(&mut self, addr: SocketAddr, err: AfcError) -> Result<(), AfcError> {
+        let supported = SUPPORTED_VERSIONS.to_vec();
+        match self.cfg.version_mismatch {
+            VersionMismatchPolicy::Error => {
+                self.streams.rep.handshake_failed(addr.ip());
+                Err(err)
+            }
+            VersionMismatchPolicy::Advertise { grace } => {
+                let stream = self
+                    .streams
+                    .streams
+                    .get_mut(&addr)
+                    .assume("stream should exist")?;
+                let msg = Msg::Versions(Versions { supported });
+                if let Err(err) = write_msg(&mut stream.stream, &mut self.frame, &msg).await {
+                    warn!(%err, "unable to advertise versions, closing stream");
+                    self.streams
+                        .remove(&addr, DisconnectReason::from_write(&err));
+                    return Ok(());
+                }
+                // Do not extend the grace period if the peer
+                // greets us again with another version we do
+                // not speak.
+                let deadline = *stream.grace.get_or_insert(Instant::now() + grace);
+                info!(?deadline, "advertised versions to peer");
+                Ok(())
+            }
+            VersionMismatchPolicy::Goodbye => {
+                let stream = self.streams.get_mut(&addr).assume("stream should exist")?;
+                let msg = Msg::Goodbye(Goodbye {
+                    reason: GoodbyeReason::UnsupportedVersion { supported },
+                });
+                if let Err(err) = write_msg(stream, &mut self.frame, &msg).await {
+                    debug!(%err, "unable to say goodbye");
+                }
+                info!("closing stream with peer that speaks another version");
+                self.streams
+                    .remove(&addr, DisconnectReason::VersionMismatch);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles the versions advertised by the peer at `addr`
+    /// after it rejected our [`Hello`].
+    ///
+    /// We greet the peer again with the newest version that we
+    /// both speak. If there is none, the peer closes the stream
+    /// once its grace period elapses.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn handle_versions(
+        &mut self,
+        addr: SocketAddr,
+        versions: &Versions,
+    ) -> Result<(), AfcError> {
+        let device_id = self.streams.device_id;
+        let port = self.streams.port;
+        let stream = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        match common_version(&versions.supported) {
+            Some(version) if version != stream.greeting => {
+                info!(?version, supported = ?versions.supported, "downgrading to the peer's version");
+                stream.greeting = version;
+                stream.greeted = false;
+                stream.greet(device_id, port).await?;
+            }
+            Some(_) => {
+                warn!(supported = ?versions.supported, "peer rejected a version it advertises");
+            }
+            None => {
+                warn!(supported = ?versions.supported, "peer does not speak our versions");
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a [`Goodbye`] from the peer at `addr`.
+    ///
+    /// The peer is about to close the stream, so we remove it.
+    /// Channels that use the stream reconnect the next time
+    /// they send data.
+    ///
+    /// If the peer keeps another stream with us instead, the
+    /// stream is drained like the loser of a tie-break (see
+    /// [`handle_hello`][Self::handle_hello]) so that messages in
+    /// flight are not lost.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn handle_goodbye(&mut self, addr: SocketAddr, goodbye: &Goodbye) {
+        if goodbye.reason != GoodbyeReason::Duplicate {
+            warn!(reason = ?goodbye.reason, "peer said goodbye");
+            self.streams
+                .remove(&addr, DisconnectReason::Goodbye(goodbye.reason.clone()));
+            return;
+        }
+        info!("peer is replacing the stream");
+        if let Some(ps) = self.streams.streams.get_mut(&addr) {
+            if !ps.draining {
+                ps.draining = true;
+                if let Err(err) = ps.stream.close().await {
+                    warn!(?err, "unable to shutdown replaced stream");
+                }
+            }
+        }
+        let survivor = self.streams.survivor(addr);
+        for chan in self.chans.values_mut() {
+            if chan.addr == Some(addr) {
+                chan.addr = (survivor != addr).then_some(survivor);
+            }
+        }
+    }
+
+    /// Reads a [`Msg`] from the stream.
+    ///
+    /// The greeting that trails the first frame of a stream, if
+    /// any, is handled first (see [`PeerStream::greet`]).
+    ///
+    /// Malformed messages count against the peer's reputation.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn read_msg(&mut self, addr: SocketAddr) -> Result<Msg, AfcError> {
+        let result = self.try_read_msg(addr).await;
+        if matches!(
+            result,
+            Err(AfcError::InvalidMagic(_) | AfcError::MsgTooLarge { .. } | AfcError::Serde(_))
+        ) {
+            self.streams.rep.protocol_violation(addr.ip());
+        }
+        let (msg, greeting) = result?;
+        self.streams.heard(addr);
+        if greeting.is_some() || !matches!(msg, Msg::Ctrl(_) | Msg::Data(_)) {
+            if let Some(stream) = self.streams.streams.get_mut(&addr) {
+                stream.extended = true;
+            }
+        }
+        match greeting {
+            None => {}
+            Some(Msg::Hello(hello)) => self.handle_hello(addr, hello).await?,
+            Some(_) => warn!("ignoring a trailer that is not a greeting"),
+        }
+        // The greeting might have closed the stream.
+        if !self.streams.streams.contains_key(&addr) {
+            return Err(AfcError::StreamNotFound(addr));
+        }
+        Ok(msg)
+    }
+
+    /// Reads a data message, [`Nack`], or keep-alive message
+    /// from the UDP socket, if one is available.
+    ///
+    /// Malformed datagrams are dropped. Unlike streams, they do
+    /// not count against the sender's reputation, since their
+    /// source address can be spoofed.
+    #[instrument(skip_all)]
+    pub fn read_datagram(&mut self) -> Result<Option<(SocketAddr, Msg)>, AfcError> {
+        let mut buf = self.bufs.take()?;
+        buf.resize(MAX_DATAGRAM_SIZE.min(self.bufs.size()), 0);
+        let (n, addr) = match self.udp.try_recv_from(&mut buf) {
+            Ok(v) => v,
+            Err(err) => {
+                self.bufs.put(buf);
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(None);
+                }
+                return Err(AfcError::Udp(err));
+            }
+        };
+        buf.truncate(n);
+        debug!(n, %addr, "read datagram");
+
+        let msg = decode_datagram(&mut buf, self.cfg.max_msg_size);
+        self.bufs.put(buf);
+        match msg {
+            Ok(
+                msg @ (Msg::Data(_)
+                | Msg::Enveloped(_)
+                | Msg::Nack(_)
+                | Msg::Ping(_)
+                | Msg::Pong(_)
+                | Msg::Window(_)),
+            ) => Ok(Some((addr, msg))),
+            Ok(_) => {
+                warn!(%addr, "dropping non-data datagram");
+                self.drop_datagram();
+                Ok(None)
+            }
+            Err(err) => {
+                warn!(%addr, %err, "dropping malformed datagram");
+                self.drop_datagram();
+                Ok(None)
+            }
+        }
+    }
+
+    /// Records that a datagram was dropped.
+    pub fn drop_datagram(&mut self) {
+        self.stats.datagrams_dropped = self.stats.datagrams_dropped.saturating_add(1);
+        metrics::msgs_dropped("datagram", 1);
+    }
+
+    async fn try_read_msg(&mut self, addr: SocketAddr) -> Result<(Msg, Option<Msg>), AfcError> {
+        debug!("reading message from stream");
+
+        let stream = self
+            .streams
+            .get_mut(&addr)
+            .ok_or_else(|| AfcError::StreamNotFound(addr))?;
+
+        let mut hdr = [0u8; WIRE_HEADER_SIZE];
+        stream
+            .read_exact(&mut hdr)
+            .await
+            .map_err(AfcError::StreamRead)?;
+
+        let len = decode_wire_header(&hdr, self.cfg.max_msg_size)?;
+        debug!(%len, "read message length");
+
+        let mut buf = self.bufs.take()?;
+        buf.resize(len as usize, 0);
+        if let Err(err) = stream.read_exact(&mut buf).await {
+            self.bufs.put(buf);
+            return Err(AfcError::StreamRead(err));
+        }
+        debug!(%len, "read message bytes");
+
+        let msg = decode_frame(&mut buf, 0);
+        self.bufs.put(buf);
+        msg
+    }
+
+    /// Decrypts `data`.
+    ///
+    /// The buffers of `data` and, unless it is delivered, the
+    /// plaintext are returned to the pool.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", data.afc_id)))]
+    pub async fn open_data(&mut self, data: Data) -> Result<Opened, AfcError> {
+        debug!(n = data.ciphertext.len(), "decrypting data");
+
+        let result = match self.bufs.take() {
+            Ok(mut plaintext) => {
+                let result = self.try_open_data(&data, &mut plaintext).await;
+                self.bufs.put(plaintext);
+                result
+            }
+            Err(err) => Err(err),
+        };
+        self.bufs.put(data.ciphertext.into_inner());
+        result
+    }
+
+    /// Decrypts `data` into `plaintext`.
+    ///
+    /// If the message should be delivered, `plaintext` is moved
+    /// into [`Opened::Msg`].
+    async fn try_open_data(
+        &mut self,
+        data: &Data,
+        plaintext: &mut Vec<u8>,
+    ) -> Result<Opened, AfcError> {
+        self.check_version(data.version)?;
+
+        let Some(chan) = self.chans.get_mut(&data.afc_id) else {
+            return self.open_removed(data.afc_id);
+        };
+        let chan_id = chan.chan_id;
+        debug!(%chan_id, "found channel");
+
+        // Might as well check this first to limit how much work
+        // we do for expired channels.
+        if chan.replay.is_exhausted() {
+            return Err(AfcError::EndOfChannel);
+        }
+        if chan.expiry.is_expired() {
+            debug!("dropping data for expired channel");
+            return Err(AfcError::ChannelExpired(data.afc_id));
+        }
+
+        let Message { payload, .. } = Message::try_parse(&data.ciphertext)?;
+        let ciphertext = match payload {
+            Payload::Data(v) => v,
+            Payload::Control(_) => bug!("`Data` should not contain control messages"),
+        };
+
+        let plaintext_len = ciphertext
+            .len()
+            .checked_sub(Client::<S>::OVERHEAD)
+            .ok_or(AfcError::PayloadTooSmall)?;
+        plaintext.clear();
+        plaintext.resize(plaintext_len, 0);
+        let (label, seq) = metrics::timed(metrics::OPEN_LATENCY, || {
+            self.afc.open(chan_id.node_id(), plaintext, ciphertext)
+        })
+        .map_err(AfcError::Decryption)?;
+        debug!(%label, %seq, "decrypted data");
+
+        if chan_id.label() != label {
+            error!(got = %label, expected = %chan_id.label(), "mismatched labels");
+            bug!("decrypted data with mismatched labels");
+        }
+
+        // Reserve the sequence number on disk before accepting it
+        // so that it cannot be replayed after a restart.
+        let reserve = self.cfg.channel_state_path.is_some()
+            && chan.reserved.map_or(true, |r| seq.to_u64() > r);
+        if reserve {
+            let old = chan
+                .reserved
+                .replace(seq.to_u64().saturating_add(self.cfg.seq_reservation));
+            if let Err(err) = self.save_chans().await {
+                error!(%err, "unable to reserve sequence numbers");
+                if let Some(chan) = self.chans.get_mut(&data.afc_id) {
+                    chan.reserved = old;
+                }
+                return Err(AfcError::ChannelState(err));
+            }
+        }
+        let chan = self
+            .chans
+            .get_mut(&data.afc_id)
+            .assume("channel should still exist")?;
+
+        match chan.replay.insert(seq.to_u64()) {
+            Check::Newest => {}
+            Check::Reordered => {
+                debug!(%seq, "accepted reordered message");
+                chan.stats.reordered = chan.stats.reordered.saturating_add(1);
+            }
+            Check::Replayed => {
+                chan.stats.replays_rejected = chan.stats.replays_rejected.saturating_add(1);
+                metrics::msgs_dropped("replayed", 1);
+                return Err(AfcError::MsgReplayed(seq));
+            }
+        }
+        if let Some(fc) = &self.cfg.flow_control {
+            chan.flow.received(seq.to_u64());
+            if chan.flow.is_due(fc.window.max(1)) {
+                self.windows.insert(data.afc_id);
+            }
+        }
+        if let Some(reason) = chan.expiry.count(Instant::now()) {
+            self.expired
+                .push((data.afc_id, reason, chan.expiry.policy.delete));
+        }
+
+        if let Some(liveness) = chan.probe.as_mut().and_then(|p| p.heard(Instant::now())) {
+            chan.stats.liveness = liveness;
+            self.liveness.push((data.afc_id, liveness));
+        }
+
+        // Only authentic messages count against the limit, so
+        // a forged flood cannot starve the channel.
+        if chan.limiter.as_mut().is_some_and(|l| !l.recv()) {
+            warn!(%seq, "receive rate limit exceeded, dropping message");
+            chan.stats.rate_limited = chan.stats.rate_limited.saturating_add(1);
+            metrics::msgs_dropped("rate_limited", 1);
+            return Err(AfcError::RateLimited(data.afc_id));
+        }
+
+        let (exts, range) = if data.enveloped {
+            decode_envelope(plaintext)?
+        } else {
+            (Exts { buf: &[], n: 0 }, 0..plaintext.len())
+        };
+        let pt = plaintext
+            .get(range.clone())
+            .assume("`range` is within `plaintext`")?;
+        chan.stats.received(pt.len(), seq);
+        // Check the checksum first so that a corrupted message
+        // cannot be reported as a duplicate.
+        for ext in exts.clone() {
+            if let Ext::Checksum(want) = ext {
+                let got = checksum(pt);
+                if got != want {
+                    warn!(%seq, want, got, "checksum mismatch");
+                    chan.stats.checksum_mismatches =
+                        chan.stats.checksum_mismatches.saturating_add(1);
+                    return Ok(Opened::ChecksumMismatch {
+                        afc_id: data.afc_id,
+                        seq,
+                    });
+                }
+            }
+        }
+        let sent_at = exts.clone().find_map(|ext| match ext {
+            Ext::Timestamp(micros) => Some(from_unix_micros(micros)),
+            _ => None,
+        });
+        if let Some(sent_at) = sent_at {
+            let now = SystemTime::now();
+            let skew = sent_at.duration_since(now).unwrap_or_default();
+            if skew > self.cfg.max_clock_skew {
+                warn!(%seq, ?skew, "timestamp too far in the future");
+                chan.stats.timestamps_rejected = chan.stats.timestamps_rejected.saturating_add(1);
+                return Ok(Opened::TimestampRejected {
+                    afc_id: data.afc_id,
+                    seq,
+                    sent_at,
+                });
+            }
+            chan.stats.timestamped(sent_at, now);
+        }
+        let mut fragment = None;
+        let mut rpc = None;
+        for ext in exts {
+            match ext {
+                Ext::IdempotencyToken(token) => {
+                    if !chan.tokens.insert(token) {
+                        debug!(%token, %seq, "duplicate idempotency token");
+                        return Ok(Opened::Duplicate {
+                            afc_id: data.afc_id,
+                            token,
+                            seq,
+                        });
+                    }
+                }
+                Ext::Checksum(_) | Ext::Timestamp(_) => {}
+                Ext::Fragment(f) => fragment = Some(f),
+                Ext::Request(id) => rpc = Some(Rpc::Request(id)),
+                Ext::Response(id) => rpc = Some(Rpc::Response(id)),
+            }
+        }
+
+        if let Some(fragment) = fragment {
+            if self.cfg.bounded.is_some() {
+                return Err(AfcError::Capacity("stream reassembly"));
+            }
+            let afc_id = data.afc_id;
+            return Ok(
+                match chan.reassemble(fragment, pt, self.cfg.max_stream_size) {
+                    Reassembled::Pending => Opened::Fragment { afc_id, seq },
+                    Reassembled::Dropped => Opened::StreamDropped { afc_id, seq },
+                    Reassembled::Done(data) => Opened::Msg {
+                        data,
+                        afc_id,
+                        label,
+                        seq,
+                        sent_at,
+                        rpc,
+                    },
+                },
+            );
+        }
+
+        // Strip the envelope in place.
+        plaintext.truncate(range.end);
+        plaintext.drain(..range.start);
+        Ok(Opened::Msg {
+            data: mem::take(plaintext),
+            afc_id: data.afc_id,
+            label,
+            seq,
+            sent_at,
+            rpc,
+        })
+    }
+
+    /// Get the local address the AFC server bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, AfcError> {
+        self.listener.local_addr().map_err(AfcError::RouterAddr)
+    }
+
+    /// Get every local address the AFC server bound to,
+    /// starting with [`local_addr`][Self::local_addr].
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, AfcError> {
+        self.listener.local_addrs().map_err(AfcError::RouterAddr)
+    }
+
+    /// Adds a new channel.
+    ///
+    /// It is an error if the channel already exists.
+    #[instrument(skip_all, fields(
+        afc_id = %ShortId::new("afc", id),
+        %net_id,
+        team_id = %ShortId::new("team", team_id),
+        %chan_id,
+        %addr,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn add_channel(
+        &mut self,
+        id: AfcId,
+        net_id: NetIdentifier,
+        team_id: TeamId,
+        chan_id: ChannelId,
+        addr: SocketAddr,
+        udp: Option<SocketAddr>,
+        self_check: bool,
+    ) -> Result<(), AfcError> {
+        debug!(?udp, self_check, "adding channel");
+
+        // The stream might have lost a tie-break.
+        let addr = self.streams.survivor(addr);
+
+        let bounded = self.cfg.bounded.is_some();
+        if bounded && self.chans.len() >= self.max_chans && !self.chans.contains_key(&id) {
+            return Err(AfcError::Capacity("channels"));
+        }
+        // The peer might accept streams on another port than the
+        // one in `net_id`, so dial the one that it advertised.
+        if let Some(port) = self
+            .streams
+            .streams
+            .get(&addr)
+            .filter(|s| s.verified && !s.outbound)
+            .and_then(|s| s.port)
+        {
+            self.streams
+                .dns
+                .advertise(net_id.as_ref(), SocketAddr::new(addr.ip(), port));
+        }
+        match self.chans.entry(id) {
+            // Reject duplicates because
+            // 1. Channel IDs are globally unique (a
+            //    cryptographically negligible probability of
+            //    collisions). This probably means we're
+            //    processing the same `ctrl` again. It might mean
+            //    that the graph/daemon/whatever is buggy?
+            // 2. It would reset the sequence number, which would
+            //    allow replay attacks.
+            btree_map::Entry::Occupied(_) => {
+                if self.cfg.strict {
+                    error!(afc_id = %ShortId::new("afc", id), "duplicate channel ID");
+                    return Err(AfcError::DuplicateChannel(id));
+                }
+                warn!(afc_id = %ShortId::new("afc", id), "duplicate channel ID");
+
+                // Don't return an error, though, since the most
+                // likely cause is that we're processing
+                // a duplicate control message.
+            }
+            btree_map::Entry::Vacant(v) => {
+                // `addr` comes from either `Status::Accept` or
+                // `send_ctrl`, so use it instead of performing
+                // a DNS lookup. In both cases we likely already
+                // have an open TCP stream. If we don't, the next
+                // operation on the channel will perform the DNS
+                // lookup anyway.
+                v.insert(Chan::new(
+                    &self.cfg,
+                    team_id,
+                    net_id,
+                    chan_id,
+                    Some(addr),
+                    udp,
+                    self_check,
+                ));
+                self.persist().await;
+            }
+        }
+        // If the peer resumes the stream, the new stream takes
+        // the channel over even if this one was reaped by then.
+        if let Some(peer) = self
+            .streams
+            .streams
+            .get(&addr)
+            .filter(|s| s.verified && !s.outbound)
+            .and_then(|s| s.peer)
+        {
+            self.streams.tickets.bind(peer, addr, id);
+        }
+        self.issue_ticket(addr).await;
+        debug!("added channel");
+
+        Ok(())
+    }
+
+    /// Checks whether a channel can be added to `team_id` under
+    /// [`RouterConfig::channel_limits`].
+    ///
+    /// Returns the channel to evict to make room, if any.
+    pub fn make_room(&self, team_id: TeamId) -> Result<Option<AfcId>, AfcError> {
+        let Some(limits) = &self.cfg.channel_limits else {
+            return Ok(None);
+        };
+        let team_full = limits.max_chans_per_team.is_some_and(|max| {
+            self.chans
+                .values()
+                .filter(|chan| chan.team_id == team_id)
+                .count()
+                >= max
+        });
+        let full = limits.max_chans.is_some_and(|max| self.chans.len() >= max);
+        let what = match (team_full, full) {
+            (true, _) => "channels per team",
+            (false, true) => "channels",
+            (false, false) => return Ok(None),
+        };
+
+        // Evicting one of the team's channels makes room under
+        // both caps.
+        let mut candidates = self
+            .chans
+            .iter()
+            .filter(|(_, chan)| !team_full || chan.team_id == team_id)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, chan)| chan.stats.last_activity);
+        let victim = match &limits.at_capacity {
+            CapacityPolicy::Reject => None,
+            CapacityPolicy::EvictExpired => candidates
+                .iter()
+                .find(|(_, chan)| chan.expiry.is_expired() || chan.replay.is_exhausted())
+                .map(|(&id, _)| id),
+            CapacityPolicy::Evict(f) => {
+                let summaries = candidates
+                    .iter()
+                    .map(|(&id, chan)| chan.summary(id))
+                    .collect::<Vec<_>>();
+                f(&summaries).filter(|id| candidates.iter().any(|(&c, _)| c == *id))
+            }
+        };
+        match victim {
+            Some(id) => {
+                info!(afc_id = %ShortId::new("afc", id), what, "evicting channel to make room");
+                Ok(Some(id))
+            }
+            None => {
+                warn!(what, "channel limit reached");
+                Err(AfcError::Capacity(what))
+            }
+        }
+    }
+
+    /// Updates the peer's [`NetIdentifier`] for an existing
+    /// channel.
+    ///
+    /// The channel's keys and sequence numbers are unchanged. The
+    /// cached address is discarded and the new identifier is
+    /// resolved (and connected to, if needed) the next time data
+    /// is sent over the channel.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", id), %net_id))]
+    pub async fn update_channel_peer(
+        &mut self,
+        id: AfcId,
+        net_id: NetIdentifier,
+    ) -> Result<(), AfcError> {
+        debug!("updating channel peer");
+
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| AfcError::ChannelNotFound(id))?;
+        debug!(old = %chan.net_id, "replacing net identifier");
+        chan.net_id = net_id;
+        chan.addr = None;
+        self.persist().await;
+
+        Ok(())
+    }
+
+    /// Sets the rate limit of channel `id`, or removes it if
+    /// `limit` is `None`.
+    ///
+    /// The channel starts with a full burst of tokens.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", id), ?limit))]
+    pub fn set_rate_limit(&mut self, id: AfcId, limit: Option<RateLimit>) -> Result<(), AfcError> {
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        chan.limiter = limit.as_ref().map(RateLimiter::new);
+        debug!("set channel rate limit");
+        Ok(())
+    }
+
+    /// Sets the expiry policy of channel `id`, or removes it if
+    /// `expiry` is `None`.
+    ///
+    /// The channel's age and message count are kept, so it
+    /// expires right away if it is past the new limits.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", id), ?expiry))]
+    pub fn set_expiry(&mut self, id: AfcId, expiry: Option<ChannelExpiry>) -> Result<(), AfcError> {
+        let chan = self
+            .chans
+            .get_mut(&id)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))?;
+        chan.expiry.policy = expiry.unwrap_or_default();
+        debug!("set channel expiry");
+        self.expire_channels();
+        Ok(())
+    }
+
+    /// Fails if channel `id` expired.
+    fn check_expiry(&mut self, id: AfcId) -> Result<(), AfcError> {
+        let Some(chan) = self.chans.get_mut(&id) else {
+            return Ok(());
+        };
+        if let Some(reason) = chan.expiry.check(Instant::now()) {
+            self.expired.push((id, reason, chan.expiry.policy.delete));
+        }
+        if chan.expiry.is_expired() {
+            return Err(AfcError::ChannelExpired(id));
+        }
+        Ok(())
+    }
+
+    /// Counts a message sealed with channel `id`'s keys.
+    fn count_use(&mut self, id: AfcId) {
+        if let Some(chan) = self.chans.get_mut(&id) {
+            if let Some(reason) = chan.expiry.count(Instant::now()) {
+                self.expired.push((id, reason, chan.expiry.policy.delete));
+            }
+        }
+    }
+
+    /// Expires the channels that reached their maximum age.
+    ///
+    /// Expired channels are returned by
+    /// [`take_expired`][Self::take_expired].
+    pub fn expire_channels(&mut self) {
+        let now = Instant::now();
+        for (id, chan) in &mut self.chans {
+            if let Some(reason) = chan.expiry.check(now) {
+                self.expired.push((*id, reason, chan.expiry.policy.delete));
+            }
+        }
+    }
+
+    /// Returns the channels that expired since the last call,
+    /// why they expired, and whether to delete them.
+    pub fn take_expired(&mut self) -> Vec<(AfcId, ExpiryReason, bool)> {
+        mem::take(&mut self.expired)
+    }
+
+    /// Returns the metadata of channel `id`.
+    pub fn metadata(&self, id: AfcId) -> Result<&ChannelMetadata, AfcError> {
+        self.chans
+            .get(&id)
+            .map(|chan| &chan.metadata)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))
+    }
+
+    /// Returns the metadata of channel `id` for modification.
+    pub fn metadata_mut(&mut self, id: AfcId) -> Result<&mut ChannelMetadata, AfcError> {
+        self.chans
+            .get_mut(&id)
+            .map(|chan| &mut chan.metadata)
+            .ok_or_else(|| chan_not_found(&self.tombstones, id))
+    }
+
+    /// Deletes a channel.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", id)))]
+    pub async fn remove_channel(&mut self, id: AfcId) {
+        debug!("removing channel");
+
+        self.prune_tombstones();
+        if self.chans.remove(&id).is_none() {
+            return;
+        }
+        self.persist().await;
+        if self.cfg.removed_channel != RemovedChannelPolicy::Error
+            && !self.cfg.removed_channel_grace.is_zero()
+        {
+            self.tombstones.insert(id, Tombstone::new());
+        }
+    }
+
+    /// Removes the channels that are not in `known`, which is
+    /// the set of channels that the daemon knows about.
+    ///
+    /// The removed channels are remembered as revoked for
+    /// [`RouterConfig::removed_channel_grace`] regardless of
+    /// [`RouterConfig::removed_channel`], so that using them
+    /// fails with [`AfcError::ChannelRevoked`].
+    ///
+    /// Returns the removed channels.
+    pub async fn reconcile(&mut self, known: &BTreeSet<AfcId>) -> Vec<AfcId> {
+        self.prune_tombstones();
+        let revoked = self
+            .chans
+            .keys()
+            .filter(|id| !known.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        for &id in &revoked {
+            warn!(afc_id = %ShortId::new("afc", id), "daemon removed channel");
+            self.chans.remove(&id);
+            if !self.cfg.removed_channel_grace.is_zero() {
+                let mut tombstone = Tombstone::new();
+                tombstone.revoked = true;
+                self.tombstones.insert(id, tombstone);
+            }
+        }
+        if !revoked.is_empty() {
+            self.persist().await;
+        }
+        revoked
+    }
+
+    /// Writes the channel state to
+    /// [`RouterConfig::channel_state_path`], if set.
+    async fn save_chans(&self) -> io::Result<()> {
+        let Some(path) = &self.cfg.channel_state_path else {
+            return Ok(());
+        };
+        let chans = self
+            .chans
+            .iter()
+            .map(|(&id, chan)| chan.saved(id))
+            .collect();
+        persist::save(path, &Snapshot::new(chans)).await
+    }
+
+    /// Returns the router's state, for a new process to take
+    /// over with [`take_over`][Self::take_over].
+    pub fn handoff(&self) -> Handoff {
+        let now = Instant::now();
+        let chans = self
+            .chans
+            .iter()
+            .map(|(&id, chan)| HandedChan {
+                saved: chan.saved(id),
+                top: chan.replay.top(),
+                // Keep the stream of channels bound to a ticket
+                // so that the peer still takes them over.
+                addr: chan.addr.or_else(|| self.streams.tickets.bound(id, now)),
+            })
+            .collect();
+        Handoff::new(
+            self.listener.local_addr().ok(),
+            chans,
+            self.streams.tickets.save(now),
+        )
+    }
+
+    /// Takes over the channels and session resumption tickets of
+    /// the router that returned `handoff`.
+    ///
+    /// They replace the channels restored from
+    /// [`RouterConfig::channel_state_path`], if any, which
+    /// might be older.
+    pub async fn take_over(&mut self, handoff: Handoff) {
+        self.chans.clear();
+        for handed in handoff.chans {
+            let saved = handed.saved;
+            let chan_id = ChannelId::new(saved.node_id, saved.label);
+            let mut chan = Chan::new(
+                &self.cfg,
+                saved.team_id,
+                saved.net_id,
+                chan_id,
+                handed.addr,
+                saved.udp,
+                saved.self_check,
+            );
+            if let Some(top) = handed.top {
+                // Messages that were reordered around the handoff
+                // are rejected, but none can be replayed.
+                chan.replay = ReplayWindow::after(chan.replay.size(), top);
+            }
+            chan.reserved = saved.reserved;
+            self.chans.insert(saved.id, chan);
+        }
+        self.streams.tickets = Tickets::restore(handoff.tickets, Instant::now());
+        info!(n = self.chans.len(), "took over AFC channels");
+        self.persist().await;
+    }
+
+    /// Like [`save_chans`][Self::save_chans], but only logs
+    /// failures.
+    ///
+    /// The channel set itself is recovered from the daemon by
+    /// [`reconcile`][Self::reconcile], so only reservations
+    /// must be saved strictly.
+    async fn persist(&self) {
+        if let Err(err) = self.save_chans().await {
+            warn!(%err, "unable to save channel state");
+        }
+    }
+
+    /// Returns our device ID.
+    pub fn device_id(&self) -> DeviceId {
+        self.streams.device_id
+    }
+
+    /// Replaces the channel keys with those in `afc`, e.g.,
+    /// because the daemon created its shared memory again.
+    pub fn replace_shm(&mut self, afc: Client<S>) {
+        self.afc = afc;
+    }
+
+    /// Reports whether the router has any channels.
+    pub fn has_channels(&self) -> bool {
+        !self.chans.is_empty()
+    }
+
+    /// Reports whether channel `id` is open.
+    pub fn has_channel(&self, id: AfcId) -> bool {
+        self.chans.contains_key(&id)
+    }
+
+    /// Returns the context of an error that `operation`
+    /// returned for channel `id`.
+    pub fn error_context(&self, operation: &'static str, id: AfcId) -> ErrorContext {
+        let mut ctx = ErrorContext::new(operation);
+        ctx.afc_id = Some(id);
+        if let Some(chan) = self.chans.get(&id) {
+            ctx.team_id = Some(chan.team_id);
+            ctx.peer = Some(chan.net_id.clone());
+            ctx.addr = chan.addr;
+        }
+        ctx
+    }
+
+    /// Returns a buffer from [`Opened::Msg`] to the pool.
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        self.bufs.put(buf);
+    }
+
+    /// Returns the router's effective limits.
+    pub fn limits(&self) -> Limits {
+        let max_msg_size = usize::try_from(self.cfg.max_msg_size).unwrap_or(usize::MAX);
+        let seal_overhead = Header::PACKED_SIZE + Client::<S>::OVERHEAD;
+        let max_data_size = |max: usize| max.saturating_sub(seal_overhead + MAX_FRAMING_OVERHEAD);
+        Limits {
+            max_msg_size: self.cfg.max_msg_size,
+            header_size: Header::PACKED_SIZE,
+            seal_overhead: Client::<S>::OVERHEAD,
+            max_chans: self.max_chans,
+            max_data_size: max_data_size(max_msg_size),
+            max_datagram_data_size: max_data_size(
+                MAX_DATAGRAM_SIZE
+                    .min(max_msg_size)
+                    .saturating_sub(WIRE_HEADER_SIZE),
+            ),
+            max_revocation_delay: self.cfg.reconcile_interval,
+        }
+    }
+
+    /// Returns when queued messages are next due to be resent,
+    /// if any are queued.
+    fn next_retry(&self) -> Option<Instant> {
+        self.chans
+            .values()
+            .filter_map(|chan| chan.retry.as_ref()?.at)
+            .min()
+    }
+
+    /// Returns when keep-alive pings are next due to be sent or
+    /// time out, if keep-alive is enabled.
+    fn next_probe(&self) -> Option<Instant> {
+        self.chans
+            .values()
+            .filter_map(|chan| Some(chan.probe.as_ref()?.deadline()))
+            .min()
+    }
+
+    /// Returns when the next channel reaches its maximum age,
+    /// if any.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.chans
+            .values()
+            .filter_map(|chan| chan.expiry.deadline())
+            .min()
+    }
+
+    /// Sends keep-alive pings that are due and times out the
+    /// ones that were not answered.
+    ///
+    /// Liveness changes are returned by
+    /// [`take_liveness_changes`][Self::take_liveness_changes].
+    #[instrument(skip_all)]
+    pub async fn keepalive(&mut self) -> Result<(), AfcError> {
+        let now = Instant::now();
+        let Self {
+            chans,
+            streams,
+            udp,
+            frame,
+            liveness,
+            ..
+        } = self;
+        for (id, chan) in chans.iter_mut() {
+            let Some(probe) = &mut chan.probe else {
+                continue;
+            };
+            let tick = probe.tick(now);
+            if tick.missed {
+                debug!(afc_id = %ShortId::new("afc", id), "missed ping");
+                chan.stats.pings_missed = chan.stats.pings_missed.saturating_add(1);
+            }
+            if let Some(l) = tick.changed {
+                warn!(afc_id = %ShortId::new("afc", id), liveness = ?l, "channel liveness changed");
+                chan.stats.liveness = l;
+                liveness.push((*id, l));
+            }
+            let Some(nonce) = tick.ping else {
+                continue;
+            };
+            let msg = Msg::Ping(Ping {
+                version: Version::V1,
+                afc_id: *id,
+                nonce,
+            });
+            let result = match chan.udp {
+                Some(addr) => send_datagram(udp, addr, frame, &msg).await.map(|_| ()),
+                None => {
+                    frame.encode(&msg)?;
+                    write_chan(streams, &chan.net_id, &mut chan.addr, frame.finish()?).await
+                }
+            };
+            // An unsent ping times out like a lost one.
+            match result {
+                Ok(()) => debug!(afc_id = %ShortId::new("afc", id), "sent ping"),
+                Err(err) => debug!(afc_id = %ShortId::new("afc", id), %err, "unable to send ping"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pings the streams that have been idle for
+    /// [`StreamTimeouts::idle_timeout`] and closes the ones
+    /// that did not answer in time.
+    ///
+    /// Closed streams are reported by [`poll`][Self::poll]. The
+    /// channels that used them look up their peer again the next
+    /// time data is sent over them.
+    ///
+    /// Streams whose peer did not identify itself within
+    /// [`RouterConfig::handshake_timeout`], or did not downgrade
+    /// within its grace period (see
+    /// [`VersionMismatchPolicy::Advertise`]), are closed as
+    /// well.
+    #[instrument(skip_all)]
+    pub async fn reap_streams(&mut self) {
+        let mut closed = self.streams.expire();
+        let reaped = self.streams.reap().await;
+        self.stats.streams_reaped = self
+            .stats
+            .streams_reaped
+            .saturating_add(u64::try_from(reaped.len()).unwrap_or(u64::MAX));
+        closed.extend(reaped);
+        if closed.is_empty() {
+            return;
+        }
+        for chan in self.chans.values_mut() {
+            if chan.addr.is_some_and(|addr| closed.contains(&addr)) {
+                chan.addr = None;
+            }
+        }
+    }
+
+    /// Answers a [`Msg::StreamPing`] received over the stream
+    /// with `addr`.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn handle_stream_ping(&mut self, addr: SocketAddr) -> Result<(), AfcError> {
+        let stream = self
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        write_msg(stream, &mut self.frame, &Msg::StreamPong).await?;
+        debug!("sent stream pong");
+        Ok(())
+    }
+
+    /// Returns the liveness changes since the last call.
+    pub fn take_liveness_changes(&mut self) -> Vec<(AfcId, Liveness)> {
+        mem::take(&mut self.liveness)
+    }
+
+    /// Resends queued messages that are due.
+    ///
+    /// The high-priority messages of every channel are resent
+    /// before the normal-priority messages of any of them (see
+    /// [`Priority`]).
+    ///
+    /// Returns the channels whose queued messages were dropped
+    /// after [`RouterConfig::max_reconnect_attempts`] failed
+    /// attempts, along with the number of dropped messages.
+    #[instrument(skip_all)]
+    pub async fn retry(&mut self) -> Vec<(AfcId, usize)> {
+        let now = Instant::now();
+        let Self {
+            chans,
+            streams,
+            cfg,
+            ..
+        } = self;
+        // The channels that failed, which are skipped by later
+        // lanes.
+        let mut failed = BTreeMap::new();
+        for lane in [Priority::High, Priority::Normal] {
+            for (id, chan) in chans.iter_mut() {
+                let Some(retry) = &mut chan.retry else {
+                    continue;
+                };
+                if retry.at.is_some_and(|at| at > now)
+                    || failed.contains_key(id)
+                    || retry.front(lane).is_none()
+                {
+                    continue;
+                }
+                debug!(afc_id = %ShortId::new("afc", id), ?lane, attempt = retry.attempt, queued = retry.queue.len(), "resending queued msgs");
+
+                while let Some((msg, pt_len, _)) = retry.front(lane) {
+                    match write_chan(streams, &chan.net_id, &mut chan.addr, msg).await {
+                        Ok(()) => {
+                            chan.stats.sent(*pt_len);
+                            retry.pop_front();
+                        }
+                        Err(err) => {
+                            failed.insert(*id, err);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let mut dropped = Vec::new();
+        for (id, chan) in chans.iter_mut() {
+            let Some(retry) = &mut chan.retry else {
+                continue;
+            };
+            if retry.at.is_some_and(|at| at > now) {
+                continue;
+            }
+            match failed.remove(id) {
+                None => {
+                    info!(afc_id = %ShortId::new("afc", id), attempt = retry.attempt, "resent queued msgs");
+                    chan.retry = None;
+                }
+                Some(err) if err.is_transient() && retry.attempt < cfg.max_reconnect_attempts => {
+                    let retry_in = retry.schedule(cfg);
+                    warn!(afc_id = %ShortId::new("afc", id), %err, attempt = retry.attempt, ?retry_in, "unable to resend queued msgs, will retry");
+                }
+                Some(err) => {
+                    let n = retry.queue.len();
+                    error!(afc_id = %ShortId::new("afc", id), %err, attempt = retry.attempt, dropped = n, "giving up on queued msgs");
+                    metrics::msgs_dropped("send_failed", n);
+                    dropped.push((*id, n));
+                    chan.retry = None;
+                }
+            }
+        }
+        dropped
+    }
+
+    /// Takes a token from the control message rate limit of the
+    /// peer at `addr`.
+    ///
+    /// Must be called before a control message received from
+    /// the peer is handed to the daemon. See
+    /// [`RouterConfig::ctrl_rate_limit`].
+    pub fn admit_ctrl(&mut self, addr: SocketAddr) -> Result<(), AfcError> {
+        let Some(limit) = self.cfg.ctrl_rate_limit else {
+            return Ok(());
+        };
+        let ip = addr.ip();
+        let now = Instant::now();
+        // Peers whose buckets are full are indistinguishable
+        // from peers that we have never heard from, so forget
+        // them to bound the map.
+        if !self.ctrl_limits.contains_key(&ip) && self.ctrl_limits.len() >= self.cfg.max_streams {
+            self.ctrl_limits.retain(|_, bucket| !bucket.is_full(now));
+            if self.ctrl_limits.len() >= self.cfg.max_streams {
+                warn!(%addr, "too many peers sending control messages");
+                return Err(AfcError::PeerRateLimited(ip));
+            }
+        }
+        let bucket = self
+            .ctrl_limits
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(&limit));
+        if bucket.take(now, Duration::ZERO).is_none() {
+            warn!(%addr, "control message rate limit exceeded");
+            return Err(AfcError::PeerRateLimited(ip));
+        }
+        Ok(())
+    }
+
+    /// Records that the daemon rejected a control message that
+    /// was received over the stream at `addr`.
+    ///
+    /// This counts against the peer's
+    /// [`PeerReputation`] like a failed handshake.
+    pub fn ctrl_rejected(&mut self, addr: SocketAddr) {
+        self.streams.rep.handshake_failed(addr.ip());
+    }
+
+    /// Records that the daemon accepted a control message
+    /// authored by `device` that was received over the stream
+    /// at `addr`.
+    ///
+    /// If `device` is the peer that identified itself on the
+    /// stream, the stream is verified and preferred by
+    /// [`send_ctrl`][Self::send_ctrl], and duplicate verified
+    /// streams with the peer are resolved. The peer is then
+    /// issued a session resumption ticket, if enabled. See
+    /// [`issue_ticket`][Self::issue_ticket].
+    ///
+    /// Peers that predate [`Hello`] never identify themselves,
+    /// so `device` becomes the stream's peer.
+    #[instrument(skip(self))]
+    pub async fn verify_peer(&mut self, addr: SocketAddr, device: DeviceId) {
+        let Some(stream) = self.streams.streams.get_mut(&addr) else {
+            return;
+        };
+        if stream.peer.is_none() && !stream.extended {
+            debug!("adopting the author as the peer of a stream without a greeting");
+            stream.peer = Some(device);
+        }
+        if stream.peer != Some(device) {
+            warn!(claimed = ?stream.peer, "stream peer does not match channel author");
+            return;
+        }
+        if stream.verified {
+            return;
+        }
+        debug!("verified stream peer");
+        stream.verified = true;
+        if let Err(err) = self.resolve_duplicate(addr).await {
+            warn!(%err, "unable to resolve duplicate stream");
+        }
+        self.issue_ticket(addr).await;
+    }
+
+    /// Records that data received over the stream at `addr`
+    /// was decrypted, which authenticates it.
+    ///
+    /// See [`PeerStream::authenticated`].
+    pub fn data_authenticated(&mut self, addr: SocketAddr) {
+        if let Some(stream) = self.streams.streams.get_mut(&addr) {
+            stream.authenticated = true;
+        }
+    }
+
+    /// Issues the peer on the stream at `addr` a session
+    /// resumption ticket, if enabled and the peer does not
+    /// already hold an unexpired one for the stream.
+    ///
+    /// Only verified streams that the peer opened can be
+    /// resumed by it. The ticket's token is sealed with the key
+    /// of one of the stream's channels, so it is not issued
+    /// until the stream has a channel that we can seal with.
+    async fn issue_ticket(&mut self, addr: SocketAddr) {
+        let Some(lifetime) = self.cfg.session_resumption else {
+            return;
+        };
+        let Some(peer) = self
+            .streams
+            .streams
+            .get(&addr)
+            .filter(|s| s.verified && s.extended && !s.outbound && !s.draining)
+            .and_then(|s| s.peer)
+        else {
+            return;
+        };
+        let now = Instant::now();
+        if self.streams.tickets.is_issued(peer, addr, now) {
+            return;
+        }
+        let Some(token) = self.streams.tickets.issue(peer, addr, lifetime, now) else {
+            warn!(?lifetime, "session resumption lifetime is too long");
+            return;
+        };
+        let ids = self
+            .chans
+            .iter()
+            .filter(|(_, chan)| chan.addr == Some(addr))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        // Unidirectional channels can only seal in one
+        // direction.
+        let Some((afc_id, sealed)) = ids
+            .iter()
+            .find_map(|&id| Some((id, self.seal_ctrl(id, &token).ok()?)))
+        else {
+            debug!("no channel to seal session resumption ticket with");
+            self.streams.tickets.revoke(peer);
+            return;
+        };
+        for id in ids {
+            self.streams.tickets.bind(peer, addr, id);
+        }
+        let ticket = Ticket {
+            afc_id,
+            sealed,
+            lifetime,
+        };
+        let Some(stream) = self.streams.get_mut(&addr) else {
+            return;
+        };
+        match write_msg(stream, &mut self.frame, &Msg::Ticket(ticket)).await {
+            Ok(_) => debug!("issued session resumption ticket"),
+            Err(err) => warn!(%err, "unable to issue session resumption ticket"),
+        }
+    }
+
+    /// Handles a [`Msg::Ticket`] received from the stream at
+    /// `addr`.
+    ///
+    /// The ticket is presented the next time we open a stream
+    /// with the peer. Tickets that were not sealed with the key
+    /// of one of the stream's channels are ignored.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", ticket.afc_id)))]
+    pub fn handle_ticket(&mut self, addr: SocketAddr, ticket: Ticket) {
+        // Only streams that we opened can be resumed by us.
+        if !self.streams.streams.get(&addr).is_some_and(|s| s.outbound) {
+            warn!("ignoring ticket for stream that we did not open");
+            return;
+        }
+        let Some(chan_id) = self
+            .chans
+            .get(&ticket.afc_id)
+            .filter(|chan| chan.addr == Some(addr))
+            .map(|chan| chan.chan_id)
+        else {
+            warn!("ignoring ticket for channel of another stream");
+            return;
+        };
+        let token = match self.open_ctrl(ticket.afc_id, chan_id, &ticket.sealed) {
+            Ok(Some(plaintext)) => Token::try_from(plaintext.as_slice()).ok(),
+            Ok(None) => None,
+            Err(err) => {
+                warn!(%err, "ignoring ticket that could not be opened");
+                return;
+            }
+        };
+        let Some(token) = token else {
+            warn!("ignoring unauthentic ticket");
+            return;
+        };
+        debug!(lifetime = ?ticket.lifetime, "received session resumption ticket");
+        self.streams
+            .tickets
+            .hold(addr, token, ticket.lifetime, Instant::now());
+    }
+
+    /// Handles a [`Challenge`] received from the stream at
+    /// `addr`.
+    ///
+    /// If we opened the stream and hold a session resumption
+    /// ticket for it, we answer with a [`Resume`]. The ticket is
+    /// only presented once.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn handle_challenge(
+        &mut self,
+        addr: SocketAddr,
+        challenge: Challenge,
+    ) -> Result<(), AfcError> {
+        let device_id = self.streams.device_id;
+        let stream = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        let Some(token) = stream.token.take().filter(|_| stream.outbound) else {
+            debug!("no session resumption ticket to answer challenge with");
+            return Ok(());
+        };
+        let msg = Msg::Resume(Resume::new(&token, &device_id, &challenge)?);
+        write_msg(&mut stream.stream, &mut self.frame, &msg).await?;
+        debug!("answered session resumption challenge");
+        Ok(())
+    }
+
+    /// Handles a [`Resume`] received from the stream at `addr`.
+    ///
+    /// If it answers the [`Challenge`] that we sent over the
+    /// stream and proves that the peer holds a valid ticket,
+    /// the stream is verified and takes over the channels of
+    /// the stream that the ticket was issued for, including the
+    /// ones bound to the ticket whose stream was already reaped.
+    /// The channels keep their replay windows, so nothing sent
+    /// over the old stream can be replayed.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn handle_resume(
+        &mut self,
+        addr: SocketAddr,
+        resume: Resume,
+    ) -> Result<(), AfcError> {
+        debug!("handling resume");
+
+        let stream = self
+            .streams
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        // Each challenge can only be answered once.
+        let (Some(challenge), Some(peer)) = (stream.challenge.take(), stream.peer) else {
+            warn!("ignoring unsolicited resume");
+            return Ok(());
+        };
+        if stream.draining {
+            return Ok(());
+        }
+        let Some((old, bound)) =
+            self.streams
+                .tickets
+                .redeem(peer, &challenge, &resume, Instant::now())?
+        else {
+            debug!("invalid or expired session resumption ticket");
+            return Ok(());
+        };
+        let mut resumed = Vec::new();
+        for (&id, chan) in &mut self.chans {
+            if chan.addr == Some(old) || (chan.addr.is_none() && bound.contains(&id)) {
+                chan.addr = Some(addr);
+                resumed.push(id);
+            }
+        }
+        self.stats.resumptions = self.stats.resumptions.saturating_add(1);
+        info!(%old, n = resumed.len(), "resumed stream");
+        self.verify_peer(addr, peer).await;
+        for id in resumed {
+            self.streams.tickets.bind(peer, addr, id);
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics for channel `id`.
+    pub fn channel_stats(&self, id: AfcId) -> Result<ChannelStats, AfcError> {
+        self.chans
+            .get(&id)
+            .map(|chan| chan.stats)
+            .ok_or(AfcError::ChannelNotFound(id))
+    }
+
+    /// Records that a payload received on channel `id` was
+    /// rejected by its label's validator.
+    pub fn payload_rejected(&mut self, id: AfcId) {
+        if let Some(chan) = self.chans.get_mut(&id) {
+            chan.stats.payloads_rejected = chan.stats.payloads_rejected.saturating_add(1);
+        }
+    }
+
+    /// Returns a summary of each open channel.
+    pub fn channels(&self) -> impl Iterator<Item = ChannelSummary> + '_ {
+        self.chans.iter().map(|(id, chan)| chan.summary(*id))
+    }
+
+    /// Returns a summary of channel `id`, if it is open.
+    pub fn channel(&self, id: AfcId) -> Option<ChannelSummary> {
+        self.chans.get(&id).map(|chan| chan.summary(id))
+    }
+
+    /// Handles data for a channel that does not exist.
+    ///
+    /// Data for recently removed channels is dropped unless the
+    /// policy is [`RemovedChannelPolicy::Error`].
+    fn open_removed(&mut self, id: AfcId) -> Result<Opened, AfcError> {
+        self.prune_tombstones();
+        let policy = self.cfg.removed_channel;
+        let tombstone = match (policy, self.tombstones.get_mut(&id)) {
+            (_, None) => return Err(AfcError::ChannelNotFound(id)),
+            (RemovedChannelPolicy::Error, Some(_)) => {
+                return Err(chan_not_found(&self.tombstones, id))
+            }
+            (_, Some(tombstone)) => tombstone,
+        };
+        tombstone.dropped = tombstone.dropped.saturating_add(1);
+        self.stats.removed_channel_drops = self.stats.removed_channel_drops.saturating_add(1);
+        metrics::msgs_dropped("removed_channel", 1);
+        if tombstone.dropped == 1 {
+            // Only log the first message to avoid spamming the
+            // logs.
+            debug!(afc_id = %ShortId::new("afc", id), "dropping data for removed channel");
+        }
+        let notify = policy == RemovedChannelPolicy::DropAndNotify && !tombstone.notified;
+        tombstone.notified |= notify;
+        Ok(Opened::Removed { afc_id: id, notify })
+    }
+
+    /// Forgets channels that were removed longer than
+    /// [`RouterConfig::removed_channel_grace`] ago.
+    fn prune_tombstones(&mut self) {
+        let grace = self.cfg.removed_channel_grace;
+        self.tombstones
+            .retain(|_, t| t.removed_at.elapsed() < grace);
+    }
+
+    /// Tells the peer at `addr` that we dropped its data for
+    /// the removed channel `id`.
+    ///
+    /// If `udp` is true, the nack is sent as a datagram.
+    /// Otherwise, it is not sent to peers that predate it (see
+    /// [`PeerStream::extended`]).
+    #[instrument(skip(self))]
+    pub async fn send_nack(
+        &mut self,
+        addr: SocketAddr,
+        id: AfcId,
+        udp: bool,
+    ) -> Result<(), AfcError> {
+        let msg = Msg::Nack(Nack {
+            version: Version::V1,
+            afc_id: id,
+        });
+        if udp {
+            send_datagram(&self.udp, addr, &mut self.frame, &msg).await?;
+        } else {
+            let ps = self
+                .streams
+                .streams
+                .get_mut(&addr)
+                .ok_or(AfcError::StreamNotFound(addr))?;
+            if !ps.extended {
+                debug!("peer predates nacks, not sending one");
+                return Ok(());
+            }
+            write_msg(&mut ps.stream, &mut self.frame, &msg).await?;
+        }
+        debug!("sent nack");
+        Ok(())
+    }
+
+    /// Creates the [`Close`] that tells the peer that we are
+    /// deleting channel `id`, to be sent with
+    /// [`send_close`][Self::send_close].
+    ///
+    /// It must be created before the daemon removes the
+    /// channel's keys. Returns `None` if the channel's stream is
+    /// not open or its peer predates [`Close`]: a peer that
+    /// misses the close learns about the deletion from
+    /// a [`Nack`] the next time it sends data.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", id)))]
+    pub fn close_msg(&mut self, id: AfcId) -> Result<Option<Close>, AfcError> {
+        let Some(addr) = self.chans.get(&id).and_then(|chan| chan.addr) else {
+            return Ok(None);
+        };
+        let Some(ps) = self.streams.streams.get(&addr) else {
+            debug!(%addr, "stream is not open, not sending close");
+            return Ok(None);
+        };
+        if !ps.extended {
+            debug!(%addr, "peer predates close, not sending it");
+            return Ok(None);
+        }
+        Ok(Some(Close {
+            version: Version::V1,
+            afc_id: id,
+            ciphertext: self.seal_ctrl(id, CLOSE_MARKER)?,
+        }))
+    }
+
+    /// Sends `close`, created by [`close_msg`][Self::close_msg],
+    /// over its channel's stream.
+    #[instrument(skip_all, fields(afc_id = %ShortId::new("afc", close.afc_id)))]
+    pub async fn send_close(&mut self, close: Close) -> Result<(), AfcError> {
+        let Some(addr) = self.chans.get(&close.afc_id).and_then(|chan| chan.addr) else {
+            return Ok(());
+        };
+        let msg = Msg::Close(close);
+        let stream = self
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        write_msg(stream, &mut self.frame, &msg).await?;
+        debug!(%addr, "sent close");
+        Ok(())
+    }
+
+    /// Seals `plaintext` with the key of channel `id` for
+    /// a control message, like [`Close`].
+    fn seal_ctrl(&mut self, id: AfcId, plaintext: &[u8]) -> Result<Vec<u8>, AfcError> {
+        let chan_id = self
+            .chans
+            .get(&id)
+            .ok_or(AfcError::ChannelNotFound(id))?
+            .chan_id;
+        let mut buf = vec![0; Header::PACKED_SIZE + plaintext.len() + Client::<S>::OVERHEAD];
+        let (header, ciphertext) = buf
+            .split_first_chunk_mut()
+            .assume("`buf.len()` >= `Header::PACKED_SIZE`")?;
+        let hdr = self
+            .afc
+            .seal(chan_id, ciphertext, plaintext)
+            .map_err(AfcError::Encryption)?;
+        hdr.encode(header)?;
+        Ok(buf)
+    }
+
+    /// Opens `sealed`, which was sealed by [`seal_ctrl`][Self::seal_ctrl]
+    /// with the key of channel `afc_id`, `chan_id`.
+    ///
+    /// Returns `None` if it was sealed with another key or was
+    /// replayed.
+    fn open_ctrl(
+        &mut self,
+        afc_id: AfcId,
+        chan_id: ChannelId,
+        sealed: &[u8],
+    ) -> Result<Option<Vec<u8>>, AfcError> {
+        let Message { payload, .. } = Message::try_parse(sealed)?;
+        let Payload::Data(ciphertext) = payload else {
+            return Ok(None);
+        };
+        let len = ciphertext
+            .len()
+            .checked_sub(Client::<S>::OVERHEAD)
+            .ok_or(AfcError::PayloadTooSmall)?;
+        let mut plaintext = vec![0; len];
+        let (label, seq) = self
+            .afc
+            .open(chan_id.node_id(), &mut plaintext, ciphertext)
+            .map_err(AfcError::Decryption)?;
+        if label != chan_id.label() {
+            return Ok(None);
+        }
+        let chan = self.chans.get_mut(&afc_id).assume("channel should exist")?;
+        if chan.replay.insert(seq.to_u64()) == Check::Replayed {
+            return Ok(None);
+        }
+        Ok(Some(plaintext))
+    }
+
+    /// Handles a [`Close`] received from `addr` by removing the
+    /// channel.
+    ///
+    /// Returns whether the channel was removed. Closes received
+    /// over a stream that the channel does not use, or that
+    /// were not sealed with the channel's key, are ignored so
+    /// that other peers cannot delete it.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", close.afc_id)))]
+    pub async fn handle_close(&mut self, addr: SocketAddr, close: Close) -> Result<bool, AfcError> {
+        self.check_version(close.version)?;
+        let Some(chan) = self.chans.get(&close.afc_id) else {
+            debug!("ignoring close for unknown channel");
+            return Ok(false);
+        };
+        if chan.addr != Some(addr) {
+            warn!(chan_addr = %FmtOr(chan.addr, "unresolved"), "ignoring close from another stream");
+            return Ok(false);
+        }
+        let chan_id = chan.chan_id;
+        match self.open_ctrl(close.afc_id, chan_id, &close.ciphertext) {
+            Ok(Some(plaintext)) if plaintext == CLOSE_MARKER => {}
+            Ok(_) => {
+                warn!("ignoring unauthentic close");
+                return Ok(false);
+            }
+            Err(err) => {
+                warn!(%err, "ignoring close that could not be opened");
+                return Ok(false);
+            }
+        }
+        info!("peer deleted channel");
+        self.remove_channel(close.afc_id).await;
+        Ok(true)
+    }
+
+    /// Handles a [`Nack`] received from `addr`.
+    ///
+    /// Returns the channel that the peer removed, if any.
+    /// Nacks are not authenticated, so the ones for unknown
+    /// channels, or that did not arrive over the channel's
+    /// stream or from its UDP peer, are ignored.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", nack.afc_id)))]
+    pub fn handle_nack(&mut self, addr: SocketAddr, nack: Nack) -> Result<Option<AfcId>, AfcError> {
+        self.check_version(nack.version)?;
+        let Some(chan) = self.chans.get(&nack.afc_id) else {
+            debug!("ignoring nack for unknown channel");
+            return Ok(None);
+        };
+        if chan.addr != Some(addr) && chan.udp != Some(addr) {
+            warn!(chan_addr = %FmtOr(chan.addr, "unresolved"), "ignoring nack from another peer");
+            return Ok(None);
+        }
+        debug!("peer removed channel");
+        Ok(Some(nack.afc_id))
+    }
+
+    /// Answers a [`Msg::Ping`] received from `addr`.
+    ///
+    /// Pings for unknown channels are ignored. If `udp` is
+    /// true, the pong is sent as a datagram.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", ping.afc_id)))]
+    pub async fn handle_ping(
+        &mut self,
+        addr: SocketAddr,
+        ping: Ping,
+        udp: bool,
+    ) -> Result<(), AfcError> {
+        self.check_version(ping.version)?;
+        if !self.chans.contains_key(&ping.afc_id) {
+            debug!("ignoring ping for unknown channel");
+            return Ok(());
+        }
+        let msg = Msg::Pong(ping);
+        if udp {
+            send_datagram(&self.udp, addr, &mut self.frame, &msg).await?;
+        } else {
+            let stream = self
+                .streams
+                .get_mut(&addr)
+                .ok_or(AfcError::StreamNotFound(addr))?;
+            write_msg(stream, &mut self.frame, &msg).await?;
+        }
+        debug!("sent pong");
+        Ok(())
+    }
+
+    /// Handles a [`Msg::Pong`] received from `addr`.
+    ///
+    /// Pongs that do not answer the channel's outstanding ping
+    /// are ignored.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", pong.afc_id)))]
+    pub fn handle_pong(&mut self, addr: SocketAddr, pong: Ping) -> Result<(), AfcError> {
+        self.check_version(pong.version)?;
+        let Some(chan) = self.chans.get_mut(&pong.afc_id) else {
+            debug!("ignoring pong for unknown channel");
+            return Ok(());
+        };
+        let Some(probe) = &mut chan.probe else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let Some(rtt) = probe.pong(now, pong.nonce) else {
+            debug!("ignoring unexpected pong");
+            return Ok(());
+        };
+        debug!(?rtt, "received pong");
+        chan.stats.ponged(rtt);
+        if let Some(liveness) = probe.heard(now) {
+            info!(?liveness, "channel liveness changed");
+            chan.stats.liveness = liveness;
+            self.liveness.push((pong.afc_id, liveness));
+        }
+        Ok(())
+    }
+
+    /// Reports whether the peer's flow control window allows
+    /// sending data over channel `id`.
+    ///
+    /// It is `true` for unknown channels, so that sending fails
+    /// for the right reason.
+    pub fn window_open(&self, id: AfcId) -> bool {
+        self.chans
+            .get(&id)
+            .map_or(true, |chan| chan.flow.can_send())
+    }
+
+    /// Returns how long sending data waits for the peer's flow
+    /// control window to open.
+    pub fn window_max_wait(&self) -> Duration {
+        self.cfg
+            .flow_control
+            .map_or(FlowControl::DEFAULT_MAX_WAIT, |fc| fc.max_wait)
+    }
+
+    /// Records that `n` messages received over channel `id` are
+    /// waiting to be read.
+    ///
+    /// See [`FlowControl`].
+    pub fn set_queued(&mut self, id: AfcId, n: usize) {
+        let Some(fc) = &self.cfg.flow_control else {
+            return;
+        };
+        if let Some(chan) = self.chans.get_mut(&id) {
+            chan.flow.set_queued(u64::try_from(n).unwrap_or(u64::MAX));
+            if chan.flow.is_due(fc.window.max(1)) {
+                self.windows.insert(id);
+            }
+        }
+    }
+
+    /// Advertises the flow control windows that are due.
+    ///
+    /// A window is due once it grew by half since it was last
+    /// advertised.
+    ///
+    /// Windows are sent over each channel's transport. Unsent
+    /// windows are made up for by the next one.
+    #[instrument(skip_all)]
+    pub async fn send_windows(&mut self) -> Result<(), AfcError> {
+        let Some(fc) = self.cfg.flow_control else {
+            return Ok(());
+        };
+        let Self {
+            chans,
+            streams,
+            udp,
+            frame,
+            windows,
+            ..
+        } = self;
+        for id in mem::take(windows) {
+            let Some(chan) = chans.get_mut(&id) else {
+                continue;
+            };
+            let Some(limit) = chan.flow.advertise(fc.window.max(1)) else {
+                continue;
+            };
+            let msg = Msg::Window(Window {
+                version: Version::V1,
+                afc_id: id,
+                limit,
+            });
+            let result = match chan.udp {
+                Some(addr) => send_datagram(udp, addr, frame, &msg).await.map(|_| ()),
+                None => {
+                    frame.encode(&msg)?;
+                    write_chan(streams, &chan.net_id, &mut chan.addr, frame.finish()?).await
+                }
+            };
+            match result {
+                Ok(()) => debug!(afc_id = %ShortId::new("afc", id), limit, "sent window"),
+                Err(err) => {
+                    debug!(afc_id = %ShortId::new("afc", id), %err, "unable to send window")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a [`Msg::Window`] received from `addr`.
+    ///
+    /// Windows for unknown channels, or received from an address
+    /// that the channel does not use, are ignored.
+    #[instrument(skip_all, fields(%addr, afc_id = %ShortId::new("afc", window.afc_id)))]
+    pub fn handle_window(
+        &mut self,
+        addr: SocketAddr,
+        window: Window,
+        udp: bool,
+    ) -> Result<(), AfcError> {
+        self.check_version(window.version)?;
+        let Some(chan) = self.chans.get_mut(&window.afc_id) else {
+            debug!("ignoring window for unknown channel");
+            return Ok(());
+        };
+        let from = if udp { chan.udp } else { chan.addr };
+        if from != Some(addr) {
+            warn!("ignoring window from another address");
+            return Ok(());
+        }
+        debug!(limit = window.limit, "received window");
+        chan.flow.limit(window.limit);
+        Ok(())
+    }
+}
+
+impl<S> fmt::Debug for Afc<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("listener", &self.listener)
+            .field("streams", &self.streams)
+            .field("chans", &self.chans)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Setup the Aranya Client's read side of the AFC channel keys shared memory.
+///
+/// The shared memory is POSIX shared memory, so this is only
+/// supported on unix targets. See the crate's "Platform support"
+/// docs.
+pub(super) fn setup_afc_shm(shm_path: &Path, max_chans: usize) -> Result<ReadState<CS>, AfcError> {
+    debug!(?shm_path, "setting up afc shm read side");
+
+    let Some(path) = shm_path.to_str() else {
+        return Err(anyhow!("unable to convert shm path to string").into());
+    };
+    let path = ShmPathBuf::from_str(path).map_err(AfcError::ShmPathParse)?;
+    let read = ReadState::open(&path, Flag::OpenOnly, Mode::ReadWrite, max_chans)
+        .map_err(Into::into)
+        .map_err(AfcError::ShmReadState)?;
+    Ok(read)
+}
+
+/// Verifies that the daemon's shared memory layout matches
+/// ours.
+///
+/// This must be checked before opening the shared memory.
+pub(super) fn check_shm_layout(daemon: &ShmLayout, max_chans: usize) -> Result<(), AfcError> {
+    let diffs = daemon.mismatches(&ShmLayout::current(max_chans));
+    if diffs.is_empty() {
+        Ok(())
+    } else {
+        error!(?diffs, "shared memory layout mismatch");
+        Err(AfcError::ShmLayoutMismatch(diffs))
+    }
+}
+
+/// A set of TCP streams, keyed by the remote peer's address.
+#[derive(Debug)]
+struct TcpStreams {
+    /// Our device ID, sent to peers in [`Hello`].
+    device_id: DeviceId,
+    /// How peers are reached.
+    net: Net,
+    streams: IndexMap<SocketAddr, PeerStream>,
+    /// Peer reputations.
+    rep: Reputations,
+    /// Cached DNS lookups.
+    dns: DnsCache,
+    /// Wraps streams in TLS, if enabled.
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    /// Woken when a stream is added to an empty set.
+    ///
+    /// Without this, [`next`][Self::next] would never be polled
+    /// again after returning [`Poll::Pending`] for an empty set
+    /// since there are no streams to register interest with.
+    waker: Option<Waker>,
+    /// Streams that were closed, but not yet reported by
+    /// [`next`][Self::next], and why.
+    closed: VecDeque<(SocketAddr, DisconnectReason)>,
+    /// Detects half-open streams, if enabled.
+    timeouts: Option<StreamTimeouts>,
+    /// See [`RouterConfig::handshake_timeout`].
+    handshake_timeout: Duration,
+    /// The port that we accept streams on, if we do, which we
+    /// advertise in [`Hello`].
+    port: Option<u16>,
+    /// See [`RouterConfig::connect_attempt_delay`].
+    connect_delay: Option<Duration>,
+    /// See [`RouterConfig::strict`].
+    strict: bool,
+    /// Session resumption tickets.
+    tickets: Tickets,
+}
+
+impl TcpStreams {
+    fn new(
+        device_id: DeviceId,
+        net: Net,
+        rep: Reputations,
+        dns: DnsCache,
+        timeouts: Option<StreamTimeouts>,
+        handshake_timeout: Duration,
+        port: Option<u16>,
+        connect_delay: Option<Duration>,
+        strict: bool,
+        #[cfg(feature = "tls")] tls: Option<TlsConfig>,
+    ) -> Self {
+        Self {
+            device_id,
+            net,
+            streams: IndexMap::new(),
+            rep,
+            dns,
+            #[cfg(feature = "tls")]
+            tls,
+            waker: None,
+            closed: VecDeque::new(),
+            timeouts,
+            handshake_timeout,
+            port,
+            connect_delay,
+            strict,
+            tickets: Tickets::default(),
+        }
+    }
+
+    /// Records that something was read from the stream at
+    /// `addr`, which proves that it is still open.
+    fn heard(&mut self, addr: SocketAddr) {
+        if let Some(stream) = self.streams.get_mut(&addr) {
+            stream.last_heard = Instant::now();
+            stream.pinged = None;
+        }
+    }
+
+    /// Returns when the peer of the stream must have identified
+    /// itself, if it has not yet.
+    ///
+    /// Only incoming streams have a deadline, until data
+    /// received over them is authenticated. Streams with
+    /// a grace period (see [`expire`][Self::expire]) are
+    /// closed when it ends instead.
+    fn handshake_deadline(&self, s: &PeerStream) -> Option<Instant> {
+        (!s.outbound && s.peer.is_none() && !s.authenticated && s.grace.is_none())
+            .then(|| s.opened + self.handshake_timeout)
+    }
+
+    /// Returns when [`reap`][Self::reap] or
+    /// [`expire`][Self::expire] next has something to do, if
+    /// ever.
+    fn next_reap(&self) -> Option<Instant> {
+        let grace = self
+            .streams
+            .values()
+            .filter_map(|s| s.grace.or_else(|| self.handshake_deadline(s)))
+            .min();
+        let Some(t) = self.timeouts else {
+            return grace;
+        };
+        self.streams
+            .values()
+            .map(|s| match s.pinged {
+                Some(sent) => sent + t.response_timeout,
+                None => s.last_heard + t.idle_timeout,
+            })
+            .chain(grace)
+            .min()
+    }
+
+    /// Removes the streams whose peer did not identify itself
+    /// within [`RouterConfig::handshake_timeout`] or did not
+    /// downgrade within its grace period.
+    ///
+    /// Returns the removed streams.
+    fn expire(&mut self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let timed_out = self
+            .streams
+            .iter()
+            .filter(|(_, s)| {
+                self.handshake_deadline(s)
+                    .is_some_and(|deadline| now >= deadline)
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in &timed_out {
+            warn!(%addr, "peer did not identify itself in time, closing stream");
+            self.remove(addr, DisconnectReason::TimedOut);
+        }
+
+        let mut expired = self
+            .streams
+            .iter()
+            .filter(|(_, s)| s.grace.is_some_and(|deadline| now >= deadline))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return timed_out;
+        }
+        let goodbye = goodbye_frame(GoodbyeReason::UnsupportedVersion {
+            supported: SUPPORTED_VERSIONS.to_vec(),
+        });
+        for addr in &expired {
+            warn!(%addr, "peer did not downgrade in time, closing stream");
+            // Closing the stream must not wait on the peer.
+            if let (Ok(frame), Some(stream)) = (&goodbye, self.get_mut(addr)) {
+                if let Err(err) = stream.try_write_frame(frame) {
+                    debug!(%addr, %err, "unable to say goodbye");
+                }
+            }
+            self.remove(addr, DisconnectReason::VersionMismatch);
+        }
+        expired.extend(timed_out);
+        expired
+    }
+
+    /// Pings idle streams and removes the ones that did not
+    /// answer in time.
+    ///
+    /// Returns the removed streams.
+    async fn reap(&mut self) -> Vec<SocketAddr> {
+        let Some(t) = self.timeouts else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut frame = FrameBuf::default();
+        let mut dead = Vec::new();
+        for (addr, s) in self.streams.iter_mut() {
+            match s.pinged {
+                Some(sent) if now >= sent + t.response_timeout => {
+                    warn!(%addr, "stream did not answer ping, closing it");
+                    dead.push(*addr);
+                }
+                // Peers that predate stream pings cannot answer
+                // them.
+                None if now >= s.last_heard + t.idle_timeout && !s.extended => {
+                    warn!(%addr, "stream is idle, closing it");
+                    dead.push(*addr);
+                }
+                None if now >= s.last_heard + t.idle_timeout => {
+                    let ping = write_msg(&mut s.stream, &mut frame, &Msg::StreamPing);
+                    match timeout(t.response_timeout, ping).await {
+                        Ok(Ok(_)) => {
+                            debug!(%addr, "sent stream ping");
+                            s.pinged = Some(now);
+                        }
+                        Ok(Err(err)) => {
+                            warn!(%addr, %err, "unable to ping stream, closing it");
+                            dead.push(*addr);
+                        }
+                        Err(_) => {
+                            warn!(%addr, "timed out pinging stream, closing it");
+                            dead.push(*addr);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for addr in &dead {
+            self.remove(addr, DisconnectReason::TimedOut);
+        }
+        dead
+    }
+
+    /// Removes the stream at `addr`, which was closed because
+    /// of `reason`.
+    ///
+    /// Unless we were draining the stream, its closure is
+    /// reported by [`next`][Self::next].
+    fn remove(&mut self, addr: &SocketAddr, reason: DisconnectReason) {
+        if let Some(stream) = self.streams.swap_remove(addr) {
+            if !stream.draining {
+                self.closed.push_back((*addr, reason));
+            }
+        }
+    }
+
+    /// Reports whether streams are wrapped in TLS.
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Returns a future that wraps a newly connected
+    /// (`outbound`) or accepted stream with the peer at `addr`
+    /// in TLS, if enabled.
+    ///
+    /// The future does not borrow `self`, so incoming
+    /// handshakes can run on their own tasks. It fails if the
+    /// handshake takes longer than
+    /// [`RouterConfig::handshake_timeout`]. Pass its result to
+    /// [`handshake_done`][Self::handshake_done].
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn handshake(
+        &self,
+        conn: Conn,
+        addr: SocketAddr,
+        outbound: bool,
+    ) -> impl Future<Output = Result<Stream, AfcError>> + Send + 'static {
+        if let Conn::Tcp(stream) = &conn {
+            self.set_keepalive(stream, addr);
+        }
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        #[cfg(feature = "tls")]
+        let handshake_timeout = self.handshake_timeout;
+        async move {
+            let stream = match conn {
+                Conn::Tcp(stream) => stream,
+                // TLS is rejected when the router uses a custom
+                // transport.
+                Conn::Custom(conn) => return Ok(Stream::custom(conn)),
+                // TLS is rejected when the router is bound to
+                // a simulated network.
+                #[cfg(feature = "sim")]
+                Conn::Sim(stream) => return Ok(Stream::sim(stream)),
+            };
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                let handshake = async {
+                    if outbound {
+                        tls.connect(stream, addr).await
+                    } else {
+                        tls.accept(stream).await
+                    }
+                };
+                let stream = timeout(handshake_timeout, handshake)
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+                    .map_err(AfcError::Tls)?;
+                debug!(%addr, "completed TLS handshake");
+                return Ok(Stream::tls(stream));
+            }
+            Ok(Stream::tcp(stream))
+        }
+    }
+
+    /// Sets TCP keepalive on `stream` with the peer at `addr`
+    /// per [`RouterConfig::stream_timeouts`].
+    fn set_keepalive(&self, stream: &TcpStream, addr: SocketAddr) {
+        if let Some(t) = &self.timeouts {
+            if let Err(err) = set_tcp_keepalive(stream, t) {
+                warn!(%addr, %err, "unable to set TCP keepalive");
+            }
+        }
+    }
+
+    /// Handles the result of a [`handshake`][Self::handshake]
+    /// with the peer at `addr`.
+    ///
+    /// Failed handshakes count against the peer's reputation.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn handshake_done<T>(
+        &mut self,
+        addr: SocketAddr,
+        result: Result<T, AfcError>,
+    ) -> Result<T, AfcError> {
+        #[cfg(feature = "tls")]
+        if matches!(result, Err(AfcError::Tls(_))) {
+            self.rep.handshake_failed(addr.ip());
+        }
+        result
+    }
+
+    /// Gets or opens a stream with `peer`.
+    ///
+    /// If the stream is draining, the stream that replaced it is
+    /// used instead.
+    async fn get_or_open(&mut self, peer: (SocketAddr, &str)) -> Result<&mut Stream, AfcError> {
+        let (addr, host) = peer;
+        let addr = self.survivor(addr);
+        if !self.streams.contains_key(&addr) {
+            debug!("opening new stream");
+
+            if let Some(retry_in) = self.rep.retry_in(addr.ip()) {
+                return Err(AfcError::Backoff { addr, retry_in });
+            }
+            let addrs = self.dns.resolve(host).await?;
+            let rep = &mut self.rep;
+            let result = self
+                .net
+                .race(&addrs, self.connect_delay, |addr| {
+                    rep.connect_failed(addr.ip())
+                })
+                .await;
+            let stream = match result {
+                Ok((stream, chosen)) => {
+                    self.rep.connect_succeeded(chosen.ip());
+                    info!(%chosen, "connected to peer");
+                    stream
+                }
+                Err(err) => {
+                    self.dns.evict(host);
+                    return Err(AfcError::StreamConnect(err));
+                }
+            };
+            let result = self.handshake(stream, addr, true).await;
+            let stream = self.handshake_done(addr, result)?;
+
+            let prev_len = self.streams.len();
+            self.streams.insert(addr, PeerStream::new(stream, true));
+            debug!(len = prev_len + 1, "inserted stream");
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+        self.greet(addr).await?;
+        Ok(self.get_mut(&addr).assume("stream should exist")?)
+    }
+
+    /// Gets or opens a stream with `peer`.
+    async fn try_get_or_open(
+        &mut self,
+        peer: (Option<SocketAddr>, &str),
+    ) -> Result<&mut Stream, AfcError> {
+        let (addr, host) = peer;
+        if let Some(addr) = addr {
+            self.get_or_open((addr, host)).await
+        } else {
+            self.connect(host).await
+        }
+    }
+
+    /// Opens a new stream with `peer`.
+    async fn connect(&mut self, peer: &str) -> Result<&mut Stream, AfcError> {
+        debug!("opening new stream");
+
+        let stream = self.dial(peer).await?;
+        debug!(addr = %TryFmt(stream.peer_addr()), "connected to peer");
+
+        let addr = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
+        let (_, new) = self.insert(stream, true)?;
+        if let Some(mut stream) = new {
+            // Reuse the existing TCP stream.
+            if let Err(err) = stream.shutdown().await {
+                warn!(?err, "shutdown");
+            }
+        }
+        self.greet(addr).await?;
+        Ok(self.get_mut(&addr).assume("stream should exist")?)
+    }
+
+    /// Connects to one of the addresses `peer` resolves to,
+    /// Happy Eyeballs-style (see
+    /// [`RouterConfig::connect_attempt_delay`]).
+    ///
+    /// Addresses that we are backing off from are skipped, as
+    /// are addresses whose TLS handshake fails. If no address
+    /// can be reached, `peer` is evicted from the DNS cache.
+    async fn dial(&mut self, peer: &str) -> Result<Stream, AfcError> {
+        let mut last_err = None;
+        let mut candidates = Vec::new();
+        for &addr in self.dns.resolve(peer).await?.iter() {
+            if let Some(retry_in) = self.rep.retry_in(addr.ip()) {
+                debug!(%addr, ?retry_in, "skipping peer address");
+                last_err = Some(AfcError::Backoff { addr, retry_in });
+                continue;
+            }
+            candidates.push(addr);
+        }
+        while !candidates.is_empty() {
+            let rep = &mut self.rep;
+            let result = self
+                .net
+                .race(&candidates, self.connect_delay, |addr| {
+                    rep.connect_failed(addr.ip())
+                })
+                .await;
+            let (stream, addr) = match result {
+                Ok(v) => v,
+                Err(err) => {
+                    // Every candidate failed.
+                    last_err = Some(AfcError::StreamConnect(err));
+                    break;
+                }
+            };
+            self.rep.connect_succeeded(addr.ip());
+            info!(%addr, "connected to peer address");
+            let result = self.handshake(stream, addr, true).await;
+            match self.handshake_done(addr, result) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!(%addr, %err, "unable to complete handshake with peer address");
+                    last_err = Some(err);
+                    candidates.retain(|&a| a != addr);
+                }
+            }
+        }
+        self.dns.evict(peer);
+        Err(last_err.unwrap_or_else(|| {
+            AfcError::DnsLookup(io::Error::new(
+                io::ErrorKind::NotFound,
+                "peer did not resolve to any addresses",
+            ))
+        }))
+    }
+
+    /// Adds a stream, returning an exclusive reference to it.
+    ///
+    /// `outbound` reports whether we opened the stream.
+    ///
+    /// It refuses to clobber an existing stream. If a stream
+    /// already exists, it returns the existing stream and
+    /// `Some(stream)`, or an error in strict mode.
+    fn insert(
+        &mut self,
+        stream: Stream,
+        outbound: bool,
+    ) -> Result<(&mut PeerStream, Option<Stream>), AfcError> {
+        let addr = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
+        let prev_len = self.streams.len();
+        let (stream, dupe) = match self.streams.entry(addr) {
+            map::Entry::Occupied(v) => {
+                if self.strict {
+                    error!(%addr, "duplicate stream");
+                    return Err(AfcError::DuplicateStream(addr));
+                }
+                warn!(%addr, "duplicate stream");
+                (v.into_mut(), Some(stream))
+            }
+            map::Entry::Vacant(v) => {
+                let stream = v.insert(PeerStream::new(stream, outbound));
+                debug!(len = prev_len + 1, "inserted stream");
+                if let Some(waker) = self.waker.take() {
+                    waker.wake();
+                }
+                (stream, None)
+            }
+        };
+        Ok((stream, dupe))
+    }
+
+    /// Sends our [`Hello`] over the stream, if we haven't
+    /// already.
+    ///
+    /// If we opened the stream and hold a session resumption
+    /// ticket for it, the stream keeps the ticket until the
+    /// peer challenges us to present it (see
+    /// [`Afc::handle_challenge`]).
+    async fn greet(&mut self, addr: SocketAddr) -> Result<(), AfcError> {
+        let stream = self
+            .streams
+            .get_mut(&addr)
+            .ok_or(AfcError::StreamNotFound(addr))?;
+        if stream.outbound && !stream.greeted {
+            if let Some(token) = self.tickets.take(&addr, Instant::now()) {
+                stream.token = Some(token);
+            }
         }
+        stream.greet(self.device_id, self.port).await
+    }
 
-        let len = u32::from_le_bytes(buf[1]);
-        if len > MAX_MSG_SIZE {
-            error!(got = %len, expected = %MAX_MSG_SIZE, "msg size too large");
-            return Err(AfcError::MsgTooLarge {
-                got: len.try_into().unwrap_or(usize::MAX),
-                max: MAX_MSG_SIZE.try_into().unwrap_or(usize::MAX),
+    /// Returns the number of incoming streams whose peer has
+    /// not sent [`Hello`].
+    fn pending_handshakes(&self) -> usize {
+        self.streams
+            .values()
+            .filter(|s| !s.outbound && s.peer.is_none())
+            .count()
+    }
+
+    /// Reports whether the stream exists and can be written
+    /// to.
+    fn contains(&mut self, addr: &SocketAddr) -> bool {
+        self.streams.get(addr).is_some_and(|s| !s.draining)
+    }
+
+    /// Writes the frames queued on the streams at `addrs`
+    /// concurrently, then flushes them.
+    ///
+    /// Streams that fail are removed. Returns why they failed.
+    async fn write_queued(
+        &mut self,
+        addrs: &BTreeSet<SocketAddr>,
+    ) -> BTreeMap<SocketAddr, io::ErrorKind> {
+        let mut pending = self
+            .streams
+            .iter_mut()
+            .filter(|(addr, _)| addrs.contains(addr))
+            .map(|(addr, s)| (*addr, &mut s.stream))
+            .collect::<Vec<_>>();
+        let mut failed = BTreeMap::new();
+        let write = poll_fn(|cx| {
+            pending.retain_mut(|(addr, stream)| match stream.poll_write_queued(cx) {
+                Poll::Ready(Ok(())) => false,
+                Poll::Ready(Err(err)) => {
+                    failed.insert(*addr, err.kind());
+                    false
+                }
+                Poll::Pending => true,
             });
+            if pending.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        match self.timeouts {
+            // Like `write_chan`, a write that does not complete
+            // means that the peer stopped reading.
+            Some(t) => {
+                if timeout(t.response_timeout, write).await.is_err() {
+                    for (addr, _) in &pending {
+                        failed.insert(*addr, io::ErrorKind::TimedOut);
+                    }
+                }
+            }
+            None => write.await,
         }
-        debug!(%len, "read message length");
+        for (addr, kind) in &failed {
+            debug!(%addr, "removing broken stream");
+            self.remove(addr, DisconnectReason::from_io(*kind));
+        }
+        failed
+    }
 
-        // TODO(eric): Use a cached buffer.
-        let mut buf = vec![0; len as usize];
-        stream
-            .read_exact(&mut buf)
-            .await
-            .map_err(AfcError::StreamRead)?;
-        debug!(%len, "read message bytes");
+    /// Retrieves an exclusive reference to a stream.
+    ///
+    /// Unlike [`get_or_open`][Self::get_or_open], this returns
+    /// draining streams since they still need to be read from.
+    fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut Stream> {
+        self.streams.get_mut(addr).map(|s| &mut s.stream)
+    }
 
-        postcard::from_bytes(&buf).map_err(AfcError::Serde)
+    /// Returns the address of the stream that should be used
+    /// instead of `addr`.
+    ///
+    /// This is `addr` unless the stream is draining and was
+    /// replaced by another stream with the same peer.
+    fn survivor(&self, addr: SocketAddr) -> SocketAddr {
+        let Some(PeerStream {
+            peer: Some(peer),
+            draining: true,
+            ..
+        }) = self.streams.get(&addr)
+        else {
+            return addr;
+        };
+        self.find_peer(*peer, addr).unwrap_or(addr)
     }
 
-    /// Decrypts `data`.
-    #[instrument(skip_all, fields(afc_id = %data.afc_id))]
-    pub fn open_data(&mut self, data: Data) -> Result<(Vec<u8>, AfcId, Label, Seq), AfcError> {
-        debug!(n = data.ciphertext.len(), "decrypting data");
+    /// Finds a verified, non-draining stream with `peer`.
+    fn find_verified(&self, peer: DeviceId) -> Option<SocketAddr> {
+        self.streams
+            .iter()
+            .find(|(_, s)| s.peer == Some(peer) && s.verified && !s.draining)
+            .map(|(addr, _)| *addr)
+    }
 
-        self.check_version(data.version)?;
+    /// Finds a verified, non-draining stream other than `skip`
+    /// with `peer`.
+    ///
+    /// Unverified streams are skipped, since anyone can claim
+    /// to be `peer` in a [`Hello`].
+    fn find_peer(&self, peer: DeviceId, skip: SocketAddr) -> Option<SocketAddr> {
+        self.streams
+            .iter()
+            .find(|(addr, s)| **addr != skip && s.peer == Some(peer) && s.verified && !s.draining)
+            .map(|(addr, _)| *addr)
+    }
 
-        let chan = self
-            .chans
-            .get_mut(&data.afc_id)
-            .ok_or_else(|| AfcError::ChannelNotFound(data.afc_id))?;
-        let chan_id = chan.chan_id;
-        debug!(%chan_id, "found channel");
+    /// Identifies the next readable stream or reports a stream
+    /// that was closed.
+    // The implementation is partially borrowed from Tokio's
+    // `StreamMap`.
+    #[instrument(skip_all)]
+    fn next_ready(&mut self, cx: &mut Context<'_>) -> Result<Poll<Next>, Bug> {
+        if let Some((addr, reason)) = self.closed.pop_front() {
+            return Ok(Poll::Ready(Next::Closed(addr, reason)));
+        }
+        if self.streams.is_empty() {
+            debug!("no streams to check");
+            // Register interest in new streams so that we're
+            // polled again once one is inserted.
+            self.waker = Some(cx.waker().clone());
+            return Ok(Poll::Pending);
+        }
+        // Distribution via % isn't uniform, but it doesn't
+        // matter here.
+        let start = usize::random(&mut Rng) % self.streams.len();
+        let mut idx = start;
+        for _ in 0..self.streams.len() {
+            match self.streams[idx].stream.poll_ready(cx) {
+                Ok(true) => {
+                    let id = *self.streams.get_index(idx).assume("index should exist")?.0;
+                    debug!(%id, "stream is ready");
+                    return Ok(Poll::Ready(Next::Readable(id)));
+                }
+                Err(err) => {
+                    if err.kind() == io::ErrorKind::UnexpectedEof {
+                        debug!(idx, "stream closed by peer");
+                    } else {
+                        error!(?err, idx, "`poll_ready` returned an error");
+                    }
 
-        // Might as well check this first to limit how much work
-        // we do for expired channels.
-        let next_min_seq = chan.next_min_seq()?;
+                    // streams[idx] = streams[streams.len()-1];
+                    if let Some((addr, stream)) = self.streams.swap_remove_index(idx) {
+                        if !stream.draining {
+                            let reason = DisconnectReason::from_io(err.kind());
+                            self.closed.push_back((addr, reason));
+                        }
+                    }
+                    if self.streams.is_empty() {
+                        break;
+                    }
+                    if idx == self.streams.len() {
+                        idx = 0;
+                    } else if idx < start && start <= self.streams.len() {
+                        // Already polled the stream being
+                        // swapped, so ignore it.
+                        idx = idx.wrapping_add(1) % self.streams.len();
+                    }
+                }
+                Ok(false) => {
+                    idx = idx.wrapping_add(1) % self.streams.len();
+                }
+            }
+        }
 
-        let Message { payload, .. } = Message::try_parse(&data.ciphertext)?;
-        let ciphertext = match payload {
-            Payload::Data(v) => v,
-            Payload::Control(_) => bug!("`Data` should not contain control messages"),
-        };
+        debug!(total = self.streams.len(), "no streams ready");
 
-        // TODO(eric): Update `Message` to handle both shared and
-        // exclusive refs so that we can reuse the
-        // `data.ciphertext` allocation.
-        let plaintext_len = ciphertext
-            .len()
-            .checked_sub(Client::<S>::OVERHEAD)
-            .ok_or(AfcError::PayloadTooSmall)?;
-        let mut plaintext = vec![0; plaintext_len];
-        let (label, seq) = self
-            .afc
-            .open(chan_id.node_id(), &mut plaintext, ciphertext)
-            .map_err(AfcError::Decryption)?;
-        debug!(%label, %seq, "decrypted data");
+        if let Some((addr, reason)) = self.closed.pop_front() {
+            return Ok(Poll::Ready(Next::Closed(addr, reason)));
+        }
 
-        if chan_id.label() != label {
-            error!(got = %label, expected = %chan_id.label(), "mismatched labels");
-            bug!("decrypted data with mismatched labels");
+        if self.streams.is_empty() {
+            self.waker = Some(cx.waker().clone());
         }
+        Ok(Poll::Pending)
+    }
+
+    /// Returns a future that identifies the next readable
+    /// stream or reports a stream that was closed.
+    fn next(&mut self) -> NextStream<'_> {
+        NextStream { streams: self }
+    }
+}
+
+/// A TCP stream with a peer.
+#[derive(Debug)]
+struct PeerStream {
+    stream: Stream,
+    /// The peer's device ID, once it has sent [`Hello`].
+    ///
+    /// Peers that predate [`Hello`] never send it, so it is the
+    /// author of the first control message that the daemon
+    /// accepts over the stream instead (see
+    /// [`Afc::verify_peer`]).
+    peer: Option<DeviceId>,
+    /// Did we open the stream?
+    outbound: bool,
+    /// Have we sent [`Hello`]?
+    greeted: bool,
+    /// The peer sent a message that peers that predate
+    /// [`Hello`] do not, so it can be sent them too.
+    ///
+    /// Until then, only [`Msg::Ctrl`] and [`Msg::Data`] are
+    /// sent, and our greeting trails the first frame (see
+    /// [`Stream::set_trailer`]).
+    extended: bool,
+    /// Data received over the stream was authenticated.
+    ///
+    /// Peers that predate [`Hello`] might never identify
+    /// themselves, so this lifts the handshake deadline too.
+    authenticated: bool,
+    /// The stream lost a tie-break (see [`Afc::handle_hello`]).
+    ///
+    /// We no longer write to it, but keep reading from it until
+    /// the peer closes it so that messages already in flight
+    /// are not lost.
+    draining: bool,
+    /// The daemon accepted a control message received over the
+    /// stream from `peer`.
+    ///
+    /// Unlike [`Hello`], which anyone can send, this proves
+    /// that the stream is with `peer`.
+    verified: bool,
+    /// When the stream was opened or accepted.
+    opened: Instant,
+    /// When we last read from the stream.
+    last_heard: Instant,
+    /// When we sent an unanswered [`Msg::StreamPing`], if we
+    /// did.
+    pinged: Option<Instant>,
+    /// The version of the peer's last [`Hello`], if any.
+    version: Option<Version>,
+    /// The port that the peer accepts streams on, according to
+    /// its last [`Hello`].
+    port: Option<u16>,
+    /// The version that we greet the peer with.
+    ///
+    /// It starts out as the newest version that we speak and
+    /// is lowered to the peer's version during negotiation.
+    greeting: Version,
+    /// The version that both peers agreed to use, once they
+    /// have.
+    negotiated: Option<Version>,
+    /// When the stream is closed unless the peer greets us with
+    /// a version that we speak, if we advertised our versions.
+    grace: Option<Instant>,
+    /// The token of the session resumption ticket that we
+    /// present when the peer challenges us, if we opened the
+    /// stream and hold one.
+    token: Option<Token>,
+    /// The unanswered [`Challenge`] that we sent the peer, if
+    /// we issued it a session resumption ticket.
+    challenge: Option<Challenge>,
+}
 
-        if seq < next_min_seq {
-            // TODO(eric): zeroize `plaintext`.
-            return Err(AfcError::MsgReplayed(seq));
+impl PeerStream {
+    fn new(stream: Stream, outbound: bool) -> Self {
+        let greeting = SUPPORTED_VERSIONS.first().copied().unwrap_or(Version::V1);
+        Self {
+            stream,
+            peer: None,
+            outbound,
+            greeted: false,
+            extended: false,
+            authenticated: false,
+            draining: false,
+            verified: false,
+            opened: Instant::now(),
+            last_heard: Instant::now(),
+            pinged: None,
+            version: None,
+            port: None,
+            greeting,
+            negotiated: None,
+            grace: None,
+            token: None,
+            challenge: None,
         }
-        chan.next_min_seq = seq.to_u64().checked_add(1).map(Seq::new);
-        debug!(next = %FmtOr(chan.next_min_seq, "expired"), "min next seq number");
+    }
 
-        Ok((plaintext, data.afc_id, label, seq))
+    /// Sends [`Hello`], if we haven't already.
+    ///
+    /// Peers that predate [`Hello`] cannot decode it, but
+    /// ignore whatever follows a message. So unless the peer is
+    /// [`extended`][Self::extended], the greeting trails the
+    /// next frame that we write instead.
+    async fn greet(&mut self, device_id: DeviceId, port: Option<u16>) -> Result<(), AfcError> {
+        if self.greeted {
+            return Ok(());
+        }
+        let msg = Msg::Hello(Hello {
+            version: self.greeting,
+            device_id,
+            port,
+        });
+        if self.extended {
+            self.stream.set_trailer(Vec::new());
+            write_msg(&mut self.stream, &mut FrameBuf::default(), &msg).await?;
+        } else {
+            self.stream
+                .set_trailer(postcard::to_allocvec(&msg).map_err(AfcError::Serde)?);
+        }
+        self.greeted = true;
+        debug!(%device_id, version = ?self.greeting, "sent hello");
+        Ok(())
     }
+}
 
-    /// Get the local address the AFC server bound to.
-    pub fn local_addr(&self) -> Result<SocketAddr, AfcError> {
-        self.listener.local_addr().map_err(AfcError::RouterAddr)
+/// Reports whether the newer of two streams with the same peer
+/// should survive.
+///
+/// When two devices dial each other at the same time, each ends
+/// up with one stream it opened (outbound) and one that the peer
+/// opened (inbound). Both devices must agree on which stream to
+/// keep, so the stream opened by the device with the lower ID
+/// survives. Otherwise, both streams were opened by the same
+/// device (e.g., it reconnected), so the newer one survives.
+///
+/// `local` and `peer` are the IDs of this device and the peer.
+fn keep_newer<T: Ord>(local: &T, peer: &T, new_outbound: bool, old_outbound: bool) -> bool {
+    if new_outbound == old_outbound {
+        return true;
     }
+    new_outbound == (local < peer)
+}
+
+/// A future that identifies the next readable stream.
+#[derive(Debug)]
+struct NextStream<'a> {
+    streams: &'a mut TcpStreams,
+}
+
+/// The output of [`NextStream`].
+#[derive(Debug)]
+enum Next {
+    /// The stream at the address is ready to be read from.
+    Readable(SocketAddr),
+    /// The stream at the address was closed.
+    Closed(SocketAddr, DisconnectReason),
+}
+
+impl Future for NextStream<'_> {
+    type Output = Result<Next, Bug>;
 
-    /// Get the next Node ID in the sequence.
-    pub async fn get_next_node_id(&mut self) -> Result<NodeId, AfcError> {
-        let node_id = NodeId::new(self.next_node_id);
-        self.next_node_id += 1;
-        Ok(node_id)
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.streams.next_ready(cx) {
+            Ok(v) => v.map(Ok),
+            Err(err) => Poll::Ready(Err(err)),
+        }
     }
+}
 
-    /// Adds a new channel.
+/// An open channel.
+#[derive(Debug)]
+struct Chan {
+    team_id: TeamId,
+    net_id: NetIdentifier,
+    chan_id: ChannelId,
+    /// Used to look up the TCP stream.
     ///
-    /// It is an error if the channel already exists.
-    #[instrument(skip_all, fields(
-        afc_id = %id,
-        %net_id,
-        %team_id,
-        %chan_id,
-        %addr,
-    ))]
-    pub async fn add_channel(
-        &mut self,
-        id: AfcId,
-        net_id: NetIdentifier,
+    /// `None` if `net_id` has not been resolved yet.
+    addr: Option<SocketAddr>,
+    /// The peer's UDP address if data is sent over UDP.
+    udp: Option<SocketAddr>,
+    /// The sequence numbers received recently, used to prevent
+    /// replay attacks.
+    replay: ReplayWindow,
+    /// Recently received idempotency tokens.
+    tokens: TokenWindow,
+    /// Is self-check enabled? See [`RouterConfig::self_check`].
+    self_check: bool,
+    stats: ChannelStats,
+    /// Messages waiting to be resent after a transient failure.
+    retry: Option<Retry>,
+    /// A partially received stream, if any.
+    stream: Option<Reassembly>,
+    /// Limits the rate of messages, if enabled.
+    limiter: Option<RateLimiter>,
+    /// Probes whether the peer is reachable, if enabled.
+    probe: Option<Probe>,
+    expiry: Expiry,
+    /// The priority of data sent without one.
+    ///
+    /// See [`LabelOptions::priority`][crate::LabelOptions::priority].
+    priority: Priority,
+    /// The highest sequence number reserved on disk, if any.
+    ///
+    /// See [`RouterConfig::seq_reservation`].
+    reserved: Option<u64>,
+    metadata: ChannelMetadata,
+    /// See [`FlowControl`].
+    flow: Flow,
+    /// Messages fed with [`Afc::feed_data`] that are waiting to
+    /// be flushed.
+    fed: Vec<Vec<u8>>,
+}
+
+impl Chan {
+    fn new(
+        cfg: &RouterConfig,
         team_id: TeamId,
+        net_id: NetIdentifier,
         chan_id: ChannelId,
-        addr: SocketAddr,
-    ) -> Result<(), AfcError> {
-        debug!("adding channel");
+        addr: Option<SocketAddr>,
+        udp: Option<SocketAddr>,
+        self_check: bool,
+    ) -> Self {
+        let opts = cfg.label_options.get(&chan_id.label());
+        let replay_window = opts
+            .and_then(|o| o.replay_window)
+            .unwrap_or(cfg.replay_window);
+        let rate_limit = opts
+            .and_then(|o| o.rate_limit.as_ref())
+            .or(cfg.rate_limit.as_ref());
+        let keepalive = opts
+            .and_then(|o| o.keepalive.as_ref())
+            .or(cfg.keepalive.as_ref());
+        let expiry = opts.and_then(|o| o.expiry).or(cfg.expiry);
+        Self {
+            team_id,
+            net_id,
+            chan_id,
+            addr,
+            udp,
+            replay: ReplayWindow::new(replay_window),
+            tokens: if cfg.bounded.is_some() {
+                TokenWindow::preallocated()
+            } else {
+                TokenWindow::default()
+            },
+            self_check,
+            stats: ChannelStats::default(),
+            retry: None,
+            stream: None,
+            limiter: rate_limit.map(RateLimiter::new),
+            probe: keepalive.map(Probe::new),
+            expiry: Expiry::new(expiry.unwrap_or_default()),
+            priority: opts.map_or(Priority::Normal, |o| o.priority),
+            reserved: None,
+            metadata: ChannelMetadata::new(),
+            flow: Flow::default(),
+            fed: Vec::new(),
+        }
+    }
+}
 
-        match self.chans.entry(id) {
-            // Reject duplicates because
-            // 1. Channel IDs are globally unique (a
-            //    cryptographically negligible probability of
-            //    collisions). This probably means we're
-            //    processing the same `ctrl` again. It might mean
-            //    that the graph/daemon/whatever is buggy?
-            // 2. It would reset the sequence number, which would
-            //    allow replay attacks.
-            btree_map::Entry::Occupied(_) => {
-                warn!(%id, "duplicate channel ID");
+/// Tracks a channel's [`ChannelExpiry`].
+#[derive(Debug)]
+struct Expiry {
+    policy: ChannelExpiry,
+    /// When the channel was added.
+    created: Instant,
+    /// The number of messages sealed or opened.
+    used: u64,
+    /// Why the channel expired, if it did.
+    expired: Option<ExpiryReason>,
+}
 
-                // Don't return an error, though, since the most
-                // likely cause is that we're processing
-                // a duplicate control message.
-            }
-            btree_map::Entry::Vacant(v) => {
-                v.insert(Chan {
-                    net_id,
-                    chan_id,
-                    // `addr` comes from either `Status::Accept`
-                    // or `send_ctrl`, so use it instead of
-                    // performing a DNS lookup. In both cases we
-                    // likely already have an open TCP stream. If
-                    // we don't, the next operation on the
-                    // channel will perform the DNS lookup
-                    // anyway.
-                    addr,
-                    next_min_seq: Some(Seq::ZERO),
-                });
-            }
+impl Expiry {
+    fn new(policy: ChannelExpiry) -> Self {
+        Self {
+            policy,
+            created: Instant::now(),
+            used: 0,
+            expired: None,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.is_some()
+    }
+
+    /// Returns when the channel reaches its maximum age, unless
+    /// it already expired.
+    fn deadline(&self) -> Option<Instant> {
+        if self.is_expired() {
+            return None;
+        }
+        self.created.checked_add(self.policy.max_age?)
+    }
+
+    /// Expires the channel if it reached either limit.
+    ///
+    /// Returns the reason if the channel just expired.
+    fn check(&mut self, now: Instant) -> Option<ExpiryReason> {
+        if self.is_expired() {
+            return None;
+        }
+        let reason = if self.policy.max_msgs.is_some_and(|max| self.used >= max) {
+            ExpiryReason::MaxMsgs
+        } else if self.deadline().is_some_and(|deadline| now >= deadline) {
+            ExpiryReason::MaxAge
+        } else {
+            return None;
+        };
+        self.expired = Some(reason);
+        Some(reason)
+    }
+
+    /// Counts a message, then checks the limits like
+    /// [`check`][Self::check].
+    fn count(&mut self, now: Instant) -> Option<ExpiryReason> {
+        self.used = self.used.saturating_add(1);
+        self.check(now)
+    }
+}
+
+/// A partially received stream.
+#[derive(Debug, Default)]
+struct Reassembly {
+    /// The index of the next fragment.
+    next: u32,
+    /// The data received so far.
+    buf: Vec<u8>,
+}
+
+/// The result of [`Chan::reassemble`].
+#[derive(Debug)]
+enum Reassembled {
+    /// The stream is not yet complete.
+    Pending,
+    /// The partial stream was dropped.
+    Dropped,
+    /// The stream is complete.
+    Done(Vec<u8>),
+}
+
+/// Messages waiting to be resent over a channel.
+#[derive(Debug, Default)]
+struct Retry {
+    /// The number of failed attempts to resend the messages.
+    attempt: u32,
+    /// When to try again.
+    at: Option<Instant>,
+    /// Encoded frames, the length of their plaintext, and the
+    /// order they were sealed in (see [`pushed`][Self::pushed]).
+    ///
+    /// The first [`high`][Self::high] frames are high-priority
+    /// frames. The rest are in the order they were sent.
+    queue: VecDeque<(Vec<u8>, usize, u64)>,
+    /// The number of high-priority frames at the front of
+    /// `queue`.
+    high: usize,
+    /// The number of frames pushed so far.
+    ///
+    /// Every frame sealed for the channel is pushed while it
+    /// has a retry queue, so the difference between two frames'
+    /// counts is the difference between their sequence numbers.
+    pushed: u64,
+}
+
+impl Retry {
+    /// Queues a frame for channel `id`.
+    ///
+    /// A high-priority frame is queued ahead of the
+    /// normal-priority frames if the peer, whose replay window
+    /// is `window`, would still accept them after it (see
+    /// [`Priority`]).
+    fn push(
+        &mut self,
+        id: AfcId,
+        msg: Vec<u8>,
+        pt_len: usize,
+        priority: Priority,
+        window: u32,
+        cfg: &RouterConfig,
+    ) -> Result<(), AfcError> {
+        let max = cfg.retry_queue_len;
+        if self.queue.len() >= max {
+            warn!(afc_id = %ShortId::new("afc", id), max, "retry queue is full");
+            return Err(AfcError::RetryQueueFull(id));
+        }
+        let n = self.pushed;
+        self.pushed = self.pushed.wrapping_add(1);
+        let window = u64::from(window.min(RouterConfig::MAX_REPLAY_WINDOW));
+        // The oldest normal-priority frame has the lowest
+        // sequence number of those that would be overtaken.
+        let overtake = priority == Priority::High
+            && self
+                .queue
+                .get(self.high)
+                .map_or(true, |&(_, _, oldest)| n.wrapping_sub(oldest) < window);
+        if overtake {
+            self.queue.insert(self.high, (msg, pt_len, n));
+            self.high = self.high.saturating_add(1);
+        } else {
+            self.queue.push_back((msg, pt_len, n));
         }
-        debug!("added channel");
-
         Ok(())
     }
 
-    /// Deletes a channel.
-    #[instrument(skip_all, fields(afc_id = %id))]
-    pub async fn remove_channel(&mut self, id: AfcId) {
-        debug!("removing channel");
+    /// Returns the next frame to resend in `lane`, if any.
+    fn front(&self, lane: Priority) -> Option<&(Vec<u8>, usize, u64)> {
+        match lane {
+            Priority::High if self.high == 0 => None,
+            _ => self.queue.front(),
+        }
+    }
 
-        self.chans.remove(&id);
+    /// Removes the frame returned by [`front`][Self::front].
+    fn pop_front(&mut self) {
+        self.high = self.high.saturating_sub(1);
+        self.queue.pop_front();
     }
-}
 
-impl<S> fmt::Debug for Afc<S> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Router")
-            .field("listener", &self.listener)
-            .field("streams", &self.streams)
-            .field("chans", &self.chans)
-            .field("next_node_id", &self.next_node_id)
-            .finish_non_exhaustive()
+    /// Records a failed attempt and schedules the next one.
+    ///
+    /// Returns how long until the next attempt.
+    fn schedule(&mut self, cfg: &RouterConfig) -> Duration {
+        self.attempt = self.attempt.saturating_add(1);
+        let retry_in = retry_backoff(cfg, self.attempt);
+        self.at = Some(Instant::now() + retry_in);
+        retry_in
     }
 }
 
-/// Setup the Aranya Client's read side of the AFC channel keys shared memory.
-pub(super) fn setup_afc_shm(shm_path: &Path, max_chans: usize) -> Result<ReadState<CS>, AfcError> {
-    debug!(?shm_path, "setting up afc shm read side");
-
-    let Some(path) = shm_path.to_str() else {
-        return Err(anyhow!("unable to convert shm path to string").into());
-    };
-    let path = ShmPathBuf::from_str(path).map_err(AfcError::ShmPathParse)?;
-    let read = ReadState::open(&path, Flag::OpenOnly, Mode::ReadWrite, max_chans)
-        .map_err(Into::into)
-        .map_err(AfcError::ShmReadState)?;
-    Ok(read)
+/// Returns how long to wait before the `attempt`-th attempt to
+/// resend queued messages.
+///
+/// The backoff doubles with each attempt, starting at
+/// [`RouterConfig::reconnect_backoff`] and capped at
+/// [`RouterConfig::max_reconnect_backoff`]. With jitter, a random
+/// duration between half of the backoff and the full backoff is
+/// used so that peers do not reconnect in lockstep.
+fn retry_backoff(cfg: &RouterConfig, attempt: u32) -> Duration {
+    let shift = attempt.saturating_sub(1).min(31);
+    let backoff = cfg
+        .reconnect_backoff
+        .saturating_mul(1u32 << shift)
+        .min(cfg.max_reconnect_backoff);
+    if !cfg.reconnect_jitter {
+        return backoff;
+    }
+    let half = backoff / 2;
+    let nanos = u64::try_from(half.as_nanos()).unwrap_or(u64::MAX);
+    half + Duration::from_nanos(u64::random(&mut Rng) % nanos.saturating_add(1))
 }
 
-/// A set of TCP streams, keyed by the remote peer's address.
+/// A recently removed channel.
 #[derive(Debug)]
-struct TcpStreams {
-    streams: IndexMap<SocketAddr, TcpStream>,
+struct Tombstone {
+    removed_at: Instant,
+    /// The number of messages dropped for the channel.
+    dropped: u64,
+    /// Have we sent the peer a [`Nack`]?
+    notified: bool,
+    /// Did the daemon remove the channel?
+    revoked: bool,
 }
 
-impl TcpStreams {
+impl Tombstone {
     fn new() -> Self {
         Self {
-            streams: IndexMap::new(),
+            removed_at: Instant::now(),
+            dropped: 0,
+            notified: false,
+            revoked: false,
         }
     }
+}
 
-    /// Gets or opens a stream with `peer`.
-    async fn get_or_open(
-        &mut self,
-        peer: (SocketAddr, impl ToSocketAddrs),
-    ) -> Result<&mut TcpStream, AfcError> {
-        let (addr, host) = peer;
-        let prev_len = self.streams.len();
-        match self.streams.entry(addr) {
-            map::Entry::Occupied(v) => Ok(v.into_mut()),
-            map::Entry::Vacant(v) => {
-                debug!("opening new stream");
+/// Returns the error for channel `id`, which is not open.
+fn chan_not_found(tombstones: &BTreeMap<AfcId, Tombstone>, id: AfcId) -> AfcError {
+    if tombstones.get(&id).is_some_and(|t| t.revoked) {
+        AfcError::ChannelRevoked(id)
+    } else {
+        AfcError::ChannelNotFound(id)
+    }
+}
 
-                let stream = TcpStream::connect(host)
-                    .await
-                    .map_err(AfcError::StreamConnect)?;
-                debug!(addr = %TryFmt(stream.peer_addr()), "connected to peer");
+impl Chan {
+    /// Returns the number of messages waiting to be resent.
+    fn queued(&self) -> usize {
+        self.retry.as_ref().map_or(0, |r| r.queue.len())
+    }
 
-                let stream = v.insert(stream);
-                debug!(len = prev_len + 1, "inserted stream");
-                Ok(stream)
-            }
+    /// Returns the channel's persisted state.
+    fn saved(&self, id: AfcId) -> SavedChan {
+        SavedChan {
+            id,
+            team_id: self.team_id,
+            net_id: self.net_id.clone(),
+            node_id: self.chan_id.node_id(),
+            label: self.chan_id.label(),
+            udp: self.udp,
+            self_check: self.self_check,
+            reserved: self.reserved,
         }
     }
 
-    /// Gets or opens a stream with `peer`.
-    async fn try_get_or_open(
-        &mut self,
-        peer: (Option<SocketAddr>, impl ToSocketAddrs),
-    ) -> Result<&mut TcpStream, AfcError> {
-        let (addr, host) = peer;
-        if let Some(addr) = addr {
-            self.get_or_open((addr, host)).await
+    fn summary(&self, id: AfcId) -> ChannelSummary {
+        ChannelSummary {
+            id,
+            peer: self.net_id.clone(),
+            peer_addr: self.udp.or(self.addr),
+            label: self.chan_id.label(),
+            transport: if self.udp.is_some() {
+                Transport::Udp
+            } else {
+                Transport::Tcp
+            },
+            self_check: self.self_check,
+            queued: self.queued(),
+            stats: self.stats,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Appends `data`, a fragment of a stream, to the partial
+    /// stream.
+    ///
+    /// A fragment that does not follow the previous one drops
+    /// the partial stream. If it is the first fragment of a new
+    /// stream, the new stream is started. Otherwise, it is
+    /// ignored. Streams larger than `max` bytes are dropped.
+    fn reassemble(&mut self, fragment: Fragment, data: &[u8], max: usize) -> Reassembled {
+        let mut dropped = false;
+        if self.stream.as_ref().map_or(0, |s| s.next) != fragment.index {
+            dropped = self.stream.take().is_some();
+            if fragment.index != 0 {
+                debug!(index = fragment.index, "ignoring out of order fragment");
+                return if dropped {
+                    Reassembled::Dropped
+                } else {
+                    Reassembled::Pending
+                };
+            }
+        }
+        let stream = self.stream.get_or_insert_with(Reassembly::default);
+        if stream.buf.len().saturating_add(data.len()) > max {
+            warn!(max, "stream is too large, dropping it");
+            self.stream = None;
+            return Reassembled::Dropped;
+        }
+        stream.buf.extend_from_slice(data);
+        stream.next = fragment.index.saturating_add(1);
+        if fragment.last {
+            let buf = self.stream.take().map(|s| s.buf).unwrap_or_default();
+            return Reassembled::Done(buf);
+        }
+        if dropped {
+            Reassembled::Dropped
         } else {
-            self.connect(host).await
+            Reassembled::Pending
         }
     }
+}
 
-    /// Opens a new stream with `peer`.
-    async fn connect(&mut self, peer: impl ToSocketAddrs) -> Result<&mut TcpStream, AfcError> {
-        debug!("opening new stream");
+/// The most recent [`IDEMPOTENCY_WINDOW`] idempotency tokens.
+#[derive(Debug, Default)]
+struct TokenWindow {
+    /// Oldest first.
+    order: VecDeque<IdempotencyToken>,
+    seen: HashSet<IdempotencyToken>,
+}
 
-        let stream = TcpStream::connect(peer)
-            .await
-            .map_err(AfcError::StreamConnect)?;
-        debug!(addr = %TryFmt(stream.peer_addr()), "connected to peer");
+impl TokenWindow {
+    /// Creates a window with room for every token, so that
+    /// inserting never allocates.
+    fn preallocated() -> Self {
+        Self {
+            order: VecDeque::with_capacity(IDEMPOTENCY_WINDOW + 1),
+            seen: HashSet::with_capacity(IDEMPOTENCY_WINDOW + 1),
+        }
+    }
 
-        let (old, new) = self.insert(stream)?;
-        if let Some(mut stream) = new {
-            // Reuse the existing TCP stream.
-            if let Err(err) = stream.shutdown().await {
-                warn!(?err, "shutdown");
+    /// Records `token`, returning `false` if it is already in
+    /// the window.
+    fn insert(&mut self, token: IdempotencyToken) -> bool {
+        if !self.seen.insert(token) {
+            return false;
+        }
+        self.order.push_back(token);
+        if self.order.len() > IDEMPOTENCY_WINDOW {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
             }
         }
-        Ok(old)
+        true
     }
+}
 
-    /// Adds a stream, returning an exclusive reference to it.
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+
+    use aranya_fast_channels::NodeId;
+
+    use super::*;
+
+    /// Golden encoding of `magic || len` with `len = 0x01020304`.
     ///
-    /// It refuses to clobber an existing stream. If a stream
-    /// already exists, it returns the existing stream and
-    /// `Some(stream)`.
-    fn insert(
-        &mut self,
-        stream: TcpStream,
-    ) -> Result<(&mut TcpStream, Option<TcpStream>), AfcError> {
-        let addr = stream.peer_addr().map_err(AfcError::StreamPeerAddr)?;
-        let prev_len = self.streams.len();
-        let (stream, dupe) = match self.streams.entry(addr) {
-            map::Entry::Occupied(v) => {
-                warn!(%addr, "duplicate stream");
-                (v.into_mut(), Some(stream))
-            }
-            map::Entry::Vacant(v) => {
-                let stream = v.insert(stream);
-                debug!(len = prev_len + 1, "inserted stream");
-                (stream, None)
-            }
+    /// This must hold on both little- and big-endian hosts.
+    const GOLDEN_HEADER: [u8; WIRE_HEADER_SIZE] = *b"AFC\0\x04\x03\x02\x01";
+
+    #[test]
+    fn test_wire_header_golden() {
+        assert_eq!(encode_wire_header(0x0102_0304), GOLDEN_HEADER);
+        assert_eq!(
+            decode_wire_header(&GOLDEN_HEADER, u32::MAX).unwrap(),
+            0x0102_0304
+        );
+    }
+
+    #[test]
+    fn test_wire_header_round_trip() {
+        const MAX: u32 = RouterConfig::DEFAULT_MAX_MSG_SIZE;
+        for len in [0, 1, 0xff, 0x100, 0xffff, MAX - 1, MAX] {
+            let got = decode_wire_header(&encode_wire_header(len), MAX).unwrap();
+            assert_eq!(got, len);
+        }
+    }
+
+    #[test]
+    fn test_wire_header_max_msg_size() {
+        let hdr = encode_wire_header(RouterConfig::MIN_MAX_MSG_SIZE + 1);
+        let err = decode_wire_header(&hdr, RouterConfig::MIN_MAX_MSG_SIZE).unwrap_err();
+        assert!(matches!(err, AfcError::MsgTooLarge { .. }), "{err}");
+        decode_wire_header(&hdr, RouterConfig::MIN_MAX_MSG_SIZE + 1).unwrap();
+    }
+
+    #[test]
+    fn test_wire_header_byte_swapped_magic() {
+        // What a big-endian peer would write if it encoded the
+        // magic in its native byte order.
+        let mut hdr = GOLDEN_HEADER;
+        hdr[..4].copy_from_slice(&u32::from_le_bytes(*WIRE_MAGIC).to_be_bytes());
+        let err = decode_wire_header(&hdr, RouterConfig::DEFAULT_MAX_MSG_SIZE).unwrap_err();
+        assert!(matches!(err, AfcError::InvalidMagic(_)), "{err}");
+    }
+
+    #[test]
+    fn test_wire_header_byte_swapped_len() {
+        // What a big-endian peer would write if it encoded `len`
+        // in its native byte order: 1 becomes 2^24.
+        let mut hdr = encode_wire_header(0);
+        hdr[4..].copy_from_slice(&1u32.to_be_bytes());
+        let err = decode_wire_header(&hdr, RouterConfig::DEFAULT_MAX_MSG_SIZE).unwrap_err();
+        assert!(matches!(err, AfcError::MsgTooLarge { .. }), "{err}");
+    }
+
+    #[test]
+    fn test_msg_data_encoding() {
+        let afc_id: AfcId = postcard::from_bytes(&[0x11; 16]).unwrap();
+        let ciphertext = vec![0xaa; 300];
+        let msg = Msg::Data(Data {
+            version: Version::V1,
+            afc_id,
+            ciphertext: ciphertext.clone().into(),
+            enveloped: false,
+        });
+        let buf = postcard::to_allocvec(&msg).unwrap();
+
+        // `Data` is the second variant.
+        assert_eq!(buf[0], 1);
+        // The ciphertext is prefixed with its varint-encoded
+        // length, which does not depend on the host's byte order.
+        let tail = [&[0xac, 0x02][..], &ciphertext].concat();
+        assert!(buf.ends_with(&tail));
+
+        let Msg::Data(got) = postcard::from_bytes(&buf).unwrap() else {
+            panic!("expected `Msg::Data`");
         };
-        Ok((stream, dupe))
+        assert_eq!(got.version, Version::V1);
+        assert_eq!(got.afc_id, afc_id);
+        assert_eq!(*got.ciphertext, ciphertext);
     }
 
-    /// Reports whether the stream exists.
-    fn contains(&mut self, addr: &SocketAddr) -> bool {
-        self.streams.contains_key(addr)
+    #[test]
+    fn test_msg_enveloped_encoding() {
+        // `Enveloped` is appended so that peers that predate
+        // extensions can still read `Data`.
+        let afc_id: AfcId = postcard::from_bytes(&[0x11; 16]).unwrap();
+        let mut buf = postcard::to_allocvec(&Msg::Enveloped(Data {
+            version: Version::V1,
+            afc_id,
+            ciphertext: vec![0xaa; 3].into(),
+            enveloped: true,
+        }))
+        .unwrap();
+        assert_eq!(buf[0], 15);
+        let Msg::Enveloped(got) = decode_msg(&mut buf, 0).unwrap() else {
+            panic!("expected `Msg::Enveloped`");
+        };
+        assert!(got.enveloped);
+        assert_eq!(*got.ciphertext, [0xaa; 3]);
     }
 
-    /// Retrieves an exclusive reference to a stream.
-    fn get_mut(&mut self, addr: &SocketAddr) -> Option<&mut TcpStream> {
-        self.streams.get_mut(addr)
+    #[tokio::test]
+    async fn test_bind_fallback_ports() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        // Reserve a free port without listening on it so that
+        // nothing else can take it before the router binds it.
+        // The router can still bind it since both sockets reuse
+        // the address.
+        let probe = tokio::net::TcpSocket::new_v4().unwrap();
+        probe.set_reuseaddr(true).unwrap();
+        probe.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let free_port = probe.local_addr().unwrap().port();
+
+        let listener = bind(("127.0.0.1", taken_port), &[taken_port, free_port])
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), free_port);
+        drop(probe);
+
+        let err = bind(("127.0.0.1", taken_port), &[taken_port])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AfcError::Bind(_)), "{err}");
     }
 
-    /// Identifies the next readable stream.
-    // The implementation is partially borrowed from Tokio's
-    // `StreamMap`.
-    #[instrument(skip_all)]
-    fn next_ready(&mut self, cx: &mut Context<'_>) -> Result<Poll<SocketAddr>, Bug> {
-        if self.streams.is_empty() {
-            debug!("no streams to check");
-            return Ok(Poll::Pending);
-        }
-        // Distribution via % isn't uniform, but it doesn't
-        // matter here.
-        let start = usize::random(&mut Rng) % self.streams.len();
-        let mut idx = start;
-        for _ in 0..self.streams.len() {
-            match stream_is_ready(cx, &self.streams[idx]) {
-                Ok(true) => {
-                    let id = *self.streams.get_index(idx).assume("index should exist")?.0;
-                    debug!(%id, "stream is ready");
-                    return Ok(Poll::Ready(id));
-                }
-                Err(err) => {
-                    error!(?err, idx, "`stream_is_ready` returned an error");
+    #[test]
+    fn test_check_shm_layout() {
+        check_shm_layout(&ShmLayout::current(100), 100).unwrap();
 
-                    // streams[idx] = streams[streams.len()-1];
-                    self.streams.swap_remove_index(idx);
-                    if idx == self.streams.len() {
-                        idx = 0;
-                    } else if idx < start && start <= self.streams.len() {
-                        // Already polled the stream being
-                        // swapped, so ignore it.
-                        idx = idx.wrapping_add(1) % self.streams.len();
-                    }
-                }
-                Ok(false) => {
-                    idx = idx.wrapping_add(1) % self.streams.len();
-                }
-            }
-        }
+        let daemon = ShmLayout {
+            little_endian: !cfg!(target_endian = "little"),
+            ..ShmLayout::current(100)
+        };
+        let err = check_shm_layout(&daemon, 10).unwrap_err();
+        let AfcError::ShmLayoutMismatch(diffs) = &err else {
+            panic!("expected `ShmLayoutMismatch`: {err}");
+        };
+        assert_eq!(diffs.len(), 2, "{err}");
+        assert!(diffs[0].starts_with("little_endian"), "{err}");
+        assert_eq!(diffs[1], "max_chans: 100 != 10", "{err}");
+    }
 
-        debug!(total = self.streams.len(), "no streams ready");
+    #[tokio::test]
+    async fn test_starts_with_magic() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
 
-        Ok(Poll::Pending)
+        // The magic arrives in two parts.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let (got, ()) = tokio::join!(starts_with_magic(&server), async {
+            client.write_all(&WIRE_MAGIC[..2]).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            client.write_all(&WIRE_MAGIC[2..]).await.unwrap();
+        });
+        assert!(got.unwrap());
+        // Nothing was read.
+        let mut buf = [0; WIRE_MAGIC.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, WIRE_MAGIC);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(!starts_with_magic(&server).await.unwrap());
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        drop(client);
+        let err = starts_with_magic(&server).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
     }
 
-    /// Returns a future that identifies the next readable
-    /// stream.
-    fn next(&mut self) -> NextStream<'_> {
-        NextStream { streams: self }
+    #[test]
+    fn test_msg_busy_encoding() {
+        // `Busy` is appended so that the other variants keep
+        // their encodings.
+        let buf = postcard::to_allocvec(&Msg::Busy).unwrap();
+        assert_eq!(buf, [3]);
     }
-}
 
-/// A future that identifies the next readable stream.
-#[derive(Debug)]
-struct NextStream<'a> {
-    streams: &'a mut TcpStreams,
-}
+    #[test]
+    fn test_common_version() {
+        assert_eq!(common_version(&[Version::V1]), Some(Version::V1));
+        assert_eq!(
+            common_version(SUPPORTED_VERSIONS),
+            SUPPORTED_VERSIONS.first().copied()
+        );
+        assert_eq!(common_version(&[]), None);
+    }
 
-impl Future for NextStream<'_> {
-    type Output = Result<SocketAddr, Bug>;
+    #[test]
+    fn test_msg_window_encoding() {
+        // `Window` is appended so that the other variants keep
+        // their encodings.
+        let afc_id: AfcId = postcard::from_bytes(&[0x33; 16]).unwrap();
+        let buf = postcard::to_allocvec(&Msg::Window(Window {
+            version: Version::V1,
+            afc_id,
+            limit: 42,
+        }))
+        .unwrap();
+        assert_eq!(buf[0], 14);
+        let Msg::Window(got) = postcard::from_bytes(&buf).unwrap() else {
+            panic!("should be a window");
+        };
+        assert_eq!((got.afc_id, got.limit), (afc_id, 42));
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.streams.next_ready(cx) {
-            Ok(v) => v.map(Ok),
-            Err(err) => Poll::Ready(Err(err)),
-        }
+    #[test]
+    fn test_msg_close_encoding() {
+        // `Close` is appended so that the other variants keep
+        // their encodings.
+        let afc_id: AfcId = postcard::from_bytes(&[0x22; 16]).unwrap();
+        let buf = postcard::to_allocvec(&Msg::Close(Close {
+            version: Version::V1,
+            afc_id,
+            ciphertext: vec![1, 2, 3],
+        }))
+        .unwrap();
+        assert_eq!(buf[0], 13);
+        let Msg::Close(got) = postcard::from_bytes(&buf).unwrap() else {
+            panic!("should be a close");
+        };
+        assert_eq!(got.afc_id, afc_id);
+        assert_eq!(got.ciphertext, [1, 2, 3]);
     }
-}
 
-/// Is the stream ready to be read from?
-///
-/// A stream is "ready" if we've received at least the wire
-/// format header.
-fn stream_is_ready(cx: &mut Context<'_>, stream: &TcpStream) -> io::Result<bool> {
-    match stream.poll_read_ready(cx) {
-        Poll::Ready(Ok(())) => {}
-        Poll::Ready(Err(err)) => return Err(err),
-        Poll::Pending => {
-            debug!("`poll_read_ready` is pending");
-            return Ok(false);
+    #[test]
+    fn test_msg_goodbye_encoding() {
+        // `Versions` and `Goodbye` are appended so that the
+        // other variants keep their encodings.
+        let supported = vec![Version::V1];
+        let buf = postcard::to_allocvec(&Msg::Versions(Versions {
+            supported: supported.clone(),
+        }))
+        .unwrap();
+        assert_eq!(buf[..2], [9, 1]);
+
+        let buf = postcard::to_allocvec(&Msg::Goodbye(Goodbye {
+            reason: GoodbyeReason::UnsupportedVersion {
+                supported: supported.clone(),
+            },
+        }))
+        .unwrap();
+        assert_eq!(buf[..3], [10, 0, 1]);
+        let Msg::Goodbye(got) = postcard::from_bytes(&buf).unwrap() else {
+            panic!("should be a goodbye");
+        };
+        assert_eq!(got.reason, GoodbyeReason::UnsupportedVersion { supported });
+
+        // New reasons are appended as well.
+        for (reason, idx) in [(GoodbyeReason::Shutdown, 1), (GoodbyeReason::Duplicate, 2)] {
+            let buf = postcard::to_allocvec(&Msg::Goodbye(Goodbye {
+                reason: reason.clone(),
+            }))
+            .unwrap();
+            assert_eq!(buf, [10, idx]);
+            let frame = goodbye_frame(reason).unwrap();
+            assert_eq!(frame[WIRE_HEADER_SIZE..], buf);
         }
     }
 
-    #[cfg(target_family = "unix")]
-    if ioctl_fionread(stream)
-        .inspect(|n| debug!(n, "fionread"))
-        .is_ok_and(|n| n >= WIRE_HEADER_SIZE)
-    {
-        return Ok(true);
+    #[test]
+    fn test_max_framing_overhead() {
+        let afc_id: AfcId = postcard::from_bytes(&[0xff; 16]).unwrap();
+        let pt = vec![0u8; 100_000];
+        let envelope = postcard::to_allocvec(&Envelope {
+            exts: &[
+                Ext::IdempotencyToken(IdempotencyToken::new([0xff; 16])),
+                Ext::Checksum(u64::MAX),
+                Ext::Fragment(Fragment {
+                    index: u32::MAX,
+                    last: true,
+                }),
+                Ext::Timestamp(u64::MAX),
+            ],
+            data: &pt,
+        })
+        .unwrap();
+        let msg = postcard::to_allocvec(&Msg::Enveloped(Data {
+            version: Version::V1,
+            afc_id,
+            ciphertext: envelope.into(),
+            enveloped: true,
+        }))
+        .unwrap();
+        assert!(msg.len() - pt.len() <= MAX_FRAMING_OVERHEAD);
+    }
+
+    #[test]
+    fn test_smooth() {
+        let ms = Duration::from_millis;
+        assert_eq!(smooth(None, ms(80)), ms(80));
+        assert_eq!(smooth(Some(ms(80)), ms(160)), ms(90));
+        assert_eq!(smooth(Some(ms(80)), ms(0)), ms(70));
+        assert_eq!(smooth(Some(ms(80)), ms(80)), ms(80));
     }
 
-    let mut buf = [0; WIRE_HEADER_SIZE];
-    match stream.poll_peek(cx, &mut ReadBuf::new(&mut buf)) {
-        Poll::Ready(Ok(n)) => {
-            debug!(n, "peeked");
-            Ok(n == WIRE_HEADER_SIZE)
+    #[test]
+    fn test_retry_backoff() {
+        let mut cfg = RouterConfig {
+            reconnect_backoff: Duration::from_millis(100),
+            max_reconnect_backoff: Duration::from_secs(1),
+            reconnect_jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(retry_backoff(&cfg, 1), Duration::from_millis(100));
+        assert_eq!(retry_backoff(&cfg, 3), Duration::from_millis(400));
+        assert_eq!(retry_backoff(&cfg, 5), Duration::from_secs(1));
+        assert_eq!(retry_backoff(&cfg, u32::MAX), Duration::from_secs(1));
+
+        cfg.reconnect_jitter = true;
+        for _ in 0..100 {
+            let got = retry_backoff(&cfg, 3);
+            assert!(
+                (Duration::from_millis(200)..=Duration::from_millis(400)).contains(&got),
+                "{got:?}"
+            );
         }
-        Poll::Ready(Err(err)) => Err(err),
-        Poll::Pending => Ok(false),
     }
-}
 
-#[cfg(target_family = "unix")]
-fn ioctl_fionread(stream: &TcpStream) -> io::Result<usize> {
-    let mut n: c_int = 0;
-    // SAFETY: FFI call, no invariants.
-    let ret = unsafe { libc::ioctl(stream.as_raw_fd(), libc::FIONREAD, &mut n) };
-    if ret < 0 {
-        debug!(%ret, "FIONREAD");
-        // TODO(eric): update `aranya_libc`
-        // if errno() == Errno::EINTR {
-        //     continue;
-        // }
-        return Err(io::Error::new(io::ErrorKind::Other, "ioctl returned -1"));
+    #[test]
+    fn test_retry_queue_full() {
+        let id: AfcId = postcard::from_bytes(&[0u8; 16]).unwrap();
+        let cfg = RouterConfig {
+            retry_queue_len: 1,
+            ..Default::default()
+        };
+        let mut retry = Retry::default();
+        retry
+            .push(id, vec![1], 1, Priority::Normal, 0, &cfg)
+            .unwrap();
+        let err = retry
+            .push(id, vec![2], 1, Priority::Normal, 0, &cfg)
+            .unwrap_err();
+        assert!(matches!(err, AfcError::RetryQueueFull(_)), "{err}");
+        assert_eq!(retry.queue.len(), 1);
     }
-    usize::try_from(n).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "n < 0"))
-}
 
-trait AsyncWriteVectored: AsyncWrite {
-    async fn write_all_vectored<'a, 'b>(
-        &'a mut self,
-        mut bufs: &mut [IoSlice<'b>],
-    ) -> Result<(), io::Error>
-    where
-        'a: 'b,
-        Self: Unpin,
-    {
-        let mut remain = bufs.iter().fold(0, |acc, s| acc + s.len());
-        while !bufs.is_empty() {
-            let n = self.write_vectored(bufs).await?;
-            if n == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::WriteZero,
-                    "wrote 0 bytes, but `bufs` is not empty",
-                ));
-            }
-            // Sanity check since `advance_slices` panics of `n`
-            // is out of range.
-            if n > remain {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "bogus response from `write_vectored`",
-                ));
-            }
-            remain -= n;
-            IoSlice::advance_slices(&mut bufs, n);
+    #[test]
+    fn test_retry_priority() {
+        let id: AfcId = postcard::from_bytes(&[0u8; 16]).unwrap();
+        let order = |retry: &Retry| {
+            retry
+                .queue
+                .iter()
+                .map(|(msg, _, _)| msg[0])
+                .collect::<Vec<_>>()
+        };
+
+        let cfg = RouterConfig::default();
+        let mut retry = Retry::default();
+        retry
+            .push(id, vec![1], 1, Priority::Normal, 4, &cfg)
+            .unwrap();
+        retry
+            .push(id, vec![2], 1, Priority::Normal, 4, &cfg)
+            .unwrap();
+        retry.push(id, vec![3], 1, Priority::High, 4, &cfg).unwrap();
+        retry.push(id, vec![4], 1, Priority::High, 4, &cfg).unwrap();
+        assert_eq!(order(&retry), [3, 4, 1, 2]);
+        // Overtaking frame 1 would put it outside of the
+        // peer's window.
+        retry.push(id, vec![5], 1, Priority::High, 4, &cfg).unwrap();
+        assert_eq!(order(&retry), [3, 4, 1, 2, 5]);
+
+        assert_eq!(retry.front(Priority::High).unwrap().0, [3]);
+        retry.pop_front();
+        retry.pop_front();
+        assert!(retry.front(Priority::High).is_none());
+        assert_eq!(retry.front(Priority::Normal).unwrap().0, [1]);
+
+        // Without a window, frames are never reordered.
+        let mut retry = Retry::default();
+        retry
+            .push(id, vec![1], 1, Priority::Normal, 0, &cfg)
+            .unwrap();
+        retry.push(id, vec![2], 1, Priority::High, 0, &cfg).unwrap();
+        assert_eq!(order(&retry), [1, 2]);
+        assert!(retry.front(Priority::High).is_none());
+    }
+
+    #[test]
+    fn test_checksum() {
+        // FNV-1a test vectors.
+        assert_eq!(checksum(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(checksum(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(checksum(b"ab"), checksum(b"ba"));
+    }
+
+    #[test]
+    fn test_msg_nack_encoding() {
+        // `Nack` is appended after `Busy`.
+        let nack = Msg::Nack(Nack {
+            version: Version::V1,
+            afc_id: postcard::from_bytes(&[0u8; 16]).unwrap(),
+        });
+        let buf = postcard::to_allocvec(&nack).unwrap();
+        assert_eq!(buf[0], 4);
+    }
+
+    #[test]
+    fn test_envelope_encoding() {
+        let token = IdempotencyToken::new([0x22; 16]);
+        let env = Envelope {
+            exts: &[Ext::IdempotencyToken(token)],
+            data: b"hello",
+        };
+        let buf = postcard::to_allocvec(&env).unwrap();
+        // One extension, `IdempotencyToken` is the first
+        // variant, then the token, then the data.
+        let want = [&[1, 0][..], &[0x22; 16], &[5], b"hello"].concat();
+        assert_eq!(buf, want);
+
+        let (exts, range) = decode_envelope(&buf).unwrap();
+        assert_eq!(&buf[range], b"hello");
+        assert!(matches!(exts.collect::<Vec<_>>()[..], [Ext::IdempotencyToken(t)] if t == token));
+    }
+
+    #[test]
+    fn test_decode_envelope_malformed() {
+        let buf = postcard::to_allocvec(&Envelope {
+            exts: &[Ext::Checksum(42)],
+            data: b"hello",
+        })
+        .unwrap();
+        for n in 0..buf.len() {
+            assert!(decode_envelope(&buf[..n]).is_err(), "n = {n}");
         }
-        Ok(())
+        // Trailing bytes are ignored.
+        let buf = [&buf[..], b"!"].concat();
+        let (_, range) = decode_envelope(&buf).unwrap();
+        assert_eq!(&buf[range], b"hello");
     }
-}
 
-impl<W: AsyncWrite + ?Sized> AsyncWriteVectored for W {}
+    #[test]
+    fn test_reassemble() {
+        let mut chan = Chan {
+            team_id: TeamId::default(),
+            net_id: NetIdentifier("localhost:0".into()),
+            chan_id: ChannelId::new(NodeId::new(0), Label::new(0)),
+            addr: None,
+            udp: None,
+            replay: ReplayWindow::new(0),
+            tokens: TokenWindow::default(),
+            self_check: false,
+            stats: ChannelStats::default(),
+            retry: None,
+            stream: None,
+            limiter: None,
+            probe: None,
+            expiry: Expiry::new(ChannelExpiry::default()),
+            priority: Priority::Normal,
+            reserved: None,
+            metadata: ChannelMetadata::new(),
+            flow: Flow::default(),
+            fed: Vec::new(),
+        };
+        let frag = |index, last| Fragment { index, last };
 
-/// An open channel.
-#[derive(Debug)]
-struct Chan {
-    net_id: NetIdentifier,
-    chan_id: ChannelId,
-    /// Used to look up the TCP stream.
-    addr: SocketAddr,
-    /// The minimum allowed next sequence number for a channel,
-    /// used to prevent replay attacks.
-    ///
-    /// `None` indicates that the sequence number would've
-    /// overflowed and [`AfcError::EndOfChannel`] should be
-    /// returned.
-    ///
-    /// It's `Option<Seq>` instead of `Result<Seq, AfcError>` for
-    /// size purposes.
-    next_min_seq: Option<Seq>,
-}
+        assert!(matches!(
+            chan.reassemble(frag(0, false), b"hello, ", 100),
+            Reassembled::Pending
+        ));
+        assert!(matches!(
+            chan.reassemble(frag(1, true), b"world", 100),
+            Reassembled::Done(data) if data == b"hello, world"
+        ));
 
-impl Chan {
-    fn next_min_seq(&self) -> Result<Seq, AfcError> {
-        match self.next_min_seq {
-            Some(v) => Ok(v),
-            None => Err(AfcError::EndOfChannel),
+        // A lost fragment drops the stream and the rest of it is
+        // ignored.
+        chan.reassemble(frag(0, false), b"a", 100);
+        assert!(matches!(
+            chan.reassemble(frag(2, false), b"c", 100),
+            Reassembled::Dropped
+        ));
+        assert!(matches!(
+            chan.reassemble(frag(3, true), b"d", 100),
+            Reassembled::Pending
+        ));
+
+        // A new stream replaces a partial one.
+        chan.reassemble(frag(0, false), b"a", 100);
+        assert!(matches!(
+            chan.reassemble(frag(0, false), b"b", 100),
+            Reassembled::Dropped
+        ));
+        assert!(matches!(
+            chan.reassemble(frag(1, true), b"c", 100),
+            Reassembled::Done(data) if data == b"bc"
+        ));
+
+        // Streams are limited to `max` bytes.
+        chan.reassemble(frag(0, false), &[0; 60], 100);
+        assert!(matches!(
+            chan.reassemble(frag(1, true), &[0; 60], 100),
+            Reassembled::Dropped
+        ));
+        assert!(chan.stream.is_none());
+    }
+
+    #[test]
+    fn test_disconnect_reason_from_io() {
+        use io::ErrorKind;
+        for (kind, want) in [
+            (ErrorKind::UnexpectedEof, DisconnectReason::Closed),
+            (ErrorKind::ConnectionReset, DisconnectReason::Reset),
+            (ErrorKind::BrokenPipe, DisconnectReason::Reset),
+            (ErrorKind::TimedOut, DisconnectReason::TimedOut),
+            (
+                ErrorKind::PermissionDenied,
+                DisconnectReason::Io(ErrorKind::PermissionDenied),
+            ),
+        ] {
+            assert_eq!(DisconnectReason::from_io(kind), want, "{kind:?}");
         }
+        let err = AfcError::StreamWrite(ErrorKind::TimedOut.into());
+        assert_eq!(
+            DisconnectReason::from_write(&err),
+            DisconnectReason::TimedOut
+        );
     }
-}
 
-#[derive(Debug)]
-struct FmtOr<T>(T, &'static str);
+    #[test]
+    fn test_token_window() {
+        let mut window = TokenWindow::default();
+        let token = |i: usize| {
+            let mut b = [0u8; 16];
+            b[..8].copy_from_slice(&u64::try_from(i).unwrap().to_le_bytes());
+            IdempotencyToken::new(b)
+        };
 
-impl<T: fmt::Display> fmt::Display for FmtOr<Option<T>> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
-            Some(v) => v.fmt(f),
-            None => self.1.fmt(f),
+        assert!(window.insert(token(0)));
+        assert!(!window.insert(token(0)));
+
+        for i in 1..=IDEMPOTENCY_WINDOW {
+            assert!(window.insert(token(i)));
         }
+        // `token(0)` fell out of the window.
+        assert!(window.insert(token(0)));
+        // `token(IDEMPOTENCY_WINDOW)` is still in the window.
+        assert!(!window.insert(token(IDEMPOTENCY_WINDOW)));
     }
-}
 
-#[derive(Debug)]
-struct TryFmt<T>(T);
+    /// Both peers must agree on which of two crossed streams
+    /// survives.
+    #[test]
+    fn test_keep_newer_crossed_streams() {
+        let (x, y) = (1, 2);
+        // For each order in which the streams are identified:
+        // `x_first` is the stream that `x` opened.
+        for x_first_on_x in [true, false] {
+            for x_first_on_y in [true, false] {
+                // From x's perspective, the stream x opened is
+                // outbound.
+                let keep_xs_on_x = if x_first_on_x {
+                    // new = y's stream (inbound), old = x's.
+                    !keep_newer(&x, &y, false, true)
+                } else {
+                    // new = x's stream (outbound), old = y's.
+                    keep_newer(&x, &y, true, false)
+                };
+                // From y's perspective, the stream x opened is
+                // inbound.
+                let keep_xs_on_y = if x_first_on_y {
+                    // new = y's stream (outbound), old = x's.
+                    !keep_newer(&y, &x, true, false)
+                } else {
+                    // new = x's stream (inbound), old = y's.
+                    keep_newer(&y, &x, false, true)
+                };
+                assert_eq!(keep_xs_on_x, keep_xs_on_y);
+                // The stream opened by the lower ID survives.
+                assert!(keep_xs_on_x);
+            }
+        }
+    }
 
-impl<T, E> fmt::Display for TryFmt<Result<T, E>>
-where
-    T: fmt::Display,
-    E: fmt::Display,
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
-            Ok(v) => v.fmt(f),
-            Err(err) => err.fmt(f),
+    /// Streams opened in the same direction are not crossed, so
+    /// the newer stream survives.
+    #[test]
+    fn test_keep_newer_same_direction() {
+        for outbound in [true, false] {
+            assert!(keep_newer(&1, &2, outbound, outbound));
+            assert!(keep_newer(&2, &1, outbound, outbound));
         }
     }
 }
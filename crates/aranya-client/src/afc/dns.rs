@@ -0,0 +1,479 @@
+//! Caches the addresses that peers' [`NetIdentifier`]s resolve
+//! to.
+//!
+//! [`NetIdentifier`]: aranya_daemon_api::NetIdentifier
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use super::{AfcError, DnsStats};
+use crate::{config::AddrPreference, metrics, resolve::Resolver};
+
+/// The most hosts whose statistics are kept.
+const MAX_STATS: usize = 256;
+
+/// A cached lookup.
+#[derive(Clone, Debug)]
+struct Entry {
+    /// The resolved addresses, or the reason the lookup failed.
+    result: Result<Arc<[SocketAddr]>, (io::ErrorKind, String)>,
+    /// When the entry must be looked up again.
+    expires: Instant,
+}
+
+/// A cache of DNS lookups.
+///
+/// Resolvers do not report the TTLs of the records they return,
+/// so successful lookups are cached for a fixed `ttl` that
+/// should be no longer than the records' TTLs.
+/// Failed lookups are cached for `negative_ttl`. A zero TTL
+/// disables that kind of caching.
+#[derive(Debug)]
+pub(super) struct DnsCache {
+    /// Looks up the hosts that are not cached.
+    resolver: Arc<dyn Resolver>,
+    entries: HashMap<String, Entry>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    /// How resolved addresses are ordered.
+    pref: AddrPreference,
+    /// Statistics by host, including hosts that are no longer
+    /// cached.
+    stats: HashMap<String, DnsStats>,
+    /// The addresses that peers advertised for hosts, which are
+    /// tried before the resolved ones. See [`advertise`].
+    ///
+    /// [`advertise`]: Self::advertise
+    advertised: HashMap<String, SocketAddr>,
+}
+
+impl DnsCache {
+    /// Creates an empty cache.
+    pub fn new(
+        resolver: Arc<dyn Resolver>,
+        ttl: Duration,
+        negative_ttl: Duration,
+        max_entries: usize,
+        pref: AddrPreference,
+    ) -> Self {
+        Self {
+            resolver,
+            entries: HashMap::new(),
+            ttl,
+            negative_ttl,
+            max_entries,
+            pref,
+            stats: HashMap::new(),
+            advertised: HashMap::new(),
+        }
+    }
+
+    /// Returns the statistics of each host that was looked up.
+    pub fn stats(&self) -> impl Iterator<Item = &DnsStats> {
+        self.stats.values()
+    }
+
+    /// Reports whether `host` has an unexpired cache entry.
+    pub fn is_cached(&self, host: &str) -> bool {
+        self.entries
+            .get(host)
+            .is_some_and(|e| e.expires > Instant::now())
+    }
+
+    /// Records that the peer at `host` accepts streams at
+    /// `addr`, e.g., because it fell back to another port than
+    /// the one in `host`.
+    ///
+    /// The address is tried first until `host` is evicted.
+    pub fn advertise(&mut self, host: &str, addr: SocketAddr) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if !self.advertised.contains_key(host) && self.advertised.len() >= self.max_entries {
+            // Forget an arbitrary host to make room.
+            if let Some(old) = self.advertised.keys().next().cloned() {
+                self.advertised.remove(&old);
+            }
+        }
+        if self.advertised.insert(host.to_owned(), addr) != Some(addr) {
+            debug!(host, %addr, "peer advertised address");
+        }
+    }
+
+    /// Resolves `host`, which is a `host:port` pair.
+    ///
+    /// The address that the peer advertised, if any, comes
+    /// first. The rest are ordered by [`AddrPreference`], which
+    /// also interleaves IPv6 and IPv4 addresses so that dialing
+    /// them in order quickly falls back to the other family if
+    /// one is unreachable.
+    pub async fn resolve(&mut self, host: &str) -> Result<Arc<[SocketAddr]>, AfcError> {
+        let result = self.lookup(host).await;
+        let Some(&first) = self.advertised.get(host) else {
+            return result;
+        };
+        match result {
+            Ok(addrs) => Ok(std::iter::once(first)
+                .chain(addrs.iter().copied().filter(|&addr| addr != first))
+                .collect()),
+            Err(err) => {
+                debug!(host, %err, "lookup failed, using the advertised address");
+                Ok(Arc::from([first]))
+            }
+        }
+    }
+
+    /// Looks up `host`, using the cache if possible.
+    async fn lookup(&mut self, host: &str) -> Result<Arc<[SocketAddr]>, AfcError> {
+        if let Ok(addr) = host.parse::<SocketAddr>() {
+            // Nothing to look up.
+            return Ok(Arc::from([addr]));
+        }
+
+        let now = Instant::now();
+        if let Some(entry) = self.entries.get(host) {
+            if entry.expires > now {
+                debug!(host, "DNS cache hit");
+                if let Some(stats) = self.stats.get_mut(host) {
+                    stats.cache_hits = stats.cache_hits.saturating_add(1);
+                }
+                return entry
+                    .result
+                    .clone()
+                    .map_err(|(kind, msg)| AfcError::DnsLookup(io::Error::new(kind, msg)));
+            }
+        }
+
+        debug!(host, "DNS cache miss");
+        let result = self
+            .resolver
+            .resolve(host)
+            .await
+            .and_then(|addrs| {
+                if addrs.is_empty() {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"))
+                } else {
+                    Ok(order(addrs, self.pref))
+                }
+            })
+            .map_err(|err| (err.kind(), err.to_string()));
+        self.record(host, &result, now.elapsed());
+        let ttl = match result {
+            Ok(_) => self.ttl,
+            Err(_) => self.negative_ttl,
+        };
+        if !ttl.is_zero() && self.max_entries > 0 {
+            self.make_room(now);
+            self.entries.insert(
+                host.to_owned(),
+                Entry {
+                    result: result.clone(),
+                    expires: now + ttl,
+                },
+            );
+        } else {
+            self.entries.remove(host);
+        }
+        result.map_err(|(kind, msg)| AfcError::DnsLookup(io::Error::new(kind, msg)))
+    }
+
+    /// Records the result of looking up `host`, which took
+    /// `latency`.
+    fn record(
+        &mut self,
+        host: &str,
+        result: &Result<Arc<[SocketAddr]>, (io::ErrorKind, String)>,
+        latency: Duration,
+    ) {
+        metrics::dns_lookup(latency, result.is_ok());
+        if !self.stats.contains_key(host) && self.stats.len() >= MAX_STATS {
+            // Forget the host that was looked up longest ago.
+            if let Some(oldest) = self
+                .stats
+                .values()
+                .min_by_key(|s| s.last_lookup)
+                .map(|s| s.host.clone())
+            {
+                self.stats.remove(&oldest);
+            }
+        }
+        let stats = self
+            .stats
+            .entry(host.to_owned())
+            .or_insert_with(|| DnsStats {
+                host: host.to_owned(),
+                lookups: 0,
+                failures: 0,
+                cache_hits: 0,
+                last_latency: Duration::ZERO,
+                last_lookup: SystemTime::UNIX_EPOCH,
+                last_resolved: Vec::new(),
+                last_error: None,
+            });
+        stats.lookups = stats.lookups.saturating_add(1);
+        stats.last_latency = latency;
+        stats.last_lookup = SystemTime::now();
+        match result {
+            Ok(addrs) => {
+                debug!(host, ?latency, n = addrs.len(), "resolved host");
+                stats.last_resolved = addrs.to_vec();
+                stats.last_error = None;
+            }
+            Err((_, msg)) => {
+                warn!(host, ?latency, err = msg, "unable to resolve host");
+                stats.failures = stats.failures.saturating_add(1);
+                stats.last_error = Some(msg.clone());
+            }
+        }
+    }
+
+    /// Forgets `host`, e.g., because none of its addresses
+    /// could be reached.
+    pub fn evict(&mut self, host: &str) {
+        let cached = self.entries.remove(host).is_some();
+        let advertised = self.advertised.remove(host).is_some();
+        if cached || advertised {
+            debug!(host, "evicted DNS cache entry");
+        }
+    }
+
+    /// Forgets every host.
+    pub fn flush(&mut self) {
+        debug!(n = self.entries.len(), "flushing DNS cache");
+        self.entries.clear();
+        self.advertised.clear();
+    }
+
+    /// Removes entries until there is room for another one.
+    ///
+    /// Expired entries are removed first, then the entry that
+    /// expires soonest.
+    fn make_room(&mut self, now: Instant) {
+        if self.entries.len() < self.max_entries {
+            return;
+        }
+        self.entries.retain(|_, e| e.expires > now);
+        while self.entries.len() >= self.max_entries {
+            let Some(host) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(host, _)| host.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&host);
+        }
+    }
+}
+
+/// Orders `addrs` according to `pref`, dropping the addresses
+/// that it rules out.
+fn order(addrs: Vec<SocketAddr>, pref: AddrPreference) -> Arc<[SocketAddr]> {
+    let (addrs, first_v6) = match pref {
+        AddrPreference::System => {
+            let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+            (addrs, first_v6)
+        }
+        AddrPreference::PreferIpv6 => (addrs, true),
+        AddrPreference::PreferIpv4 => (addrs, false),
+        AddrPreference::Ipv6Only => (
+            addrs.into_iter().filter(SocketAddr::is_ipv6).collect(),
+            true,
+        ),
+        AddrPreference::Ipv4Only => (
+            addrs.into_iter().filter(SocketAddr::is_ipv4).collect(),
+            false,
+        ),
+    };
+    interleave(addrs, first_v6)
+}
+
+/// Reorders `addrs` so that the address families alternate,
+/// starting with IPv6 if `first_v6` is true.
+///
+/// This is the ordering recommended by RFC 8305, section 4.
+fn interleave(addrs: Vec<SocketAddr>, first_v6: bool) -> Arc<[SocketAddr]> {
+    let (mut a, mut b): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    a.reverse();
+    b.reverse();
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    loop {
+        match (a.pop(), b.pop()) {
+            (None, None) => break,
+            (x, y) => out.extend(x.into_iter().chain(y)),
+        }
+    }
+    out.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::resolve::{StaticResolver, SystemResolver};
+
+    fn v4(port: u16) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, port).into()
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        (Ipv6Addr::LOCALHOST, port).into()
+    }
+
+    #[test]
+    fn test_interleave() {
+        let got = order(vec![v6(1), v6(2), v6(3), v4(4)], AddrPreference::System);
+        assert_eq!(*got, [v6(1), v4(4), v6(2), v6(3)]);
+        let got = order(vec![v4(1), v6(2), v6(3), v4(4)], AddrPreference::System);
+        assert_eq!(*got, [v4(1), v6(2), v4(4), v6(3)]);
+        assert!(order(Vec::new(), AddrPreference::System).is_empty());
+    }
+
+    #[test]
+    fn test_addr_preference() {
+        let addrs = || vec![v6(1), v4(2), v6(3), v4(4)];
+        let got = order(addrs(), AddrPreference::PreferIpv4);
+        assert_eq!(*got, [v4(2), v6(1), v4(4), v6(3)]);
+        let got = order(addrs(), AddrPreference::PreferIpv6);
+        assert_eq!(*got, [v6(1), v4(2), v6(3), v4(4)]);
+        let got = order(addrs(), AddrPreference::Ipv4Only);
+        assert_eq!(*got, [v4(2), v4(4)]);
+        let got = order(addrs(), AddrPreference::Ipv6Only);
+        assert_eq!(*got, [v6(1), v6(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache() {
+        let mut dns = DnsCache::new(
+            Arc::new(SystemResolver),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            1,
+            AddrPreference::System,
+        );
+        let host = "localhost:1234";
+
+        let addrs = dns.resolve(host).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.port() == 1234));
+        let cached = dns.resolve(host).await.unwrap();
+        assert!(Arc::ptr_eq(&addrs, &cached));
+
+        // Expired.
+        dns.entries.get_mut(host).unwrap().expires = Instant::now();
+        let fresh = dns.resolve(host).await.unwrap();
+        assert!(!Arc::ptr_eq(&addrs, &fresh));
+
+        // Flushed.
+        dns.flush();
+        let fresh2 = dns.resolve(host).await.unwrap();
+        assert!(!Arc::ptr_eq(&fresh, &fresh2));
+
+        // Literals are not cached and do not displace entries.
+        dns.resolve("127.0.0.1:1").await.unwrap();
+        let cached = dns.resolve(host).await.unwrap();
+        assert!(Arc::ptr_eq(&fresh2, &cached));
+    }
+
+    #[tokio::test]
+    async fn test_dns_resolver() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let resolver = StaticResolver::new().with_host("peer.local", ip);
+        let mut dns = DnsCache::new(
+            Arc::new(resolver),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            8,
+            AddrPreference::System,
+        );
+
+        let addrs = dns.resolve("peer.local:1234").await.unwrap();
+        assert_eq!(*addrs, [SocketAddr::new(ip, 1234)]);
+        assert!(dns.is_cached("peer.local:1234"));
+        // Hosts that are not in the map are not looked up with
+        // the system resolver.
+        dns.resolve("localhost:1234").await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_dns_advertise() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let resolver = StaticResolver::new().with_host("peer.local", ip);
+        let mut dns = DnsCache::new(
+            Arc::new(resolver),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            8,
+            AddrPreference::System,
+        );
+        let host = "peer.local:1234";
+        let advertised = SocketAddr::new(ip, 5678);
+
+        dns.advertise(host, advertised);
+        let addrs = dns.resolve(host).await.unwrap();
+        assert_eq!(*addrs, [advertised, SocketAddr::new(ip, 1234)]);
+
+        // The advertised address is used even if the host
+        // cannot be looked up.
+        dns.advertise("127.0.0.1:1", v4(2));
+        let addrs = dns.resolve("127.0.0.1:1").await.unwrap();
+        assert_eq!(*addrs, [v4(2), v4(1)]);
+        dns.advertise("missing.local:1", v4(2));
+        let addrs = dns.resolve("missing.local:1").await.unwrap();
+        assert_eq!(*addrs, [v4(2)]);
+
+        // Evicting the host forgets the advertised address.
+        dns.evict(host);
+        let addrs = dns.resolve(host).await.unwrap();
+        assert_eq!(*addrs, [SocketAddr::new(ip, 1234)]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_stats() {
+        let mut dns = DnsCache::new(
+            Arc::new(SystemResolver),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            8,
+            AddrPreference::System,
+        );
+        let host = "localhost:1234";
+        let bad = "does-not-exist.invalid:1234";
+
+        let addrs = dns.resolve(host).await.unwrap();
+        assert!(dns.is_cached(host));
+        dns.resolve(host).await.unwrap();
+        dns.resolve(bad).await.unwrap_err();
+        // Literals are not looked up.
+        dns.resolve("127.0.0.1:1").await.unwrap();
+
+        let mut stats = dns.stats().cloned().collect::<Vec<_>>();
+        stats.sort_by(|a, b| a.host.cmp(&b.host));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].host, bad);
+        assert_eq!((stats[0].lookups, stats[0].failures), (1, 1));
+        assert!(stats[0].last_error.is_some());
+        assert_eq!(stats[1].host, host);
+        assert_eq!(stats[1].lookups, 1);
+        assert_eq!(stats[1].failures, 0);
+        assert_eq!(stats[1].cache_hits, 1);
+        assert_eq!(*stats[1].last_resolved, *addrs);
+
+        // Statistics outlive the cache.
+        dns.flush();
+        assert!(!dns.is_cached(host));
+        assert_eq!(dns.stats().count(), 2);
+    }
+}
@@ -0,0 +1,146 @@
+//! Receiver-driven flow control of a channel.
+//!
+//! The receiver advertises a limit: the sequence number below
+//! which the sender may send. The limit is the next sequence
+//! number that the receiver expects plus its window, less the
+//! messages that are waiting to be read. Limits are absolute, so
+//! a lost or reordered advertisement is made up for by the next
+//! one.
+
+/// The flow control state of a channel.
+#[derive(Debug, Default)]
+pub(super) struct Flow {
+    /// The limit advertised by the peer, if any.
+    send_limit: Option<u64>,
+    /// The sequence number of the last message that we sent
+    /// since the channel was added.
+    last_sent: Option<u64>,
+    /// The highest sequence number received.
+    highest: Option<u64>,
+    /// The number of received messages waiting to be read.
+    queued: u64,
+    /// The limit that we last advertised.
+    advertised: Option<u64>,
+}
+
+impl Flow {
+    /// Reports whether the peer's window allows us to send
+    /// another message.
+    ///
+    /// Until the peer advertises a limit, or until we send our
+    /// first message (whose sequence number we cannot predict),
+    /// the window is open.
+    pub fn can_send(&self) -> bool {
+        match (self.send_limit, self.last_sent) {
+            (Some(limit), Some(last)) => last.saturating_add(1) < limit,
+            _ => true,
+        }
+    }
+
+    /// Records that we sent the message with `seq`.
+    pub fn sent(&mut self, seq: u64) {
+        self.last_sent = Some(self.last_sent.map_or(seq, |last| last.max(seq)));
+    }
+
+    /// Handles a limit advertised by the peer.
+    ///
+    /// Stale limits, which were overtaken by a larger one, are
+    /// ignored.
+    pub fn limit(&mut self, limit: u64) {
+        self.send_limit = Some(self.send_limit.map_or(limit, |old| old.max(limit)));
+    }
+
+    /// Records that we received the message with `seq`.
+    pub fn received(&mut self, seq: u64) {
+        self.highest = Some(self.highest.map_or(seq, |highest| highest.max(seq)));
+    }
+
+    /// Records that `n` received messages are waiting to be
+    /// read.
+    pub fn set_queued(&mut self, n: u64) {
+        self.queued = n;
+    }
+
+    /// Returns the limit to advertise with a window of `window`
+    /// messages, if it grew by at least half of the window since
+    /// the last advertisement.
+    ///
+    /// Nothing is advertised before we receive a message, since
+    /// we cannot know the peer's sequence numbers until then.
+    fn next_limit(&self, window: u32) -> Option<u64> {
+        let limit = self
+            .highest?
+            .saturating_add(1)
+            .saturating_add(u64::from(window))
+            .saturating_sub(self.queued);
+        let step = u64::from(window / 2).max(1);
+        if self
+            .advertised
+            .is_some_and(|old| limit < old.saturating_add(step))
+        {
+            return None;
+        }
+        Some(limit)
+    }
+
+    /// Reports whether a limit is due to be advertised.
+    ///
+    /// See [`advertise`][Self::advertise].
+    pub fn is_due(&self, window: u32) -> bool {
+        self.next_limit(window).is_some()
+    }
+
+    /// Returns the limit to advertise, if one is due.
+    pub fn advertise(&mut self, window: u32) -> Option<u64> {
+        let limit = self.next_limit(window)?;
+        self.advertised = Some(limit);
+        Some(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_send() {
+        let mut flow = Flow::default();
+        assert!(flow.can_send());
+        flow.sent(10);
+        // No limit yet.
+        assert!(flow.can_send());
+
+        flow.limit(12);
+        assert!(flow.can_send());
+        flow.sent(11);
+        assert!(!flow.can_send());
+
+        // Stale limits are ignored.
+        flow.limit(11);
+        assert!(!flow.can_send());
+        flow.limit(13);
+        assert!(flow.can_send());
+    }
+
+    #[test]
+    fn test_flow_advertise() {
+        let mut flow = Flow::default();
+        assert_eq!(flow.advertise(4), None);
+
+        flow.received(100);
+        flow.set_queued(1);
+        assert!(flow.is_due(4));
+        assert_eq!(flow.advertise(4), Some(104));
+        assert!(!flow.is_due(4));
+        // Nothing changed.
+        assert_eq!(flow.advertise(4), None);
+
+        // One more message was received and both were read,
+        // which is not enough to advertise again.
+        flow.received(101);
+        flow.set_queued(1);
+        assert_eq!(flow.advertise(4), None);
+        flow.set_queued(0);
+        assert_eq!(flow.advertise(4), Some(106));
+    }
+}
@@ -0,0 +1,387 @@
+//! Reusable buffers for encoding and decoding frames.
+
+use std::mem;
+
+use aranya_buggy::{Bug, BugExt};
+use aranya_daemon_api::{AfcCtrl, AfcId, TeamId};
+use aranya_fast_channels::Version;
+use serde::{Deserialize, Serialize};
+
+use super::{encode_wire_header, pool::RecvBuf, AfcError, Ctrl, Data, Msg, WIRE_HEADER_SIZE};
+
+/// The index of [`Msg::Ctrl`][super::Msg::Ctrl] in postcard's
+/// encoding of [`Msg`][super::Msg].
+const CTRL_VARIANT: u32 = 0;
+
+/// The index of [`Msg::Data`][super::Msg::Data] in postcard's
+/// encoding of [`Msg`][super::Msg].
+const DATA_VARIANT: u32 = 1;
+
+/// The index of [`Msg::Enveloped`][super::Msg::Enveloped] in
+/// postcard's encoding of [`Msg`][super::Msg].
+const ENVELOPED_VARIANT: u32 = 15;
+
+/// A reusable buffer that holds one frame: `magic || len || msg`
+/// (see the wire format).
+///
+/// Encoding into the same buffer over and over avoids
+/// allocating once the buffer has grown to fit the largest
+/// message.
+#[derive(Debug, Default)]
+pub(super) struct FrameBuf {
+    buf: Vec<u8>,
+}
+
+impl FrameBuf {
+    /// Creates a buffer that can hold a `len` byte message
+    /// without allocating.
+    pub fn with_capacity(len: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(WIRE_HEADER_SIZE + len),
+        }
+    }
+
+    /// Clears the buffer and reserves room for the wire header.
+    fn reset(&mut self) {
+        self.buf.clear();
+        self.buf.resize(WIRE_HEADER_SIZE, 0);
+    }
+
+    /// Starts a new frame containing `msg`.
+    pub fn encode<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), AfcError> {
+        self.reset();
+        self.buf = postcard::to_extend(msg, mem::take(&mut self.buf)).map_err(AfcError::Serde)?;
+        Ok(())
+    }
+
+    /// Starts a new frame containing a [`Data`][super::Data]
+    /// message whose ciphertext is `len` bytes long.
+    ///
+    /// Returns the zeroed ciphertext, which must be filled in
+    /// before the frame is [finished][Self::finish].
+    ///
+    /// The frame is encoded exactly like
+    /// `Msg::Data(Data { .. })`, or `Msg::Enveloped(Data { .. })`
+    /// if `enveloped` is true, but the ciphertext is written in
+    /// place instead of being copied out of a separate buffer.
+    pub fn data(
+        &mut self,
+        afc_id: AfcId,
+        len: usize,
+        enveloped: bool,
+    ) -> Result<&mut [u8], AfcError> {
+        let variant = if enveloped {
+            ENVELOPED_VARIANT
+        } else {
+            DATA_VARIANT
+        };
+        // postcard encodes the variant index and the length of
+        // a byte sequence as varints, just like `u32` and
+        // `usize`.
+        self.encode(&(variant, Version::V1, afc_id, len))?;
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        Ok(self.buf.get_mut(start..).assume("`start` <= `buf.len()`")?)
+    }
+
+    /// Returns the size of the encoded message, excluding the
+    /// wire header.
+    pub fn msg_len(&self) -> usize {
+        self.buf.len().saturating_sub(WIRE_HEADER_SIZE)
+    }
+
+    /// Writes the wire header and returns the frame.
+    pub fn finish(&mut self) -> Result<&[u8], Bug> {
+        let len = u32::try_from(self.msg_len()).assume("`msg` should be < 2^32-1")?;
+        let (hdr, _) = self
+            .buf
+            .split_first_chunk_mut::<WIRE_HEADER_SIZE>()
+            .assume("`buf` should contain the wire header")?;
+        *hdr = encode_wire_header(len);
+        Ok(&self.buf)
+    }
+}
+
+/// A [`Ctrl`] sent by a peer that predates its optional
+/// fields.
+///
+/// Fields are only ever added to the end of [`Ctrl`], so this
+/// is a prefix of it.
+#[derive(Deserialize)]
+struct LegacyCtrl {
+    version: Version,
+    team_id: TeamId,
+    cmd: AfcCtrl,
+}
+
+impl From<LegacyCtrl> for Ctrl {
+    fn from(ctrl: LegacyCtrl) -> Self {
+        Self {
+            version: ctrl.version,
+            team_id: ctrl.team_id,
+            cmd: ctrl.cmd,
+            udp_port: None,
+            trace_id: None,
+            self_check: false,
+        }
+    }
+}
+
+/// Decodes a [`Msg`] from `buf[start..]`, which excludes the
+/// wire header.
+///
+/// Anything that follows the message is ignored. See
+/// [`decode_frame`].
+pub(super) fn decode_msg(buf: &mut Vec<u8>, start: usize) -> Result<Msg, AfcError> {
+    decode_frame(buf, start).map(|(msg, _)| msg)
+}
+
+/// Decodes a [`Msg`] from `buf[start..]`, which excludes the
+/// wire header, along with the message that trails it, if any.
+///
+/// This is the inverse of [`FrameBuf::data`]: the ciphertext of
+/// a [`Data`] message is decoded in place and `buf` is moved
+/// into the message, leaving it empty. Neither the ciphertext
+/// nor the rest of the buffer is copied. Other messages are
+/// decoded normally.
+///
+/// Peers that predate [`Hello`][super::Hello] ignore whatever
+/// follows a message, so the first frame of a stream carries
+/// the dialer's greeting as a trailer (see
+/// [`Stream::set_trailer`][super::stream::Stream::set_trailer]). Their
+/// own [`Ctrl`] messages lack its optional fields, which are
+/// left unset.
+pub(super) fn decode_frame(
+    buf: &mut Vec<u8>,
+    start: usize,
+) -> Result<(Msg, Option<Msg>), AfcError> {
+    let msg = buf.get(start..).ok_or(AfcError::PayloadTooSmall)?;
+    if let Ok(((variant @ (DATA_VARIANT | ENVELOPED_VARIANT), version, afc_id, len), rest)) =
+        postcard::take_from_bytes::<(u32, Version, AfcId, usize)>(msg)
+    {
+        if len == rest.len() {
+            let start = buf.len().saturating_sub(len);
+            let enveloped = variant == ENVELOPED_VARIANT;
+            let data = Data {
+                version,
+                afc_id,
+                ciphertext: RecvBuf::new(mem::take(buf), start),
+                enveloped,
+            };
+            let msg = if enveloped {
+                Msg::Enveloped(data)
+            } else {
+                Msg::Data(data)
+            };
+            return Ok((msg, None));
+        }
+    }
+    let (msg, rest) = match postcard::take_from_bytes::<Msg>(msg) {
+        Ok(v) => v,
+        Err(err) => {
+            return match postcard::from_bytes::<(u32, LegacyCtrl)>(msg) {
+                Ok((CTRL_VARIANT, ctrl)) => Ok((Msg::Ctrl(ctrl.into()), None)),
+                _ => Err(AfcError::Serde(err)),
+            }
+        }
+    };
+    let trailer = if rest.is_empty() {
+        None
+    } else {
+        Some(postcard::from_bytes(rest).map_err(AfcError::Serde)?)
+    };
+    let msg = match msg {
+        Msg::Enveloped(data) => Msg::Enveloped(Data {
+            enveloped: true,
+            ..data
+        }),
+        msg => msg,
+    };
+    Ok((msg, trailer))
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+
+    use aranya_daemon_api::DeviceId;
+    use tarpc::trace::TraceId;
+
+    use super::{
+        super::{Hello, Nack},
+        *,
+    };
+
+    /// The messages of peers that predate [`Hello`].
+    mod baseline {
+        use super::*;
+
+        #[derive(Serialize, Deserialize)]
+        pub enum Msg {
+            Ctrl(Ctrl),
+            Data(Data),
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Ctrl {
+            pub version: Version,
+            pub team_id: TeamId,
+            pub cmd: AfcCtrl,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Data {
+            pub version: Version,
+            pub afc_id: AfcId,
+            pub ciphertext: Vec<u8>,
+        }
+    }
+
+    fn cmd() -> AfcCtrl {
+        vec![Box::from(&b"cmd"[..]), Box::from(&b"more"[..])]
+    }
+
+    fn afc_id() -> AfcId {
+        postcard::from_bytes(&[0x42u8; 16]).unwrap()
+    }
+
+    #[test]
+    fn test_frame_buf_encode() {
+        let msg = Msg::Nack(Nack {
+            version: Version::V1,
+            afc_id: afc_id(),
+        });
+        let want = postcard::to_allocvec(&msg).unwrap();
+
+        let mut buf = FrameBuf::default();
+        // Reusing the buffer must not leave stale bytes behind.
+        buf.encode(&[0xffu8; 100][..]).unwrap();
+        buf.encode(&msg).unwrap();
+        assert_eq!(buf.msg_len(), want.len());
+        let frame = buf.finish().unwrap();
+        let (hdr, rest) = frame.split_first_chunk::<WIRE_HEADER_SIZE>().unwrap();
+        assert_eq!(*hdr, encode_wire_header(u32::try_from(want.len()).unwrap()));
+        assert_eq!(rest, want);
+    }
+
+    #[test]
+    fn test_frame_buf_data() {
+        for len in [0, 1, 127, 128, 16_384, 100_000] {
+            let ciphertext = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let want = postcard::to_allocvec(&Msg::Data(Data {
+                version: Version::V1,
+                afc_id: afc_id(),
+                ciphertext: ciphertext.clone().into(),
+                enveloped: false,
+            }))
+            .unwrap();
+
+            let mut buf = FrameBuf::default();
+            buf.data(afc_id(), len, false)
+                .unwrap()
+                .copy_from_slice(&ciphertext);
+            let frame = buf.finish().unwrap();
+            assert_eq!(&frame[WIRE_HEADER_SIZE..], want, "len = {len}");
+        }
+    }
+
+    #[test]
+    fn test_decode_msg() {
+        for len in [0, 1, 127, 128, 16_384] {
+            let ciphertext = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let msg = postcard::to_allocvec(&Msg::Data(Data {
+                version: Version::V1,
+                afc_id: afc_id(),
+                ciphertext: ciphertext.clone().into(),
+                enveloped: false,
+            }))
+            .unwrap();
+            // Decoding skips whatever precedes the message.
+            let mut buf = [&[0xff; WIRE_HEADER_SIZE][..], &msg].concat();
+            let ptr = buf.as_ptr();
+            let Msg::Data(data) = decode_msg(&mut buf, WIRE_HEADER_SIZE).unwrap() else {
+                panic!("should be `Data`");
+            };
+            assert_eq!(data.afc_id, afc_id());
+            assert_eq!(*data.ciphertext, ciphertext, "len = {len}");
+            // The ciphertext was decoded in place.
+            assert_eq!(data.ciphertext.into_inner().as_ptr(), ptr);
+            assert!(buf.is_empty());
+        }
+
+        let mut buf = postcard::to_allocvec(&Msg::Nack(Nack {
+            version: Version::V1,
+            afc_id: afc_id(),
+        }))
+        .unwrap();
+        assert!(matches!(decode_msg(&mut buf, 0), Ok(Msg::Nack(_))));
+        assert!(!buf.is_empty());
+    }
+
+    /// Messages from peers that predate [`Hello`] decode.
+    #[test]
+    fn test_decode_baseline() {
+        let mut buf = postcard::to_allocvec(&baseline::Msg::Ctrl(baseline::Ctrl {
+            version: Version::V1,
+            team_id: TeamId::default(),
+            cmd: cmd(),
+        }))
+        .unwrap();
+        let Ok((Msg::Ctrl(ctrl), None)) = decode_frame(&mut buf, 0) else {
+            panic!("should be `Ctrl`");
+        };
+        assert_eq!(ctrl.team_id, TeamId::default());
+        assert_eq!(ctrl.cmd, cmd());
+        assert_eq!(ctrl.udp_port, None);
+        assert_eq!(ctrl.trace_id, None);
+        assert!(!ctrl.self_check);
+
+        let mut buf = postcard::to_allocvec(&baseline::Msg::Data(baseline::Data {
+            version: Version::V1,
+            afc_id: afc_id(),
+            ciphertext: vec![1, 2, 3],
+        }))
+        .unwrap();
+        let Ok((Msg::Data(data), None)) = decode_frame(&mut buf, 0) else {
+            panic!("should be `Data`");
+        };
+        assert_eq!(data.afc_id, afc_id());
+        assert_eq!(*data.ciphertext, [1, 2, 3]);
+    }
+
+    /// Peers that predate [`Hello`] decode our messages and
+    /// ignore the greeting that trails them.
+    #[test]
+    fn test_decode_as_baseline() {
+        let ctrl = postcard::to_allocvec(&Msg::Ctrl(Ctrl {
+            version: Version::V1,
+            team_id: TeamId::default(),
+            cmd: cmd(),
+            udp_port: Some(1234),
+            trace_id: Some(TraceId::from(42)),
+            self_check: true,
+        }))
+        .unwrap();
+        let hello = postcard::to_allocvec(&Msg::Hello(Hello {
+            version: Version::V1,
+            device_id: DeviceId::default(),
+            port: Some(4321),
+        }))
+        .unwrap();
+        let mut buf = [ctrl, hello].concat();
+
+        let baseline::Msg::Ctrl(old) = postcard::from_bytes(&buf).unwrap() else {
+            panic!("should be `Ctrl`");
+        };
+        assert_eq!(old.team_id, TeamId::default());
+        assert_eq!(old.cmd, cmd());
+
+        let Ok((Msg::Ctrl(ctrl), Some(Msg::Hello(hello)))) = decode_frame(&mut buf, 0) else {
+            panic!("should be `Ctrl` and `Hello`");
+        };
+        assert_eq!(ctrl.udp_port, Some(1234));
+        assert_eq!(ctrl.trace_id, Some(TraceId::from(42)));
+        assert!(ctrl.self_check);
+        assert_eq!(hello.port, Some(4321));
+    }
+}
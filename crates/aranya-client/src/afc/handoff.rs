@@ -0,0 +1,177 @@
+//! Handing the router's state to a new process.
+//!
+//! To upgrade the client in place, the old process hands its
+//! channels, replay windows, and session resumption tickets to
+//! the new one over a Unix socket (see
+//! [`Client::hand_off`][crate::Client::hand_off]). The keys live
+//! in the daemon's shared memory, so the channels are not
+//! recreated. And since the old process stops routing before it
+//! sends its state, the replay windows are handed over as they
+//! are instead of being reserved as for a restart (see
+//! [`RouterConfig::seq_reservation`][crate::RouterConfig::seq_reservation]).
+//!
+//! Streams cannot be handed over. Each channel keeps the address
+//! of its stream, so that a peer that resumes the stream with a
+//! ticket takes its channels with it.
+//!
+//! Only a process running as the same user as the old one can
+//! take over: the socket is only accessible to its owner, and
+//! connections from other users are refused.
+//!
+//! The old and new processes might run different versions of
+//! the client, so every version must keep reading the formats of
+//! the versions before it.
+
+use std::{
+    fs::{self, Permissions},
+    io,
+    net::SocketAddr,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use tracing::{debug, warn};
+
+use super::{persist::SavedChan, resume::SavedTickets};
+
+/// The current [`Handoff::version`].
+const VERSION: u32 = 1;
+
+/// The state of a router, handed to a new process.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Handoff {
+    /// The format version.
+    version: u32,
+    /// The address that the old router listened on, if it did.
+    pub addr: Option<SocketAddr>,
+    pub(super) chans: Vec<HandedChan>,
+    pub(super) tickets: SavedTickets,
+}
+
+/// The state of one channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct HandedChan {
+    pub saved: SavedChan,
+    /// The highest sequence number accepted, if any.
+    pub top: Option<u64>,
+    /// The address of the channel's stream, if any.
+    pub addr: Option<SocketAddr>,
+}
+
+impl Handoff {
+    pub(super) fn new(
+        addr: Option<SocketAddr>,
+        chans: Vec<HandedChan>,
+        tickets: SavedTickets,
+    ) -> Self {
+        Self {
+            version: VERSION,
+            addr,
+            chans,
+            tickets,
+        }
+    }
+
+    /// Waits for the new process to connect to `path`.
+    ///
+    /// Connections from processes of other users are refused.
+    pub async fn accept(path: &Path) -> io::Result<UnixStream> {
+        let listener = UnixListener::bind(path)?;
+        let result = Self::accept_owner(&listener, path).await;
+        // Only one process takes over.
+        if let Err(err) = fs::remove_file(path) {
+            debug!(?path, %err, "unable to remove handoff socket");
+        }
+        result
+    }
+
+    async fn accept_owner(listener: &UnixListener, path: &Path) -> io::Result<UnixStream> {
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        // We created the socket, so it is owned by our user.
+        let uid = fs::metadata(path)?.uid();
+        loop {
+            let (conn, _) = listener.accept().await?;
+            let cred = conn.peer_cred()?;
+            if cred.uid() == uid {
+                debug!(pid = ?cred.pid(), "accepted handoff connection");
+                return Ok(conn);
+            }
+            warn!(uid = cred.uid(), pid = ?cred.pid(), "refusing handoff connection from another user");
+        }
+    }
+
+    /// Sends the state to the new process over `conn`.
+    ///
+    /// The new process does not receive the state until `conn`
+    /// is shut down.
+    pub async fn send(&self, conn: &mut UnixStream) -> io::Result<()> {
+        let buf = postcard::to_allocvec(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        conn.write_all(&buf).await
+    }
+
+    /// Receives the state from the old process, which waits on
+    /// `path`.
+    pub async fn receive(path: &Path) -> io::Result<Self> {
+        let mut conn = UnixStream::connect(path).await?;
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).await?;
+        if buf.is_empty() {
+            // The old process failed before sending its state.
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the old process did not hand off its state",
+            ));
+        }
+        // Check the version before decoding the rest, which
+        // depends on it.
+        let (version, _) = postcard::take_from_bytes::<u32>(&buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported handoff version {version}"),
+            ));
+        }
+        postcard::from_bytes(&buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{sleep, Instant};
+
+    use super::{super::resume::Tickets, *};
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("handoff.sock");
+        let handoff = Handoff::new(None, Vec::new(), Tickets::default().save(Instant::now()));
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let mut conn = Handoff::accept(&path).await?;
+                handoff.send(&mut conn).await?;
+                conn.shutdown().await
+            }
+        });
+
+        // The socket is only accessible to its owner.
+        while !fs::metadata(&path).is_ok_and(|md| md.mode() & 0o777 == 0o600) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let got = Handoff::receive(&path).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(got.addr, None);
+        assert!(got.chans.is_empty());
+        assert!(!path.exists());
+    }
+}
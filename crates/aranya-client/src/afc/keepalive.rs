@@ -0,0 +1,165 @@
+//! Keep-alive probing of a channel's peer.
+
+use std::time::Duration;
+
+use aranya_crypto::{csprng::Random, default::Rng};
+use tokio::time::Instant;
+
+use super::Liveness;
+use crate::config::KeepAlive;
+
+/// Tracks whether a channel's peer answers pings.
+#[derive(Debug)]
+pub(super) struct Probe {
+    interval: Duration,
+    timeout: Duration,
+    max_missed: u32,
+    /// When the next ping is due, unless we hear from the peer
+    /// first.
+    due: Instant,
+    /// The nonce of the outstanding ping and when it was sent.
+    pending: Option<(u64, Instant)>,
+    /// The number of consecutive missed pings.
+    missed: u32,
+    liveness: Liveness,
+}
+
+/// The result of [`Probe::tick`].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(super) struct Tick {
+    /// The nonce of a ping to send, if one is due.
+    pub ping: Option<u64>,
+    /// Was the outstanding ping missed?
+    pub missed: bool,
+    /// The new liveness, if it changed.
+    pub changed: Option<Liveness>,
+}
+
+impl Probe {
+    /// Creates a probe for a peer that we just heard from.
+    pub fn new(cfg: &KeepAlive) -> Self {
+        Self {
+            interval: cfg.interval,
+            timeout: cfg.timeout,
+            max_missed: cfg.max_missed.max(1),
+            due: Instant::now() + cfg.interval,
+            pending: None,
+            missed: 0,
+            liveness: Liveness::Alive,
+        }
+    }
+
+    /// Returns when [`tick`][Self::tick] next has something to
+    /// do.
+    pub fn deadline(&self) -> Instant {
+        match self.pending {
+            Some((_, sent)) => sent + self.timeout,
+            None => self.due,
+        }
+    }
+
+    /// Records that we heard from the peer.
+    ///
+    /// Returns the new liveness if it changed.
+    pub fn heard(&mut self, now: Instant) -> Option<Liveness> {
+        self.due = now + self.interval;
+        self.pending = None;
+        self.missed = 0;
+        self.set(Liveness::Alive)
+    }
+
+    /// Handles a pong carrying `nonce`.
+    ///
+    /// Returns the round-trip time if it answers the outstanding
+    /// ping. Otherwise, the pong is ignored.
+    pub fn pong(&self, now: Instant, nonce: u64) -> Option<Duration> {
+        match self.pending {
+            Some((want, sent)) if want == nonce => Some(now.saturating_duration_since(sent)),
+            _ => None,
+        }
+    }
+
+    /// Times out the outstanding ping and starts a new one if
+    /// one is due.
+    pub fn tick(&mut self, now: Instant) -> Tick {
+        let mut tick = Tick::default();
+        if let Some((_, sent)) = self.pending {
+            if sent + self.timeout > now {
+                return tick;
+            }
+            self.pending = None;
+            self.missed = self.missed.saturating_add(1);
+            tick.missed = true;
+            tick.changed = self.set(if self.missed >= self.max_missed {
+                Liveness::Unreachable
+            } else {
+                Liveness::Degraded
+            });
+        }
+        if self.due <= now {
+            let nonce = u64::random(&mut Rng);
+            self.pending = Some((nonce, now));
+            self.due = now + self.interval;
+            tick.ping = Some(nonce);
+        }
+        tick
+    }
+
+    fn set(&mut self, liveness: Liveness) -> Option<Liveness> {
+        if self.liveness == liveness {
+            return None;
+        }
+        self.liveness = liveness;
+        Some(liveness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        let cfg = KeepAlive {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            max_missed: 2,
+        };
+        let mut probe = Probe::new(&cfg);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Nothing is due until the peer has been silent for
+        // `interval`.
+        assert_eq!(probe.tick(start), Tick::default());
+        let tick = probe.tick(at(10));
+        let nonce = tick.ping.unwrap();
+        assert!(!tick.missed);
+        assert_eq!(probe.deadline(), at(12));
+
+        // Pongs for other pings are ignored.
+        assert_eq!(probe.pong(at(11), nonce.wrapping_add(1)), None);
+        assert_eq!(probe.pong(at(11), nonce), Some(Duration::from_secs(1)));
+        assert_eq!(probe.heard(at(11)), None);
+        assert_eq!(probe.deadline(), at(21));
+
+        // Missed pings degrade the channel, then make it
+        // unreachable.
+        let tick = probe.tick(at(21));
+        assert!(tick.ping.is_some());
+        let tick = probe.tick(at(23));
+        assert_eq!(
+            (tick.ping, tick.missed, tick.changed),
+            (None, true, Some(Liveness::Degraded))
+        );
+        assert_eq!(probe.deadline(), at(31));
+        assert!(probe.tick(at(31)).ping.is_some());
+        let tick = probe.tick(at(33));
+        assert!(tick.missed);
+        assert_eq!(tick.changed, Some(Liveness::Unreachable));
+
+        // Hearing from the peer revives it.
+        assert_eq!(probe.heard(at(34)), Some(Liveness::Alive));
+        assert_eq!(probe.deadline(), at(44));
+    }
+}
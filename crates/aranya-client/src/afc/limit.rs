@@ -0,0 +1,144 @@
+//! Per-channel rate limiting.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::RateLimit;
+
+/// A token bucket, implemented as a generic cell rate algorithm
+/// (GCRA).
+///
+/// Instead of counting tokens, the bucket tracks the time at
+/// which it will be full again.
+#[derive(Debug)]
+pub(super) struct TokenBucket {
+    /// The time it takes to earn one token.
+    interval: Duration,
+    /// The time it takes to earn a full burst.
+    window: Duration,
+    /// When the bucket will be full again.
+    full_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: &RateLimit) -> Self {
+        let interval = Duration::from_secs(1) / limit.msgs_per_sec.get();
+        Self {
+            interval,
+            window: interval.saturating_mul(limit.burst.max(1)),
+            full_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, waiting at most `max_wait` for it.
+    ///
+    /// Returns how long to wait before using the token, or
+    /// `None` if that would take longer than `max_wait`, in
+    /// which case no token is taken.
+    pub fn take(&mut self, now: Instant, max_wait: Duration) -> Option<Duration> {
+        let full_at = self.full_at.max(now) + self.interval;
+        let wait = full_at
+            .saturating_duration_since(now)
+            .saturating_sub(self.window);
+        if wait > max_wait {
+            return None;
+        }
+        self.full_at = full_at;
+        Some(wait)
+    }
+
+    /// Reports whether the bucket is full at `now`.
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.full_at <= now
+    }
+}
+
+/// Limits the rate at which a channel sends and receives
+/// messages.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    /// The longest that sending waits for a token.
+    max_wait: Duration,
+    send: TokenBucket,
+    recv: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        Self {
+            max_wait: limit.max_wait,
+            send: TokenBucket::new(limit),
+            recv: TokenBucket::new(limit),
+        }
+    }
+
+    /// Takes a token for sending a message.
+    ///
+    /// Returns how long to wait before sending it, or `None` if
+    /// that would take longer than [`RateLimit::max_wait`].
+    pub fn send(&mut self) -> Option<Duration> {
+        self.send.take(Instant::now(), self.max_wait)
+    }
+
+    /// Takes a token for sending a message without waiting.
+    ///
+    /// Reports whether a token was available.
+    pub fn try_send(&mut self) -> bool {
+        self.send.take(Instant::now(), Duration::ZERO).is_some()
+    }
+
+    /// Takes a token for receiving a message.
+    ///
+    /// Received messages are never delayed, so this reports
+    /// whether a token was available.
+    pub fn recv(&mut self) -> bool {
+        self.recv.take(Instant::now(), Duration::ZERO).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit {
+            msgs_per_sec: NonZeroU32::new(10).unwrap(),
+            burst: 3,
+            max_wait: Duration::ZERO,
+        };
+        let interval = Duration::from_millis(100);
+        let mut bucket = TokenBucket::new(&limit);
+        let now = Instant::now();
+
+        // A full burst is allowed immediately.
+        for _ in 0..3 {
+            assert_eq!(bucket.take(now, Duration::ZERO), Some(Duration::ZERO));
+        }
+        assert_eq!(bucket.take(now, Duration::ZERO), None);
+
+        // Waiting is allowed up to `max_wait`.
+        assert_eq!(bucket.take(now, interval), Some(interval));
+        assert_eq!(bucket.take(now, interval), None);
+
+        // Tokens are earned over time.
+        let later = now + interval * 2;
+        assert_eq!(bucket.take(later, Duration::ZERO), Some(Duration::ZERO));
+        assert_eq!(bucket.take(later, Duration::ZERO), None);
+
+        // The bucket never holds more than a burst.
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take(much_later, Duration::ZERO).is_some());
+        }
+        assert_eq!(bucket.take(much_later, Duration::ZERO), None);
+
+        // And is full again once the burst has been earned back.
+        assert!(!bucket.is_full(much_later + interval * 2));
+        assert!(bucket.is_full(much_later + interval * 3));
+    }
+}
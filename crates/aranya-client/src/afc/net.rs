@@ -0,0 +1,695 @@
+//! The network that the router uses.
+//!
+//! This is normally the OS's TCP and UDP sockets, but it can
+//! also be a custom [`AfcTransport`] or, with the `sim` feature,
+//! a simulated network.
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::{pin, Pin},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(feature = "sim")]
+use tokio::net::lookup_host;
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{sleep, Instant},
+};
+use tracing::{debug, info};
+
+use super::{bind, AfcError};
+#[cfg(feature = "sim")]
+use crate::sim::{SimListener, SimNet, SimSocket, SimStream};
+use crate::{
+    config::{Outbound, RouterConfig, StreamTimeouts},
+    transport::{AfcConn, AfcTransport},
+};
+
+/// How the router reaches its peers.
+#[derive(Debug)]
+pub(super) enum Net {
+    /// The OS's sockets, opening streams as configured.
+    Os(Outbound),
+    /// A custom transport.
+    Custom(Arc<dyn AfcTransport>),
+    /// A simulated network, as the host bound to `host`.
+    #[cfg(feature = "sim")]
+    Sim { net: SimNet, host: SocketAddr },
+}
+
+impl Net {
+    /// Connects to the first address in `addrs` that accepts
+    /// the connection.
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> io::Result<Conn> {
+        match self {
+            Self::Os(outbound) if *outbound == Outbound::default() => {
+                TcpStream::connect(addrs).await.map(Conn::Tcp)
+            }
+            Self::Os(outbound) => {
+                let mut last_err = None;
+                for &addr in addrs {
+                    match connect_from(outbound, addr).await {
+                        Ok(stream) => return Ok(Conn::Tcp(stream)),
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "could not resolve to any address",
+                    )
+                }))
+            }
+            Self::Custom(transport) => {
+                let mut last_err = None;
+                for &addr in addrs {
+                    match transport.connect(addr).await {
+                        Ok(conn) => return Ok(Conn::Custom(conn)),
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "could not resolve to any address",
+                    )
+                }))
+            }
+            #[cfg(feature = "sim")]
+            Self::Sim { net, host } => {
+                let mut last_err = None;
+                for &addr in addrs {
+                    match net.connect(*host, addr).await {
+                        Ok(stream) => return Ok(Conn::Sim(stream)),
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "could not resolve to any address",
+                    )
+                }))
+            }
+        }
+    }
+}
+
+/// Opens a stream to `addr` as configured by `outbound`.
+async fn connect_from(outbound: &Outbound, addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match addr.ip() {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(ip) = outbound.local_ip {
+        if ip.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("local address {ip} cannot reach {addr}"),
+            ));
+        }
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    if let Some(interface) = &outbound.interface {
+        bind_device(&socket, interface)?;
+    }
+    if let Some(dscp) = outbound.dscp {
+        set_dscp(&socket, addr, dscp)?;
+    }
+    let stream = socket.connect(addr).await?;
+    debug!(%addr, local_addr = ?stream.local_addr().ok(), "opened outbound stream");
+    Ok(stream)
+}
+
+/// Binds `socket` to the network interface named `interface`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    SockRef::from(socket).bind_device(Some(interface.as_bytes()))
+}
+
+/// Binds `socket` to the network interface named `interface`.
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is not supported on this OS",
+    ))
+}
+
+/// Marks the packets that `socket` sends to `addr` with `dscp`.
+fn set_dscp(socket: &TcpSocket, addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    if dscp > Outbound::MAX_DSCP {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("DSCP {dscp} is larger than {}", Outbound::MAX_DSCP),
+        ));
+    }
+    // The DSCP is the upper six bits of the IPv4 TOS field and
+    // the IPv6 traffic class.
+    let tos = u32::from(dscp) << 2;
+    let sock = SockRef::from(socket);
+    match addr {
+        SocketAddr::V4(_) => sock.set_tos(tos),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        SocketAddr::V6(_) => sock.set_tclass_v6(tos),
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "marking IPv6 streams is not supported on this OS",
+        )),
+    }
+}
+
+/// A pending connection attempt started by [`Net::race`].
+type Attempt<'a> = (
+    SocketAddr,
+    Pin<Box<dyn Future<Output = io::Result<Conn>> + Send + 'a>>,
+);
+
+impl Net {
+    /// Connects to one of `addrs`, racing them Happy
+    /// Eyeballs-style (RFC 8305).
+    ///
+    /// Addresses are dialed in order. Once an attempt has been
+    /// pending for `delay`, the next address is dialed without
+    /// abandoning it, and the first attempt to succeed wins. If
+    /// `delay` is `None`, an address is only dialed once the
+    /// previous attempt failed. `failed` is called with each
+    /// address that could not be connected to.
+    ///
+    /// Returns the connection and the address it was made to.
+    pub async fn race<F>(
+        &self,
+        addrs: &[SocketAddr],
+        delay: Option<Duration>,
+        mut failed: F,
+    ) -> io::Result<(Conn, SocketAddr)>
+    where
+        F: FnMut(SocketAddr),
+    {
+        let mut pending = addrs.iter().copied();
+        let mut attempts: Vec<Attempt<'_>> = Vec::new();
+        let mut last_err = None;
+        let mut timer = pin!(sleep(Duration::ZERO));
+        let mut timer_armed = false;
+        // Start the first attempt right away.
+        let mut start_next = true;
+        poll_fn(|cx| loop {
+            if start_next || (timer_armed && timer.as_mut().poll(cx).is_ready()) {
+                start_next = false;
+                timer_armed = false;
+                if let Some(addr) = pending.next() {
+                    debug!(%addr, "dialing peer address");
+                    attempts.push((addr, Box::pin(async move { self.connect(&[addr]).await })));
+                    if let Some(delay) = delay {
+                        timer.as_mut().reset(Instant::now() + delay);
+                        // Poll the timer so that it wakes us.
+                        timer_armed = true;
+                        continue;
+                    }
+                }
+            }
+            if attempts.is_empty() {
+                return Poll::Ready(Err(last_err.take().unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "could not resolve to any address",
+                    )
+                })));
+            }
+            let mut i = 0;
+            while let Some((addr, attempt)) = attempts.get_mut(i) {
+                match attempt.as_mut().poll(cx) {
+                    Poll::Ready(Ok(conn)) => return Poll::Ready(Ok((conn, *addr))),
+                    Poll::Ready(Err(err)) => {
+                        let addr = *addr;
+                        debug!(%addr, %err, "unable to connect to peer address");
+                        failed(addr);
+                        last_err = Some(err);
+                        attempts.swap_remove(i);
+                        // A failed attempt does not need to
+                        // wait for the delay.
+                        start_next = true;
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+            if !start_next {
+                return Poll::Pending;
+            }
+        })
+        .await
+    }
+}
+
+/// Binds the router's listeners and datagram sockets to `addr`
+/// and [`RouterConfig::additional_addrs`].
+pub(super) async fn bind_router(
+    addr: impl ToSocketAddrs,
+    cfg: &RouterConfig,
+) -> Result<(Listeners, Sockets, Net), AfcError> {
+    if let Some(transport) = &cfg.transport {
+        return custom(transport, cfg, true);
+    }
+    let (listener, socket, net) = bind_primary(addr, cfg).await?;
+    let mut listeners = vec![listener];
+    let mut sockets = vec![socket];
+    for &addr in &cfg.additional_addrs {
+        let (listener, socket) = match &net {
+            Net::Os(_) => {
+                let listener = TcpListener::bind(addr).await.map_err(AfcError::Bind)?;
+                let local_addr = listener.local_addr().map_err(AfcError::RouterAddr)?;
+                let udp = UdpSocket::bind(local_addr).await.map_err(AfcError::Bind)?;
+                (Listener::Tcp(listener), Socket::Udp(udp))
+            }
+            // Custom transports return early above.
+            Net::Custom(_) => break,
+            #[cfg(feature = "sim")]
+            Net::Sim { net, .. } => {
+                let (listener, socket) = net.bind(addr).map_err(AfcError::Bind)?;
+                (Listener::Sim(listener), Socket::Sim(socket))
+            }
+        };
+        info!(addr = %listener.local_addr().map_err(AfcError::RouterAddr)?, "bound additional AFC address");
+        listeners.push(listener);
+        sockets.push(socket);
+    }
+    Ok((Listeners(listeners), Sockets(sockets), net))
+}
+
+/// Returns a network for a router that does not listen, so it
+/// can only open streams.
+#[cfg_attr(not(feature = "sim"), allow(unused_variables))]
+pub(super) fn unbound(cfg: &RouterConfig) -> Result<(Listeners, Sockets, Net), AfcError> {
+    if let Some(transport) = &cfg.transport {
+        return custom(transport, cfg, false);
+    }
+    #[cfg(feature = "sim")]
+    if cfg.sim.is_some() {
+        return Err(AfcError::Bind(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "a router on a simulated network must listen",
+        )));
+    }
+    Ok((
+        Listeners(Vec::new()),
+        Sockets(Vec::new()),
+        Net::Os(cfg.outbound.clone()),
+    ))
+}
+
+/// Returns a network for a router that uses a custom
+/// transport, accepting streams from it if `listen` is true.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn custom(
+    transport: &Arc<dyn AfcTransport>,
+    cfg: &RouterConfig,
+    listen: bool,
+) -> Result<(Listeners, Sockets, Net), AfcError> {
+    #[cfg(feature = "tls")]
+    if cfg.tls.is_some() {
+        return Err(AfcError::Bind(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS is not supported over a custom transport",
+        )));
+    }
+    let listeners = if listen {
+        vec![Listener::Custom(Arc::clone(transport))]
+    } else {
+        Vec::new()
+    };
+    Ok((
+        Listeners(listeners),
+        Sockets(Vec::new()),
+        Net::Custom(Arc::clone(transport)),
+    ))
+}
+
+/// Binds the router's primary listener and datagram socket to
+/// `addr`.
+///
+/// See [`bind`] for how `fallback_ports` is used. Simulated
+/// networks do not use fallback ports.
+async fn bind_primary(
+    addr: impl ToSocketAddrs,
+    cfg: &RouterConfig,
+) -> Result<(Listener, Socket, Net), AfcError> {
+    #[cfg(feature = "sim")]
+    if let Some(net) = &cfg.sim {
+        #[cfg(feature = "tls")]
+        if cfg.tls.is_some() {
+            return Err(AfcError::Bind(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS is not supported over a simulated network",
+            )));
+        }
+        let addr = lookup_host(addr)
+            .await
+            .map_err(AfcError::Bind)?
+            .next()
+            .ok_or_else(|| {
+                AfcError::Bind(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "address did not resolve to any addresses",
+                ))
+            })?;
+        let (listener, socket) = net.bind(addr).map_err(AfcError::Bind)?;
+        let host = listener.local_addr();
+        return Ok((
+            Listener::Sim(listener),
+            Socket::Sim(socket),
+            Net::Sim {
+                net: net.clone(),
+                host,
+            },
+        ));
+    }
+    let listener = bind(addr, &cfg.fallback_ports).await?;
+    let local_addr = listener.local_addr().map_err(AfcError::RouterAddr)?;
+    let udp = UdpSocket::bind(local_addr).await.map_err(AfcError::Bind)?;
+    Ok((
+        Listener::Tcp(listener),
+        Socket::Udp(udp),
+        Net::Os(cfg.outbound.clone()),
+    ))
+}
+
+/// Configures the OS's keepalive probes for `stream`, if
+/// [`StreamTimeouts::tcp_keepalive`] is set.
+///
+/// The interval and retries are only set where the OS supports
+/// them.
+pub(super) fn set_tcp_keepalive(stream: &TcpStream, cfg: &StreamTimeouts) -> io::Result<()> {
+    let Some(time) = cfg.tcp_keepalive else {
+        return Ok(());
+    };
+    let ka = TcpKeepalive::new().with_time(time);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        windows,
+    ))]
+    let ka = ka.with_interval(cfg.tcp_keepalive_interval);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+    ))]
+    let ka = ka.with_retries(cfg.tcp_keepalive_retries);
+    SockRef::from(stream).set_tcp_keepalive(&ka)
+}
+
+/// Listens for incoming streams.
+#[derive(Debug)]
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    Custom(Arc<dyn AfcTransport>),
+    #[cfg(feature = "sim")]
+    Sim(SimListener),
+}
+
+impl Listener {
+    /// Polls for an incoming stream.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Conn, SocketAddr)>> {
+        match self {
+            Self::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Conn::Tcp(stream), addr)),
+            Self::Custom(transport) => transport
+                .poll_accept(cx)
+                .map_ok(|(conn, addr)| (Conn::Custom(conn), addr)),
+            #[cfg(feature = "sim")]
+            Self::Sim(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Conn::Sim(stream), addr)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Custom(transport) => transport.local_addr(),
+            #[cfg(feature = "sim")]
+            Self::Sim(listener) => Ok(listener.local_addr()),
+        }
+    }
+}
+
+/// Sends and receives datagrams.
+#[derive(Debug)]
+pub(super) enum Socket {
+    Udp(UdpSocket),
+    #[cfg(feature = "sim")]
+    Sim(SimSocket),
+}
+
+impl Socket {
+    /// Polls until a datagram might be available.
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Self::Udp(udp) => udp.poll_recv_ready(cx),
+            #[cfg(feature = "sim")]
+            Self::Sim(socket) => socket.poll_recv_ready(cx),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Udp(udp) => udp.local_addr(),
+            #[cfg(feature = "sim")]
+            Self::Sim(socket) => Ok(socket.local_addr()),
+        }
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Udp(udp) => udp.try_recv_from(buf),
+            #[cfg(feature = "sim")]
+            Self::Sim(socket) => socket.try_recv_from(buf),
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Self::Udp(udp) => udp.send_to(buf, addr).await,
+            #[cfg(feature = "sim")]
+            Self::Sim(socket) => socket.send_to(buf, addr),
+        }
+    }
+
+    fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Self::Udp(udp) => udp.try_send_to(buf, addr),
+            #[cfg(feature = "sim")]
+            Self::Sim(socket) => socket.send_to(buf, addr),
+        }
+    }
+}
+
+/// The router's listeners, starting with the one bound to the
+/// primary address.
+#[derive(Debug)]
+pub(super) struct Listeners(Vec<Listener>);
+
+impl Listeners {
+    /// Accepts an incoming stream from any listener.
+    pub async fn accept(&mut self) -> io::Result<(Conn, SocketAddr)> {
+        poll_fn(|cx| {
+            for listener in &mut self.0 {
+                if let Poll::Ready(result) = listener.poll_accept(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Returns the primary address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.0.first() {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Returns every bound address.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.0.iter().map(Listener::local_addr).collect()
+    }
+}
+
+/// The router's datagram sockets, one per listener.
+#[derive(Debug)]
+pub(super) struct Sockets(Vec<Socket>);
+
+impl Sockets {
+    /// Waits until a datagram might be available on any socket.
+    pub async fn readable(&self) -> io::Result<()> {
+        poll_fn(|cx| {
+            for socket in &self.0 {
+                if let Poll::Ready(result) = socket.poll_recv_ready(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Receives a datagram from any socket without waiting.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        for socket in &self.0 {
+            match socket.try_recv_from(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    /// Sends a datagram to `addr`.
+    ///
+    /// It is sent from the first socket in the same address
+    /// family as `addr`, if any.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket_for(addr)?.send_to(buf, addr).await
+    }
+
+    /// Sends a datagram to `addr` without waiting.
+    ///
+    /// See [`send_to`][Self::send_to].
+    pub fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.socket_for(addr)?.try_send_to(buf, addr)
+    }
+
+    /// Returns the socket that datagrams to `addr` are sent
+    /// from.
+    fn socket_for(&self, addr: SocketAddr) -> io::Result<&Socket> {
+        let socket = self
+            .0
+            .iter()
+            .find(|s| s.local_addr().is_ok_and(|a| a.is_ipv4() == addr.is_ipv4()))
+            .or_else(|| self.0.first())
+            .ok_or(io::ErrorKind::NotConnected)?;
+        Ok(socket)
+    }
+}
+
+/// A connected stream that has not been handshaked yet.
+#[derive(Debug)]
+pub(super) enum Conn {
+    Tcp(TcpStream),
+    Custom(Box<dyn AfcConn>),
+    #[cfg(feature = "sim")]
+    Sim(SimStream),
+}
+
+impl Conn {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr(),
+            Self::Custom(conn) => conn.peer_addr(),
+            #[cfg(feature = "sim")]
+            Self::Sim(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    /// Writes `buf` without waiting.
+    ///
+    /// Custom transports cannot be written to without waiting,
+    /// so this always fails with [`io::ErrorKind::WouldBlock`]
+    /// for them.
+    pub fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.try_write(buf),
+            Self::Custom(_) => Err(io::ErrorKind::WouldBlock.into()),
+            #[cfg(feature = "sim")]
+            Self::Sim(stream) => stream.try_write(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_race_falls_back() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let up = listener.local_addr().unwrap();
+        // Nothing listens on a port that was just freed.
+        let down = {
+            let l = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            l.local_addr().unwrap()
+        };
+
+        for delay in [None, Some(Duration::from_secs(60))] {
+            let mut failed = Vec::new();
+            let (_, addr) = Net::Os(Outbound::default())
+                .race(&[down, up], delay, |addr| failed.push(addr))
+                .await
+                .unwrap();
+            assert_eq!(addr, up, "{delay:?}");
+            assert_eq!(failed, [down], "{delay:?}");
+        }
+
+        let mut failed = Vec::new();
+        Net::Os(Outbound::default())
+            .race(&[down], None, |addr| failed.push(addr))
+            .await
+            .expect_err("should not connect");
+        assert_eq!(failed, [down]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_outbound() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        let addr = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            listener.local_addr().unwrap().port(),
+        );
+
+        let outbound = Outbound {
+            local_ip: Some(Ipv4Addr::LOCALHOST.into()),
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let stream = connect_from(&outbound, addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(peer.ip(), Ipv4Addr::LOCALHOST);
+        #[cfg(target_os = "linux")]
+        assert_eq!(SockRef::from(&stream).tos().unwrap(), 46 << 2);
+
+        // The local address must be of the peer's IP version.
+        let outbound = Outbound {
+            local_ip: Some(Ipv6Addr::LOCALHOST.into()),
+            ..Default::default()
+        };
+        let err = connect_from(&outbound, addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+        let outbound = Outbound {
+            dscp: Some(Outbound::MAX_DSCP + 1),
+            ..Default::default()
+        };
+        let err = connect_from(&outbound, addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
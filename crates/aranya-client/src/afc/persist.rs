@@ -0,0 +1,74 @@
+//! Channel state that survives restarts.
+//!
+//! Only the channel metadata is persisted. The keys live in the
+//! daemon's shared memory, which outlives the client.
+
+use std::{fs, io, net::SocketAddr, path::Path};
+
+use aranya_daemon_api::{AfcId, NetIdentifier, TeamId};
+use aranya_fast_channels::{Label, NodeId};
+use serde::{Deserialize, Serialize};
+
+/// The current [`Snapshot::version`].
+const VERSION: u32 = 1;
+
+/// The persisted state of the router's channels.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Snapshot {
+    /// The format version.
+    pub version: u32,
+    pub chans: Vec<SavedChan>,
+}
+
+impl Snapshot {
+    pub fn new(chans: Vec<SavedChan>) -> Self {
+        Self {
+            version: VERSION,
+            chans,
+        }
+    }
+}
+
+/// The persisted state of one channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct SavedChan {
+    pub id: AfcId,
+    pub team_id: TeamId,
+    pub net_id: NetIdentifier,
+    pub node_id: NodeId,
+    pub label: Label,
+    pub udp: Option<SocketAddr>,
+    pub self_check: bool,
+    /// Every sequence number up to and including this one might
+    /// have been accepted, if any were.
+    pub reserved: Option<u64>,
+}
+
+/// Loads the snapshot at `path`, if it exists.
+pub(super) fn load(path: &Path) -> io::Result<Option<Snapshot>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // Check the version before decoding the rest, which
+    // depends on it.
+    let (version, _) = postcard::take_from_bytes::<u32>(&buf)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported channel state version {version}"),
+        ));
+    }
+    let snap = postcard::from_bytes::<Snapshot>(&buf)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Some(snap))
+}
+
+/// Durably writes `snap` to `path`.
+pub(super) async fn save(path: &Path, snap: &Snapshot) -> io::Result<()> {
+    let buf = postcard::to_allocvec(snap)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    aranya_util::write_file_atomic(path, &buf).await
+}
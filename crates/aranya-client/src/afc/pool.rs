@@ -0,0 +1,203 @@
+//! Preallocated message buffers.
+
+use std::ops::Deref;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::AfcError;
+
+/// The number of spare buffers that an unbounded pool keeps.
+const MAX_SPARE_BUFS: usize = 8;
+
+/// A pool of message buffers.
+///
+/// In bounded-memory mode (see
+/// [`RouterConfig::bounded`][crate::RouterConfig::bounded]),
+/// every buffer is allocated up front and taking a buffer from
+/// an empty pool is an error. Otherwise, buffers are allocated
+/// on demand and up to [`MAX_SPARE_BUFS`] returned buffers are
+/// kept for reuse, so that steady traffic does not allocate a
+/// buffer per message.
+#[derive(Debug, Default)]
+pub(super) struct BufPool {
+    /// Spare buffers.
+    bufs: Vec<Vec<u8>>,
+    /// The number of bytes that each buffer can hold.
+    size: usize,
+    /// The number of buffers, if bounded.
+    bounded: Option<usize>,
+}
+
+impl BufPool {
+    /// Creates a pool that allocates buffers on demand.
+    pub fn new(size: usize) -> Self {
+        Self {
+            bufs: Vec::new(),
+            size,
+            bounded: None,
+        }
+    }
+
+    /// Creates a pool of `n` preallocated buffers.
+    pub fn bounded(size: usize, n: usize) -> Self {
+        Self {
+            bufs: (0..n).map(|_| Vec::with_capacity(size)).collect(),
+            size,
+            bounded: Some(n),
+        }
+    }
+
+    /// Returns the number of bytes that each buffer can hold
+    /// without allocating.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of spare buffers.
+    pub fn available(&self) -> usize {
+        self.bufs.len()
+    }
+
+    /// Takes an empty buffer from the pool.
+    pub fn take(&mut self) -> Result<Vec<u8>, AfcError> {
+        match self.bufs.pop() {
+            Some(buf) => Ok(buf),
+            None if self.bounded.is_some() => Err(AfcError::Capacity("message buffers")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns `buf` to the pool.
+    ///
+    /// The buffer is zeroed first, since it might contain
+    /// plaintext. Buffers in excess of the pool's size and, if
+    /// bounded, buffers that did not come from the pool are
+    /// dropped.
+    pub fn put(&mut self, mut buf: Vec<u8>) {
+        buf.fill(0);
+        buf.clear();
+        let keep = match self.bounded {
+            Some(n) => buf.capacity() >= self.size && self.bufs.len() < n,
+            None => buf.capacity() > 0 && self.bufs.len() < MAX_SPARE_BUFS,
+        };
+        if keep {
+            self.bufs.push(buf);
+        }
+    }
+}
+
+/// A received buffer whose contents start at an offset.
+///
+/// This lets a message's prefix be skipped without moving the
+/// rest of the buffer, which can then be returned to the
+/// [`BufPool`] whole. It is encoded like `Vec<u8>`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct RecvBuf {
+    buf: Vec<u8>,
+    start: usize,
+}
+
+impl RecvBuf {
+    /// Creates a buffer whose contents are `buf[start..]`.
+    pub fn new(buf: Vec<u8>, start: usize) -> Self {
+        Self { buf, start }
+    }
+
+    /// Returns the underlying buffer, including the skipped
+    /// prefix.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Deref for RecvBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.get(self.start..).unwrap_or_default()
+    }
+}
+
+impl From<Vec<u8>> for RecvBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        Self::new(buf, 0)
+    }
+}
+
+impl Serialize for RecvBuf {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        <[u8]>::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RecvBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buf_pool_bounded() {
+        let mut pool = BufPool::bounded(128, 2);
+        let a = pool.take().unwrap();
+        let mut b = pool.take().unwrap();
+        assert!(a.capacity() >= 128);
+        assert!(matches!(pool.take(), Err(AfcError::Capacity(_))));
+
+        b.extend_from_slice(b"secret");
+        pool.put(b);
+        let b = pool.take().unwrap();
+        assert!(b.is_empty());
+        assert!(b.capacity() >= 128);
+
+        // Buffers that are too small or in excess are dropped.
+        pool.put(Vec::new());
+        assert_eq!(pool.available(), 0);
+        pool.put(a);
+        pool.put(b);
+        pool.put(Vec::with_capacity(128));
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn test_buf_pool_unbounded() {
+        let mut pool = BufPool::new(128);
+        assert_eq!(pool.take().unwrap().capacity(), 0);
+
+        // Returned buffers are reused.
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(b"secret");
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        assert_eq!(pool.available(), 1);
+        let buf = pool.take().unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // Empty buffers and buffers in excess are dropped.
+        pool.put(Vec::new());
+        assert_eq!(pool.available(), 0);
+        for _ in 0..MAX_SPARE_BUFS + 1 {
+            pool.put(Vec::with_capacity(128));
+        }
+        assert_eq!(pool.available(), MAX_SPARE_BUFS);
+    }
+
+    #[test]
+    fn test_recv_buf() {
+        let buf = RecvBuf::new(b"hdr:payload".to_vec(), 4);
+        assert_eq!(&*buf, b"payload");
+        assert_eq!(
+            postcard::to_allocvec(&buf).unwrap(),
+            postcard::to_allocvec(&b"payload".to_vec()).unwrap()
+        );
+        assert_eq!(buf.into_inner(), b"hdr:payload");
+
+        // Out of range offsets are empty rather than panicking.
+        assert!(RecvBuf::new(vec![1, 2], 3).is_empty());
+    }
+}
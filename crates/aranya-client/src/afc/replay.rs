@@ -0,0 +1,202 @@
+//! Replay protection.
+
+/// Rejects replayed sequence numbers while tolerating some
+/// reordering.
+///
+/// This is the anti-replay window from RFC 4303, section
+/// 3.4.3. The bitmap is a ring buffer, as in RFC 6479, so that
+/// advancing the window never shifts it.
+#[derive(Debug)]
+pub(super) struct ReplayWindow {
+    /// The highest accepted sequence number, if any.
+    top: Option<u64>,
+    /// How far below `top` a sequence number may be.
+    size: u64,
+    /// Bit `seq % (64 * bits.len())` is set if `seq` is in the
+    /// window and was accepted.
+    bits: Box<[u64]>,
+}
+
+/// The result of [`ReplayWindow::insert`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum Check {
+    /// The sequence number is the highest so far.
+    Newest,
+    /// The sequence number arrived out of order, but within
+    /// the window.
+    Reordered,
+    /// The sequence number was already accepted or is too old.
+    Replayed,
+}
+
+impl ReplayWindow {
+    /// Creates a window that accepts sequence numbers up to
+    /// `size - 1` below the highest one.
+    ///
+    /// A `size` of zero requires strictly increasing sequence
+    /// numbers.
+    pub fn new(size: u32) -> Self {
+        let words = usize::try_from(size.div_ceil(64)).unwrap_or(usize::MAX);
+        Self {
+            top: None,
+            size: u64::from(size),
+            bits: vec![0; words].into_boxed_slice(),
+        }
+    }
+
+    /// Returns the size of the window.
+    pub fn size(&self) -> u32 {
+        u32::try_from(self.size).unwrap_or(u32::MAX)
+    }
+
+    /// Returns the highest accepted sequence number, if any.
+    pub fn top(&self) -> Option<u64> {
+        self.top
+    }
+
+    /// Creates a window that rejects every sequence number up to
+    /// and including `seq`, e.g., because they might have been
+    /// accepted before a restart.
+    pub fn after(size: u32, seq: u64) -> Self {
+        let mut w = Self::new(size);
+        w.top = Some(seq);
+        w.bits.fill(u64::MAX);
+        w
+    }
+
+    /// Reports whether the highest possible sequence number was
+    /// accepted, which ends the channel.
+    pub fn is_exhausted(&self) -> bool {
+        self.top == Some(u64::MAX)
+    }
+
+    /// Records `seq` unless it was replayed.
+    pub fn insert(&mut self, seq: u64) -> Check {
+        let top = match self.top {
+            Some(top) if seq <= top => top,
+            top => {
+                self.advance(top, seq);
+                return Check::Newest;
+            }
+        };
+        if top - seq >= self.size || self.get(seq) {
+            return Check::Replayed;
+        }
+        self.set(seq, true);
+        Check::Reordered
+    }
+
+    /// Slides the window up to `seq`, which is higher than
+    /// `top`.
+    fn advance(&mut self, top: Option<u64>, seq: u64) {
+        let nbits = self.nbits();
+        match top {
+            Some(top) if seq - top < nbits => {
+                for s in top + 1..seq {
+                    self.set(s, false);
+                }
+            }
+            _ => self.bits.fill(0),
+        }
+        self.top = Some(seq);
+        self.set(seq, true);
+    }
+
+    fn nbits(&self) -> u64 {
+        u64::try_from(self.bits.len())
+            .unwrap_or(u64::MAX)
+            .saturating_mul(64)
+    }
+
+    /// Returns the word and mask of the bit for `seq`.
+    fn bit(&self, seq: u64) -> Option<(usize, u64)> {
+        let idx = seq.checked_rem(self.nbits())?;
+        let word = usize::try_from(idx / 64).ok()?;
+        Some((word, 1 << (idx % 64)))
+    }
+
+    fn get(&self, seq: u64) -> bool {
+        self.bit(seq)
+            .and_then(|(word, mask)| self.bits.get(word).map(|w| w & mask != 0))
+            .unwrap_or(false)
+    }
+
+    fn set(&mut self, seq: u64, on: bool) {
+        let Some((word, mask)) = self.bit(seq) else {
+            return;
+        };
+        if let Some(w) = self.bits.get_mut(word) {
+            if on {
+                *w |= mask;
+            } else {
+                *w &= !mask;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window_strict() {
+        let mut w = ReplayWindow::new(0);
+        assert_eq!(w.insert(5), Check::Newest);
+        assert_eq!(w.insert(5), Check::Replayed);
+        assert_eq!(w.insert(4), Check::Replayed);
+        assert_eq!(w.insert(7), Check::Newest);
+        assert_eq!(w.insert(6), Check::Replayed);
+        assert!(!w.is_exhausted());
+        assert_eq!(w.insert(u64::MAX), Check::Newest);
+        assert!(w.is_exhausted());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut w = ReplayWindow::new(100);
+        assert_eq!(w.insert(10), Check::Newest);
+        // Lower than the first sequence number, but within the
+        // window.
+        assert_eq!(w.insert(3), Check::Reordered);
+        assert_eq!(w.insert(3), Check::Replayed);
+
+        assert_eq!(w.insert(20), Check::Newest);
+        assert_eq!(w.insert(15), Check::Reordered);
+        assert_eq!(w.insert(15), Check::Replayed);
+        assert_eq!(w.insert(10), Check::Replayed);
+        assert_eq!(w.insert(20), Check::Replayed);
+
+        // Sliding the window drops sequence numbers that are
+        // too old and remembers the rest.
+        assert_eq!(w.insert(119), Check::Newest);
+        assert_eq!(w.insert(19), Check::Replayed);
+        assert_eq!(w.insert(20), Check::Replayed);
+        assert_eq!(w.insert(21), Check::Reordered);
+        assert_eq!(w.insert(118), Check::Reordered);
+
+        // Jumps larger than the bitmap clear it.
+        assert_eq!(w.insert(10_000), Check::Newest);
+        for seq in 9_901..10_000 {
+            assert_eq!(w.insert(seq), Check::Reordered, "{seq}");
+        }
+        assert_eq!(w.insert(9_900), Check::Replayed);
+    }
+
+    #[test]
+    fn test_replay_window_after() {
+        for size in [0, 100] {
+            let mut w = ReplayWindow::after(size, 50);
+            for seq in [0, 49, 50] {
+                assert_eq!(w.insert(seq), Check::Replayed, "{size} {seq}");
+            }
+            assert_eq!(w.insert(52), Check::Newest);
+            assert_eq!(w.insert(50), Check::Replayed);
+        }
+        // Sequence numbers skipped after the restart are still
+        // accepted within the window.
+        let mut w = ReplayWindow::after(100, 50);
+        assert_eq!(w.insert(52), Check::Newest);
+        assert_eq!(w.insert(51), Check::Reordered);
+    }
+}
@@ -0,0 +1,489 @@
+//! Session resumption for streams.
+//!
+//! Once the daemon verifies the peer on an incoming stream, we
+//! send the peer a [`Ticket`] (see
+//! [`RouterConfig::session_resumption`][crate::RouterConfig::session_resumption]).
+//! The ticket's token is sealed with the key of the channel that
+//! the peer created, so only the verified peer can learn it,
+//! even without TLS. If the stream breaks, the peer greets us on
+//! its next stream with a `Hello` as usual, and since we issued
+//! it a ticket, we answer with a [`Challenge`]. The peer answers
+//! that with a [`Resume`], which proves that it knows the token
+//! without revealing it: an HMAC with the token over its device
+//! ID and our challenge. A valid proof shows that the peer is
+//! the device that we verified before, so the new stream is
+//! verified right away and takes over the channels of the old
+//! one. Each challenge is fresh and only valid on the stream
+//! that it was sent over, so a `Resume` cannot be replayed on
+//! another stream, even by an attacker on the path.
+//!
+//! Each channel that the peer creates over the stream is bound
+//! to the stream's ticket, so the new stream takes it over even
+//! if the old stream was already reaped. The channels keep their
+//! sequence numbers and replay windows, so messages sent over
+//! the old stream cannot be replayed over the new one.
+//!
+//! Tickets can only be redeemed once, by the device they were
+//! issued to, before they expire. A wrong proof does not burn
+//! the ticket, so that other hosts cannot cancel a peer's
+//! resumption by guessing.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use aranya_buggy::{Bug, BugExt};
+use aranya_crypto::{default::Rng, Csprng};
+use aranya_daemon_api::{AfcId, DeviceId};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tokio::time::Instant;
+
+/// A session resumption token, which is a secret shared with
+/// the peer.
+pub(crate) type Token = [u8; 32];
+
+/// A session resumption ticket issued by the peer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Ticket {
+    /// The channel whose key sealed the token.
+    pub afc_id: AfcId,
+    /// The sealed [`Token`], `header || ciphertext`.
+    pub sealed: Vec<u8>,
+    /// How long the ticket is valid for.
+    pub lifetime: Duration,
+}
+
+/// Sent by the issuer of a [`Ticket`] to the peer that holds
+/// it, once the peer greets it on a new stream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Challenge {
+    pub nonce: [u8; 32],
+}
+
+impl Challenge {
+    /// Creates a fresh `Challenge`.
+    pub fn new() -> Self {
+        let mut nonce = [0; 32];
+        Rng.fill_bytes(&mut nonce);
+        Self { nonce }
+    }
+}
+
+/// Answers a [`Challenge`] from the issuer of a [`Ticket`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Resume {
+    /// Proves that the sender holds the ticket. See [`prove`].
+    pub proof: [u8; 32],
+}
+
+impl Resume {
+    /// Answers `challenge` with the ticket with `token`.
+    pub fn new(token: &Token, device_id: &DeviceId, challenge: &Challenge) -> Result<Self, Bug> {
+        Ok(Self {
+            proof: prove(token, device_id, challenge)?,
+        })
+    }
+}
+
+/// The contextual binding of [`Resume::proof`].
+const PROOF_CONTEXT: &[u8] = b"aranya afc resume v2";
+
+/// Computes HMAC-SHA256 keyed with `token` over `device_id`
+/// and `challenge`.
+fn prove(token: &Token, device_id: &DeviceId, challenge: &Challenge) -> Result<[u8; 32], Bug> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(token)
+        .ok()
+        .assume("HMAC accepts keys of any length")?;
+    mac.update(PROOF_CONTEXT);
+    mac.update(device_id.as_array());
+    mac.update(&challenge.nonce);
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// A ticket that we issued.
+#[derive(Debug)]
+struct Issued {
+    token: Token,
+    /// The stream that the ticket resumes.
+    addr: SocketAddr,
+    expires: Instant,
+    /// The channels created over the stream.
+    chans: BTreeSet<AfcId>,
+}
+
+/// The unexpired [`Tickets`] of a router, handed to another
+/// process.
+///
+/// Expiry times are relative, since an [`Instant`] is only
+/// meaningful in the process that created it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct SavedTickets {
+    /// `(addr, token, valid for)`.
+    held: Vec<(SocketAddr, Token, Duration)>,
+    /// `(peer, token, addr, valid for)`.
+    issued: Vec<(DeviceId, Token, SocketAddr, Duration)>,
+}
+
+/// The tickets that we issued and the ones that we hold.
+#[derive(Debug, Default)]
+pub(super) struct Tickets {
+    /// Tickets issued to us, by the address of the stream that
+    /// we opened with the issuer.
+    held: BTreeMap<SocketAddr, (Token, Instant)>,
+    /// Tickets that we issued, by peer.
+    ///
+    /// Each peer only has its latest ticket.
+    issued: BTreeMap<DeviceId, Issued>,
+}
+
+impl Tickets {
+    /// Issues a ticket for the stream at `addr` with `peer`.
+    ///
+    /// Returns the ticket's token, to be sealed into a
+    /// [`Ticket`], or `None` if `lifetime` is too long to
+    /// represent.
+    pub fn issue(
+        &mut self,
+        peer: DeviceId,
+        addr: SocketAddr,
+        lifetime: Duration,
+        now: Instant,
+    ) -> Option<Token> {
+        self.issued.retain(|_, t| t.expires > now);
+        let expires = now.checked_add(lifetime)?;
+        let mut token = Token::default();
+        Rng.fill_bytes(&mut token);
+        self.issued.insert(
+            peer,
+            Issued {
+                token,
+                addr,
+                expires,
+                chans: BTreeSet::new(),
+            },
+        );
+        Some(token)
+    }
+
+    /// Revokes `peer`'s ticket.
+    pub fn revoke(&mut self, peer: DeviceId) {
+        self.issued.remove(&peer);
+    }
+
+    /// Reports whether `peer` holds an unexpired ticket.
+    pub fn is_issued_to(&self, peer: DeviceId, now: Instant) -> bool {
+        self.issued.get(&peer).is_some_and(|t| t.expires > now)
+    }
+
+    /// Reports whether `peer` holds an unexpired ticket for the
+    /// stream at `addr`.
+    pub fn is_issued(&self, peer: DeviceId, addr: SocketAddr, now: Instant) -> bool {
+        self.issued
+            .get(&peer)
+            .is_some_and(|t| t.addr == addr && t.expires > now)
+    }
+
+    /// Binds channel `id`, which `peer` created over the stream
+    /// at `addr`, to `peer`'s ticket for that stream, if any.
+    pub fn bind(&mut self, peer: DeviceId, addr: SocketAddr, id: AfcId) {
+        if let Some(issued) = self.issued.get_mut(&peer).filter(|t| t.addr == addr) {
+            issued.chans.insert(id);
+        }
+    }
+
+    /// Returns the address of the stream whose unexpired ticket
+    /// channel `id` is bound to, if any.
+    pub fn bound(&self, id: AfcId, now: Instant) -> Option<SocketAddr> {
+        self.issued
+            .values()
+            .find(|t| t.expires > now && t.chans.contains(&id))
+            .map(|t| t.addr)
+    }
+
+    /// Redeems `peer`'s ticket with its answer to `challenge`.
+    ///
+    /// Returns the address of the stream that the ticket
+    /// resumes and the channels bound to it, if `resume` proves
+    /// that `peer` holds its unexpired ticket. The ticket can
+    /// then no longer be redeemed. A wrong proof leaves the
+    /// ticket alone.
+    pub fn redeem(
+        &mut self,
+        peer: DeviceId,
+        challenge: &Challenge,
+        resume: &Resume,
+        now: Instant,
+    ) -> Result<Option<(SocketAddr, BTreeSet<AfcId>)>, Bug> {
+        let Some(issued) = self.issued.get(&peer) else {
+            return Ok(None);
+        };
+        if issued.expires <= now {
+            self.issued.remove(&peer);
+            return Ok(None);
+        }
+        let want = prove(&issued.token, &peer, challenge)?;
+        if !bool::from(want.ct_eq(&resume.proof)) {
+            return Ok(None);
+        }
+        Ok(self
+            .issued
+            .remove(&peer)
+            .map(|issued| (issued.addr, issued.chans)))
+    }
+
+    /// Holds a ticket with `token` issued by the peer on the
+    /// stream at `addr`.
+    pub fn hold(&mut self, addr: SocketAddr, token: Token, lifetime: Duration, now: Instant) {
+        self.held.retain(|_, (_, expires)| *expires > now);
+        if let Some(expires) = now.checked_add(lifetime) {
+            self.held.insert(addr, (token, expires));
+        }
+    }
+
+    /// Takes the unexpired ticket for the stream at `addr`, if
+    /// we hold one.
+    pub fn take(&mut self, addr: &SocketAddr, now: Instant) -> Option<Token> {
+        let (token, expires) = self.held.remove(addr)?;
+        (expires > now).then_some(token)
+    }
+
+    /// Returns the unexpired tickets, to be restored with
+    /// [`restore`][Self::restore] by another process.
+    ///
+    /// The channels bound to the tickets are not saved, since
+    /// the handed off channels keep the addresses of their
+    /// streams (see [`bound`][Self::bound]).
+    pub fn save(&self, now: Instant) -> SavedTickets {
+        let held = self
+            .held
+            .iter()
+            .filter(|(_, (_, expires))| *expires > now)
+            .map(|(addr, (token, expires))| (*addr, *token, expires.duration_since(now)))
+            .collect();
+        let issued = self
+            .issued
+            .iter()
+            .filter(|(_, t)| t.expires > now)
+            .map(|(peer, t)| (*peer, t.token, t.addr, t.expires.duration_since(now)))
+            .collect();
+        SavedTickets { held, issued }
+    }
+
+    /// Restores tickets saved by [`save`][Self::save].
+    pub fn restore(saved: SavedTickets, now: Instant) -> Self {
+        let mut tickets = Self::default();
+        for (addr, token, valid) in saved.held {
+            if let Some(expires) = now.checked_add(valid) {
+                tickets.held.insert(addr, (token, expires));
+            }
+        }
+        for (peer, token, addr, valid) in saved.issued {
+            if let Some(expires) = now.checked_add(valid) {
+                tickets.issued.insert(
+                    peer,
+                    Issued {
+                        token,
+                        addr,
+                        expires,
+                        chans: BTreeSet::new(),
+                    },
+                );
+            }
+        }
+        tickets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aranya_crypto::{csprng::Random, Id};
+
+    use super::*;
+
+    /// Redeems `peer`'s ticket, answering a fresh challenge with
+    /// `token`.
+    fn redeem(
+        tickets: &mut Tickets,
+        peer: DeviceId,
+        token: &Token,
+        now: Instant,
+    ) -> Option<(SocketAddr, BTreeSet<AfcId>)> {
+        let challenge = Challenge::new();
+        let resume = Resume::new(token, &peer, &challenge).unwrap();
+        tickets.redeem(peer, &challenge, &resume, now).unwrap()
+    }
+
+    #[test]
+    fn test_tickets() {
+        let mut tickets = Tickets::default();
+        let peer = DeviceId::default();
+        let old: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Tickets can only be redeemed once.
+        let token = tickets.issue(peer, old, lifetime, start).unwrap();
+        assert!(tickets.is_issued(peer, old, at(1)));
+        assert!(tickets.is_issued_to(peer, at(1)));
+        assert_eq!(
+            redeem(&mut tickets, peer, &token, at(1)),
+            Some((old, BTreeSet::new()))
+        );
+        assert!(!tickets.is_issued(peer, old, at(1)));
+        assert!(!tickets.is_issued_to(peer, at(1)));
+        assert_eq!(redeem(&mut tickets, peer, &token, at(1)), None);
+
+        // Only the latest ticket is valid.
+        let first = tickets.issue(peer, old, lifetime, start).unwrap();
+        let second = tickets.issue(peer, old, lifetime, start).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(redeem(&mut tickets, peer, &first, at(1)), None);
+
+        // A bad proof does not burn the ticket.
+        let token = tickets.issue(peer, old, lifetime, start).unwrap();
+        assert_eq!(redeem(&mut tickets, peer, &[0; 32], at(1)), None);
+        let other = DeviceId::from(Id::random(&mut Rng));
+        let challenge = Challenge::new();
+        let forged = Resume::new(&token, &other, &challenge).unwrap();
+        assert_eq!(
+            tickets.redeem(peer, &challenge, &forged, at(1)).unwrap(),
+            None
+        );
+        assert_eq!(
+            redeem(&mut tickets, peer, &token, at(1)),
+            Some((old, BTreeSet::new()))
+        );
+
+        // Expired tickets cannot be redeemed.
+        let token = tickets.issue(peer, old, lifetime, start).unwrap();
+        assert!(!tickets.is_issued(peer, old, at(60)));
+        assert!(!tickets.is_issued_to(peer, at(60)));
+        assert_eq!(redeem(&mut tickets, peer, &token, at(60)), None);
+    }
+
+    #[test]
+    fn test_replayed_resume() {
+        let mut tickets = Tickets::default();
+        let peer = DeviceId::default();
+        let old: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let start = Instant::now();
+        let token = tickets
+            .issue(peer, old, Duration::from_secs(60), start)
+            .unwrap();
+
+        // An answer to one challenge does not answer another, so
+        // it cannot be replayed over another stream.
+        let seen = Challenge::new();
+        let resume = Resume::new(&token, &peer, &seen).unwrap();
+        let fresh = Challenge::new();
+        assert_eq!(tickets.redeem(peer, &fresh, &resume, start).unwrap(), None);
+        assert_eq!(
+            tickets.redeem(peer, &seen, &resume, start).unwrap(),
+            Some((old, BTreeSet::new()))
+        );
+    }
+
+    #[test]
+    fn test_proof() {
+        let peer = DeviceId::default();
+        let a = Challenge::new();
+        let b = Challenge::new();
+
+        // The proof depends on the challenge, so it is never
+        // reused.
+        assert_ne!(a.nonce, b.nonce);
+        assert_ne!(
+            prove(&[1; 32], &peer, &a).unwrap(),
+            prove(&[1; 32], &peer, &b).unwrap()
+        );
+        assert_ne!(
+            prove(&[1; 32], &peer, &a).unwrap(),
+            prove(&[2; 32], &peer, &a).unwrap()
+        );
+        assert_eq!(
+            Resume::new(&[1; 32], &peer, &a).unwrap().proof,
+            prove(&[1; 32], &peer, &a).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bound_channels() {
+        let mut tickets = Tickets::default();
+        let peer = DeviceId::default();
+        let old: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5678".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let a = AfcId::from(Id::random(&mut Rng));
+        let b = AfcId::from(Id::random(&mut Rng));
+
+        // Channels created over another stream are not bound.
+        let token = tickets.issue(peer, old, lifetime, start).unwrap();
+        tickets.bind(peer, old, a);
+        tickets.bind(peer, other, b);
+        assert_eq!(tickets.bound(a, at(1)), Some(old));
+        assert_eq!(tickets.bound(b, at(1)), None);
+        assert_eq!(tickets.bound(a, at(60)), None);
+        assert_eq!(
+            redeem(&mut tickets, peer, &token, at(1)),
+            Some((old, BTreeSet::from([a])))
+        );
+        assert_eq!(tickets.bound(a, at(1)), None);
+
+        // A new ticket starts without channels.
+        let token = tickets.issue(peer, old, lifetime, start).unwrap();
+        assert_eq!(
+            redeem(&mut tickets, peer, &token, at(1)),
+            Some((old, BTreeSet::new()))
+        );
+    }
+
+    #[test]
+    fn test_held_tickets() {
+        let mut tickets = Tickets::default();
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        tickets.hold(addr, [1; 32], lifetime, start);
+        assert_eq!(tickets.take(&addr, at(59)), Some([1; 32]));
+        assert_eq!(tickets.take(&addr, at(59)), None);
+
+        tickets.hold(addr, [1; 32], lifetime, start);
+        assert_eq!(tickets.take(&addr, at(60)), None);
+    }
+
+    #[test]
+    fn test_saved_tickets() {
+        let mut tickets = Tickets::default();
+        let peer = DeviceId::default();
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let lifetime = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let issued = tickets.issue(peer, addr, lifetime, start).unwrap();
+        tickets.hold(addr, [1; 32], lifetime, start);
+
+        // The expiry times carry over.
+        let mut restored = Tickets::restore(tickets.save(at(30)), at(100));
+        assert_eq!(restored.take(&addr, at(129)), Some([1; 32]));
+        assert_eq!(
+            redeem(&mut restored, peer, &issued, at(129)),
+            Some((addr, BTreeSet::new()))
+        );
+
+        // Expired tickets are not saved.
+        let mut restored = Tickets::restore(tickets.save(at(60)), at(60));
+        assert_eq!(restored.take(&addr, at(60)), None);
+        assert_eq!(redeem(&mut restored, peer, &issued, at(60)), None);
+    }
+}
@@ -0,0 +1,646 @@
+//! TCP streams that are optionally wrapped in TLS, streams over
+//! a custom transport, or streams on a simulated network.
+
+use std::{
+    fmt,
+    future::poll_fn,
+    io, mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, LazyLock},
+    task::{ready, Context, Poll, Wake, Waker},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsStream;
+use tracing::debug;
+
+use super::{encode_wire_header, WIRE_HEADER_SIZE};
+#[cfg(feature = "sim")]
+use crate::sim::SimStream;
+use crate::transport::AfcConn;
+
+/// The size of each stream's read buffer.
+///
+/// Frames are read from the socket in chunks of up to this many
+/// bytes, so a chunk usually holds several small frames.
+const READ_BUF_SIZE: usize = 16 * 1024;
+
+/// Wakes nothing, for polling without waiting.
+static NOOP_WAKER: LazyLock<Waker> = LazyLock::new(|| Waker::from(Arc::new(NoopWaker)));
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// A stream with a peer.
+///
+/// Reads are buffered: the stream reads as much as it can from
+/// the socket at once and frames are parsed out of the buffer,
+/// which saves a few syscalls per frame.
+///
+/// Frames written with [`write_frame`][Self::write_frame] are
+/// never interleaved or cut short by cancellation: see
+/// [`unsent`][Self::unsent].
+pub(super) struct Stream {
+    io: Io,
+    /// Bytes read from `io` ahead of time.
+    ///
+    /// `buf[start..end]` has not been consumed yet.
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    /// The rest of a frame whose write was interrupted, e.g.,
+    /// because the future writing it was dropped.
+    ///
+    /// It is written before anything else so that the peer
+    /// never sees a partial frame followed by the next one.
+    /// This only allocates when a write is interrupted.
+    unsent: Vec<u8>,
+    /// Appended to the message of the next frame written.
+    ///
+    /// See [`set_trailer`][Self::set_trailer].
+    trailer: Vec<u8>,
+}
+
+/// The underlying stream.
+enum Io {
+    /// A plaintext stream.
+    Tcp(TcpStream),
+    /// A TLS stream.
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    /// A stream over a custom transport.
+    Custom(Box<dyn AfcConn>),
+    /// A stream on a simulated network.
+    #[cfg(feature = "sim")]
+    Sim(SimStream),
+}
+
+impl Stream {
+    fn new(io: Io) -> Self {
+        Self {
+            io,
+            buf: vec![0; READ_BUF_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            unsent: Vec::new(),
+            trailer: Vec::new(),
+        }
+    }
+
+    /// Wraps a plaintext stream.
+    pub fn tcp(stream: TcpStream) -> Self {
+        Self::new(Io::Tcp(stream))
+    }
+
+    /// Wraps a TLS stream.
+    #[cfg(feature = "tls")]
+    pub fn tls(stream: TlsStream<TcpStream>) -> Self {
+        Self::new(Io::Tls(Box::new(stream)))
+    }
+
+    /// Wraps a stream over a custom transport.
+    pub fn custom(conn: Box<dyn AfcConn>) -> Self {
+        Self::new(Io::Custom(conn))
+    }
+
+    /// Wraps a stream on a simulated network.
+    #[cfg(feature = "sim")]
+    pub fn sim(stream: SimStream) -> Self {
+        Self::new(Io::Sim(stream))
+    }
+
+    /// Returns the remote peer's address.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match &self.io {
+            Io::Tcp(stream) => stream.peer_addr(),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => stream.get_ref().0.peer_addr(),
+            Io::Custom(conn) => conn.peer_addr(),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    /// Returns the ALPN protocol negotiated by a TLS stream, if
+    /// any.
+    #[cfg(feature = "tls")]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.io {
+            Io::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// Unwraps a TLS stream that nothing was read from.
+    #[cfg(feature = "tls")]
+    pub fn into_tls(self) -> Option<Box<TlsStream<TcpStream>>> {
+        match self.io {
+            Io::Tls(stream) if self.start == self.end => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Reports whether part of a frame still has to be written.
+    pub fn has_unsent(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+
+    /// Appends `trailer` to the message of the next frame
+    /// written, or cancels the previous one if it is empty.
+    ///
+    /// Peers decode a message from the start of a frame and
+    /// ignore whatever follows it, so this carries something
+    /// that only some of them understand.
+    pub fn set_trailer(&mut self, trailer: Vec<u8>) {
+        self.trailer = trailer;
+    }
+
+    /// Writes `frame` to the stream, then flushes it.
+    ///
+    /// This is cancel safe: if the future is dropped after part
+    /// of `frame` was written, the rest is written before the
+    /// next frame or by [`close`][Self::close].
+    pub async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        poll_fn(|cx| self.poll_unsent(cx)).await?;
+        let trailed = with_trailer(frame, &self.trailer);
+        let frame = trailed.as_deref().unwrap_or(frame);
+        let mut w = FrameWriter {
+            stream: &mut *self,
+            frame,
+            n: 0,
+        };
+        while let Some(rest) = frame.get(w.n..).filter(|rest| !rest.is_empty()) {
+            let n = poll_fn(|cx| Pin::new(&mut *w.stream).poll_write(cx, rest)).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            w.n = w.n.saturating_add(n);
+            w.stream.trailer.clear();
+        }
+        drop(w);
+        self.flush().await
+    }
+
+    /// Queues `frame` to be written after the rest of an
+    /// interrupted frame, if any, e.g., by
+    /// [`close`][Self::close].
+    pub fn queue_frame(&mut self, frame: &[u8]) {
+        let trailed = with_trailer(frame, &self.trailer);
+        self.unsent
+            .extend_from_slice(trailed.as_deref().unwrap_or(frame));
+        self.trailer.clear();
+    }
+
+    /// Writes the frames queued with
+    /// [`queue_frame`][Self::queue_frame], then flushes the
+    /// stream.
+    pub fn poll_write_queued(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_unsent(cx))?;
+        Pin::new(self).poll_flush(cx)
+    }
+
+    /// Writes `frame` to the stream without waiting.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if none of
+    /// `frame` could be written, e.g., because the socket's send
+    /// buffer is full or an interrupted frame is still being
+    /// written. If only part of `frame` could be written, the
+    /// rest is written before the next frame, as if the write
+    /// had been interrupted.
+    pub fn try_write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut cx = Context::from_waker(&NOOP_WAKER);
+        if self.poll_unsent(&mut cx)?.is_pending() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let trailed = with_trailer(frame, &self.trailer);
+        let frame = trailed.as_deref().unwrap_or(frame);
+        let mut w = FrameWriter {
+            stream: &mut *self,
+            frame,
+            n: 0,
+        };
+        while let Some(rest) = frame.get(w.n..).filter(|rest| !rest.is_empty()) {
+            match Pin::new(&mut *w.stream).poll_write(&mut cx, rest)? {
+                Poll::Ready(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(n) => {
+                    w.n = w.n.saturating_add(n);
+                    w.stream.trailer.clear();
+                }
+                Poll::Pending if w.n == 0 => return Err(io::ErrorKind::WouldBlock.into()),
+                Poll::Pending => return Ok(()),
+            }
+        }
+        drop(w);
+        // A flush that cannot finish now finishes with the next
+        // write.
+        match Pin::new(self).poll_flush(&mut cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    /// Writes the rest of an interrupted frame, if any.
+    fn poll_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let unsent = mem::take(&mut self.unsent);
+            let res = Pin::new(&mut *self).poll_write(cx, &unsent);
+            self.unsent = unsent;
+            let n = ready!(res)?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..n.min(self.unsent.len()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Finishes writing an interrupted frame, if any, then
+    /// shuts down the write half of the stream.
+    pub async fn close(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_close(cx)).await
+    }
+
+    /// See [`close`][Self::close].
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_unsent(cx))?;
+        Pin::new(self).poll_shutdown(cx)
+    }
+
+    /// Returns the number of buffered bytes.
+    fn buffered(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// Is the stream ready to be read from?
+    ///
+    /// A stream is "ready" if we've received at least the wire
+    /// format header. Neither the socket's readiness nor, for
+    /// TLS, the TCP stream's readiness says whether that is the
+    /// case, so this reads ahead into the buffer.
+    ///
+    /// Reading ahead works the same on every platform and
+    /// transport. Unlike asking the socket how many bytes it
+    /// has (`FIONREAD`), it cannot be misled by platforms or
+    /// socket options that misreport that count.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> io::Result<bool> {
+        while self.buffered() < WIRE_HEADER_SIZE {
+            if self.end == self.buf.len() {
+                // Make room after the partial header.
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end = self.buffered();
+                self.start = 0;
+            }
+            let mut rb = ReadBuf::new(self.buf.get_mut(self.end..).unwrap_or_default());
+            match Pin::new(&mut self.io).poll_read(cx, &mut rb) {
+                Poll::Ready(Ok(())) if rb.filled().is_empty() => {
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                }
+                Poll::Ready(Ok(())) => {
+                    let n = rb.filled().len();
+                    debug!(n, "read ahead");
+                    self.end = self.end.saturating_add(n);
+                }
+                Poll::Ready(Err(err)) => return Err(err),
+                Poll::Pending => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Appends `trailer` to the message in `frame`
+/// (`magic || len || msg`), fixing up `len`.
+///
+/// Returns `None` if there is nothing to append.
+fn with_trailer(frame: &[u8], trailer: &[u8]) -> Option<Vec<u8>> {
+    if trailer.is_empty() {
+        return None;
+    }
+    let (_, msg) = frame.split_first_chunk::<WIRE_HEADER_SIZE>()?;
+    let len = u32::try_from(msg.len().checked_add(trailer.len())?).ok()?;
+    let mut trailed = Vec::with_capacity(frame.len().saturating_add(trailer.len()));
+    trailed.extend_from_slice(&encode_wire_header(len));
+    trailed.extend_from_slice(msg);
+    trailed.extend_from_slice(trailer);
+    Some(trailed)
+}
+
+/// Saves the rest of a frame if its write is interrupted.
+struct FrameWriter<'a> {
+    stream: &'a mut Stream,
+    frame: &'a [u8],
+    /// The number of bytes written.
+    n: usize,
+}
+
+impl Drop for FrameWriter<'_> {
+    fn drop(&mut self) {
+        // If nothing was written, the frame can be dropped as
+        // a whole.
+        if self.n == 0 {
+            return;
+        }
+        if let Some(rest) = self.frame.get(self.n..) {
+            self.stream.unsent.extend_from_slice(rest);
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, stream): (_, &dyn fmt::Debug) = match &self.io {
+            Io::Tcp(stream) => ("Tcp", stream),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => ("Tls", stream.get_ref().0),
+            Io::Custom(conn) => ("Custom", conn),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => ("Sim", stream),
+        };
+        f.debug_struct(name)
+            .field("stream", stream)
+            .field("buffered", &self.buffered())
+            .field("unsent", &self.unsent.len())
+            .field("trailer", &self.trailer.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buffered() == 0 {
+            if out.remaining() >= this.buf.len() {
+                // Large reads bypass the buffer.
+                return Pin::new(&mut this.io).poll_read(cx, out);
+            }
+            let mut rb = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.io).poll_read(cx, &mut rb))?;
+            this.start = 0;
+            this.end = rb.filled().len();
+        }
+        let n = this.buffered().min(out.remaining());
+        let end = this.start.saturating_add(n);
+        if let Some(buf) = this.buf.get(this.start..end) {
+            out.put_slice(buf);
+            this.start = end;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Io {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, out),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => Pin::new(&mut **stream).poll_read(cx, out),
+            Self::Custom(conn) => Pin::new(&mut **conn).poll_read(cx, out),
+            #[cfg(feature = "sim")]
+            Self::Sim(stream) => Pin::new(stream).poll_read(cx, out),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_write(cx, data),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(&mut **stream).poll_write(cx, data),
+            Io::Custom(conn) => Pin::new(&mut **conn).poll_write(cx, data),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => Pin::new(stream).poll_write(cx, data),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(&mut **stream).poll_write_vectored(cx, bufs),
+            Io::Custom(conn) => Pin::new(&mut **conn).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.io {
+            Io::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => stream.is_write_vectored(),
+            Io::Custom(conn) => conn.is_write_vectored(),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(&mut **stream).poll_flush(cx),
+            Io::Custom(conn) => Pin::new(&mut **conn).poll_flush(cx),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            Io::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Io::Tls(stream) => Pin::new(&mut **stream).poll_shutdown(cx),
+            Io::Custom(conn) => Pin::new(&mut **conn).poll_shutdown(cx),
+            #[cfg(feature = "sim")]
+            Io::Sim(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::{timeout, Duration},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_read_ahead() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut stream = Stream::tcp(server);
+
+        // Several frames followed by a partial one.
+        let mut data = Vec::new();
+        for i in 0..3u8 {
+            data.extend_from_slice(&[i; WIRE_HEADER_SIZE]);
+        }
+        data.extend_from_slice(&[0xff; WIRE_HEADER_SIZE - 1]);
+        client.write_all(&data).await.unwrap();
+
+        poll_fn(|cx| match stream.poll_ready(cx) {
+            Ok(false) => Poll::Pending,
+            v => Poll::Ready(v),
+        })
+        .await
+        .unwrap();
+        for i in 0..3u8 {
+            // Every complete header is already buffered.
+            assert!(poll_fn(|cx| Poll::Ready(stream.poll_ready(cx)))
+                .await
+                .unwrap());
+            let mut got = [0u8; WIRE_HEADER_SIZE];
+            stream.read_exact(&mut got).await.unwrap();
+            assert_eq!(got, [i; WIRE_HEADER_SIZE]);
+        }
+        assert_eq!(stream.buffered(), WIRE_HEADER_SIZE - 1);
+
+        // Frames can be larger than the buffer.
+        client.write_all(&[0xff]).await.unwrap();
+        let big = vec![0xee; READ_BUF_SIZE];
+        client.write_all(&big).await.unwrap();
+        let mut got = [0u8; WIRE_HEADER_SIZE];
+        stream.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, [0xff; WIRE_HEADER_SIZE]);
+        let mut got = vec![0u8; READ_BUF_SIZE];
+        stream.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, big);
+
+        drop(client);
+        let mut got = [0u8; 1];
+        assert_eq!(stream.read(&mut got).await.unwrap(), 0);
+    }
+
+    /// Interrupted frame writes are finished before the next
+    /// frame and before the stream is closed.
+    #[tokio::test]
+    async fn test_stream_interrupted_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut stream = Stream::tcp(server);
+
+        // The peer is not reading, so this frame is too large
+        // to be written before the timeout.
+        let big = (0..=250u8)
+            .cycle()
+            .take(64 * 1024 * 1024)
+            .collect::<Vec<_>>();
+        timeout(Duration::from_millis(50), stream.write_frame(&big))
+            .await
+            .unwrap_err();
+        assert!(stream.has_unsent());
+
+        let reader = tokio::spawn(async move {
+            let mut got = vec![0u8; big.len()];
+            peer.read_exact(&mut got).await.unwrap();
+            assert!(got == big, "first frame was corrupted");
+            let mut got = [0u8; 4];
+            peer.read_exact(&mut got).await.unwrap();
+            assert_eq!(got, [1, 2, 3, 4]);
+            // The peer sees the end of the stream after the
+            // second frame.
+            let mut rest = Vec::new();
+            peer.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+
+        stream.write_frame(&[1, 2, 3, 4]).await.unwrap();
+        assert!(!stream.has_unsent());
+        stream.close().await.unwrap();
+        reader.await.unwrap();
+    }
+
+    /// The trailer is appended to the next frame only, and the
+    /// frame's length covers it.
+    #[tokio::test]
+    async fn test_stream_trailer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut stream = Stream::tcp(server);
+
+        let frame = |msg: &[u8]| {
+            let len = u32::try_from(msg.len()).unwrap();
+            [&encode_wire_header(len)[..], msg].concat()
+        };
+        stream.set_trailer(vec![4, 5]);
+        stream.write_frame(&frame(&[1, 2, 3])).await.unwrap();
+        stream.write_frame(&frame(&[6])).await.unwrap();
+        stream.close().await.unwrap();
+
+        let mut got = Vec::new();
+        peer.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, [frame(&[1, 2, 3, 4, 5]), frame(&[6])].concat());
+    }
+
+    /// Frames are not written without waiting while the peer is
+    /// not reading, and the ones that were are not corrupted.
+    #[tokio::test]
+    async fn test_stream_try_write_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut stream = Stream::tcp(server);
+
+        // Fill the socket's buffers.
+        let frame = [0xab; 64 * 1024];
+        let mut sent = 0usize;
+        let err = loop {
+            match stream.try_write_frame(&frame) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(sent > 0);
+
+        // Every frame that was accepted, including one that was
+        // only partially written, reaches the peer.
+        let reader = tokio::spawn(async move {
+            let mut got = vec![0u8; frame.len() * sent];
+            peer.read_exact(&mut got).await.unwrap();
+            assert!(got.iter().all(|&b| b == 0xab));
+            let mut rest = Vec::new();
+            peer.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+        stream.close().await.unwrap();
+        reader.await.unwrap();
+    }
+}
@@ -1,19 +1,49 @@
 //! Client-daemon connection.
 
-use std::{collections::VecDeque, net::SocketAddr, path::Path, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::Infallible,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 
+use aranya_buggy::BugExt;
 pub use aranya_daemon_api::AfcId;
-use aranya_daemon_api::{DaemonApiClient, DeviceId, KeyBundle, NetIdentifier, Role, TeamId, CS};
+use aranya_daemon_api::{
+    BackupInfo, CipherSuiteInfo, DaemonApiClient, DeviceId, GraphFormat, Invitation, KeyBundle,
+    NetIdentifier, PinViolation, PolicyInfo, Role, RoleInfo, SyncKeyId, SyncPin, SyncSession,
+    TeamId, CS,
+};
 use aranya_fast_channels::{self as afc, shm::ReadState, ChannelId};
-pub use aranya_fast_channels::{Label, Seq};
-use aranya_util::addr::Addr;
-use tarpc::{context, tokio_serde::formats::Json};
-use tokio::net::ToSocketAddrs;
-use tracing::{debug, info, instrument};
+pub use aranya_fast_channels::{Label, Seq, Version};
+use aranya_util::{addr::Addr, display::ShortId};
+use tarpc::context;
+use tokio::{
+    io::AsyncRead,
+    net::ToSocketAddrs,
+    runtime::Handle,
+    sync::mpsc::{self, error::SendError},
+};
+use tracing::{debug, error, field::display, info, instrument, warn, Span};
 
 use crate::{
-    afc::{setup_afc_shm, Afc, Msg, State},
-    Error, Result,
+    afc::{
+        check_shm_layout, setup_afc_shm, Afc, AfcError, ChannelMetadata, ChannelStats,
+        ChannelSummary, Close, DisconnectReason, DnsStats, ExpiryReason, Ext, GoodbyeReason,
+        Handoff, IdempotencyToken, Limits, Liveness, Msg, Nack, Opened, Priority, QueueChange,
+        ResolveDebug, State,
+    },
+    daemon::{DaemonStatus, Link},
+    error::{rpc, ErrorContext, ResultExt},
+    metrics,
+    rpc::{Pending, Request, RequestId, Response, Rpc},
+    schedule::LabelQueues,
+    ChannelExpiry, ChannelOptions, Error, LabelSchedule, ListenOptions, MemoryBounds,
+    PayloadRejection, PayloadValidator, PeerReputation, RateLimit, Result, RouterConfig,
+    RouterStats, SendOptions, StreamStats, TrySendError,
 };
 
 /// Data that can be polled by the AFC router.
@@ -29,6 +59,22 @@ pub struct PollData(State);
 /// The client provides AFC functionality by interfacing with AFC
 /// utilities in the Aranya core crates.
 ///
+/// # Shutdown
+///
+/// The peer never sees part of a frame followed by the next
+/// one: if a future that sends data is dropped in the middle of
+/// writing a frame, the rest of the frame is written before the
+/// next one. When the client is shut down with
+/// [`shutdown`][Self::shutdown] or dropped, such frames are
+/// finished and the streams are closed before the rest of the
+/// router (e.g., the shared memory mapping) is torn down, taking
+/// at most [`RouterConfig::close_timeout`]. Dropping the client
+/// does this in a background task, which requires a Tokio
+/// runtime; outside of one, the streams are closed right away.
+/// A stream that is closed in the middle of a frame ends with an
+/// error on the peer's side, so the peer never waits for the
+/// rest of the frame.
+///
 /// [Aranya daemon]: https://crates.io/crates/aranya-daemon
 /// [`aranya-daemon-api`]: https://crates.io/crates/aranya-daemon-api
 /// [`tarpc`]: https://crates.io/crates/tarpc
@@ -36,14 +82,73 @@ pub struct PollData(State);
 pub struct Client {
     /// RPC connection to the daemon.
     daemon: DaemonApiClient,
+    /// Supervises `daemon`.
+    link: Link,
     /// AFC support.
     afc: Afc<ReadState<CS>>,
     /// Messages from `handle_data`.
-    msgs: VecDeque<AfcMsg>,
+    msgs: LabelQueues,
+    /// Events from `handle_data`.
+    events: VecDeque<AfcEvent>,
+    /// Requests from `handle_data`.
+    requests: VecDeque<Request>,
+    /// See [`Client::request`].
+    pending: Pending,
+    /// The number of messages and requests of each channel that
+    /// are waiting to be read.
+    ///
+    /// See [`RouterConfig::flow_control`].
+    queued: BTreeMap<AfcId, usize>,
+    /// Subscribers to changes to the set of channels.
+    watchers: Vec<mpsc::UnboundedSender<ChannelSetDelta>>,
+    /// See [`Client::subscribe`].
+    subscribers: BTreeMap<AfcId, mpsc::Sender<(Vec<u8>, Label, Seq)>>,
+    /// See [`RouterConfig::bounded`].
+    bounds: Option<MemoryBounds>,
+    /// See [`Client::set_payload_validator`].
+    validators: BTreeMap<Label, PayloadValidator>,
+    /// The daemon's shared memory, which
+    /// [`Client::isolate_team`] opens again.
+    afc_shm_path: PathBuf,
+    max_chans: usize,
+    /// The teams whose channels this client handles.
+    teams: Routing,
+    /// The sequence number of the last clock jump that was
+    /// reported.
+    clock_jump: Option<u64>,
     #[cfg(feature = "debug")]
     name: String,
 }
 
+/// The teams whose channels a [`Client`] handles.
+#[derive(Clone, Debug)]
+enum Routing {
+    /// Every team except the ones that were isolated with
+    /// [`Client::isolate_team`].
+    AllExcept(BTreeSet<TeamId>),
+    /// Only this team. See [`Client::isolate_team`].
+    Only(TeamId),
+}
+
+/// Creates the context for a daemon RPC made by `operation`.
+///
+/// The daemon logs the context's trace ID along with the RPC, so
+/// it is logged here too so that the two can be correlated.
+fn rpc_context(operation: &'static str) -> context::Context {
+    let ctx = context::current();
+    debug!(trace_id = %ctx.trace_id(), operation, "calling daemon");
+    ctx
+}
+
+impl Routing {
+    fn routes(&self, team_id: TeamId) -> bool {
+        match self {
+            Self::AllExcept(isolated) => !isolated.contains(&team_id),
+            Self::Only(id) => *id == team_id,
+        }
+    }
+}
+
 /// An Aranya Fast Channel message.
 #[derive(Clone, Debug, PartialEq)]
 pub struct AfcMsg {
@@ -57,9 +162,336 @@ pub struct AfcMsg {
     pub label: Label,
     /// The order of the message in the channel.
     pub seq: Seq,
+    /// When the peer sent the message, if it sends timestamps.
+    ///
+    /// See [`RouterConfig::send_timestamps`].
+    pub sent_at: Option<SystemTime>,
+}
+
+/// An Aranya Fast Channel event.
+///
+/// Events report things that happened on a channel other than
+/// receiving data, which is read with
+/// [`Client::try_recv_data`]. The exception is
+/// [`AfcEvent::Data`], which is only produced by
+/// `Client::afc_events` (requires the `stream` feature).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum AfcEvent {
+    /// A peer opened a channel with us.
+    ChannelOpened {
+        /// The address from which the channel was opened.
+        addr: SocketAddr,
+        /// The new channel.
+        channel: AfcId,
+        /// The channel's label.
+        label: Label,
+    },
+    /// A message was received.
+    ///
+    /// Only produced by `Client::afc_events`.
+    Data(AfcMsg),
+    /// The stream with a peer was closed.
+    ///
+    /// Channels that use the stream reconnect the next time
+    /// they send data. Applications that would rather not wait
+    /// for that can re-establish or tear down the channels now.
+    PeerDisconnected {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// Why the stream was closed.
+        reason: DisconnectReason,
+    },
+    /// The channel's sequence numbers were exhausted, so the
+    /// channel cannot receive any more data. A new channel must
+    /// be created.
+    ChannelExpired {
+        /// The expired channel.
+        channel: AfcId,
+    },
+    /// The daemon removed a channel, e.g., because a policy
+    /// change revoked its label.
+    ///
+    /// See [`Client::reconcile_channels`].
+    ChannelRevoked {
+        /// The revoked channel.
+        channel: AfcId,
+    },
+    /// A channel was deleted to make room for a new one.
+    ///
+    /// See [`CapacityPolicy`][crate::CapacityPolicy].
+    ChannelEvicted {
+        /// The evicted channel.
+        channel: AfcId,
+    },
+    /// Whether the channel's peer is reachable changed.
+    ///
+    /// See [`RouterConfig::keepalive`].
+    LivenessChanged {
+        /// The channel.
+        channel: AfcId,
+        /// The channel's new liveness.
+        liveness: Liveness,
+    },
+    /// The channel reached a limit of its
+    /// [`ChannelExpiry`], so it can no longer be used. A new
+    /// channel must be created.
+    ///
+    /// If the policy says so, the channel is then deleted and
+    /// reported as removed with [`RemovalReason::Expired`].
+    ExpiryReached {
+        /// The expired channel.
+        channel: AfcId,
+        /// Which limit the channel reached.
+        reason: ExpiryReason,
+    },
+    /// A message was not delivered because a message with the
+    /// same idempotency token was recently delivered on the same
+    /// channel.
+    ///
+    /// See [`SendOptions::token`].
+    Duplicate {
+        /// The address from which the message was received.
+        addr: SocketAddr,
+        /// The channel from which the message was received.
+        channel: AfcId,
+        /// The message's idempotency token.
+        token: IdempotencyToken,
+        /// The order of the message in the channel.
+        seq: Seq,
+    },
+    /// The peer dropped data that we sent on a channel because
+    /// it removed the channel.
+    ///
+    /// See [`RemovedChannelPolicy`][crate::RemovedChannelPolicy].
+    ChannelRemovedByPeer {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// The channel that the peer removed.
+        channel: AfcId,
+    },
+    /// The peer deleted a channel with
+    /// [`Client::delete_channel`], so we removed it too.
+    ///
+    /// Using the channel afterwards fails with
+    /// [`AfcError::ChannelNotFound`].
+    ChannelClosedByPeer {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// The deleted channel.
+        channel: AfcId,
+    },
+    /// Messages queued after a transient failure could not be
+    /// resent and were dropped.
+    ///
+    /// See [`RouterConfig::max_reconnect_attempts`].
+    SendFailed {
+        /// The channel the messages were sent over.
+        channel: AfcId,
+        /// The number of dropped messages.
+        dropped: usize,
+    },
+    /// The number of messages waiting to be written to the
+    /// stream with a peer reached the high watermark.
+    ///
+    /// See [`RouterConfig::queue_watermarks`].
+    StreamCongested {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// The number of queued messages.
+        queued: usize,
+    },
+    /// The peer does not speak the wire version that we greeted
+    /// it with.
+    ///
+    /// It keeps the stream open for a while in case we can
+    /// greet it with one of the versions it speaks. Otherwise,
+    /// it closes the stream, which is reported as
+    /// [`AfcEvent::PeerDisconnected`].
+    ///
+    /// See [`VersionMismatchPolicy::Advertise`][crate::VersionMismatchPolicy::Advertise].
+    VersionsAdvertised {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// The versions that the peer speaks.
+        supported: Vec<Version>,
+    },
+    /// The peer closed the stream with us.
+    ///
+    /// Unless the reason is [`GoodbyeReason::Duplicate`], this
+    /// is followed by [`AfcEvent::PeerDisconnected`].
+    Goodbye {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// Why the peer closed the stream.
+        reason: GoodbyeReason,
+    },
+    /// The number of messages waiting to be written to
+    /// a congested stream fell to the low watermark.
+    ///
+    /// See [`RouterConfig::queue_watermarks`].
+    StreamDrained {
+        /// The peer's address.
+        addr: SocketAddr,
+        /// The number of queued messages.
+        queued: usize,
+    },
+    /// A partially received stream was dropped because
+    /// a fragment was lost or because it was larger than
+    /// [`RouterConfig::max_stream_size`].
+    ///
+    /// See [`Client::send_stream`].
+    StreamDropped {
+        /// The address from which the stream was received.
+        addr: SocketAddr,
+        /// The channel from which the stream was received.
+        channel: AfcId,
+        /// The order of the message that dropped the stream.
+        seq: Seq,
+    },
+    /// A message was not delivered because its checksum did not
+    /// match its plaintext.
+    ///
+    /// See [`RouterConfig::self_check`].
+    ChecksumMismatch {
+        /// The address from which the message was received.
+        addr: SocketAddr,
+        /// The channel from which the message was received.
+        channel: AfcId,
+        /// The order of the message in the channel.
+        seq: Seq,
+    },
+    /// A message was not delivered because its timestamp was
+    /// too far in the future.
+    ///
+    /// See [`RouterConfig::max_clock_skew`].
+    TimestampRejected {
+        /// The address from which the message was received.
+        addr: SocketAddr,
+        /// The channel from which the message was received.
+        channel: AfcId,
+        /// The order of the message in the channel.
+        seq: Seq,
+        /// When the peer claims to have sent the message.
+        sent_at: SystemTime,
+    },
+    /// A message was not delivered because the channel's
+    /// label's payload validator rejected it.
+    ///
+    /// See [`Client::set_payload_validator`].
+    PayloadRejected {
+        /// The address from which the message was received.
+        addr: SocketAddr,
+        /// The channel from which the message was received.
+        channel: AfcId,
+        /// The order of the message in the channel.
+        seq: Seq,
+        /// Why the message was rejected.
+        reason: PayloadRejection,
+    },
+    /// A message was not delivered because too many messages
+    /// with the same label were waiting to be read.
+    ///
+    /// See [`LabelSchedule::max_queued`].
+    LabelQueueFull {
+        /// The address from which the message was received.
+        addr: SocketAddr,
+        /// The channel from which the message was received.
+        channel: AfcId,
+        /// The channel's label.
+        label: Label,
+        /// The order of the message in the channel.
+        seq: Seq,
+    },
+    /// A response was dropped because no request was waiting
+    /// for it: the request timed out or the response was
+    /// a duplicate.
+    ///
+    /// See [`Client::request`].
+    ResponseDropped {
+        /// The address from which the response was received.
+        addr: SocketAddr,
+        /// The channel from which the response was received.
+        channel: AfcId,
+        /// The request that the response answers.
+        request: RequestId,
+        /// The order of the response in the channel.
+        seq: Seq,
+    },
+    /// The daemon's wall clock jumped, e.g., because it was set
+    /// by NTP.
+    ///
+    /// Timestamps taken before the jump, such as
+    /// [`AfcMsg::sent_at`] or [`BackupInfo::created`], are off
+    /// by `by` compared to timestamps taken afterwards. Timeouts
+    /// and intervals are not affected.
+    ///
+    /// Checked whenever channels are reconciled. See
+    /// [`RouterConfig::reconcile_interval`].
+    ClockJumped {
+        /// When the jump was detected, according to the new
+        /// wall clock.
+        at: SystemTime,
+        /// How far the wall clock jumped.
+        by: Duration,
+        /// Whether the wall clock jumped backward.
+        backward: bool,
+    },
+}
+
+/// A change to the set of AFC channels.
+///
+/// Produced by `Client::watch_channels` (requires the `stream`
+/// feature).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ChannelSetDelta {
+    /// A channel was created, either by us or by a peer.
+    Added(ChannelSummary),
+    /// A channel was removed.
+    Removed {
+        /// The removed channel.
+        channel: AfcId,
+        /// Why the channel was removed.
+        reason: RemovalReason,
+    },
+    /// The channel's peer was rotated to a new
+    /// [`NetIdentifier`] with [`Client::update_channel_peer`],
+    /// or its metadata was changed with
+    /// [`Client::set_channel_metadata`] or
+    /// [`Client::remove_channel_metadata`].
+    Updated(ChannelSummary),
+}
+
+/// Why a channel was removed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RemovalReason {
+    /// The channel was deleted with [`Client::delete_channel`].
+    Deleted,
+    /// The daemon removed the channel.
+    ///
+    /// See [`AfcEvent::ChannelRevoked`].
+    Revoked,
+    /// The peer deleted the channel.
+    ///
+    /// See [`AfcEvent::ChannelClosedByPeer`].
+    ClosedByPeer,
+    /// The channel expired and was deleted.
+    ///
+    /// See [`ChannelExpiry::delete`].
+    Expired,
+    /// The channel was deleted to make room for a new one.
+    ///
+    /// See [`AfcEvent::ChannelEvicted`].
+    Evicted,
 }
 
 impl Client {
+    /// How many messages a receiver from
+    /// [`subscribe`][Self::subscribe] holds.
+    pub const SUBSCRIPTION_CAPACITY: usize = 64;
+
     /// Creates a client connection to the daemon.
     ///
     /// - `daemon_sock`: The socket path to communicate with the
@@ -71,37 +503,279 @@ impl Client {
     ///   number.
     /// - `afc_listen_addr`: The address that AFC listens for
     ///   incoming connections on.
-    #[instrument(skip_all, fields(?daemon_sock, ?afc_shm_path, max_chans))]
     pub async fn connect<A>(
         daemon_sock: &Path,
         afc_shm_path: &Path,
         max_chans: usize,
         afc_listen_addr: A,
     ) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Self::connect_with_config(
+            daemon_sock,
+            afc_shm_path,
+            max_chans,
+            afc_listen_addr,
+            RouterConfig::default(),
+        )
+        .await
+    }
+
+    /// Like [`connect`][Self::connect], but configures the AFC
+    /// router with `cfg`.
+    ///
+    /// If `afc_listen_addr` cannot be bound, the router falls
+    /// back to [`RouterConfig::fallback_ports`]. The router
+    /// advertises the port that it actually bound, which is
+    /// returned by [`afc_local_addr`][Self::afc_local_addr], when
+    /// it greets its peers, and peers that learn it dial it
+    /// instead of the one in the router's net identifier.
+    ///
+    /// If [`RouterConfig::channel_state_path`] is set, the
+    /// channels open before the client last stopped are
+    /// restored and reconciled with the daemon. Channels that
+    /// the daemon removed in the meantime are reported as
+    /// [`AfcEvent::ChannelRevoked`].
+    #[instrument(skip_all, fields(?daemon_sock, ?afc_shm_path, max_chans, ?cfg))]
+    pub async fn connect_with_config<A>(
+        daemon_sock: &Path,
+        afc_shm_path: &Path,
+        max_chans: usize,
+        afc_listen_addr: A,
+        cfg: RouterConfig,
+    ) -> Result<Self>
     where
         A: ToSocketAddrs,
     {
         info!("starting Aranya client");
 
-        let transport = tarpc::serde_transport::unix::connect(daemon_sock, Json::default)
+        let ctx = || ErrorContext::new("connect_with_config");
+        let (daemon, device_id, link) = Self::connect_daemon(daemon_sock, max_chans)
+            .await
+            .context(ctx)?;
+        Self::with_router(
+            daemon,
+            link,
+            device_id,
+            afc_shm_path,
+            max_chans,
+            Some(afc_listen_addr),
+            cfg,
+            Routing::AllExcept(BTreeSet::new()),
+            None,
+        )
+        .await
+        .context(ctx)
+    }
+
+    /// Like [`connect_with_config`][Self::connect_with_config],
+    /// but takes over the AFC router of a client in another
+    /// process that is calling [`hand_off`][Self::hand_off] with
+    /// `handoff_sock`, e.g., to upgrade the client in place.
+    ///
+    /// The router listens on the address that the old one
+    /// listened on and keeps its channels, which are reconciled
+    /// with the daemon. Fails if the old client is not waiting
+    /// on `handoff_sock` yet, in which case it can be retried.
+    #[instrument(skip_all, fields(?daemon_sock, ?afc_shm_path, max_chans, ?handoff_sock, ?cfg))]
+    pub async fn take_over(
+        daemon_sock: &Path,
+        afc_shm_path: &Path,
+        max_chans: usize,
+        handoff_sock: &Path,
+        cfg: RouterConfig,
+    ) -> Result<Self> {
+        info!("taking over Aranya client");
+
+        // Connect to the daemon first: once we connect to the
+        // old client, it stops routing.
+        let ctx = || ErrorContext::new("take_over");
+        let (daemon, device_id, link) = Self::connect_daemon(daemon_sock, max_chans)
             .await
-            .map_err(Error::Connecting)?;
-        let daemon = DaemonApiClient::new(tarpc::client::Config::default(), transport).spawn();
+            .context(ctx)?;
+        let handoff = Handoff::receive(handoff_sock)
+            .await
+            .map_err(AfcError::Handoff)
+            .context(ctx)?;
+        debug!(addr = ?handoff.addr, "received router state");
+
+        Self::with_router(
+            daemon,
+            link,
+            device_id,
+            afc_shm_path,
+            max_chans,
+            handoff.addr,
+            cfg,
+            Routing::AllExcept(BTreeSet::new()),
+            Some(handoff),
+        )
+        .await
+        .context(ctx)
+    }
+
+    /// Connects to the daemon and checks that its shm layout
+    /// matches.
+    async fn connect_daemon(
+        daemon_sock: &Path,
+        max_chans: usize,
+    ) -> Result<(DaemonApiClient, DeviceId, Link)> {
+        let (daemon, link) = Link::connect(daemon_sock).await?;
         debug!("connected to daemon");
 
+        let ctx = rpc_context("connect");
+        let device_id = daemon.get_device_id(ctx).await??;
+        debug!(%device_id, "retrieved device ID");
+
+        let layout = daemon.afc_shm_layout(ctx).await??;
+        check_shm_layout(&layout, max_chans)?;
+        debug!("validated shm layout");
+
+        Ok((daemon, device_id, link))
+    }
+
+    /// Creates a client with its own AFC router, taking over the
+    /// state in `handoff`, if any.
+    #[allow(clippy::too_many_arguments)]
+    async fn with_router<A>(
+        daemon: DaemonApiClient,
+        link: Link,
+        device_id: DeviceId,
+        afc_shm_path: &Path,
+        max_chans: usize,
+        afc_listen_addr: Option<A>,
+        cfg: RouterConfig,
+        teams: Routing,
+        handoff: Option<Handoff>,
+    ) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let bounds = cfg.bounded;
+        let events = match bounds {
+            Some(bounds) => VecDeque::with_capacity(bounds.max_queued_events),
+            None => VecDeque::new(),
+        };
+
+        let mut msgs = LabelQueues::default();
+        for (label, opts) in &cfg.label_options {
+            if let Some(schedule) = opts.schedule {
+                msgs.set_schedule(*label, schedule);
+            }
+        }
+
+        // Only jumps after the client connected are reported.
+        let clock_jump = daemon
+            .clock_jumps(rpc_context("with_router"), None)
+            .await??
+            .last()
+            .map(|jump| jump.seq);
+
         let read = setup_afc_shm(afc_shm_path, max_chans)?;
-        let afc = Afc::new(afc::Client::new(read), afc_listen_addr).await?;
-        debug!(
-            addr = ?afc.local_addr().map_err(Error::Afc)?,
-            "bound AFC router",
-        );
-        Ok(Self {
+        let mut afc = Afc::new(
+            afc::Client::new(read),
+            afc_listen_addr,
+            device_id,
+            max_chans,
+            cfg,
+        )
+        .await?;
+        if let Some(handoff) = handoff {
+            afc.take_over(handoff).await;
+        }
+        let mut client = Self {
             daemon,
+            link,
             afc,
-            msgs: VecDeque::new(),
+            msgs,
+            events,
+            requests: VecDeque::new(),
+            pending: Pending::default(),
+            queued: BTreeMap::new(),
+            watchers: Vec::new(),
+            subscribers: BTreeMap::new(),
+            validators: BTreeMap::new(),
+            afc_shm_path: afc_shm_path.to_owned(),
+            max_chans,
+            teams,
+            clock_jump,
+            bounds,
             #[cfg(feature = "debug")]
             name: String::new(),
-        })
+        };
+        // Channels restored from `channel_state_path` or handed
+        // off might have been removed while the client was down.
+        if client.afc.has_channels() {
+            if let Err(err) = client.reconcile_channels().await {
+                warn!(%err, "unable to reconcile restored channels");
+            }
+        }
+        Ok(client)
+    }
+
+    /// Handles the new channels of `team_id` with a separate AFC
+    /// router.
+    ///
+    /// The returned client has its own streams, buffers, queues,
+    /// and limits, configured with [`ListenOptions::config`], so
+    /// that a flood of data or a hostile peer in one team cannot
+    /// delay the channels of another. Poll it separately from
+    /// this client, e.g., on its own task. It shares this
+    /// client's daemon connection and can only create or accept
+    /// channels for `team_id`.
+    ///
+    /// If the router listens (see [`ListenOptions::addr`]), the
+    /// team's peers should be told the address that it bound
+    /// (see [`afc_local_addr`][Self::afc_local_addr]), e.g.,
+    /// with [`Team::assign_net_identifier`].
+    ///
+    /// Afterwards, this client rejects new channels for
+    /// `team_id` with [`AfcError::TeamNotRouted`]. Channels that
+    /// are already open stay with this client. Calling this
+    /// again for the same team creates another router, so drop
+    /// the previous one first. The router's
+    /// [`RouterConfig::channel_state_path`], if set, must differ
+    /// from this client's.
+    ///
+    /// It is an error to call this on a client that was
+    /// returned by this method.
+    #[instrument(skip_all, fields(self = self.debug(), team_id = %ShortId::new("team", team_id), addr = ?opts.addr))]
+    pub async fn isolate_team(&mut self, team_id: TeamId, opts: ListenOptions) -> Result<Client> {
+        let ctx = || ErrorContext::new("isolate_team").team(team_id);
+        if matches!(self.teams, Routing::Only(_)) {
+            return Err(AfcError::TeamNotRouted(team_id)).context(ctx);
+        }
+        let device_id =
+            rpc(self.daemon.get_device_id(rpc_context("isolate_team")).await).context(ctx)?;
+        let client = Self::with_router(
+            self.daemon.clone(),
+            self.link.clone(),
+            device_id,
+            &self.afc_shm_path,
+            self.max_chans,
+            opts.addr,
+            opts.config,
+            Routing::Only(team_id),
+            None,
+        )
+        .await
+        .context(ctx)?;
+        if let Routing::AllExcept(isolated) = &mut self.teams {
+            isolated.insert(team_id);
+        }
+        info!("isolated team");
+        Ok(client)
+    }
+
+    /// Fails if this client does not handle the channels of
+    /// `team_id`. See [`isolate_team`][Self::isolate_team].
+    fn check_routed(&self, team_id: TeamId) -> Result<(), AfcError> {
+        if self.teams.routes(team_id) {
+            Ok(())
+        } else {
+            Err(AfcError::TeamNotRouted(team_id))
+        }
     }
 
     #[doc(hidden)]
@@ -125,12 +799,235 @@ impl Client {
     /// Returns the address that the Aranya sync server is bound
     /// to.
     pub async fn aranya_local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.daemon.aranya_local_addr(context::current()).await??)
+        rpc(self
+            .daemon
+            .aranya_local_addr(rpc_context("aranya_local_addr"))
+            .await)
+        .context(|| ErrorContext::new("aranya_local_addr"))
+    }
+
+    /// Returns the state of the client's connection to the
+    /// daemon.
+    ///
+    /// If the connection broke (e.g., because the daemon
+    /// restarted), the client reconnects while it is polled.
+    pub fn daemon_status(&self) -> DaemonStatus {
+        self.link.status()
+    }
+
+    /// Reconnects to the daemon, e.g., after it restarted.
+    ///
+    /// The client does this by itself while it is polled, backing
+    /// off between attempts (see
+    /// [`daemon_status`][Self::daemon_status]). Afterwards, the
+    /// daemon's shared memory is opened again and the channels
+    /// are reconciled with the daemon: the channels that it
+    /// forgot are reported as [`AfcEvent::ChannelRevoked`].
+    ///
+    /// It is an error if the daemon now has another device ID.
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub async fn reconnect_daemon(&mut self) -> Result<()> {
+        let result = self
+            .try_reconnect_daemon()
+            .await
+            .context(|| ErrorContext::new("reconnect_daemon"));
+        if result.is_err() {
+            self.link.failed();
+        }
+        result
+    }
+
+    async fn try_reconnect_daemon(&mut self) -> Result<()> {
+        let (daemon, device_id, link) =
+            Self::connect_daemon(self.link.sock(), self.max_chans).await?;
+        let want = self.afc.device_id();
+        if device_id != want {
+            return Err(Error::Connecting(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("daemon has device ID {device_id}, expected {want}"),
+            )));
+        }
+        // Clock jumps are numbered by the daemon, which might
+        // have restarted.
+        let clock_jump = daemon
+            .clock_jumps(rpc_context("try_reconnect_daemon"), None)
+            .await??
+            .last()
+            .map(|jump| jump.seq);
+        // So might its shared memory have been.
+        let read = setup_afc_shm(&self.afc_shm_path, self.max_chans)?;
+        self.afc.replace_shm(afc::Client::new(read));
+        self.daemon = daemon;
+        self.link = link;
+        self.clock_jump = clock_jump;
+        info!("reconnected to daemon");
+
+        if self.afc.has_channels() {
+            if let Err(err) = self.reconcile_channels().await {
+                warn!(%err, "unable to reconcile channels");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the daemon's effective configuration as JSON.
+    ///
+    /// This includes defaults and environment variable
+    /// overrides, which makes it useful for diagnosing
+    /// misconfiguration.
+    pub async fn daemon_config(&self) -> Result<String> {
+        rpc(self.daemon.config(rpc_context("daemon_config")).await)
+            .context(|| ErrorContext::new("daemon_config"))
     }
 
     /// Returns the address that AFC is bound to.
     pub async fn afc_local_addr(&self) -> Result<SocketAddr> {
-        self.afc.local_addr().map_err(Into::into)
+        self.afc
+            .local_addr()
+            .context(|| ErrorContext::new("afc_local_addr"))
+    }
+
+    /// Returns every address that AFC is bound to, starting
+    /// with [`afc_local_addr`][Self::afc_local_addr].
+    ///
+    /// See [`RouterConfig::additional_addrs`].
+    pub async fn afc_local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.afc
+            .local_addrs()
+            .context(|| ErrorContext::new("afc_local_addrs"))
+    }
+
+    /// Returns the client's effective limits.
+    pub fn limits(&self) -> Limits {
+        self.afc.limits()
+    }
+
+    /// Returns the AFC router's statistics.
+    pub fn router_stats(&self) -> RouterStats {
+        self.afc.stats()
+    }
+
+    /// Returns the statistics of each open stream with a peer
+    /// and of each stream that messages are waiting to be
+    /// written to.
+    pub fn stream_stats(&self) -> Vec<StreamStats> {
+        self.afc.stream_stats()
+    }
+
+    /// Returns the statistics for the AFC channel `id`.
+    pub fn afc_channel_stats(&self, id: AfcId) -> Result<ChannelStats> {
+        self.afc
+            .channel_stats(id)
+            .context(|| self.afc.error_context("afc_channel_stats", id))
+    }
+
+    /// Returns a summary of each open AFC channel.
+    pub fn list_channels(&self) -> Vec<ChannelSummary> {
+        self.afc.channels().collect()
+    }
+
+    /// Validates the payloads received on channels with `label`
+    /// with `validator`, replacing its previous validator.
+    ///
+    /// Payloads are validated after they are decrypted and
+    /// before they are delivered. Those that fail validation
+    /// are dropped and reported as
+    /// [`AfcEvent::PayloadRejected`].
+    pub fn set_payload_validator(&mut self, label: Label, validator: PayloadValidator) {
+        self.validators.insert(label, validator);
+    }
+
+    /// Stops validating the payloads received on channels with
+    /// `label`.
+    ///
+    /// Returns the removed validator, if any.
+    pub fn remove_payload_validator(&mut self, label: Label) -> Option<PayloadValidator> {
+        self.validators.remove(&label)
+    }
+
+    /// Delivers the messages received on channels with `label`
+    /// per `schedule`, replacing its previous schedule.
+    ///
+    /// Received messages wait in one queue per label, and
+    /// [`try_recv_data`][Self::try_recv_data] takes turns
+    /// between the labels with waiting messages, so a flood on
+    /// one label (e.g., a bulk transfer) does not delay the
+    /// others. Giving a label a higher weight or limiting the
+    /// queue of a bulk label bounds the latency of critical
+    /// labels.
+    ///
+    /// Messages delivered to subscribers (see
+    /// [`subscribe`][Self::subscribe]) are not scheduled.
+    pub fn set_label_schedule(&mut self, label: Label, schedule: LabelSchedule) {
+        self.msgs.set_schedule(label, schedule);
+    }
+
+    /// Delivers the messages received on channels with `label`
+    /// per [`LabelSchedule::default`].
+    ///
+    /// Returns the removed schedule, if any.
+    pub fn remove_label_schedule(&mut self, label: Label) -> Option<LabelSchedule> {
+        self.msgs.remove_schedule(label)
+    }
+
+    /// Returns a summary of the open AFC channel `id`.
+    pub fn channel_info(&self, id: AfcId) -> Result<ChannelSummary> {
+        self.afc
+            .channel(id)
+            .ok_or(AfcError::ChannelNotFound(id))
+            .context(|| self.afc.error_context("channel_info", id))
+    }
+
+    /// Returns the reputation of the peer at `ip`, if the
+    /// router has interacted with it.
+    pub fn peer_reputation(&self, ip: IpAddr) -> Option<PeerReputation> {
+        self.afc.reputation(ip)
+    }
+
+    /// Returns the reputations of all peers the router has
+    /// interacted with.
+    pub fn peer_reputations(&self) -> Vec<(IpAddr, PeerReputation)> {
+        self.afc.reputations()
+    }
+
+    /// Forgets the reputation of the peer at `ip`.
+    ///
+    /// This also cancels any pending reconnect backoff.
+    pub fn reset_peer_reputation(&mut self, ip: IpAddr) {
+        self.afc.reset_reputation(Some(ip))
+    }
+
+    /// Forgets the reputations of all peers.
+    pub fn reset_peer_reputations(&mut self) {
+        self.afc.reset_reputation(None)
+    }
+
+    /// Forgets the cached addresses of all peers.
+    ///
+    /// Use this when a peer's address is known to have changed
+    /// before its cache entry expires (see
+    /// [`RouterConfig::dns_ttl`][crate::RouterConfig::dns_ttl]).
+    pub fn flush_dns_cache(&mut self) {
+        self.afc.flush_dns_cache()
+    }
+
+    /// Returns the DNS lookup statistics of each peer host that
+    /// the router looked up recently.
+    ///
+    /// Unlike the DNS cache, the statistics are kept after the
+    /// lookups expire, so they show why a peer that is reached
+    /// by hostname could not be reached.
+    pub fn dns_stats(&self) -> Vec<DnsStats> {
+        self.afc.dns_stats()
+    }
+
+    /// Reports what `net_id` currently resolves to and which
+    /// address the router would dial to reach it.
+    ///
+    /// This resolves `net_id` through the DNS cache like
+    /// opening a stream would, but does not dial it.
+    pub async fn resolve_debug(&mut self, net_id: &NetIdentifier) -> ResolveDebug {
+        self.afc.resolve_debug(net_id).await
     }
 
     /// Creates a bidirectional AFC channel with a peer.
@@ -143,50 +1040,248 @@ impl Client {
     ///
     /// It is NOT safe to cancel the resulting future. Doing so
     /// might lose data.
-    #[instrument(skip_all, fields(self = self.debug(), %team_id, %peer, %label))]
     pub async fn create_bidi_channel(
         &mut self,
         team_id: TeamId,
         peer: NetIdentifier,
         label: Label,
+    ) -> Result<AfcId> {
+        let ctx = ErrorContext::new("create_bidi_channel")
+            .team(team_id)
+            .peer(peer.clone());
+        self.open_bidi_channel(team_id, peer, label, ChannelOptions::default())
+            .await
+            .context(|| ctx)
+    }
+
+    /// Like [`create_bidi_channel`][Self::create_bidi_channel],
+    /// but with `opts`.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to cancel the resulting future. Doing so
+    /// might lose data.
+    #[instrument(
+        skip_all,
+        err,
+        fields(self = self.debug(), team_id = %ShortId::new("team", team_id), %peer, %label, ?opts, trace_id),
+    )]
+    pub async fn create_bidi_channel_with_options(
+        &mut self,
+        team_id: TeamId,
+        peer: NetIdentifier,
+        label: Label,
+        opts: ChannelOptions,
+    ) -> Result<AfcId> {
+        let ctx = ErrorContext::new("create_bidi_channel_with_options")
+            .team(team_id)
+            .peer(peer.clone());
+        self.open_bidi_channel(team_id, peer, label, opts)
+            .await
+            .context(|| ctx)
+    }
+
+    /// Creates a bidirectional AFC channel with a peer.
+    async fn open_bidi_channel(
+        &mut self,
+        team_id: TeamId,
+        peer: NetIdentifier,
+        label: Label,
+        opts: ChannelOptions,
     ) -> Result<AfcId> {
         debug!("creating bidi channel");
 
-        let node_id = self.afc.get_next_node_id().await?;
-        debug!(%node_id, "selected node ID");
+        self.check_routed(team_id)?;
+        self.make_room(team_id).await?;
+        // The trace ID correlates this operation across the
+        // daemon RPCs, the control message, and the peer.
+        let ctx = rpc_context("create_bidi_channel");
+        let trace_id = *ctx.trace_id();
+        Span::current().record("trace_id", display(trace_id));
 
-        let (afc_id, ctrl) = self
+        let node_id = self.daemon.allocate_node_id(ctx).await??;
+        debug!(%node_id, "allocated node ID");
+
+        let (afc_id, peer_id, ctrl) = self
             .daemon
-            .create_bidi_channel(context::current(), team_id, peer.clone(), node_id, label)
+            .create_bidi_channel(ctx, team_id, peer.clone(), node_id, label)
             .await??;
-        debug!(%afc_id, %node_id, %label, "created bidi channel");
+        debug!(afc_id = %ShortId::new("afc", afc_id), %node_id, %label, "created bidi channel");
 
         let chan_id = ChannelId::new(node_id, label);
         self.afc
-            .send_ctrl(peer, ctrl, team_id, afc_id, chan_id)
+            .send_ctrl(
+                peer,
+                peer_id,
+                ctrl,
+                team_id,
+                afc_id,
+                chan_id,
+                trace_id,
+                opts.transport,
+            )
             .await?;
         debug!("sent control message");
+        if let Some(limit) = opts.rate_limit {
+            self.afc.set_rate_limit(afc_id, Some(limit))?;
+        }
+        if let Some(expiry) = opts.expiry {
+            self.afc.set_expiry(afc_id, Some(expiry))?;
+        }
+        self.notify_added(afc_id);
 
         Ok(afc_id)
     }
 
     /// Deletes an AFC channel.
+    ///
+    /// The peer is told about the deletion if the channel's
+    /// stream is open, in which case it removes the channel too
+    /// and reports [`AfcEvent::ChannelClosedByPeer`].
     // TODO(eric): Is it an error if the channel does not exist?
-    #[instrument(skip_all, fields(self = self.debug(), afc_id = %id))]
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
     pub async fn delete_channel(&mut self, id: AfcId) -> Result<()> {
-        let _ctrl = self.daemon.delete_channel(context::current(), id).await??;
+        let ctx = self.afc.error_context("delete_channel", id);
+        self.remove_channel(id, RemovalReason::Deleted)
+            .await
+            .context(|| ctx)
+    }
+
+    /// Deletes channel `id` like
+    /// [`delete_channel`][Self::delete_channel], reporting it as
+    /// removed for `reason`.
+    async fn remove_channel(&mut self, id: AfcId, reason: RemovalReason) -> Result<()> {
+        // The close is sealed with the channel's key, so it has
+        // to be created before the daemon removes the key.
+        let close = self.afc.close_msg(id);
+        let _ctrl = self
+            .daemon
+            .delete_channel(rpc_context("remove_channel"), id)
+            .await??;
+        let existed = self.afc.channel(id).is_some();
+        match close {
+            Ok(Some(close)) => {
+                if let Err(err) = self.afc.send_close(close).await {
+                    warn!(%err, "unable to tell peer about deleted channel");
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "unable to tell peer about deleted channel"),
+        }
         self.afc.remove_channel(id).await;
-        // TODO(eric): Send control message.
-        // self.afc.send_ctrl(peer, ctrl, team_id, id, chan_id);
+        self.report_queue_changes();
+        if existed {
+            self.notify_watchers(ChannelSetDelta::Removed {
+                channel: id,
+                reason,
+            });
+        }
+        Ok(())
+    }
+
+    /// Updates the [`NetIdentifier`] of the peer on the other
+    /// side of an AFC channel.
+    ///
+    /// This is useful when the peer's hostname changes but its
+    /// keys remain valid. The channel does not need to be
+    /// recreated: the new identifier is resolved and connected
+    /// to the next time data is sent over the channel.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id), %net_id))]
+    pub async fn update_channel_peer(&mut self, id: AfcId, net_id: NetIdentifier) -> Result<()> {
+        self.afc
+            .update_channel_peer(id, net_id)
+            .await
+            .context(|| self.afc.error_context("update_channel_peer", id))?;
+        if let Some(summary) = self.afc.channel(id) {
+            self.notify_watchers(ChannelSetDelta::Updated(summary));
+        }
         Ok(())
     }
 
+    /// Sets the rate limit of an AFC channel, overriding
+    /// [`RouterConfig::rate_limit`], or removes it if `limit`
+    /// is `None`.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn set_channel_rate_limit(&mut self, id: AfcId, limit: Option<RateLimit>) -> Result<()> {
+        self.afc
+            .set_rate_limit(id, limit)
+            .context(|| self.afc.error_context("set_channel_rate_limit", id))
+    }
+
+    /// Sets the expiry policy of an AFC channel, overriding
+    /// [`RouterConfig::expiry`], or removes it if `expiry` is
+    /// `None`.
+    ///
+    /// The channel's age and message count are kept, so it
+    /// expires right away if it is already past the new limits.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn set_channel_expiry(&mut self, id: AfcId, expiry: Option<ChannelExpiry>) -> Result<()> {
+        self.afc
+            .set_expiry(id, expiry)
+            .context(|| self.afc.error_context("set_channel_expiry", id))
+    }
+
+    /// Returns the metadata attached to an AFC channel.
+    pub fn channel_metadata(&self, id: AfcId) -> Result<&ChannelMetadata> {
+        self.afc
+            .metadata(id)
+            .context(|| self.afc.error_context("channel_metadata", id))
+    }
+
+    /// Sets the metadata entry `key` of an AFC channel to
+    /// `value`.
+    ///
+    /// Returns the previous value, if any.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn set_channel_metadata(
+        &mut self,
+        id: AfcId,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<Option<String>> {
+        let ctx = self.afc.error_context("set_channel_metadata", id);
+        let old = self
+            .afc
+            .metadata_mut(id)
+            .context(|| ctx)?
+            .insert(key.into(), value.into());
+        if let Some(summary) = self.afc.channel(id) {
+            self.notify_watchers(ChannelSetDelta::Updated(summary));
+        }
+        Ok(old)
+    }
+
+    /// Removes the metadata entry `key` of an AFC channel.
+    ///
+    /// Returns the removed value, if any.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn remove_channel_metadata(&mut self, id: AfcId, key: &str) -> Result<Option<String>> {
+        let ctx = self.afc.error_context("remove_channel_metadata", id);
+        let old = self.afc.metadata_mut(id).context(|| ctx)?.remove(key);
+        if old.is_some() {
+            if let Some(summary) = self.afc.channel(id) {
+                self.notify_watchers(ChannelSetDelta::Updated(summary));
+            }
+        }
+        Ok(old)
+    }
+
     /// Polls the client to check for new data, then retrieves
     /// any new data.
     ///
-    /// This is shorthand for [`poll`][Self::poll] and
+    /// This is shorthand for [`poll_data`][Self::poll_data] and
     /// [`handle_data`][Self::handle_data].
     ///
+    /// It waits until a peer opens a new connection or sends
+    /// a message, or until queued data is due to be resent,
+    /// even if there are currently no open channels or
+    /// connections. Use [`poll_timeout`][Self::poll_timeout]
+    /// to bound how long it waits.
+    ///
+    /// Under sustained load it does not need to wait, so it
+    /// yields to other tasks every
+    /// [`RouterConfig::yield_budget`] messages instead.
+    ///
     /// # Cancellation Safety
     ///
     /// It is NOT safe to cancel the resulting future. Doing so
@@ -197,14 +1292,42 @@ impl Client {
         self.handle_data(data).await
     }
 
+    /// Like [`poll`][Self::poll], but gives up after `timeout`.
+    ///
+    /// Returns `true` if data was handled and `false` if the
+    /// timeout elapsed first.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to cancel the resulting future. Doing so
+    /// might lose data.
+    #[instrument(skip_all, fields(?timeout))]
+    pub async fn poll_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        // `poll_data` is cancellation safe, so it's fine to
+        // drop it when the timeout elapses.
+        let Ok(data) = tokio::time::timeout(timeout, self.poll_data()).await else {
+            debug!("poll timed out");
+            return Ok(false);
+        };
+        self.handle_data(data?).await?;
+        Ok(true)
+    }
+
     /// Polls the client to check for new data.
     ///
+    /// See [`poll`][Self::poll] for details on when this
+    /// returns.
+    ///
     /// # Cancellation Safety
     ///
     /// It is safe to cancel the resulting future.
     #[instrument(skip_all)]
     pub async fn poll_data(&mut self) -> Result<PollData> {
-        let data = self.afc.poll().await?;
+        let data = tokio::select! {
+            biased;
+            () = self.link.lost() => State::DaemonLost,
+            result = self.afc.poll() => result.context(|| ErrorContext::new("poll_data"))?,
+        };
         Ok(PollData(data))
     }
 
@@ -216,53 +1339,1148 @@ impl Client {
     /// might lose data.
     #[instrument(skip_all, fields(self = self.debug(), ?data))]
     pub async fn handle_data(&mut self, data: PollData) -> Result<()> {
-        match data.0 {
-            State::Accept(addr) | State::Msg(addr) => match self.afc.read_msg(addr).await? {
-                Msg::Data(data) => {
-                    debug!(%addr, "read data message");
-
-                    let (data, channel, label, seq) = self.afc.open_data(data)?;
-                    self.msgs.push_back(AfcMsg {
-                        data,
-                        addr,
-                        channel,
-                        label,
-                        seq,
-                    });
-                    debug!(n = self.msgs.len(), "stored msg");
-                }
-                Msg::Ctrl(ctrl) => {
-                    debug!(%addr, "read control message");
-
-                    let node_id = self.afc.get_next_node_id().await?;
-                    debug!(%node_id, "selected node ID");
-
-                    let (afc_id, peer, label) = self
-                        .daemon
-                        .receive_afc_ctrl(context::current(), ctrl.team_id, node_id, ctrl.cmd)
-                        .await??;
-                    debug!(%node_id, %label, "applied AFC control msg");
-
-                    let chan_id = ChannelId::new(node_id, label);
-                    self.afc
-                        .add_channel(afc_id, peer, ctrl.team_id, chan_id, addr)
-                        .await?;
+        self.afc.consume_budget().await;
+
+        let result = self
+            .handle_state(data.0)
+            .await
+            .context(|| ErrorContext::new("handle_data"));
+        // Sending and receiving data can expire channels.
+        self.report_expiries().await;
+        result
+    }
+
+    /// Handles `state` from [`poll_data`][Self::poll_data].
+    async fn handle_state(&mut self, state: State) -> Result<()> {
+        let addr = match state {
+            State::Accept(_) => {
+                // The peer that opened the stream greets us
+                // first, and we answer its `Hello`. Its messages
+                // are handled once the stream is readable, so a
+                // slow peer cannot stall the router.
+                return Ok(());
+            }
+            State::Msg(addr) => addr,
+            State::Disconnected(addr, reason) => {
+                debug!(%addr, ?reason, "stream closed");
+                self.push_event(AfcEvent::PeerDisconnected { addr, reason });
+                return Ok(());
+            }
+            State::Retry => {
+                for (channel, dropped) in self.afc.retry().await {
+                    self.push_event(AfcEvent::SendFailed { channel, dropped });
+                }
+                self.report_queue_changes();
+                return Ok(());
+            }
+            State::DaemonLost => {
+                if let Err(err) = self.reconnect_daemon().await {
+                    warn!(%err, "unable to reconnect to daemon");
+                }
+                return Ok(());
+            }
+            State::Reconcile => {
+                self.reconcile_channels().await?;
+                self.check_clock_jumps().await?;
+                return Ok(());
+            }
+            State::KeepAlive => {
+                self.afc.keepalive().await?;
+                self.report_liveness();
+                return Ok(());
+            }
+            State::Reap => {
+                // Reaped streams are reported as closed by the
+                // next poll.
+                self.afc.reap_streams().await;
+                return Ok(());
+            }
+            State::Expire => {
+                // Expired channels are reported by
+                // `handle_data`.
+                self.afc.expire_channels();
+                return Ok(());
+            }
+            State::Window => {
+                self.afc.send_windows().await?;
+                return Ok(());
+            }
+            State::Datagram => {
+                let Some((addr, msg)) = self.afc.read_datagram()? else {
+                    return Ok(());
+                };
+                let data = match msg {
+                    Msg::Data(data) | Msg::Enveloped(data) => data,
+                    Msg::Nack(nack) => {
+                        self.handle_nack(addr, nack);
+                        return Ok(());
+                    }
+                    Msg::Ping(ping) => {
+                        self.afc.handle_ping(addr, ping, true).await?;
+                        return Ok(());
+                    }
+                    Msg::Pong(pong) => {
+                        self.afc.handle_pong(addr, pong)?;
+                        self.report_liveness();
+                        return Ok(());
+                    }
+                    Msg::Window(window) => {
+                        self.afc.handle_window(addr, window, true)?;
+                        return Ok(());
+                    }
+                    // `read_datagram` only returns data, nacks,
+                    // keep-alive, and flow control messages.
+                    _ => return Ok(()),
+                };
+                debug!(%addr, "read data datagram");
+
+                // Datagrams can be lost, duplicated, or
+                // reordered, so drop the ones that we cannot
+                // open instead of failing.
+                let opened = match self.afc.open_data(data).await {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        warn!(%addr, %err, "dropping datagram");
+                        self.afc.drop_datagram();
+                        return Ok(());
+                    }
+                };
+                self.store_opened(addr, opened, true).await?;
+                return Ok(());
+            }
+        };
+        match self.afc.read_msg(addr).await? {
+            Msg::Data(data) | Msg::Enveloped(data) => {
+                debug!(%addr, "read data message");
+
+                let afc_id = data.afc_id;
+                let opened = match self.afc.open_data(data).await {
+                    Ok(opened) => opened,
+                    Err(err) => return Err(self.check_revoked(afc_id, err).await),
+                };
+                if !matches!(opened, Opened::Removed { .. }) {
+                    self.afc.data_authenticated(addr);
+                }
+                self.store_opened(addr, opened, false).await?;
+            }
+            Msg::Ctrl(ctrl) => {
+                // Reuse the peer's trace ID, if it sent one, so
+                // that both sides of the channel creation can be
+                // correlated.
+                let mut ctx = context::current();
+                if let Some(trace_id) = ctrl.trace_id {
+                    ctx.trace_context.trace_id = trace_id;
+                }
+                let trace_id = ctx.trace_context.trace_id;
+                debug!(%addr, %trace_id, "read control message");
+
+                if let Err(err) = self.check_routed(ctrl.team_id) {
+                    warn!(%addr, %trace_id, team_id = %ShortId::new("team", ctrl.team_id), "rejecting control message for another router's team");
+                    return Err(err.into());
+                }
+                // Shed floods before they reach the daemon.
+                self.afc.admit_ctrl(addr)?;
+
+                // The daemon verifies the control message before
+                // anything else happens, so that only verified
+                // messages change the channel table.
+                let (afc_id, author, peer, label, node_id) = match self
+                    .daemon
+                    .receive_afc_ctrl(ctx, ctrl.team_id, ctrl.cmd)
+                    .await?
+                {
+                    Ok(v) => v,
+                    Err(err) => {
+                        error!(%trace_id, %err, "unable to apply AFC control msg");
+                        self.afc.ctrl_rejected(addr);
+                        return Err(err.into());
+                    }
+                };
+                debug!(%node_id, %label, %trace_id, "applied AFC control msg");
+                self.afc.verify_peer(addr, author).await;
+
+                // The control message might be a duplicate.
+                let is_new = self.afc.channel(afc_id).is_none();
+                if is_new {
+                    if let Err(err) = self.make_room(ctrl.team_id).await {
+                        // The daemon already added the channel.
+                        if let Err(err) = self
+                            .daemon
+                            .delete_channel(ctx, afc_id)
+                            .await
+                            .map_err(Error::from)
+                            .and_then(|r| r.map_err(Error::from))
+                        {
+                            warn!(%err, "unable to delete rejected channel from daemon");
+                        }
+                        return Err(err);
+                    }
+                }
+
+                let chan_id = ChannelId::new(node_id, label);
+                let udp = ctrl.udp_port.map(|port| SocketAddr::new(addr.ip(), port));
+                self.afc
+                    .add_channel(
+                        afc_id,
+                        peer,
+                        ctrl.team_id,
+                        chan_id,
+                        addr,
+                        udp,
+                        ctrl.self_check,
+                    )
+                    .await?;
+                if is_new {
+                    self.notify_added(afc_id);
+                }
+                self.push_event(AfcEvent::ChannelOpened {
+                    addr,
+                    channel: afc_id,
+                    label,
+                });
+            }
+            Msg::Hello(hello) => {
+                debug!(%addr, "read hello message");
+
+                self.afc.handle_hello(addr, hello).await?;
+            }
+            Msg::Resume(resume) => {
+                debug!(%addr, "read resume message");
+
+                self.afc.handle_resume(addr, resume).await?;
+            }
+            Msg::Ticket(ticket) => {
+                debug!(%addr, "read ticket message");
+
+                self.afc.handle_ticket(addr, ticket);
+            }
+            Msg::Challenge(challenge) => {
+                debug!(%addr, "read challenge message");
+
+                self.afc.handle_challenge(addr, challenge).await?;
+            }
+            Msg::Busy => {
+                self.afc.handle_busy(addr);
+            }
+            Msg::Nack(nack) => {
+                self.handle_nack(addr, nack);
+            }
+            Msg::Ping(ping) => {
+                self.afc.handle_ping(addr, ping, false).await?;
+            }
+            Msg::Pong(pong) => {
+                self.afc.handle_pong(addr, pong)?;
+                self.report_liveness();
+            }
+            Msg::StreamPing => {
+                self.afc.handle_stream_ping(addr).await?;
+            }
+            // Reading it already proved that the stream is open.
+            Msg::StreamPong => {}
+            Msg::Versions(versions) => {
+                self.afc.handle_versions(addr, &versions).await?;
+                self.push_event(AfcEvent::VersionsAdvertised {
+                    addr,
+                    supported: versions.supported,
+                });
+            }
+            Msg::Goodbye(goodbye) => {
+                self.afc.handle_goodbye(addr, &goodbye).await;
+                self.push_event(AfcEvent::Goodbye {
+                    addr,
+                    reason: goodbye.reason,
+                });
+            }
+            Msg::Close(close) => {
+                debug!(%addr, "read close message");
+
+                self.handle_close(addr, close).await?;
+            }
+            Msg::Window(window) => {
+                self.afc.handle_window(addr, window, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a channel that the peer at `addr` deleted.
+    async fn handle_close(&mut self, addr: SocketAddr, close: Close) -> Result<()> {
+        let channel = close.afc_id;
+        if !self.afc.handle_close(addr, close).await? {
+            return Ok(());
+        }
+        // Our half of the channel is useless without the peer's,
+        // so the daemon can forget its keys.
+        if let Err(err) = self
+            .daemon
+            .delete_channel(rpc_context("handle_close"), channel)
+            .await
+            .map_err(Error::from)
+            .and_then(|r| r.map_err(Error::from))
+        {
+            warn!(%err, "unable to delete closed channel from daemon");
+        }
+        self.report_queue_changes();
+        self.push_event(AfcEvent::ChannelClosedByPeer { addr, channel });
+        self.notify_watchers(ChannelSetDelta::Removed {
+            channel,
+            reason: RemovalReason::ClosedByPeer,
+        });
+        Ok(())
+    }
+
+    /// Removes the channels that the daemon removed, e.g.,
+    /// because a policy change revoked their label or the peer
+    /// was removed from the team.
+    ///
+    /// Otherwise, the router's channels would linger until
+    /// sealing or opening data for them fails with an
+    /// unhelpful crypto error. Each removed channel is reported
+    /// as [`AfcEvent::ChannelRevoked`] and using it fails with
+    /// [`AfcError::ChannelRevoked`].
+    ///
+    /// This is done every
+    /// [`RouterConfig::reconcile_interval`] by
+    /// [`poll_data`][Self::poll_data] and whenever sealing or
+    /// opening data fails.
+    ///
+    /// Returns the removed channels.
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub async fn reconcile_channels(&mut self) -> Result<Vec<AfcId>> {
+        let known = rpc(self
+            .daemon
+            .afc_channels(rpc_context("reconcile_channels"))
+            .await)
+        .context(|| ErrorContext::new("reconcile_channels"))?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+        let revoked = self.afc.reconcile(&known).await;
+        for &channel in &revoked {
+            self.push_event(AfcEvent::ChannelRevoked { channel });
+            self.notify_watchers(ChannelSetDelta::Removed {
+                channel,
+                reason: RemovalReason::Revoked,
+            });
+        }
+        self.report_queue_changes();
+        Ok(revoked)
+    }
+
+    /// Reports the daemon's clock jumps since the last check as
+    /// [`AfcEvent::ClockJumped`].
+    async fn check_clock_jumps(&mut self) -> Result<()> {
+        let jumps = self
+            .daemon
+            .clock_jumps(rpc_context("check_clock_jumps"), self.clock_jump)
+            .await??;
+        for jump in jumps {
+            warn!(by = ?jump.by, backward = jump.backward, "daemon's clock jumped");
+            self.clock_jump = Some(jump.seq);
+            self.push_event(AfcEvent::ClockJumped {
+                at: jump.at,
+                by: jump.by,
+                backward: jump.backward,
+            });
+        }
+        Ok(())
+    }
+
+    /// Evicts a channel if adding one to `team_id` would exceed
+    /// [`RouterConfig::channel_limits`].
+    async fn make_room(&mut self, team_id: TeamId) -> Result<()> {
+        let Some(channel) = self.afc.make_room(team_id)? else {
+            return Ok(());
+        };
+        self.remove_channel(channel, RemovalReason::Evicted).await?;
+        self.push_event(AfcEvent::ChannelEvicted { channel });
+        Ok(())
+    }
+
+    /// Converts `err`, a failure to use channel `id`, into
+    /// [`AfcError::ChannelRevoked`] if the daemon removed the
+    /// channel.
+    async fn check_revoked(&mut self, id: AfcId, err: AfcError) -> Error {
+        if !matches!(err, AfcError::Encryption(_) | AfcError::Decryption(_)) {
+            return err.into();
+        }
+        match self.reconcile_channels().await {
+            Ok(revoked) if revoked.contains(&id) => AfcError::ChannelRevoked(id).into(),
+            Ok(_) => err.into(),
+            Err(rerr) => {
+                warn!(%rerr, "unable to reconcile channels");
+                err.into()
+            }
+        }
+    }
+
+    /// Records that the peer at `addr` removed a channel.
+    fn handle_nack(&mut self, addr: SocketAddr, nack: Nack) {
+        match self.afc.handle_nack(addr, nack) {
+            Ok(Some(channel)) => {
+                self.push_event(AfcEvent::ChannelRemovedByPeer { addr, channel });
+                debug!(n = self.events.len(), "stored channel removed event");
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%addr, %err, "ignoring invalid nack"),
+        }
+    }
+
+    /// Reports the channels that expired as
+    /// [`AfcEvent::ExpiryReached`] and deletes the ones whose
+    /// policy says so.
+    async fn report_expiries(&mut self) {
+        for (channel, reason, delete) in self.afc.take_expired() {
+            warn!(%channel, ?reason, "channel reached its expiry");
+            self.push_event(AfcEvent::ExpiryReached { channel, reason });
+            if delete {
+                if let Err(err) = self.remove_channel(channel, RemovalReason::Expired).await {
+                    warn!(%channel, %err, "unable to delete expired channel");
+                }
+            }
+        }
+    }
+
+    /// Reports the router's liveness changes as
+    /// [`AfcEvent::LivenessChanged`].
+    fn report_liveness(&mut self) {
+        for (channel, liveness) in self.afc.take_liveness_changes() {
+            self.push_event(AfcEvent::LivenessChanged { channel, liveness });
+        }
+    }
+
+    /// Reports the streams that became congested or drained as
+    /// [`AfcEvent::StreamCongested`] and
+    /// [`AfcEvent::StreamDrained`].
+    fn report_queue_changes(&mut self) {
+        for QueueChange {
+            addr,
+            queued,
+            congested,
+        } in self.afc.take_queue_changes()
+        {
+            if congested {
+                self.push_event(AfcEvent::StreamCongested { addr, queued });
+            } else {
+                self.push_event(AfcEvent::StreamDrained { addr, queued });
+            }
+        }
+    }
+
+    /// Tells the subscribers to changes to the set of channels
+    /// that channel `id` was added.
+    fn notify_added(&mut self, id: AfcId) {
+        if let Some(summary) = self.afc.channel(id) {
+            self.notify_watchers(ChannelSetDelta::Added(summary));
+        }
+    }
+
+    /// Sends `delta` to the subscribers to changes to the set
+    /// of channels, forgetting the ones that went away.
+    fn notify_watchers(&mut self, delta: ChannelSetDelta) {
+        // A removed channel's subscriber will not get any more
+        // data, so end its stream.
+        if let ChannelSetDelta::Removed { channel, .. } = &delta {
+            self.subscribers.remove(channel);
+        }
+        self.watchers.retain(|tx| tx.send(delta.clone()).is_ok());
+    }
+
+    /// Subscribes to changes to the set of channels.
+    ///
+    /// The current channels are sent as
+    /// [`ChannelSetDelta::Added`] first.
+    #[cfg(feature = "stream")]
+    pub(crate) fn subscribe_channels(&mut self) -> mpsc::UnboundedReceiver<ChannelSetDelta> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for summary in self.afc.channels() {
+            // `rx` is still alive.
+            let _ = tx.send(ChannelSetDelta::Added(summary));
+        }
+        self.watchers.push(tx);
+        rx
+    }
+
+    /// Queues `event`.
+    ///
+    /// In bounded-memory mode, the event is dropped if the queue
+    /// is full.
+    fn push_event(&mut self, event: AfcEvent) {
+        if self
+            .bounds
+            .is_some_and(|bounds| self.events.len() >= bounds.max_queued_events)
+        {
+            warn!(?event, "event queue is full, dropping event");
+            return;
+        }
+        self.events.push_back(event);
+    }
+
+    /// Stores a message or event from
+    /// [`Afc::open_data`].
+    ///
+    /// `udp` reports whether the message was received as
+    /// a datagram.
+    ///
+    /// In bounded-memory mode, it is an error if the message
+    /// queue is full. The message is dropped.
+    async fn store_opened(&mut self, addr: SocketAddr, opened: Opened, udp: bool) -> Result<()> {
+        // Data revives channels whose peer stopped answering
+        // pings.
+        self.report_liveness();
+        match opened {
+            Opened::Msg {
+                data,
+                afc_id: channel,
+                label,
+                seq,
+                sent_at,
+                rpc,
+            } => {
+                let expired = seq.to_u64() == u64::MAX;
+                if let Some(Err(reason)) = self.validators.get(&label).map(|v| v.validate(&data)) {
+                    warn!(%channel, %seq, ?reason, "rejected payload");
+                    self.afc.recycle(data);
+                    self.afc.payload_rejected(channel);
+                    self.push_event(AfcEvent::PayloadRejected {
+                        addr,
+                        channel,
+                        seq,
+                        reason,
+                    });
+                } else if let Some(rpc) = rpc {
+                    self.store_rpc(
+                        rpc,
+                        AfcMsg {
+                            data,
+                            addr,
+                            channel,
+                            label,
+                            seq,
+                            sent_at,
+                        },
+                    )?;
+                } else {
+                    let data = match self.subscribers.get(&channel).cloned() {
+                        Some(tx) => match tx.send((data, label, seq)).await {
+                            Ok(()) => {
+                                debug!(%channel, %seq, "sent msg to subscriber");
+                                None
+                            }
+                            // The subscriber went away, so the
+                            // message is queued as usual.
+                            Err(SendError((data, ..))) => {
+                                self.subscribers.remove(&channel);
+                                Some(data)
+                            }
+                        },
+                        None => Some(data),
+                    };
+                    if let Some(data) = data {
+                        self.queue_msg(AfcMsg {
+                            data,
+                            addr,
+                            channel,
+                            label,
+                            seq,
+                            sent_at,
+                        })?;
+                    }
+                }
+                if expired {
+                    warn!(%channel, "channel expired");
+                    self.push_event(AfcEvent::ChannelExpired { channel });
+                }
+            }
+            Opened::Duplicate {
+                afc_id: channel,
+                token,
+                seq,
+            } => {
+                self.push_event(AfcEvent::Duplicate {
+                    addr,
+                    channel,
+                    token,
+                    seq,
+                });
+                debug!(n = self.events.len(), "stored duplicate event");
+            }
+            Opened::ChecksumMismatch {
+                afc_id: channel,
+                seq,
+            } => {
+                self.push_event(AfcEvent::ChecksumMismatch { addr, channel, seq });
+                debug!(n = self.events.len(), "stored checksum mismatch event");
+            }
+            Opened::TimestampRejected {
+                afc_id: channel,
+                seq,
+                sent_at,
+            } => {
+                self.push_event(AfcEvent::TimestampRejected {
+                    addr,
+                    channel,
+                    seq,
+                    sent_at,
+                });
+                debug!(n = self.events.len(), "stored timestamp rejected event");
+            }
+            Opened::Fragment { afc_id, seq } => {
+                debug!(afc_id = %ShortId::new("afc", afc_id), %seq, "stored stream fragment");
+            }
+            Opened::StreamDropped {
+                afc_id: channel,
+                seq,
+            } => {
+                self.push_event(AfcEvent::StreamDropped { addr, channel, seq });
+                debug!(n = self.events.len(), "stored stream dropped event");
+            }
+            Opened::Removed { afc_id, notify } => {
+                if !notify {
+                    return Ok(());
+                }
+                // The nack is only advisory, so failing to send
+                // it is not an error.
+                if let Err(err) = self.afc.send_nack(addr, afc_id, udp).await {
+                    warn!(%addr, afc_id = %ShortId::new("afc", afc_id), %err, "unable to send nack");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a request for
+    /// [`try_recv_request`][Self::try_recv_request] or stores
+    /// the response to a pending request.
+    ///
+    /// In bounded-memory mode, it is an error if the request
+    /// queue is full. The request is dropped.
+    fn store_rpc(&mut self, rpc: Rpc, msg: AfcMsg) -> Result<()> {
+        let AfcMsg {
+            data,
+            addr,
+            channel,
+            label,
+            seq,
+            ..
+        } = msg;
+        match rpc {
+            Rpc::Request(id) => {
+                if self
+                    .bounds
+                    .is_some_and(|bounds| self.requests.len() >= bounds.max_queued_msgs)
+                {
+                    self.afc.recycle(data);
+                    return Err(AfcError::Capacity("request queue").into());
+                }
+                self.requests.push_back(Request {
+                    id,
+                    data,
+                    addr,
+                    channel,
+                    label,
+                    seq,
+                });
+                self.count_queued(channel, true);
+                debug!(n = self.requests.len(), "stored request");
+            }
+            Rpc::Response(id) => {
+                let resp = Response { data, addr, seq };
+                if let Err(resp) = self.pending.complete(id, channel, resp) {
+                    debug!(%channel, request = %id, %seq, "dropping unexpected response");
+                    metrics::msgs_dropped("unexpected_response", 1);
+                    self.afc.recycle(resp.data);
+                    self.push_event(AfcEvent::ResponseDropped {
+                        addr,
+                        channel,
+                        request: id,
+                        seq,
+                    });
                 }
-            },
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues `msg` for [`try_recv_data`][Self::try_recv_data].
+    ///
+    /// In bounded-memory mode, it is an error if the queue is
+    /// full. The message is dropped.
+    fn queue_msg(&mut self, msg: AfcMsg) -> Result<()> {
+        if self
+            .bounds
+            .is_some_and(|bounds| self.msgs.len() >= bounds.max_queued_msgs)
+        {
+            self.afc.recycle(msg.data);
+            return Err(AfcError::Capacity("message queue").into());
+        }
+        let channel = msg.channel;
+        if let Err(msg) = self.msgs.push(msg) {
+            warn!(label = %msg.label, seq = %msg.seq, "label queue is full, dropping msg");
+            metrics::msgs_dropped("label_queue_full", 1);
+            self.afc.recycle(msg.data);
+            self.push_event(AfcEvent::LabelQueueFull {
+                addr: msg.addr,
+                channel: msg.channel,
+                label: msg.label,
+                seq: msg.seq,
+            });
+            return Ok(());
         }
+        self.count_queued(channel, true);
+        debug!(n = self.msgs.len(), "stored msg");
         Ok(())
     }
 
+    /// Counts a message of `channel` that was queued (`added`)
+    /// or read, and tells the router for flow control.
+    fn count_queued(&mut self, channel: AfcId, added: bool) {
+        let n = self.queued.entry(channel).or_default();
+        *n = if added {
+            n.saturating_add(1)
+        } else {
+            n.saturating_sub(1)
+        };
+        let n = *n;
+        if n == 0 {
+            self.queued.remove(&channel);
+        }
+        self.afc.set_queued(channel, n);
+    }
+
     /// Send data over a specific fast channel.
     ///
+    /// If the data cannot be sent because of a transient
+    /// failure, such as a dropped TCP stream, it is queued and
+    /// resent by [`poll`][Self::poll] (see
+    /// [`RouterConfig::max_reconnect_attempts`]).
+    ///
+    /// If the peer's flow control window is exhausted (see
+    /// [`RouterConfig::flow_control`]), it polls the router
+    /// until the window opens.
+    ///
     /// # Cancellation Safety
     ///
-    /// It is safe to cancel the resulting future. However,
-    /// a partial message may be written to the channel.
+    /// It is safe to cancel the resulting future, but the
+    /// message might still be sent. The peer never sees part of
+    /// a message: if it was partially written, the rest is
+    /// written before the next message (see [`Client`]'s
+    /// shutdown guarantees). While it waits for the peer's flow
+    /// control window, cancelling it might lose data like
+    /// cancelling [`poll`][Self::poll].
     // TODO(eric): Return a sequence number?
-    #[instrument(skip_all, fields(self = self.debug(), afc_id = %id))]
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
     pub async fn send_data(&mut self, id: AfcId, data: &[u8]) -> Result<()> {
-        self.afc.send_data(id, data).await.map_err(Into::into)
+        self.send("send_data", id, data, None, None).await
+    }
+
+    /// Sends `data` over a fast channel without waiting.
+    ///
+    /// Unlike [`send_data`][Self::send_data], this never waits
+    /// for the channel's rate limit, for the peer's flow control
+    /// window, for data waiting to be resent, or for the socket
+    /// to be writable. If it would
+    /// have to, nothing is sent and it fails with
+    /// [`TrySendError::WouldBlock`], so latency-sensitive
+    /// applications (e.g., games or audio) can drop the data
+    /// instead of stalling.
+    ///
+    /// It does not open streams either. Over TCP, it fails with
+    /// [`TrySendError::WouldBlock`] until
+    /// [`send_data`][Self::send_data] or the peer opens a stream
+    /// for the channel.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn try_send_data(&mut self, id: AfcId, data: &[u8]) -> Result<(), TrySendError> {
+        match self.afc.try_send_data(id, data) {
+            Ok(()) => Ok(()),
+            Err(AfcError::WouldBlock(_) | AfcError::WindowExhausted(_)) => {
+                Err(TrySendError::WouldBlock)
+            }
+            Err(err) => Err(Error::from(err)
+                .with_context(self.afc.error_context("try_send_data", id))
+                .into()),
+        }
+    }
+
+    /// Sends each of `payloads` over a fast channel, in order.
+    ///
+    /// This is like calling [`send_data`][Self::send_data] for
+    /// each payload, but over TCP the sealed messages are
+    /// coalesced into a single write and flushed once, which is
+    /// much cheaper for many small messages. Each payload is
+    /// still delivered to the peer as its own [`AfcMsg`] with
+    /// its own sequence number.
+    ///
+    /// If any payload is larger than
+    /// [`Limits::max_data_size`], nothing is sent.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future. However,
+    /// a partial batch may be written to the channel.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id), n = payloads.len()))]
+    pub async fn send_data_batch(&mut self, id: AfcId, payloads: &[&[u8]]) -> Result<()> {
+        let result = self.afc.send_data_batch(id, payloads).await;
+        self.sent("send_data_batch", id, result).await
+    }
+
+    /// Queues `data` to be sent over a fast channel by the next
+    /// [`flush`][Self::flush], like `SinkExt::feed` in
+    /// `futures`.
+    ///
+    /// This lets callers that generate many small messages
+    /// choose where batches end. Nothing is sent until the
+    /// channel is flushed, which writes every message fed since
+    /// the last flush like [`send_data_batch`][Self::send_data_batch].
+    /// Sending data over the channel in any other way flushes it
+    /// first, so messages are still sent in order, and
+    /// [`try_send_data`][Self::try_send_data] fails with
+    /// [`TrySendError::WouldBlock`] until the channel is flushed.
+    ///
+    /// If `data` is larger than [`Limits::max_data_size`], it
+    /// is not queued.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub fn feed_data(&mut self, id: AfcId, data: &[u8]) -> Result<()> {
+        self.afc
+            .feed_data(id, data)
+            .context(|| self.afc.error_context("feed_data", id))
+    }
+
+    /// Sends the messages fed to a fast channel with
+    /// [`feed_data`][Self::feed_data], like `SinkExt::flush` in
+    /// `futures`.
+    ///
+    /// Over TCP, the messages are written with a single write.
+    /// If sending them fails, they are dropped.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future. However, the
+    /// messages are dropped and some of them may have been
+    /// written to the channel.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub async fn flush(&mut self, id: AfcId) -> Result<()> {
+        let result = self.afc.flush(id).await;
+        self.sent("flush", id, result).await
+    }
+
+    /// Sends `data` over every AFC channel with `label`.
+    ///
+    /// The data is sealed separately for each channel, then the
+    /// messages are written to the channels' streams
+    /// concurrently instead of one channel at a time. Channels
+    /// that use UDP, whose streams are not open yet, or whose
+    /// earlier messages are waiting to be resent are sent to
+    /// one at a time, like [`send_data`][Self::send_data].
+    ///
+    /// Returns the result for each channel with `label`.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future, but the
+    /// message might still be sent over some of the channels.
+    #[instrument(skip_all, fields(self = self.debug(), %label))]
+    pub async fn send_data_by_label(
+        &mut self,
+        label: Label,
+        data: &[u8],
+    ) -> BTreeMap<AfcId, Result<()>> {
+        let results = self.afc.send_data_by_label(label, data).await;
+        self.report_queue_changes();
+        self.report_expiries().await;
+        let mut out = BTreeMap::new();
+        for (id, result) in results {
+            let result = match result {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    let ctx = self.afc.error_context("send_data_by_label", id);
+                    Err(self.check_revoked(id, err).await.with_context(ctx))
+                }
+            };
+            out.insert(id, result);
+        }
+        out
+    }
+
+    /// Like [`send_data`][Self::send_data], but with `opts`.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// See [`send_data`][Self::send_data].
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id), ?opts))]
+    pub async fn send_data_with_options(
+        &mut self,
+        id: AfcId,
+        data: &[u8],
+        opts: SendOptions,
+    ) -> Result<()> {
+        let ext = opts.token.map(Ext::IdempotencyToken);
+        self.send("send_data_with_options", id, data, ext, opts.priority)
+            .await
+    }
+
+    /// Sends `data` over channel `id` once the peer's flow
+    /// control window is open.
+    ///
+    /// `operation` is the public method that sent it.
+    async fn send(
+        &mut self,
+        operation: &'static str,
+        id: AfcId,
+        data: &[u8],
+        ext: Option<Ext>,
+        priority: Option<Priority>,
+    ) -> Result<()> {
+        self.await_window(id)
+            .await
+            .context(|| self.afc.error_context(operation, id))?;
+        let result = self.afc.send_data(id, data, ext, None, priority).await;
+        self.sent(operation, id, result).await
+    }
+
+    /// Reports the changes caused by sending over channel `id`
+    /// and adds the context of `operation` to the error, if
+    /// any.
+    ///
+    /// The context is captured before the channel is checked
+    /// for revocation, which might remove it.
+    async fn sent<T>(
+        &mut self,
+        operation: &'static str,
+        id: AfcId,
+        result: Result<T, AfcError>,
+    ) -> Result<T> {
+        let result = result.map_err(|err| (err, self.afc.error_context(operation, id)));
+        self.report_queue_changes();
+        self.report_expiries().await;
+        match result {
+            Ok(v) => Ok(v),
+            Err((err, ctx)) => Err(self.check_revoked(id, err).await.with_context(ctx)),
+        }
+    }
+
+    /// Sends `data` over channel `id` as a request and waits up
+    /// to `timeout` for the peer's response.
+    ///
+    /// The peer answers with [`respond`][Self::respond] or
+    /// [`serve`][Self::serve]. The response is matched to the
+    /// request by a random [`RequestId`], which is encrypted and
+    /// authenticated along with the data, and must arrive over
+    /// the same channel. Responses that arrive after the
+    /// timeout or more than once are dropped and reported as
+    /// [`AfcEvent::ResponseDropped`].
+    ///
+    /// While waiting, this polls the router like
+    /// [`poll`][Self::poll], so other messages, requests, and
+    /// events are queued as usual.
+    ///
+    /// It is an error if the response does not arrive in time
+    /// ([`AfcError::RequestTimeout`]) or if the channel is
+    /// removed while waiting.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to cancel the resulting future. Doing so
+    /// might lose data.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id), ?timeout))]
+    pub async fn request(&mut self, id: AfcId, data: &[u8], timeout: Duration) -> Result<Response> {
+        let request = RequestId::random();
+        self.pending.insert(request, id);
+        let ctx = self.afc.error_context("request", id);
+        let result = self.await_response(id, data, request, timeout).await;
+        let resp = self.pending.remove(request);
+        result.context(|| ctx.clone())?;
+        resp.assume("response should have arrived").context(|| ctx)
+    }
+
+    /// Sends request `request` and polls until its response
+    /// arrives.
+    async fn await_response(
+        &mut self,
+        id: AfcId,
+        data: &[u8],
+        request: RequestId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now().checked_add(timeout);
+        self.await_window(id).await?;
+        let result = self
+            .afc
+            .send_data(id, data, Some(Ext::Request(request)), None, None)
+            .await;
+        self.report_queue_changes();
+        self.report_expiries().await;
+        if let Err(err) = result {
+            return Err(self.check_revoked(id, err).await);
+        }
+        debug!(%request, "sent request");
+
+        while !self.pending.is_done(request) {
+            if !self.afc.has_channel(id) {
+                return Err(AfcError::ChannelNotFound(id).into());
+            }
+            let remaining = deadline.map_or(Duration::MAX, |d| {
+                d.saturating_duration_since(Instant::now())
+            });
+            if remaining.is_zero() || !self.poll_timeout(remaining).await? {
+                debug!(%request, "request timed out");
+                return Err(AfcError::RequestTimeout(id).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Retrieves the next request received from a peer, if any.
+    ///
+    /// Requests are not returned by
+    /// [`try_recv_data`][Self::try_recv_data]. Answer them with
+    /// [`respond`][Self::respond].
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub fn try_recv_request(&mut self) -> Option<Request> {
+        let req = self.requests.pop_front()?;
+        self.count_queued(req.channel, false);
+        debug!(request = %req.id, seq = %req.seq, "received request");
+        Some(req)
+    }
+
+    /// Waits for the flow control window of channel `id`'s peer
+    /// to open, polling the router meanwhile.
+    ///
+    /// Fails with [`AfcError::WindowExhausted`] if it does not
+    /// open within [`FlowControl::max_wait`][crate::FlowControl::max_wait].
+    async fn await_window(&mut self, id: AfcId) -> Result<()> {
+        if self.afc.window_open(id) {
+            return Ok(());
+        }
+        debug!(afc_id = %ShortId::new("afc", id), "waiting for flow control window");
+        let deadline = Instant::now().checked_add(self.afc.window_max_wait());
+        while !self.afc.window_open(id) {
+            let remaining = deadline.map_or(Duration::MAX, |d| {
+                d.saturating_duration_since(Instant::now())
+            });
+            if remaining.is_zero() || !self.poll_timeout(remaining).await? {
+                return Err(AfcError::WindowExhausted(id).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as the response to `request` over the
+    /// channel that it was received from.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future, but the
+    /// response might still be sent.
+    pub async fn respond(&mut self, request: &Request, data: &[u8]) -> Result<()> {
+        self.send_response(request.channel, request.id, data).await
+    }
+
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id), %request))]
+    async fn send_response(&mut self, id: AfcId, request: RequestId, data: &[u8]) -> Result<()> {
+        self.send("respond", id, data, Some(Ext::Response(request)), None)
+            .await
+    }
+
+    /// Answers every request received from a peer with
+    /// `handler`, polling the router until polling fails.
+    ///
+    /// Each request is answered with the data that `handler`
+    /// returns, or not at all if it returns `None`. A response
+    /// that cannot be sent (e.g., because the channel was
+    /// removed) is logged and skipped.
+    ///
+    /// Other messages and events are queued as usual, so
+    /// a client that serves requests should either only be used
+    /// for requests (see [`isolate_team`][Self::isolate_team])
+    /// or deliver its other messages to subscribers (see
+    /// [`subscribe`][Self::subscribe]).
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to cancel the resulting future. Doing so
+    /// might lose data.
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub async fn serve<F, Fut>(&mut self, mut handler: F) -> Result<Infallible>
+    where
+        F: FnMut(Request) -> Fut,
+        Fut: Future<Output = Option<Vec<u8>>>,
+    {
+        loop {
+            while let Some(req) = self.try_recv_request() {
+                let (channel, id) = (req.channel, req.id);
+                let Some(resp) = handler(req).await else {
+                    debug!(request = %id, "not answering request");
+                    continue;
+                };
+                if let Err(err) = self.send_response(channel, id, &resp).await {
+                    warn!(%channel, request = %id, %err, "unable to send response");
+                }
+            }
+            self.poll().await?;
+        }
+    }
+
+    /// Sends everything read from `reader` over a fast channel
+    /// as a stream.
+    ///
+    /// Unlike [`send_data`][Self::send_data], the data can be
+    /// larger than [`Limits::max_data_size`]: it is split into
+    /// fragments that are sent as separate messages. The peer
+    /// reassembles the fragments and delivers the whole stream
+    /// as a single [`AfcMsg`] whose `seq` is that of the last
+    /// fragment, up to [`RouterConfig::max_stream_size`] bytes.
+    ///
+    /// Other data can be sent over the channel while a stream
+    /// is being sent, but only one stream at a time. Over UDP,
+    /// a lost fragment drops the whole stream, which is reported
+    /// as [`AfcEvent::StreamDropped`].
+    ///
+    /// Returns the number of bytes sent.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is safe to cancel the resulting future, but the peer
+    /// drops the partial stream when the next stream starts.
+    pub async fn send_stream<R>(&mut self, id: AfcId, reader: R) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.stream("send_stream", id, reader, |_| {}).await
+    }
+
+    /// Like [`send_stream`][Self::send_stream], but calls
+    /// `progress` with the number of bytes sent so far after
+    /// each fragment is sent.
+    #[instrument(skip_all, fields(self = self.debug(), afc_id = %ShortId::new("afc", id)))]
+    pub async fn send_stream_with_progress<R, F>(
+        &mut self,
+        id: AfcId,
+        reader: R,
+        progress: F,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64),
+    {
+        self.stream("send_stream_with_progress", id, reader, progress)
+            .await
+    }
+
+    /// Sends everything read from `reader` over channel `id`
+    /// for `operation`.
+    async fn stream<R, F>(
+        &mut self,
+        operation: &'static str,
+        id: AfcId,
+        reader: R,
+        progress: F,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64),
+    {
+        let result = self.afc.send_stream(id, reader, progress).await;
+        self.sent(operation, id, result).await
     }
 
     /// Retrieves the next AFC message, if any.
@@ -277,31 +2495,369 @@ impl Client {
     pub fn try_recv_data(&mut self) -> Option<AfcMsg> {
         // TODO(eric): This method should block until a message
         // has been received.
-        let msg = self.msgs.pop_front()?;
+        let msg = self.msgs.pop()?;
+        self.count_queued(msg.channel, false);
         debug!(label = %msg.label, seq = %msg.seq, "received AFC data message");
         Some(msg)
     }
+
+    /// Subscribes to the data received over channel `id`.
+    ///
+    /// Afterwards, [`poll`][Self::poll] sends the channel's
+    /// messages to the returned receiver instead of queueing
+    /// them for [`try_recv_data`][Self::try_recv_data], so
+    /// different parts of an application can each read their
+    /// own channels. Subscribing again replaces the previous
+    /// subscriber. Dropping the receiver unsubscribes.
+    ///
+    /// The receiver holds up to
+    /// [`SUBSCRIPTION_CAPACITY`][Self::SUBSCRIPTION_CAPACITY]
+    /// messages. Once it is full, `poll` waits for the
+    /// subscriber to catch up, which stalls every channel, so
+    /// subscribers should keep reading. The stream ends when
+    /// the channel is deleted or revoked.
+    ///
+    /// In bounded-memory mode (see [`RouterConfig::bounded`]),
+    /// the buffers are borrowed from the router's pool like
+    /// those of [`try_recv_data`][Self::try_recv_data] and
+    /// should be given back with
+    /// [`recycle_buf`][Self::recycle_buf].
+    pub fn subscribe(&mut self, id: AfcId) -> mpsc::Receiver<(Vec<u8>, Label, Seq)> {
+        let (tx, rx) = mpsc::channel(Self::SUBSCRIPTION_CAPACITY);
+        self.subscribers.insert(id, tx);
+        rx
+    }
+
+    /// Returns the buffer of a message from
+    /// [`try_recv_data`][Self::try_recv_data] to the router so
+    /// that it can be reused.
+    ///
+    /// This is required in bounded-memory mode (see
+    /// [`RouterConfig::bounded`]), where received messages
+    /// borrow their buffers from a fixed pool. Messages that are
+    /// never recycled eventually exhaust it. Otherwise, it is
+    /// optional.
+    pub fn recycle(&mut self, msg: AfcMsg) {
+        self.afc.recycle(msg.data);
+    }
+
+    /// Like [`recycle`][Self::recycle], but for the buffer of
+    /// a message from [`subscribe`][Self::subscribe].
+    pub fn recycle_buf(&mut self, buf: Vec<u8>) {
+        self.afc.recycle(buf);
+    }
+
+    /// Retrieves the next AFC event, if any.
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub fn try_recv_event(&mut self) -> Option<AfcEvent> {
+        let event = self.events.pop_front()?;
+        debug!(?event, "received AFC event");
+        Some(event)
+    }
+
+    /// Polls the router until it has an event or message.
+    ///
+    /// Events are returned before messages so that, e.g.,
+    /// [`AfcEvent::ChannelOpened`] precedes the channel's data.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to cancel the resulting future. Doing so
+    /// might lose data.
+    #[cfg(feature = "stream")]
+    pub(crate) async fn next_event(&mut self) -> Result<AfcEvent> {
+        loop {
+            if let Some(event) = self.try_recv_event() {
+                return Ok(event);
+            }
+            if let Some(msg) = self.try_recv_data() {
+                return Ok(AfcEvent::Data(msg));
+            }
+            let data = self.poll_data().await?;
+            self.handle_data(data).await?;
+        }
+    }
+
+    /// Finishes frames whose writes were interrupted and closes
+    /// the router's streams.
+    ///
+    /// Peers are told why the streams are closed with
+    /// [`GoodbyeReason::Shutdown`].
+    ///
+    /// Unlike dropping the client, this waits for the streams
+    /// to be closed, for up to [`RouterConfig::close_timeout`].
+    /// Sending data afterwards opens new streams.
+    #[instrument(skip_all, fields(self = self.debug()))]
+    pub async fn shutdown(&mut self) {
+        self.afc.take_streams().close().await;
+    }
+
+    /// Hands the AFC router to a client in a new process, e.g.,
+    /// to upgrade the client in place, and stops this one.
+    ///
+    /// Waits for the new process to call
+    /// [`take_over`][Self::take_over] with `handoff_sock`, which
+    /// must not exist yet. Processes of other users are refused.
+    /// Then, the streams are closed like
+    /// [`shutdown`][Self::shutdown] does, the router's sockets
+    /// are released, and its channels, replay windows, and
+    /// session resumption tickets are handed to the new client.
+    /// The channels are not deleted. With
+    /// [`RouterConfig::session_resumption`], peers resume their
+    /// streams with the new client instead of being verified
+    /// again.
+    ///
+    /// Data that was received but not read is not handed off,
+    /// nor are the channels of teams isolated with
+    /// [`isolate_team`][Self::isolate_team], whose clients hand
+    /// off separately.
+    #[instrument(skip_all, fields(self = self.debug(), ?handoff_sock))]
+    pub async fn hand_off(mut self, handoff_sock: &Path) -> Result<()> {
+        let ctx = || ErrorContext::new("hand_off");
+        let mut conn = Handoff::accept(handoff_sock)
+            .await
+            .map_err(AfcError::Handoff)
+            .context(ctx)?;
+        let handoff = self.afc.handoff();
+        self.shutdown().await;
+        handoff
+            .send(&mut conn)
+            .await
+            .map_err(AfcError::Handoff)
+            .context(ctx)?;
+        // Release the router's sockets before the new client
+        // binds them, which it does once the connection is
+        // shut down.
+        drop(self);
+        conn.shutdown()
+            .await
+            .map_err(AfcError::Handoff)
+            .context(ctx)?;
+        info!("handed off AFC router");
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Close the streams before the rest of the router is
+        // torn down.
+        let streams = self.afc.take_streams();
+        if !streams.needs_flush() {
+            return;
+        }
+        match Handle::try_current() {
+            Ok(rt) => {
+                rt.spawn(streams.close());
+            }
+            Err(_) => warn!("not in a Tokio runtime, closing streams without saying goodbye"),
+        }
+    }
 }
 
 impl Client {
     /// Gets the public key bundle for this device.
     pub async fn get_key_bundle(&mut self) -> Result<KeyBundle> {
-        Ok(self.daemon.get_key_bundle(context::current()).await??)
+        rpc(self
+            .daemon
+            .get_key_bundle(rpc_context("get_key_bundle"))
+            .await)
+        .context(|| ErrorContext::new("get_key_bundle"))
     }
 
     /// Gets the public device ID for this device.
     pub async fn get_device_id(&mut self) -> Result<DeviceId> {
-        Ok(self.daemon.get_device_id(context::current()).await??)
+        rpc(self
+            .daemon
+            .get_device_id(rpc_context("get_device_id"))
+            .await)
+        .context(|| ErrorContext::new("get_device_id"))
     }
 
     /// Create a new graph/team with the current device as the owner.
     pub async fn create_team(&mut self) -> Result<TeamId> {
-        Ok(self.daemon.create_team(context::current()).await??)
+        rpc(self.daemon.create_team(rpc_context("create_team")).await)
+            .context(|| ErrorContext::new("create_team"))
     }
 
     /// Add a team to the local device store.
     pub async fn add_team(&mut self, team: TeamId) -> Result<()> {
-        Ok(self.daemon.add_team(context::current(), team).await??)
+        rpc(self.daemon.add_team(rpc_context("add_team"), team).await)
+            .context(|| ErrorContext::new("add_team").team(team))
+    }
+
+    /// Gets the capabilities of each role on the team.
+    ///
+    /// This can be used to check whether a device's role
+    /// permits an operation before attempting it.
+    pub async fn team_roles(&mut self, team: TeamId) -> Result<Vec<RoleInfo>> {
+        rpc(self
+            .daemon
+            .team_roles(rpc_context("team_roles"), team)
+            .await)
+        .context(|| ErrorContext::new("team_roles").team(team))
+    }
+
+    /// Exports the team's devices, roles, and label assignments
+    /// in `format`, e.g., to visualize them.
+    ///
+    /// This reads the daemon's copy of the team's state, so it
+    /// does not reflect changes that have not been synced yet.
+    pub async fn export_team_graph(&mut self, team: TeamId, format: GraphFormat) -> Result<String> {
+        rpc(self
+            .daemon
+            .export_team_graph(rpc_context("export_team_graph"), team, format)
+            .await)
+        .context(|| ErrorContext::new("export_team_graph").team(team))
+    }
+
+    /// Gets the daemon's sync sessions that are in progress.
+    pub async fn sync_status(&mut self) -> Result<Vec<SyncSession>> {
+        rpc(self.daemon.sync_status(rpc_context("sync_status")).await)
+            .context(|| ErrorContext::new("sync_status"))
+    }
+
+    /// Gets the ID of the key that the daemon proves its
+    /// identity to sync peers with.
+    ///
+    /// Other daemons can pin it with
+    /// [`pin_sync_peer`][Self::pin_sync_peer].
+    pub async fn sync_key_id(&mut self) -> Result<SyncKeyId> {
+        rpc(self.daemon.sync_key_id(rpc_context("sync_key_id")).await)
+            .context(|| ErrorContext::new("sync_key_id"))
+    }
+
+    /// Gets the keys that the daemon's sync peers must present.
+    pub async fn sync_pins(&mut self) -> Result<Vec<SyncPin>> {
+        rpc(self.daemon.sync_pins(rpc_context("sync_pins")).await)
+            .context(|| ErrorContext::new("sync_pins"))
+    }
+
+    /// Pins the key of the sync peer at `addr`, replacing its
+    /// current pin, if any.
+    ///
+    /// The daemon refuses to sync with the peer unless it
+    /// presents `key`. Pinning the key from a [`PinViolation`]
+    /// accepts a peer's new key.
+    pub async fn pin_sync_peer(&mut self, addr: Addr, key: SyncKeyId) -> Result<()> {
+        rpc(self
+            .daemon
+            .pin_sync_peer(rpc_context("pin_sync_peer"), addr, key)
+            .await)
+        .context(|| ErrorContext::new("pin_sync_peer"))
+    }
+
+    /// Removes the pin of the sync peer at `addr`.
+    ///
+    /// Returns whether the peer was pinned.
+    pub async fn unpin_sync_peer(&mut self, addr: Addr) -> Result<bool> {
+        rpc(self
+            .daemon
+            .unpin_sync_peer(rpc_context("unpin_sync_peer"), addr)
+            .await)
+        .context(|| ErrorContext::new("unpin_sync_peer"))
+    }
+
+    /// Gets the sync peers that the daemon recently rejected
+    /// because they did not present their pinned key, after the
+    /// violation with sequence number `after`, oldest first.
+    pub async fn sync_pin_violations(&mut self, after: Option<u64>) -> Result<Vec<PinViolation>> {
+        rpc(self
+            .daemon
+            .sync_pin_violations(rpc_context("sync_pin_violations"), after)
+            .await)
+        .context(|| ErrorContext::new("sync_pin_violations"))
+    }
+
+    /// Writes a backup of the daemon's persistent state to
+    /// `path` on the daemon's host.
+    ///
+    /// The backup holds the daemon's secret keys, so it must be
+    /// protected like the daemon's working directory. It is
+    /// restored by starting the daemon with `--restore`.
+    pub async fn backup(&mut self, path: PathBuf) -> Result<BackupInfo> {
+        rpc(self.daemon.backup(rpc_context("backup"), path).await)
+            .context(|| ErrorContext::new("backup"))
+    }
+
+    /// Checks the integrity of the backup at `path` on the
+    /// daemon's host without restoring it.
+    pub async fn verify_backup(&mut self, path: PathBuf) -> Result<BackupInfo> {
+        rpc(self
+            .daemon
+            .verify_backup(rpc_context("verify_backup"), path)
+            .await)
+        .context(|| ErrorContext::new("verify_backup"))
+    }
+
+    /// Gets the cipher suite that the daemon uses.
+    ///
+    /// The client checks that it was built with the same suite
+    /// when it connects.
+    pub async fn cipher_suite(&mut self) -> Result<CipherSuiteInfo> {
+        rpc(self.daemon.cipher_suite(rpc_context("cipher_suite")).await)
+            .context(|| ErrorContext::new("cipher_suite"))
+    }
+
+    /// Gets the daemon's policy versions, including the version
+    /// it is using.
+    pub async fn policy_info(&mut self) -> Result<PolicyInfo> {
+        rpc(self.daemon.policy_info(rpc_context("policy_info")).await)
+            .context(|| ErrorContext::new("policy_info"))
+    }
+
+    /// Loads a policy bundle into the daemon.
+    ///
+    /// Bundles are immutable, so `version` must not already
+    /// refer to a different bundle. The daemon switches to the
+    /// bundle if the team already upgraded to `version`.
+    pub async fn load_policy(&mut self, version: u32, doc: String) -> Result<()> {
+        rpc(self
+            .daemon
+            .load_policy(rpc_context("load_policy"), version, doc)
+            .await)
+        .context(|| ErrorContext::new("load_policy"))
+    }
+
+    /// Pins the daemon to a policy version, or unpins it if
+    /// `version` is `None`.
+    ///
+    /// A pinned daemon ignores the team's upgrades to other
+    /// versions.
+    pub async fn pin_policy(&mut self, version: Option<u32>) -> Result<()> {
+        rpc(self
+            .daemon
+            .pin_policy(rpc_context("pin_policy"), version)
+            .await)
+        .context(|| ErrorContext::new("pin_policy"))
+    }
+
+    /// Accepts an [`Invitation`] created by another device.
+    ///
+    /// `inviter` is the key bundle of the device that created
+    /// the invitation, which must be obtained from a trusted
+    /// source. The invitation is rejected unless it was created
+    /// and signed by that device.
+    ///
+    /// The device joins the team once it is able to sync with
+    /// one of the invitation's sync addresses, which it does
+    /// every `interval`.
+    pub async fn accept_invitation(
+        &mut self,
+        invitation: Invitation,
+        inviter: KeyBundle,
+        interval: Duration,
+    ) -> Result<TeamId> {
+        rpc(self
+            .daemon
+            .accept_invitation(
+                rpc_context("accept_invitation"),
+                invitation,
+                inviter,
+                interval,
+            )
+            .await)
+        .context(|| ErrorContext::new("accept_invitation"))
     }
 
     /// Remove a team from the local device store.
@@ -331,67 +2887,141 @@ pub struct Team<'a> {
 }
 
 impl Team<'_> {
+    /// Returns the context of an error that `operation`
+    /// returned.
+    fn context(&self, operation: &'static str) -> ErrorContext {
+        ErrorContext::new(operation).team(self.id)
+    }
+
     /// Adds a peer for automatic periodic Aranya state syncing.
     pub async fn add_sync_peer(&mut self, addr: Addr, interval: Duration) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .add_sync_peer(context::current(), addr, self.id, interval)
-            .await??)
+            .add_sync_peer(rpc_context("add_sync_peer"), addr, self.id, interval)
+            .await)
+        .context(|| self.context("add_sync_peer"))
     }
 
     /// Removes a peer from automatic Aranya state syncing.
     pub async fn remove_sync_peer(&mut self, addr: Addr) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .remove_sync_peer(context::current(), addr, self.id)
-            .await??)
+            .remove_sync_peer(rpc_context("remove_sync_peer"), addr, self.id)
+            .await)
+        .context(|| self.context("remove_sync_peer"))
+    }
+
+    /// Cancels the sync session with a peer, if one is in
+    /// progress.
+    ///
+    /// The peer is still synced with at its next interval.
+    /// Returns whether a session was cancelled.
+    pub async fn cancel_sync(&mut self, addr: Addr) -> Result<bool> {
+        rpc(self
+            .client
+            .daemon
+            .cancel_sync(rpc_context("cancel_sync"), addr, self.id)
+            .await)
+        .context(|| self.context("cancel_sync"))
     }
 
     /// Close the team and stop all operations on the graph.
     pub async fn close_team(&mut self) -> Result<()> {
-        Ok(self
+        rpc(self
+            .client
+            .daemon
+            .close_team(rpc_context("close_team"), self.id)
+            .await)
+        .context(|| self.context("close_team"))
+    }
+
+    /// Switches the team to another policy version.
+    ///
+    /// The daemon must have loaded the version's bundle with
+    /// [`Client::load_policy`]. Other devices only switch to the
+    /// same bundle, not just one with the same version. Rolling
+    /// back is an upgrade to an older version.
+    pub async fn upgrade_policy(&mut self, version: u32) -> Result<()> {
+        rpc(self
             .client
             .daemon
-            .close_team(context::current(), self.id)
-            .await??)
+            .upgrade_policy(rpc_context("upgrade_policy"), self.id, version)
+            .await)
+        .context(|| self.context("upgrade_policy"))
     }
 
     /// Add a device to the team with the default `Member` role.
     pub async fn add_device_to_team(&mut self, keys: KeyBundle) -> Result<()> {
-        Ok(self
+        rpc(self
+            .client
+            .daemon
+            .add_device_to_team(rpc_context("add_device_to_team"), self.id, keys)
+            .await)
+        .context(|| self.context("add_device_to_team"))
+    }
+
+    /// Add a device to the team with the default `Member` role
+    /// and create an [`Invitation`] for it.
+    ///
+    /// The invitation can be carried to the device out-of-band
+    /// and accepted with [`Client::accept_invitation`]. If
+    /// `sync_addrs` is empty, the invitation contains this
+    /// device's sync address.
+    pub async fn create_invitation(
+        &mut self,
+        keys: KeyBundle,
+        sync_addrs: Vec<Addr>,
+    ) -> Result<Invitation> {
+        rpc(self
             .client
             .daemon
-            .add_device_to_team(context::current(), self.id, keys)
-            .await??)
+            .create_invitation(rpc_context("create_invitation"), self.id, keys, sync_addrs)
+            .await)
+        .context(|| self.context("create_invitation"))
     }
 
     /// Remove a device from the team.
+    ///
+    /// The daemon immediately removes its AFC channels with the
+    /// device and pushes the removal to its sync peers. Online
+    /// peers that sync with this daemon pull the removal and
+    /// remove their channels with the device right away.
+    /// Otherwise, peers find out at their next sync, so the
+    /// removal takes at most one sync interval per hop to
+    /// reach them.
+    ///
+    /// Once a daemon has removed a channel, sealing or opening
+    /// data for it fails, and the router removes it within
+    /// [`Limits::max_revocation_delay`].
     pub async fn remove_device_from_team(&mut self, device: DeviceId) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .remove_device_from_team(context::current(), self.id, device)
-            .await??)
+            .remove_device_from_team(rpc_context("remove_device_from_team"), self.id, device)
+            .await)
+        .context(|| self.context("remove_device_from_team"))
     }
 
     /// Assign a role to a device.
     pub async fn assign_role(&mut self, device: DeviceId, role: Role) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .assign_role(context::current(), self.id, device, role)
-            .await??)
+            .assign_role(rpc_context("assign_role"), self.id, device, role)
+            .await)
+        .context(|| self.context("assign_role"))
     }
 
     /// Revoke a role from a device. This sets the device's role back to the default `Member` role.
     pub async fn revoke_role(&mut self, device: DeviceId, role: Role) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .revoke_role(context::current(), self.id, device, role)
-            .await??)
+            .revoke_role(rpc_context("revoke_role"), self.id, device, role)
+            .await)
+        .context(|| self.context("revoke_role"))
     }
 
     /// Associate a network identifier to a device for use with AFC.
@@ -404,11 +3034,17 @@ impl Team<'_> {
         device: DeviceId,
         net_identifier: NetIdentifier,
     ) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .assign_net_identifier(context::current(), self.id, device, net_identifier)
-            .await??)
+            .assign_net_identifier(
+                rpc_context("assign_net_identifier"),
+                self.id,
+                device,
+                net_identifier,
+            )
+            .await)
+        .context(|| self.context("assign_net_identifier"))
     }
 
     /// Disassociate a network identifier from a device.
@@ -417,29 +3053,103 @@ impl Team<'_> {
         device: DeviceId,
         net_identifier: NetIdentifier,
     ) -> Result<()> {
-        Ok(self
+        rpc(self
+            .client
+            .daemon
+            .remove_net_identifier(
+                rpc_context("remove_net_identifier"),
+                self.id,
+                device,
+                net_identifier,
+            )
+            .await)
+        .context(|| self.context("remove_net_identifier"))
+    }
+
+    /// Sets the metadata entry `key` of a device to `value`,
+    /// e.g., its location or hardware revision.
+    ///
+    /// The entry is replicated to the rest of the team. Keys
+    /// are limited to [`MAX_DEVICE_METADATA_KEY_LEN`] bytes and
+    /// values to [`MAX_DEVICE_METADATA_VALUE_LEN`] bytes.
+    ///
+    /// [`MAX_DEVICE_METADATA_KEY_LEN`]: aranya_daemon_api::MAX_DEVICE_METADATA_KEY_LEN
+    /// [`MAX_DEVICE_METADATA_VALUE_LEN`]: aranya_daemon_api::MAX_DEVICE_METADATA_VALUE_LEN
+    pub async fn set_device_metadata(
+        &mut self,
+        device: DeviceId,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<()> {
+        rpc(self
+            .client
+            .daemon
+            .set_device_metadata(
+                rpc_context("set_device_metadata"),
+                self.id,
+                device,
+                key.into(),
+                value.into(),
+            )
+            .await)
+        .context(|| self.context("set_device_metadata"))
+    }
+
+    /// Unsets the metadata entry `key` of a device.
+    pub async fn unset_device_metadata(
+        &mut self,
+        device: DeviceId,
+        key: impl Into<String>,
+    ) -> Result<()> {
+        rpc(self
+            .client
+            .daemon
+            .unset_device_metadata(
+                rpc_context("unset_device_metadata"),
+                self.id,
+                device,
+                key.into(),
+            )
+            .await)
+        .context(|| self.context("unset_device_metadata"))
+    }
+
+    /// Returns the metadata entry `key` of a device, if it is
+    /// set.
+    ///
+    /// This reads the daemon's copy of the team's state, so it
+    /// does not reflect changes that have not been synced yet.
+    pub async fn device_metadata(
+        &mut self,
+        device: DeviceId,
+        key: impl Into<String>,
+    ) -> Result<Option<String>> {
+        rpc(self
             .client
             .daemon
-            .remove_net_identifier(context::current(), self.id, device, net_identifier)
-            .await??)
+            .device_metadata(rpc_context("device_metadata"), self.id, device, key.into())
+            .await)
+        .context(|| self.context("device_metadata"))
     }
 
     /// Create an Aranya Fast Channels (AFC) label.
     pub async fn create_label(&mut self, label: Label) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .create_label(context::current(), self.id, label)
-            .await??)
+            .create_label(rpc_context("create_label"), self.id, label)
+            .await)
+        .context(|| self.context("create_label"))
     }
 
     /// Delete an Aranya Fast Channels (AFC) label.
     pub async fn delete_label(&mut self, label: Label) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .delete_label(context::current(), self.id, label)
-            .await??)
+            .delete_label(rpc_context("delete_label"), self.id, label)
+            .await)
+        .context(|| self.context("delete_label"))
     }
 
     /// Assign an Aranya Fast Channels (AFC) label to a device.
@@ -447,19 +3157,21 @@ impl Team<'_> {
     /// This grants the device permission to send/receive AFC data using that label.
     /// A channel must be created with the label in order to send data using that label.
     pub async fn assign_label(&mut self, device: DeviceId, label: Label) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .assign_label(context::current(), self.id, device, label)
-            .await??)
+            .assign_label(rpc_context("assign_label"), self.id, device, label)
+            .await)
+        .context(|| self.context("assign_label"))
     }
 
     /// Revoke an Aranya Fast Channels (AFC) label from a device.
     pub async fn revoke_label(&mut self, device: DeviceId, label: Label) -> Result<()> {
-        Ok(self
+        rpc(self
             .client
             .daemon
-            .revoke_label(context::current(), self.id, device, label)
-            .await??)
+            .revoke_label(rpc_context("revoke_label"), self.id, device, label)
+            .await)
+        .context(|| self.context("revoke_label"))
     }
 }
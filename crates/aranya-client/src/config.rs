@@ -0,0 +1,1230 @@
+//! Client configuration.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(feature = "sim")]
+use crate::sim::SimNet;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{
+    foreign::ForeignHandler, resolve::Resolver, transport::AfcTransport, AfcId, ChannelSummary,
+    Label, LabelSchedule, Priority,
+};
+
+/// Configures the AFC router.
+#[derive(Clone, Debug)]
+pub struct RouterConfig {
+    /// Ports to try, in order, if the router's listen address
+    /// cannot be bound (e.g., because another instance already
+    /// uses its port).
+    ///
+    /// Each port is tried with the IP addresses that the listen
+    /// address resolves to. Port 0 asks the OS for any free
+    /// port. Use [`Client::afc_local_addr`] to find out which
+    /// port was bound. The router tells its peers the bound port
+    /// when it greets them.
+    ///
+    /// [`Client::afc_local_addr`]: crate::Client::afc_local_addr
+    pub fallback_ports: Vec<u16>,
+    /// Additional addresses to listen on, e.g., an IPv6 address
+    /// in addition to an IPv4 one.
+    ///
+    /// Each address gets its own TCP listener and UDP socket.
+    /// Fallback ports are not used for them, but port 0 asks
+    /// the OS for any free port. Use
+    /// [`Client::afc_local_addrs`] to find out which addresses
+    /// were bound. Datagrams are sent from the first socket in
+    /// the peer's address family.
+    ///
+    /// [`Client::afc_local_addrs`]: crate::Client::afc_local_addrs
+    pub additional_addrs: Vec<SocketAddr>,
+    /// The maximum number of accepted streams whose peer has
+    /// not yet identified itself.
+    ///
+    /// Incoming streams beyond this limit are shed: the router
+    /// sends a busy frame and closes the stream.
+    pub max_pending_handshakes: usize,
+    /// How long the peer of an accepted stream has to identify
+    /// itself.
+    ///
+    /// Streams whose peer has not sent a hello by then are
+    /// closed, so that they do not hold on to one of the
+    /// [`max_pending_handshakes`][Self::max_pending_handshakes]
+    /// slots. It also bounds TLS handshakes, both ways. Unlike
+    /// [`stream_timeouts`][Self::stream_timeouts], this cannot be
+    /// disabled.
+    pub handshake_timeout: Duration,
+    /// The maximum number of open streams.
+    ///
+    /// Incoming streams beyond this limit are shed. Streams
+    /// that we open are not limited.
+    ///
+    /// Suspicious peers (see [`PeerReputation`]) are held to
+    /// half of this limit and half of
+    /// [`max_pending_handshakes`][Self::max_pending_handshakes].
+    ///
+    /// [`PeerReputation`]: crate::PeerReputation
+    pub max_streams: usize,
+    /// Where to persist peer reputations.
+    ///
+    /// Changes are written a second after they happen, so that
+    /// bursts of changes are written at once, and when the
+    /// router is dropped. If `None`, reputations are only kept
+    /// in memory.
+    pub reputation_path: Option<PathBuf>,
+    /// How long to wait before reconnecting to a peer after
+    /// a connection failure.
+    ///
+    /// The wait grows with the peer's penalty (see
+    /// [`PeerReputation::reconnect_backoff`]).
+    ///
+    /// [`PeerReputation::reconnect_backoff`]: crate::PeerReputation::reconnect_backoff
+    pub reconnect_backoff: Duration,
+    /// The maximum number of times to try to resend data after
+    /// a transient failure (e.g., the TCP stream dropped).
+    ///
+    /// Data that cannot be sent is queued and resent, after
+    /// reconnecting if needed, with exponential backoff
+    /// starting at [`reconnect_backoff`][Self::reconnect_backoff].
+    /// Once the attempts are exhausted, the queued data is
+    /// dropped and reported as
+    /// [`AfcEvent::SendFailed`][crate::AfcEvent::SendFailed].
+    ///
+    /// If zero, transient failures are returned to the caller
+    /// instead.
+    pub max_reconnect_attempts: u32,
+    /// The maximum time to wait between attempts to resend
+    /// data.
+    pub max_reconnect_backoff: Duration,
+    /// Resolves the hosts of peers' [`NetIdentifier`]s instead
+    /// of the system resolver.
+    ///
+    /// Use a [`StaticResolver`] to map identifiers to addresses
+    /// without DNS. See [`Resolver`].
+    ///
+    /// Defaults to `None`.
+    ///
+    /// [`NetIdentifier`]: aranya_daemon_api::NetIdentifier
+    /// [`StaticResolver`]: crate::StaticResolver
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// How long to cache the addresses that a peer's
+    /// [`NetIdentifier`] resolves to.
+    ///
+    /// Resolvers do not report the TTLs of DNS records, so this should be no longer than the shortest
+    /// TTL of the peers' records. Cached addresses are also
+    /// forgotten when none of them can be reached, and
+    /// [`Client::flush_dns_cache`] forgets all of them. If
+    /// zero, addresses are looked up every time they are
+    /// needed.
+    ///
+    /// [`NetIdentifier`]: aranya_daemon_api::NetIdentifier
+    /// [`Client::flush_dns_cache`]: crate::Client::flush_dns_cache
+    pub dns_ttl: Duration,
+    /// How long to cache failed DNS lookups.
+    ///
+    /// If zero, failed lookups are not cached.
+    pub dns_negative_ttl: Duration,
+    /// The maximum number of cached DNS lookups.
+    ///
+    /// When the cache is full, the lookup that expires soonest
+    /// is forgotten.
+    pub max_dns_entries: usize,
+    /// Which addresses to dial first when a peer's network
+    /// identifier resolves to both IPv6 and IPv4 addresses.
+    ///
+    /// See [`AddrPreference`]. Defaults to
+    /// [`AddrPreference::System`].
+    pub addr_preference: AddrPreference,
+    /// How long to wait for a connection attempt to a peer
+    /// before also dialing its next address.
+    ///
+    /// This is the "Connection Attempt Delay" of Happy Eyeballs
+    /// (RFC 8305): the slow attempt is not abandoned, and the
+    /// first attempt to succeed wins, so an unreachable address
+    /// family only costs this much. A failed attempt starts the
+    /// next one right away. If `None`, addresses are dialed one
+    /// at a time. Defaults to
+    /// [`DEFAULT_CONNECT_ATTEMPT_DELAY`][Self::DEFAULT_CONNECT_ATTEMPT_DELAY].
+    pub connect_attempt_delay: Option<Duration>,
+    /// How streams to peers are opened, e.g., to keep AFC
+    /// traffic on a management network.
+    ///
+    /// See [`Outbound`]. Defaults to letting the OS choose.
+    pub outbound: Outbound,
+    /// Randomizes the wait between attempts to resend data so
+    /// that peers do not reconnect in lockstep.
+    pub reconnect_jitter: bool,
+    /// How long session resumption tickets are valid for, or
+    /// `None` to not issue them.
+    ///
+    /// Once the daemon verifies the peer on a stream that the
+    /// peer opened, we issue it a ticket. If the stream breaks,
+    /// the peer proves that it holds the ticket by answering a
+    /// fresh challenge when it reconnects, and the new stream
+    /// is verified and takes over the old stream's channels
+    /// without waiting for another control message, even if
+    /// the old stream was already closed. The channels
+    /// keep their sequence numbers, so messages sent over the
+    /// old stream cannot be replayed over the new one. The
+    /// ticket is sealed with the key of one of the stream's
+    /// channels and never sent again, so it cannot be stolen
+    /// from the stream even without TLS.
+    ///
+    /// Only the peer that accepts streams needs this. Peers
+    /// that predate session resumption do not understand
+    /// tickets, so only enable it if every peer supports it.
+    ///
+    /// Defaults to `None`.
+    pub session_resumption: Option<Duration>,
+    /// The maximum number of messages per channel waiting to be
+    /// resent.
+    ///
+    /// Sending data over a channel whose queue is full fails
+    /// with [`AfcError::RetryQueueFull`].
+    ///
+    /// [`AfcError::RetryQueueFull`]: crate::AfcError::RetryQueueFull
+    pub retry_queue_len: usize,
+    /// What to do with data received for a channel that was
+    /// recently removed.
+    pub removed_channel: RemovedChannelPolicy,
+    /// What to do when a peer greets us with a wire version
+    /// that we do not speak.
+    pub version_mismatch: VersionMismatchPolicy,
+    /// How long a removed channel is remembered.
+    ///
+    /// Data received for a channel after this period is
+    /// treated as data for an unknown channel.
+    pub removed_channel_grace: Duration,
+    /// How often to remove the channels that the daemon
+    /// removed, e.g., because a policy change revoked their
+    /// label or the peer was removed from the team.
+    ///
+    /// See [`Client::reconcile_channels`]. If `None`, channels
+    /// are only reconciled when sealing or opening data fails.
+    ///
+    /// [`Client::reconcile_channels`]: crate::Client::reconcile_channels
+    pub reconcile_interval: Option<Duration>,
+    /// How long the router may take to close its streams when
+    /// the [`Client`] is shut down or dropped.
+    ///
+    /// Frames whose writes were interrupted (e.g., because the
+    /// future sending them was dropped) are finished before the
+    /// streams are closed, so the peer never sees part of
+    /// a frame. See [`Client::shutdown`]. Defaults to
+    /// [`DEFAULT_CLOSE_TIMEOUT`][Self::DEFAULT_CLOSE_TIMEOUT].
+    ///
+    /// [`Client`]: crate::Client
+    /// [`Client::shutdown`]: crate::Client::shutdown
+    pub close_timeout: Duration,
+    /// How many messages the router may handle in a row without
+    /// waiting before it yields to other tasks.
+    ///
+    /// Under sustained load, the router's streams are always
+    /// ready, so [`Client::poll`] never waits and would
+    /// otherwise keep its Tokio worker from running other
+    /// tasks. Yielding lets them run at the cost of some
+    /// throughput. The budget is refilled whenever the router
+    /// waits. Zero disables yielding. Defaults to
+    /// [`DEFAULT_YIELD_BUDGET`][Self::DEFAULT_YIELD_BUDGET].
+    ///
+    /// [`Client::poll`]: crate::Client::poll
+    pub yield_budget: u32,
+    /// Enables the end-to-end integrity self-check for channels
+    /// that we create.
+    ///
+    /// This is a diagnostic for bring-up. Each message carries
+    /// a checksum of its plaintext inside the encrypted
+    /// payload, which the peer verifies after decrypting it.
+    /// A mismatch means that the plaintext was corrupted before
+    /// it was sealed or after it was opened (e.g., by an
+    /// application buffer bug) rather than in transit, which
+    /// would cause a decryption failure. Mismatches are
+    /// reported as
+    /// [`AfcEvent::ChecksumMismatch`][crate::AfcEvent::ChecksumMismatch]
+    /// and the message is not delivered.
+    ///
+    /// The setting is sent to the peer when the channel is
+    /// created, so the peer does not need to enable it.
+    /// Defaults to `false`.
+    pub self_check: bool,
+    /// Adds the time that each message was sent to the
+    /// messages that we send.
+    ///
+    /// The timestamp is sealed inside the encrypted payload, so
+    /// it is authenticated along with the message and is
+    /// reported as [`AfcMsg::sent_at`][crate::AfcMsg::sent_at].
+    /// (The message's sequence number is always authenticated.)
+    /// Channel keys are shared by both peers, so the timestamp
+    /// proves that a channel member sent the message at that
+    /// time, not which member did.
+    ///
+    /// The peer also uses the timestamps to estimate the
+    /// channel's one-way latency (see
+    /// [`ChannelStats::latency`][crate::ChannelStats::latency]).
+    ///
+    /// Peers that predate timestamps cannot open messages that
+    /// carry one. Defaults to `false`.
+    pub send_timestamps: bool,
+    /// How far in the future a received message's timestamp
+    /// may be.
+    ///
+    /// Messages with later timestamps are not delivered and are
+    /// reported as
+    /// [`AfcEvent::TimestampRejected`][crate::AfcEvent::TimestampRejected].
+    /// Timestamps in the past are always accepted since
+    /// messages can be delayed. Defaults to
+    /// [`DEFAULT_MAX_CLOCK_SKEW`][Self::DEFAULT_MAX_CLOCK_SKEW].
+    pub max_clock_skew: Duration,
+    /// The maximum size in bytes of an encoded message.
+    ///
+    /// Data larger than this is rejected with
+    /// [`AfcError::MsgTooLarge`] when it is sent, and messages
+    /// larger than this are rejected when they are received.
+    /// Peers should use the same limit. Values below
+    /// [`MIN_MAX_MSG_SIZE`][Self::MIN_MAX_MSG_SIZE] are raised
+    /// to it.
+    ///
+    /// [`AfcError::MsgTooLarge`]: crate::AfcError::MsgTooLarge
+    pub max_msg_size: u32,
+    /// The maximum size in bytes of a stream received with
+    /// [`Client::send_stream`].
+    ///
+    /// Larger streams are dropped and reported as
+    /// [`AfcEvent::StreamDropped`].
+    ///
+    /// [`Client::send_stream`]: crate::Client::send_stream
+    /// [`AfcEvent::StreamDropped`]: crate::AfcEvent::StreamDropped
+    pub max_stream_size: usize,
+    /// The default rate limit for each channel.
+    ///
+    /// Override it for a channel with
+    /// [`Client::set_channel_rate_limit`]. See [`RateLimit`].
+    /// Defaults to `None`.
+    ///
+    /// [`Client::set_channel_rate_limit`]: crate::Client::set_channel_rate_limit
+    pub rate_limit: Option<RateLimit>,
+    /// Limits the rate at which each peer (by IP address) can
+    /// send us control messages, which open channels.
+    ///
+    /// Control messages over the limit are rejected before the
+    /// daemon verifies them, so a peer cannot make the daemon
+    /// do unbounded work. [`RateLimit::max_wait`] does not
+    /// apply. Defaults to
+    /// [`DEFAULT_CTRL_RATE_LIMIT`][Self::DEFAULT_CTRL_RATE_LIMIT].
+    pub ctrl_rate_limit: Option<RateLimit>,
+    /// Periodically probes whether the peer of each channel is
+    /// still reachable.
+    ///
+    /// See [`KeepAlive`]. Defaults to `None`.
+    pub keepalive: Option<KeepAlive>,
+    /// Limits how many messages the peer of each channel can
+    /// send before we read them.
+    ///
+    /// See [`FlowControl`]. Defaults to `None`.
+    pub flow_control: Option<FlowControl>,
+    /// The default expiry policy for each channel.
+    ///
+    /// Override it for a channel with
+    /// [`Client::set_channel_expiry`]. See [`ChannelExpiry`].
+    /// Defaults to `None`.
+    ///
+    /// [`Client::set_channel_expiry`]: crate::Client::set_channel_expiry
+    pub expiry: Option<ChannelExpiry>,
+    /// Detects and closes half-open streams, e.g., after the
+    /// peer lost power or a NAT mapping expired.
+    ///
+    /// See [`StreamTimeouts`]. If `None`, a stream is only
+    /// closed once the peer closes it or writing to it fails,
+    /// which might never happen. Defaults to `None`.
+    pub stream_timeouts: Option<StreamTimeouts>,
+    /// Reports when the messages waiting to be written to
+    /// a peer's stream pile up and when they drain.
+    ///
+    /// See [`QueueWatermarks`]. The queue depths are reported by
+    /// [`Client::stream_stats`] either way. Defaults to `None`.
+    ///
+    /// [`Client::stream_stats`]: crate::Client::stream_stats
+    pub queue_watermarks: Option<QueueWatermarks>,
+    /// The number of sequence numbers below the highest one
+    /// received on a channel that may still arrive.
+    ///
+    /// Each message is accepted at most once. Within the
+    /// window, messages may arrive out of order, e.g., because
+    /// they were sent over UDP. Older messages are rejected as
+    /// replays. Zero requires strictly increasing sequence
+    /// numbers. Values above
+    /// [`MAX_REPLAY_WINDOW`][Self::MAX_REPLAY_WINDOW] are
+    /// lowered to it. Defaults to
+    /// [`DEFAULT_REPLAY_WINDOW`][Self::DEFAULT_REPLAY_WINDOW].
+    pub replay_window: u32,
+    /// Defaults for the channels with particular labels.
+    ///
+    /// They apply to every channel with the label, whether it
+    /// is created locally or accepted from a peer, so the
+    /// application does not have to configure each channel
+    /// where it is created. See [`LabelOptions`]. Defaults to
+    /// no options.
+    pub label_options: BTreeMap<Label, LabelOptions>,
+    /// Where to persist channel metadata so that channels
+    /// survive restarts of the client.
+    ///
+    /// Restored channels keep their keys, which the daemon
+    /// holds, and their replay protection (see
+    /// [`seq_reservation`][Self::seq_reservation]), so they
+    /// can be used without another control handshake. Channels
+    /// that the daemon removed in the meantime are reconciled
+    /// when the client connects.
+    ///
+    /// If `None`, channels are only kept in memory. Defaults to
+    /// `None`.
+    pub channel_state_path: Option<PathBuf>,
+    /// How far ahead of the highest sequence number received on
+    /// a channel to persist its replay protection.
+    ///
+    /// Writing the state for every message would be slow, so
+    /// the router instead persists a reservation before it
+    /// accepts a message above the previous one. After
+    /// a restart, every sequence number up to the reservation
+    /// is rejected as a replay, so up to this many messages
+    /// sent by the peer around the restart can be lost, but
+    /// none can be replayed. Values below one are raised to
+    /// one. Only used with
+    /// [`channel_state_path`][Self::channel_state_path].
+    /// Defaults to
+    /// [`DEFAULT_SEQ_RESERVATION`][Self::DEFAULT_SEQ_RESERVATION].
+    pub seq_reservation: u64,
+    /// Preallocates the router's buffers and queues so that
+    /// sending and receiving data does not allocate.
+    ///
+    /// See [`MemoryBounds`]. Defaults to `None`.
+    pub bounded: Option<MemoryBounds>,
+    /// Caps the number of channels that the router holds at
+    /// once.
+    ///
+    /// See [`ChannelLimits`]. Defaults to `None`.
+    pub channel_limits: Option<ChannelLimits>,
+    /// Turns anomalies that are otherwise logged and ignored
+    /// into errors, e.g., so that certification runs can assert
+    /// that none occur.
+    ///
+    /// In strict mode,
+    /// - a control message for a channel that already exists
+    ///   fails with [`AfcError::DuplicateChannel`] instead of
+    ///   being ignored, and
+    /// - a new stream with the same peer address as an
+    ///   existing one fails with [`AfcError::DuplicateStream`]
+    ///   instead of being closed in favor of the existing one.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`AfcError::DuplicateChannel`]: crate::AfcError::DuplicateChannel
+    /// [`AfcError::DuplicateStream`]: crate::AfcError::DuplicateStream
+    pub strict: bool,
+    /// Wraps the TCP streams used by AFC in TLS.
+    ///
+    /// AFC payloads are encrypted either way, but TLS also hides
+    /// the wire framing and control messages and authenticates
+    /// the peer's endpoint. Peers must use the same setting.
+    /// UDP datagrams are not wrapped.
+    ///
+    /// Defaults to `None`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Opens and accepts the router's streams over a custom
+    /// transport instead of TCP.
+    ///
+    /// The router listens wherever the transport does, so the
+    /// address that it is bound to and
+    /// [`additional_addrs`][Self::additional_addrs] are ignored.
+    /// Datagram channels and TLS are not supported. See
+    /// [`AfcTransport`].
+    ///
+    /// Defaults to `None`.
+    pub transport: Option<Arc<dyn AfcTransport>>,
+    /// Handles the incoming streams that are not AFC, so that
+    /// another protocol can share the router's port.
+    ///
+    /// Without it, such streams are closed as malformed. See
+    /// [`ForeignHandler`] for how they are told apart. Ignored
+    /// with a custom [`transport`][Self::transport] or a
+    /// simulated network.
+    ///
+    /// Defaults to `None`.
+    pub foreign_handler: Option<Arc<dyn ForeignHandler>>,
+    /// Uses a simulated network instead of the OS's sockets.
+    ///
+    /// The router binds its listen address on the simulated
+    /// network and can only reach peers on the same network.
+    /// [`fallback_ports`][Self::fallback_ports] is ignored and
+    /// TLS is not supported.
+    ///
+    /// Defaults to `None`.
+    #[cfg(feature = "sim")]
+    pub sim: Option<SimNet>,
+}
+
+impl RouterConfig {
+    /// The default for
+    /// [`max_pending_handshakes`][Self::max_pending_handshakes].
+    pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 64;
+    /// The default for
+    /// [`handshake_timeout`][Self::handshake_timeout].
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+    /// The default for [`max_streams`][Self::max_streams].
+    pub const DEFAULT_MAX_STREAMS: usize = 1024;
+    /// The default for
+    /// [`reconnect_backoff`][Self::reconnect_backoff].
+    pub const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+    /// The default for
+    /// [`max_reconnect_attempts`][Self::max_reconnect_attempts].
+    pub const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 8;
+    /// The default for
+    /// [`max_reconnect_backoff`][Self::max_reconnect_backoff].
+    pub const DEFAULT_MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+    /// The default for [`dns_ttl`][Self::dns_ttl].
+    pub const DEFAULT_DNS_TTL: Duration = Duration::from_secs(30);
+    /// The default for
+    /// [`dns_negative_ttl`][Self::dns_negative_ttl].
+    pub const DEFAULT_DNS_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+    /// The default for [`max_dns_entries`][Self::max_dns_entries].
+    pub const DEFAULT_MAX_DNS_ENTRIES: usize = 1024;
+    /// The default for
+    /// [`connect_attempt_delay`][Self::connect_attempt_delay].
+    pub const DEFAULT_CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+    /// The default for [`retry_queue_len`][Self::retry_queue_len].
+    pub const DEFAULT_RETRY_QUEUE_LEN: usize = 64;
+    /// The default for
+    /// [`removed_channel_grace`][Self::removed_channel_grace].
+    pub const DEFAULT_REMOVED_CHANNEL_GRACE: Duration = Duration::from_secs(5 * 60);
+    /// The default for
+    /// [`reconcile_interval`][Self::reconcile_interval].
+    pub const DEFAULT_RECONCILE_INTERVAL: Duration = Duration::from_secs(10);
+    /// The default for [`close_timeout`][Self::close_timeout].
+    pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_millis(100);
+    /// The default for [`yield_budget`][Self::yield_budget].
+    pub const DEFAULT_YIELD_BUDGET: u32 = 64;
+    /// The default for [`max_clock_skew`][Self::max_clock_skew].
+    pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+    /// The default for [`max_msg_size`][Self::max_msg_size].
+    pub const DEFAULT_MAX_MSG_SIZE: u32 = 10 * 1024 * 1024;
+    /// The smallest allowed
+    /// [`max_msg_size`][Self::max_msg_size].
+    ///
+    /// Control messages must always fit.
+    pub const MIN_MAX_MSG_SIZE: u32 = 4 * 1024;
+    /// The default for
+    /// [`max_stream_size`][Self::max_stream_size].
+    pub const DEFAULT_MAX_STREAM_SIZE: usize = 64 * 1024 * 1024;
+    /// The default for [`replay_window`][Self::replay_window].
+    pub const DEFAULT_REPLAY_WINDOW: u32 = 0;
+    /// The largest allowed
+    /// [`replay_window`][Self::replay_window].
+    pub const MAX_REPLAY_WINDOW: u32 = 4096;
+    /// The default for
+    /// [`seq_reservation`][Self::seq_reservation].
+    pub const DEFAULT_SEQ_RESERVATION: u64 = 1024;
+    /// The default for [`ctrl_rate_limit`][Self::ctrl_rate_limit]:
+    /// ten control messages per second.
+    pub const DEFAULT_CTRL_RATE_LIMIT: RateLimit =
+        RateLimit::new(NonZeroU32::MIN.saturating_add(9));
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            fallback_ports: Vec::new(),
+            additional_addrs: Vec::new(),
+            max_pending_handshakes: Self::DEFAULT_MAX_PENDING_HANDSHAKES,
+            handshake_timeout: Self::DEFAULT_HANDSHAKE_TIMEOUT,
+            max_streams: Self::DEFAULT_MAX_STREAMS,
+            reputation_path: None,
+            reconnect_backoff: Self::DEFAULT_RECONNECT_BACKOFF,
+            max_reconnect_attempts: Self::DEFAULT_MAX_RECONNECT_ATTEMPTS,
+            max_reconnect_backoff: Self::DEFAULT_MAX_RECONNECT_BACKOFF,
+            resolver: None,
+            dns_ttl: Self::DEFAULT_DNS_TTL,
+            dns_negative_ttl: Self::DEFAULT_DNS_NEGATIVE_TTL,
+            max_dns_entries: Self::DEFAULT_MAX_DNS_ENTRIES,
+            addr_preference: AddrPreference::default(),
+            connect_attempt_delay: Some(Self::DEFAULT_CONNECT_ATTEMPT_DELAY),
+            outbound: Outbound::default(),
+            reconnect_jitter: true,
+            session_resumption: None,
+            retry_queue_len: Self::DEFAULT_RETRY_QUEUE_LEN,
+            removed_channel: RemovedChannelPolicy::default(),
+            version_mismatch: VersionMismatchPolicy::default(),
+            removed_channel_grace: Self::DEFAULT_REMOVED_CHANNEL_GRACE,
+            reconcile_interval: Some(Self::DEFAULT_RECONCILE_INTERVAL),
+            close_timeout: Self::DEFAULT_CLOSE_TIMEOUT,
+            yield_budget: Self::DEFAULT_YIELD_BUDGET,
+            self_check: false,
+            send_timestamps: false,
+            max_clock_skew: Self::DEFAULT_MAX_CLOCK_SKEW,
+            max_msg_size: Self::DEFAULT_MAX_MSG_SIZE,
+            max_stream_size: Self::DEFAULT_MAX_STREAM_SIZE,
+            rate_limit: None,
+            ctrl_rate_limit: Some(Self::DEFAULT_CTRL_RATE_LIMIT),
+            keepalive: None,
+            flow_control: None,
+            expiry: None,
+            stream_timeouts: None,
+            queue_watermarks: None,
+            replay_window: Self::DEFAULT_REPLAY_WINDOW,
+            label_options: BTreeMap::new(),
+            channel_state_path: None,
+            seq_reservation: Self::DEFAULT_SEQ_RESERVATION,
+            bounded: None,
+            channel_limits: None,
+            strict: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            transport: None,
+            foreign_handler: None,
+            #[cfg(feature = "sim")]
+            sim: None,
+        }
+    }
+}
+
+/// Bounds for the router's bounded-memory mode.
+///
+/// When [`RouterConfig::bounded`] is set, everything that the
+/// router needs to send and receive data is allocated up front:
+///
+/// - `max_queued_msgs + 2` message buffers, each large enough
+///   for a [`max_msg_size`][RouterConfig::max_msg_size]
+///   message,
+/// - the buffers used to encode outgoing messages,
+/// - the queues of received messages and events,
+/// - and each channel's window of idempotency tokens.
+///
+/// Once a bound is reached, the router fails with
+/// [`AfcError::Capacity`] instead of allocating, and the
+/// number of channels is limited to the capacity of the shared
+/// memory. Since buffers are preallocated,
+/// [`max_msg_size`][RouterConfig::max_msg_size] should be no
+/// larger than needed.
+///
+/// Received messages borrow their buffers from the router.
+/// Return them with [`Client::recycle`] once they have been
+/// handled, or the router eventually runs out of buffers.
+///
+/// Resending data after a transient failure requires copying
+/// it, so it is disabled: transient failures are returned to
+/// the caller as if
+/// [`max_reconnect_attempts`][RouterConfig::max_reconnect_attempts]
+/// were zero.
+///
+/// Only sending and receiving data is allocation-free. Setting
+/// up channels and streams, control messages, and TLS still
+/// allocate. Streams sent with [`Client::send_stream`] cannot be
+/// reassembled without allocating, so receiving them fails with
+/// [`AfcError::Capacity`]. Batches sent with
+/// [`Client::send_data_batch`] are coalesced into a buffer that
+/// grows to fit the largest batch, and messages queued with
+/// [`Client::feed_data`] are copied until they are flushed.
+///
+/// [`Client::send_stream`]: crate::Client::send_stream
+/// [`Client::send_data_batch`]: crate::Client::send_data_batch
+/// [`Client::feed_data`]: crate::Client::feed_data
+///
+/// [`AfcError::Capacity`]: crate::AfcError::Capacity
+/// [`Client::recycle`]: crate::Client::recycle
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryBounds {
+    /// The maximum number of received messages waiting to be
+    /// read with [`Client::try_recv_data`].
+    ///
+    /// [`Client::try_recv_data`]: crate::Client::try_recv_data
+    pub max_queued_msgs: usize,
+    /// The maximum number of events waiting to be read with
+    /// [`Client::try_recv_event`].
+    ///
+    /// Events that do not fit are dropped.
+    ///
+    /// [`Client::try_recv_event`]: crate::Client::try_recv_event
+    pub max_queued_events: usize,
+}
+
+impl MemoryBounds {
+    /// The default for
+    /// [`max_queued_msgs`][Self::max_queued_msgs].
+    pub const DEFAULT_MAX_QUEUED_MSGS: usize = 64;
+    /// The default for
+    /// [`max_queued_events`][Self::max_queued_events].
+    pub const DEFAULT_MAX_QUEUED_EVENTS: usize = 64;
+}
+
+impl Default for MemoryBounds {
+    fn default() -> Self {
+        Self {
+            max_queued_msgs: Self::DEFAULT_MAX_QUEUED_MSGS,
+            max_queued_events: Self::DEFAULT_MAX_QUEUED_EVENTS,
+        }
+    }
+}
+
+/// Caps the number of channels that the router holds at once,
+/// e.g., on devices that churn through many short-lived
+/// channels.
+///
+/// Every channel takes memory in the router and a slot in the
+/// daemon's shared memory until it is deleted. Before
+/// a channel is created or accepted from a peer, the router
+/// checks the caps and applies [`at_capacity`][Self::at_capacity]
+/// if the new channel would exceed either of them.
+///
+/// Channels restored from [`RouterConfig::channel_state_path`]
+/// by older versions of the router did not record their team,
+/// so they only count towards
+/// [`max_chans`][Self::max_chans].
+#[derive(Clone, Debug, Default)]
+pub struct ChannelLimits {
+    /// The maximum number of channels. Defaults to `None`.
+    pub max_chans: Option<usize>,
+    /// The maximum number of channels per team. Defaults to
+    /// `None`.
+    pub max_chans_per_team: Option<usize>,
+    /// What happens when a new channel would exceed a cap.
+    /// Defaults to [`CapacityPolicy::Reject`].
+    pub at_capacity: CapacityPolicy,
+}
+
+/// A custom eviction policy. See [`CapacityPolicy::Evict`].
+type EvictFn = dyn Fn(&[ChannelSummary]) -> Option<AfcId> + Send + Sync;
+
+/// What happens when a new channel would exceed
+/// [`ChannelLimits`].
+///
+/// An evicted channel is deleted as if with
+/// [`Client::delete_channel`], reported as
+/// [`AfcEvent::ChannelEvicted`], and makes room for the new
+/// channel. Only channels that count towards the exceeded cap
+/// are candidates: the team's channels if
+/// [`ChannelLimits::max_chans_per_team`] was reached and every
+/// channel otherwise.
+///
+/// [`Client::delete_channel`]: crate::Client::delete_channel
+/// [`AfcEvent::ChannelEvicted`]: crate::AfcEvent::ChannelEvicted
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum CapacityPolicy {
+    /// The new channel is rejected with
+    /// [`AfcError::Capacity`][crate::AfcError::Capacity].
+    #[default]
+    Reject,
+    /// The least recently used channel that expired (see
+    /// [`ChannelExpiry`]) or ran out of sequence numbers is
+    /// evicted. If there is none, the new channel is rejected.
+    EvictExpired,
+    /// The function is called with the candidates, least
+    /// recently used first, and the channel that it returns is
+    /// evicted. If it returns `None` or a channel that is not
+    /// a candidate, the new channel is rejected.
+    ///
+    /// It runs on the router's task, so it should be fast.
+    Evict(Arc<EvictFn>),
+}
+
+impl CapacityPolicy {
+    /// Creates a [`CapacityPolicy::Evict`] policy that calls
+    /// `f`.
+    pub fn evict<F>(f: F) -> Self
+    where
+        F: Fn(&[ChannelSummary]) -> Option<AfcId> + Send + Sync + 'static,
+    {
+        Self::Evict(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for CapacityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reject => f.write_str("Reject"),
+            Self::EvictExpired => f.write_str("EvictExpired"),
+            Self::Evict(_) => f.write_str("Evict(..)"),
+        }
+    }
+}
+
+/// Defaults for the channels with a particular label.
+///
+/// See [`RouterConfig::label_options`]. Options that are not
+/// set fall back to the router's.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LabelOptions {
+    /// Overrides [`RouterConfig::replay_window`].
+    ///
+    /// The peer must use the same window for the label.
+    pub replay_window: Option<u32>,
+    /// Overrides [`RouterConfig::rate_limit`].
+    ///
+    /// It can still be changed for a channel with
+    /// [`Client::set_channel_rate_limit`].
+    ///
+    /// [`Client::set_channel_rate_limit`]: crate::Client::set_channel_rate_limit
+    pub rate_limit: Option<RateLimit>,
+    /// Overrides [`RouterConfig::keepalive`].
+    pub keepalive: Option<KeepAlive>,
+    /// Overrides [`RouterConfig::expiry`].
+    ///
+    /// It can still be changed for a channel with
+    /// [`Client::set_channel_expiry`].
+    ///
+    /// [`Client::set_channel_expiry`]: crate::Client::set_channel_expiry
+    pub expiry: Option<ChannelExpiry>,
+    /// The priority of the data sent with
+    /// [`Client::send_data`], [`Client::send_data_batch`], and
+    /// [`Client::send_stream`].
+    ///
+    /// [`SendOptions::priority`] overrides it.
+    /// Defaults to [`Priority::Normal`].
+    ///
+    /// [`Client::send_data`]: crate::Client::send_data
+    /// [`Client::send_data_batch`]: crate::Client::send_data_batch
+    /// [`Client::send_stream`]: crate::Client::send_stream
+    /// [`SendOptions::priority`]: crate::SendOptions::priority
+    pub priority: Priority,
+    /// How the messages received with the label are delivered.
+    ///
+    /// This is the initial schedule, as if it were set with
+    /// [`Client::set_label_schedule`].
+    ///
+    /// [`Client::set_label_schedule`]: crate::Client::set_label_schedule
+    pub schedule: Option<LabelSchedule>,
+}
+
+/// A token-bucket limit on the rate at which a channel sends
+/// and receives messages.
+///
+/// Sending and receiving are limited separately. Each message
+/// takes a token, and tokens are earned at
+/// [`msgs_per_sec`][Self::msgs_per_sec] up to
+/// [`burst`][Self::burst] tokens.
+///
+/// Sending data without a token waits for one, up to
+/// [`max_wait`][Self::max_wait]. Otherwise, and when receiving
+/// data without a token, the message is dropped with
+/// [`AfcError::RateLimited`]. This keeps a single chatty
+/// channel from starving the others.
+///
+/// Streams sent with [`Client::send_stream`] take a token per
+/// fragment.
+///
+/// [`AfcError::RateLimited`]: crate::AfcError::RateLimited
+/// [`Client::send_stream`]: crate::Client::send_stream
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// The sustained number of messages per second.
+    pub msgs_per_sec: NonZeroU32,
+    /// The number of messages that can be sent or received at
+    /// once. Values below one are raised to one.
+    pub burst: u32,
+    /// The longest that sending data waits for a token.
+    pub max_wait: Duration,
+}
+
+impl RateLimit {
+    /// The default for [`max_wait`][Self::max_wait].
+    pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(1);
+
+    /// Creates a limit of `msgs_per_sec` messages per second
+    /// that allows bursts of up to one second's worth of
+    /// messages.
+    pub const fn new(msgs_per_sec: NonZeroU32) -> Self {
+        Self {
+            msgs_per_sec,
+            burst: msgs_per_sec.get(),
+            max_wait: Self::DEFAULT_MAX_WAIT,
+        }
+    }
+}
+
+/// Keep-alive probing of a channel's peer.
+///
+/// When the router has not heard from a channel's peer for
+/// [`interval`][Self::interval], it sends the peer a ping over
+/// the channel's transport. A ping that is not answered within
+/// [`timeout`][Self::timeout] is missed and the channel becomes
+/// [`Liveness::Degraded`]. After
+/// [`max_missed`][Self::max_missed] consecutive missed pings,
+/// it becomes [`Liveness::Unreachable`]. Any answer or data
+/// from the peer makes the channel [`Liveness::Alive`] again.
+///
+/// Changes are reported as
+/// [`AfcEvent::LivenessChanged`][crate::AfcEvent::LivenessChanged]
+/// and the current state is part of the channel's
+/// [`ChannelStats`][crate::ChannelStats].
+///
+/// Pings are not encrypted, so an on-path attacker can keep an
+/// unreachable channel looking alive, but it cannot forge data.
+///
+/// [`Liveness::Degraded`]: crate::Liveness::Degraded
+/// [`Liveness::Unreachable`]: crate::Liveness::Unreachable
+/// [`Liveness::Alive`]: crate::Liveness::Alive
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeepAlive {
+    /// How long the peer can be silent before it is pinged.
+    pub interval: Duration,
+    /// How long to wait for a ping to be answered.
+    pub timeout: Duration,
+    /// The number of consecutive missed pings after which the
+    /// peer is unreachable. Values below one are raised to one.
+    pub max_missed: u32,
+}
+
+impl KeepAlive {
+    /// The default for [`interval`][Self::interval].
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+    /// The default for [`timeout`][Self::timeout].
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+    /// The default for [`max_missed`][Self::max_missed].
+    pub const DEFAULT_MAX_MISSED: u32 = 3;
+}
+
+/// Bounds how long and how much a channel's keys are used.
+///
+/// A channel expires once it reaches either limit. From then
+/// on, sending data over it fails with
+/// [`AfcError::ChannelExpired`] and the data that it receives
+/// is dropped. The expiry is reported once as
+/// [`AfcEvent::ExpiryReached`], after which a new channel must
+/// be created. An expired channel stays expired even if its
+/// policy is changed.
+///
+/// The age and message count are kept in memory, so they start
+/// over when a channel is restored from
+/// [`RouterConfig::channel_state_path`].
+///
+/// [`AfcError::ChannelExpired`]: crate::AfcError::ChannelExpired
+/// [`AfcEvent::ExpiryReached`]: crate::AfcEvent::ExpiryReached
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChannelExpiry {
+    /// How long after it is added to the router the channel
+    /// expires.
+    pub max_age: Option<Duration>,
+    /// The number of messages that can be sealed or opened with
+    /// the channel's keys, counted together.
+    pub max_msgs: Option<u64>,
+    /// Whether to delete the channel with the daemon once it
+    /// expires, as if with [`Client::delete_channel`], instead
+    /// of leaving that to the application.
+    ///
+    /// [`Client::delete_channel`]: crate::Client::delete_channel
+    pub delete: bool,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: Self::DEFAULT_INTERVAL,
+            timeout: Self::DEFAULT_TIMEOUT,
+            max_missed: Self::DEFAULT_MAX_MISSED,
+        }
+    }
+}
+
+/// Receiver-driven flow control of channels.
+///
+/// The receiver of each channel advertises a window to the
+/// sender: the number of messages that the sender can send
+/// before the receiver reads them with
+/// [`Client::try_recv_data`]. Once the window is exhausted,
+/// [`Client::send_data`] waits for the receiver to read some of
+/// them, up to [`max_wait`][Self::max_wait], and then fails with
+/// [`AfcError::WindowExhausted`]. Batches, streams, and data
+/// sent to every channel with a label fail right away instead
+/// of waiting. So a fast sender cannot
+/// overwhelm a slow reader, even if the channel shares a stream
+/// with others and TCP's backpressure cannot tell them apart.
+///
+/// Windows are only advertised by routers that enable flow
+/// control, but they are honored either way. Messages that are
+/// dropped instead of read (e.g., because they were rate
+/// limited) do not count against the window.
+///
+/// [`AfcError::WindowExhausted`]: crate::AfcError::WindowExhausted
+/// [`Client::send_data`]: crate::Client::send_data
+/// [`Client::try_recv_data`]: crate::Client::try_recv_data
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FlowControl {
+    /// The number of messages that the peer of each channel can
+    /// send before we read them. Values below one are raised to
+    /// one.
+    pub window: u32,
+    /// The longest that sending data waits for the peer to open
+    /// its window.
+    ///
+    /// Routers that do not enable flow control wait for
+    /// [`DEFAULT_MAX_WAIT`][Self::DEFAULT_MAX_WAIT].
+    pub max_wait: Duration,
+}
+
+impl FlowControl {
+    /// The default for [`window`][Self::window].
+    pub const DEFAULT_WINDOW: u32 = 64;
+    /// The default for [`max_wait`][Self::max_wait].
+    pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(5);
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self {
+            window: Self::DEFAULT_WINDOW,
+            max_wait: Self::DEFAULT_MAX_WAIT,
+        }
+    }
+}
+
+/// How the router opens streams to its peers.
+///
+/// By default, the OS picks the source address and interface of
+/// each stream from its routing table. On multi-homed hosts,
+/// these settings keep AFC traffic on a particular network.
+///
+/// They only apply to the streams that the router opens over
+/// the OS's sockets, not to streams that peers open, datagrams,
+/// or [custom transports][RouterConfig::transport]. If a setting
+/// cannot be applied, opening the stream fails.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Outbound {
+    /// The local IP address to open streams from.
+    ///
+    /// The OS picks the port. Peer addresses of the other IP
+    /// version cannot be reached, so they are skipped in favor
+    /// of the peer's other addresses.
+    pub local_ip: Option<IpAddr>,
+    /// The name of the network interface to open streams on
+    /// (`SO_BINDTODEVICE`).
+    ///
+    /// Only supported on Linux and Android, where it usually
+    /// requires `CAP_NET_RAW`.
+    pub interface: Option<String>,
+    /// The Differentiated Services Code Point (RFC 2474) to mark
+    /// the streams' packets with.
+    ///
+    /// Must be at most [`MAX_DSCP`][Self::MAX_DSCP]. Marking
+    /// IPv6 streams is only supported on Linux and Android.
+    pub dscp: Option<u8>,
+}
+
+impl Outbound {
+    /// The largest allowed [`dscp`][Self::dscp].
+    pub const MAX_DSCP: u8 = 63;
+}
+
+/// Detection of half-open streams.
+///
+/// A stream is half-open when its peer vanished without closing
+/// it. Writes to it keep succeeding until the OS's send buffer
+/// fills up, and reads never return, so the channels that use it
+/// stall.
+///
+/// The router detects this in two ways:
+///
+/// - The OS sends TCP keepalive probes once the stream has been
+///   idle for [`tcp_keepalive`][Self::tcp_keepalive] and closes
+///   it if they go unanswered.
+/// - When nothing has been received over a stream for
+///   [`idle_timeout`][Self::idle_timeout], the router pings the
+///   peer over it. If nothing is received within
+///   [`response_timeout`][Self::response_timeout] of the ping,
+///   the stream is closed. Writes to the stream, including TLS
+///   handshakes, that take longer than `response_timeout` close
+///   it as well.
+///
+/// Closed streams are reported as
+/// [`AfcEvent::PeerDisconnected`][crate::AfcEvent::PeerDisconnected].
+/// The channels that used them look up their peer again and
+/// open a new stream the next time data is sent over them.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StreamTimeouts {
+    /// How long a stream can be idle before the OS starts
+    /// sending TCP keepalive probes.
+    ///
+    /// If `None`, the OS's settings are left alone. Streams on
+    /// a simulated network do not use TCP keepalive.
+    pub tcp_keepalive: Option<Duration>,
+    /// How long to wait between TCP keepalive probes, where the
+    /// OS supports it.
+    pub tcp_keepalive_interval: Duration,
+    /// The number of unanswered TCP keepalive probes after
+    /// which the OS closes the stream, where the OS supports
+    /// it.
+    pub tcp_keepalive_retries: u32,
+    /// How long the peer can be silent before it is pinged over
+    /// the stream.
+    pub idle_timeout: Duration,
+    /// How long to wait for the peer to answer a ping or for
+    /// a write to complete.
+    pub response_timeout: Duration,
+}
+
+impl StreamTimeouts {
+    /// The default for [`tcp_keepalive`][Self::tcp_keepalive].
+    pub const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+    /// The default for
+    /// [`tcp_keepalive_interval`][Self::tcp_keepalive_interval].
+    pub const DEFAULT_TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+    /// The default for
+    /// [`tcp_keepalive_retries`][Self::tcp_keepalive_retries].
+    pub const DEFAULT_TCP_KEEPALIVE_RETRIES: u32 = 3;
+    /// The default for [`idle_timeout`][Self::idle_timeout].
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    /// The default for
+    /// [`response_timeout`][Self::response_timeout].
+    pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+}
+
+impl Default for StreamTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp_keepalive: Some(Self::DEFAULT_TCP_KEEPALIVE),
+            tcp_keepalive_interval: Self::DEFAULT_TCP_KEEPALIVE_INTERVAL,
+            tcp_keepalive_retries: Self::DEFAULT_TCP_KEEPALIVE_RETRIES,
+            idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+            response_timeout: Self::DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+/// Which of a peer's addresses the AFC router dials first.
+///
+/// Whatever the preference, the address families alternate
+/// after the first address (RFC 8305, section 4), so that an
+/// unreachable family is quickly skipped. See
+/// [`RouterConfig::connect_attempt_delay`].
+///
+/// Network identifiers that are already socket addresses are
+/// used as is.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum AddrPreference {
+    /// Start with the family of the first address returned by
+    /// the resolver (see [`RouterConfig::resolver`]).
+    #[default]
+    System,
+    /// Start with an IPv6 address.
+    PreferIpv6,
+    /// Start with an IPv4 address.
+    PreferIpv4,
+    /// Only dial IPv6 addresses.
+    Ipv6Only,
+    /// Only dial IPv4 addresses.
+    Ipv4Only,
+}
+
+/// What the AFC router does when the peer on a stream greets it
+/// with a wire version that it does not speak, e.g., because the
+/// peer was upgraded first.
+///
+/// The version the peer greeted us with is reported by
+/// [`Client::stream_stats`] regardless, which helps plan
+/// upgrades across a fleet.
+///
+/// [`Client::stream_stats`]: crate::Client::stream_stats
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum VersionMismatchPolicy {
+    /// Report [`AfcError::VersionMismatch`] and leave the stream
+    /// open.
+    ///
+    /// The mismatch counts against the peer's reputation.
+    ///
+    /// [`AfcError::VersionMismatch`]: crate::AfcError::VersionMismatch
+    #[default]
+    Error,
+    /// Tell the peer which versions we speak and keep the
+    /// stream open for `grace` so that it can greet us again
+    /// with one of them.
+    ///
+    /// If it does not, the stream is closed. The peer reports
+    /// the advertisement as
+    /// [`AfcEvent::VersionsAdvertised`][crate::AfcEvent::VersionsAdvertised].
+    Advertise {
+        /// How long the peer has to downgrade.
+        grace: Duration,
+    },
+    /// Tell the peer which versions we speak, then close the
+    /// stream.
+    ///
+    /// The peer reports this as
+    /// [`AfcEvent::Goodbye`][crate::AfcEvent::Goodbye] with
+    /// [`GoodbyeReason::UnsupportedVersion`][crate::GoodbyeReason::UnsupportedVersion].
+    Goodbye,
+}
+
+/// Thresholds for reporting congested streams.
+///
+/// Messages that could not be written to a peer's stream, e.g.,
+/// because it was dropped, wait to be resent (see
+/// [`RouterConfig::max_reconnect_attempts`]). A stream's queue
+/// depth is the number of such messages across every channel
+/// that uses it.
+///
+/// Once the depth reaches [`high`][Self::high], the stream is
+/// reported as
+/// [`AfcEvent::StreamCongested`][crate::AfcEvent::StreamCongested].
+/// Once it falls to [`low`][Self::low] or below, including
+/// because the queued messages were dropped, it is reported as
+/// [`AfcEvent::StreamDrained`][crate::AfcEvent::StreamDrained].
+/// The gap between the two keeps a stream hovering around one
+/// threshold from flapping.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QueueWatermarks {
+    /// The queue depth at which a stream is congested. Values
+    /// below one are raised to one.
+    pub high: usize,
+    /// The queue depth at which a congested stream is drained.
+    /// Values at or above [`high`][Self::high] are lowered to
+    /// `high - 1`.
+    pub low: usize,
+}
+
+impl QueueWatermarks {
+    /// The default for [`high`][Self::high].
+    pub const DEFAULT_HIGH: usize = 32;
+    /// The default for [`low`][Self::low].
+    pub const DEFAULT_LOW: usize = 8;
+}
+
+impl Default for QueueWatermarks {
+    fn default() -> Self {
+        Self {
+            high: Self::DEFAULT_HIGH,
+            low: Self::DEFAULT_LOW,
+        }
+    }
+}
+
+/// What the AFC router does with data received for a channel
+/// that was recently removed.
+///
+/// A peer may keep sending data until it learns that the
+/// channel was removed, so treating this data as an error is
+/// usually too noisy.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RemovedChannelPolicy {
+    /// Report [`AfcError::ChannelNotFound`].
+    ///
+    /// [`AfcError::ChannelNotFound`]: crate::AfcError::ChannelNotFound
+    Error,
+    /// Silently drop the data.
+    Drop,
+    /// Drop the data and tell the peer, once, that the channel
+    /// was removed.
+    ///
+    /// The peer reports this as
+    /// [`AfcEvent::ChannelRemovedByPeer`][crate::AfcEvent::ChannelRemovedByPeer].
+    #[default]
+    DropAndNotify,
+}
@@ -0,0 +1,121 @@
+//! Supervision of the client's connection to the daemon.
+//!
+//! If the daemon restarts (e.g., to be upgraded), the client's
+//! connection to it breaks and every call to it fails. The
+//! client notices while it is polled and reconnects to the same
+//! socket, backing off between attempts.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aranya_daemon_api::DaemonApiClient;
+use tarpc::tokio_serde::formats::Json;
+use tokio::{
+    sync::watch,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::{Error, Result};
+
+/// How long to wait before the first attempt to reconnect.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest to wait between attempts to reconnect.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The state of a [`Client`][crate::Client]'s connection to the
+/// daemon.
+///
+/// See [`Client::daemon_status`][crate::Client::daemon_status].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum DaemonStatus {
+    /// The client is connected to the daemon.
+    Connected,
+    /// The connection broke. The client reconnects while it is
+    /// polled.
+    Disconnected {
+        /// The number of failed attempts to reconnect.
+        attempts: u32,
+    },
+}
+
+/// A connection to the daemon.
+#[derive(Clone, Debug)]
+pub(crate) struct Link {
+    /// The daemon's socket.
+    sock: PathBuf,
+    /// Becomes `false` when the connection breaks.
+    up: watch::Receiver<bool>,
+    /// The number of failed attempts to reconnect.
+    attempts: u32,
+    /// When to try to reconnect next.
+    retry_at: Option<Instant>,
+}
+
+impl Link {
+    /// Connects to the daemon at `sock`.
+    pub async fn connect(sock: &Path) -> Result<(DaemonApiClient, Self)> {
+        let transport = tarpc::serde_transport::unix::connect(sock, Json::default)
+            .await
+            .map_err(Error::Connecting)?;
+        let new = DaemonApiClient::new(tarpc::client::Config::default(), transport);
+        let (tx, up) = watch::channel(true);
+        let dispatch = new.dispatch;
+        tokio::spawn(async move {
+            if let Err(err) = dispatch.await {
+                warn!(%err, "connection to daemon failed");
+            }
+            debug!("disconnected from daemon");
+            // Every receiver might be gone.
+            let _ = tx.send(false);
+        });
+        let link = Self {
+            sock: sock.to_owned(),
+            up,
+            attempts: 0,
+            retry_at: None,
+        };
+        Ok((new.client, link))
+    }
+
+    /// Returns the daemon's socket.
+    pub fn sock(&self) -> &Path {
+        &self.sock
+    }
+
+    /// Returns the state of the connection.
+    pub fn status(&self) -> DaemonStatus {
+        if *self.up.borrow() {
+            DaemonStatus::Connected
+        } else {
+            DaemonStatus::Disconnected {
+                attempts: self.attempts,
+            }
+        }
+    }
+
+    /// Resolves once the connection is broken and it is time to
+    /// try to reconnect.
+    ///
+    /// This is cancel safe.
+    pub async fn lost(&mut self) {
+        // The sender is only dropped after the connection broke.
+        let _ = self.up.wait_for(|up| !*up).await;
+        if let Some(at) = self.retry_at {
+            sleep_until(at).await;
+        }
+    }
+
+    /// Records a failed attempt to reconnect.
+    pub fn failed(&mut self) {
+        let backoff = MIN_BACKOFF
+            .saturating_mul(1u32.checked_shl(self.attempts).unwrap_or(u32::MAX))
+            .min(MAX_BACKOFF);
+        self.attempts = self.attempts.saturating_add(1);
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+}
@@ -1,3 +1,8 @@
+use core::fmt;
+use std::net::SocketAddr;
+
+use aranya_daemon_api::{AfcId, NetIdentifier, TeamId};
+
 /// Errors that could occur in the Aranya client.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -20,6 +25,189 @@ pub enum Error {
     /// Could not send request to daemon.
     #[error("could not send request to daemon: {0}")]
     Rpc(#[from] tarpc::client::RpcError),
+
+    /// An error returned by one of the client's methods, with
+    /// what the method was doing.
+    ///
+    /// Every error that the client's methods return has this
+    /// context. Methods that only call another method (e.g.,
+    /// [`Client::poll`][crate::Client::poll]) report that
+    /// method's context. Use [`inner`][Self::inner] to match on
+    /// the error itself.
+    #[error("{context}: {inner}")]
+    Context {
+        /// What the method was doing.
+        context: Box<ErrorContext>,
+        /// The error.
+        #[source]
+        inner: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Returns the error without its context.
+    pub fn inner(&self) -> &Self {
+        let mut err = self;
+        while let Self::Context { inner, .. } = err {
+            err = &**inner;
+        }
+        err
+    }
+
+    /// Like [`inner`][Self::inner], but takes ownership of the
+    /// error.
+    pub fn into_inner(self) -> Self {
+        let mut err = self;
+        while let Self::Context { inner, .. } = err {
+            err = *inner;
+        }
+        err
+    }
+
+    /// Returns the outermost context of the error, if any.
+    ///
+    /// See [`contexts`][Self::contexts] for the rest.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.contexts().next()
+    }
+
+    /// Returns the contexts of the error, from the outermost to
+    /// the innermost.
+    ///
+    /// There is more than one when a method failed because
+    /// another method that it called did, e.g., when
+    /// [`Client::send_data`][crate::Client::send_data] polls the
+    /// router while it waits for the peer's flow control window
+    /// and polling fails.
+    pub fn contexts(&self) -> impl Iterator<Item = &ErrorContext> {
+        let mut err = self;
+        core::iter::from_fn(move || match err {
+            Self::Context { context, inner } => {
+                err = &**inner;
+                Some(&**context)
+            }
+            _ => None,
+        })
+    }
+
+    /// Adds `context` to the error.
+    pub(crate) fn with_context(self, context: ErrorContext) -> Self {
+        Self::Context {
+            context: Box::new(context),
+            inner: Box::new(self),
+        }
+    }
 }
 
 pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// Adds an [`ErrorContext`] to the errors that cross the public
+/// API.
+pub(crate) trait ResultExt<T> {
+    /// Adds the context returned by `f` to the error, if any.
+    fn context<F>(self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> ErrorContext;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: Into<Error>,
+{
+    fn context<F>(self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> ErrorContext,
+    {
+        self.map_err(|err| err.into().with_context(f()))
+    }
+}
+
+/// Flattens the result of a call to the daemon.
+pub(crate) fn rpc<T>(
+    result: Result<Result<T, aranya_daemon_api::Error>, tarpc::client::RpcError>,
+) -> Result<T> {
+    Ok(result??)
+}
+
+/// What a method of the client was doing when it failed.
+///
+/// See [`Error::Context`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ErrorContext {
+    /// The name of the method, e.g., `"send_data"`.
+    pub operation: &'static str,
+    /// The AFC channel that the method used, if any.
+    pub afc_id: Option<AfcId>,
+    /// The team that the method used, if any.
+    pub team_id: Option<TeamId>,
+    /// The network identifier of the peer that the method
+    /// communicated with, if any.
+    pub peer: Option<NetIdentifier>,
+    /// The address of the peer's stream, if one was open.
+    pub addr: Option<SocketAddr>,
+}
+
+impl ErrorContext {
+    /// Creates the context of `operation`.
+    pub(crate) fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            ..Default::default()
+        }
+    }
+
+    /// Sets [`team_id`][Self::team_id].
+    pub(crate) fn team(mut self, team_id: TeamId) -> Self {
+        self.team_id = Some(team_id);
+        self
+    }
+
+    /// Sets [`peer`][Self::peer].
+    pub(crate) fn peer(mut self, peer: NetIdentifier) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.operation)?;
+        let mut sep = " (";
+        let mut field = |f: &mut fmt::Formatter<'_>, name: &str, value: &dyn fmt::Display| {
+            let result = write!(f, "{sep}{name}: {value}");
+            sep = ", ";
+            result
+        };
+        if let Some(id) = &self.afc_id {
+            field(f, "afc_id", id)?;
+        }
+        if let Some(id) = &self.team_id {
+            field(f, "team_id", id)?;
+        }
+        if let Some(peer) = &self.peer {
+            field(f, "peer", peer)?;
+        }
+        if let Some(addr) = &self.addr {
+            field(f, "addr", addr)?;
+        }
+        if sep == ", " {
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors returned by
+/// [`Client::try_send_data`][crate::Client::try_send_data].
+#[derive(Debug, thiserror::Error)]
+pub enum TrySendError {
+    /// The data could not be sent without waiting, so it was
+    /// not sent.
+    #[error("sending would block")]
+    WouldBlock,
+
+    /// Sending the data failed.
+    #[error(transparent)]
+    Other(#[from] Error),
+}
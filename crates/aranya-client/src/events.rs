@@ -0,0 +1,55 @@
+//! AFC events as a [`Stream`].
+//!
+//! [`Client::afc_events`] drives the AFC router, so applications
+//! that only need to react to events do not have to own
+//! a select loop around [`Client::poll_data`] and
+//! [`Client::handle_data`].
+//!
+//! [`Client::watch_channels`] does not drive the router, so it
+//! can be handed to another task.
+
+use futures_util::stream::{self, Stream};
+
+use crate::{AfcEvent, ChannelSetDelta, Client, Result};
+
+impl Client {
+    /// Returns a stream of AFC events.
+    ///
+    /// The stream drives the router: it polls for data, handles
+    /// it, and yields [`AfcEvent`]s as they occur. Received
+    /// messages are yielded as [`AfcEvent::Data`] instead of
+    /// being queued for [`Client::try_recv_data`].
+    ///
+    /// The stream never ends. Errors are yielded and do not
+    /// stop the stream.
+    ///
+    /// # Cancellation Safety
+    ///
+    /// It is NOT safe to drop the stream while it is being
+    /// polled. Doing so might lose data.
+    pub fn afc_events(&mut self) -> impl Stream<Item = Result<AfcEvent>> + '_ {
+        stream::unfold(self, |client| async move {
+            let event = client.next_event().await;
+            Some((event, client))
+        })
+    }
+
+    /// Returns a stream of changes to the set of AFC channels.
+    ///
+    /// The stream first yields [`ChannelSetDelta::Added`] for
+    /// each open channel, then yields changes as they happen,
+    /// whether they were made locally or by a peer. Mirroring
+    /// the stream reproduces [`Client::list_channels`] without
+    /// polling it.
+    ///
+    /// The stream does not drive the router: changes are only
+    /// made while the client is used, e.g., by
+    /// [`Client::poll`]. It ends when the client is dropped.
+    ///
+    /// Changes are buffered until the stream is polled, so
+    /// a stream that is never polled grows without bound.
+    pub fn watch_channels(&mut self) -> impl Stream<Item = ChannelSetDelta> + Send + 'static {
+        let mut rx = self.subscribe_channels();
+        stream::poll_fn(move |cx| rx.poll_recv(cx))
+    }
+}
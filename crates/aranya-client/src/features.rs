@@ -0,0 +1,41 @@
+//! Optional capabilities of the client.
+//!
+//! The client's optional capabilities are enabled with Cargo
+//! features. [`features`] reports which ones this build has, so
+//! that applications and support tooling can adapt at runtime
+//! instead of mirroring the client's `cfg`s.
+
+/// The optional capabilities of a build of the client.
+///
+/// See [`features`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Features {
+    /// AFC streams can be wrapped in TLS (the `tls` feature).
+    pub tls: bool,
+    /// Router metrics are recorded (the `metrics` feature).
+    ///
+    /// See [`metrics`][crate::metrics].
+    pub metrics: bool,
+    /// The network used by AFC can be simulated (the `sim`
+    /// feature).
+    pub sim: bool,
+    /// The AFC router is exposed as a `tower::Service` (the
+    /// `tower` feature).
+    pub tower: bool,
+    /// AFC events are exposed as a `Stream` (the `stream`
+    /// feature).
+    pub stream: bool,
+}
+
+/// Returns the optional capabilities of this build of the
+/// client.
+pub const fn features() -> Features {
+    Features {
+        tls: cfg!(feature = "tls"),
+        metrics: cfg!(feature = "metrics"),
+        sim: cfg!(feature = "sim"),
+        tower: cfg!(feature = "tower"),
+        stream: cfg!(feature = "stream"),
+    }
+}
@@ -0,0 +1,59 @@
+//! Sharing the AFC port with other protocols.
+//!
+//! Devices that only get one open port can serve another
+//! protocol on the AFC router's port by setting
+//! [`RouterConfig::foreign_handler`][crate::RouterConfig::foreign_handler].
+
+use std::{fmt, net::SocketAddr};
+
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsStream;
+
+/// An incoming stream that is not AFC.
+///
+/// See [`ForeignHandler`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ForeignConn {
+    /// A plaintext stream. Nothing was read from it.
+    Tcp(TcpStream),
+    /// A TLS stream whose peer negotiated another ALPN protocol.
+    /// Nothing was read from it after the handshake.
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Handles the incoming streams on the AFC router's port that
+/// are not AFC.
+///
+/// The router tells AFC streams from others by how they start:
+///
+/// - Without TLS, every AFC stream starts with AFC's magic
+///   (`"AFC\0"`). The router peeks at the first bytes of each
+///   incoming stream and hands streams that start with anything
+///   else to the handler. Streams that send nothing for a second
+///   are assumed to be AFC, so protocols in which the server
+///   speaks first cannot share the port.
+/// - With TLS (see [`RouterConfig::tls`][crate::RouterConfig::tls]),
+///   the router tells them apart after the handshake by the
+///   negotiated ALPN protocol. Streams that negotiated a
+///   protocol other than [`AFC_ALPN`][crate::AFC_ALPN] are
+///   handed to the handler. The server configuration must list
+///   the other protocols, and their clients must offer them.
+///
+/// Streams are told apart off the router's task, but they count
+/// against
+/// [`RouterConfig::max_pending_handshakes`][crate::RouterConfig::max_pending_handshakes]
+/// meanwhile, and the router sheds streams of every protocol
+/// when it is overloaded.
+///
+/// See [`RouterConfig::foreign_handler`][crate::RouterConfig::foreign_handler].
+pub trait ForeignHandler: fmt::Debug + Send + Sync + 'static {
+    /// Takes over `conn`, which the peer at `addr` opened.
+    ///
+    /// This is called on the router's task, so it should hand
+    /// the stream off (e.g., to a spawned task or a channel)
+    /// instead of serving it.
+    fn handle(&self, conn: ForeignConn, addr: SocketAddr);
+}
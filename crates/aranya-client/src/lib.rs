@@ -10,13 +10,72 @@
 //!
 //! [README]: https://github.com/aranya-project/aranya/tree/main/crates/aranya-client/README.md
 //! [walkthrough]: https://github.com/aranya-project/aranya/tree/main/docs/walkthrough.md
+//!
+//! # Platform support
+//!
+//! The client requires a unix target. It connects to the daemon
+//! over a Unix domain socket and reads AFC channel keys from the
+//! daemon's POSIX shared memory, whose layout is defined by
+//! `aranya-fast-channels`. Supporting other targets (e.g.,
+//! Windows named shared memory) requires a shared memory
+//! backend in `aranya-fast-channels` that the daemon and client
+//! both use.
 
 mod afc;
 mod client;
+mod config;
+mod daemon;
 mod error;
+#[cfg(feature = "stream")]
+mod events;
+mod features;
+mod foreign;
+pub mod metrics;
+mod options;
+mod reputation;
+mod resolve;
+mod rpc;
+mod schedule;
+#[cfg(feature = "tower")]
+mod service;
+#[cfg(feature = "sim")]
+pub mod sim;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+mod validate;
+
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
 
+#[cfg(feature = "tower")]
+pub use crate::service::{AfcRequest, AfcResponse, AfcService};
+#[cfg(feature = "tls")]
+pub use crate::tls::{TlsConfig, AFC_ALPN};
 pub use crate::{
-    afc::AfcError,
-    client::{AfcId, AfcMsg, Client, Label, PollData, Seq, Team},
-    error::{Error, Result},
+    afc::{
+        AfcError, ChannelMetadata, ChannelStats, ChannelSummary, DisconnectReason, DnsStats,
+        ExpiryReason, GoodbyeReason, IdempotencyToken, Limits, Liveness, Priority, ResolveDebug,
+        RouterStats, StreamStats, Transport, IDEMPOTENCY_WINDOW, SUPPORTED_VERSIONS,
+    },
+    client::{
+        AfcEvent, AfcId, AfcMsg, ChannelSetDelta, Client, Label, PollData, RemovalReason, Seq,
+        Team, Version,
+    },
+    config::{
+        AddrPreference, CapacityPolicy, ChannelExpiry, ChannelLimits, FlowControl, KeepAlive,
+        LabelOptions, MemoryBounds, Outbound, QueueWatermarks, RateLimit, RemovedChannelPolicy,
+        RouterConfig, StreamTimeouts, VersionMismatchPolicy,
+    },
+    daemon::DaemonStatus,
+    error::{Error, ErrorContext, Result, TrySendError},
+    features::{features, Features},
+    foreign::{ForeignConn, ForeignHandler},
+    options::{ChannelOptions, ListenOptions, SendOptions},
+    reputation::PeerReputation,
+    resolve::{ResolveFuture, Resolver, StaticResolver, SystemResolver},
+    rpc::{Request, RequestId, Response},
+    schedule::LabelSchedule,
+    transport::{AfcConn, AfcTransport, ConnectFuture},
+    validate::{PayloadRejection, PayloadValidator},
 };
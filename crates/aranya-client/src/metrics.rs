@@ -0,0 +1,152 @@
+//! AFC router metrics.
+//!
+//! With the `metrics` feature, the router records the metrics
+//! below through the [`metrics`](https://docs.rs/metrics) facade.
+//! Install a recorder (e.g., `metrics-exporter-prometheus`) to
+//! collect them. Without the feature, nothing is recorded and
+//! the router does not depend on `metrics`.
+//!
+//! The gauges are updated whenever the router polls, so they
+//! only make sense for one [`Client`][crate::Client] per
+//! process.
+
+/// A counter of the data messages sent.
+pub const MSGS_SENT: &str = "aranya_afc_msgs_sent_total";
+
+/// A counter of the data messages received.
+pub const MSGS_RECEIVED: &str = "aranya_afc_msgs_received_total";
+
+/// A counter of the data messages that were dropped, labeled by
+/// `reason`:
+///
+/// - `removed_channel`: received for a recently removed channel.
+/// - `datagram`: a malformed, stale, or unknown datagram.
+/// - `replayed`: a replayed message.
+/// - `rate_limited`: received over the channel's rate limit.
+/// - `send_failed`: queued to be resent, but resending failed.
+/// - `label_queue_full`: received while too many messages with
+///   the same label were waiting to be read.
+/// - `unexpected_response`: a response that no request was
+///   waiting for.
+pub const MSGS_DROPPED: &str = "aranya_afc_msgs_dropped_total";
+
+/// A histogram of the plaintext sizes in bytes of data messages,
+/// labeled by `direction` (`sent` or `received`).
+pub const MSG_SIZE: &str = "aranya_afc_msg_size_bytes";
+
+/// A histogram of how long it took to seal data messages, in
+/// seconds.
+pub const SEAL_LATENCY: &str = "aranya_afc_seal_seconds";
+
+/// A histogram of how long it took to open data messages, in
+/// seconds.
+pub const OPEN_LATENCY: &str = "aranya_afc_open_seconds";
+
+/// A histogram of the one-way latencies of received data
+/// messages that carry timestamps, in seconds.
+///
+/// See [`RouterConfig::send_timestamps`][crate::RouterConfig::send_timestamps].
+pub const MSG_LATENCY: &str = "aranya_afc_msg_latency_seconds";
+
+/// A histogram of the round-trip times of answered keep-alive
+/// pings, in seconds.
+pub const PING_RTT: &str = "aranya_afc_ping_rtt_seconds";
+
+/// A histogram of how long DNS lookups took, in seconds,
+/// labeled by `result` (`ok` or `error`).
+///
+/// Cache hits are not recorded.
+pub const DNS_LATENCY: &str = "aranya_afc_dns_lookup_seconds";
+
+/// A gauge of the router's open channels.
+pub const CHANNELS: &str = "aranya_afc_channels";
+
+/// A gauge of the router's open streams.
+pub const STREAMS: &str = "aranya_afc_streams";
+
+/// Converts `n` for a gauge or histogram, saturating.
+#[cfg(feature = "metrics")]
+fn to_f64(n: usize) -> f64 {
+    f64::from(u32::try_from(n).unwrap_or(u32::MAX))
+}
+
+/// Records that a data message with `len` bytes of plaintext
+/// was sent.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn msg_sent(len: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(MSGS_SENT).increment(1);
+        ::metrics::histogram!(MSG_SIZE, "direction" => "sent").record(to_f64(len));
+    }
+}
+
+/// Records that a data message with `len` bytes of plaintext
+/// was received.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn msg_received(len: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(MSGS_RECEIVED).increment(1);
+        ::metrics::histogram!(MSG_SIZE, "direction" => "received").record(to_f64(len));
+    }
+}
+
+/// Records that `n` data messages were dropped.
+///
+/// See [`MSGS_DROPPED`] for the reasons.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn msgs_dropped(reason: &'static str, n: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(MSGS_DROPPED, "reason" => reason)
+        .increment(u64::try_from(n).unwrap_or(u64::MAX));
+}
+
+/// Records a DNS lookup that took `latency`.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn dns_lookup(latency: std::time::Duration, ok: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if ok { "ok" } else { "error" };
+        ::metrics::histogram!(DNS_LATENCY, "result" => result).record(latency);
+    }
+}
+
+/// Records the one-way latency of a received data message.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn msg_latency(latency: std::time::Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(MSG_LATENCY).record(latency);
+}
+
+/// Records the round-trip time of an answered keep-alive ping.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn rtt(rtt: std::time::Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!(PING_RTT).record(rtt);
+}
+
+/// Records the number of open channels and streams.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn router(chans: usize, streams: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::gauge!(CHANNELS).set(to_f64(chans));
+        ::metrics::gauge!(STREAMS).set(to_f64(streams));
+    }
+}
+
+/// Runs `f`, recording how long it took in the `name`
+/// histogram.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn timed<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "metrics")]
+    {
+        let start = std::time::Instant::now();
+        let v = f();
+        ::metrics::histogram!(name).record(start.elapsed());
+        v
+    }
+    #[cfg(not(feature = "metrics"))]
+    f()
+}
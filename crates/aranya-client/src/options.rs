@@ -0,0 +1,132 @@
+//! Options for the client's methods.
+//!
+//! Methods that take more than their required arguments take
+//! an options struct instead of more parameters. The structs are
+//! `#[non_exhaustive]` so that options can be added without
+//! breaking callers: start from [`Default`] and set options with
+//! the `with_*` methods, e.g.,
+//! `SendOptions::default().with_priority(Priority::High)`.
+
+use std::net::SocketAddr;
+
+use crate::{ChannelExpiry, IdempotencyToken, Priority, RateLimit, RouterConfig, Transport};
+
+/// Options for sending a message.
+///
+/// See [`Client::send_data_with_options`][crate::Client::send_data_with_options].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct SendOptions {
+    /// The message's priority.
+    ///
+    /// High-priority messages do not wait behind queued
+    /// normal-priority messages, e.g., while a dropped stream
+    /// is reconnected. See [`Priority`] for when messages are
+    /// reordered.
+    ///
+    /// Defaults to `None`, which uses the priority of the
+    /// channel's label (see
+    /// [`LabelOptions::priority`][crate::LabelOptions::priority]).
+    pub priority: Option<Priority>,
+    /// Tags the message with an idempotency token.
+    ///
+    /// If the peer recently received a message with the same
+    /// token on the channel, it reports an
+    /// [`AfcEvent::Duplicate`][crate::AfcEvent::Duplicate]
+    /// instead of delivering the message again. This makes it
+    /// safe to retry a send after an ambiguous failure. The
+    /// token is encrypted along with the data.
+    ///
+    /// Defaults to `None`.
+    pub token: Option<IdempotencyToken>,
+}
+
+impl SendOptions {
+    /// Sets [`priority`][Self::priority].
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Sets [`token`][Self::token].
+    pub fn with_token(mut self, token: IdempotencyToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+}
+
+/// Options for creating a channel.
+///
+/// See [`Client::create_bidi_channel_with_options`][crate::Client::create_bidi_channel_with_options].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ChannelOptions {
+    /// The transport that both peers send data over.
+    ///
+    /// Defaults to [`Transport::Tcp`].
+    pub transport: Transport,
+    /// The channel's rate limit, as if it were set with
+    /// [`Client::set_channel_rate_limit`][crate::Client::set_channel_rate_limit].
+    ///
+    /// Defaults to `None`, which uses the label's or the
+    /// router's.
+    pub rate_limit: Option<RateLimit>,
+    /// The channel's expiry policy, as if it were set with
+    /// [`Client::set_channel_expiry`][crate::Client::set_channel_expiry].
+    ///
+    /// Defaults to `None`, which uses the label's or the
+    /// router's.
+    pub expiry: Option<ChannelExpiry>,
+}
+
+impl ChannelOptions {
+    /// Sets [`transport`][Self::transport].
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Sets [`rate_limit`][Self::rate_limit].
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Sets [`expiry`][Self::expiry].
+    pub fn with_expiry(mut self, expiry: ChannelExpiry) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+}
+
+/// Options for an AFC router that handles a team's channels.
+///
+/// See [`Client::isolate_team`][crate::Client::isolate_team].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ListenOptions {
+    /// The address that the router listens on.
+    ///
+    /// Defaults to `None`, which does not listen: the router
+    /// only reaches peers over streams that it opens and cannot
+    /// send data over UDP.
+    pub addr: Option<SocketAddr>,
+    /// Configures the router.
+    ///
+    /// Defaults to [`RouterConfig::default`].
+    pub config: RouterConfig,
+}
+
+impl ListenOptions {
+    /// Sets [`addr`][Self::addr].
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Sets [`config`][Self::config].
+    pub fn with_config(mut self, config: RouterConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
@@ -0,0 +1,355 @@
+//! Peer reputation.
+//!
+//! The AFC router keeps track of how reliable each peer has been
+//! in the past. Peers that often fail to connect, fail
+//! handshakes, or violate the wire protocol are considered
+//! suspicious: we wait longer before reconnecting to them and
+//! accept fewer streams from them.
+//!
+//! Peers are identified by IP address since an incoming stream
+//! must be filtered before the peer identifies itself.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// The largest exponent used when computing the reconnect
+/// backoff.
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+/// How long changed reputations wait to be written to disk, so
+/// that bursts of changes are written at once.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// The historical reliability of a peer.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeerReputation {
+    /// The number of successful outbound connections.
+    pub connect_successes: u64,
+    /// The number of failed outbound connections.
+    pub connect_failures: u64,
+    /// The number of failed handshakes (e.g., a version
+    /// mismatch).
+    pub handshake_failures: u64,
+    /// The number of malformed messages received from the
+    /// peer.
+    pub protocol_violations: u64,
+}
+
+impl PeerReputation {
+    /// The penalty at which a peer becomes suspicious.
+    pub const SUSPICIOUS_PENALTY: u64 = 8;
+
+    /// Returns the peer's penalty.
+    ///
+    /// Handshake failures and protocol violations weigh more
+    /// than connection failures since they cannot be explained
+    /// by an unreliable network. Successful connections offset
+    /// connection failures.
+    pub fn penalty(&self) -> u64 {
+        self.connect_failures
+            .saturating_sub(self.connect_successes)
+            .saturating_add(self.handshake_failures.saturating_mul(2))
+            .saturating_add(self.protocol_violations.saturating_mul(4))
+    }
+
+    /// Reports whether the peer is suspicious.
+    pub fn is_suspicious(&self) -> bool {
+        self.penalty() >= Self::SUSPICIOUS_PENALTY
+    }
+
+    /// Returns how long to wait before reconnecting to the peer
+    /// after a connection failure.
+    ///
+    /// The backoff doubles with each unit of penalty, up to
+    /// `base * 2^6`. Suspicious peers wait four times as long.
+    pub fn reconnect_backoff(&self, base: Duration) -> Duration {
+        let shift = u32::try_from(self.penalty())
+            .unwrap_or(u32::MAX)
+            .min(MAX_BACKOFF_SHIFT);
+        let backoff = base.saturating_mul(1 << shift);
+        if self.is_suspicious() {
+            backoff.saturating_mul(4)
+        } else {
+            backoff
+        }
+    }
+}
+
+/// A peer's reputation along with transient state.
+#[derive(Clone, Debug, Default)]
+struct Entry {
+    rep: PeerReputation,
+    /// Do not reconnect to the peer before this time.
+    retry_at: Option<Instant>,
+}
+
+/// Peer reputations, optionally persisted to disk.
+#[derive(Debug)]
+pub(crate) struct Reputations {
+    /// Where reputations are persisted, if at all.
+    path: Option<PathBuf>,
+    /// The base reconnect backoff.
+    backoff: Duration,
+    peers: BTreeMap<IpAddr, Entry>,
+    /// When the reputations first changed since they were last
+    /// written to disk, if they did.
+    dirty_since: Option<Instant>,
+}
+
+impl Reputations {
+    /// Creates an in-memory set of reputations.
+    pub fn new(backoff: Duration) -> Self {
+        Self {
+            path: None,
+            backoff,
+            peers: BTreeMap::new(),
+            dirty_since: None,
+        }
+    }
+
+    /// Loads reputations from `path`, which is created the
+    /// first time changed reputations are written to disk (see
+    /// [`flush`][Self::flush]).
+    pub fn load(path: &Path, backoff: Duration) -> io::Result<Self> {
+        let peers = match fs::read(path) {
+            Ok(buf) => postcard::from_bytes::<Vec<(IpAddr, PeerReputation)>>(&buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                .into_iter()
+                .map(|(ip, rep)| {
+                    (
+                        ip,
+                        Entry {
+                            rep,
+                            retry_at: None,
+                        },
+                    )
+                })
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        debug!(?path, n = peers.len(), "loaded peer reputations");
+        Ok(Self {
+            path: Some(path.to_owned()),
+            backoff,
+            peers,
+            dirty_since: None,
+        })
+    }
+
+    /// Returns the reputation of `ip`.
+    pub fn get(&self, ip: IpAddr) -> Option<PeerReputation> {
+        self.peers.get(&ip).map(|e| e.rep)
+    }
+
+    /// Returns all known reputations.
+    pub fn iter(&self) -> impl Iterator<Item = (IpAddr, PeerReputation)> + '_ {
+        self.peers.iter().map(|(ip, e)| (*ip, e.rep))
+    }
+
+    /// Reports whether `ip` is suspicious.
+    pub fn is_suspicious(&self, ip: IpAddr) -> bool {
+        self.get(ip).is_some_and(|rep| rep.is_suspicious())
+    }
+
+    /// Returns how long to wait before connecting to `ip`, if
+    /// at all.
+    pub fn retry_in(&self, ip: IpAddr) -> Option<Duration> {
+        let retry_at = self.peers.get(&ip)?.retry_at?;
+        let now = Instant::now();
+        (retry_at > now).then(|| retry_at - now)
+    }
+
+    /// Records a successful outbound connection.
+    pub fn connect_succeeded(&mut self, ip: IpAddr) {
+        let e = self.peers.entry(ip).or_default();
+        e.rep.connect_successes = e.rep.connect_successes.saturating_add(1);
+        e.retry_at = None;
+        self.touch();
+    }
+
+    /// Records a failed outbound connection.
+    pub fn connect_failed(&mut self, ip: IpAddr) {
+        let e = self.peers.entry(ip).or_default();
+        e.rep.connect_failures = e.rep.connect_failures.saturating_add(1);
+        let backoff = e.rep.reconnect_backoff(self.backoff);
+        e.retry_at = Some(Instant::now() + backoff);
+        debug!(%ip, ?backoff, "backing off peer");
+        self.touch();
+    }
+
+    /// Records a failed handshake.
+    pub fn handshake_failed(&mut self, ip: IpAddr) {
+        let e = self.peers.entry(ip).or_default();
+        e.rep.handshake_failures = e.rep.handshake_failures.saturating_add(1);
+        self.touch();
+    }
+
+    /// Records a protocol violation.
+    pub fn protocol_violation(&mut self, ip: IpAddr) {
+        let e = self.peers.entry(ip).or_default();
+        e.rep.protocol_violations = e.rep.protocol_violations.saturating_add(1);
+        self.touch();
+    }
+
+    /// Forgets the reputation of `ip`.
+    pub fn reset(&mut self, ip: IpAddr) {
+        if self.peers.remove(&ip).is_some() {
+            self.touch();
+        }
+    }
+
+    /// Forgets all reputations.
+    pub fn reset_all(&mut self) {
+        self.peers.clear();
+        self.touch();
+    }
+
+    /// Records that the reputations changed.
+    fn touch(&mut self) {
+        if self.path.is_some() && self.dirty_since.is_none() {
+            self.dirty_since = Some(Instant::now());
+        }
+    }
+
+    /// Returns when [`flush`][Self::flush] is due, if changed
+    /// reputations have not been written to disk yet.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.dirty_since.map(|since| since + SAVE_DELAY)
+    }
+
+    /// Writes the reputations to disk, if persistent and they
+    /// changed since they were last written.
+    ///
+    /// Failures are logged instead of returned since
+    /// reputations are only advisory.
+    pub async fn flush(&mut self) {
+        let Some((path, buf)) = self.take_dirty() else {
+            return;
+        };
+        if let Err(err) = aranya_util::write_file_atomic(path, &buf).await {
+            warn!(?path, %err, "unable to save peer reputations");
+        }
+    }
+
+    /// Encodes the reputations to be written to disk, if
+    /// persistent and they changed since they were last
+    /// written.
+    fn take_dirty(&mut self) -> Option<(&Path, Vec<u8>)> {
+        self.dirty_since.take()?;
+        let path = self.path.as_deref()?;
+        let peers = self.iter().collect::<Vec<_>>();
+        match postcard::to_allocvec(&peers) {
+            Ok(buf) => Some((path, buf)),
+            Err(err) => {
+                warn!(?path, %err, "unable to encode peer reputations");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Reputations {
+    fn drop(&mut self) {
+        // `Drop` cannot wait for the write to finish elsewhere,
+        // so this is the only place that blocks on it.
+        let Some((path, buf)) = self.take_dirty() else {
+            return;
+        };
+        if let Err(err) = aranya_util::write_file_atomic_blocking(path, &buf) {
+            warn!(?path, %err, "unable to save peer reputations");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_penalty() {
+        let rep = PeerReputation {
+            connect_successes: 3,
+            connect_failures: 1,
+            ..Default::default()
+        };
+        assert_eq!(rep.penalty(), 0);
+        assert!(!rep.is_suspicious());
+
+        let rep = PeerReputation {
+            protocol_violations: 2,
+            ..Default::default()
+        };
+        assert_eq!(rep.penalty(), 8);
+        assert!(rep.is_suspicious());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let base = Duration::from_millis(100);
+        assert_eq!(PeerReputation::default().reconnect_backoff(base), base);
+
+        let rep = PeerReputation {
+            connect_failures: 2,
+            ..Default::default()
+        };
+        assert_eq!(rep.reconnect_backoff(base), base * 4);
+
+        // Capped and multiplied for suspicious peers.
+        let rep = PeerReputation {
+            connect_failures: 100,
+            ..Default::default()
+        };
+        assert_eq!(rep.reconnect_backoff(base), base * 64 * 4);
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reputation");
+        let backoff = Duration::from_millis(100);
+
+        let mut reps = Reputations::load(&path, backoff).unwrap();
+        assert_eq!(reps.get(IP), None);
+        reps.protocol_violation(IP);
+        reps.connect_failed(IP);
+        assert!(reps.retry_in(IP).is_some());
+
+        // Changes are not written until they are flushed.
+        assert!(reps.next_flush().is_some());
+        assert_eq!(Reputations::load(&path, backoff).unwrap().get(IP), None);
+        reps.flush().await;
+        assert_eq!(reps.next_flush(), None);
+
+        let mut reps = Reputations::load(&path, backoff).unwrap();
+        let want = PeerReputation {
+            connect_failures: 1,
+            protocol_violations: 1,
+            ..Default::default()
+        };
+        assert_eq!(reps.get(IP), Some(want));
+        // Backoff is not persisted.
+        assert_eq!(reps.retry_in(IP), None);
+
+        // Changes are written when the reputations are dropped.
+        reps.reset(IP);
+        drop(reps);
+        let reps = Reputations::load(&path, backoff).unwrap();
+        assert_eq!(reps.get(IP), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Resolving peers' network identifiers.
+//!
+//! By default, the router looks up the host of a peer's
+//! [`NetIdentifier`] with the system resolver. Setting
+//! [`RouterConfig::resolver`][crate::RouterConfig::resolver]
+//! replaces it, e.g., with a [`StaticResolver`] on networks
+//! without DNS.
+//!
+//! Identifiers that are already socket addresses are never
+//! resolved, and the resolved addresses are cached and ordered
+//! by the router (see
+//! [`RouterConfig::dns_ttl`][crate::RouterConfig::dns_ttl] and
+//! [`RouterConfig::addr_preference`][crate::RouterConfig::addr_preference])
+//! no matter which resolver returned them.
+//!
+//! [`NetIdentifier`]: aranya_daemon_api::NetIdentifier
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+};
+
+use tokio::net::lookup_host;
+
+/// A boxed future returned by [`Resolver::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves the hosts of peers' network identifiers.
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Resolves `host`, a `host:port` pair.
+    ///
+    /// Returns the addresses in the order that they should be
+    /// dialed. It is an error if `host` has no addresses.
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a>;
+}
+
+/// Resolves hosts with the system resolver, like
+/// [`lookup_host`].
+///
+/// This is the default [`Resolver`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(lookup_host(host).await?.collect()) })
+    }
+}
+
+/// Resolves hosts from a fixed map of host names to IP
+/// addresses, like a hosts file.
+///
+/// The addresses are returned in the order that they were
+/// added, with the port of the network identifier. Hosts that
+/// are not in the map are resolved with the fallback resolver,
+/// if any, or fail.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl StaticResolver {
+    /// Creates an empty map without a fallback.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `host` to `addr`, in addition to the addresses it
+    /// is already mapped to.
+    ///
+    /// Host names are case insensitive.
+    pub fn with_host(mut self, host: impl Into<String>, addr: IpAddr) -> Self {
+        let host = host.into().to_ascii_lowercase();
+        let addrs = self.hosts.entry(host).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        self
+    }
+
+    /// Resolves the hosts that are not in the map with
+    /// `fallback`, e.g., [`SystemResolver`].
+    pub fn with_fallback(mut self, fallback: Arc<dyn Resolver>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Looks up `host` in the map.
+    fn lookup(&self, host: &str) -> Option<io::Result<Vec<SocketAddr>>> {
+        let Some((name, port)) = split_host(host) else {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host: {host}"),
+            )));
+        };
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(err) => return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, err))),
+        };
+        let addrs = self.hosts.get(&name.to_ascii_lowercase())?;
+        Some(Ok(addrs
+            .iter()
+            .map(|&ip| SocketAddr::new(ip, port))
+            .collect()))
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            match (self.lookup(host), &self.fallback) {
+                (Some(result), _) => result,
+                (None, Some(fallback)) => fallback.resolve(host).await,
+                (None, None) => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown host: {host}"),
+                )),
+            }
+        })
+    }
+}
+
+/// Splits `host`, a `host:port` pair, into the host name and the
+/// port.
+///
+/// IPv6 literals are enclosed in brackets.
+fn split_host(host: &str) -> Option<(&str, &str)> {
+    let (name, port) = host.rsplit_once(':')?;
+    let name = name
+        .strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+        .unwrap_or(name);
+    Some((name, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_static_resolver() {
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let resolver = StaticResolver::new()
+            .with_host("Peer.local", a)
+            .with_host("peer.local", b)
+            .with_host("peer.local", a);
+
+        let got = resolver.resolve("peer.local:1234").await.unwrap();
+        assert_eq!(got, [SocketAddr::new(a, 1234), SocketAddr::new(b, 1234)]);
+
+        let err = resolver.resolve("other.local:1234").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = resolver.resolve("peer.local").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = resolver.resolve("peer.local:http").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let resolver = resolver.with_fallback(Arc::new(SystemResolver));
+        let got = resolver.resolve("localhost:1234").await.unwrap();
+        assert!(got.iter().all(|addr| addr.port() == 1234));
+    }
+}
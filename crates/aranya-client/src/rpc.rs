@@ -0,0 +1,178 @@
+//! Request/response over AFC channels.
+//!
+//! [`Client::request`][crate::Client::request] sends a request
+//! over a channel and waits for the peer's response on the same
+//! channel. The request carries a random [`RequestId`] in an
+//! extension of the message's envelope, which is encrypted and
+//! authenticated along with the data, and the response echoes
+//! it. The peer reads requests with
+//! [`Client::try_recv_request`][crate::Client::try_recv_request]
+//! and answers them with
+//! [`Client::respond`][crate::Client::respond], or leaves both to
+//! [`Client::serve`][crate::Client::serve].
+
+use std::{collections::BTreeMap, fmt, net::SocketAddr};
+
+use aranya_base58::ToBase58;
+use aranya_crypto::{default::Rng, Csprng};
+use aranya_daemon_api::AfcId;
+use serde::{Deserialize, Serialize};
+
+use crate::{Label, Seq};
+
+/// Correlates a request with its response.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct RequestId([u8; 16]);
+
+impl RequestId {
+    /// Creates a random ID.
+    pub(crate) fn random() -> Self {
+        let mut bytes = [0u8; 16];
+        Rng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// Returns the ID's bytes.
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_base58())
+    }
+}
+
+/// Whether a message is a request or a response.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Rpc {
+    Request(RequestId),
+    Response(RequestId),
+}
+
+/// A request received from a peer.
+///
+/// See [`Client::respond`][crate::Client::respond].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    /// Identifies the request.
+    pub id: RequestId,
+    /// The plaintext data.
+    pub data: Vec<u8>,
+    /// The address from which the request was received.
+    pub addr: SocketAddr,
+    /// The channel from which the request was received, over
+    /// which the response is sent.
+    pub channel: AfcId,
+    /// The channel's label.
+    pub label: Label,
+    /// The order of the request in the channel.
+    pub seq: Seq,
+}
+
+/// The response to a request.
+///
+/// See [`Client::request`][crate::Client::request].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    /// The plaintext data.
+    pub data: Vec<u8>,
+    /// The address from which the response was received.
+    pub addr: SocketAddr,
+    /// The order of the response in the channel.
+    pub seq: Seq,
+}
+
+/// The requests that are waiting for responses.
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    /// The channel that each request was sent over and, once it
+    /// arrives, its response.
+    waiting: BTreeMap<RequestId, (AfcId, Option<Response>)>,
+}
+
+impl Pending {
+    /// Starts waiting for the response to `id`, which was sent
+    /// over `channel`.
+    pub fn insert(&mut self, id: RequestId, channel: AfcId) {
+        self.waiting.insert(id, (channel, None));
+    }
+
+    /// Stops waiting for the response to `id`, returning it if
+    /// it arrived.
+    pub fn remove(&mut self, id: RequestId) -> Option<Response> {
+        self.waiting.remove(&id).and_then(|(_, resp)| resp)
+    }
+
+    /// Reports whether the response to `id` arrived.
+    pub fn is_done(&self, id: RequestId) -> bool {
+        self.waiting
+            .get(&id)
+            .is_some_and(|(_, resp)| resp.is_some())
+    }
+
+    /// Stores the response to `id`, which was received over
+    /// `channel`.
+    ///
+    /// Returns `resp` if no request is waiting for it: the
+    /// request timed out, the response is a duplicate, or it
+    /// was received over a different channel than the request
+    /// was sent over.
+    pub fn complete(
+        &mut self,
+        id: RequestId,
+        channel: AfcId,
+        resp: Response,
+    ) -> Result<(), Response> {
+        match self.waiting.get_mut(&id) {
+            Some((want, slot @ None)) if *want == channel => {
+                *slot = Some(resp);
+                Ok(())
+            }
+            _ => Err(resp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn afc_id(b: u8) -> AfcId {
+        postcard::from_bytes(&[b; 16]).unwrap()
+    }
+
+    fn resp(data: u8) -> Response {
+        Response {
+            data: vec![data],
+            addr: SocketAddr::from(([127, 0, 0, 1], 1234)),
+            seq: Seq::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_pending_requests() {
+        let mut pending = Pending::default();
+        let id = RequestId::random();
+        pending.insert(id, afc_id(1));
+        assert!(!pending.is_done(id));
+
+        // Responses over other channels and to unknown requests
+        // are rejected.
+        assert_eq!(pending.complete(id, afc_id(2), resp(0)), Err(resp(0)));
+        let other = RequestId::random();
+        assert_eq!(pending.complete(other, afc_id(1), resp(0)), Err(resp(0)));
+        assert!(!pending.is_done(id));
+
+        pending.complete(id, afc_id(1), resp(1)).unwrap();
+        assert!(pending.is_done(id));
+        // So are duplicates.
+        assert_eq!(pending.complete(id, afc_id(1), resp(2)), Err(resp(2)));
+
+        assert_eq!(pending.remove(id), Some(resp(1)));
+        // And responses that arrive too late.
+        assert_eq!(pending.complete(id, afc_id(1), resp(3)), Err(resp(3)));
+        assert_eq!(pending.remove(id), None);
+    }
+}
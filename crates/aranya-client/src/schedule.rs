@@ -0,0 +1,216 @@
+//! Fair delivery of received messages across labels.
+//!
+//! Received messages wait in one queue per label until they are
+//! read with [`Client::try_recv_data`][crate::Client::try_recv_data].
+//! The queues take turns: each label delivers up to its weight
+//! in messages before the next label gets a turn, so a flood on
+//! one label cannot delay the messages of another label by more
+//! than a round.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    num::NonZeroU32,
+};
+
+use crate::{AfcMsg, Label};
+
+/// How the messages received on channels with a particular label
+/// are delivered.
+///
+/// See [`Client::set_label_schedule`][crate::Client::set_label_schedule].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LabelSchedule {
+    weight: NonZeroU32,
+    max_queued: Option<usize>,
+}
+
+impl LabelSchedule {
+    /// Creates a schedule with a weight of one and no queue
+    /// limit, which is how labels without a schedule are
+    /// delivered.
+    pub const fn new() -> Self {
+        Self {
+            weight: NonZeroU32::MIN,
+            max_queued: None,
+        }
+    }
+
+    /// Delivers up to `weight` of the label's messages per
+    /// turn.
+    ///
+    /// Critical labels should have higher weights than bulk
+    /// labels.
+    pub const fn weight(mut self, weight: NonZeroU32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Drops the label's messages while `max` of them are
+    /// waiting to be read.
+    ///
+    /// Dropped messages are reported as
+    /// [`AfcEvent::LabelQueueFull`][crate::AfcEvent::LabelQueueFull].
+    pub const fn max_queued(mut self, max: usize) -> Self {
+        self.max_queued = Some(max);
+        self
+    }
+}
+
+impl Default for LabelSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Received messages, queued by label.
+#[derive(Debug, Default)]
+pub(crate) struct LabelQueues {
+    queues: BTreeMap<Label, VecDeque<AfcMsg>>,
+    schedules: BTreeMap<Label, LabelSchedule>,
+    /// The labels with queued messages, in the order that they
+    /// take turns. The first label's turn is in progress.
+    turns: VecDeque<Label>,
+    /// The number of messages delivered during the current
+    /// turn.
+    served: u32,
+    /// The total number of queued messages.
+    len: usize,
+}
+
+impl LabelQueues {
+    /// Returns the total number of queued messages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `label`'s schedule.
+    fn schedule(&self, label: Label) -> LabelSchedule {
+        self.schedules.get(&label).copied().unwrap_or_default()
+    }
+
+    /// Sets `label`'s schedule.
+    ///
+    /// Messages that are already queued are kept even if they
+    /// exceed the new limit.
+    pub fn set_schedule(&mut self, label: Label, schedule: LabelSchedule) {
+        self.schedules.insert(label, schedule);
+    }
+
+    /// Removes `label`'s schedule.
+    pub fn remove_schedule(&mut self, label: Label) -> Option<LabelSchedule> {
+        self.schedules.remove(&label)
+    }
+
+    /// Queues `msg`.
+    ///
+    /// Returns `msg` if its label's queue is full.
+    pub fn push(&mut self, msg: AfcMsg) -> Result<(), AfcMsg> {
+        let label = msg.label;
+        let max = self.schedule(label).max_queued;
+        let queue = self.queues.entry(label).or_default();
+        if max.is_some_and(|max| queue.len() >= max) {
+            return Err(msg);
+        }
+        if queue.is_empty() {
+            self.turns.push_back(label);
+        }
+        queue.push_back(msg);
+        self.len = self.len.saturating_add(1);
+        Ok(())
+    }
+
+    /// Removes the next message to deliver.
+    pub fn pop(&mut self) -> Option<AfcMsg> {
+        let label = *self.turns.front()?;
+        let weight = self.schedule(label).weight.get();
+        let queue = self.queues.get_mut(&label)?;
+        let msg = queue.pop_front()?;
+        self.len = self.len.saturating_sub(1);
+        self.served = self.served.saturating_add(1);
+        if queue.is_empty() {
+            self.queues.remove(&label);
+            self.turns.pop_front();
+            self.served = 0;
+        } else if self.served >= weight {
+            self.turns.rotate_left(1);
+            self.served = 0;
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use aranya_daemon_api::AfcId;
+
+    use super::*;
+    use crate::Seq;
+
+    fn msg(label: u32, data: u8) -> AfcMsg {
+        AfcMsg {
+            data: vec![data],
+            addr: SocketAddr::from(([127, 0, 0, 1], 1234)),
+            channel: postcard::from_bytes::<AfcId>(&[0; 16]).unwrap(),
+            label: Label::new(label),
+            seq: Seq::ZERO,
+            sent_at: None,
+        }
+    }
+
+    fn drain(queues: &mut LabelQueues) -> Vec<(u32, u8)> {
+        std::iter::from_fn(|| queues.pop())
+            .map(|msg| (msg.label.to_u32(), msg.data[0]))
+            .collect()
+    }
+
+    #[test]
+    fn test_label_queues_round_robin() {
+        let mut queues = LabelQueues::default();
+        for i in 0..4 {
+            queues.push(msg(1, i)).unwrap();
+        }
+        for i in 0..2 {
+            queues.push(msg(2, i)).unwrap();
+        }
+        assert_eq!(queues.len(), 6);
+        assert_eq!(
+            drain(&mut queues),
+            [(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (1, 3)]
+        );
+        assert_eq!(queues.len(), 0);
+    }
+
+    #[test]
+    fn test_label_queues_weights() {
+        let mut queues = LabelQueues::default();
+        queues.set_schedule(
+            Label::new(2),
+            LabelSchedule::new().weight(NonZeroU32::new(2).unwrap()),
+        );
+        for i in 0..3 {
+            queues.push(msg(1, i)).unwrap();
+        }
+        for i in 0..4 {
+            queues.push(msg(2, i)).unwrap();
+        }
+        assert_eq!(
+            drain(&mut queues),
+            [(1, 0), (2, 0), (2, 1), (1, 1), (2, 2), (2, 3), (1, 2)]
+        );
+    }
+
+    #[test]
+    fn test_label_queues_max_queued() {
+        let mut queues = LabelQueues::default();
+        queues.set_schedule(Label::new(1), LabelSchedule::new().max_queued(2));
+        queues.push(msg(1, 0)).unwrap();
+        queues.push(msg(1, 1)).unwrap();
+        let rejected = queues.push(msg(1, 2)).unwrap_err();
+        assert_eq!(rejected.data, [2]);
+        // Other labels are unaffected.
+        queues.push(msg(2, 0)).unwrap();
+        assert_eq!(drain(&mut queues), [(1, 0), (2, 0), (1, 1)]);
+    }
+}
@@ -0,0 +1,137 @@
+//! A [`tower`] facade over the AFC router.
+//!
+//! [`AfcService`] implements [`Service<AfcRequest>`], so it can
+//! be composed with [`tower`] middleware like retries, timeouts,
+//! and metrics. Downstream code can also depend on
+//! `Service<AfcRequest>` instead of [`Client`] and substitute
+//! a mock in tests.
+//!
+//! [`tower`]: https://docs.rs/tower
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use aranya_daemon_api::{NetIdentifier, TeamId};
+use tokio::sync::Mutex;
+use tower_service::Service;
+
+use crate::{AfcId, Client, Error, Label, Result};
+
+/// A request for [`AfcService`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum AfcRequest {
+    /// Create a bidirectional channel.
+    ///
+    /// See [`Client::create_bidi_channel`].
+    CreateBidiChannel {
+        /// The team the channel belongs to.
+        team_id: TeamId,
+        /// The peer on the other side of the channel.
+        peer: NetIdentifier,
+        /// The channel's label.
+        label: Label,
+    },
+    /// Send data over a channel.
+    ///
+    /// See [`Client::send_data`].
+    SendData {
+        /// The channel to send the data over.
+        id: AfcId,
+        /// The plaintext.
+        data: Vec<u8>,
+    },
+    /// Close (delete) a channel.
+    ///
+    /// See [`Client::delete_channel`].
+    DeleteChannel {
+        /// The channel to close.
+        id: AfcId,
+    },
+}
+
+/// A response from [`AfcService`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum AfcResponse {
+    /// The channel was created.
+    Created(AfcId),
+    /// The data was sent.
+    Sent,
+    /// The channel was deleted.
+    Deleted,
+}
+
+/// A [`Service`] over a shared [`Client`].
+///
+/// Requests are processed one at a time since each request needs
+/// exclusive access to the client.
+#[derive(Clone)]
+pub struct AfcService {
+    client: Arc<Mutex<Client>>,
+}
+
+impl AfcService {
+    /// Creates a service that owns `client`.
+    pub fn new(client: Client) -> Self {
+        Self::from_shared(Arc::new(Mutex::new(client)))
+    }
+
+    /// Creates a service from a client that is shared with other
+    /// code, like the task that calls [`Client::poll`].
+    pub fn from_shared(client: Arc<Mutex<Client>>) -> Self {
+        Self { client }
+    }
+
+    /// Returns the underlying client.
+    pub fn client(&self) -> &Arc<Mutex<Client>> {
+        &self.client
+    }
+}
+
+impl fmt::Debug for AfcService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AfcService").finish_non_exhaustive()
+    }
+}
+
+impl Service<AfcRequest> for AfcService {
+    type Response = AfcResponse;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<AfcResponse>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Requests wait for the client in `call`.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: AfcRequest) -> Self::Future {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move {
+            let mut client = client.lock().await;
+            match req {
+                AfcRequest::CreateBidiChannel {
+                    team_id,
+                    peer,
+                    label,
+                } => {
+                    let id = client.create_bidi_channel(team_id, peer, label).await?;
+                    Ok(AfcResponse::Created(id))
+                }
+                AfcRequest::SendData { id, data } => {
+                    client.send_data(id, &data).await?;
+                    Ok(AfcResponse::Sent)
+                }
+                AfcRequest::DeleteChannel { id } => {
+                    client.delete_channel(id).await?;
+                    Ok(AfcResponse::Deleted)
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,793 @@
+//! A simulated network for testing the AFC router.
+//!
+//! [`SimNet`] is an in-memory network whose links have
+//! configurable latency, jitter, and loss, and which can be
+//! partitioned and healed at will. Setting
+//! [`RouterConfig::sim`][crate::RouterConfig::sim] makes the
+//! router use it instead of the OS's TCP and UDP sockets, so
+//! protocol features can be exercised under adverse conditions
+//! from `cargo test`.
+//!
+//! Hosts are identified by the addresses that their routers are
+//! bound to. The addresses are virtual: binding port 0 picks an
+//! unused port in the simulation, not on the machine.
+//!
+//! Like TCP, streams are reliable and ordered, so loss only
+//! applies to datagrams. A partition resets the streams that
+//! cross it, refuses new connections across it, and drops the
+//! datagrams sent across it. A blackhole is like a partition,
+//! except that the streams that cross it go silent instead of
+//! being reset, which makes them half-open.
+//!
+//! TLS is not supported over a simulated network.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use aranya_crypto::{csprng::Random, default::Rng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    time::{sleep, sleep_until, Instant, Sleep},
+};
+
+/// The first ephemeral port.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// The conditions of a link between two hosts.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LinkConfig {
+    /// The one-way delay.
+    pub latency: Duration,
+    /// Up to this much extra delay, chosen at random for each
+    /// write or datagram.
+    ///
+    /// Datagrams can be reordered by jitter. Streams cannot.
+    pub jitter: Duration,
+    /// The probability, between 0 and 1, that a datagram is
+    /// dropped.
+    pub loss: f64,
+}
+
+/// An in-memory network.
+///
+/// Clones refer to the same network.
+#[derive(Clone)]
+pub struct SimNet {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    /// The conditions of links without their own config.
+    default_link: LinkConfig,
+    /// Per-link configs, keyed by [`link`].
+    links: HashMap<(SocketAddr, SocketAddr), LinkConfig>,
+    /// Partitioned links, keyed by [`link`].
+    partitions: HashSet<(SocketAddr, SocketAddr)>,
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<(SimStream, SocketAddr)>>,
+    sockets: HashMap<SocketAddr, Arc<Mutex<Inbox>>>,
+    /// Open streams, so that partitions can reset them.
+    wires: Vec<Wire>,
+    next_port: u16,
+    /// The state of the PRNG used for jitter and loss.
+    rng: u64,
+}
+
+/// Both directions of a stream between two hosts.
+struct Wire {
+    link: (SocketAddr, SocketAddr),
+    pipes: [Weak<Mutex<Pipe>>; 2],
+}
+
+impl SimNet {
+    /// Creates a network with perfect links.
+    pub fn new() -> Self {
+        Self::with_seed(u64::random(&mut Rng))
+    }
+
+    /// Creates a network with perfect links whose jitter and
+    /// loss are derived from `seed`.
+    ///
+    /// This makes runs repeatable, as long as the hosts send
+    /// the same things in the same order.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                listeners: HashMap::new(),
+                sockets: HashMap::new(),
+                wires: Vec::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                rng: seed,
+            })),
+        }
+    }
+
+    /// Sets the conditions of every link that does not have its
+    /// own config.
+    pub fn set_default_link(&self, cfg: LinkConfig) {
+        self.lock().default_link = cfg;
+    }
+
+    /// Sets the conditions of the link between `a` and `b`.
+    pub fn set_link(&self, a: SocketAddr, b: SocketAddr, cfg: LinkConfig) {
+        self.lock().links.insert(link(a, b), cfg);
+    }
+
+    /// Partitions `a` from `b`.
+    ///
+    /// Streams between them are reset.
+    pub fn partition(&self, a: SocketAddr, b: SocketAddr) {
+        let mut inner = self.lock();
+        let key = link(a, b);
+        inner.partitions.insert(key);
+        inner.wires.retain(|wire| {
+            let mut alive = false;
+            for pipe in wire.pipes.iter().filter_map(Weak::upgrade) {
+                alive = true;
+                if wire.link == key {
+                    lock(&pipe).reset();
+                }
+            }
+            alive && wire.link != key
+        });
+    }
+
+    /// Silently drops everything sent between `a` and `b`, as
+    /// if one of them lost power.
+    ///
+    /// Unlike [`partition`][Self::partition], the streams that
+    /// cross it are not reset: writes to them succeed, but never
+    /// arrive, even after the link is healed. New connections
+    /// across it are refused and datagrams are dropped until it
+    /// is healed.
+    pub fn blackhole(&self, a: SocketAddr, b: SocketAddr) {
+        let mut inner = self.lock();
+        let key = link(a, b);
+        inner.partitions.insert(key);
+        for wire in inner.wires.iter().filter(|wire| wire.link == key) {
+            for pipe in wire.pipes.iter().filter_map(Weak::upgrade) {
+                let mut pipe = lock(&pipe);
+                pipe.blackholed = true;
+                pipe.chunks.clear();
+            }
+        }
+    }
+
+    /// Undoes [`partition`][Self::partition] and
+    /// [`blackhole`][Self::blackhole].
+    pub fn heal(&self, a: SocketAddr, b: SocketAddr) {
+        self.lock().partitions.remove(&link(a, b));
+    }
+
+    /// Heals every partition and blackhole.
+    pub fn heal_all(&self) {
+        self.lock().partitions.clear();
+    }
+
+    /// Binds a listener and a datagram socket to `addr`.
+    ///
+    /// Port 0 binds an unused port.
+    pub(crate) fn bind(&self, addr: SocketAddr) -> io::Result<(SimListener, SimSocket)> {
+        let mut inner = self.lock();
+        let addr = if addr.port() == 0 {
+            inner.ephemeral(addr.ip())?
+        } else {
+            addr
+        };
+        if inner.listeners.contains_key(&addr) || inner.sockets.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        inner.listeners.insert(addr, tx);
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        inner.sockets.insert(addr, Arc::clone(&inbox));
+        let listener = SimListener {
+            net: self.clone(),
+            addr,
+            rx,
+        };
+        let socket = SimSocket {
+            net: self.clone(),
+            addr,
+            inbox,
+            sleep: Mutex::new(None),
+        };
+        Ok((listener, socket))
+    }
+
+    /// Connects the host bound to `host` to the listener at
+    /// `to`.
+    ///
+    /// Connecting takes a round trip.
+    pub(crate) async fn connect(&self, host: SocketAddr, to: SocketAddr) -> io::Result<SimStream> {
+        let latency = {
+            let inner = self.lock();
+            if inner.partitions.contains(&link(host, to)) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            inner.link(host, to).latency
+        };
+        sleep(latency.saturating_mul(2)).await;
+
+        let mut inner = self.lock();
+        if inner.partitions.contains(&link(host, to)) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let Some(listener) = inner.listeners.get(&to).cloned() else {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        };
+        let local = inner.ephemeral(host.ip())?;
+        let a = Arc::new(Mutex::new(Pipe::default()));
+        let b = Arc::new(Mutex::new(Pipe::default()));
+        inner.wires.push(Wire {
+            link: link(host, to),
+            pipes: [Arc::downgrade(&a), Arc::downgrade(&b)],
+        });
+        drop(inner);
+
+        let ours = SimStream::new(
+            self.clone(),
+            (host, local),
+            to,
+            Arc::clone(&a),
+            Arc::clone(&b),
+        );
+        let theirs = SimStream::new(self.clone(), (to, to), local, b, a);
+        listener
+            .send((theirs, local))
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(ours)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        lock(&self.inner)
+    }
+}
+
+impl Default for SimNet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SimNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("SimNet")
+            .field("default_link", &inner.default_link)
+            .field("links", &inner.links)
+            .field("partitions", &inner.partitions)
+            .field("hosts", &inner.listeners.keys())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Inner {
+    fn link(&self, a: SocketAddr, b: SocketAddr) -> LinkConfig {
+        self.links
+            .get(&link(a, b))
+            .copied()
+            .unwrap_or(self.default_link)
+    }
+
+    /// Returns the delay of the next write or datagram sent
+    /// over `link`.
+    fn delay(&mut self, link: &LinkConfig) -> Duration {
+        link.latency
+            .saturating_add(link.jitter.mul_f64(self.random()))
+    }
+
+    /// Returns a random number in `[0, 1)`.
+    ///
+    /// This is SplitMix64, which is plenty for simulating a
+    /// network and, unlike a CSPRNG, can be seeded.
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // A float in `[1, 2)` with a random mantissa.
+        f64::from_bits(0x3ff0_0000_0000_0000 | (z >> 12)) - 1.0
+    }
+
+    /// Picks an unused ephemeral port on `ip`.
+    fn ephemeral(&mut self, ip: IpAddr) -> io::Result<SocketAddr> {
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            let addr = SocketAddr::new(ip, port);
+            if !self.listeners.contains_key(&addr) && !self.sockets.contains_key(&addr) {
+                return Ok(addr);
+            }
+        }
+        Err(io::ErrorKind::AddrNotAvailable.into())
+    }
+}
+
+/// Listens for streams on a [`SimNet`].
+#[derive(Debug)]
+pub(crate) struct SimListener {
+    net: SimNet,
+    addr: SocketAddr,
+    rx: mpsc::UnboundedReceiver<(SimStream, SocketAddr)>,
+}
+
+impl SimListener {
+    /// Accepts a stream.
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(SimStream, SocketAddr)>> {
+        self.rx
+            .poll_recv(cx)
+            .map(|v| v.ok_or_else(|| io::ErrorKind::NotConnected.into()))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.net.lock().listeners.remove(&self.addr);
+    }
+}
+
+/// One direction of a stream.
+#[derive(Debug, Default)]
+struct Pipe {
+    /// Data in flight and when it arrives, in order.
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    /// When the last chunk arrives.
+    last: Option<Instant>,
+    /// The writer is done.
+    closed: bool,
+    /// The reader is gone.
+    orphaned: bool,
+    /// The stream was reset.
+    reset: bool,
+    /// Writes disappear and closing is not noticed by the
+    /// reader. See [`SimNet::blackhole`].
+    blackholed: bool,
+    /// Woken when the reader has something to do.
+    waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn reset(&mut self) {
+        self.reset = true;
+        self.chunks.clear();
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A stream on a [`SimNet`].
+pub(crate) struct SimStream {
+    net: SimNet,
+    /// The address of the listener of the host that owns this
+    /// end.
+    host: SocketAddr,
+    local: SocketAddr,
+    peer: SocketAddr,
+    tx: Arc<Mutex<Pipe>>,
+    rx: Arc<Mutex<Pipe>>,
+    /// Waits for the next chunk to arrive.
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl SimStream {
+    fn new(
+        net: SimNet,
+        (host, local): (SocketAddr, SocketAddr),
+        peer: SocketAddr,
+        tx: Arc<Mutex<Pipe>>,
+        rx: Arc<Mutex<Pipe>>,
+    ) -> Self {
+        Self {
+            net,
+            host,
+            local,
+            peer,
+            tx,
+            rx,
+            sleep: None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Writes `data` without waiting.
+    ///
+    /// Writes never block since pipes are unbounded.
+    pub fn try_write(&self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let delay = {
+            let mut inner = self.net.lock();
+            let link = inner.link(self.host, self.peer);
+            inner.delay(&link)
+        };
+        let mut tx = lock(&self.tx);
+        if tx.reset {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        if tx.closed || tx.orphaned {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if tx.blackholed {
+            return Ok(data.len());
+        }
+        // Streams are ordered, so a chunk never arrives before
+        // the previous one.
+        let at = (Instant::now() + delay).max(tx.last.unwrap_or_else(Instant::now));
+        tx.last = Some(at);
+        tx.chunks.push_back((at, data.to_vec()));
+        tx.wake();
+        Ok(data.len())
+    }
+}
+
+impl fmt::Debug for SimStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimStream")
+            .field("local", &self.local)
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut rx = lock(&this.rx);
+            if rx.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let now = Instant::now();
+            match rx.chunks.front_mut() {
+                Some((at, chunk)) if *at <= now => {
+                    let n = chunk.len().min(out.remaining());
+                    out.put_slice(chunk.get(..n).unwrap_or_default());
+                    chunk.drain(..n);
+                    if chunk.is_empty() {
+                        rx.chunks.pop_front();
+                    }
+                    this.sleep = None;
+                    return Poll::Ready(Ok(()));
+                }
+                Some((at, _)) => {
+                    let at = *at;
+                    rx.waker = Some(cx.waker().clone());
+                    drop(rx);
+                    let sleep = this.sleep.get_or_insert_with(|| Box::pin(sleep_until(at)));
+                    sleep.as_mut().reset(at);
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                None if rx.closed => return Poll::Ready(Ok(())),
+                None => {
+                    rx.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.try_write(data))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut tx = lock(&self.tx);
+        if !tx.blackholed {
+            tx.closed = true;
+            tx.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut tx = lock(&self.tx);
+        if !tx.blackholed {
+            tx.closed = true;
+            tx.wake();
+        }
+        drop(tx);
+        let mut rx = lock(&self.rx);
+        if !rx.blackholed {
+            rx.orphaned = true;
+        }
+        rx.chunks.clear();
+    }
+}
+
+/// Datagrams in flight to a socket.
+#[derive(Debug, Default)]
+struct Inbox {
+    /// Datagrams ordered by when they arrive.
+    queue: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+    /// Woken when a datagram is queued.
+    waker: Option<Waker>,
+}
+
+/// A datagram socket on a [`SimNet`].
+#[derive(Debug)]
+pub(crate) struct SimSocket {
+    net: SimNet,
+    addr: SocketAddr,
+    inbox: Arc<Mutex<Inbox>>,
+    /// Waits for the next datagram to arrive.
+    sleep: Mutex<Option<Pin<Box<Sleep>>>>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Polls until a datagram has arrived.
+    pub fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut inbox = lock(&self.inbox);
+            let at = match inbox.queue.front() {
+                Some((at, ..)) if *at <= Instant::now() => return Poll::Ready(Ok(())),
+                Some((at, ..)) => *at,
+                None => {
+                    inbox.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            // Either it arrives or an earlier datagram is
+            // queued.
+            inbox.waker = Some(cx.waker().clone());
+            drop(inbox);
+            let mut sleep = lock(&self.sleep);
+            let sleep = sleep.get_or_insert_with(|| Box::pin(sleep_until(at)));
+            sleep.as_mut().reset(at);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Receives a datagram without waiting.
+    ///
+    /// Like UDP, datagrams that do not fit in `buf` are
+    /// truncated.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut inbox = lock(&self.inbox);
+        let now = Instant::now();
+        if !inbox.queue.front().is_some_and(|(at, ..)| *at <= now) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let Some((_, from, data)) = inbox.queue.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let n = data.len().min(buf.len());
+        buf.iter_mut().zip(&data).for_each(|(dst, src)| *dst = *src);
+        Ok((n, from))
+    }
+
+    /// Sends a datagram to `to`.
+    ///
+    /// Like UDP, datagrams that are lost, cross a partition, or
+    /// are sent to an unbound address silently disappear.
+    pub fn send_to(&self, data: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let mut inner = self.net.lock();
+        if inner.partitions.contains(&link(self.addr, to)) {
+            return Ok(data.len());
+        }
+        let cfg = inner.link(self.addr, to);
+        if inner.random() < cfg.loss {
+            return Ok(data.len());
+        }
+        let at = Instant::now() + inner.delay(&cfg);
+        let Some(inbox) = inner.sockets.get(&to).cloned() else {
+            return Ok(data.len());
+        };
+        drop(inner);
+
+        let mut inbox = lock(&inbox);
+        let idx = inbox.queue.partition_point(|(t, ..)| *t <= at);
+        inbox.queue.insert(idx, (at, self.addr, data.to_vec()));
+        if let Some(waker) = inbox.waker.take() {
+            waker.wake();
+        }
+        Ok(data.len())
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.net.lock().sockets.remove(&self.addr);
+    }
+}
+
+/// Returns the key for the link between `a` and `b`.
+///
+/// Links are symmetric.
+fn link(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Locks `mutex`, ignoring poisoning since the state is never
+/// left half-updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    impl SimListener {
+        async fn accept(&mut self) -> io::Result<(SimStream, SocketAddr)> {
+            poll_fn(|cx| self.poll_accept(cx)).await
+        }
+    }
+
+    impl SimSocket {
+        async fn readable(&self) -> io::Result<()> {
+            poll_fn(|cx| self.poll_recv_ready(cx)).await
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn test_sim_stream() {
+        let net = SimNet::with_seed(1);
+        net.set_default_link(LinkConfig {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(20),
+            loss: 1.0,
+        });
+        let (mut listener, _a) = net.bind(addr(1)).unwrap();
+        let (_, _b) = net.bind(addr(2)).unwrap();
+        assert_eq!(
+            net.bind(addr(1)).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        let start = Instant::now();
+        let mut client = net.connect(addr(2), addr(1)).await.unwrap();
+        let (mut server, from) = listener.accept().await.unwrap();
+        assert_eq!(from, client.local);
+        assert_eq!(server.peer_addr(), client.local);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        // Streams are lossless and ordered despite jitter.
+        let start = Instant::now();
+        for i in 0..10u8 {
+            client.write_all(&[i]).await.unwrap();
+        }
+        let mut got = [0u8; 10];
+        server.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        client.shutdown().await.unwrap();
+        assert_eq!(server.read(&mut got).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sim_partition() {
+        let net = SimNet::with_seed(1);
+        let (mut listener, a) = net.bind(addr(1)).unwrap();
+        let (_, b) = net.bind(addr(2)).unwrap();
+        let mut client = net.connect(addr(2), addr(1)).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        net.partition(addr(1), addr(2));
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            server.read(&mut buf).await.unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+        assert!(client.write_all(&[1]).await.is_err());
+        assert_eq!(
+            net.connect(addr(2), addr(1)).await.unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        b.send_to(&[1], addr(1)).unwrap();
+        assert_eq!(
+            a.try_recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        net.heal(addr(1), addr(2));
+        net.connect(addr(2), addr(1)).await.unwrap();
+        b.send_to(&[2], addr(1)).unwrap();
+        a.readable().await.unwrap();
+        assert_eq!(a.try_recv_from(&mut buf).unwrap(), (1, addr(2)));
+        assert_eq!(buf, [2]);
+    }
+
+    #[tokio::test]
+    async fn test_sim_datagrams() {
+        let net = SimNet::with_seed(1);
+        let (_l1, a) = net.bind(addr(1)).unwrap();
+        let (_l2, b) = net.bind(addr(2)).unwrap();
+        net.set_link(
+            addr(1),
+            addr(2),
+            LinkConfig {
+                latency: Duration::from_millis(5),
+                jitter: Duration::from_millis(50),
+                loss: 0.5,
+            },
+        );
+
+        const N: u8 = 200;
+        for i in 0..N {
+            b.send_to(&[i], addr(1)).unwrap();
+        }
+        sleep(Duration::from_millis(60)).await;
+        let mut got = Vec::new();
+        let mut buf = [0u8; 1];
+        while let Ok((_, from)) = a.try_recv_from(&mut buf) {
+            assert_eq!(from, addr(2));
+            got.push(buf[0]);
+        }
+
+        // Some, but not all, datagrams are lost, and jitter
+        // reorders the rest.
+        assert!(
+            !got.is_empty() && got.len() < usize::from(N),
+            "{}",
+            got.len()
+        );
+        assert!(got.windows(2).any(|w| w[0] > w[1]));
+    }
+}
@@ -0,0 +1,131 @@
+//! Optional TLS for the AFC TCP transport.
+//!
+//! AFC payloads are already encrypted and authenticated, but the
+//! wire framing, control messages, and traffic patterns are not.
+//! Wrapping the TCP streams in TLS hides them from the network
+//! and authenticates the peer's endpoint.
+//!
+//! Every peer must use TLS or none of them can: a plaintext
+//! peer cannot talk to a TLS peer. UDP datagrams are not
+//! wrapped.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector, TlsStream,
+};
+
+/// The ALPN protocol that identifies AFC.
+///
+/// Peers offer it so that a listener that shares its port with
+/// another protocol can tell AFC streams apart (see
+/// [`ForeignHandler`][crate::ForeignHandler]). Streams that
+/// negotiate no protocol are AFC too, for peers that predate it.
+pub const AFC_ALPN: &[u8] = b"aranya-afc";
+
+/// Configures TLS for the AFC TCP transport.
+///
+/// See [`RouterConfig::tls`][crate::RouterConfig::tls].
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Used for streams that we open.
+    client: Arc<ClientConfig>,
+    /// Used for streams that peers open.
+    server: Arc<ServerConfig>,
+    /// The name used to verify peers' certificates.
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsConfig {
+    /// Creates a TLS configuration.
+    ///
+    /// `client` is used for streams that we open and `server`
+    /// is used for streams that peers open. Requiring client
+    /// certificates in `server` authenticates both ends of each
+    /// stream.
+    ///
+    /// By default, a peer's certificate must be valid for the
+    /// peer's IP address. Use
+    /// [`with_server_name`][Self::with_server_name] to verify
+    /// it against a fixed name instead.
+    ///
+    /// `client` offers [`AFC_ALPN`] instead of its own ALPN
+    /// protocols. If `server` lists ALPN protocols, e.g., for
+    /// another protocol that shares the port, [`AFC_ALPN`] is
+    /// preferred over them.
+    pub fn new(client: Arc<ClientConfig>, server: Arc<ServerConfig>) -> Self {
+        let mut client = Arc::unwrap_or_clone(client);
+        client.alpn_protocols = vec![AFC_ALPN.to_vec()];
+        let mut server = Arc::unwrap_or_clone(server);
+        if !server.alpn_protocols.is_empty() {
+            server.alpn_protocols.retain(|p| p != AFC_ALPN);
+            server.alpn_protocols.insert(0, AFC_ALPN.to_vec());
+        }
+        Self {
+            client: Arc::new(client),
+            server: Arc::new(server),
+            server_name: None,
+        }
+    }
+
+    /// Creates a mutual TLS configuration where each peer
+    /// presents `certs` and verifies the other peer's
+    /// certificate chain against `roots`.
+    pub fn mutual(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        roots: RootCertStore,
+    ) -> Result<Self, rustls::Error> {
+        let roots = Arc::new(roots);
+        let verifier = WebPkiClientVerifier::builder(Arc::clone(&roots))
+            .build()
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)?;
+        Ok(Self::new(Arc::new(client), Arc::new(server)))
+    }
+
+    /// Verifies peers' certificates against `name` instead of
+    /// their IP addresses.
+    ///
+    /// This is useful when peers share a certificate or do not
+    /// have stable addresses.
+    pub fn with_server_name(mut self, name: ServerName<'static>) -> Self {
+        self.server_name = Some(name);
+        self
+    }
+
+    /// Performs the client side of the handshake over `stream`
+    /// with the peer at `addr`.
+    pub(crate) async fn connect(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let name = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| ServerName::IpAddress(addr.ip().into()));
+        let connector = TlsConnector::from(Arc::clone(&self.client));
+        let stream = connector.connect(name, stream).await?;
+        Ok(stream.into())
+    }
+
+    /// Performs the server side of the handshake over `stream`.
+    pub(crate) async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let acceptor = TlsAcceptor::from(Arc::clone(&self.server));
+        let stream = acceptor.accept(stream).await?;
+        Ok(stream.into())
+    }
+}
@@ -0,0 +1,56 @@
+//! Custom transports for AFC streams.
+//!
+//! By default, the router's streams are TCP streams. Setting
+//! [`RouterConfig::transport`][crate::RouterConfig::transport]
+//! replaces TCP with an [`AfcTransport`], e.g., one that tunnels
+//! streams over an existing message bus.
+//!
+//! The router still frames, encrypts, and multiplexes channels
+//! over the streams itself, so a transport only has to move
+//! bytes in order between two peers. Peers are still identified
+//! by socket addresses, which the transport maps onto its own
+//! addressing (e.g., subjects).
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A boxed future returned by [`AfcTransport::connect`].
+pub type ConnectFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<Box<dyn AfcConn>>> + Send + 'a>>;
+
+/// Opens and accepts the streams used by the AFC router.
+///
+/// Datagram channels are not supported over a custom transport,
+/// and neither is TLS.
+pub trait AfcTransport: fmt::Debug + Send + Sync + 'static {
+    /// Opens a stream with the peer at `addr`.
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_>;
+
+    /// Polls for a stream opened by a peer.
+    ///
+    /// Returns the stream and the peer's address.
+    fn poll_accept(&self, cx: &mut Context<'_>)
+        -> Poll<io::Result<(Box<dyn AfcConn>, SocketAddr)>>;
+
+    /// Returns the address that peers use to reach us.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// A stream opened or accepted by an [`AfcTransport`].
+///
+/// Bytes must be delivered to the peer reliably and in order.
+/// Shutting down the write half tells the peer that we are done
+/// with the stream, and the peer should see it as the end of
+/// the stream.
+pub trait AfcConn: AsyncRead + AsyncWrite + fmt::Debug + Send + Sync + Unpin + 'static {
+    /// Returns the peer's address.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
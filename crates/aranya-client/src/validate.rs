@@ -0,0 +1,133 @@
+//! Validation of received payloads.
+
+use std::{fmt, sync::Arc};
+
+/// A custom payload check. See [`PayloadValidator::check`].
+type CheckFn = dyn Fn(&[u8]) -> Result<(), String> + Send + Sync;
+
+/// Checks the payloads received on channels with a particular
+/// label before they are delivered.
+///
+/// Payloads are checked after they are decrypted. Those that
+/// fail any check are dropped and reported as
+/// [`AfcEvent::PayloadRejected`][crate::AfcEvent::PayloadRejected].
+///
+/// See [`Client::set_payload_validator`][crate::Client::set_payload_validator].
+#[derive(Clone, Default)]
+pub struct PayloadValidator {
+    max_size: Option<usize>,
+    prefix: Option<Vec<u8>>,
+    check: Option<Arc<CheckFn>>,
+}
+
+impl PayloadValidator {
+    /// Creates a validator that accepts every payload.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rejects payloads larger than `max` bytes.
+    pub fn max_size(mut self, max: usize) -> Self {
+        self.max_size = Some(max);
+        self
+    }
+
+    /// Rejects payloads that do not start with `prefix`, e.g.,
+    /// a magic number.
+    pub fn prefix(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Rejects payloads for which `f` returns an error, e.g.,
+    /// because they do not decode with the expected schema.
+    ///
+    /// `f` runs after the other checks, on the router's task,
+    /// so it should be fast.
+    pub fn check<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.check = Some(Arc::new(f));
+        self
+    }
+
+    /// Checks `payload`.
+    pub(crate) fn validate(&self, payload: &[u8]) -> Result<(), PayloadRejection> {
+        if let Some(max) = self.max_size {
+            if payload.len() > max {
+                return Err(PayloadRejection::TooLarge {
+                    size: payload.len(),
+                    max,
+                });
+            }
+        }
+        if let Some(prefix) = &self.prefix {
+            if !payload.starts_with(prefix) {
+                return Err(PayloadRejection::MissingPrefix);
+            }
+        }
+        if let Some(check) = &self.check {
+            check(payload).map_err(PayloadRejection::Invalid)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PayloadValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadValidator")
+            .field("max_size", &self.max_size)
+            .field("prefix", &self.prefix)
+            .field("check", &self.check.is_some())
+            .finish()
+    }
+}
+
+/// Why a [`PayloadValidator`] rejected a payload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PayloadRejection {
+    /// The payload was larger than
+    /// [`PayloadValidator::max_size`].
+    TooLarge {
+        /// The payload's size in bytes.
+        size: usize,
+        /// The maximum size in bytes.
+        max: usize,
+    },
+    /// The payload did not start with
+    /// [`PayloadValidator::prefix`].
+    MissingPrefix,
+    /// [`PayloadValidator::check`] returned an error.
+    Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_validator() {
+        let v = PayloadValidator::new();
+        assert_eq!(v.validate(b""), Ok(()));
+
+        let v = PayloadValidator::new()
+            .max_size(8)
+            .prefix(*b"AR")
+            .check(|data| match data.get(2) {
+                Some(1) => Ok(()),
+                _ => Err("bad version".into()),
+            });
+        assert_eq!(v.validate(b"AR\x01"), Ok(()));
+        assert_eq!(
+            v.validate(b"AR\x01 too long"),
+            Err(PayloadRejection::TooLarge { size: 12, max: 8 })
+        );
+        assert_eq!(v.validate(b"XX\x01"), Err(PayloadRejection::MissingPrefix));
+        assert_eq!(
+            v.validate(b"AR\x02"),
+            Err(PayloadRejection::Invalid("bad version".into()))
+        );
+    }
+}
@@ -10,31 +10,52 @@
 )]
 
 use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::BTreeMap,
     fmt,
+    future::{poll_fn, Future},
+    iter, mem,
     net::SocketAddr,
+    num::NonZeroU32,
     path::{Path, PathBuf},
-    time::Duration,
+    pin::pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
 use aranya_base58::ToBase58;
-use aranya_client::{AfcMsg, Client, Label, Seq};
+use aranya_client::{
+    AfcError, AfcEvent, AfcMsg, CapacityPolicy, ChannelExpiry, ChannelLimits, ChannelOptions,
+    ChannelStats, Client, DaemonStatus, DisconnectReason, Error, ExpiryReason, FlowControl,
+    GoodbyeReason, IdempotencyToken, KeepAlive, Label, LabelOptions, LabelSchedule, Liveness,
+    MemoryBounds, PayloadRejection, PayloadValidator, Priority, QueueWatermarks, RateLimit,
+    RouterConfig, SendOptions, Seq, StreamTimeouts, Transport, TrySendError, Version,
+    SUPPORTED_VERSIONS,
+};
 use aranya_crypto::{hash::Hash, rust::Sha256};
 use aranya_daemon::{
-    config::{AfcConfig, Config},
+    config::{AfcConfig, Config, PolicyConfig, SyncConfig},
+    vm_policy::TEST_POLICY_1,
     Daemon,
 };
-use aranya_daemon_api::{DeviceId, KeyBundle, NetIdentifier, Role};
+use aranya_daemon_api::{
+    CipherSuiteInfo, DeviceId, Invitation, KeyBundle, LabelScope, NetIdentifier, Operation,
+    PolicyInfo, Role, SyncStage, TeamId,
+};
 use aranya_util::addr::Addr;
 use backon::{ExponentialBuilder, Retryable};
 use tempfile::tempdir;
 use test_log::test;
 use tokio::{
     fs,
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
     task::{self, AbortHandle},
     time::{self, Sleep},
 };
-use tracing::{debug, info, instrument};
+use tracing::{debug, dispatcher, info, instrument, Dispatch};
 
 #[instrument(skip_all, fields(%duration = FmtDuration(d)))]
 fn sleep(d: Duration) -> Sleep {
@@ -132,6 +153,78 @@ fn trim(mut d: u128, mut width: usize) -> (u128, usize) {
     (d, width)
 }
 
+/// Counts the allocations made by futures passed to
+/// [`assert_no_alloc`].
+#[global_allocator]
+static ALLOC: GuardAlloc = GuardAlloc;
+
+struct GuardAlloc;
+
+thread_local! {
+    /// Set while a guarded future is being polled.
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The number of allocations made while [`GUARDED`] was set.
+static GUARDED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+impl GuardAlloc {
+    fn count(&self) {
+        // `try_with` fails while the thread is being torn down.
+        if GUARDED.try_with(Cell::get).unwrap_or(false) {
+            GUARDED_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// SAFETY: this defers to `System`.
+unsafe impl GlobalAlloc for GuardAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        // SAFETY: the caller upholds `alloc`'s contract.
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        // SAFETY: the caller upholds `alloc_zeroed`'s contract.
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.count();
+        // SAFETY: the caller upholds `realloc`'s contract.
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: the caller upholds `dealloc`'s contract.
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+/// Awaits `fut`, asserting that polling it never allocates.
+///
+/// Logging is disabled while `fut` is polled since the test
+/// subscriber allocates.
+async fn assert_no_alloc<F: Future>(fut: F) -> F::Output {
+    let none = Dispatch::none();
+    let mut fut = pin!(fut);
+    let before = GUARDED_ALLOCS.load(Ordering::Relaxed);
+    let out = poll_fn(|cx| {
+        dispatcher::with_default(&none, || {
+            GUARDED.set(true);
+            let poll = fut.as_mut().poll(cx);
+            GUARDED.set(false);
+            poll
+        })
+    })
+    .await;
+    let allocs = GUARDED_ALLOCS.load(Ordering::Relaxed) - before;
+    assert_eq!(allocs, 0, "should not allocate");
+    out
+}
+
 /// Repeatedly calls `poll_data`, followed by `handle_data`,
 /// until all of the clients are pending.
 macro_rules! do_poll {
@@ -166,14 +259,29 @@ struct TeamCtx {
 
 impl TeamCtx {
     pub async fn new(name: String, work_dir: PathBuf) -> Result<Self> {
-        let owner = UserCtx::new(name.clone(), "owner".into(), work_dir.join("owner")).await?;
-        let admin = UserCtx::new(name.clone(), "admin".into(), work_dir.join("admin")).await?;
-        let operator =
-            UserCtx::new(name.clone(), "operator".into(), work_dir.join("operator")).await?;
-        let membera =
-            UserCtx::new(name.clone(), "membera".into(), work_dir.join("membera")).await?;
-        let memberb =
-            UserCtx::new(name.clone(), "memberb".into(), work_dir.join("memberb")).await?;
+        Self::with_config(name, work_dir, RouterConfig::default()).await
+    }
+
+    /// Like [`new`][Self::new], but every user's AFC router
+    /// uses `router_cfg`.
+    pub async fn with_config(
+        name: String,
+        work_dir: PathBuf,
+        router_cfg: RouterConfig,
+    ) -> Result<Self> {
+        let user = |who: &str| {
+            UserCtx::with_config(
+                name.clone(),
+                who.into(),
+                work_dir.join(who),
+                router_cfg.clone(),
+            )
+        };
+        let owner = user("owner").await?;
+        let admin = user("admin").await?;
+        let operator = user("operator").await?;
+        let membera = user("membera").await?;
+        let memberb = user("memberb").await?;
 
         Ok(Self {
             owner,
@@ -185,17 +293,147 @@ impl TeamCtx {
     }
 }
 
+impl TeamCtx {
+    /// Creates a team where `membera` and `memberb` can create
+    /// AFC channels with each other using `label`.
+    ///
+    /// `membera` and `memberb` are assigned their AFC router
+    /// addresses as network identifiers.
+    pub async fn setup_afc(&mut self, label: Label) -> Result<TeamId> {
+        let sync_interval = Duration::from_millis(100);
+        let sleep_interval = sync_interval * 6;
+
+        // create team.
+        let team_id = self.owner.client.create_team().await?;
+        info!(?team_id);
+
+        // get sync addresses.
+        let owner_addr = self.owner.aranya_local_addr().await?;
+        let admin_addr = self.admin.aranya_local_addr().await?;
+        let operator_addr = self.operator.aranya_local_addr().await?;
+        let membera_addr = self.membera.aranya_local_addr().await?;
+        let memberb_addr = self.memberb.aranya_local_addr().await?;
+
+        // get afc addresses.
+        let membera_afc_addr = self.membera.afc_local_addr().await?;
+        let memberb_afc_addr = self.memberb.afc_local_addr().await?;
+
+        // setup sync peers.
+        let addrs = [
+            owner_addr,
+            admin_addr,
+            operator_addr,
+            membera_addr,
+            memberb_addr,
+        ];
+        for (user, addr) in [
+            &mut self.owner,
+            &mut self.admin,
+            &mut self.operator,
+            &mut self.membera,
+            &mut self.memberb,
+        ]
+        .into_iter()
+        .zip(addrs)
+        {
+            let mut team = user.client.team(team_id);
+            for peer in addrs.into_iter().filter(|peer| *peer != addr) {
+                team.add_sync_peer(peer.into(), sync_interval).await?;
+            }
+        }
+
+        let mut owner_team = self.owner.client.team(team_id);
+        let mut admin_team = self.admin.client.team(team_id);
+        let mut operator_team = self.operator.client.team(team_id);
+
+        // add admin to team.
+        info!("adding admin to team");
+        owner_team.add_device_to_team(self.admin.pk.clone()).await?;
+        owner_team.assign_role(self.admin.id, Role::Admin).await?;
+
+        // wait for syncing.
+        sleep(sleep_interval).await;
+
+        // add operator to team.
+        info!("adding operator to team");
+        owner_team
+            .add_device_to_team(self.operator.pk.clone())
+            .await?;
+
+        // wait for syncing.
+        sleep(sleep_interval).await;
+
+        admin_team
+            .assign_role(self.operator.id, Role::Operator)
+            .await?;
+
+        // wait for syncing.
+        sleep(sleep_interval).await;
+
+        // add members to team.
+        info!("adding members to team");
+        operator_team
+            .add_device_to_team(self.membera.pk.clone())
+            .await?;
+        operator_team
+            .add_device_to_team(self.memberb.pk.clone())
+            .await?;
+
+        // wait for syncing.
+        sleep(sleep_interval).await;
+
+        // operator assigns labels for AFC channels.
+        operator_team.create_label(label).await?;
+        operator_team.assign_label(self.membera.id, label).await?;
+        operator_team.assign_label(self.memberb.id, label).await?;
+
+        // assign network addresses.
+        operator_team
+            .assign_net_identifier(self.membera.id, NetIdentifier(membera_afc_addr.to_string()))
+            .await?;
+        operator_team
+            .assign_net_identifier(self.memberb.id, NetIdentifier(memberb_afc_addr.to_string()))
+            .await?;
+
+        // wait for syncing.
+        sleep(sleep_interval).await;
+
+        Ok(team_id)
+    }
+}
+
 struct UserCtx {
     client: Client,
     pk: KeyBundle,
     id: DeviceId,
     daemon: AbortHandle,
+    daemon_cfg: Config,
+    uds_api_path: PathBuf,
+    shm_path: String,
+    max_chans: usize,
+    router_cfg: RouterConfig,
 }
 
 impl UserCtx {
     pub async fn new(team_name: String, name: String, work_dir: PathBuf) -> Result<Self> {
+        Self::with_config(team_name, name, work_dir, RouterConfig::default()).await
+    }
+
+    pub async fn with_config(
+        team_name: String,
+        name: String,
+        work_dir: PathBuf,
+        mut router_cfg: RouterConfig,
+    ) -> Result<Self> {
         fs::create_dir_all(work_dir.clone()).await?;
 
+        // Relative paths are per user.
+        if let Some(path) = &mut router_cfg.channel_state_path {
+            if path.is_relative() {
+                *path = work_dir.join(&*path);
+            }
+        }
+
         let mut shm_path = format!("/{team_name}_{name}");
         if cfg!(target_os = "macos") && shm_path.len() > 31 {
             // Shrink the size of the team name down to 22 bytes
@@ -214,6 +452,7 @@ impl UserCtx {
             uds_api_path: uds_api_path.clone(),
             pid_file: work_dir.join("pid"),
             sync_addr: Addr::new("localhost", 0)?,
+            sync: SyncConfig::default(),
             afc: AfcConfig {
                 shm_path: shm_path.clone(),
                 unlink_on_startup: true,
@@ -221,29 +460,18 @@ impl UserCtx {
                 create: true,
                 max_chans,
             },
+            policy: PolicyConfig::default(),
         };
-        // Load daemon from config.
-        let daemon = Daemon::load(cfg.clone())
-            .await
-            .context("unable to init daemon")?;
-        // Start daemon.
-        let handle = task::spawn(async move {
-            daemon
-                .run()
-                .await
-                .expect("expected no errors running daemon")
-        })
-        .abort_handle();
-        // give daemon time to setup UDS API.
-        sleep(Duration::from_millis(100)).await;
+        let handle = start_daemon(cfg.clone()).await?;
 
         // Initialize the user library.
         let mut client = (|| {
-            Client::connect(
+            Client::connect_with_config(
                 &uds_api_path,
                 Path::new(&shm_path),
                 max_chans,
                 "localhost:0",
+                router_cfg.clone(),
             )
         })
         .retry(ExponentialBuilder::default())
@@ -260,9 +488,69 @@ impl UserCtx {
             pk,
             id,
             daemon: handle,
+            daemon_cfg: cfg,
+            uds_api_path,
+            shm_path,
+            max_chans,
+            router_cfg,
         })
     }
 
+    /// Replaces the daemon with a new one, as if it had been
+    /// upgraded. The client keeps running.
+    async fn restart_daemon(&mut self) -> Result<()> {
+        self.daemon.abort();
+        // Give the daemon time to stop.
+        sleep(Duration::from_millis(100)).await;
+        self.daemon = start_daemon(self.daemon_cfg.clone()).await?;
+        Ok(())
+    }
+
+    /// Replaces the client with a new one, as if it had been
+    /// restarted. The daemon keeps running.
+    async fn restart_client(&mut self) -> Result<()> {
+        self.client = Client::connect_with_config(
+            &self.uds_api_path,
+            Path::new(&self.shm_path),
+            self.max_chans,
+            "localhost:0",
+            self.router_cfg.clone(),
+        )
+        .await
+        .context("unable to restart client")?;
+        Ok(())
+    }
+
+    /// Replaces the client with a new one that takes over its
+    /// AFC router, as if it had been upgraded in place.
+    async fn upgrade_client(&mut self, handoff_sock: &Path) -> Result<()> {
+        // Stands in for the old client while it hands off.
+        let spare = Client::connect(
+            &self.uds_api_path,
+            Path::new(&self.shm_path),
+            self.max_chans,
+            "localhost:0",
+        )
+        .await?;
+        let old = mem::replace(&mut self.client, spare);
+        let (handed_off, new) = tokio::join!(
+            old.hand_off(handoff_sock),
+            (|| {
+                Client::take_over(
+                    &self.uds_api_path,
+                    Path::new(&self.shm_path),
+                    self.max_chans,
+                    handoff_sock,
+                    self.router_cfg.clone(),
+                )
+            })
+            .retry(ExponentialBuilder::default()),
+        );
+        handed_off.context("unable to hand off client")?;
+        self.client = new.context("unable to take over client")?;
+        Ok(())
+    }
+
     async fn aranya_local_addr(&self) -> Result<SocketAddr> {
         Ok(self.client.aranya_local_addr().await?)
     }
@@ -272,6 +560,21 @@ impl UserCtx {
     }
 }
 
+/// Loads and starts a daemon.
+async fn start_daemon(cfg: Config) -> Result<AbortHandle> {
+    let daemon = Daemon::load(cfg).await.context("unable to init daemon")?;
+    let handle = task::spawn(async move {
+        daemon
+            .run()
+            .await
+            .expect("expected no errors running daemon")
+    })
+    .abort_handle();
+    // give daemon time to setup UDS API.
+    sleep(Duration::from_millis(100)).await;
+    Ok(handle)
+}
+
 impl Drop for UserCtx {
     fn drop(&mut self) {
         self.daemon.abort();
@@ -623,6 +926,7 @@ async fn test_afc_one_way_two_chans() -> Result<()> {
         channel: afc_id1,
         label: label1,
         seq: Seq::ZERO,
+        sent_at: None,
     };
     assert_eq!(got, want);
 
@@ -639,6 +943,7 @@ async fn test_afc_one_way_two_chans() -> Result<()> {
         channel: afc_id2,
         label: label2,
         seq: Seq::ZERO,
+        sent_at: None,
     };
     assert_eq!(got, want);
 
@@ -826,6 +1131,7 @@ async fn test_afc_two_way_one_chan() -> Result<()> {
         channel: afc_id1,
         label: label1,
         seq: Seq::ZERO,
+        sent_at: None,
     };
     assert_eq!(got, want, "a->b");
 
@@ -845,6 +1151,7 @@ async fn test_afc_two_way_one_chan() -> Result<()> {
         channel: afc_id1,
         label: label1,
         seq: Seq::ZERO,
+        sent_at: None,
     };
     let got = team
         .membera
@@ -1042,6 +1349,7 @@ async fn test_afc_monotonic_seq() -> Result<()> {
             channel: afc_id1,
             label: label1,
             seq,
+            sent_at: None,
         };
         assert_eq!(got, want, "a->b");
 
@@ -1062,6 +1370,7 @@ async fn test_afc_monotonic_seq() -> Result<()> {
             channel: afc_id1,
             label: label1,
             seq,
+            sent_at: None,
         };
         let got = team
             .membera
@@ -1073,3 +1382,4260 @@ async fn test_afc_monotonic_seq() -> Result<()> {
 
     Ok(())
 }
+
+/// Tests that `poll_timeout` returns when there are no open
+/// streams or channels.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_poll_timeout_idle() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::new(
+        "test_afc_poll_timeout_idle".into(),
+        "user".into(),
+        work_dir.join("user"),
+    )
+    .await?;
+
+    let got = user.client.poll_timeout(Duration::from_millis(100)).await?;
+    assert!(!got, "nothing should be ready");
+
+    Ok(())
+}
+
+/// Tests that a channel keeps working after its peer's network
+/// identifier is updated.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_update_channel_peer() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_update_channel_peer".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // Same peer, different name.
+    let renamed = NetIdentifier(format!("localhost:{}", memberb_afc_addr.port()));
+    team.membera
+        .client
+        .update_channel_peer(afc_id1, renamed)
+        .await?;
+
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    debug!(msg = msg, "sent message");
+
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    let want = AfcMsg {
+        data: msg.as_bytes().to_vec(),
+        addr: got.addr,
+        channel: afc_id1,
+        label: label1,
+        seq: Seq::ZERO,
+        sent_at: None,
+    };
+    assert_eq!(got, want, "a->b");
+
+    Ok(())
+}
+
+/// Tests that the daemon reports its effective configuration.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_daemon_config() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let user = UserCtx::new(
+        "test_daemon_config".into(),
+        "user".into(),
+        work_dir.join("user"),
+    )
+    .await?;
+
+    let cfg: serde_json::Value = serde_json::from_str(&user.client.daemon_config().await?)?;
+    assert_eq!(cfg["afc"]["max_chans"], 100);
+    assert_eq!(
+        cfg["work_dir"].as_str(),
+        work_dir.join("user").to_str(),
+        "{cfg:#}"
+    );
+
+    Ok(())
+}
+
+/// Tests that the daemon reports its cipher suite.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_daemon_cipher_suite() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::new(
+        "test_daemon_cipher_suite".into(),
+        "user".into(),
+        work_dir.join("user"),
+    )
+    .await?;
+
+    let suite = user.client.cipher_suite().await?;
+    assert_eq!(suite, CipherSuiteInfo::current());
+    assert_eq!(suite.aead_key_size, 32, "{suite:?}");
+
+    Ok(())
+}
+
+/// Tests that the client reconnects to a restarted daemon.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_daemon_reconnect() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::new(
+        "test_daemon_reconnect".into(),
+        "user".into(),
+        work_dir.join("user"),
+    )
+    .await?;
+    assert_eq!(user.client.daemon_status(), DaemonStatus::Connected);
+
+    user.restart_daemon().await?;
+
+    // The client notices that the connection broke, then
+    // reconnects while it is polled. Until then, calls to the
+    // daemon fail.
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let id = loop {
+        assert!(time::Instant::now() < deadline);
+        user.client.poll_timeout(Duration::from_millis(50)).await?;
+        if let Ok(id) = user.client.get_device_id().await {
+            break id;
+        }
+    };
+    assert_eq!(id, user.id);
+    assert_eq!(user.client.daemon_status(), DaemonStatus::Connected);
+
+    Ok(())
+}
+
+/// Tests that retried messages with the same idempotency token
+/// are reported as duplicates instead of being delivered twice.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_idempotency_token() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_idempotency_token".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let token = IdempotencyToken::random();
+    let msg = "a to b";
+    for _ in 0..2 {
+        team.membera
+            .client
+            .send_data_with_options(
+                afc_id1,
+                msg.as_bytes(),
+                SendOptions::default().with_token(token),
+            )
+            .await?;
+    }
+
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert_eq!(got.seq, Seq::ZERO);
+    assert!(
+        team.memberb.client.try_recv_data().is_none(),
+        "retry should not be delivered"
+    );
+
+    let event = team
+        .memberb
+        .client
+        .try_recv_event()
+        .expect("should have an event");
+    assert!(
+        matches!(event, AfcEvent::ChannelOpened { channel, .. } if channel == afc_id1),
+        "{event:?}"
+    );
+    let event = team
+        .memberb
+        .client
+        .try_recv_event()
+        .expect("should have an event");
+    assert_eq!(
+        event,
+        AfcEvent::Duplicate {
+            addr: got.addr,
+            channel: afc_id1,
+            token,
+            seq: Seq::new(1),
+        }
+    );
+
+    Ok(())
+}
+
+/// Tests per-channel statistics.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_stats() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_channel_stats".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats, ChannelStats::default());
+
+    for msg in ["hello", "world!"] {
+        team.membera
+            .client
+            .send_data(afc_id1, msg.as_bytes())
+            .await?;
+    }
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.msgs_sealed, 2);
+    assert_eq!(stats.bytes_sent, 11);
+    assert!(stats.last_activity.is_some());
+
+    let channels = team.memberb.client.list_channels();
+    assert_eq!(channels.len(), 1);
+    let summary = &channels[0];
+    assert_eq!(summary.label, label1);
+    assert_eq!(summary.transport, Transport::Tcp);
+    assert_eq!(summary.stats.msgs_opened, 2);
+    assert_eq!(summary.stats.bytes_received, 11);
+    assert_eq!(summary.stats.last_seq, Some(Seq::new(1)));
+    assert_eq!(summary.stats.replays_rejected, 0);
+
+    Ok(())
+}
+
+/// Tests that channels are rate limited when sending and
+/// receiving.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_rate_limit() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        rate_limit: Some(RateLimit {
+            msgs_per_sec: NonZeroU32::MIN,
+            burst: 2,
+            max_wait: Duration::ZERO,
+        }),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_rate_limit".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    for _ in 0..2 {
+        team.membera.client.send_data(afc_id1, b"hello").await?;
+    }
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, b"hello")
+        .await
+        .expect_err("should be rate limited");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::RateLimited(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+    assert_eq!(
+        team.membera.client.afc_channel_stats(afc_id1)?.rate_limited,
+        1
+    );
+
+    // Lift the sender's limit so that it outpaces the receiver.
+    team.membera.client.set_channel_rate_limit(afc_id1, None)?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+
+    let err = loop {
+        let data = time::timeout(Duration::from_secs(5), team.memberb.client.poll_data()).await??;
+        if let Err(err) = team.memberb.client.handle_data(data).await {
+            break err;
+        }
+    };
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::RateLimited(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+    assert_eq!(
+        iter::from_fn(|| team.memberb.client.try_recv_data()).count(),
+        2
+    );
+    let channels = team.memberb.client.list_channels();
+    assert_eq!(channels[0].stats.rate_limited, 1);
+
+    Ok(())
+}
+
+/// Tests that label options apply to the channels created with
+/// the label and the channels accepted with it.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_label_options() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let label1 = Label::new(1);
+    let cfg = RouterConfig {
+        label_options: BTreeMap::from([(
+            label1,
+            LabelOptions {
+                rate_limit: Some(RateLimit {
+                    msgs_per_sec: NonZeroU32::MIN,
+                    burst: 1,
+                    max_wait: Duration::ZERO,
+                }),
+                priority: Priority::High,
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_label_options".into(), work_dir, cfg).await?;
+
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, b"hello")
+        .await
+        .expect_err("should be rate limited");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::RateLimited(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+
+    // The accepted channel has the same limit.
+    team.membera.client.set_channel_rate_limit(afc_id1, None)?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    let err = loop {
+        let data = time::timeout(Duration::from_secs(5), team.memberb.client.poll_data()).await??;
+        if let Err(err) = team.memberb.client.handle_data(data).await {
+            break err;
+        }
+    };
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::RateLimited(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+    assert_eq!(
+        iter::from_fn(|| team.memberb.client.try_recv_data()).count(),
+        1
+    );
+
+    Ok(())
+}
+
+/// Tests sending data over every channel with a label.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_send_data_by_label() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_send_data_by_label".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let peer = NetIdentifier(memberb_afc_addr.to_string());
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer.clone(), label1)
+        .await?;
+    let afc_id2 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer, label1)
+        .await?;
+    let mut want = vec![afc_id1, afc_id2];
+    want.sort();
+
+    let results = team
+        .membera
+        .client
+        .send_data_by_label(label1, b"hello")
+        .await;
+    assert_eq!(results.keys().copied().collect::<Vec<_>>(), want);
+    for (id, result) in results {
+        result.with_context(|| format!("unable to send over {id}"))?;
+    }
+
+    // Channels with other labels are skipped.
+    let results = team
+        .membera
+        .client
+        .send_data_by_label(Label::new(2), b"hello")
+        .await;
+    assert!(results.is_empty());
+
+    let mut msgs = Vec::new();
+    time::timeout(Duration::from_secs(5), async {
+        while msgs.len() < 2 {
+            team.memberb.client.poll().await?;
+            msgs.extend(iter::from_fn(|| team.memberb.client.try_recv_data()));
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+    let mut got = msgs.iter().map(|msg| msg.channel).collect::<Vec<_>>();
+    got.sort();
+    assert_eq!(got, want);
+    assert!(msgs.iter().all(|msg| msg.data == b"hello"));
+
+    Ok(())
+}
+
+/// Tests that requests are matched with their responses and
+/// that late responses are dropped.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_request_response() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_request_response".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let afc_id = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let a = &mut team.membera.client;
+    let b = &mut team.memberb.client;
+
+    let resp = tokio::select! {
+        resp = a.request(afc_id, b"ping", Duration::from_secs(5)) => resp?,
+        err = b.serve(|req| async move {
+            assert_eq!(req.label, label1);
+            Some([b"re: ", &req.data[..]].concat())
+        }) => anyhow::bail!("serve failed: {err:?}"),
+    };
+    assert_eq!(resp.data, b"re: ping");
+    // Requests and responses are not delivered as data.
+    assert!(a.try_recv_data().is_none());
+    assert!(b.try_recv_data().is_none());
+
+    // Nobody answers, so the request times out.
+    let err = a
+        .request(afc_id, b"late", Duration::from_millis(200))
+        .await
+        .expect_err("request should time out");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::RequestTimeout(id)) if *id == afc_id),
+        "{err}"
+    );
+
+    let req = time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(req) = b.try_recv_request() {
+                return Ok::<_, anyhow::Error>(req);
+            }
+            b.poll().await?;
+        }
+    })
+    .await??;
+    assert_eq!((req.channel, &req.data[..]), (afc_id, &b"late"[..]));
+    b.respond(&req, b"too late").await?;
+
+    // So the response is dropped.
+    let dropped = time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(request) = iter::from_fn(|| a.try_recv_event()).find_map(|e| match e {
+                AfcEvent::ResponseDropped {
+                    channel, request, ..
+                } if channel == afc_id => Some(request),
+                _ => None,
+            }) {
+                return Ok::<_, anyhow::Error>(request);
+            }
+            a.poll().await?;
+        }
+    })
+    .await??;
+    assert_eq!(dropped, req.id);
+    assert!(a.try_recv_data().is_none());
+
+    Ok(())
+}
+
+/// Tests that channels expire after their maximum number of
+/// messages or their maximum age.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_expiry() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        expiry: Some(ChannelExpiry {
+            max_msgs: Some(2),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_channel_expiry".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"one").await?;
+    team.membera.client.send_data(afc_id1, b"two").await?;
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, b"three")
+        .await
+        .expect_err("channel should be expired");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::ChannelExpired(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+    let events = iter::from_fn(|| team.membera.client.try_recv_event()).collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [AfcEvent::ExpiryReached {
+            channel: afc_id1,
+            reason: ExpiryReason::MaxMsgs,
+        }]
+    );
+
+    // Opening messages counts too.
+    let mut received = 0;
+    time::timeout(Duration::from_secs(5), async {
+        while received < 2 {
+            team.memberb.client.poll().await?;
+            received += iter::from_fn(|| team.memberb.client.try_recv_data()).count();
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+    assert!(
+        iter::from_fn(|| team.memberb.client.try_recv_event()).any(|event| event
+            == AfcEvent::ExpiryReached {
+                channel: afc_id1,
+                reason: ExpiryReason::MaxMsgs,
+            })
+    );
+
+    // Expire a channel by age and have it deleted.
+    let afc_id2 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.set_channel_expiry(
+        afc_id2,
+        Some(ChannelExpiry {
+            max_age: Some(Duration::from_millis(200)),
+            max_msgs: None,
+            delete: true,
+        }),
+    )?;
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            team.membera.client.poll().await?;
+            let expired = iter::from_fn(|| team.membera.client.try_recv_event()).any(|event| {
+                event
+                    == AfcEvent::ExpiryReached {
+                        channel: afc_id2,
+                        reason: ExpiryReason::MaxAge,
+                    }
+            });
+            if expired {
+                break;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+    team.membera
+        .client
+        .channel_info(afc_id2)
+        .expect_err("expired channel should be deleted");
+
+    // The expiry policy can be set when the channel is created.
+    let afc_id3 = team
+        .membera
+        .client
+        .create_bidi_channel_with_options(
+            team_id,
+            NetIdentifier(memberb_afc_addr.to_string()),
+            label1,
+            ChannelOptions::default().with_expiry(ChannelExpiry {
+                max_age: Some(Duration::from_millis(200)),
+                max_msgs: None,
+                delete: false,
+            }),
+        )
+        .await?;
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            team.membera.client.poll().await?;
+            let expired = iter::from_fn(|| team.membera.client.try_recv_event()).any(|event| {
+                event
+                    == AfcEvent::ExpiryReached {
+                        channel: afc_id3,
+                        reason: ExpiryReason::MaxAge,
+                    }
+            });
+            if expired {
+                break;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Tests that a sender waits for a slow reader's flow control
+/// window.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_flow_control() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        flow_control: Some(FlowControl {
+            window: 4,
+            max_wait: Duration::from_millis(200),
+        }),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_flow_control".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let afc_id = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let a = &mut team.membera.client;
+    let b = &mut team.memberb.client;
+
+    // The first message tells `b` where `a`'s sequence numbers
+    // start, so that it can advertise its window.
+    a.send_data(afc_id, b"0").await?;
+    sleep(Duration::from_millis(200)).await;
+    do_poll!(b);
+    sleep(Duration::from_millis(200)).await;
+    do_poll!(a);
+
+    // `b` has not read anything, so `a` can only send three
+    // more messages.
+    for data in [b"1", b"2", b"3"] {
+        a.send_data(afc_id, data).await?;
+    }
+    let err = a
+        .send_data(afc_id, b"4")
+        .await
+        .expect_err("window should be exhausted");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::WindowExhausted(id)) if *id == afc_id),
+        "{err}"
+    );
+
+    // Reading the messages opens the window again.
+    sleep(Duration::from_millis(200)).await;
+    do_poll!(b);
+    let got = iter::from_fn(|| b.try_recv_data())
+        .map(|msg| msg.data)
+        .collect::<Vec<_>>();
+    assert_eq!(got, ["0", "1", "2", "3"].map(|s| s.as_bytes().to_vec()));
+    do_poll!(b);
+
+    a.send_data(afc_id, b"4").await?;
+    sleep(Duration::from_millis(200)).await;
+    do_poll!(b);
+    assert_eq!(b.try_recv_data().map(|msg| msg.data), Some(b"4".to_vec()));
+
+    Ok(())
+}
+
+/// Tests that the channel cap rejects new channels until an
+/// expired channel can be evicted.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_limits() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        channel_limits: Some(ChannelLimits {
+            max_chans: Some(1),
+            at_capacity: CapacityPolicy::EvictExpired,
+            ..Default::default()
+        }),
+        expiry: Some(ChannelExpiry {
+            max_msgs: Some(1),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_channel_limits".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let peer = NetIdentifier(memberb_afc_addr.to_string());
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer.clone(), label1)
+        .await?;
+
+    // The channel has not expired, so it cannot be evicted.
+    let err = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer.clone(), label1)
+        .await
+        .expect_err("should be at capacity");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::Capacity(_))),
+        "{err:?}"
+    );
+
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    let afc_id2 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer, label1)
+        .await?;
+    assert_ne!(afc_id1, afc_id2);
+    assert!(iter::from_fn(|| team.membera.client.try_recv_event())
+        .any(|event| event == AfcEvent::ChannelEvicted { channel: afc_id1 }));
+    team.membera
+        .client
+        .channel_info(afc_id1)
+        .expect_err("evicted channel should be deleted");
+    team.membera.client.channel_info(afc_id2)?;
+
+    Ok(())
+}
+
+/// Tests that keep-alive pings detect a peer that stops
+/// answering and that answering again revives the channel.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_keepalive() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        keepalive: Some(KeepAlive {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(100),
+            max_missed: 2,
+        }),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_keepalive".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // Both peers poll, so pings are answered.
+    let stats = time::timeout(Duration::from_secs(5), async {
+        loop {
+            tokio::select! {
+                data = team.membera.client.poll_data() => {
+                    team.membera.client.handle_data(data?).await?
+                }
+                data = team.memberb.client.poll_data() => {
+                    team.memberb.client.handle_data(data?).await?
+                }
+            }
+            let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+            if stats.rtt.is_some() {
+                break Ok::<_, anyhow::Error>(stats);
+            }
+        }
+    })
+    .await??;
+    assert_eq!(stats.liveness, Liveness::Alive);
+    // The first ping seeds the smoothed RTT.
+    assert_eq!(stats.srtt, stats.rtt);
+
+    // B stops polling, so its pings go unanswered.
+    let mut seen = Vec::new();
+    time::timeout(Duration::from_secs(5), async {
+        while !seen.contains(&Liveness::Unreachable) {
+            team.membera.client.poll().await?;
+            while let Some(event) = team.membera.client.try_recv_event() {
+                if let AfcEvent::LivenessChanged { channel, liveness } = event {
+                    assert_eq!(channel, afc_id1);
+                    seen.push(liveness);
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+    assert_eq!(seen, [Liveness::Degraded, Liveness::Unreachable]);
+    let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.liveness, Liveness::Unreachable);
+    assert!(stats.pings_missed >= 2);
+
+    // B answers again.
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            tokio::select! {
+                data = team.membera.client.poll_data() => {
+                    team.membera.client.handle_data(data?).await?
+                }
+                data = team.memberb.client.poll_data() => {
+                    team.memberb.client.handle_data(data?).await?
+                }
+            }
+            if let Some(AfcEvent::LivenessChanged { channel, liveness }) =
+                team.membera.client.try_recv_event()
+            {
+                assert_eq!((channel, liveness), (afc_id1, Liveness::Alive));
+                break Ok::<_, anyhow::Error>(());
+            }
+        }
+    })
+    .await??;
+
+    Ok(())
+}
+
+/// Tests that the integrity self-check is negotiated when the
+/// channel is created and does not affect delivery.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_self_check() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        self_check: true,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_self_check".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert!(matches!(
+        team.memberb.client.try_recv_event(),
+        Some(AfcEvent::ChannelOpened { .. })
+    ));
+    assert!(team.memberb.client.try_recv_event().is_none());
+
+    for user in [&team.membera, &team.memberb] {
+        let channels = user.client.list_channels();
+        assert_eq!(channels.len(), 1);
+        assert!(channels[0].self_check);
+        assert_eq!(channels[0].stats.checksum_mismatches, 0);
+    }
+
+    Ok(())
+}
+
+/// Tests that messages carry authenticated timestamps when
+/// enabled.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_send_timestamps() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        send_timestamps: true,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_send_timestamps".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    // Timestamps have microsecond precision.
+    let before = SystemTime::now() - Duration::from_micros(1);
+    team.membera
+        .client
+        .send_data(afc_id1, "a to b".as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, "a to b".as_bytes());
+    let sent_at = got.sent_at.expect("should have a timestamp");
+    assert!(sent_at >= before, "{sent_at:?} < {before:?}");
+    assert!(sent_at <= SystemTime::now());
+
+    let channels = team.memberb.client.list_channels();
+    assert_eq!(channels[0].stats.timestamps_rejected, 0);
+    // Both peers share a clock, so the latency is at most the
+    // time since the message was sent.
+    let latency = channels[0].stats.latency.expect("should have a latency");
+    assert!(latency <= SystemTime::now().duration_since(before)?);
+    // A has not received any messages.
+    let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.latency, None);
+
+    Ok(())
+}
+
+/// Tests opening streams from a configured local address.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_outbound() -> Result<()> {
+    use std::net::Ipv4Addr;
+
+    use aranya_client::Outbound;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        outbound: Outbound {
+            local_ip: Some(Ipv4Addr::LOCALHOST.into()),
+            dscp: Some(16),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_outbound".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera
+        .client
+        .send_data(afc_id1, "a to b".as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, "a to b".as_bytes());
+    assert_eq!(got.addr.ip(), Ipv4Addr::LOCALHOST);
+
+    Ok(())
+}
+
+/// Tests resolving peers' network identifiers with a static
+/// hosts map.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_static_resolver() -> Result<()> {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use aranya_client::StaticResolver;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let resolver = StaticResolver::new().with_host("memberb.test", Ipv4Addr::LOCALHOST.into());
+    let cfg = RouterConfig {
+        resolver: Some(Arc::new(resolver)),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_static_resolver".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let peer = NetIdentifier(format!("memberb.test:{}", memberb_afc_addr.port()));
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer, label1)
+        .await?;
+    team.membera
+        .client
+        .send_data(afc_id1, "a to b".as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, "a to b".as_bytes());
+
+    // Hosts that are not in the map cannot be resolved.
+    team.membera
+        .client
+        .create_bidi_channel(
+            team_id,
+            NetIdentifier(format!("localhost:{}", memberb_afc_addr.port())),
+            label1,
+        )
+        .await
+        .expect_err("host should not resolve");
+
+    Ok(())
+}
+
+/// Tests that data larger than the configured maximum message
+/// size is rejected when it is sent.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_max_msg_size() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        max_msg_size: RouterConfig::MIN_MAX_MSG_SIZE,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_max_msg_size".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let limits = team.membera.client.limits();
+    assert_eq!(limits.max_msg_size, RouterConfig::MIN_MAX_MSG_SIZE);
+    let max = limits.max_data_size;
+
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, &vec![0u8; 2 * max])
+        .await
+        .expect_err("message should be too large");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::MsgTooLarge { .. })),
+        "{err}"
+    );
+
+    // Messages within the limit are still delivered.
+    team.membera
+        .client
+        .send_data(afc_id1, &vec![0u8; max])
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data.len(), max);
+
+    Ok(())
+}
+
+/// Tests that data larger than the maximum message size can be
+/// sent as a stream.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_send_stream() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        max_msg_size: RouterConfig::MIN_MAX_MSG_SIZE,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_send_stream".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut progress = Vec::new();
+    let sent = team
+        .membera
+        .client
+        .send_stream_with_progress(afc_id1, &data[..], |n| progress.push(n))
+        .await?;
+    assert_eq!(sent, data.len() as u64);
+    assert!(progress.len() > 1, "should be sent in fragments");
+    assert!(progress.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(progress.last(), Some(&sent));
+
+    // Streams and other messages are delivered in order.
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    team.membera.client.send_stream(afc_id1, &[][..]).await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have the stream");
+    assert_eq!(got.data, data);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, b"hello");
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have the empty stream");
+    assert!(got.data.is_empty());
+    assert!(team.memberb.client.try_recv_data().is_none());
+    assert!(matches!(
+        team.memberb.client.try_recv_event(),
+        Some(AfcEvent::ChannelOpened { .. })
+    ));
+    assert!(team.memberb.client.try_recv_event().is_none());
+
+    Ok(())
+}
+
+/// Tests that sending and receiving data does not allocate in
+/// bounded-memory mode and that the bounds are enforced.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_bounded_memory() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    const MAX_QUEUED_MSGS: usize = 4;
+    let cfg = RouterConfig {
+        max_msg_size: 16 * 1024,
+        bounded: Some(MemoryBounds {
+            max_queued_msgs: MAX_QUEUED_MSGS,
+            ..Default::default()
+        }),
+        self_check: true,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_bounded_memory".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // Setting up the channel and the stream allocates.
+    team.membera.client.send_data(afc_id1, b"warm up").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let msg = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    team.memberb.client.recycle(msg);
+
+    let max = team.membera.client.limits().max_data_size;
+    let data = vec![0x42u8; max];
+    for i in 0..(2 * MAX_QUEUED_MSGS) {
+        let token = IdempotencyToken::new([u8::try_from(i).unwrap(); 16]);
+        assert_no_alloc(team.membera.client.send_data_with_options(
+            afc_id1,
+            &data,
+            SendOptions::default().with_token(token),
+        ))
+        .await?;
+        let poll = assert_no_alloc(team.memberb.client.poll_data()).await?;
+        assert_no_alloc(team.memberb.client.handle_data(poll)).await?;
+        let msg = assert_no_alloc(async { team.memberb.client.try_recv_data() })
+            .await
+            .expect("should have a message");
+        assert_eq!(msg.data, data);
+        assert_no_alloc(async { team.memberb.client.recycle(msg) }).await;
+    }
+
+    // Data that does not fit in the preallocated buffers is
+    // rejected.
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, &vec![0u8; max + 1])
+        .await
+        .expect_err("message should be too large");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::MsgTooLarge { .. })),
+        "{err}"
+    );
+
+    // Messages beyond the queue's bound are dropped.
+    for _ in 0..=MAX_QUEUED_MSGS {
+        team.membera.client.send_data(afc_id1, b"hello").await?;
+    }
+    let mut errs = 0;
+    for _ in 0..=MAX_QUEUED_MSGS {
+        let poll = team.memberb.client.poll_data().await?;
+        match team.memberb.client.handle_data(poll).await {
+            Ok(()) => {}
+            Err(err) if matches!(err.inner(), Error::Afc(AfcError::Capacity(_))) => errs += 1,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    assert_eq!(errs, 1);
+    for _ in 0..MAX_QUEUED_MSGS {
+        let msg = team
+            .memberb
+            .client
+            .try_recv_data()
+            .expect("should have a message");
+        team.memberb.client.recycle(msg);
+    }
+
+    Ok(())
+}
+
+/// Tests that a peer with a verified inbound stream is sent
+/// control messages over that stream instead of being dialed.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_reuse_inbound_stream() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_reuse_inbound_stream".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    // `membera` dials `memberb`, which verifies the stream when
+    // it accepts the control message.
+    team.membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let afc_id2 = team
+        .memberb
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(membera_afc_addr.to_string()), label1)
+        .await?;
+    let msg = "b to a";
+    team.memberb
+        .client
+        .send_data(afc_id2, msg.as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .membera
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert_eq!(
+        team.membera.client.router_stats().accepted,
+        0,
+        "`memberb` should not have dialed `membera`"
+    );
+
+    Ok(())
+}
+
+/// Tests that data for a recently removed channel is dropped
+/// and that the peer is told about it once.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_removed_channel() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_removed_channel".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera
+        .client
+        .send_data(afc_id1, "hello".as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    team.memberb.client.delete_channel(got.channel).await?;
+
+    for _ in 0..2 {
+        team.membera
+            .client
+            .send_data(afc_id1, "dropped".as_bytes())
+            .await?;
+    }
+    do_poll!(team.membera.client, team.memberb.client);
+
+    assert!(team.memberb.client.try_recv_data().is_none());
+    assert_eq!(team.memberb.client.router_stats().removed_channel_drops, 2);
+
+    let event = team
+        .membera
+        .client
+        .try_recv_event()
+        .expect("should have an event");
+    assert!(
+        matches!(
+            event,
+            AfcEvent::ChannelRemovedByPeer { channel, .. } if channel == afc_id1
+        ),
+        "{event:?}"
+    );
+    assert!(
+        team.membera.client.try_recv_event().is_none(),
+        "peer should only be notified once"
+    );
+
+    Ok(())
+}
+
+/// Tests that a channel whose label is revoked is removed from
+/// the router and reported as revoked.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_revoked() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_channel_revoked".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"a to b").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    assert!(team.memberb.client.try_recv_data().is_some());
+    assert!(team.memberb.client.reconcile_channels().await?.is_empty());
+
+    team.operator
+        .client
+        .team(team_id)
+        .revoke_label(team.memberb.id, label1)
+        .await?;
+
+    // wait for syncing.
+    sleep(Duration::from_millis(600)).await;
+
+    let revoked = team.memberb.client.reconcile_channels().await?;
+    assert_eq!(revoked, [afc_id1]);
+    assert!(team.memberb.client.list_channels().is_empty());
+
+    // membera's end of the channel is torn down too, since its
+    // peer no longer holds the label.
+    let revoked = team.membera.client.reconcile_channels().await?;
+    assert_eq!(revoked, [afc_id1]);
+    assert!(team.membera.client.list_channels().is_empty());
+    let events = iter::from_fn(|| team.memberb.client.try_recv_event()).collect::<Vec<_>>();
+    assert!(
+        events.contains(&AfcEvent::ChannelRevoked { channel: afc_id1 }),
+        "{events:?}"
+    );
+
+    let err = team
+        .memberb
+        .client
+        .send_data(afc_id1, b"b to a")
+        .await
+        .expect_err("channel should be revoked");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::ChannelRevoked(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+
+    Ok(())
+}
+
+/// Tests that revoking a label revokes it from the given device
+/// rather than from the caller.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_revoke_label() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_revoke_label".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    // The operator does not hold the label, so this fails if
+    // it revokes the label from itself.
+    team.operator
+        .client
+        .team(team_id)
+        .revoke_label(team.memberb.id, label1)
+        .await?;
+
+    // wait for syncing.
+    sleep(Duration::from_millis(600)).await;
+
+    // memberb no longer holds the label, so channels with it
+    // cannot use the label.
+    team.membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await
+        .expect_err("memberb's label should be revoked");
+
+    Ok(())
+}
+
+/// Tests creating an invitation, carrying it out-of-band, and
+/// accepting it on another device.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_invitation() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_invitation".into(), work_dir).await?;
+
+    let team_id = team.owner.client.create_team().await?;
+    let owner_addr = team.owner.aranya_local_addr().await?;
+
+    let invitation = team
+        .owner
+        .client
+        .team(team_id)
+        .create_invitation(team.membera.pk.clone(), Vec::new())
+        .await?;
+    assert_eq!(invitation.team_id, team_id);
+    assert_eq!(invitation.inviter, team.owner.id);
+    assert_eq!(invitation.sync_addrs, [Addr::from(owner_addr)]);
+
+    let buf = invitation.to_bytes()?;
+    let invitation = Invitation::from_bytes(&buf)?;
+
+    // Tampering with the invitation invalidates the signature.
+    let mut tampered = invitation.clone();
+    tampered.sync_addrs = vec![Addr::new("localhost", 1)?];
+    team.memberb
+        .client
+        .accept_invitation(tampered, team.owner.pk.clone(), Duration::from_millis(100))
+        .await
+        .expect_err("tampered invitation should be rejected");
+
+    // The invitation is only trusted if it was created by the
+    // device whose key bundle we trust.
+    team.memberb
+        .client
+        .accept_invitation(
+            invitation.clone(),
+            team.membera.pk.clone(),
+            Duration::from_millis(100),
+        )
+        .await
+        .expect_err("invitation from an untrusted device should be rejected");
+
+    let got = team
+        .membera
+        .client
+        .accept_invitation(
+            invitation,
+            team.owner.pk.clone(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    assert_eq!(got, team_id);
+
+    Ok(())
+}
+
+/// Tests that the router sheds incoming streams once too many
+/// handshakes are pending.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_accept_shedding() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::with_config(
+        "test_afc_accept_shedding".into(),
+        "user".into(),
+        work_dir.join("user"),
+        RouterConfig {
+            max_pending_handshakes: 1,
+            ..RouterConfig::default()
+        },
+    )
+    .await?;
+    let addr = user.afc_local_addr().await?;
+
+    // Neither stream sends a hello, so the first one occupies
+    // the only pending handshake slot.
+    let _first = TcpStream::connect(addr).await?;
+    assert!(user.client.poll_timeout(Duration::from_millis(100)).await?);
+    let mut second = TcpStream::connect(addr).await?;
+    assert!(!user.client.poll_timeout(Duration::from_millis(100)).await?);
+
+    // The second stream receives a busy frame and is closed.
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(1), second.read_to_end(&mut buf)).await??;
+    assert!(!buf.is_empty(), "expected a busy frame");
+
+    let stats = user.client.router_stats();
+    assert_eq!(stats.accepted, 2);
+    assert_eq!(stats.shed, 1);
+    assert_eq!(stats.streams, 1);
+    assert_eq!(stats.pending_handshakes, 1);
+
+    Ok(())
+}
+
+/// Tests that incoming streams whose peer does not identify
+/// itself in time are closed, even without stream timeouts.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_handshake_timeout() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::with_config(
+        "test_afc_handshake_timeout".into(),
+        "user".into(),
+        work_dir.join("user"),
+        RouterConfig {
+            handshake_timeout: Duration::from_millis(200),
+            stream_timeouts: None,
+            ..RouterConfig::default()
+        },
+    )
+    .await?;
+    let addr = user.afc_local_addr().await?;
+
+    // The stream never sends a hello.
+    let mut stream = TcpStream::connect(addr).await?;
+    assert!(user.client.poll_timeout(Duration::from_millis(100)).await?);
+    assert_eq!(user.client.router_stats().pending_handshakes, 1);
+
+    // Poll until the stream is closed.
+    let mut buf = Vec::new();
+    let read = stream.read_to_end(&mut buf);
+    tokio::pin!(read);
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            tokio::select! {
+                result = &mut read => {
+                    result?;
+                    return Ok::<_, anyhow::Error>(());
+                }
+                result = user.client.poll_timeout(Duration::from_millis(100)) => {
+                    result?;
+                }
+            }
+        }
+    })
+    .await??;
+
+    let stats = user.client.router_stats();
+    assert_eq!(stats.streams, 0);
+    assert_eq!(stats.pending_handshakes, 0);
+
+    Ok(())
+}
+
+/// Tests that streams that are not AFC are handed to the
+/// foreign handler, untouched.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_foreign_handler() -> Result<()> {
+    use std::sync::Arc;
+
+    use aranya_client::{ForeignConn, ForeignHandler};
+    use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+    #[derive(Debug)]
+    struct Handler(mpsc::UnboundedSender<(ForeignConn, SocketAddr)>);
+
+    impl ForeignHandler for Handler {
+        fn handle(&self, conn: ForeignConn, addr: SocketAddr) {
+            self.0.send((conn, addr)).unwrap();
+        }
+    }
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let cfg = RouterConfig {
+        foreign_handler: Some(Arc::new(Handler(tx))),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_foreign_handler".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+
+    let mut foreign = TcpStream::connect(membera_afc_addr).await?;
+    foreign.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+    assert!(
+        !team
+            .membera
+            .client
+            .poll_timeout(Duration::from_millis(100))
+            .await?
+    );
+    let (conn, addr) = rx.try_recv().expect("should have a foreign stream");
+    assert_eq!(addr, foreign.local_addr()?);
+    let ForeignConn::Tcp(mut conn) = conn else {
+        panic!("expected a plaintext stream: {conn:?}");
+    };
+    let mut buf = [0u8; 16];
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+    assert_eq!(team.membera.client.router_stats().foreign, 1);
+
+    // AFC still works on the same port.
+    let afc_id = team
+        .memberb
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(membera_afc_addr.to_string()), label1)
+        .await?;
+    team.memberb.client.send_data(afc_id, b"hello").await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let got = loop {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.memberb.client, team.membera.client);
+        if let Some(got) = team.membera.client.try_recv_data() {
+            break got;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"hello");
+    assert!(rx.try_recv().is_err());
+
+    Ok(())
+}
+
+/// Tests retrieving the team's role and permission model.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_team_roles() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut user = UserCtx::new(
+        "test_team_roles".into(),
+        "user".into(),
+        work_dir.join("user"),
+    )
+    .await?;
+    let team_id = user.client.create_team().await?;
+
+    let roles = user.client.team_roles(team_id).await?;
+    let get = |role| {
+        roles
+            .iter()
+            .find(|info| info.role == role)
+            .unwrap_or_else(|| panic!("missing {role:?}"))
+    };
+
+    let owner = get(Role::Owner);
+    assert!(owner.allows(Operation::TerminateTeam));
+    assert!(owner.allows(Operation::UpgradePolicy));
+    assert!(!owner.allows(Operation::CreateBidiChannel));
+    assert_eq!(owner.label_scope, LabelScope::None);
+
+    let member = get(Role::Member);
+    assert!(member.allows(Operation::CreateBidiChannel));
+    assert!(!member.allows(Operation::AddMember));
+    assert_eq!(member.label_scope, LabelScope::Assigned);
+
+    Ok(())
+}
+
+/// Tests exporting the team graph.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_export_team_graph() -> Result<()> {
+    use aranya_daemon_api::{ChanOp, GraphFormat, LabelAssignment, TeamGraph};
+
+    let sleep_interval = Duration::from_millis(600);
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_export_team_graph".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    // wait for syncing.
+    sleep(sleep_interval).await;
+
+    let json = team
+        .membera
+        .client
+        .export_team_graph(team_id, GraphFormat::Json)
+        .await?;
+    let graph: TeamGraph = serde_json::from_str(&json)?;
+    assert_eq!(graph.team, team_id);
+    assert_eq!(graph.labels, [label1]);
+    assert_eq!(graph.devices.len(), 5);
+    let get = |id| {
+        graph
+            .devices
+            .iter()
+            .find(|device| device.id == id)
+            .unwrap_or_else(|| panic!("missing {id}"))
+    };
+    let owner = get(team.owner.id);
+    assert_eq!(owner.role, Role::Owner);
+    assert!(owner.labels.is_empty());
+    let membera = get(team.membera.id);
+    assert_eq!(membera.role, Role::Member);
+    assert_eq!(
+        membera.labels,
+        [LabelAssignment {
+            label: label1,
+            op: ChanOp::ReadWrite,
+        }]
+    );
+
+    let dot = team
+        .membera
+        .client
+        .export_team_graph(team_id, GraphFormat::Dot)
+        .await?;
+    assert!(dot.starts_with("digraph "));
+    assert!(dot.contains(&format!("\"{}\" -> \"label 1\"", team.membera.id)));
+
+    Ok(())
+}
+
+/// Tests upgrading, pinning, and rolling back the team's
+/// policy.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_policy_upgrade() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let sync_interval = Duration::from_millis(100);
+    let sleep_interval = sync_interval * 6;
+
+    let mut owner = UserCtx::new(
+        "test_policy_upgrade".into(),
+        "owner".into(),
+        work_dir.join("owner"),
+    )
+    .await?;
+    let mut member = UserCtx::new(
+        "test_policy_upgrade".into(),
+        "member".into(),
+        work_dir.join("member"),
+    )
+    .await?;
+
+    let team_id = owner.client.create_team().await?;
+    let owner_addr = owner.aranya_local_addr().await?;
+    let member_addr = member.aranya_local_addr().await?;
+    owner
+        .client
+        .team(team_id)
+        .add_sync_peer(member_addr.into(), sync_interval)
+        .await?;
+    member
+        .client
+        .team(team_id)
+        .add_sync_peer(owner_addr.into(), sync_interval)
+        .await?;
+    owner
+        .client
+        .team(team_id)
+        .add_device_to_team(member.pk.clone())
+        .await?;
+
+    let info = |active, pinned, available: &[u32]| PolicyInfo {
+        active,
+        pinned,
+        available: available.to_vec(),
+    };
+    assert_eq!(owner.client.policy_info().await?, info(0, None, &[0]));
+
+    // Bundles must compile.
+    owner
+        .client
+        .load_policy(1, "not a policy".into())
+        .await
+        .expect_err("invalid bundle should be rejected");
+
+    let doc = format!("{TEST_POLICY_1}\nVersion 1.\n");
+    owner.client.load_policy(1, doc.clone()).await?;
+    assert_eq!(owner.client.policy_info().await?, info(0, None, &[0, 1]));
+
+    let mut team = owner.client.team(team_id);
+    team.upgrade_policy(2)
+        .await
+        .expect_err("missing bundle should be rejected");
+    team.upgrade_policy(1).await?;
+    assert_eq!(owner.client.policy_info().await?, info(1, None, &[0, 1]));
+
+    // The member switches once it has the bundle.
+    sleep(sleep_interval).await;
+    assert_eq!(member.client.policy_info().await?.active, 0);
+    // It must be the same bundle, not just the same version.
+    member
+        .client
+        .load_policy(1, format!("{TEST_POLICY_1}\nAnother version 1.\n"))
+        .await
+        .expect_err("bundle that differs from the team's should be rejected");
+    member.client.load_policy(1, doc).await?;
+    assert_eq!(member.client.policy_info().await?, info(1, None, &[0, 1]));
+
+    // A pinned daemon ignores the team's upgrades.
+    member.client.pin_policy(Some(0)).await?;
+    assert_eq!(
+        member.client.policy_info().await?,
+        info(0, Some(0), &[0, 1])
+    );
+    owner.client.team(team_id).upgrade_policy(0).await?;
+    owner.client.team(team_id).upgrade_policy(1).await?;
+    sleep(sleep_interval).await;
+    assert_eq!(member.client.policy_info().await?.active, 0);
+
+    // Unpinning catches up with the team.
+    member.client.pin_policy(None).await?;
+    assert_eq!(member.client.policy_info().await?, info(1, None, &[0, 1]));
+
+    // Rolling back is an upgrade to an older version.
+    owner.client.team(team_id).upgrade_policy(0).await?;
+    sleep(sleep_interval).await;
+    assert_eq!(owner.client.policy_info().await?.active, 0);
+    assert_eq!(member.client.policy_info().await?.active, 0);
+
+    Ok(())
+}
+
+/// Tests sending data in both directions over a UDP channel.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_udp_transport() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_udp_transport".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel_with_options(
+            team_id,
+            NetIdentifier(memberb_afc_addr.to_string()),
+            label1,
+            ChannelOptions::default().with_transport(Transport::Udp),
+        )
+        .await?;
+
+    // The control message is sent over TCP.
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    // Give the datagram time to arrive.
+    sleep(Duration::from_millis(100)).await;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert_eq!(got.channel, afc_id1);
+
+    // The peer also uses UDP.
+    let msg = "b to a";
+    team.memberb
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    // Give the datagram time to arrive.
+    sleep(Duration::from_millis(100)).await;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .membera
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert_eq!(got.addr, memberb_afc_addr);
+
+    Ok(())
+}
+
+/// Tests that the router accepts channels on each of its
+/// addresses.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_additional_addrs() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        additional_addrs: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_additional_addrs".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let addrs = team.memberb.client.afc_local_addrs().await?;
+    assert_eq!(addrs.len(), 2);
+    assert_eq!(addrs[0], team.memberb.afc_local_addr().await?);
+    assert_ne!(addrs[0], addrs[1]);
+
+    // Use the additional address.
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel_with_options(
+            team_id,
+            NetIdentifier(addrs[1].to_string()),
+            label1,
+            ChannelOptions::default().with_transport(Transport::Udp),
+        )
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    // Give the datagram time to arrive.
+    sleep(Duration::from_millis(100)).await;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+    assert_eq!(got.channel, afc_id1);
+
+    Ok(())
+}
+
+/// Tests that AFC works when the TCP streams are wrapped in
+/// TLS.
+#[cfg(feature = "tls")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_tls() -> Result<()> {
+    use aranya_client::{
+        rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            RootCertStore,
+        },
+        TlsConfig,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    // Every device shares one certificate issued by a test CA.
+    let ca_key = KeyPair::generate()?;
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key)?;
+    let key = KeyPair::generate()?;
+    let cert =
+        CertificateParams::new(vec!["127.0.0.1".to_string()])?.signed_by(&key, &ca, &ca_key)?;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(ca.der().to_vec()))?;
+    let tls = TlsConfig::mutual(
+        vec![CertificateDer::from(cert.der().to_vec())],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+        roots,
+    )?;
+    let cfg = RouterConfig {
+        tls: Some(tls),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_tls".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    // A peer that never starts its handshake must not stall
+    // memberb's router.
+    let _stalled = TcpStream::connect(memberb_afc_addr).await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+
+    let msg = "b to a";
+    team.memberb
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .membera
+        .client
+        .try_recv_data()
+        .expect("should have a message");
+    assert_eq!(got.data, msg.as_bytes());
+
+    Ok(())
+}
+
+/// Tests that [`Client::afc_events`] drives the router and
+/// yields events and data.
+#[cfg(feature = "stream")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_events() -> Result<()> {
+    use futures_util::StreamExt;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_events".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let msg = "a to b";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+
+    let mut events = pin!(team.memberb.client.afc_events());
+    let timeout = Duration::from_secs(5);
+
+    let event = time::timeout(timeout, events.next())
+        .await?
+        .expect("stream should not end")?;
+    assert!(
+        matches!(event, AfcEvent::ChannelOpened { channel, label, .. }
+            if channel == afc_id1 && label == label1),
+        "{event:?}"
+    );
+    let event = time::timeout(timeout, events.next())
+        .await?
+        .expect("stream should not end")?;
+    let AfcEvent::Data(got) = event else {
+        panic!("expected data, got {event:?}");
+    };
+    assert_eq!(got.channel, afc_id1);
+    assert_eq!(got.data, msg.as_bytes());
+
+    Ok(())
+}
+
+/// Tests that watching the set of channels reports channels
+/// created by either peer, updated, and deleted.
+#[cfg(feature = "stream")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_watch_channels() -> Result<()> {
+    use aranya_client::{ChannelSetDelta, RemovalReason};
+    use futures_util::StreamExt;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_watch_channels".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let mut watch_a = team.membera.client.watch_channels();
+    let mut watch_b = team.memberb.client.watch_channels();
+    let timeout = Duration::from_secs(5);
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let delta = time::timeout(timeout, watch_a.next())
+        .await?
+        .expect("stream should not end");
+    assert!(
+        matches!(&delta, ChannelSetDelta::Added(c) if c.id == afc_id1 && c.label == label1),
+        "{delta:?}"
+    );
+
+    // The peer's channel is added once the control message is
+    // handled.
+    do_poll!(team.membera.client, team.memberb.client);
+    let delta = time::timeout(timeout, watch_b.next())
+        .await?
+        .expect("stream should not end");
+    assert!(
+        matches!(&delta, ChannelSetDelta::Added(c) if c.id == afc_id1),
+        "{delta:?}"
+    );
+
+    // New subscribers start with the current channels.
+    let mut watch_a2 = team.membera.client.watch_channels();
+    let delta = time::timeout(timeout, watch_a2.next())
+        .await?
+        .expect("stream should not end");
+    assert!(
+        matches!(&delta, ChannelSetDelta::Added(c) if c.id == afc_id1),
+        "{delta:?}"
+    );
+
+    let peer = NetIdentifier(format!("localhost:{}", memberb_afc_addr.port()));
+    team.membera
+        .client
+        .update_channel_peer(afc_id1, peer.clone())
+        .await?;
+    team.membera.client.delete_channel(afc_id1).await?;
+    for watch in [&mut watch_a, &mut watch_a2] {
+        let delta = time::timeout(timeout, watch.next())
+            .await?
+            .expect("stream should not end");
+        assert!(
+            matches!(&delta, ChannelSetDelta::Updated(c) if c.id == afc_id1 && c.peer == peer),
+            "{delta:?}"
+        );
+        let delta = time::timeout(timeout, watch.next())
+            .await?
+            .expect("stream should not end");
+        assert!(
+            matches!(
+                delta,
+                ChannelSetDelta::Removed {
+                    channel,
+                    reason: RemovalReason::Deleted,
+                } if channel == afc_id1
+            ),
+            "{delta:?}"
+        );
+    }
+
+    // The stream ends with the client.
+    drop(team);
+    assert!(time::timeout(timeout, watch_b.next()).await?.is_none());
+
+    Ok(())
+}
+
+/// Tests AFC over a simulated network with latency, jitter, and
+/// a partition.
+#[cfg(feature = "sim")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_sim() -> Result<()> {
+    use aranya_client::sim::{LinkConfig, SimNet};
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let net = SimNet::with_seed(42);
+    net.set_default_link(LinkConfig {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        loss: 0.0,
+    });
+    let cfg = RouterConfig {
+        keepalive: Some(KeepAlive {
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_millis(200),
+            max_missed: 2,
+        }),
+        sim: Some(net.clone()),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_sim".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // Polls both clients until `done` returns true.
+    macro_rules! poll_until {
+        ($done:expr) => {
+            time::timeout(Duration::from_secs(10), async {
+                loop {
+                    tokio::select! {
+                        data = team.membera.client.poll_data() => {
+                            team.membera.client.handle_data(data?).await?
+                        }
+                        data = team.memberb.client.poll_data() => {
+                            team.memberb.client.handle_data(data?).await?
+                        }
+                    }
+                    if $done(&mut team) {
+                        break Ok::<_, anyhow::Error>(());
+                    }
+                }
+            })
+            .await??
+        };
+    }
+
+    let msg = "hello";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    // Nothing arrives before the latency has elapsed.
+    do_poll!(team.membera.client, team.memberb.client);
+    assert!(team.memberb.client.try_recv_data().is_none());
+    let mut got = None;
+    poll_until!(|team: &mut TeamCtx| {
+        got = team.memberb.client.try_recv_data();
+        got.is_some()
+    });
+    assert_eq!(got.expect("should have a message").data, msg.as_bytes());
+
+    // A partition makes the peer unreachable.
+    net.partition(membera_afc_addr, memberb_afc_addr);
+    poll_until!(|team: &mut TeamCtx| {
+        team.membera
+            .client
+            .afc_channel_stats(afc_id1)
+            .is_ok_and(|stats| stats.liveness == Liveness::Unreachable)
+    });
+
+    // Healing it lets the channel recover.
+    net.heal(membera_afc_addr, memberb_afc_addr);
+    poll_until!(|team: &mut TeamCtx| {
+        team.membera
+            .client
+            .afc_channel_stats(afc_id1)
+            .is_ok_and(|stats| stats.liveness == Liveness::Alive)
+    });
+    let msg = "world";
+    team.membera
+        .client
+        .send_data(afc_id1, msg.as_bytes())
+        .await?;
+    let mut got = None;
+    poll_until!(|team: &mut TeamCtx| {
+        got = team.memberb.client.try_recv_data();
+        got.is_some()
+    });
+    assert_eq!(got.expect("should have a message").data, msg.as_bytes());
+
+    Ok(())
+}
+
+/// Tests that the replay window tolerates datagrams that jitter
+/// reorders.
+#[cfg(feature = "sim")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_replay_window() -> Result<()> {
+    use aranya_client::sim::{LinkConfig, SimNet};
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let net = SimNet::with_seed(7);
+    let cfg = RouterConfig {
+        replay_window: 64,
+        sim: Some(net.clone()),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_replay_window".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel_with_options(
+            team_id,
+            NetIdentifier(memberb_afc_addr.to_string()),
+            label1,
+            ChannelOptions::default().with_transport(Transport::Udp),
+        )
+        .await?;
+    // The control message is sent over TCP.
+    do_poll!(team.membera.client, team.memberb.client);
+
+    net.set_default_link(LinkConfig {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(50),
+        loss: 0.0,
+    });
+    const N: usize = 20;
+    for i in 0..N {
+        team.membera
+            .client
+            .send_data(afc_id1, format!("msg {i}").as_bytes())
+            .await?;
+    }
+    // Give the datagrams time to arrive.
+    sleep(Duration::from_millis(200)).await;
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let mut got = Vec::new();
+    while let Some(msg) = team.memberb.client.try_recv_data() {
+        got.push(msg.seq);
+    }
+    assert_eq!(got.len(), N);
+    let stats = team.memberb.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.replays_rejected, 0);
+    assert!(stats.reordered > 0);
+    got.sort();
+    got.dedup();
+    assert_eq!(got.len(), N);
+
+    Ok(())
+}
+
+/// Tests that removing a device from the team tears down the
+/// other devices' channels with it without waiting for the next
+/// scheduled sync.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_device_removed() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_device_removed".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"a to b").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    assert!(team.memberb.client.try_recv_data().is_some());
+
+    // Make scheduled syncs too slow to matter so that only the
+    // pushed removal can reach the members.
+    let addrs = [
+        team.owner.aranya_local_addr().await?,
+        team.admin.aranya_local_addr().await?,
+        team.operator.aranya_local_addr().await?,
+        team.membera.aranya_local_addr().await?,
+        team.memberb.aranya_local_addr().await?,
+    ];
+    for (user, addr) in [
+        &mut team.owner,
+        &mut team.admin,
+        &mut team.operator,
+        &mut team.membera,
+        &mut team.memberb,
+    ]
+    .into_iter()
+    .zip(addrs)
+    {
+        let mut t = user.client.team(team_id);
+        for peer in addrs.into_iter().filter(|peer| *peer != addr) {
+            t.add_sync_peer(peer.into(), Duration::from_secs(3600))
+                .await?;
+        }
+    }
+
+    team.operator
+        .client
+        .team(team_id)
+        .remove_device_from_team(team.memberb.id)
+        .await?;
+
+    // wait for the push.
+    sleep(Duration::from_millis(600)).await;
+
+    assert_eq!(team.membera.client.reconcile_channels().await?, [afc_id1]);
+    assert_eq!(team.memberb.client.reconcile_channels().await?, [afc_id1]);
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, b"a to b")
+        .await
+        .expect_err("channel should be revoked");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::ChannelRevoked(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+    assert_eq!(
+        team.membera.client.limits().max_revocation_delay,
+        RouterConfig::default().reconcile_interval,
+    );
+
+    Ok(())
+}
+
+/// Tests that payloads rejected by a label's validator are
+/// dropped and reported.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_payload_validator() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_payload_validator".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    team.memberb
+        .client
+        .set_payload_validator(label1, PayloadValidator::new().prefix(*b"AR"));
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"bad").await?;
+    team.membera.client.send_data(afc_id1, b"AR good").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    do_poll!(team.membera.client, team.memberb.client);
+
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"AR good");
+    assert!(team.memberb.client.try_recv_data().is_none());
+    let events = iter::from_fn(|| team.memberb.client.try_recv_event()).collect::<Vec<_>>();
+    assert!(
+        events.iter().any(|e| matches!(e,
+            AfcEvent::PayloadRejected { channel, reason: PayloadRejection::MissingPrefix, .. }
+                if *channel == afc_id1)),
+        "{events:?}"
+    );
+    let stats = team.memberb.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.payloads_rejected, 1);
+
+    // Without the validator, the payload is delivered.
+    assert!(team
+        .memberb
+        .client
+        .remove_payload_validator(label1)
+        .is_some());
+    team.membera.client.send_data(afc_id1, b"bad").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"bad");
+
+    Ok(())
+}
+
+/// Tests that channels survive a client restart with
+/// `channel_state_path`.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_state() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        channel_state_path: Some("afc-state".into()),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_channel_state".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"before").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"before");
+
+    team.memberb.restart_client().await?;
+    let ids = team
+        .memberb
+        .client
+        .list_channels()
+        .into_iter()
+        .map(|c| c.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [afc_id1]);
+
+    // The restored channel is usable without another control
+    // message.
+    team.memberb.client.send_data(afc_id1, b"after").await?;
+    do_poll!(team.memberb.client, team.membera.client);
+    let got = team
+        .membera
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"after");
+
+    Ok(())
+}
+
+/// Tests that half-open streams are reaped and that channels
+/// reconnect afterwards.
+#[cfg(feature = "sim")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_reap_half_open() -> Result<()> {
+    use aranya_client::sim::SimNet;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let net = SimNet::with_seed(11);
+    let cfg = RouterConfig {
+        stream_timeouts: Some(StreamTimeouts {
+            idle_timeout: Duration::from_millis(200),
+            response_timeout: Duration::from_millis(200),
+            ..Default::default()
+        }),
+        sim: Some(net.clone()),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_reap_half_open".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"before").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    assert!(team.memberb.client.try_recv_data().is_some());
+
+    // The stream goes silent without being closed.
+    net.blackhole(membera_afc_addr, memberb_afc_addr);
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut events = Vec::new();
+    while !events.iter().any(|e| {
+        matches!(
+            e,
+            AfcEvent::PeerDisconnected {
+                reason: DisconnectReason::TimedOut,
+                ..
+            }
+        )
+    }) {
+        assert!(time::Instant::now() < deadline, "{events:?}");
+        team.membera
+            .client
+            .poll_timeout(Duration::from_millis(50))
+            .await?;
+        events.extend(iter::from_fn(|| team.membera.client.try_recv_event()));
+    }
+    let stats = team.membera.client.router_stats();
+    assert_eq!(stats.streams_reaped, 1);
+    assert_eq!(stats.streams, 0);
+
+    // The channel opens a new stream.
+    net.heal_all();
+    team.membera.client.send_data(afc_id1, b"after").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"after");
+
+    Ok(())
+}
+
+/// Tests running a team's channels on a separate router.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_isolate_team() -> Result<()> {
+    use aranya_client::ListenOptions;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_isolate_team".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let mut isolated = team
+        .membera
+        .client
+        .isolate_team(
+            team_id,
+            ListenOptions::default().with_addr("127.0.0.1:0".parse()?),
+        )
+        .await?;
+    assert_ne!(
+        isolated.afc_local_addr().await?,
+        team.membera.afc_local_addr().await?
+    );
+
+    // The original client no longer handles the team.
+    let err = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await
+        .expect_err("team should be isolated");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::TeamNotRouted(id)) if *id == team_id),
+        "{err}"
+    );
+
+    let afc_id1 = isolated
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    isolated.send_data(afc_id1, b"a to b").await?;
+    do_poll!(isolated, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"a to b");
+
+    team.memberb.client.send_data(afc_id1, b"b to a").await?;
+    do_poll!(team.memberb.client, isolated, team.membera.client);
+    let got = isolated.try_recv_data().expect("should have data");
+    assert_eq!(got.data, b"b to a");
+    assert!(team.membera.client.try_recv_data().is_none());
+    assert!(team.membera.client.list_channels().is_empty());
+
+    Ok(())
+}
+
+/// Tests getting and setting a channel's metadata.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_channel_metadata() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_channel_metadata".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let client = &mut team.membera.client;
+    assert!(client.channel_metadata(afc_id1)?.is_empty());
+
+    let old = client.set_channel_metadata(afc_id1, "purpose", "telemetry")?;
+    assert_eq!(old, None);
+    let old = client.set_channel_metadata(afc_id1, "purpose", "control")?;
+    assert_eq!(old.as_deref(), Some("telemetry"));
+    client.set_channel_metadata(afc_id1, "priority", "high")?;
+
+    let got = client.channel_metadata(afc_id1)?;
+    assert_eq!(got.get("purpose").map(String::as_str), Some("control"));
+    assert_eq!(got.get("priority").map(String::as_str), Some("high"));
+    assert_eq!(client.channel_info(afc_id1)?.metadata, *got);
+
+    let old = client.remove_channel_metadata(afc_id1, "priority")?;
+    assert_eq!(old.as_deref(), Some("high"));
+    let old = client.remove_channel_metadata(afc_id1, "priority")?;
+    assert_eq!(old, None);
+    assert_eq!(client.channel_metadata(afc_id1)?.len(), 1);
+
+    // The metadata does not outlive the channel.
+    client.delete_channel(afc_id1).await?;
+    client
+        .channel_metadata(afc_id1)
+        .expect_err("channel should be deleted");
+    client
+        .set_channel_metadata(afc_id1, "purpose", "control")
+        .expect_err("channel should be deleted");
+
+    Ok(())
+}
+
+/// Tests setting, reading, and unsetting device metadata.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_device_metadata() -> Result<()> {
+    let sleep_interval = Duration::from_millis(600);
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_device_metadata".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+    let memberb = team.memberb.id;
+
+    team.operator
+        .client
+        .team(team_id)
+        .set_device_metadata(memberb, "location", "rack 7")
+        .await?;
+    team.operator
+        .client
+        .team(team_id)
+        .set_device_metadata(memberb, "location", "rack 9")
+        .await?;
+
+    // Members cannot set metadata.
+    team.membera
+        .client
+        .team(team_id)
+        .set_device_metadata(memberb, "owner", "mallory")
+        .await
+        .expect_err("members should not be able to set metadata");
+
+    // Keys are checked.
+    team.operator
+        .client
+        .team(team_id)
+        .set_device_metadata(memberb, "", "empty")
+        .await
+        .expect_err("empty keys should be rejected");
+
+    // wait for syncing.
+    sleep(sleep_interval).await;
+
+    let got = team
+        .membera
+        .client
+        .team(team_id)
+        .device_metadata(memberb, "location")
+        .await?;
+    assert_eq!(got.as_deref(), Some("rack 9"));
+    let got = team
+        .membera
+        .client
+        .team(team_id)
+        .device_metadata(memberb, "owner")
+        .await?;
+    assert_eq!(got, None);
+
+    team.admin
+        .client
+        .team(team_id)
+        .unset_device_metadata(memberb, "location")
+        .await?;
+
+    // wait for syncing.
+    sleep(sleep_interval).await;
+
+    let got = team
+        .memberb
+        .client
+        .team(team_id)
+        .device_metadata(memberb, "location")
+        .await?;
+    assert_eq!(got, None);
+
+    Ok(())
+}
+
+/// Tests sending a batch of messages.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_send_data_batch() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_send_data_batch".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let msgs = (0..100)
+        .map(|i| format!("reading {i}").into_bytes())
+        .collect::<Vec<_>>();
+    let payloads = msgs.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    team.membera
+        .client
+        .send_data_batch(afc_id1, &payloads)
+        .await?;
+
+    // Nothing is sent if any payload is too large.
+    let big = vec![0u8; team.membera.client.limits().max_data_size + 1];
+    team.membera
+        .client
+        .send_data_batch(afc_id1, &[b"small".as_slice(), big.as_slice()])
+        .await
+        .expect_err("payload should be too large");
+
+    let mut got = Vec::new();
+    while got.len() < msgs.len() {
+        do_poll!(team.membera.client, team.memberb.client);
+        match team.memberb.client.try_recv_data() {
+            Some(msg) => got.push(msg),
+            None => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    for (i, (got, want)) in got.iter().zip(&msgs).enumerate() {
+        assert_eq!(got.data, *want);
+        assert_eq!(got.channel, afc_id1);
+        assert_eq!(got.seq, Seq::new(u64::try_from(i)?));
+    }
+    assert!(team.memberb.client.try_recv_data().is_none());
+
+    let stats = team.membera.client.afc_channel_stats(afc_id1)?;
+    assert_eq!(stats.msgs_sealed, 100);
+
+    Ok(())
+}
+
+/// Tests feeding messages and flushing them.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_feed_flush() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_feed_flush".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    let msgs = (0..10)
+        .map(|i| format!("reading {i}").into_bytes())
+        .collect::<Vec<_>>();
+    for msg in &msgs[..5] {
+        team.membera.client.feed_data(afc_id1, msg)?;
+    }
+
+    // Payloads that are too large are not queued.
+    let big = vec![0u8; team.membera.client.limits().max_data_size + 1];
+    team.membera
+        .client
+        .feed_data(afc_id1, &big)
+        .expect_err("payload should be too large");
+
+    // Nothing is sent until the channel is flushed.
+    for _ in 0..10 {
+        do_poll!(team.membera.client, team.memberb.client);
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(team.memberb.client.try_recv_data().is_none());
+    assert!(matches!(
+        team.membera.client.try_send_data(afc_id1, b"now"),
+        Err(TrySendError::WouldBlock)
+    ));
+
+    team.membera.client.flush(afc_id1).await?;
+    // Messages fed before a send are sent first.
+    for msg in &msgs[5..9] {
+        team.membera.client.feed_data(afc_id1, msg)?;
+    }
+    team.membera.client.send_data(afc_id1, &msgs[9]).await?;
+    // Flushing an empty channel does nothing.
+    team.membera.client.flush(afc_id1).await?;
+
+    let mut got = Vec::new();
+    while got.len() < msgs.len() {
+        do_poll!(team.membera.client, team.memberb.client);
+        match team.memberb.client.try_recv_data() {
+            Some(msg) => got.push(msg),
+            None => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    for (i, (got, want)) in got.iter().zip(&msgs).enumerate() {
+        assert_eq!(got.data, *want);
+        assert_eq!(got.seq, Seq::new(u64::try_from(i)?));
+    }
+    assert!(team.memberb.client.try_recv_data().is_none());
+
+    Ok(())
+}
+
+/// Tests that errors carry the context of the method that
+/// returned them.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_error_context() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_error_context".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let peer = NetIdentifier(memberb_afc_addr.to_string());
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, peer.clone(), label1)
+        .await?;
+
+    let big = vec![0u8; team.membera.client.limits().max_data_size + 1];
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, &big)
+        .await
+        .expect_err("payload should be too large");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::MsgTooLarge { .. })),
+        "{err:?}"
+    );
+    let ctx = err.context().expect("error should have context");
+    assert_eq!(ctx.operation, "send_data");
+    assert_eq!(ctx.afc_id, Some(afc_id1));
+    assert_eq!(ctx.team_id, Some(team_id));
+    assert_eq!(ctx.peer.as_ref(), Some(&peer));
+    assert_eq!(err.contexts().count(), 1);
+    let msg = err.to_string();
+    assert!(
+        msg.starts_with(&format!(
+            "send_data (afc_id: {afc_id1}, team_id: {team_id}, peer: {peer}"
+        )),
+        "{msg}"
+    );
+
+    // Channels that do not exist only have an ID.
+    team.membera.client.delete_channel(afc_id1).await?;
+    let err = team
+        .membera
+        .client
+        .send_data(afc_id1, b"hello")
+        .await
+        .expect_err("channel should be deleted");
+    let ctx = err.context().expect("error should have context");
+    assert_eq!(ctx.afc_id, Some(afc_id1));
+    assert_eq!(ctx.peer, None);
+
+    Ok(())
+}
+
+/// Tests that clients sharing a daemon get distinct node IDs, so
+/// their channels do not clobber each other's keys.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_shared_daemon_node_ids() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_shared_daemon_node_ids".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    // A second client attached to membera's daemon.
+    let mut other = Client::connect(
+        &team.membera.uds_api_path,
+        Path::new(&team.membera.shm_path),
+        team.membera.max_chans,
+        "localhost:0",
+    )
+    .await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let afc_id2 = other
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    team.membera.client.send_data(afc_id1, b"first").await?;
+    other.send_data(afc_id2, b"second").await?;
+
+    let mut got = Vec::new();
+    while got.len() < 2 {
+        do_poll!(team.membera.client, other, team.memberb.client);
+        match team.memberb.client.try_recv_data() {
+            Some(msg) => got.push((msg.channel, msg.data)),
+            None => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    got.sort();
+    let mut want = vec![(afc_id1, b"first".to_vec()), (afc_id2, b"second".to_vec())];
+    want.sort();
+    assert_eq!(got, want);
+
+    Ok(())
+}
+
+/// Tests that a stream whose queue piles up is reported as
+/// congested, and as drained once the queue is resent.
+#[cfg(feature = "sim")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_stream_congestion() -> Result<()> {
+    use aranya_client::sim::SimNet;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let net = SimNet::with_seed(13);
+    let cfg = RouterConfig {
+        queue_watermarks: Some(QueueWatermarks { high: 3, low: 1 }),
+        reconnect_backoff: Duration::from_millis(10),
+        reconnect_jitter: false,
+        sim: Some(net.clone()),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_stream_congestion".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"before").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    assert!(team.memberb.client.try_recv_data().is_some());
+
+    // Sends fail while the peer is unreachable, so they pile up.
+    net.partition(membera_afc_addr, memberb_afc_addr);
+    for msg in [b"one", b"two", b"six"] {
+        team.membera.client.send_data(afc_id1, msg).await?;
+    }
+    let events = iter::from_fn(|| team.membera.client.try_recv_event()).collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [AfcEvent::StreamCongested {
+            addr: memberb_afc_addr,
+            queued: 3,
+        }]
+    );
+    let stats = team.membera.client.router_stats();
+    assert_eq!(stats.queued, 3);
+    assert_eq!(stats.congested_streams, 1);
+    let stream = team
+        .membera
+        .client
+        .stream_stats()
+        .into_iter()
+        .find(|s| s.addr == memberb_afc_addr)
+        .expect("stream should have stats");
+    assert_eq!(stream.queued, 3);
+    assert!(stream.congested);
+
+    // The queue drains once the peer is reachable again.
+    net.heal_all();
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut events = Vec::new();
+    while !events
+        .iter()
+        .any(|e| matches!(e, AfcEvent::StreamDrained { .. }))
+    {
+        assert!(time::Instant::now() < deadline, "{events:?}");
+        team.membera
+            .client
+            .poll_timeout(Duration::from_millis(50))
+            .await?;
+        events.extend(iter::from_fn(|| team.membera.client.try_recv_event()));
+    }
+    assert!(events.contains(&AfcEvent::StreamDrained {
+        addr: memberb_afc_addr,
+        queued: 0,
+    }));
+    let stats = team.membera.client.router_stats();
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.congested_streams, 0);
+
+    Ok(())
+}
+
+/// Tests that channels work over a custom transport.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_custom_transport() -> Result<()> {
+    use std::{
+        collections::HashMap,
+        io,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    use aranya_client::{AfcConn, AfcTransport, ConnectFuture};
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+        sync::mpsc,
+    };
+
+    type Accepted = (Box<dyn AfcConn>, SocketAddr);
+
+    /// One end of an in-memory stream.
+    #[derive(Debug)]
+    struct MemConn {
+        io: DuplexStream,
+        peer: SocketAddr,
+    }
+
+    impl AfcConn for MemConn {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+    }
+
+    impl AsyncRead for MemConn {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MemConn {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().io).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+        }
+    }
+
+    /// Connects the transports created from it, like a message
+    /// bus would.
+    #[derive(Clone, Debug, Default)]
+    struct Hub(Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Accepted>>>>);
+
+    impl Hub {
+        fn transport(&self, addr: SocketAddr) -> Arc<dyn AfcTransport> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.0.lock().unwrap().insert(addr, tx);
+            Arc::new(MemTransport {
+                hub: self.clone(),
+                addr,
+                rx: Mutex::new(rx),
+            })
+        }
+    }
+
+    /// A transport on a [`Hub`].
+    #[derive(Debug)]
+    struct MemTransport {
+        hub: Hub,
+        addr: SocketAddr,
+        rx: Mutex<mpsc::UnboundedReceiver<Accepted>>,
+    }
+
+    impl AfcTransport for MemTransport {
+        fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_> {
+            Box::pin(async move {
+                let tx = self
+                    .hub
+                    .0
+                    .lock()
+                    .unwrap()
+                    .get(&addr)
+                    .cloned()
+                    .ok_or(io::ErrorKind::ConnectionRefused)?;
+                let (ours, theirs) = tokio::io::duplex(64 * 1024);
+                let theirs = MemConn {
+                    io: theirs,
+                    peer: self.addr,
+                };
+                tx.send((Box::new(theirs), self.addr))
+                    .map_err(|_| io::ErrorKind::ConnectionRefused)?;
+                Ok(Box::new(MemConn {
+                    io: ours,
+                    peer: addr,
+                }) as Box<dyn AfcConn>)
+            })
+        }
+
+        fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Accepted>> {
+            self.rx
+                .lock()
+                .unwrap()
+                .poll_recv(cx)
+                .map(|v| v.ok_or_else(|| io::ErrorKind::NotConnected.into()))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.addr)
+        }
+    }
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_custom_transport".into(), work_dir).await?;
+
+    // The network identifiers are assigned by `setup_afc`, so
+    // switch transports first.
+    let hub = Hub::default();
+    let membera_afc_addr = SocketAddr::from(([10, 0, 0, 1], 4000));
+    let memberb_afc_addr = SocketAddr::from(([10, 0, 0, 2], 4000));
+    for (user, addr) in [
+        (&mut team.membera, membera_afc_addr),
+        (&mut team.memberb, memberb_afc_addr),
+    ] {
+        user.router_cfg.transport = Some(hub.transport(addr));
+        user.restart_client().await?;
+        assert_eq!(user.afc_local_addr().await?, addr);
+    }
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+
+    let got = loop {
+        do_poll!(team.membera.client, team.memberb.client);
+        if let Some(msg) = team.memberb.client.try_recv_data() {
+            break msg;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"hello");
+    assert_eq!(got.addr, membera_afc_addr);
+
+    // And back the other way, over the same stream.
+    team.memberb.client.send_data(afc_id1, b"world").await?;
+    let got = loop {
+        do_poll!(team.membera.client, team.memberb.client);
+        if let Some(msg) = team.membera.client.try_recv_data() {
+            break msg;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"world");
+    assert_eq!(team.membera.client.router_stats().streams, 1);
+
+    Ok(())
+}
+
+/// Tests that a busy router yields to the other tasks on its
+/// worker.
+#[test(tokio::test(flavor = "current_thread"))]
+async fn test_afc_yield_budget() -> Result<()> {
+    use std::sync::Arc;
+
+    const MSGS: usize = 1000;
+    const BUDGET: u32 = 16;
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        yield_budget: BUDGET,
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_yield_budget".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // Send everything before the peer reads anything so that
+    // its stream is always ready.
+    let msgs = (0..MSGS)
+        .map(|i| format!("reading {i}").into_bytes())
+        .collect::<Vec<_>>();
+    for chunk in msgs.chunks(100) {
+        let payloads = chunk.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        team.membera
+            .client
+            .send_data_batch(afc_id1, &payloads)
+            .await?;
+    }
+
+    // A co-located task that counts how often it gets to run.
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticker = task::spawn({
+        let ticks = Arc::clone(&ticks);
+        async move {
+            loop {
+                ticks.fetch_add(1, Ordering::Relaxed);
+                task::yield_now().await;
+            }
+        }
+    });
+
+    // Count how many messages the router handles in a row
+    // without letting the ticker run.
+    let mut got = 0;
+    let mut run = 0;
+    let mut max_run = 0;
+    let mut last = ticks.load(Ordering::Relaxed);
+    while got < MSGS {
+        team.memberb.client.poll().await?;
+        let now = ticks.load(Ordering::Relaxed);
+        if now == last {
+            run += 1;
+        } else {
+            run = 1;
+            last = now;
+        }
+        max_run = max_run.max(run);
+        got += iter::from_fn(|| team.memberb.client.try_recv_data()).count();
+    }
+    ticker.abort();
+
+    assert!(max_run <= usize::try_from(BUDGET)?, "{max_run}");
+    assert!(team.memberb.client.router_stats().yields > 0);
+
+    Ok(())
+}
+
+/// Tests that the router records metrics.
+#[cfg(feature = "metrics")]
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_metrics() -> Result<()> {
+    use aranya_client::metrics;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    // The recorder is global, so other tests might record
+    // metrics too.
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    assert!(recorder.install().is_ok(), "unable to install recorder");
+
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_metrics".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    for msg in [b"one", b"two", b"six"] {
+        team.membera.client.send_data(afc_id1, msg).await?;
+    }
+    let mut got = 0;
+    while got < 3 {
+        do_poll!(team.membera.client, team.memberb.client);
+        match team.memberb.client.try_recv_data() {
+            Some(_) => got += 1,
+            None => sleep(Duration::from_millis(10)).await,
+        }
+    }
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let find = |name: &'static str| {
+        snapshot
+            .iter()
+            .filter(move |(key, ..)| key.key().name() == name)
+            .map(|(.., value)| value)
+    };
+    let count = |name: &'static str| {
+        find(name)
+            .map(|value| match value {
+                DebugValue::Counter(n) => *n,
+                _ => panic!("{name} should be a counter"),
+            })
+            .sum::<u64>()
+    };
+    assert!(count(metrics::MSGS_SENT) >= 3);
+    assert!(count(metrics::MSGS_RECEIVED) >= 3);
+    for name in [
+        metrics::SEAL_LATENCY,
+        metrics::OPEN_LATENCY,
+        metrics::MSG_SIZE,
+    ] {
+        let n = find(name)
+            .map(|value| match value {
+                DebugValue::Histogram(v) => v.len(),
+                _ => panic!("{name} should be a histogram"),
+            })
+            .sum::<usize>();
+        assert!(n >= 3, "{name}");
+    }
+    for name in [metrics::CHANNELS, metrics::STREAMS] {
+        assert!(
+            find(name).any(|value| matches!(value, DebugValue::Gauge(_))),
+            "{name}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Tests that a channel's subscriber receives its data instead of
+/// `try_recv_data`.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_subscribe() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_subscribe".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let afc_id2 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    let mut sub = team.memberb.client.subscribe(afc_id2);
+
+    team.membera.client.send_data(afc_id1, b"one").await?;
+    team.membera.client.send_data(afc_id2, b"two").await?;
+    team.membera.client.send_data(afc_id2, b"six").await?;
+
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut got = Vec::new();
+    let mut subbed = Vec::new();
+    while got.is_empty() || subbed.len() < 2 {
+        assert!(time::Instant::now() < deadline, "{got:?} {subbed:?}");
+        do_poll!(team.membera.client, team.memberb.client);
+        got.extend(iter::from_fn(|| team.memberb.client.try_recv_data()));
+        while let Ok(msg) = sub.try_recv() {
+            subbed.push(msg);
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].channel, afc_id1);
+    assert_eq!(got[0].data, b"one");
+    assert_eq!(
+        subbed,
+        [
+            (b"two".to_vec(), label1, Seq::new(0)),
+            (b"six".to_vec(), label1, Seq::new(1)),
+        ]
+    );
+
+    // Deleting the channel ends the subscription.
+    team.memberb.client.delete_channel(afc_id2).await?;
+    assert!(sub.recv().await.is_none());
+
+    Ok(())
+}
+
+/// Tests that a peer resumes its stream with a session resumption
+/// ticket after reconnecting.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_session_resumption() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        session_resumption: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let mut team =
+        TeamCtx::with_config("test_afc_session_resumption".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // memberb issues a ticket once it verifies membera, which is
+    // before it replies over the same stream.
+    for (i, msg) in [&b"one"[..], b"two"].into_iter().enumerate() {
+        if i > 0 {
+            // membera reconnects and presents the ticket.
+            team.membera.client.shutdown().await;
+        }
+        team.membera.client.send_data(afc_id1, msg).await?;
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        let got = loop {
+            assert!(time::Instant::now() < deadline);
+            do_poll!(team.membera.client, team.memberb.client);
+            if let Some(got) = team.memberb.client.try_recv_data() {
+                break got;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(got.data, msg);
+
+        // membera answers memberb's challenge with the ticket.
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        while team.memberb.client.router_stats().resumptions < i as u64 {
+            assert!(time::Instant::now() < deadline);
+            do_poll!(team.membera.client, team.memberb.client);
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        team.memberb.client.send_data(got.channel, b"ack").await?;
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(time::Instant::now() < deadline);
+            do_poll!(team.memberb.client, team.membera.client);
+            if let Some(got) = team.membera.client.try_recv_data() {
+                assert_eq!(got.data, b"ack");
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    assert_eq!(team.memberb.client.router_stats().resumptions, 1);
+
+    Ok(())
+}
+
+/// Tests that a new client takes over the channels of an old one
+/// without re-creating them or being verified again.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_handoff() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let cfg = RouterConfig {
+        session_resumption: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let mut team = TeamCtx::with_config("test_afc_handoff".into(), work_dir, cfg).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let membera_afc_addr = team.membera.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+
+    // memberb issues a ticket to membera once it verifies it.
+    team.membera.client.send_data(afc_id1, b"before").await?;
+    do_poll!(team.membera.client, team.memberb.client);
+    let got = team
+        .memberb
+        .client
+        .try_recv_data()
+        .expect("should have data");
+    assert_eq!(got.data, b"before");
+    let memberb_afc_id = got.channel;
+
+    team.membera
+        .upgrade_client(&tmp.path().join("handoff.sock"))
+        .await?;
+    assert_eq!(team.membera.afc_local_addr().await?, membera_afc_addr);
+    team.membera.client.channel_info(afc_id1)?;
+
+    // The channel still works in both directions, and the new
+    // client resumes the stream with the old one's ticket.
+    team.membera.client.send_data(afc_id1, b"after").await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let got = loop {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        if let Some(got) = team.memberb.client.try_recv_data() {
+            break got;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"after");
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    while team.memberb.client.router_stats().resumptions < 1 {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(team.memberb.client.router_stats().resumptions, 1);
+
+    team.memberb
+        .client
+        .send_data(memberb_afc_id, b"ack")
+        .await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    loop {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.memberb.client, team.membera.client);
+        if let Some(got) = team.membera.client.try_recv_data() {
+            assert_eq!(got.data, b"ack");
+            assert_eq!(got.channel, afc_id1);
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    Ok(())
+}
+
+/// Tests that shutting down tells peers why their streams are
+/// closed.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_goodbye_shutdown() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_goodbye_shutdown".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    while team.memberb.client.try_recv_data().is_none() {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    team.membera.client.shutdown().await;
+
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut events = Vec::new();
+    while !events.iter().any(|e| {
+        matches!(
+            e,
+            AfcEvent::PeerDisconnected {
+                reason: DisconnectReason::Goodbye(GoodbyeReason::Shutdown),
+                ..
+            }
+        )
+    }) {
+        assert!(time::Instant::now() < deadline, "{events:?}");
+        do_poll!(team.memberb.client);
+        events.extend(iter::from_fn(|| team.memberb.client.try_recv_event()));
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        events.iter().any(|e| matches!(
+            e,
+            AfcEvent::Goodbye {
+                reason: GoodbyeReason::Shutdown,
+                ..
+            }
+        )),
+        "{events:?}"
+    );
+
+    Ok(())
+}
+
+/// Tests that `try_send_data` fails instead of waiting for the
+/// channel's rate limit.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_try_send_data() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_try_send_data".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.set_channel_rate_limit(
+        afc_id1,
+        Some(RateLimit {
+            msgs_per_sec: NonZeroU32::new(1).expect("non-zero"),
+            burst: 1,
+            max_wait: Duration::from_secs(5),
+        }),
+    )?;
+
+    team.membera.client.try_send_data(afc_id1, b"first")?;
+    // The next token is a second away, which `send_data` would
+    // wait for.
+    let err = team
+        .membera
+        .client
+        .try_send_data(afc_id1, b"second")
+        .expect_err("should block");
+    assert!(matches!(err, TrySendError::WouldBlock), "{err:?}");
+
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let got = loop {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        if let Some(got) = team.memberb.client.try_recv_data() {
+            break got;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"first");
+    assert!(team.memberb.client.try_recv_data().is_none());
+    assert_eq!(
+        team.membera.client.afc_channel_stats(afc_id1)?.bytes_sent,
+        5
+    );
+
+    Ok(())
+}
+
+/// Tests that both ends of a stream agree on a wire version.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_version_negotiation() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_version_negotiation".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let membera_id = team.membera.client.get_device_id().await?;
+    let memberb_id = team.memberb.client.get_device_id().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+
+    let newest = SUPPORTED_VERSIONS.first().copied();
+    assert_eq!(newest, Some(Version::V1));
+    // The stream's address differs on each end, so find it by
+    // the peer.
+    let negotiated = |client: &Client, peer| {
+        client
+            .stream_stats()
+            .into_iter()
+            .find(|s| s.peer == Some(peer))
+            .and_then(|s| s.negotiated)
+    };
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    while negotiated(&team.membera.client, memberb_id).is_none()
+        || negotiated(&team.memberb.client, membera_id).is_none()
+    {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(negotiated(&team.membera.client, memberb_id), newest);
+    assert_eq!(negotiated(&team.memberb.client, membera_id), newest);
+
+    Ok(())
+}
+
+/// Tests that a label's messages are dropped while its queue is
+/// full.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_label_queue_limit() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_label_queue_limit".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    team.memberb
+        .client
+        .set_label_schedule(label1, LabelSchedule::new().max_queued(2));
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    for msg in [b"one", b"two", b"six", b"ten"] {
+        team.membera.client.send_data(afc_id1, msg).await?;
+    }
+
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut dropped = Vec::new();
+    while dropped.len() < 2 {
+        assert!(time::Instant::now() < deadline, "{dropped:?}");
+        do_poll!(team.membera.client, team.memberb.client);
+        dropped.extend(
+            iter::from_fn(|| team.memberb.client.try_recv_event())
+                .filter(|e| matches!(e, AfcEvent::LabelQueueFull { .. })),
+        );
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        dropped.iter().all(|e| matches!(e,
+            AfcEvent::LabelQueueFull { channel, label, .. }
+                if *channel == afc_id1 && *label == label1)),
+        "{dropped:?}"
+    );
+    let got = iter::from_fn(|| team.memberb.client.try_recv_data())
+        .map(|msg| msg.data)
+        .collect::<Vec<_>>();
+    assert_eq!(got, [b"one", b"two"]);
+
+    // Reading the queue makes room for more.
+    team.membera.client.send_data(afc_id1, b"new").await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let got = loop {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        if let Some(got) = team.memberb.client.try_recv_data() {
+            break got;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(got.data, b"new");
+
+    Ok(())
+}
+
+/// Tests that deleting a channel removes it from the peer.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_delete_channel_closes_peer() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_delete_channel_closes_peer".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+
+    let afc_id1 = team
+        .membera
+        .client
+        .create_bidi_channel(team_id, NetIdentifier(memberb_afc_addr.to_string()), label1)
+        .await?;
+    team.membera.client.send_data(afc_id1, b"hello").await?;
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    while team.memberb.client.try_recv_data().is_none() {
+        assert!(time::Instant::now() < deadline);
+        do_poll!(team.membera.client, team.memberb.client);
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(team.memberb.client.list_channels().len(), 1);
+
+    team.membera.client.delete_channel(afc_id1).await?;
+
+    let deadline = time::Instant::now() + Duration::from_secs(5);
+    let mut events = Vec::new();
+    while !events
+        .iter()
+        .any(|e| matches!(e, AfcEvent::ChannelClosedByPeer { .. }))
+    {
+        assert!(time::Instant::now() < deadline, "{events:?}");
+        do_poll!(team.membera.client, team.memberb.client);
+        events.extend(iter::from_fn(|| team.memberb.client.try_recv_event()));
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        events.iter().any(|e| matches!(e,
+            AfcEvent::ChannelClosedByPeer { channel, .. } if *channel == afc_id1)),
+        "{events:?}"
+    );
+    assert!(team.memberb.client.list_channels().is_empty());
+
+    let err = team
+        .memberb
+        .client
+        .send_data(afc_id1, b"anyone there?")
+        .await
+        .expect_err("channel should be closed");
+    assert!(
+        matches!(err.inner(), Error::Afc(AfcError::ChannelNotFound(id)) if *id == afc_id1),
+        "{err:?}"
+    );
+
+    Ok(())
+}
+
+/// Tests that the router reports how it resolves peers.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_afc_resolve_debug() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_afc_resolve_debug".into(), work_dir).await?;
+
+    let memberb_afc_addr = team.memberb.afc_local_addr().await?;
+    let peer = NetIdentifier(format!("localhost:{}", memberb_afc_addr.port()));
+
+    let got = team.membera.client.resolve_debug(&peer).await;
+    assert_eq!(got.net_id, peer);
+    assert!(!got.cached);
+    assert!(got.error.is_none(), "{got:?}");
+    assert!(!got.addrs.is_empty());
+    assert_eq!(got.candidate, got.addrs.first().copied());
+    assert!(got.stream.is_none());
+
+    let again = team.membera.client.resolve_debug(&peer).await;
+    assert!(again.cached);
+    assert_eq!(again.addrs, got.addrs);
+
+    let stats = team.membera.client.dns_stats();
+    let stats = stats
+        .iter()
+        .find(|s| s.host == peer.0)
+        .expect("host should have stats");
+    assert_eq!(stats.lookups, 1);
+    assert_eq!(stats.cache_hits, 1);
+    assert_eq!(stats.last_resolved, got.addrs);
+
+    let bad = NetIdentifier("does-not-exist.invalid:1234".into());
+    let got = team.membera.client.resolve_debug(&bad).await;
+    assert!(got.error.is_some(), "{got:?}");
+    assert!(got.addrs.is_empty());
+    assert!(got.candidate.is_none());
+
+    Ok(())
+}
+
+/// Tests that a stuck sync peer does not hold up syncing with
+/// other peers and that its session can be cancelled.
+#[test(tokio::test(flavor = "multi_thread"))]
+async fn test_sync_status_cancel() -> Result<()> {
+    let tmp = tempdir()?;
+    let work_dir = tmp.path().to_path_buf();
+
+    let mut team = TeamCtx::new("test_sync_status_cancel".into(), work_dir).await?;
+
+    let label1 = Label::new(1);
+    let team_id = team.setup_afc(label1).await?;
+    let memberb = team.memberb.id;
+
+    // A peer that accepts connections but never responds.
+    let stuck = TcpListener::bind("127.0.0.1:0").await?;
+    let stuck_addr = Addr::from(stuck.local_addr()?);
+    team.admin
+        .client
+        .team(team_id)
+        .add_sync_peer(stuck_addr, Duration::from_millis(100))
+        .await?;
+
+    let session = time::timeout(Duration::from_secs(5), async {
+        loop {
+            let sessions = team.admin.client.sync_status().await?;
+            if let Some(s) = sessions.into_iter().find(|s| s.addr == stuck_addr) {
+                return anyhow::Ok(s);
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await??;
+    assert_eq!(session.team, team_id);
+    assert_eq!(session.stage, SyncStage::Waiting);
+
+    // The admin still syncs with its other peers.
+    team.operator
+        .client
+        .team(team_id)
+        .set_device_metadata(memberb, "location", "rack 7")
+        .await?;
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            let got = team
+                .admin
+                .client
+                .team(team_id)
+                .device_metadata(memberb, "location")
+                .await?;
+            if got.as_deref() == Some("rack 7") {
+                return anyhow::Ok(());
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await??;
+
+    let cancelled = team
+        .admin
+        .client
+        .team(team_id)
+        .cancel_sync(stuck_addr)
+        .await?;
+    assert!(cancelled);
+
+    // Stop syncing with the stuck peer.
+    team.admin
+        .client
+        .team(team_id)
+        .remove_sync_peer(stuck_addr)
+        .await?;
+    time::timeout(Duration::from_secs(5), async {
+        loop {
+            let sessions = team.admin.client.sync_status().await?;
+            if sessions.iter().all(|s| s.addr != stuck_addr) {
+                return anyhow::Ok(());
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await??;
+    let cancelled = team
+        .admin
+        .client
+        .team(team_id)
+        .cancel_sync(stuck_addr)
+        .await?;
+    assert!(!cancelled);
+
+    Ok(())
+}
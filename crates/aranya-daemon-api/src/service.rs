@@ -1,13 +1,16 @@
 #![allow(clippy::disallowed_macros)] // tarpc uses unreachable
 
 use core::{fmt, hash::Hash, net::SocketAddr, time::Duration};
+use std::{path::PathBuf, time::SystemTime};
 
 use aranya_base58::ToBase58;
 use aranya_crypto::{
+    aead::Aead,
     afc::{BidiChannelId, UniChannelId},
     custom_id,
     default::DefaultCipherSuite,
-    Id,
+    generic_array::GenericArray,
+    CipherSuite, Id,
 };
 use aranya_fast_channels::{Label, NodeId};
 use aranya_util::Addr;
@@ -56,6 +59,14 @@ custom_id! {
     pub struct TeamId;
 }
 
+custom_id! {
+    /// Identifies the key that a daemon proves its identity to
+    /// sync peers with.
+    ///
+    /// See [`DaemonApi::sync_key_id`].
+    pub struct SyncKeyId;
+}
+
 /// A device's public key bundle.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyBundle {
@@ -65,7 +76,7 @@ pub struct KeyBundle {
 }
 
 /// A device's role on the team.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Role {
     Owner,
     Admin,
@@ -73,6 +84,154 @@ pub enum Role {
     Member,
 }
 
+/// An operation governed by the team's policy.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Terminate the team.
+    TerminateTeam,
+    /// Add a device to the team.
+    AddMember,
+    /// Remove a device with the `Member` role from the team.
+    RemoveMember,
+    /// Assign the `Owner` role.
+    AssignOwner,
+    /// Assign the `Admin` role.
+    AssignAdmin,
+    /// Assign the `Operator` role.
+    AssignOperator,
+    /// Revoke the `Owner` role.
+    RevokeOwner,
+    /// Revoke the `Admin` role.
+    RevokeAdmin,
+    /// Revoke the `Operator` role.
+    RevokeOperator,
+    /// Define a label.
+    DefineLabel,
+    /// Undefine a label.
+    UndefineLabel,
+    /// Assign a label to a device with the `Member` role.
+    AssignLabel,
+    /// Revoke a label from a device with the `Member` role.
+    RevokeLabel,
+    /// Set the network identifier of a device with the
+    /// `Member` role.
+    SetNetworkName,
+    /// Unset the network identifier of a device with the
+    /// `Member` role.
+    UnsetNetworkName,
+    /// Set an entry of a device's metadata.
+    SetDeviceMetadata,
+    /// Unset an entry of a device's metadata.
+    UnsetDeviceMetadata,
+    /// Create a bidirectional AFC channel.
+    CreateBidiChannel,
+    /// Create a unidirectional AFC channel.
+    CreateUniChannel,
+    /// Switch the team to another policy version.
+    UpgradePolicy,
+}
+
+/// The labels that a role may use to create AFC channels.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum LabelScope {
+    /// The role cannot create channels.
+    None,
+    /// The role can create channels with labels that have been
+    /// assigned to the device.
+    Assigned,
+}
+
+/// The capabilities of a role on a team.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RoleInfo {
+    /// The role.
+    pub role: Role,
+    /// The operations a device with this role may perform.
+    pub operations: Vec<Operation>,
+    /// The labels a device with this role may use.
+    pub label_scope: LabelScope,
+}
+
+impl RoleInfo {
+    /// Reports whether the role may perform `op`.
+    pub fn allows(&self, op: Operation) -> bool {
+        self.operations.contains(&op)
+    }
+}
+
+/// The daemon's policy versions.
+///
+/// Version 0 is the policy built into the daemon.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PolicyInfo {
+    /// The version the daemon is using.
+    pub active: u32,
+    /// The version the daemon is pinned to, if any.
+    pub pinned: Option<u32>,
+    /// The versions the daemon has bundles for, in ascending
+    /// order.
+    pub available: Vec<u32>,
+}
+
+/// The operations that a device may perform on the channels of
+/// a label assigned to it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ChanOp {
+    /// The device may only receive data.
+    ReadOnly,
+    /// The device may only send data.
+    WriteOnly,
+    /// The device may send and receive data.
+    ReadWrite,
+}
+
+/// A label assigned to a device.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LabelAssignment {
+    /// The label.
+    pub label: Label,
+    /// What the device may do on the label's channels.
+    pub op: ChanOp,
+}
+
+/// A device in a [`TeamGraph`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GraphDevice {
+    /// The device's ID.
+    pub id: DeviceId,
+    /// The device's role.
+    pub role: Role,
+    /// The labels assigned to the device, in ascending order.
+    pub labels: Vec<LabelAssignment>,
+}
+
+/// A snapshot of a team's devices, roles, and labels.
+///
+/// This is the schema of [`GraphFormat::Json`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TeamGraph {
+    /// The team's ID.
+    pub team: TeamId,
+    /// The devices on the team.
+    pub devices: Vec<GraphDevice>,
+    /// The labels defined on the team, in ascending order.
+    pub labels: Vec<Label>,
+}
+
+/// A format in which to export a [`TeamGraph`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum GraphFormat {
+    /// JSON, as described by [`TeamGraph`].
+    Json,
+    /// Graphviz's DOT language. Devices and labels are nodes,
+    /// and each label assignment is an edge from the device to
+    /// the label.
+    Dot,
+}
+
 /// A device's network identifier.
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
 pub struct NetIdentifier(pub String);
@@ -129,14 +288,302 @@ impl From<Id> for AfcId {
     }
 }
 
+/// Describes the layout of the AFC shared memory.
+///
+/// The daemon writes AFC keys to shared memory and the client
+/// reads them. Both must be built for the same target and with
+/// the same options, otherwise the client would misread the key
+/// material.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ShmLayout {
+    /// The target architecture (e.g., `x86_64`).
+    pub target_arch: String,
+    /// Whether the target is little-endian.
+    pub little_endian: bool,
+    /// The size of a pointer in bits.
+    pub pointer_width: u32,
+    /// The cipher suite used to encrypt AFC messages.
+    pub cipher_suite: String,
+    /// The maximum number of channels in shared memory.
+    pub max_chans: usize,
+}
+
+impl ShmLayout {
+    /// Returns the layout used by this build with `max_chans`
+    /// channels.
+    pub fn current(max_chans: usize) -> Self {
+        Self {
+            target_arch: std::env::consts::ARCH.to_owned(),
+            little_endian: cfg!(target_endian = "little"),
+            pointer_width: usize::BITS,
+            cipher_suite: core::any::type_name::<CS>().to_owned(),
+            max_chans,
+        }
+    }
+
+    /// Describes each field that differs between `self` and
+    /// `other`.
+    pub fn mismatches(&self, other: &Self) -> Vec<String> {
+        let mut diffs = Vec::new();
+        macro_rules! check {
+            ($($field:ident),* $(,)?) => {
+                $(if self.$field != other.$field {
+                    diffs.push(format!(
+                        "{}: {:?} != {:?}",
+                        stringify!($field),
+                        self.$field,
+                        other.$field,
+                    ));
+                })*
+            };
+        }
+        check!(
+            target_arch,
+            little_endian,
+            pointer_width,
+            cipher_suite,
+            max_chans,
+        );
+        diffs
+    }
+}
+
+/// The cipher suite that the daemon uses.
+///
+/// The suite ([`CS`]) is chosen when the daemon is built.
+/// Deployments with compliance requirements (e.g., only certain
+/// AEADs) can use this to verify what is in use.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CipherSuiteInfo {
+    /// The cipher suite's name.
+    pub name: String,
+    /// The AEAD, which encrypts AFC messages.
+    pub aead: String,
+    /// The size in bytes of the AEAD's keys.
+    pub aead_key_size: usize,
+    /// The hash function.
+    pub hash: String,
+    /// The key derivation function.
+    pub kdf: String,
+    /// The key encapsulation mechanism.
+    pub kem: String,
+    /// The message authentication code.
+    pub mac: String,
+    /// The digital signature algorithm.
+    pub signer: String,
+}
+
+impl CipherSuiteInfo {
+    /// Returns the cipher suite used by this build.
+    pub fn current() -> Self {
+        use core::any::type_name;
+        type KeySize = <<CS as CipherSuite>::Aead as Aead>::KeySize;
+        Self {
+            name: type_name::<CS>().to_owned(),
+            aead: type_name::<<CS as CipherSuite>::Aead>().to_owned(),
+            aead_key_size: core::mem::size_of::<GenericArray<u8, KeySize>>(),
+            hash: type_name::<<CS as CipherSuite>::Hash>().to_owned(),
+            kdf: type_name::<<CS as CipherSuite>::Kdf>().to_owned(),
+            kem: type_name::<<CS as CipherSuite>::Kem>().to_owned(),
+            mac: type_name::<<CS as CipherSuite>::Mac>().to_owned(),
+            signer: type_name::<<CS as CipherSuite>::Signer>().to_owned(),
+        }
+    }
+}
+
 // serialized command which must be passed over AFC.
 pub type AfcCtrl = Vec<Box<[u8]>>;
 
+/// A signed invitation to join a team.
+///
+/// Invitations allow devices to be onboarded without network
+/// connectivity between the inviter and the invitee. The
+/// inviter's daemon adds the invitee to the team and signs an
+/// invitation with [`DaemonApi::create_invitation`]. The
+/// invitation is then carried to the invitee out-of-band (as
+/// a file, QR code, etc.) using [`Invitation::to_bytes`]. The
+/// invitee imports it with [`DaemonApi::accept_invitation`]
+/// and joins the team the next time it is able to sync with
+/// one of the invitation's sync addresses.
+///
+/// The invitation carries the inviter's signing key, so its
+/// signature alone proves nothing. The invitee must also be
+/// given the inviter's [`KeyBundle`] out-of-band, which
+/// [`DaemonApi::accept_invitation`] checks the invitation
+/// against.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    /// The team the device is being invited to.
+    pub team_id: TeamId,
+    /// The device that created the invitation.
+    pub inviter: DeviceId,
+    /// Addresses to sync the team from.
+    pub sync_addrs: Vec<Addr>,
+    /// The inviter's encoded public signing key.
+    pub signing_key: Vec<u8>,
+    /// The inviter's encoded signature over
+    /// [`signed_bytes`][Self::signed_bytes].
+    pub signature: Vec<u8>,
+}
+
+impl Invitation {
+    /// The contextual binding used when signing invitations.
+    pub const SIGNATURE_CONTEXT: &'static [u8] = b"aranya-daemon-api invitation v1";
+
+    /// Returns the bytes covered by the invitation's
+    /// signature.
+    pub fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let fields = (
+            &self.team_id,
+            &self.inviter,
+            &self.sync_addrs,
+            &self.signing_key,
+        );
+        Ok(postcard::to_allocvec(&fields).map_err(anyhow::Error::from)?)
+    }
+
+    /// Encodes the invitation so that it can be written to
+    /// a file or QR code.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(postcard::to_allocvec(self).map_err(anyhow::Error::from)?)
+    }
+
+    /// Decodes an invitation created by
+    /// [`to_bytes`][Self::to_bytes].
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Ok(postcard::from_bytes(buf).map_err(anyhow::Error::from)?)
+    }
+}
+/// Describes a backup of a daemon's persistent state.
+///
+/// See [`DaemonApi::backup`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    /// The device whose state was backed up.
+    pub device_id: DeviceId,
+    /// When the backup was created.
+    pub created: SystemTime,
+    /// The number of files in the backup.
+    pub files: u64,
+    /// The total size of the files in bytes.
+    pub bytes: u64,
+    /// The SHA-256 digest of the backup, which is checked
+    /// before the backup is restored.
+    pub digest: [u8; 32],
+}
+
+/// What a sync session is doing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum SyncStage {
+    /// Preparing the sync request.
+    Requesting,
+    /// Waiting for the peer's response.
+    Waiting,
+    /// Adding the commands received from the peer.
+    Processing,
+}
+
+/// A sync session that is in progress.
+///
+/// See [`DaemonApi::sync_status`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SyncSession {
+    /// The peer's sync address.
+    pub addr: Addr,
+    /// The team being synced.
+    pub team: TeamId,
+    /// What the session is doing.
+    pub stage: SyncStage,
+    /// How long the session has been running.
+    pub elapsed: Duration,
+    /// The size in bytes of the peer's response, once it has
+    /// been received.
+    pub bytes_received: u64,
+    /// The number of commands received from the peer, once the
+    /// response has been parsed.
+    pub commands: u64,
+}
+
+/// A jump of the daemon's wall clock, e.g., once NTP set the
+/// clock of a device without a real-time clock.
+///
+/// See [`DaemonApi::clock_jumps`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClockJump {
+    /// Identifies the jump. Later jumps have larger sequence
+    /// numbers.
+    pub seq: u64,
+    /// The wall-clock time after the jump.
+    pub at: SystemTime,
+    /// How far the clock jumped.
+    pub by: Duration,
+    /// Whether the clock jumped backward.
+    pub backward: bool,
+}
+
+/// A sync peer's pinned key.
+///
+/// See [`DaemonApi::sync_pins`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SyncPin {
+    /// The peer's sync address.
+    pub addr: Addr,
+    /// The key that the peer must present.
+    pub key: SyncKeyId,
+}
+
+/// A sync peer that was rejected because it did not present its
+/// pinned key.
+///
+/// See [`DaemonApi::sync_pin_violations`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PinViolation {
+    /// Identifies the violation. Later violations have larger
+    /// sequence numbers.
+    pub seq: u64,
+    /// When the peer was rejected.
+    pub at: SystemTime,
+    /// The peer's sync address.
+    pub addr: Addr,
+    /// The key that the peer is pinned to, or `None` if it is
+    /// not pinned.
+    pub pinned: Option<SyncKeyId>,
+    /// The key that the peer presented, or `None` if it did not
+    /// present a valid one.
+    pub presented: Option<SyncKeyId>,
+}
+
+/// The maximum length in bytes of a device metadata key.
+pub const MAX_DEVICE_METADATA_KEY_LEN: usize = 64;
+
+/// The maximum length in bytes of a device metadata value.
+pub const MAX_DEVICE_METADATA_VALUE_LEN: usize = 1024;
+
 #[tarpc::service]
 pub trait DaemonApi {
     /// Gets local address the Aranya sync server is bound to.
     async fn aranya_local_addr() -> Result<SocketAddr>;
 
+    /// Gets the daemon's effective configuration as JSON.
+    ///
+    /// This includes defaults and environment variable
+    /// overrides.
+    async fn config() -> Result<String>;
+
+    /// Gets the layout of the AFC shared memory.
+    async fn afc_shm_layout() -> Result<ShmLayout>;
+
+    /// Gets the cipher suite that the daemon uses.
+    async fn cipher_suite() -> Result<CipherSuiteInfo>;
+
+    /// Gets the recent jumps of the daemon's wall clock after
+    /// the one with sequence number `after`, oldest first.
+    ///
+    /// Timestamps taken before a jump cannot be compared with
+    /// those taken after it.
+    async fn clock_jumps(after: Option<u64>) -> Result<Vec<ClockJump>>;
+
     /// Gets the public key bundle for this device
     async fn get_key_bundle() -> Result<KeyBundle>;
 
@@ -148,6 +595,38 @@ pub trait DaemonApi {
 
     /// Removes the peer from automatic syncing.
     async fn remove_sync_peer(addr: Addr, team: TeamId) -> Result<()>;
+    /// Gets the sync sessions that are in progress.
+    ///
+    /// Sessions with different peers run concurrently, so
+    /// a slow peer does not delay syncing with other peers.
+    async fn sync_status() -> Result<Vec<SyncSession>>;
+    /// Cancels the sync session with the peer, if one is in
+    /// progress.
+    ///
+    /// The peer is still synced with at its next interval.
+    /// Returns whether a session was cancelled.
+    async fn cancel_sync(addr: Addr, team: TeamId) -> Result<bool>;
+    /// Gets the ID of the key that the daemon proves its
+    /// identity to sync peers with.
+    ///
+    /// Peers can pin it with `pin_sync_peer`.
+    async fn sync_key_id() -> Result<SyncKeyId>;
+    /// Gets the sync peers' pinned keys.
+    async fn sync_pins() -> Result<Vec<SyncPin>>;
+    /// Pins the key of the sync peer at `addr`, replacing its
+    /// current pin, if any.
+    ///
+    /// This is how an operator accepts a peer's new key after
+    /// it was rejected, e.g., with the key from a
+    /// [`PinViolation`].
+    async fn pin_sync_peer(addr: Addr, key: SyncKeyId) -> Result<()>;
+    /// Removes the pin of the sync peer at `addr`.
+    ///
+    /// Returns whether the peer was pinned.
+    async fn unpin_sync_peer(addr: Addr) -> Result<bool>;
+    /// Gets the recent pin violations after the one with
+    /// sequence number `after`, oldest first.
+    async fn sync_pin_violations(after: Option<u64>) -> Result<Vec<PinViolation>>;
 
     /// add a team to the local device store that was created by someone else. Not an aranya action/command.
     async fn add_team(team: TeamId) -> Result<()>;
@@ -159,10 +638,72 @@ pub trait DaemonApi {
     async fn create_team() -> Result<TeamId>;
     /// Close the team.
     async fn close_team(team: TeamId) -> Result<()>;
+    /// Gets the capabilities of each role on the team.
+    async fn team_roles(team: TeamId) -> Result<Vec<RoleInfo>>;
+    /// Exports the team's devices, roles, and label assignments
+    /// as of this daemon's view of the team, e.g., to visualize
+    /// them.
+    async fn export_team_graph(team: TeamId, format: GraphFormat) -> Result<String>;
+
+    /// Writes a backup of the daemon's persistent state to
+    /// `path` on the daemon's host.
+    ///
+    /// The backup is a consistent snapshot: the daemon does not
+    /// change its state while it is taken. It holds the daemon's
+    /// secret keys, so it must be protected like the daemon's
+    /// working directory. Backups are restored when the daemon
+    /// starts.
+    async fn backup(path: PathBuf) -> Result<BackupInfo>;
+    /// Checks the integrity of the backup at `path` on the
+    /// daemon's host without restoring it.
+    async fn verify_backup(path: PathBuf) -> Result<BackupInfo>;
+
+    /// Gets the daemon's policy versions.
+    async fn policy_info() -> Result<PolicyInfo>;
+    /// Validates and stores a policy bundle.
+    ///
+    /// If the team already upgraded to `version`, the daemon
+    /// switches to it.
+    async fn load_policy(version: u32, doc: String) -> Result<()>;
+    /// Pins the daemon to a policy version, or unpins it if
+    /// `version` is `None`.
+    async fn pin_policy(version: Option<u32>) -> Result<()>;
+    /// Switches the team to another policy version.
+    ///
+    /// The team is switched to the daemon's bundle for
+    /// `version`, identified by its digest. Rolling back is an
+    /// upgrade to an older version.
+    async fn upgrade_policy(team: TeamId, version: u32) -> Result<()>;
 
     /// Add device to the team.
     async fn add_device_to_team(team: TeamId, keys: KeyBundle) -> Result<()>;
+    /// Adds a device to the team and returns a signed
+    /// invitation for it.
+    ///
+    /// If `sync_addrs` is empty, the invitation contains this
+    /// daemon's sync address.
+    async fn create_invitation(
+        team: TeamId,
+        keys: KeyBundle,
+        sync_addrs: Vec<Addr>,
+    ) -> Result<Invitation>;
+    /// Verifies an invitation created by another daemon and
+    /// begins syncing the team from its sync addresses at
+    /// `interval`.
+    ///
+    /// `inviter` is the trusted key bundle of the device that
+    /// created the invitation. The invitation must name the
+    /// device and be signed with its signing key.
+    async fn accept_invitation(
+        invitation: Invitation,
+        inviter: KeyBundle,
+        interval: Duration,
+    ) -> Result<TeamId>;
     /// Remove device from the team.
+    ///
+    /// Removes our AFC channels with the device and asks our
+    /// sync peers to sync right away, so that online peers
+    /// remove their channels with it too.
     async fn remove_device_from_team(team: TeamId, device: DeviceId) -> Result<()>;
 
     /// Assign a role to a device.
@@ -183,6 +724,22 @@ pub trait DaemonApi {
         name: NetIdentifier,
     ) -> Result<()>;
 
+    /// Sets an entry of a device's metadata.
+    ///
+    /// The metadata is replicated to the team like any other
+    /// change.
+    async fn set_device_metadata(
+        team: TeamId,
+        device: DeviceId,
+        key: String,
+        value: String,
+    ) -> Result<()>;
+    /// Unsets an entry of a device's metadata.
+    async fn unset_device_metadata(team: TeamId, device: DeviceId, key: String) -> Result<()>;
+    /// Gets an entry of a device's metadata, if it is set.
+    async fn device_metadata(team: TeamId, device: DeviceId, key: String)
+        -> Result<Option<String>>;
+
     /// Create a fast channels label.
     async fn create_label(team: TeamId, label: Label) -> Result<()>;
     /// Delete a fast channels label.
@@ -193,18 +750,41 @@ pub trait DaemonApi {
     /// Revoke a fast channels label from a device.
     async fn revoke_label(team: TeamId, device: DeviceId, label: Label) -> Result<()>;
     /// Create a fast channel.
+    ///
+    /// Returns the channel's ID, the peer's device ID, and the
+    /// control message to send to the peer.
     async fn create_bidi_channel(
         team: TeamId,
         peer: NetIdentifier,
         node_id: NodeId,
         label: Label,
-    ) -> Result<(AfcId, AfcCtrl)>;
+    ) -> Result<(AfcId, DeviceId, AfcCtrl)>;
+    /// Allocates a node ID for a new fast channel.
+    ///
+    /// Node IDs are unique among the channels in the daemon's
+    /// shared memory, so clients that share it cannot mint
+    /// colliding IDs.
+    async fn allocate_node_id() -> Result<NodeId>;
     /// Delete a fast channel.
     async fn delete_channel(chan: AfcId) -> Result<AfcCtrl>;
+    /// Returns the fast channels that the daemon knows about.
+    ///
+    /// The daemon removes channels on its own when, e.g.,
+    /// a policy change revokes their label or the peer is
+    /// removed from the team.
+    async fn afc_channels() -> Result<Vec<AfcId>>;
     /// Receive a fast channel ctrl message.
+    ///
+    /// The message is verified before anything else is done
+    /// with it. Only then is the channel's node ID allocated
+    /// (see [`allocate_node_id`][Self::allocate_node_id]) and
+    /// the channel added.
+    ///
+    /// Returns the channel's ID, the device ID and network
+    /// identifier of the channel's author, the channel's label,
+    /// and the channel's node ID.
     async fn receive_afc_ctrl(
         team: TeamId,
-        node_id: NodeId,
         ctrl: AfcCtrl,
-    ) -> Result<(AfcId, NetIdentifier, Label)>;
+    ) -> Result<(AfcId, DeviceId, NetIdentifier, Label, NodeId)>;
 }
@@ -4,24 +4,35 @@
 #![allow(clippy::expect_used, clippy::panic, clippy::indexing_slicing)]
 
 use std::{
+    collections::BTreeMap,
     future::{self, Future},
     net::SocketAddr,
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use aranya_afc_util::{BidiChannelCreated, BidiChannelReceived, BidiKeys, Handler};
 use aranya_buggy::BugExt;
-use aranya_crypto::{afc::BidiPeerEncap, keystore::fs_keystore::Store, Csprng, Rng, UserId};
+use aranya_crypto::{
+    afc::BidiPeerEncap, keystore::fs_keystore::Store, Csprng, IdentityVerifyingKey, KeyStoreExt,
+    Rng, Signature, SigningKey, SigningKeyId, UserId, VerifyingKey,
+};
 use aranya_daemon_api::{
-    AfcCtrl, AfcId, DaemonApi, DeviceId, KeyBundle as ApiKeyBundle, NetIdentifier,
-    Result as ApiResult, Role as ApiRole, TeamId, CS,
+    AfcCtrl, AfcId, BackupInfo, ChanOp as ApiChanOp, CipherSuiteInfo, ClockJump, DaemonApi,
+    DeviceId, GraphFormat, Invitation, KeyBundle as ApiKeyBundle, LabelScope, NetIdentifier,
+    Operation, PinViolation, PolicyInfo, Result as ApiResult, Role as ApiRole, RoleInfo, ShmLayout,
+    SyncKeyId, SyncPin, SyncSession, TeamId, CS, MAX_DEVICE_METADATA_KEY_LEN,
+    MAX_DEVICE_METADATA_VALUE_LEN,
 };
 use aranya_fast_channels::{shm::WriteState, AranyaState, ChannelId, Directed, Label, NodeId};
 use aranya_keygen::PublicKeys;
-use aranya_util::Addr;
+use aranya_runtime::{storage::linear::libc::FileManager, ClientState, GraphId};
+use aranya_util::{Addr, ShortId};
 use bimap::BiBTreeMap;
 use futures_util::{StreamExt, TryStreamExt};
 use tarpc::{
@@ -34,12 +45,18 @@ use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
     aranya::Actions,
+    backup,
+    clock::ClockMonitor,
+    config::Config,
+    graph,
+    pins::SyncPins,
+    policies::Policies,
     policy::{
         BidiChannelCreated as AfcBidiChannelCreated, BidiChannelReceived as AfcBidiChannelReceived,
-        ChanOp, Effect, KeyBundle, Role,
+        ChanOp, Effect, KeyBundle, LabelRevoked, MemberRemoved, PolicyUpgraded, Role,
     },
     sync::SyncPeers,
-    Client, CE, EF,
+    Client, CE, EF, EN, SP,
 };
 
 async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
@@ -62,7 +79,7 @@ macro_rules! find_effect {
 pub struct DaemonApiServer {
     daemon_sock: PathBuf,
     /// Channel for receiving effects from the syncer.
-    recv_effects: mpsc::Receiver<Vec<EF>>,
+    recv_effects: mpsc::Receiver<(GraphId, Vec<EF>)>,
     handler: DaemonApiHandler,
 }
 
@@ -71,29 +88,45 @@ impl DaemonApiServer {
     #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all)]
     pub fn new(
+        cfg: Arc<Config>,
         client: Arc<Client>,
         local_addr: SocketAddr,
         afc: Arc<Mutex<WriteState<CS, Rng>>>,
         eng: CE,
         store: Store,
+        sign_id: SigningKeyId,
         daemon_sock: PathBuf,
         pk: Arc<PublicKeys<CS>>,
         peers: SyncPeers,
-        recv_effects: mpsc::Receiver<Vec<EF>>,
+        pins: Arc<SyncPins>,
+        policies: Arc<Mutex<Policies>>,
+        recv_effects: mpsc::Receiver<(GraphId, Vec<EF>)>,
+        clock: Arc<ClockMonitor>,
     ) -> Result<Self> {
         info!("uds path: {:?}", daemon_sock);
         let user_id = pk.ident_pk.id()?;
+        let keys = Arc::new(Mutex::new(
+            store.try_clone().context("unable to clone keystore")?,
+        ));
         Ok(Self {
             daemon_sock,
             recv_effects,
             handler: DaemonApiHandler {
+                cfg,
                 client,
                 local_addr,
                 afc,
                 eng,
+                keys,
+                sign_id,
                 pk,
                 peers,
+                pins,
+                policies,
+                clock,
                 afc_peers: Arc::default(),
+                afc_chans: Arc::default(),
+                next_node_id: Arc::default(),
                 handler: Arc::new(Mutex::new(Handler::new(user_id, store))),
             },
         })
@@ -133,9 +166,10 @@ impl DaemonApiServer {
                 .for_each(|_| async {}),
             async {
                 // receive effects from syncer.
-                while let Some(effects) = self.recv_effects.recv().await {
+                while let Some((graph_id, effects)) = self.recv_effects.recv().await {
                     // handle effects.
-                    if let Err(e) = self.handler.handle_effects(&effects, None).await {
+                    let team = graph_id.into_id().into();
+                    if let Err(e) = self.handler.handle_effects(team, &effects, None).await {
                         error!(?e, "error handling effects");
                     }
                 }
@@ -147,6 +181,8 @@ impl DaemonApiServer {
 
 #[derive(Clone)]
 struct DaemonApiHandler {
+    /// The daemon's effective configuration.
+    cfg: Arc<Config>,
     /// Aranya client for
     client: Arc<Client>,
     /// Local socket address of the API.
@@ -155,31 +191,74 @@ struct DaemonApiHandler {
     afc: Arc<Mutex<WriteState<CS, Rng>>>,
     /// An implementation of [`Engine`][crypto::Engine].
     eng: CE,
+    /// Stores the current user's private keys.
+    keys: Arc<Mutex<Store>>,
+    /// The current user's signing key.
+    sign_id: SigningKeyId,
     /// Public keys of current user.
     pk: Arc<PublicKeys<CS>>,
     /// Aranya sync peers,
     peers: SyncPeers,
+    /// Sync peers' pinned keys.
+    pins: Arc<SyncPins>,
+    /// Policy bundles.
+    policies: Arc<Mutex<Policies>>,
+    /// The daemon's time source.
+    clock: Arc<ClockMonitor>,
     /// AFC peers.
     afc_peers: Arc<Mutex<BiBTreeMap<NetIdentifier, UserId>>>,
+    /// Our AFC channels.
+    afc_chans: Arc<Mutex<BTreeMap<AfcId, AfcChan>>>,
+    /// The next AFC node ID to hand out.
+    next_node_id: Arc<AtomicU32>,
     /// Handles AFC effects.
     handler: Arc<Mutex<Handler<Store>>>,
 }
 
+/// An AFC channel.
+#[derive(Copy, Clone, Debug)]
+struct AfcChan {
+    /// The shm channel.
+    channel_id: ChannelId,
+    /// The team that the channel belongs to.
+    team: TeamId,
+    /// The other device.
+    peer: UserId,
+}
+
 impl DaemonApiHandler {
     fn get_pk(&self) -> ApiResult<KeyBundle> {
         Ok(KeyBundle::try_from(&*self.pk).context("bad key bundle")?)
     }
 
+    /// Signs `msg` with the current user's [`SigningKey`].
+    async fn sign(&self, msg: &[u8], context: &[u8]) -> Result<Signature<CS>> {
+        let sk = self
+            .keys
+            .lock()
+            .await
+            .get_key::<_, SigningKey<CS>>(&mut self.eng.clone(), self.sign_id.into())
+            .context("unable to load `SigningKey`")?
+            .context("unable to find `SigningKey`")?;
+        sk.sign(msg, context).context("unable to sign message")
+    }
+
     /// Handles effects resulting from invoking an Aranya action.
     #[instrument(skip_all)]
-    async fn handle_effects(&self, effects: &[Effect], node_id: Option<NodeId>) -> Result<()> {
+    async fn handle_effects(
+        &self,
+        team: TeamId,
+        effects: &[Effect],
+        node_id: Option<NodeId>,
+    ) -> Result<()> {
         for effect in effects {
             debug!(?effect, "handling effect");
             match effect {
                 Effect::TeamCreated(_team_created) => {}
                 Effect::TeamTerminated(_team_terminated) => {}
+                Effect::PolicyUpgraded(v) => self.policy_upgraded(v).await?,
                 Effect::MemberAdded(_member_added) => {}
-                Effect::MemberRemoved(_member_removed) => {}
+                Effect::MemberRemoved(v) => self.member_removed(team, v).await?,
                 Effect::OwnerAssigned(_owner_assigned) => {}
                 Effect::AdminAssigned(_admin_assigned) => {}
                 Effect::OperatorAssigned(_operator_assigned) => {}
@@ -189,7 +268,7 @@ impl DaemonApiHandler {
                 Effect::LabelDefined(_label_defined) => {}
                 Effect::LabelUndefined(_label_undefined) => {}
                 Effect::LabelAssigned(_label_assigned) => {}
-                Effect::LabelRevoked(_label_revoked) => {}
+                Effect::LabelRevoked(v) => self.label_revoked(team, v).await?,
                 Effect::NetworkNameSet(e) => {
                     self.afc_peers
                         .lock()
@@ -197,6 +276,12 @@ impl DaemonApiHandler {
                         .insert(NetIdentifier(e.net_identifier.clone()), e.user_id.into());
                 }
                 Effect::NetworkNameUnset(_network_name_unset) => {}
+                Effect::DeviceMetadataSet(_device_metadata_set) => {}
+                Effect::DeviceMetadataUnset(_device_metadata_unset) => {}
+                Effect::DeviceMetadataQueried(_device_metadata_queried) => {}
+                Effect::UserQueried(_user_queried) => {}
+                Effect::LabelQueried(_label_queried) => {}
+                Effect::AssignedLabelQueried(_assigned_label_queried) => {}
                 Effect::BidiChannelCreated(v) => {
                     debug!("received BidiChannelCreated effect");
                     if let Some(node_id) = node_id {
@@ -217,6 +302,140 @@ impl DaemonApiHandler {
         Ok(())
     }
 
+    /// Allocates a node ID for a new AFC channel.
+    async fn next_node_id(&self) -> Result<NodeId> {
+        let chans = self.afc_chans.lock().await;
+        // Skip IDs that are still in use, e.g., because the
+        // counter wrapped around. One of the next `len + 1` IDs
+        // must be free.
+        for _ in 0..=chans.len() {
+            let node_id = NodeId::new(self.next_node_id.fetch_add(1, Ordering::Relaxed));
+            if !chans
+                .values()
+                .any(|chan| chan.channel_id.node_id() == node_id)
+            {
+                debug!(%node_id, "allocated node ID");
+                return Ok(node_id);
+            }
+        }
+        bail!("no AFC node IDs available")
+    }
+
+    /// Reacts to the team switching policies.
+    #[instrument(skip(self), fields(effect = ?v))]
+    async fn policy_upgraded(&self, v: &PolicyUpgraded) -> Result<()> {
+        // NB: this shouldn't happen because the policy should
+        // ensure that version fits inside a `u32`.
+        let version = u32::try_from(v.version).assume("`version` is out of range")?;
+        let mut policies = self.policies.lock().await;
+        if let Some(version) = policies.upgraded(version, v.digest.clone()).await {
+            self.switch_policy(&mut policies, version).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes our AFC channels on `team` that use a label that
+    /// was revoked from us or from the channel's peer.
+    ///
+    /// Clients find out that the channels were removed with
+    /// [`afc_channels`][DaemonApi::afc_channels].
+    #[instrument(skip(self), fields(effect = ?v))]
+    async fn label_revoked(&self, team: TeamId, v: &LabelRevoked) -> Result<()> {
+        let us: UserId = self.pk.ident_pk.id()?;
+        let device: UserId = v.user_id.into();
+        let label = Label::new(u32::try_from(v.label).assume("`label` is out of range")?);
+        self.remove_afc_chans(
+            |chan| {
+                chan.team == team
+                    && chan.channel_id.label() == label
+                    && (device == us || chan.peer == device)
+            },
+            "revoked label",
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Removes our AFC channels that a device's removal from
+    /// `team` invalidated, then pushes the removal to our sync
+    /// peers.
+    ///
+    /// If we were removed, that is all of our channels on the
+    /// team. Otherwise, it is our channels with the removed
+    /// device. Since the channels are removed from shm, clients
+    /// can no longer seal or open data for them, and they
+    /// find out why with
+    /// [`afc_channels`][DaemonApi::afc_channels].
+    ///
+    /// Pushing the removal means that online peers tear down
+    /// their channels with the device within a round trip
+    /// instead of at their next sync.
+    #[instrument(skip(self), fields(effect = ?v))]
+    async fn member_removed(&self, team: TeamId, v: &MemberRemoved) -> Result<()> {
+        let us: UserId = self.pk.ident_pk.id()?;
+        let device: UserId = v.user_id.into();
+        self.remove_afc_chans(
+            |chan| chan.team == team && (device == us || chan.peer == device),
+            "removed device",
+        )
+        .await;
+        self.peers.push(team.into_id().into()).await?;
+        Ok(())
+    }
+
+    /// Removes the AFC channels that match `pred` from shm.
+    async fn remove_afc_chans(&self, mut pred: impl FnMut(&AfcChan) -> bool, why: &str) {
+        let mut removed = Vec::new();
+        self.afc_chans.lock().await.retain(|afc_id, chan| {
+            let keep = !pred(chan);
+            if !keep {
+                removed.push((*afc_id, chan.channel_id));
+            }
+            keep
+        });
+        let mut afc = self.afc.lock().await;
+        for (afc_id, channel_id) in removed {
+            match afc.remove(channel_id) {
+                Ok(()) => {
+                    info!(afc_id = %ShortId::new("afc", afc_id), %channel_id, why, "removed AFC channel")
+                }
+                Err(err) => {
+                    warn!(afc_id = %ShortId::new("afc", afc_id), %channel_id, %err, "unable to remove AFC channel")
+                }
+            }
+        }
+    }
+
+    /// Switches the daemon to policy `version`.
+    ///
+    /// The runtime's state is rebuilt around the new policy.
+    /// Commands already in the graph are kept.
+    ///
+    /// It is an error if the bundle does not match the one the
+    /// team upgraded to.
+    #[instrument(skip(self, policies))]
+    async fn switch_policy(&self, policies: &mut Policies, version: u32) -> Result<()> {
+        let doc = policies.read(version).await?;
+        policies.check(version, &doc)?;
+        let store = self
+            .keys
+            .lock()
+            .await
+            .try_clone()
+            .context("unable to clone keystore")?;
+        let engine = EN::new(&doc, self.eng.clone(), store, self.pk.ident_pk.id()?)
+            .with_context(|| format!("unable to load policy version {version}"))?;
+        let provider = SP::new(
+            FileManager::new(self.cfg.storage_path()).context("unable to create `FileManager`")?,
+        );
+        self.client
+            .set_state(ClientState::new(engine, provider))
+            .await;
+        policies.set_active(version).await?;
+        info!(version, "switched policy");
+        Ok(())
+    }
+
     /// Reacts to a bidirectional AFC channel being created.
     #[instrument(skip(self), fields(effect = ?v))]
     async fn afc_bidi_channel_created(
@@ -292,6 +511,30 @@ impl DaemonApi for DaemonApiHandler {
         Ok(self.local_addr)
     }
 
+    #[instrument(skip(self))]
+    async fn config(self, _: context::Context) -> ApiResult<String> {
+        Ok(self.cfg.to_json()?)
+    }
+
+    #[instrument(skip(self))]
+    async fn afc_shm_layout(self, _: context::Context) -> ApiResult<ShmLayout> {
+        Ok(ShmLayout::current(self.cfg.afc.max_chans))
+    }
+
+    #[instrument(skip(self))]
+    async fn cipher_suite(self, _: context::Context) -> ApiResult<CipherSuiteInfo> {
+        Ok(CipherSuiteInfo::current())
+    }
+
+    #[instrument(skip(self))]
+    async fn clock_jumps(
+        self,
+        _: context::Context,
+        after: Option<u64>,
+    ) -> ApiResult<Vec<ClockJump>> {
+        Ok(self.clock.jumps(after).await)
+    }
+
     #[instrument(skip(self))]
     async fn get_key_bundle(self, _: context::Context) -> ApiResult<ApiKeyBundle> {
         Ok(self.get_pk()?.into())
@@ -327,6 +570,45 @@ impl DaemonApi for DaemonApiHandler {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn sync_status(self, _: context::Context) -> ApiResult<Vec<SyncSession>> {
+        Ok(self.peers.status().await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn cancel_sync(self, _: context::Context, peer: Addr, team: TeamId) -> ApiResult<bool> {
+        Ok(self.peers.cancel(peer, team.into_id().into()).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn sync_key_id(self, _: context::Context) -> ApiResult<SyncKeyId> {
+        Ok(self.pk.sign_pk.id()?.into_id().into())
+    }
+
+    #[instrument(skip(self))]
+    async fn sync_pins(self, _: context::Context) -> ApiResult<Vec<SyncPin>> {
+        Ok(self.pins.pins().await)
+    }
+
+    #[instrument(skip(self))]
+    async fn pin_sync_peer(self, _: context::Context, addr: Addr, key: SyncKeyId) -> ApiResult<()> {
+        Ok(self.pins.pin(addr, key).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn unpin_sync_peer(self, _: context::Context, addr: Addr) -> ApiResult<bool> {
+        Ok(self.pins.unpin(&addr).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn sync_pin_violations(
+        self,
+        _: context::Context,
+        after: Option<u64>,
+    ) -> ApiResult<Vec<PinViolation>> {
+        Ok(self.pins.violations(after).await)
+    }
+
     #[instrument(skip(self))]
     async fn add_team(self, _: context::Context, team: TeamId) -> ApiResult<()> {
         todo!()
@@ -353,6 +635,100 @@ impl DaemonApi for DaemonApiHandler {
         todo!();
     }
 
+    #[instrument(skip(self))]
+    async fn team_roles(self, _: context::Context, team: TeamId) -> ApiResult<Vec<RoleInfo>> {
+        // Every team currently uses the same policy.
+        Ok(role_infos())
+    }
+
+    #[instrument(skip(self))]
+    async fn export_team_graph(
+        self,
+        _: context::Context,
+        team: TeamId,
+        format: GraphFormat,
+    ) -> ApiResult<String> {
+        let (_, effects) = self
+            .client
+            .actions(&team.into_id().into())
+            .query_team_graph_off_graph()
+            .await?;
+        let graph = graph::from_effects(team, &effects)?;
+        Ok(graph::render(&graph, format)?)
+    }
+
+    #[instrument(skip(self))]
+    async fn backup(self, _: context::Context, path: PathBuf) -> ApiResult<BackupInfo> {
+        // Hold every lock that guards persistent state so that
+        // nothing changes while the backup is taken. This is the
+        // same order that `switch_policy` takes them in.
+        let _policies = self.policies.lock().await;
+        let _keys = self.keys.lock().await;
+        let _handler = self.handler.lock().await;
+        let _aranya = self.client.lock_state().await;
+        let device_id = self.pk.ident_pk.id()?.into_id().into();
+        Ok(backup::create(&self.cfg, device_id, &path, self.clock.now()).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn verify_backup(self, _: context::Context, path: PathBuf) -> ApiResult<BackupInfo> {
+        Ok(backup::verify(&path).await?)
+    }
+
+    #[instrument(skip(self))]
+    async fn policy_info(self, _: context::Context) -> ApiResult<PolicyInfo> {
+        let policies = self.policies.lock().await;
+        Ok(PolicyInfo {
+            active: policies.active(),
+            pinned: policies.pinned(),
+            available: policies.versions().await?,
+        })
+    }
+
+    #[instrument(skip(self, doc))]
+    async fn load_policy(self, _: context::Context, version: u32, doc: String) -> ApiResult<()> {
+        let mut policies = self.policies.lock().await;
+        if let Some(version) = policies.store(version, &doc).await? {
+            self.switch_policy(&mut policies, version).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn pin_policy(self, _: context::Context, version: Option<u32>) -> ApiResult<()> {
+        let mut policies = self.policies.lock().await;
+        if let Some(version) = policies.pin(version).await? {
+            self.switch_policy(&mut policies, version).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn upgrade_policy(
+        self,
+        _: context::Context,
+        team: TeamId,
+        version: u32,
+    ) -> ApiResult<()> {
+        // Don't ask the team to switch to a policy that we
+        // can't switch to ourselves. The other devices switch
+        // to the bundle with the same digest.
+        let digest = self
+            .policies
+            .lock()
+            .await
+            .digest(version)
+            .await
+            .with_context(|| format!("policy version {version} is not available"))?;
+        let effects = self
+            .client
+            .actions(&team.into_id().into())
+            .upgrade_policy(version, digest)
+            .await?;
+        self.handle_effects(team, &effects, None).await?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn add_device_to_team(
         self,
@@ -367,6 +743,83 @@ impl DaemonApi for DaemonApiHandler {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    async fn create_invitation(
+        self,
+        _: context::Context,
+        team: TeamId,
+        keys: ApiKeyBundle,
+        sync_addrs: Vec<Addr>,
+    ) -> ApiResult<Invitation> {
+        self.client
+            .actions(&team.into_id().into())
+            .add_member(keys.into())
+            .await?;
+
+        let sync_addrs = if sync_addrs.is_empty() {
+            vec![self.local_addr.into()]
+        } else {
+            sync_addrs
+        };
+        let mut invitation = Invitation {
+            team_id: team,
+            inviter: self.pk.ident_pk.id()?.into_id().into(),
+            sync_addrs,
+            signing_key: self.get_pk()?.sign_key,
+            signature: Vec::new(),
+        };
+        let sig = self
+            .sign(&invitation.signed_bytes()?, Invitation::SIGNATURE_CONTEXT)
+            .await?;
+        invitation.signature = postcard::to_allocvec(&sig).context("unable to encode signature")?;
+        info!(%team, "created invitation");
+        Ok(invitation)
+    }
+
+    #[instrument(skip_all, fields(team = %invitation.team_id))]
+    async fn accept_invitation(
+        self,
+        _: context::Context,
+        invitation: Invitation,
+        inviter: ApiKeyBundle,
+        interval: Duration,
+    ) -> ApiResult<TeamId> {
+        // Anyone can sign an invitation with a key of their own,
+        // so only trust the inviter's key bundle, which was
+        // given to us out-of-band.
+        let ident: IdentityVerifyingKey<CS> =
+            postcard::from_bytes(&inviter.identity).context("invalid inviter identity key")?;
+        let inviter_id: DeviceId = ident.id()?.into_id().into();
+        if inviter_id != invitation.inviter {
+            return Err(anyhow!("invitation was not created by the inviter").into());
+        }
+        if inviter.signing != invitation.signing_key {
+            return Err(anyhow!("invitation was not signed by the inviter").into());
+        }
+        let pk: VerifyingKey<CS> =
+            postcard::from_bytes(&inviter.signing).context("invalid inviter signing key")?;
+        let sig: Signature<CS> =
+            postcard::from_bytes(&invitation.signature).context("invalid invitation signature")?;
+        pk.verify(
+            &invitation.signed_bytes()?,
+            Invitation::SIGNATURE_CONTEXT,
+            &sig,
+        )
+        .context("unable to verify invitation")?;
+        if invitation.sync_addrs.is_empty() {
+            return Err(anyhow!("invitation has no sync addresses").into());
+        }
+
+        let team = invitation.team_id;
+        for addr in invitation.sync_addrs {
+            self.peers
+                .add_peer(addr, interval, team.into_id().into())
+                .await?;
+        }
+        info!(inviter = %invitation.inviter, "accepted invitation");
+        Ok(team)
+    }
+
     #[instrument(skip(self))]
     async fn remove_device_from_team(
         self,
@@ -374,10 +827,12 @@ impl DaemonApi for DaemonApiHandler {
         team: TeamId,
         device: DeviceId,
     ) -> ApiResult<()> {
-        self.client
+        let effects = self
+            .client
             .actions(&team.into_id().into())
             .remove_member(device.into_id().into())
             .await?;
+        self.handle_effects(team, &effects, None).await?;
         Ok(())
     }
 
@@ -424,7 +879,7 @@ impl DaemonApi for DaemonApiHandler {
             .actions(&team.into_id().into())
             .set_network_name(device.into_id().into(), name.0)
             .await?;
-        self.handle_effects(&effects, None).await?;
+        self.handle_effects(team, &effects, None).await?;
         Ok(())
     }
 
@@ -443,6 +898,67 @@ impl DaemonApi for DaemonApiHandler {
         Ok(())
     }
 
+    #[instrument(skip(self, value))]
+    async fn set_device_metadata(
+        self,
+        _: context::Context,
+        team: TeamId,
+        device: DeviceId,
+        key: String,
+        value: String,
+    ) -> ApiResult<()> {
+        check_metadata_key(&key)?;
+        if value.len() > MAX_DEVICE_METADATA_VALUE_LEN {
+            return Err(anyhow!(
+                "metadata value is longer than {MAX_DEVICE_METADATA_VALUE_LEN} bytes"
+            )
+            .into());
+        }
+        self.client
+            .actions(&team.into_id().into())
+            .set_device_metadata(device.into_id().into(), key, value)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn unset_device_metadata(
+        self,
+        _: context::Context,
+        team: TeamId,
+        device: DeviceId,
+        key: String,
+    ) -> ApiResult<()> {
+        check_metadata_key(&key)?;
+        self.client
+            .actions(&team.into_id().into())
+            .unset_device_metadata(device.into_id().into(), key)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn device_metadata(
+        self,
+        _: context::Context,
+        team: TeamId,
+        device: DeviceId,
+        key: String,
+    ) -> ApiResult<Option<String>> {
+        check_metadata_key(&key)?;
+        let (_, effects) = self
+            .client
+            .actions(&team.into_id().into())
+            .query_device_metadata_off_graph(device.into_id().into(), key)
+            .await?;
+        let Some(Effect::DeviceMetadataQueried(e)) =
+            find_effect!(effects, Effect::DeviceMetadataQueried(_))
+        else {
+            return Err(anyhow!("unable to find DeviceMetadataQueried effect").into());
+        };
+        Ok(e.found.then_some(e.value))
+    }
+
     #[instrument(skip(self))]
     async fn create_label(self, _: context::Context, team: TeamId, label: Label) -> ApiResult<()> {
         self.client
@@ -485,23 +1001,22 @@ impl DaemonApi for DaemonApiHandler {
         device: DeviceId,
         label: Label,
     ) -> ApiResult<()> {
-        let id = self.pk.ident_pk.id()?;
         self.client
             .actions(&team.into_id().into())
-            .revoke_label(id, label)
+            .revoke_label(device.into_id().into(), label)
             .await?;
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(trace_id = %ctx.trace_id(), %team, %peer, %label))]
     async fn create_bidi_channel(
         self,
-        _: context::Context,
+        ctx: context::Context,
         team: TeamId,
         peer: NetIdentifier,
         node_id: NodeId,
         label: Label,
-    ) -> ApiResult<(AfcId, AfcCtrl)> {
+    ) -> ApiResult<(AfcId, DeviceId, AfcCtrl)> {
         info!("create_bidi_channel");
 
         let peer_id = self
@@ -527,46 +1042,90 @@ impl DaemonApi for DaemonApiHandler {
         let afc_id: AfcId = e.channel_key_id.into();
         debug!(?afc_id, "processed afc ID");
 
-        self.handle_effects(&effects, Some(node_id)).await?;
-        Ok((afc_id, ctrl))
+        self.handle_effects(team, &effects, Some(node_id)).await?;
+        self.afc_chans.lock().await.insert(
+            afc_id,
+            AfcChan {
+                channel_id: ChannelId::new(node_id, label),
+                team,
+                peer: peer_id,
+            },
+        );
+        Ok((afc_id, peer_id.into_id().into(), ctrl))
     }
 
     #[instrument(skip(self))]
-    async fn delete_channel(self, _: context::Context, chan: AfcId) -> ApiResult<AfcCtrl> {
-        // TODO: remove AFC channel from Aranya.
-        todo!();
+    async fn allocate_node_id(self, _: context::Context) -> ApiResult<NodeId> {
+        Ok(self.next_node_id().await?)
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip(self))]
+    async fn afc_channels(self, _: context::Context) -> ApiResult<Vec<AfcId>> {
+        Ok(self.afc_chans.lock().await.keys().copied().collect())
+    }
+
+    #[instrument(skip(self, ctx), fields(trace_id = %ctx.trace_id()))]
+    async fn delete_channel(self, ctx: context::Context, chan: AfcId) -> ApiResult<AfcCtrl> {
+        // TODO: remove the AFC channel from the graph. Only its
+        // keys are removed from shared memory.
+        let channel_id = self
+            .afc_chans
+            .lock()
+            .await
+            .remove(&chan)
+            .context("unknown AFC channel")?
+            .channel_id;
+        self.afc
+            .lock()
+            .await
+            .remove(channel_id)
+            .map_err(|err| anyhow!("unable to remove AFC channel: {err}"))?;
+        debug!(%channel_id, "removed AFC channel");
+        Ok(AfcCtrl::new())
+    }
+
+    #[instrument(skip_all, fields(trace_id = %ctx.trace_id(), %team))]
     async fn receive_afc_ctrl(
         self,
-        _: context::Context,
+        ctx: context::Context,
         team: TeamId,
-        node_id: NodeId,
         ctrl: AfcCtrl,
-    ) -> ApiResult<(AfcId, NetIdentifier, Label)> {
+    ) -> ApiResult<(AfcId, DeviceId, NetIdentifier, Label, NodeId)> {
         let mut session = self.client.session_new(&team.into_id().into()).await?;
         for cmd in ctrl {
+            // Receiving the command verifies it, so nothing is
+            // allocated or added for commands that fail.
             let effects = self.client.session_receive(&mut session, &cmd).await?;
             let id = self.pk.ident_pk.id()?;
-            self.handle_effects(&effects, Some(node_id)).await?;
             let Some(Effect::BidiChannelReceived(e)) =
                 find_effect!(&effects, Effect::BidiChannelReceived(e) if e.peer_id == id.into())
             else {
+                self.handle_effects(team, &effects, None).await?;
                 continue;
             };
+            let node_id = self.next_node_id().await?;
+            self.handle_effects(team, &effects, Some(node_id)).await?;
             let encap = BidiPeerEncap::<CS>::from_bytes(&e.encap).context("unable to get encap")?;
             let afc_id: AfcId = encap.id().into();
             debug!(?afc_id, "processed afc ID");
             let label = Label::new(e.label.try_into().expect("expected label conversion"));
+            let author: UserId = e.author_id.into();
+            self.afc_chans.lock().await.insert(
+                afc_id,
+                AfcChan {
+                    channel_id: ChannelId::new(node_id, label),
+                    team,
+                    peer: author,
+                },
+            );
             let net = self
                 .afc_peers
                 .lock()
                 .await
-                .get_by_right(&e.author_id.into())
+                .get_by_right(&author)
                 .context("missing net identifier for channel author")?
                 .clone();
-            return Ok((afc_id, net, label));
+            return Ok((afc_id, author.into_id().into(), net, label, node_id));
         }
         Err(anyhow!("unable to find BidiChannelReceived effect").into())
     }
@@ -602,3 +1161,109 @@ impl From<ApiRole> for Role {
         }
     }
 }
+
+impl From<&Role> for ApiRole {
+    fn from(value: &Role) -> Self {
+        match value {
+            Role::Owner => ApiRole::Owner,
+            Role::Admin => ApiRole::Admin,
+            Role::Operator => ApiRole::Operator,
+            Role::Member => ApiRole::Member,
+        }
+    }
+}
+
+impl From<&ChanOp> for ApiChanOp {
+    fn from(value: &ChanOp) -> Self {
+        match value {
+            ChanOp::ReadOnly => ApiChanOp::ReadOnly,
+            ChanOp::WriteOnly => ApiChanOp::WriteOnly,
+            ChanOp::ReadWrite => ApiChanOp::ReadWrite,
+        }
+    }
+}
+
+/// Checks that `key` is a valid device metadata key.
+fn check_metadata_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        bail!("metadata key is empty");
+    }
+    if key.len() > MAX_DEVICE_METADATA_KEY_LEN {
+        bail!("metadata key is longer than {MAX_DEVICE_METADATA_KEY_LEN} bytes");
+    }
+    Ok(())
+}
+
+/// Describes the capabilities of each role.
+///
+/// This must be kept in sync with the checks in `policy.md`.
+fn role_infos() -> Vec<RoleInfo> {
+    use Operation as Op;
+
+    [
+        (
+            ApiRole::Owner,
+            vec![
+                Op::TerminateTeam,
+                Op::AddMember,
+                Op::RemoveMember,
+                Op::AssignOwner,
+                Op::AssignAdmin,
+                Op::AssignOperator,
+                Op::RevokeOwner,
+                Op::RevokeAdmin,
+                Op::RevokeOperator,
+                Op::DefineLabel,
+                Op::UndefineLabel,
+                Op::AssignLabel,
+                Op::RevokeLabel,
+                Op::SetNetworkName,
+                Op::UnsetNetworkName,
+                Op::SetDeviceMetadata,
+                Op::UnsetDeviceMetadata,
+                Op::UpgradePolicy,
+            ],
+            LabelScope::None,
+        ),
+        (
+            ApiRole::Admin,
+            vec![
+                Op::AssignOperator,
+                Op::RevokeOperator,
+                Op::DefineLabel,
+                Op::UndefineLabel,
+                Op::RevokeLabel,
+                Op::UnsetNetworkName,
+                Op::UnsetDeviceMetadata,
+            ],
+            LabelScope::None,
+        ),
+        (
+            ApiRole::Operator,
+            vec![
+                Op::AddMember,
+                Op::RemoveMember,
+                Op::DefineLabel,
+                Op::AssignLabel,
+                Op::RevokeLabel,
+                Op::SetNetworkName,
+                Op::UnsetNetworkName,
+                Op::SetDeviceMetadata,
+                Op::UnsetDeviceMetadata,
+            ],
+            LabelScope::None,
+        ),
+        (
+            ApiRole::Member,
+            vec![Op::CreateBidiChannel, Op::CreateUniChannel],
+            LabelScope::Assigned,
+        ),
+    ]
+    .into_iter()
+    .map(|(role, operations, label_scope)| RoleInfo {
+        role,
+        operations,
+        label_scope,
+    })
+    .collect()
+}
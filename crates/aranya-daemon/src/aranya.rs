@@ -1,9 +1,20 @@
 //! Aranya.
 
-use std::{borrow::Cow, future::Future, marker::PhantomData, net::SocketAddr, sync::Arc};
+use std::{
+    borrow::Cow,
+    future::{self, Future},
+    io,
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{bail, Context, Result};
 use aranya_crypto::{Csprng, Rng, UserId};
+use aranya_daemon_api::SyncStage;
 use aranya_fast_channels::Label;
 use aranya_keygen::PublicKeys;
 use aranya_policy_ifgen::{Actor, VmAction, VmEffect};
@@ -12,21 +23,41 @@ use aranya_runtime::{
     vm_action, ClientError, ClientState, Engine, GraphId, PeerCache, Policy, Session, Sink,
     StorageProvider, SyncRequester, SyncResponder, VmPolicy, MAX_SYNC_MESSAGE_SIZE,
 };
-use aranya_util::Addr;
+use aranya_util::{Addr, ShortId};
+use futures_util::SinkExt;
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, MutexGuard},
     task::JoinSet,
 };
+use tokio_tungstenite::{accept_async, tungstenite::Message};
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
 use crate::{
+    pins::{self, Signer, SyncPins},
     policy::{ActorExt, ChanOp, Effect, KeyBundle, Role},
+    sync::SyncPeers,
+    transport::{recv_binary, SyncTransports},
     vm_policy::{MsgSink, VecSink},
 };
 
+/// Prefixes a [`SyncHint`] sent to a sync server.
+///
+/// Sync requests never start with these bytes.
+const SYNC_HINT_MAGIC: &[u8] = b"ARANYA-SYNC-HINT";
+
+/// Asks a sync server to sync with its peers for a graph as soon
+/// as possible.
+///
+/// The server only syncs with peers that it already knows for
+/// the graph, so a hint cannot make it contact anybody new.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SyncHint {
+    graph_id: GraphId,
+}
+
 /// A response to a sync request.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SyncResponse {
@@ -36,21 +67,102 @@ pub enum SyncResponse {
     Err(String),
 }
 
+/// The progress of a sync session.
+///
+/// See [`Client::sync_peer_with_progress`].
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+    stage: AtomicU8,
+    bytes_received: AtomicU64,
+    commands: AtomicU64,
+}
+
+impl SyncProgress {
+    /// Returns what the session is doing.
+    pub fn stage(&self) -> SyncStage {
+        match self.stage.load(Ordering::Relaxed) {
+            0 => SyncStage::Requesting,
+            1 => SyncStage::Waiting,
+            _ => SyncStage::Processing,
+        }
+    }
+
+    fn set_stage(&self, stage: SyncStage) {
+        let n = match stage {
+            SyncStage::Requesting => 0,
+            SyncStage::Waiting => 1,
+            _ => 2,
+        };
+        self.stage.store(n, Ordering::Relaxed);
+    }
+
+    /// Returns the size in bytes of the peer's response, or
+    /// zero if it has not been received.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of commands received from the peer.
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+}
+
 /// Aranya client.
 pub struct Client<EN, SP, CE> {
     /// Thread-safe Aranya client reference.
     aranya: Arc<Mutex<ClientState<EN, SP>>>,
+    /// How to reach sync peers.
+    transports: SyncTransports,
+    /// Checks the keys that sync peers present, if set.
+    pins: Option<Arc<SyncPins>>,
     _eng: PhantomData<CE>,
 }
 
 impl<EN, SP, CE> Client<EN, SP, CE> {
     /// Creates a new [`Client`].
+    ///
+    /// It syncs with every peer over TCP unless
+    /// [`with_transports`][Self::with_transports] says
+    /// otherwise.
     pub fn new(aranya: Arc<Mutex<ClientState<EN, SP>>>) -> Self {
         Client {
             aranya,
+            transports: SyncTransports::default(),
+            pins: None,
             _eng: PhantomData,
         }
     }
+
+    /// Makes the client reach sync peers using `transports`.
+    pub fn with_transports(mut self, transports: SyncTransports) -> Self {
+        self.transports = transports;
+        self
+    }
+
+    /// Makes the client only accept sync responses from peers
+    /// that present the keys that `pins` expects.
+    ///
+    /// Otherwise, the keys that peers present are ignored.
+    pub fn with_pins(mut self, pins: Arc<SyncPins>) -> Self {
+        self.pins = Some(pins);
+        self
+    }
+
+    /// Replaces the client's state.
+    ///
+    /// This is used to switch policies at runtime.
+    pub async fn set_state(&self, state: ClientState<EN, SP>) {
+        *self.aranya.lock().await = state;
+    }
+
+    /// Locks the client's state.
+    ///
+    /// Nothing is written to storage while the guard is held,
+    /// which keeps it consistent during a backup.
+    pub async fn lock_state(&self) -> MutexGuard<'_, ClientState<EN, SP>> {
+        self.aranya.lock().await
+    }
 }
 
 impl<EN, SP, CE> Client<EN, SP, CE>
@@ -61,11 +173,28 @@ where
 {
     /// Syncs with the peer.
     /// Aranya client sends a `SyncRequest` to peer then processes the `SyncResponse`.
-    #[instrument(skip_all)]
     pub async fn sync_peer<S>(&self, id: GraphId, sink: &mut S, addr: &Addr) -> Result<()>
     where
         S: Sink<<EN as Engine>::Effect>,
     {
+        self.sync_peer_with_progress(id, sink, addr, &SyncProgress::default())
+            .await
+    }
+
+    /// Like [`sync_peer`][Self::sync_peer], but reports its
+    /// progress to `progress`.
+    #[instrument(skip_all)]
+    pub async fn sync_peer_with_progress<S>(
+        &self,
+        id: GraphId,
+        sink: &mut S,
+        addr: &Addr,
+        progress: &SyncProgress,
+    ) -> Result<()>
+    where
+        S: Sink<<EN as Engine>::Effect>,
+    {
+        progress.set_stage(SyncStage::Requesting);
         // send the sync request.
         let mut syncer = SyncRequester::new(id, &mut Rng);
         let mut send_buf = vec![0u8; MAX_SYNC_MESSAGE_SIZE];
@@ -79,27 +208,33 @@ where
         };
         debug!(?len, "sync poll finished");
         send_buf.truncate(len);
-        let mut stream = TcpStream::connect(addr.to_socket_addrs()).await?;
-        let addr = stream.peer_addr()?;
 
-        stream
-            .write_all(&send_buf)
-            .await
-            .context("failed to write sync request")?;
-        stream.shutdown().await?;
-        debug!(?addr, "sent sync request");
-
-        // get the sync response.
-        let mut recv = Vec::new();
-        stream
-            .read_to_end(&mut recv)
-            .await
-            .context("failed to read sync response")?;
+        // send the request and get the sync response.
+        progress.set_stage(SyncStage::Waiting);
+        let recv = self.transports.exchange(addr, &send_buf).await?;
         debug!(?addr, n = recv.len(), "received sync response");
+        progress.bytes_received.store(
+            u64::try_from(recv.len()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        progress.set_stage(SyncStage::Processing);
 
         // process the sync response.
-        let resp =
-            postcard::from_bytes(&recv).context("postcard unable to deserialize sync response")?;
+        let (resp, identity) = postcard::take_from_bytes(&recv)
+            .context("postcard unable to deserialize sync response")?;
+        if let Some(pins) = &self.pins {
+            // The server's identity follows the response.
+            let (response, _) = recv
+                .split_at_checked(recv.len().saturating_sub(identity.len()))
+                .context("sync identity is out of range")?;
+            let presented = match identity {
+                [] => None,
+                identity => pins::verify(&send_buf, response, identity)
+                    .inspect_err(|err| warn!(?addr, ?err, "invalid sync identity"))
+                    .ok(),
+            };
+            pins.check(addr, presented).await?;
+        }
         let data = match resp {
             SyncResponse::Ok(data) => data,
             SyncResponse::Err(msg) => bail!("sync error: {msg}"),
@@ -110,6 +245,10 @@ where
         }
         if let Some(cmds) = syncer.receive(&data)? {
             debug!(num = cmds.len(), "received commands");
+            progress.commands.store(
+                u64::try_from(cmds.len()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
             if !cmds.is_empty() {
                 let mut client = self.aranya.lock().await;
                 let mut trx = client.transaction(id);
@@ -125,6 +264,27 @@ where
         Ok(())
     }
 
+    /// Sends a [`SyncHint`] for `id` to the sync server at
+    /// `addr`.
+    #[instrument(skip_all)]
+    pub async fn send_sync_hint(&self, id: GraphId, addr: &Addr) -> Result<()> {
+        let mut buf = SYNC_HINT_MAGIC.to_vec();
+        postcard::to_io(&SyncHint { graph_id: id }, &mut buf)
+            .context("postcard unable to serialize sync hint")?;
+        // Wait for the server to acknowledge the hint.
+        let recv = self
+            .transports
+            .exchange(addr, &buf)
+            .await
+            .context("unable to send sync hint")?;
+        match postcard::from_bytes(&recv)
+            .context("postcard unable to deserialize sync hint response")?
+        {
+            SyncResponse::Ok(_) => Ok(()),
+            SyncResponse::Err(msg) => bail!("sync hint error: {msg}"),
+        }
+    }
+
     /// Creates the team.
     /// Creates a new graph, adds the `CreateTeam` command to the root of the graph.
     /// Returns the [`GraphId`] of the newly created graph.
@@ -153,7 +313,7 @@ where
 
     /// Returns an implementation of [`Actions`] for a particular
     /// storage.
-    #[instrument(skip_all, fields(id = %id))]
+    #[instrument(skip_all, fields(id = %ShortId::new("graph", id)))]
     pub fn actions(&self, id: &GraphId) -> impl Actions<EN, SP, CE> {
         ActionsImpl {
             aranya: Arc::clone(&self.aranya),
@@ -164,7 +324,7 @@ where
 
     /// Create new ephemeral Session.
     /// Once the Session has been created, call `session_receive` to add an ephemeral command to the Session.
-    #[instrument(skip_all, fields(id = %id))]
+    #[instrument(skip_all, fields(id = %ShortId::new("graph", id)))]
     pub async fn session_new(&self, id: &GraphId) -> Result<Session<SP, EN>> {
         let session = self.aranya.lock().await.session(*id)?;
         Ok(session)
@@ -244,8 +404,15 @@ pub struct Server<EN, SP> {
     aranya: Arc<Mutex<ClientState<EN, SP>>>,
     /// Used to receive sync requests and send responses.
     listener: TcpListener,
+    /// Like `listener`, but for sync requests over WebSocket,
+    /// if enabled.
+    ws_listener: Option<TcpListener>,
     /// Tracks running tasks.
     set: JoinSet<()>,
+    /// Acts on sync hints, if set.
+    hints: Option<SyncPeers>,
+    /// Signs responses, if set.
+    signer: Option<Arc<Signer>>,
 }
 
 impl<EN, SP> Server<EN, SP> {
@@ -255,14 +422,50 @@ impl<EN, SP> Server<EN, SP> {
         Self {
             aranya,
             listener,
+            ws_listener: None,
             set: JoinSet::new(),
+            hints: None,
+            signer: None,
         }
     }
 
+    /// Makes the server also accept sync requests over
+    /// WebSocket from `listener`.
+    pub fn with_ws(mut self, listener: TcpListener) -> Self {
+        self.ws_listener = Some(listener);
+        self
+    }
+
+    /// Makes the server act on [`SyncHint`]s by syncing with
+    /// `peers`.
+    ///
+    /// Otherwise, hints are ignored.
+    pub fn with_hints(mut self, peers: SyncPeers) -> Self {
+        self.hints = Some(peers);
+        self
+    }
+
+    /// Makes the server sign its responses with `signer` so that
+    /// requesters can check its identity.
+    pub fn with_signer(mut self, signer: Arc<Signer>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
     /// Returns the local address the sync server bound to.
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.listener.local_addr()?)
     }
+
+    /// Returns the local address the WebSocket sync server
+    /// bound to, if enabled.
+    pub fn ws_local_addr(&self) -> Result<Option<SocketAddr>> {
+        Ok(self
+            .ws_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?)
+    }
 }
 
 impl<EN, SP> Server<EN, SP>
@@ -273,36 +476,47 @@ where
     /// Begins accepting incoming requests.
     #[instrument(skip_all)]
     pub async fn serve(mut self) -> Result<()> {
+        #![allow(clippy::disallowed_macros)]
         // accept incoming connections to the server
         loop {
-            let incoming = self.listener.accept().await;
+            let (incoming, ws) = tokio::select! {
+                incoming = self.listener.accept() => (incoming, false),
+                incoming = accept_opt(self.ws_listener.as_ref()) => (incoming, true),
+            };
             let (mut stream, addr) = match incoming {
                 Ok(incoming) => incoming,
                 Err(err) => {
-                    error!(err = %err, "stream failure");
+                    error!(err = %err, ws, "stream failure");
                     continue;
                 }
             };
-            debug!(?addr, "received sync request");
+            debug!(?addr, ws, "received sync request");
 
             let client = Arc::clone(&self.aranya);
+            let hints = self.hints.clone();
+            let signer = self.signer.clone();
             self.set.spawn(
                 async move {
-                    if let Err(err) = Self::sync(client, &mut stream, addr).await {
+                    let result = if ws {
+                        Self::sync_ws(client, hints, signer, stream).await
+                    } else {
+                        Self::sync(client, hints, signer, &mut stream).await
+                    };
+                    if let Err(err) = result {
                         error!(%err, "request failure");
                     }
                 }
-                .instrument(info_span!("sync", %addr)),
+                .instrument(info_span!("sync", %addr, ws)),
             );
         }
     }
 
-    /// Responds to a sync.
-    #[instrument(skip_all, fields(addr = %addr))]
+    /// Responds to a sync over TCP.
     async fn sync(
         client: Arc<Mutex<ClientState<EN, SP>>>,
+        hints: Option<SyncPeers>,
+        signer: Option<Arc<Signer>>,
         stream: &mut TcpStream,
-        addr: SocketAddr,
     ) -> Result<()> {
         let mut recv = Vec::new();
         stream
@@ -311,8 +525,53 @@ where
             .context("failed to read sync request")?;
         debug!(n = recv.len(), "received sync request");
 
-        // Generate a sync response for a sync request.
-        let resp = match Self::sync_respond(client, &recv).await {
+        let data = Self::respond(client, hints, signer, &recv).await?;
+        stream.write_all(&data).await?;
+        stream.shutdown().await?;
+        debug!(n = data.len(), "sent sync response");
+
+        Ok(())
+    }
+
+    /// Responds to a sync over WebSocket.
+    async fn sync_ws(
+        client: Arc<Mutex<ClientState<EN, SP>>>,
+        hints: Option<SyncPeers>,
+        signer: Option<Arc<Signer>>,
+        stream: TcpStream,
+    ) -> Result<()> {
+        let mut ws = accept_async(stream)
+            .await
+            .context("WebSocket handshake failed")?;
+        let recv = recv_binary(&mut ws)
+            .await
+            .context("failed to read sync request")?;
+        debug!(n = recv.len(), "received sync request");
+
+        let data = Self::respond(client, hints, signer, &recv).await?;
+        let n = data.len();
+        ws.send(Message::Binary(data)).await?;
+        // The peer closes the stream once it has the response.
+        let _ = ws.close(None).await;
+        debug!(n, "sent sync response");
+
+        Ok(())
+    }
+
+    /// Handles a sync request or [`SyncHint`] and returns the
+    /// serialized response, signed by `signer`, if any.
+    async fn respond(
+        client: Arc<Mutex<ClientState<EN, SP>>>,
+        hints: Option<SyncPeers>,
+        signer: Option<Arc<Signer>>,
+        recv: &[u8],
+    ) -> Result<Vec<u8>> {
+        let result = match recv.strip_prefix(SYNC_HINT_MAGIC) {
+            Some(hint) => Self::handle_hint(hints, hint).await,
+            // Generate a sync response for a sync request.
+            None => Self::sync_respond(client, recv).await,
+        };
+        let resp = match result {
             Ok(data) => SyncResponse::Ok(data),
             Err(err) => {
                 error!(?err, "error responding to sync request");
@@ -320,14 +579,25 @@ where
             }
         };
         // Serialize the sync response.
-        let data =
-            &postcard::to_allocvec(&resp).context("postcard unable to serialize sync response")?;
-
-        stream.write_all(data).await?;
-        stream.shutdown().await?;
-        debug!(n = data.len(), "sent sync response");
+        let mut data =
+            postcard::to_allocvec(&resp).context("postcard unable to serialize sync response")?;
+        if let Some(signer) = signer {
+            let identity = signer.sign(recv, &data)?;
+            data.extend_from_slice(&identity);
+        }
+        Ok(data)
+    }
 
-        Ok(())
+    /// Handles a [`SyncHint`].
+    #[instrument(skip_all)]
+    async fn handle_hint(hints: Option<SyncPeers>, hint: &[u8]) -> Result<Box<[u8]>> {
+        let hint: SyncHint =
+            postcard::from_bytes(hint).context("postcard unable to deserialize sync hint")?;
+        debug!(graph_id = %hint.graph_id, "received sync hint");
+        if let Some(peers) = hints {
+            peers.sync_now(hint.graph_id).await?;
+        }
+        Ok(Box::default())
     }
 
     /// Generates a sync response for a sync request.
@@ -353,6 +623,15 @@ where
     }
 }
 
+/// Accepts a stream from `listener`, or waits forever if there
+/// is no listener.
+async fn accept_opt(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
 /// A programmatic API for policy actions.
 pub trait Actions<EN, SP, CE>
 where
@@ -384,6 +663,21 @@ where
         .in_current_span()
     }
 
+    /// Switches the team to another policy version, whose
+    /// bundle has the SHA-256 digest `digest`.
+    #[instrument(skip(self, digest))]
+    fn upgrade_policy(
+        &self,
+        version: u32,
+        digest: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.upgrade_policy(i64::from(version), digest)?;
+            Ok(())
+        })
+        .in_current_span()
+    }
+
     /// Adds a Member instance to the team.
     #[instrument(skip_all)]
     fn add_member(&self, keys: KeyBundle) -> impl Future<Output = Result<Vec<Effect>>> + Send {
@@ -511,6 +805,66 @@ where
         .in_current_span()
     }
 
+    /// Sets an entry of a device's metadata.
+    #[instrument(skip(self, value), fields(user_id = %user_id, key = %key))]
+    fn set_device_metadata(
+        &self,
+        user_id: UserId,
+        key: String,
+        value: String,
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        info!(%user_id, %key, "setting device metadata");
+        self.with_actor(move |actor| {
+            actor.set_device_metadata(user_id.into(), key, value)?;
+            Ok(())
+        })
+        .in_current_span()
+    }
+
+    /// Unsets an entry of a device's metadata.
+    #[instrument(skip(self), fields(user_id = %user_id, key = %key))]
+    fn unset_device_metadata(
+        &self,
+        user_id: UserId,
+        key: String,
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        info!(%user_id, %key, "unsetting device metadata");
+        self.with_actor(move |actor| {
+            actor.unset_device_metadata(user_id.into(), key)?;
+            Ok(())
+        })
+        .in_current_span()
+    }
+
+    /// Looks up an entry of a device's metadata off graph.
+    #[allow(clippy::type_complexity)]
+    #[instrument(skip(self), fields(user_id = %user_id, key = %key))]
+    fn query_device_metadata_off_graph(
+        &self,
+        user_id: UserId,
+        key: String,
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_device_metadata",
+            args: Cow::Owned(vec![Value::from(user_id), Value::from(key)]),
+        })
+        .in_current_span()
+    }
+
+    /// Lists the team's users, labels, and label assignments off
+    /// graph.
+    #[allow(clippy::type_complexity)]
+    #[instrument(skip(self))]
+    fn query_team_graph_off_graph(
+        &self,
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_team_graph",
+            args: Cow::Owned(vec![]),
+        })
+        .in_current_span()
+    }
+
     /// Creates a bidirectional AFC channel.
     #[instrument(skip(self), fields(peer_id = %peer_id, label = %label))]
     fn create_bidi_channel(
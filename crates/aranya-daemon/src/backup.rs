@@ -0,0 +1,435 @@
+//! Backups of the daemon's persistent state.
+//!
+//! A backup is a single archive with the daemon's keys, graph
+//! storage, and policy bundles:
+//!
+//! ```text
+//! MAGIC || postcard(Archive) || SHA-256(MAGIC || postcard(Archive))
+//! ```
+//!
+//! The digest detects corrupted or truncated backups. It does
+//! not prove who created the backup, and the backup holds the
+//! daemon's secret keys, so backups must be stored as carefully
+//! as the working directory itself.
+//!
+//! Backups are taken while the daemon is running (see
+//! `DaemonApi::backup`) and restored before it starts (see
+//! [`Daemon::restore`][crate::Daemon::restore]).
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use aranya_crypto::{hash::Hash, rust::Sha256};
+use aranya_daemon_api::{BackupInfo, DeviceId};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+/// Identifies backups.
+const MAGIC: &[u8; 8] = b"ARANYABK";
+
+/// The current [`Archive::version`].
+const VERSION: u32 = 1;
+
+/// The size of the digest at the end of a backup.
+const DIGEST_SIZE: usize = 32;
+
+/// Where state that a restore replaces is moved to, relative to
+/// the working directory.
+pub const PRE_RESTORE_DIR: &str = "pre_restore";
+
+/// Where a backup is unpacked before it replaces the existing
+/// state, relative to the working directory.
+const STAGING_DIR: &str = "restore.tmp";
+
+/// The contents of a backup.
+#[derive(Debug, Serialize, Deserialize)]
+struct Archive {
+    /// The format version.
+    version: u32,
+    device_id: DeviceId,
+    created: SystemTime,
+    files: Vec<File>,
+}
+
+/// A file in an [`Archive`].
+#[derive(Debug, Serialize, Deserialize)]
+struct File {
+    /// The file's path relative to the working directory, with
+    /// `/` separators.
+    path: String,
+    data: Vec<u8>,
+}
+
+/// A verified backup that is about to be restored.
+///
+/// See [`Daemon::restore`][crate::Daemon::restore].
+#[derive(Clone, Debug)]
+pub struct PendingRestore {
+    /// Describes the backup.
+    pub info: BackupInfo,
+    /// Whether the working directory already has state, which
+    /// is moved to [`PRE_RESTORE_DIR`] instead of being merged
+    /// with the backup.
+    pub replaces_state: bool,
+}
+
+/// Returns the paths that hold the daemon's persistent state.
+///
+/// Each is a direct child of the working directory.
+fn state_paths(cfg: &Config) -> [PathBuf; 7] {
+    [
+        cfg.key_wrap_key_path(),
+        cfg.key_bundle_path(),
+        cfg.state_path(),
+        cfg.keystore_path(),
+        cfg.storage_path(),
+        cfg.policies_path(),
+        cfg.sync_pins_path(),
+    ]
+}
+
+/// Writes a backup of `device_id`'s state to `path`, recording
+/// that it was created at `created`.
+///
+/// The caller must keep the state from changing until this
+/// returns.
+pub(crate) async fn create(
+    cfg: &Config,
+    device_id: DeviceId,
+    path: &Path,
+    created: SystemTime,
+) -> Result<BackupInfo> {
+    let mut files = Vec::new();
+    for root in state_paths(cfg) {
+        collect(&cfg.work_dir, &root, &mut files).await?;
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let archive = Archive {
+        version: VERSION,
+        device_id,
+        created,
+        files,
+    };
+
+    let mut buf = postcard::to_extend(&archive, MAGIC.to_vec())?;
+    let digest = digest(&buf)?;
+    buf.extend_from_slice(&digest);
+    aranya_util::write_file_atomic(path, &buf)
+        .await
+        .context("unable to write backup")?;
+
+    let info = describe(&archive, digest);
+    info!(files = info.files, bytes = info.bytes, "wrote backup");
+    Ok(info)
+}
+
+/// Checks the integrity of the backup at `path`.
+pub(crate) async fn verify(path: &Path) -> Result<BackupInfo> {
+    let (archive, digest) = read(path).await?;
+    Ok(describe(&archive, digest))
+}
+
+/// Restores the backup at `path` into `cfg`'s working directory
+/// if `confirm` approves it.
+pub(crate) async fn restore<F>(cfg: &Config, path: &Path, confirm: F) -> Result<BackupInfo>
+where
+    F: FnOnce(&PendingRestore) -> bool,
+{
+    let (archive, digest) = read(path).await?;
+    let info = describe(&archive, digest);
+
+    let roots = state_paths(cfg);
+    let names = roots
+        .iter()
+        .filter_map(|root| root.file_name())
+        .collect::<Vec<_>>();
+    for file in &archive.files {
+        let first = file.path.split('/').next().map(OsStr::new);
+        if !names.iter().any(|name| Some(*name) == first) {
+            bail!("backup has unexpected file `{}`", file.path);
+        }
+    }
+
+    let mut existing = Vec::new();
+    for root in &roots {
+        if fs::try_exists(root).await? {
+            existing.push(root);
+        }
+    }
+    let pending = PendingRestore {
+        info,
+        replaces_state: !existing.is_empty(),
+    };
+    if !confirm(&pending) {
+        bail!("restore was not confirmed");
+    }
+
+    // Unpack the backup first so that a failure cannot leave
+    // the working directory half restored.
+    aranya_util::create_dir_all(&cfg.work_dir).await?;
+    let staging = cfg.work_dir.join(STAGING_DIR);
+    remove_dir_if_exists(&staging).await?;
+    for file in &archive.files {
+        let dst = staging.join(&file.path);
+        if let Some(parent) = dst.parent() {
+            aranya_util::create_dir_all(parent).await?;
+        }
+        aranya_util::write_file(&dst, &file.data)
+            .await
+            .with_context(|| format!("unable to restore `{}`", file.path))?;
+    }
+
+    if !existing.is_empty() {
+        let aside = cfg.work_dir.join(PRE_RESTORE_DIR);
+        remove_dir_if_exists(&aside).await?;
+        aranya_util::create_dir_all(&aside).await?;
+        for root in existing {
+            if let Some(name) = root.file_name() {
+                fs::rename(root, aside.join(name)).await?;
+            }
+        }
+        warn!(path = %aside.display(), "moved existing state aside");
+    }
+    for root in &roots {
+        let Some(name) = root.file_name() else {
+            continue;
+        };
+        let staged = staging.join(name);
+        if fs::try_exists(&staged).await? {
+            fs::rename(&staged, root).await?;
+        }
+    }
+    fs::remove_dir_all(&staging).await?;
+
+    info!(
+        files = pending.info.files,
+        bytes = pending.info.bytes,
+        "restored backup"
+    );
+    Ok(pending.info)
+}
+
+/// Adds the files under `root` to `files`.
+///
+/// It is not an error if `root` does not exist.
+async fn collect(work_dir: &Path, root: &Path, files: &mut Vec<File>) -> Result<()> {
+    let mut todo = vec![root.to_owned()];
+    while let Some(path) = todo.pop() {
+        let meta = match fs::symlink_metadata(&path).await {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("unable to read `{}`", path.display()))
+            }
+        };
+        if meta.is_dir() {
+            let mut dir = fs::read_dir(&path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                todo.push(entry.path());
+            }
+        } else if meta.is_file() {
+            let data = fs::read(&path)
+                .await
+                .with_context(|| format!("unable to read `{}`", path.display()))?;
+            files.push(File {
+                path: relative(work_dir, &path)?,
+                data,
+            });
+        } else {
+            warn!(path = %path.display(), "skipping file that is not a regular file");
+        }
+    }
+    Ok(())
+}
+
+/// Returns `path` relative to `work_dir`, with `/` separators.
+fn relative(work_dir: &Path, path: &Path) -> Result<String> {
+    let parts = path
+        .strip_prefix(work_dir)?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("`{}` is not valid UTF-8", path.display()))?;
+    Ok(parts.join("/"))
+}
+
+/// Reads the backup at `path` and checks its integrity.
+///
+/// Returns the backup and its digest.
+async fn read(path: &Path) -> Result<(Archive, [u8; DIGEST_SIZE])> {
+    let buf = fs::read(path).await.context("unable to read backup")?;
+    ensure!(buf.starts_with(MAGIC), "not a backup");
+    let (body, want) = buf
+        .split_at_checked(buf.len().saturating_sub(DIGEST_SIZE))
+        .filter(|(body, _)| body.len() >= MAGIC.len())
+        .context("backup is truncated")?;
+    let digest = digest(body)?;
+    ensure!(digest == want, "backup is corrupted");
+
+    let rest = body.get(MAGIC.len()..).unwrap_or_default();
+    // Check the version before decoding the rest, which
+    // depends on it.
+    let (version, _) = postcard::take_from_bytes::<u32>(rest)?;
+    ensure!(version == VERSION, "unsupported backup version {version}");
+    let archive = postcard::from_bytes::<Archive>(rest)?;
+    for file in &archive.files {
+        check_path(&file.path)?;
+    }
+    Ok((archive, digest))
+}
+
+/// Checks that `path` stays within the working directory.
+fn check_path(path: &str) -> Result<()> {
+    let ok = path
+        .split('/')
+        .all(|part| !part.is_empty() && part != "." && part != "..");
+    ensure!(ok, "backup has invalid path `{path}`");
+    Ok(())
+}
+
+/// Returns the SHA-256 digest of `buf`.
+fn digest(buf: &[u8]) -> Result<[u8; DIGEST_SIZE]> {
+    Ok(Sha256::hash(buf)[..].try_into()?)
+}
+
+/// Describes `archive`.
+fn describe(archive: &Archive, digest: [u8; DIGEST_SIZE]) -> BackupInfo {
+    let bytes = archive.files.iter().fold(0u64, |n, file| {
+        n.saturating_add(u64::try_from(file.data.len()).unwrap_or(u64::MAX))
+    });
+    BackupInfo {
+        device_id: archive.device_id,
+        created: archive.created,
+        files: u64::try_from(archive.files.len()).unwrap_or(u64::MAX),
+        bytes,
+        digest,
+    }
+}
+
+/// Removes the directory at `path`, if it exists.
+async fn remove_dir_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path).await {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::expect_used, clippy::panic, clippy::indexing_slicing)]
+
+    use aranya_util::Addr;
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::*;
+    use crate::config::{AfcConfig, PolicyConfig, SyncConfig};
+
+    fn config(work_dir: PathBuf) -> Config {
+        Config {
+            name: "name".to_string(),
+            uds_api_path: work_dir.join("api"),
+            pid_file: work_dir.join("pid"),
+            work_dir,
+            sync_addr: Addr::new("localhost", 0).expect("should be able to create new Addr"),
+            sync: SyncConfig::default(),
+            afc: AfcConfig {
+                shm_path: "/test_backup".to_owned(),
+                unlink_on_startup: true,
+                unlink_at_exit: true,
+                create: true,
+                max_chans: 100,
+            },
+            policy: PolicyConfig::default(),
+        }
+    }
+
+    /// Tests backing up and restoring state.
+    #[test(tokio::test)]
+    async fn test_backup_restore() {
+        let dir = tempdir().expect("should be able to create temp dir");
+        let src = config(dir.path().join("src"));
+        fs::create_dir_all(src.storage_path().join("graph"))
+            .await
+            .expect("should create storage");
+        fs::write(src.key_wrap_key_path(), b"key").await.unwrap();
+        fs::write(src.storage_path().join("graph").join("0"), b"cmds")
+            .await
+            .unwrap();
+        // Not part of the state.
+        fs::write(&src.pid_file, b"123").await.unwrap();
+
+        let path = dir.path().join("backup");
+        let device_id = DeviceId::default();
+        let info = create(&src, device_id, &path, SystemTime::now())
+            .await
+            .expect("should create backup");
+        assert_eq!((info.files, info.bytes), (2, 7));
+        assert_eq!(verify(&path).await.expect("should verify"), info);
+
+        // Existing state is moved aside.
+        let dst = config(dir.path().join("dst"));
+        fs::create_dir_all(&dst.work_dir).await.unwrap();
+        fs::write(dst.key_bundle_path(), b"old").await.unwrap();
+        restore(&dst, &path, |pending| {
+            assert!(pending.replaces_state);
+            false
+        })
+        .await
+        .expect_err("should not restore without confirmation");
+        assert!(fs::try_exists(dst.key_bundle_path()).await.unwrap());
+        let got = restore(&dst, &path, |_| true)
+            .await
+            .expect("should restore");
+        assert_eq!(got, info);
+        assert_eq!(fs::read(dst.key_wrap_key_path()).await.unwrap(), b"key");
+        assert_eq!(
+            fs::read(dst.storage_path().join("graph").join("0"))
+                .await
+                .unwrap(),
+            b"cmds"
+        );
+        assert!(!fs::try_exists(dst.key_bundle_path()).await.unwrap());
+        assert_eq!(
+            fs::read(dst.work_dir.join(PRE_RESTORE_DIR).join("key_bundle"))
+                .await
+                .unwrap(),
+            b"old"
+        );
+        assert!(!fs::try_exists(dst.work_dir.join(STAGING_DIR))
+            .await
+            .unwrap());
+    }
+
+    /// Tests that corrupted backups are rejected.
+    #[test(tokio::test)]
+    async fn test_backup_corrupted() {
+        let dir = tempdir().expect("should be able to create temp dir");
+        let src = config(dir.path().join("src"));
+        fs::create_dir_all(&src.work_dir).await.unwrap();
+        fs::write(src.key_wrap_key_path(), b"key").await.unwrap();
+        let path = dir.path().join("backup");
+        create(&src, DeviceId::default(), &path, SystemTime::now())
+            .await
+            .expect("should create backup");
+
+        let mut buf = fs::read(&path).await.unwrap();
+        let mid = buf.len() / 2;
+        buf[mid] ^= 1;
+        fs::write(&path, &buf).await.unwrap();
+        let err = verify(&path).await.expect_err("should be corrupted");
+        assert!(err.to_string().contains("corrupted"), "{err}");
+
+        buf.truncate(MAGIC.len());
+        fs::write(&path, &buf).await.unwrap();
+        verify(&path).await.expect_err("should be truncated");
+    }
+}
@@ -0,0 +1,212 @@
+//! The daemon's time source.
+//!
+//! Devices without a real-time clock can boot with a wall clock
+//! that is far off, then jump once the clock is set, e.g., by
+//! NTP. So the daemon only uses the wall clock for timestamps
+//! that leave it (e.g., when a backup was created). Sync
+//! intervals, timeouts, and elapsed times use the monotonic
+//! clock, which does not jump.
+//!
+//! [`ClockMonitor`] compares the two clocks to detect jumps of
+//! the wall clock and keeps the recent ones so that clients can
+//! compensate (see `DaemonApi::clock_jumps`).
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use aranya_daemon_api::ClockJump;
+use tokio::{sync::Mutex, time};
+use tracing::warn;
+
+/// How often [`ClockMonitor::run`] compares the clocks.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much further the wall clock has to move than the
+/// monotonic clock between two checks to count as a jump.
+pub const JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// The number of jumps that are kept.
+const MAX_JUMPS: usize = 16;
+
+/// A source of time.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// Returns the wall-clock time, which can jump.
+    fn now(&self) -> SystemTime;
+
+    /// Returns the monotonic time, which does not jump.
+    fn instant(&self) -> Instant;
+}
+
+/// The operating system's clocks.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Detects jumps of a [`Clock`]'s wall clock.
+#[derive(Debug)]
+pub(crate) struct ClockMonitor {
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The clocks at the last check.
+    last: Option<(SystemTime, Instant)>,
+    /// The most recent jumps, oldest first.
+    jumps: VecDeque<ClockJump>,
+    /// The sequence number of the next jump.
+    next_seq: u64,
+}
+
+impl ClockMonitor {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Returns the wall-clock time.
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    /// Compares the clocks, recording a jump if the wall clock
+    /// moved more than [`JUMP_THRESHOLD`] further than the
+    /// monotonic clock since the last check.
+    ///
+    /// Returns the jump, if any.
+    pub async fn check(&self) -> Option<ClockJump> {
+        let wall = self.clock.now();
+        let mono = self.clock.instant();
+        let mut state = self.state.lock().await;
+        let (last_wall, last_mono) = state.last.replace((wall, mono))?;
+
+        let elapsed = mono.saturating_duration_since(last_mono);
+        let (by, backward) = match wall.duration_since(last_wall) {
+            Ok(d) if d >= elapsed => (d.saturating_sub(elapsed), false),
+            Ok(d) => (elapsed.saturating_sub(d), true),
+            Err(err) => (err.duration().saturating_add(elapsed), true),
+        };
+        if by <= JUMP_THRESHOLD {
+            return None;
+        }
+
+        let jump = ClockJump {
+            seq: state.next_seq,
+            at: wall,
+            by,
+            backward,
+        };
+        warn!(?by, backward, "wall clock jumped");
+        state.next_seq = state.next_seq.saturating_add(1);
+        if state.jumps.len() >= MAX_JUMPS {
+            state.jumps.pop_front();
+        }
+        state.jumps.push_back(jump);
+        Some(jump)
+    }
+
+    /// Returns the kept jumps after the one with sequence number
+    /// `after`, or all of them if `after` is `None`, oldest
+    /// first.
+    pub async fn jumps(&self, after: Option<u64>) -> Vec<ClockJump> {
+        self.state
+            .lock()
+            .await
+            .jumps
+            .iter()
+            .filter(|jump| after.map_or(true, |after| jump.seq > after))
+            .copied()
+            .collect()
+    }
+
+    /// Checks the clocks every [`CHECK_INTERVAL`].
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.check().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::expect_used, clippy::panic, clippy::indexing_slicing)]
+
+    use std::sync::Mutex as StdMutex;
+
+    use test_log::test;
+
+    use super::*;
+
+    /// A clock that only moves when told to.
+    #[derive(Debug)]
+    struct ManualClock(StdMutex<(SystemTime, Instant)>);
+
+    impl ManualClock {
+        fn advance(&self, wall: Duration, mono: Duration, backward: bool) {
+            let mut now = self.0.lock().unwrap();
+            now.0 = if backward { now.0 - wall } else { now.0 + wall };
+            now.1 += mono;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            self.0.lock().unwrap().0
+        }
+
+        fn instant(&self) -> Instant {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    /// Tests that jumps of the wall clock are detected.
+    #[test(tokio::test)]
+    async fn test_clock_jumps() {
+        let clock = Arc::new(ManualClock(StdMutex::new((
+            SystemTime::UNIX_EPOCH,
+            Instant::now(),
+        ))));
+        let monitor = ClockMonitor::new(Arc::clone(&clock) as Arc<dyn Clock>);
+        assert_eq!(monitor.check().await, None);
+
+        // The clocks move together.
+        let sec = Duration::from_secs(1);
+        clock.advance(sec, sec, false);
+        assert_eq!(monitor.check().await, None);
+
+        // NTP sets the clock decades ahead.
+        let ahead = Duration::from_secs(50 * 365 * 24 * 60 * 60);
+        clock.advance(ahead + sec, sec, false);
+        let jump = monitor.check().await.expect("should detect forward jump");
+        assert_eq!((jump.seq, jump.by, jump.backward), (0, ahead, false));
+
+        // And then back a bit.
+        clock.advance(10 * sec, sec, true);
+        let jump = monitor.check().await.expect("should detect backward jump");
+        assert_eq!((jump.seq, jump.by, jump.backward), (1, 11 * sec, true));
+
+        let seqs = |jumps: Vec<ClockJump>| jumps.iter().map(|j| j.seq).collect::<Vec<_>>();
+        assert_eq!(seqs(monitor.jumps(None).await), [0, 1]);
+        assert_eq!(seqs(monitor.jumps(Some(0)).await), [1]);
+        assert_eq!(seqs(monitor.jumps(Some(1)).await), Vec::<u64>::new());
+    }
+}
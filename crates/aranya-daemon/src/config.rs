@@ -1,14 +1,26 @@
 //! Daemon configuration.
+//!
+//! The configuration is read from an (h)json file. Any field can
+//! be overridden with an environment variable named
+//! `ARANYA_DAEMON_<FIELD>`, where `<FIELD>` is the upper-cased
+//! field path with `.` replaced by `_`. For example,
+//! `ARANYA_DAEMON_AFC_MAX_CHANS` overrides `afc.max_chans`.
 
 use std::{
-    fs,
+    env, error, fmt, fs,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::{Context, Result};
-use aranya_util::Addr;
+use aranya_daemon_api::SyncPin;
+use aranya_util::{Addr, ShmPathBuf};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+/// The prefix for environment variables that override
+/// configuration fields.
+pub const ENV_PREFIX: &str = "ARANYA_DAEMON_";
+
 /// Options for configuring the daemon.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -28,24 +40,153 @@ pub struct Config {
     /// Network address of Aranya sync server.
     pub sync_addr: Addr,
 
+    /// Sync transport configuration.
+    #[serde(default)]
+    pub sync: SyncConfig,
+
     /// AFC configuration.
     pub afc: AfcConfig,
+
+    /// Policy configuration.
+    #[serde(default)]
+    pub policy: PolicyConfig,
 }
 
 // TODO: remove allow dead_code once all methods are used.
 #[allow(dead_code)]
 impl Config {
-    /// Reads the configuration from `path`.
+    /// Reads the configuration from `path`, applies any
+    /// environment variable overrides, then validates the
+    /// result.
+    ///
+    /// All invalid fields are reported at once.
     pub fn load<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let cfg: Self = read_json(path.as_ref())
+        let mut cfg: Self = read_json(path.as_ref())
             .context(format!("unable to parse config: {:?}", path.as_ref()))?;
 
+        let mut errs = cfg.apply_env(|key| env::var(key).ok());
+        errs.extend(cfg.check());
+        if !errs.is_empty() {
+            return Err(ConfigError(errs)).context(format!("invalid config: {:?}", path.as_ref()));
+        }
+
         Ok(cfg)
     }
 
+    /// Validates the configuration.
+    ///
+    /// All invalid fields are reported at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let errs = self.check();
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(errs))
+        }
+    }
+
+    /// Overrides fields with environment variables.
+    ///
+    /// `get` returns the value of an environment variable, if
+    /// set.
+    pub fn apply_env_overrides<F>(&mut self, get: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let errs = self.apply_env(get);
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(errs))
+        }
+    }
+
+    /// Returns the effective configuration as pretty-printed
+    /// JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("unable to serialize config")
+    }
+
+    fn apply_env<F>(&mut self, get: F) -> Vec<FieldError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut errs = Vec::new();
+        let mut env = EnvOverrides {
+            get,
+            errs: &mut errs,
+        };
+        env.apply("name", &mut self.name);
+        env.apply("work_dir", &mut self.work_dir);
+        env.apply("uds_api_path", &mut self.uds_api_path);
+        env.apply("pid_file", &mut self.pid_file);
+        env.apply("sync_addr", &mut self.sync_addr);
+        env.apply_opt("sync.ws_addr", &mut self.sync.ws_addr);
+        env.apply_opt("sync.max_sessions", &mut self.sync.max_sessions);
+        env.apply("sync.pinning", &mut self.sync.pinning);
+        env.apply("afc.shm_path", &mut self.afc.shm_path);
+        env.apply("afc.unlink_on_startup", &mut self.afc.unlink_on_startup);
+        env.apply("afc.unlink_at_exit", &mut self.afc.unlink_at_exit);
+        env.apply("afc.create", &mut self.afc.create);
+        env.apply("afc.max_chans", &mut self.afc.max_chans);
+        env.apply_opt("policy.pinned_version", &mut self.policy.pinned_version);
+        errs
+    }
+
+    fn check(&self) -> Vec<FieldError> {
+        let mut errs = Vec::new();
+        if self.name.is_empty() {
+            errs.push(FieldError::new("name", "must not be empty"));
+        }
+        for (field, path) in [
+            ("work_dir", &self.work_dir),
+            ("uds_api_path", &self.uds_api_path),
+            ("pid_file", &self.pid_file),
+        ] {
+            if path.as_os_str().is_empty() {
+                errs.push(FieldError::new(field, "must not be empty"));
+            }
+        }
+        if let Err(err) = ShmPathBuf::from_str(&self.afc.shm_path) {
+            errs.push(FieldError::new(
+                "afc.shm_path",
+                format!("invalid shared memory path: {err}"),
+            ));
+        }
+        if self.afc.max_chans == 0 {
+            errs.push(FieldError::new("afc.max_chans", "must be at least 1"));
+        }
+        if self.sync.max_sessions == Some(0) {
+            errs.push(FieldError::new("sync.max_sessions", "must be at least 1"));
+        }
+        for (i, peer) in self.sync.peers.iter().enumerate() {
+            if !(peer.url.starts_with("ws://") || peer.url.starts_with("wss://")) {
+                errs.push(FieldError::new(
+                    "sync.peers",
+                    format!("peer {i}: URL must start with `ws://` or `wss://`"),
+                ));
+            }
+            if self.sync.peers.iter().take(i).any(|p| p.addr == peer.addr) {
+                errs.push(FieldError::new(
+                    "sync.peers",
+                    format!("peer {i}: duplicate address `{}`", peer.addr),
+                ));
+            }
+        }
+        for (i, pin) in self.sync.pins.iter().enumerate() {
+            if self.sync.pins.iter().take(i).any(|p| p.addr == pin.addr) {
+                errs.push(FieldError::new(
+                    "sync.pins",
+                    format!("pin {i}: duplicate address `{}`", pin.addr),
+                ));
+            }
+        }
+        errs
+    }
+
     /// Path to `State`.
     pub(crate) fn state_path(&self) -> PathBuf {
         self.work_dir.join("app_state.cbor")
@@ -70,6 +211,124 @@ impl Config {
     pub(crate) fn storage_path(&self) -> PathBuf {
         self.work_dir.join("storage")
     }
+
+    /// Path to the policy bundles.
+    pub(crate) fn policies_path(&self) -> PathBuf {
+        self.work_dir.join("policies")
+    }
+
+    /// Path to the sync peers' pinned keys.
+    pub(crate) fn sync_pins_path(&self) -> PathBuf {
+        self.work_dir.join("sync_pins.cbor")
+    }
+}
+
+/// Applies environment variable overrides, collecting errors.
+struct EnvOverrides<'a, F> {
+    get: F,
+    errs: &'a mut Vec<FieldError>,
+}
+
+impl<F> EnvOverrides<'_, F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    /// Overrides `field` if its environment variable is set.
+    fn apply<T>(&mut self, field: &'static str, dst: &mut T)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let key = env_var(field);
+        let Some(value) = (self.get)(&key) else {
+            return;
+        };
+        match value.parse() {
+            Ok(v) => *dst = v,
+            Err(err) => self.errs.push(FieldError::new(
+                field,
+                format!("invalid value in `{key}`: {err}"),
+            )),
+        }
+    }
+
+    /// Like [`apply`][Self::apply], but an empty value clears
+    /// `field`.
+    fn apply_opt<T>(&mut self, field: &'static str, dst: &mut Option<T>)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let key = env_var(field);
+        let Some(value) = (self.get)(&key) else {
+            return;
+        };
+        if value.is_empty() {
+            *dst = None;
+            return;
+        }
+        match value.parse() {
+            Ok(v) => *dst = Some(v),
+            Err(err) => self.errs.push(FieldError::new(
+                field,
+                format!("invalid value in `{key}`: {err}"),
+            )),
+        }
+    }
+}
+
+/// Returns the environment variable that overrides `field`.
+fn env_var(field: &str) -> String {
+    format!("{ENV_PREFIX}{}", field.replace('.', "_").to_uppercase())
+}
+
+/// An invalid configuration.
+///
+/// It contains every invalid field, not just the first.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigError(Vec<FieldError>);
+
+impl ConfigError {
+    /// Returns the invalid fields.
+    pub fn fields(&self) -> &[FieldError] {
+        &self.0
+    }
+}
+
+impl error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invalid field(s)", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "\n  - {err}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An invalid configuration field.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldError {
+    /// The path to the field, like `afc.max_chans`.
+    pub field: &'static str,
+    /// Why the field is invalid.
+    pub msg: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, msg: impl Into<String>) -> Self {
+        Self {
+            field,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.msg)
+    }
 }
 
 /// Reads JSON from `path`.
@@ -87,26 +346,182 @@ pub struct AfcConfig {
 
     /// Unlink `shm_path` before creating the shared memory?
     ///
-    /// Ignored if `create` is false.
+    /// Ignored if `create` is false. Defaults to false.
+    #[serde(default)]
     pub unlink_on_startup: bool,
 
     /// Unlink `shm_path` before on exit?
     ///
     /// If false, the shared memory will persist across daemon
-    /// restarts.
+    /// restarts. Defaults to false.
+    #[serde(default)]
     pub unlink_at_exit: bool,
 
     /// Create the shared memory?
+    ///
+    /// Defaults to true.
+    #[serde(default = "default_create")]
     pub create: bool,
 
     /// Maximum number of channels AFC should support.
+    ///
+    /// Defaults to [`AfcConfig::DEFAULT_MAX_CHANS`].
+    #[serde(default = "default_max_chans")]
     pub max_chans: usize,
 }
 
+impl AfcConfig {
+    /// The default for [`max_chans`][Self::max_chans].
+    pub const DEFAULT_MAX_CHANS: usize = 100;
+}
+
+fn default_create() -> bool {
+    true
+}
+
+fn default_max_chans() -> usize {
+    AfcConfig::DEFAULT_MAX_CHANS
+}
+
+/// Sync transport configuration.
+///
+/// By default, the daemon syncs over plain TCP. Networks that
+/// only allow outbound HTTPS can sync over WebSocket instead,
+/// optionally through an HTTP proxy.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Also serves sync requests over WebSocket at this
+    /// address.
+    ///
+    /// The daemon speaks plain WebSocket, so peers that connect
+    /// with `wss://` need a reverse proxy in front of it that
+    /// terminates TLS (e.g., on port 443).
+    ///
+    /// Defaults to `None`.
+    #[serde(default)]
+    pub ws_addr: Option<Addr>,
+
+    /// Peers to sync with over WebSocket instead of TCP.
+    ///
+    /// Other peers are synced with over TCP. Defaults to none.
+    #[serde(default)]
+    pub peers: Vec<WsPeerConfig>,
+
+    /// The maximum number of sync sessions that run at once.
+    ///
+    /// Each peer has at most one session at a time, so a slow
+    /// peer only delays its own syncs. Defaults to `None`,
+    /// which allows [`DEFAULT_MAX_SYNC_SESSIONS`].
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+
+    /// How to treat sync peers whose keys are not pinned.
+    ///
+    /// Defaults to [`PinMode::Manual`].
+    #[serde(default)]
+    pub pinning: PinMode,
+
+    /// The keys that sync peers must present.
+    ///
+    /// When the daemon starts, these replace the pins of the
+    /// same peers that were made while it was running (e.g.,
+    /// with `pin_sync_peer`). Defaults to none.
+    #[serde(default)]
+    pub pins: Vec<SyncPin>,
+}
+
+/// The default for [`SyncConfig::max_sessions`].
+pub const DEFAULT_MAX_SYNC_SESSIONS: usize = 16;
+
+/// How the daemon treats sync peers whose keys are not pinned.
+///
+/// Every sync response is signed by the responding daemon. Peers
+/// whose keys are pinned must always present the pinned key, no
+/// matter the mode, so that another host cannot stand in for
+/// them.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinMode {
+    /// Syncs with them without pinning them. Only peers that
+    /// were pinned explicitly are checked.
+    #[default]
+    Manual,
+    /// Pins the key that they present the first time they are
+    /// synced with ("trust on first use").
+    Tofu,
+    /// Refuses to sync with them.
+    Strict,
+}
+
+impl FromStr for PinMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(Self::Manual),
+            "tofu" => Ok(Self::Tofu),
+            "strict" => Ok(Self::Strict),
+            _ => Err(format!(
+                "unknown pin mode `{s}`, expected `manual`, `tofu`, or `strict`"
+            )),
+        }
+    }
+}
+
+/// How to reach a sync peer over WebSocket.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WsPeerConfig {
+    /// The peer's sync address, as added with `add_sync_peer`.
+    pub addr: Addr,
+
+    /// The peer's WebSocket URL, like
+    /// `wss://sync.example.com/aranya`.
+    pub url: String,
+
+    /// An HTTP proxy to tunnel the connection through.
+    ///
+    /// Defaults to `None`, which connects directly.
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// An HTTP proxy that supports `CONNECT`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// The proxy's address.
+    pub addr: Addr,
+
+    /// Credentials for basic authentication, as
+    /// `user:password`.
+    ///
+    /// Defaults to `None`.
+    #[serde(default)]
+    pub auth: Option<String>,
+}
+
+/// Policy configuration.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Pins the daemon to a policy version.
+    ///
+    /// A pinned daemon starts with this version and ignores
+    /// policy upgrades to any other version. Version 0 is the
+    /// policy built into the daemon.
+    ///
+    /// Defaults to `None`, which follows the team's upgrades.
+    #[serde(default)]
+    pub pinned_version: Option<u32>,
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
 
+    use aranya_daemon_api::SyncKeyId;
     use pretty_assertions::assert_eq;
 
     use super::*;
@@ -122,6 +537,23 @@ mod tests {
             uds_api_path: "/var/run/uds.sock".parse()?,
             pid_file: "/var/run/hub.pid".parse()?,
             sync_addr: Addr::new(Ipv4Addr::UNSPECIFIED.to_string(), 4321)?,
+            sync: SyncConfig {
+                ws_addr: None,
+                peers: vec![WsPeerConfig {
+                    addr: Addr::new("peer.example.com", 4321)?,
+                    url: "wss://sync.example.com/aranya".into(),
+                    proxy: Some(ProxyConfig {
+                        addr: Addr::new("proxy.example.com", 3128)?,
+                        auth: None,
+                    }),
+                }],
+                max_sessions: None,
+                pinning: PinMode::Tofu,
+                pins: vec![SyncPin {
+                    addr: Addr::new("peer.example.com", 4321)?,
+                    key: "3KmsfJLii5T5fukSQqNSiJFjMqcGbiTNm5BFHQQFicNB".parse()?,
+                }],
+            },
             afc: AfcConfig {
                 shm_path: "/hub".to_owned(),
                 unlink_on_startup: false,
@@ -129,8 +561,145 @@ mod tests {
                 create: true,
                 max_chans: 100,
             },
+            policy: PolicyConfig {
+                pinned_version: None,
+            },
         };
         assert_eq!(got, want);
         Ok(())
     }
+
+    fn example() -> Config {
+        Config {
+            name: "name".to_string(),
+            work_dir: "/var/lib/work_dir".into(),
+            uds_api_path: "/var/run/uds.sock".into(),
+            pid_file: "/var/run/hub.pid".into(),
+            sync_addr: Addr::new(Ipv4Addr::UNSPECIFIED.to_string(), 4321).unwrap(),
+            sync: SyncConfig::default(),
+            afc: AfcConfig {
+                shm_path: "/hub".to_owned(),
+                unlink_on_startup: false,
+                unlink_at_exit: false,
+                create: true,
+                max_chans: 100,
+            },
+            policy: PolicyConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_config_defaults() -> Result<()> {
+        let got: Config = deser_hjson::from_str(
+            r#"{
+                "name": "name",
+                "work_dir": "/var/lib/work_dir",
+                "uds_api_path": "/var/run/uds.sock",
+                "pid_file": "/var/run/hub.pid",
+                "sync_addr": "0.0.0.0:4321",
+                "afc": {
+                    "shm_path": "/hub",
+                },
+            }"#,
+        )?;
+        assert_eq!(got, example());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_env_overrides() {
+        let mut cfg = example();
+        cfg.apply_env_overrides(|key| match key {
+            "ARANYA_DAEMON_NAME" => Some("other".into()),
+            "ARANYA_DAEMON_SYNC_ADDR" => Some("127.0.0.1:1234".into()),
+            "ARANYA_DAEMON_SYNC_WS_ADDR" => Some("127.0.0.1:8080".into()),
+            "ARANYA_DAEMON_SYNC_MAX_SESSIONS" => Some("4".into()),
+            "ARANYA_DAEMON_SYNC_PINNING" => Some("strict".into()),
+            "ARANYA_DAEMON_AFC_MAX_CHANS" => Some("42".into()),
+            "ARANYA_DAEMON_AFC_CREATE" => Some("false".into()),
+            "ARANYA_DAEMON_POLICY_PINNED_VERSION" => Some("3".into()),
+            _ => None,
+        })
+        .unwrap();
+
+        let mut want = example();
+        want.name = "other".into();
+        want.sync_addr = Addr::new(Ipv4Addr::LOCALHOST.to_string(), 1234).unwrap();
+        want.sync.ws_addr = Some(Addr::new(Ipv4Addr::LOCALHOST.to_string(), 8080).unwrap());
+        want.sync.max_sessions = Some(4);
+        want.sync.pinning = PinMode::Strict;
+        want.afc.max_chans = 42;
+        want.afc.create = false;
+        want.policy.pinned_version = Some(3);
+        assert_eq!(cfg, want);
+
+        // An empty value unpins the version.
+        cfg.apply_env_overrides(|key| match key {
+            "ARANYA_DAEMON_POLICY_PINNED_VERSION" => Some(String::new()),
+            _ => None,
+        })
+        .unwrap();
+        want.policy.pinned_version = None;
+        assert_eq!(cfg, want);
+    }
+
+    #[test]
+    fn test_config_env_overrides_invalid() {
+        let mut cfg = example();
+        let err = cfg
+            .apply_env_overrides(|key| match key {
+                "ARANYA_DAEMON_AFC_MAX_CHANS" => Some("lots".into()),
+                "ARANYA_DAEMON_AFC_CREATE" => Some("maybe".into()),
+                "ARANYA_DAEMON_SYNC_PINNING" => Some("sometimes".into()),
+                _ => None,
+            })
+            .unwrap_err();
+        let fields = err.fields().iter().map(|e| e.field).collect::<Vec<_>>();
+        assert_eq!(fields, ["sync.pinning", "afc.create", "afc.max_chans"]);
+    }
+
+    #[test]
+    fn test_config_validate_reports_all_fields() {
+        assert_eq!(example().validate(), Ok(()));
+
+        let mut cfg = example();
+        cfg.name = String::new();
+        cfg.pid_file = PathBuf::new();
+        cfg.afc.shm_path = "/hub\0".into();
+        cfg.afc.max_chans = 0;
+        let peer = WsPeerConfig {
+            addr: Addr::new("peer", 4321).unwrap(),
+            url: "https://peer".into(),
+            proxy: None,
+        };
+        cfg.sync.peers = vec![peer.clone(), peer];
+        let pin = SyncPin {
+            addr: Addr::new("peer", 4321).unwrap(),
+            key: SyncKeyId::default(),
+        };
+        cfg.sync.pins = vec![pin, pin];
+        let err = cfg.validate().unwrap_err();
+        let fields = err.fields().iter().map(|e| e.field).collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "name",
+                "pid_file",
+                "afc.shm_path",
+                "afc.max_chans",
+                "sync.peers",
+                "sync.peers",
+                "sync.peers",
+                "sync.pins",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_to_json() -> Result<()> {
+        let cfg = example();
+        let got: Config = serde_json::from_str(&cfg.to_json()?)?;
+        assert_eq!(got, cfg);
+        Ok(())
+    }
 }
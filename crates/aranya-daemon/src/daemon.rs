@@ -3,9 +3,10 @@ use std::{io, path::Path, str::FromStr, sync::Arc};
 use anyhow::{anyhow, bail, Context, Result};
 use aranya_crypto::{
     aead::Aead, default::DefaultEngine, generic_array::GenericArray, import::Import,
-    keys::SecretKeyBytes, keystore::fs_keystore::Store, CipherSuite, Random, Rng,
+    keys::SecretKeyBytes, keystore::fs_keystore::Store, CipherSuite, KeyStoreExt, Random, Rng,
+    SigningKey,
 };
-use aranya_daemon_api::CS;
+use aranya_daemon_api::{BackupInfo, CS};
 use aranya_fast_channels::shm::{self, Flag, Mode, WriteState};
 use aranya_keygen::{KeyBundle, PublicKeys};
 use aranya_runtime::{
@@ -21,10 +22,15 @@ use tracing::{debug, error, info};
 use crate::{
     api::DaemonApiServer,
     aranya,
-    config::Config,
+    backup::{self, PendingRestore},
+    clock::{Clock, ClockMonitor, SystemClock},
+    config::{Config, DEFAULT_MAX_SYNC_SESSIONS},
+    pins::{Signer, SyncPins},
+    policies::Policies,
     policy,
     sync::Syncer,
-    vm_policy::{PolicyEngine, TEST_POLICY_1},
+    transport::SyncTransports,
+    vm_policy::PolicyEngine,
 };
 
 // Use short names so that we can more easily add generics.
@@ -47,12 +53,42 @@ type KeyWrapKey = <<CS as CipherSuite>::Aead as Aead>::Key;
 /// The daemon itself.
 pub struct Daemon {
     cfg: Config,
+    clock: Arc<dyn Clock>,
 }
 
 impl Daemon {
     /// Loads a `Daemon` using its config.
+    ///
+    /// It is an error if the config is invalid.
     pub async fn load(cfg: Config) -> Result<Self> {
-        Ok(Self { cfg })
+        cfg.validate().context("invalid config")?;
+        Ok(Self {
+            cfg,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Uses `clock` instead of the operating system's clocks.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Restores the daemon's state from the backup at `path`.
+    ///
+    /// The backup is verified, then `confirm` decides whether to
+    /// restore it. Any existing state is moved to
+    /// [`PRE_RESTORE_DIR`][backup::PRE_RESTORE_DIR] in the
+    /// working directory.
+    ///
+    /// This must be called before [`run`][Self::run].
+    pub async fn restore<F>(&self, path: &Path, confirm: F) -> Result<BackupInfo>
+    where
+        F: FnOnce(&PendingRestore) -> bool,
+    {
+        backup::restore(&self.cfg, path, confirm)
+            .await
+            .context("unable to restore backup")
     }
 
     /// The daemon's entrypoint.
@@ -69,29 +105,61 @@ impl Daemon {
             let key = self.load_or_gen_key_wrap_key().await?;
             CE::new(&key, Rng)
         };
-        let pk = self.load_or_gen_public_keys(&mut eng, &mut store).await?;
+        let bundle = self.load_or_gen_key_bundle(&mut eng, &mut store).await?;
+        let pk = bundle.public_keys(&mut eng, &store)?;
+
+        let policies = Policies::open(self.cfg.policies_path(), self.cfg.policy.pinned_version)
+            .await
+            .context("unable to open policy bundles")?;
+        let policy_doc = policies
+            .read(policies.active())
+            .await
+            .context("unable to load policy")?;
+        info!(version = policies.active(), "loaded policy");
 
         // Initialize Aranya client.
-        let (client, local_addr) = {
-            let (client, server) = self
-                .setup_aranya(
-                    eng.clone(),
-                    store.try_clone().context("unable to clone keystore")?,
-                    &pk,
-                    self.cfg.sync_addr,
-                )
-                .await?;
-            let local_addr = server.local_addr()?;
-            let client = Arc::new(client);
-            set.spawn(async move { server.serve().await });
-
-            (client, local_addr)
+        let (client, server) = self
+            .setup_aranya(
+                &policy_doc,
+                eng.clone(),
+                store.try_clone().context("unable to clone keystore")?,
+                &pk,
+                self.cfg.sync_addr,
+            )
+            .await?;
+        let local_addr = server.local_addr()?;
+
+        // Sign sync responses and check the keys that sync peers
+        // present.
+        let signer = {
+            let sk = store
+                .get_key::<_, SigningKey<CS>>(&mut eng, bundle.sign_id.into())
+                .context("unable to load `SigningKey`")?
+                .context("unable to find `SigningKey`")?;
+            Arc::new(Signer::new(sk, &pk.sign_pk)?)
         };
+        let pins = Arc::new(
+            SyncPins::load(
+                self.cfg.sync_pins_path(),
+                &self.cfg.sync,
+                Arc::clone(&self.clock),
+            )
+            .await?,
+        );
+        let client = Arc::new(client.with_pins(Arc::clone(&pins)));
 
         // Sync in the background at some specified interval.
         // Effects are sent to `Api` via `mux`.
         let (send_effects, recv_effects) = tokio::sync::mpsc::channel(256);
-        let (mut syncer, peers) = Syncer::new(Arc::clone(&client), send_effects);
+        let max_sessions = self
+            .cfg
+            .sync
+            .max_sessions
+            .unwrap_or(DEFAULT_MAX_SYNC_SESSIONS);
+        let (mut syncer, peers) = Syncer::new(Arc::clone(&client), send_effects, max_sessions);
+        // Sync hints received by the server trigger urgent syncs.
+        let server = server.with_hints(peers.clone()).with_signer(signer);
+        set.spawn(async move { server.serve().await });
         set.spawn(async move {
             loop {
                 if let Err(err) = syncer.next().await {
@@ -99,17 +167,31 @@ impl Daemon {
                 }
             }
         });
+        // Watch for jumps of the wall clock.
+        let clock = Arc::new(ClockMonitor::new(Arc::clone(&self.clock)));
+        set.spawn({
+            let clock = Arc::clone(&clock);
+            async move {
+                clock.run().await;
+                Ok(())
+            }
+        });
         let afc = self.setup_afc()?;
         let api = DaemonApiServer::new(
+            Arc::new(self.cfg.clone()),
             client,
             local_addr,
             Arc::new(Mutex::new(afc)),
             eng,
             store,
+            bundle.sign_id,
             self.cfg.uds_api_path.clone(),
             Arc::new(pk),
             peers,
+            pins,
+            Arc::new(Mutex::new(policies)),
             recv_effects,
+            clock,
         )
         .context("unable to start daemon API")?;
         api.serve().await?;
@@ -137,6 +219,7 @@ impl Daemon {
     /// Creates the Aranya client and server.
     async fn setup_aranya(
         &self,
+        policy_doc: &str,
         eng: CE,
         store: KS,
         pk: &PublicKeys<CS>,
@@ -145,22 +228,30 @@ impl Daemon {
         let user_id = pk.ident_pk.id()?;
 
         let aranya = Arc::new(Mutex::new(ClientState::new(
-            EN::new(TEST_POLICY_1, eng, store, user_id)?,
+            EN::new(policy_doc, eng, store, user_id)?,
             SP::new(
                 FileManager::new(self.cfg.storage_path())
                     .context("unable to create `FileManager`")?,
             ),
         )));
 
-        let client = Client::new(Arc::clone(&aranya));
+        let client =
+            Client::new(Arc::clone(&aranya)).with_transports(SyncTransports::new(&self.cfg.sync));
 
-        let server = {
+        let mut server = {
             info!(addr = %external_sync_addr, "starting TCP server");
             let listener = TcpListener::bind(external_sync_addr.to_socket_addrs())
                 .await
                 .context("unable to bind TCP listener")?;
             Server::new(Arc::clone(&aranya), listener)
         };
+        if let Some(addr) = self.cfg.sync.ws_addr {
+            info!(%addr, "starting WebSocket sync server");
+            let listener = TcpListener::bind(addr.to_socket_addrs())
+                .await
+                .context("unable to bind WebSocket listener")?;
+            server = server.with_ws(listener);
+        }
 
         info!(user_id = %user_id, "set up Aranya");
 
@@ -195,11 +286,7 @@ impl Daemon {
     }
 
     /// Loads the [`KeyBundle`].
-    async fn load_or_gen_public_keys(
-        &self,
-        eng: &mut CE,
-        store: &mut KS,
-    ) -> Result<PublicKeys<CS>> {
+    async fn load_or_gen_key_bundle(&self, eng: &mut CE, store: &mut KS) -> Result<KeyBundle> {
         let path = self.cfg.key_bundle_path();
         let bundle = match try_read_cbor(&path).await? {
             Some(bundle) => bundle,
@@ -213,7 +300,7 @@ impl Daemon {
                 bundle
             }
         };
-        bundle.public_keys(eng, store)
+        Ok(bundle)
     }
 
     /// Loads the key wrapping key used by [`CryptoEngine`].
@@ -259,7 +346,9 @@ impl Drop for Daemon {
 }
 
 /// Tries to read JSON from `path`.
-async fn try_read_cbor<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Option<T>> {
+pub(crate) async fn try_read_cbor<T: DeserializeOwned>(
+    path: impl AsRef<Path>,
+) -> Result<Option<T>> {
     match fs::read(path.as_ref()).await {
         Ok(buf) => Ok(cbor::from_reader(&buf[..])?),
         Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
@@ -268,7 +357,7 @@ async fn try_read_cbor<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<Op
 }
 
 /// Writes `data` as JSON to `path`.
-async fn write_cbor(path: impl AsRef<Path>, data: impl Serialize) -> Result<()> {
+pub(crate) async fn write_cbor(path: impl AsRef<Path>, data: impl Serialize) -> Result<()> {
     let mut buf = Vec::new();
     cbor::into_writer(&data, &mut buf)?;
     Ok(aranya_util::write_file(path, &buf).await?)
@@ -285,7 +374,7 @@ mod tests {
     use tokio::time;
 
     use super::*;
-    use crate::config::AfcConfig;
+    use crate::config::{AfcConfig, PolicyConfig, SyncConfig};
 
     /// Tests running the daemon.
     #[test(tokio::test)]
@@ -300,6 +389,7 @@ mod tests {
             uds_api_path: work_dir.join("api"),
             pid_file: work_dir.join("pid"),
             sync_addr: any,
+            sync: SyncConfig::default(),
             afc: AfcConfig {
                 shm_path: "/test_daemon".to_owned(),
                 unlink_on_startup: true,
@@ -307,6 +397,7 @@ mod tests {
                 create: true,
                 max_chans: 100,
             },
+            policy: PolicyConfig::default(),
         };
 
         let daemon = Daemon::load(cfg)
@@ -0,0 +1,141 @@
+//! Exporting a team's devices, roles, and labels.
+//!
+//! The daemon does not keep the team's state itself, so it runs
+//! the `query_team_graph` action in an ephemeral session, which
+//! emits one effect per user, label, and label assignment, and
+//! builds a [`TeamGraph`] from the effects.
+
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use aranya_buggy::BugExt;
+use aranya_daemon_api::{DeviceId, GraphDevice, GraphFormat, LabelAssignment, TeamGraph, TeamId};
+use aranya_fast_channels::Label;
+use tracing::debug;
+
+use crate::policy::Effect;
+
+/// Builds the team graph from the effects of the
+/// `query_team_graph` action.
+pub(crate) fn from_effects(team: TeamId, effects: &[Effect]) -> Result<TeamGraph> {
+    let mut graph = TeamGraph {
+        team,
+        devices: Vec::new(),
+        labels: Vec::new(),
+    };
+    let mut assigned = Vec::new();
+    for effect in effects {
+        match effect {
+            Effect::UserQueried(e) => graph.devices.push(GraphDevice {
+                id: e.user_id.into(),
+                role: (&e.role).into(),
+                labels: Vec::new(),
+            }),
+            Effect::LabelQueried(e) => graph.labels.push(to_label(e.label)?),
+            Effect::AssignedLabelQueried(e) => assigned.push((
+                DeviceId::from(e.user_id),
+                LabelAssignment {
+                    label: to_label(e.label)?,
+                    op: (&e.op).into(),
+                },
+            )),
+            _ => {}
+        }
+    }
+    for (id, assignment) in assigned {
+        match graph.devices.iter_mut().find(|device| device.id == id) {
+            Some(device) => device.labels.push(assignment),
+            // The label was assigned to a device that was
+            // removed since.
+            None => debug!(%id, label = %assignment.label, "skipping label of unknown device"),
+        }
+    }
+    graph.labels.sort_by_key(Label::to_u32);
+    for device in &mut graph.devices {
+        device
+            .labels
+            .sort_by_key(|assignment| assignment.label.to_u32());
+    }
+    Ok(graph)
+}
+
+/// Renders the team graph in `format`.
+pub(crate) fn render(graph: &TeamGraph, format: GraphFormat) -> Result<String> {
+    match format {
+        GraphFormat::Json => {
+            serde_json::to_string_pretty(graph).context("unable to encode team graph")
+        }
+        GraphFormat::Dot => Ok(to_dot(graph)),
+        _ => bail!("unsupported graph format: {format:?}"),
+    }
+}
+
+/// Renders the team graph in Graphviz's DOT language.
+fn to_dot(graph: &TeamGraph) -> String {
+    // NB: writing to a `String` cannot fail, so the results of
+    // `writeln!` are ignored.
+    let mut dot = String::new();
+    let _ = writeln!(dot, "digraph \"{}\" {{", graph.team);
+    for device in &graph.devices {
+        let _ = writeln!(
+            dot,
+            "    \"{id}\" [shape=box, label=\"{id}\\n{role:?}\"];",
+            id = device.id,
+            role = device.role,
+        );
+    }
+    for label in &graph.labels {
+        let _ = writeln!(dot, "    \"label {}\" [shape=ellipse];", label.to_u32());
+    }
+    for device in &graph.devices {
+        for assignment in &device.labels {
+            let _ = writeln!(
+                dot,
+                "    \"{}\" -> \"label {}\" [label=\"{:?}\"];",
+                device.id,
+                assignment.label.to_u32(),
+                assignment.op,
+            );
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Converts a label from the policy.
+fn to_label(label: i64) -> Result<Label> {
+    // NB: the policy ensures that labels fit inside a `u32`.
+    Ok(Label::new(
+        u32::try_from(label).assume("`label` is out of range")?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use aranya_daemon_api::{ChanOp, Role};
+
+    use super::*;
+
+    #[test]
+    fn test_to_dot() {
+        let id = DeviceId::default();
+        let graph = TeamGraph {
+            team: TeamId::default(),
+            devices: vec![GraphDevice {
+                id,
+                role: Role::Member,
+                labels: vec![LabelAssignment {
+                    label: Label::new(7),
+                    op: ChanOp::ReadWrite,
+                }],
+            }],
+            labels: vec![Label::new(7)],
+        };
+        let dot = to_dot(&graph);
+        assert!(dot.starts_with("digraph "));
+        assert!(dot.contains(&format!("\"{id}\" [shape=box, label=\"{id}\\nMember\"];")));
+        assert!(dot.contains("\"label 7\" [shape=ellipse];"));
+        assert!(dot.contains(&format!("\"{id}\" -> \"label 7\" [label=\"ReadWrite\"];")));
+        assert!(dot.ends_with("}\n"));
+    }
+}
@@ -26,13 +26,19 @@
 )]
 
 pub mod aranya;
+pub mod backup;
+pub mod clock;
 pub mod config;
 #[rustfmt::skip]
 pub mod policy;
+pub mod transport;
 pub mod vm_policy;
 
 mod api;
 mod daemon;
+mod graph;
+mod pins;
+mod policies;
 mod sync;
 
 pub use daemon::*;
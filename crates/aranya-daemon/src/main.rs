@@ -4,13 +4,14 @@
 #![deny(clippy::wildcard_imports, missing_docs)]
 
 use std::{
-    fmt, fs, io,
+    fmt, fs,
+    io::{self, BufRead, Write},
     path::{Path, PathBuf},
     process,
 };
 
 use anyhow::{Context, Result};
-use aranya_daemon::{config::Config, Daemon};
+use aranya_daemon::{backup::PendingRestore, config::Config, Daemon};
 use clap::Parser;
 use tokio::runtime::Runtime;
 use tracing::{error, info};
@@ -40,6 +41,12 @@ fn main() -> Result<()> {
     rt.block_on(async {
         let daemon = Daemon::load(cfg).await.context("unable to load daemon")?;
         info!("loaded Aranya daemon");
+        if let Some(path) = &flags.restore {
+            let info = daemon
+                .restore(path, |pending| flags.yes || confirm(pending))
+                .await?;
+            info!(files = info.files, bytes = info.bytes, "restored backup");
+        }
         daemon.run().await
     })
     .inspect_err(|err| error!(err = ?err))
@@ -50,6 +57,31 @@ fn main() -> Result<()> {
 struct Args {
     /// Configuration file
     cfg: PathBuf,
+    /// Restores the daemon's state from a backup before starting
+    #[arg(long)]
+    restore: Option<PathBuf>,
+    /// Restores without asking for confirmation
+    #[arg(long, requires = "restore")]
+    yes: bool,
+}
+
+/// Asks on stdin whether to restore `pending`.
+fn confirm(pending: &PendingRestore) -> bool {
+    let info = &pending.info;
+    eprintln!(
+        "Restoring backup of device {} ({} files, {} bytes).",
+        info.device_id, info.files, info.bytes
+    );
+    if pending.replaces_state {
+        eprintln!("The existing state will be moved aside and replaced.");
+    }
+    eprint!("Continue? [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
 }
 
 /// A PID file.
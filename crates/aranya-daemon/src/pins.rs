@@ -0,0 +1,352 @@
+//! Pinning sync peers' keys.
+//!
+//! Sync requests and responses are sent over plain TCP or
+//! WebSocket, so on a hostile network another host can stand in
+//! for a sync peer. To detect that, the sync server signs each
+//! response, along with the request that it answers, with the
+//! daemon's signing key and appends a [`SyncIdentity`] to it.
+//! Requesters that do not check it ignore the trailing bytes.
+//!
+//! [`SyncPins`] keeps the key that each peer must present (see
+//! [`PinMode`]) and the recent peers that were rejected, so that
+//! clients can review them (see `DaemonApi::sync_pin_violations`)
+//! and accept a peer's new key.
+
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use aranya_crypto::{Signature, SigningKey, VerifyingKey};
+use aranya_daemon_api::{PinViolation, SyncKeyId, SyncPin, CS};
+use aranya_util::Addr;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    clock::Clock,
+    config::{PinMode, SyncConfig},
+    daemon::{try_read_cbor, write_cbor},
+};
+
+/// The signature context of [`SyncIdentity`].
+const IDENTITY_CONTEXT: &[u8] = b"aranya sync identity";
+
+/// The number of violations that are kept.
+const MAX_VIOLATIONS: usize = 16;
+
+/// Proves which daemon sent a sync response.
+#[derive(Serialize, Deserialize)]
+struct SyncIdentity {
+    /// The daemon's encoded [`VerifyingKey`].
+    key: Vec<u8>,
+    /// The encoded [`Signature`] over the request and the
+    /// response.
+    signature: Vec<u8>,
+}
+
+/// Signs sync responses with the daemon's [`SigningKey`].
+pub struct Signer {
+    sk: SigningKey<CS>,
+    /// The encoded [`VerifyingKey`].
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// Creates a `Signer` from the daemon's signing key and its
+    /// public half.
+    pub fn new(sk: SigningKey<CS>, pk: &VerifyingKey<CS>) -> Result<Self> {
+        Ok(Self {
+            sk,
+            key: postcard::to_allocvec(pk).context("unable to encode verifying key")?,
+        })
+    }
+
+    /// Returns the [`SyncIdentity`] to append to `response`,
+    /// which answers `request`.
+    pub fn sign(&self, request: &[u8], response: &[u8]) -> Result<Vec<u8>> {
+        let sig = self
+            .sk
+            .sign(&signed_bytes(request, response)?, IDENTITY_CONTEXT)
+            .context("unable to sign sync response")?;
+        let identity = SyncIdentity {
+            key: self.key.clone(),
+            signature: postcard::to_allocvec(&sig).context("unable to encode signature")?,
+        };
+        postcard::to_allocvec(&identity).context("unable to encode sync identity")
+    }
+}
+
+/// Verifies the [`SyncIdentity`] that was appended to
+/// `response`, which answers `request`.
+///
+/// Returns the ID of the key that signed the response.
+pub fn verify(request: &[u8], response: &[u8], identity: &[u8]) -> Result<SyncKeyId> {
+    let identity: SyncIdentity = postcard::from_bytes(identity).context("invalid sync identity")?;
+    let pk: VerifyingKey<CS> =
+        postcard::from_bytes(&identity.key).context("invalid sync identity key")?;
+    let sig: Signature<CS> =
+        postcard::from_bytes(&identity.signature).context("invalid sync identity signature")?;
+    pk.verify(&signed_bytes(request, response)?, IDENTITY_CONTEXT, &sig)
+        .context("unable to verify sync identity")?;
+    Ok(pk.id()?.into_id().into())
+}
+
+/// Encodes the bytes that a [`SyncIdentity`] signs.
+fn signed_bytes(request: &[u8], response: &[u8]) -> Result<Vec<u8>> {
+    postcard::to_allocvec(&(request, response)).context("unable to encode signed bytes")
+}
+
+/// The keys that sync peers must present.
+#[derive(Debug)]
+pub struct SyncPins {
+    /// Where the pins are persisted.
+    path: PathBuf,
+    mode: PinMode,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pins: Vec<SyncPin>,
+    /// The most recent violations, oldest first.
+    violations: VecDeque<PinViolation>,
+    /// The sequence number of the next violation.
+    next_seq: u64,
+}
+
+impl SyncPins {
+    /// Loads the pins persisted at `path`, then applies the pins
+    /// from `cfg`.
+    pub async fn load(path: PathBuf, cfg: &SyncConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let pins = try_read_cbor(&path)
+            .await
+            .context("unable to read sync pins")?
+            .unwrap_or_default();
+        let pins = Self {
+            path,
+            mode: cfg.pinning,
+            clock,
+            state: Mutex::new(State {
+                pins,
+                ..Default::default()
+            }),
+        };
+        {
+            let mut state = pins.state.lock().await;
+            let mut changed = false;
+            for pin in &cfg.pins {
+                changed |= state.replace(*pin) != Some(pin.key);
+            }
+            if changed {
+                pins.save(&state).await?;
+            }
+        }
+        Ok(pins)
+    }
+
+    /// Returns the pins, ordered by address.
+    pub async fn pins(&self) -> Vec<SyncPin> {
+        let mut pins = self.state.lock().await.pins.clone();
+        pins.sort_by(|a, b| a.addr.cmp(&b.addr));
+        pins
+    }
+
+    /// Pins the peer at `addr` to `key`.
+    pub async fn pin(&self, addr: Addr, key: SyncKeyId) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if state.replace(SyncPin { addr, key }) != Some(key) {
+            self.save(&state).await?;
+        }
+        info!(%addr, %key, "pinned sync peer");
+        Ok(())
+    }
+
+    /// Removes the pin of the peer at `addr`.
+    ///
+    /// Returns whether the peer was pinned.
+    pub async fn unpin(&self, addr: &Addr) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let n = state.pins.len();
+        state.pins.retain(|pin| pin.addr != *addr);
+        if state.pins.len() == n {
+            return Ok(false);
+        }
+        self.save(&state).await?;
+        info!(%addr, "unpinned sync peer");
+        Ok(true)
+    }
+
+    /// Checks that the peer at `addr` presented the key that it
+    /// must, pinning it first if necessary.
+    ///
+    /// `presented` is `None` if the peer did not present a valid
+    /// key. It is an error if the peer must be rejected, in which
+    /// case a violation is recorded.
+    pub async fn check(&self, addr: &Addr, presented: Option<SyncKeyId>) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let pinned = state.get(addr);
+        match (pinned, presented, self.mode) {
+            (Some(pinned), Some(presented), _) if pinned == presented => return Ok(()),
+            (None, _, PinMode::Manual) => return Ok(()),
+            (None, Some(key), PinMode::Tofu) => {
+                state.replace(SyncPin { addr: *addr, key });
+                self.save(&state).await?;
+                info!(%addr, %key, "pinned sync peer on first use");
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let violation = PinViolation {
+            seq: state.next_seq,
+            at: self.clock.now(),
+            addr: *addr,
+            pinned,
+            presented,
+        };
+        warn!(%addr, ?pinned, ?presented, "rejected sync peer with unexpected key");
+        state.next_seq = state.next_seq.saturating_add(1);
+        if state.violations.len() >= MAX_VIOLATIONS {
+            state.violations.pop_front();
+        }
+        state.violations.push_back(violation);
+        match (pinned, presented) {
+            (Some(_), _) => bail!("sync peer `{addr}` did not present its pinned key"),
+            (None, None) => bail!("sync peer `{addr}` did not present a key"),
+            (None, Some(_)) => bail!("sync peer `{addr}` is not pinned"),
+        }
+    }
+
+    /// Returns the kept violations after the one with sequence
+    /// number `after`, or all of them if `after` is `None`,
+    /// oldest first.
+    pub async fn violations(&self, after: Option<u64>) -> Vec<PinViolation> {
+        self.state
+            .lock()
+            .await
+            .violations
+            .iter()
+            .filter(|v| after.map_or(true, |after| v.seq > after))
+            .copied()
+            .collect()
+    }
+
+    /// Persists the pins.
+    async fn save(&self, state: &State) -> Result<()> {
+        write_cbor(&self.path, &state.pins)
+            .await
+            .context("unable to write sync pins")
+    }
+}
+
+impl State {
+    /// Returns the key that the peer at `addr` is pinned to.
+    fn get(&self, addr: &Addr) -> Option<SyncKeyId> {
+        self.pins
+            .iter()
+            .find(|pin| pin.addr == *addr)
+            .map(|pin| pin.key)
+    }
+
+    /// Replaces the pin of `pin.addr`, returning the key it was
+    /// pinned to.
+    fn replace(&mut self, pin: SyncPin) -> Option<SyncKeyId> {
+        match self.pins.iter_mut().find(|p| p.addr == pin.addr) {
+            Some(old) => Some(core::mem::replace(&mut old.key, pin.key)),
+            None => {
+                self.pins.push(pin);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::expect_used, clippy::panic, clippy::indexing_slicing)]
+
+    use aranya_crypto::{Id, Random, Rng};
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::*;
+    use crate::clock::SystemClock;
+
+    /// Tests that sync responses are signed and verified.
+    #[test]
+    fn test_sync_identity() {
+        let sk = SigningKey::<CS>::new(&mut Rng);
+        let pk = sk.public().expect("should be able to get public key");
+        let want: SyncKeyId = pk.id().expect("should have key ID").into_id().into();
+        let signer = Signer::new(sk, &pk).expect("should be able to create `Signer`");
+
+        let identity = signer.sign(b"request", b"response").expect("should sign");
+        let got = verify(b"request", b"response", &identity).expect("should verify");
+        assert_eq!(got, want);
+
+        verify(b"request", b"forged", &identity).expect_err("forged response should not verify");
+        verify(b"replayed", b"response", &identity)
+            .expect_err("replayed response should not verify");
+    }
+
+    /// Tests checking peers' keys in each [`PinMode`].
+    #[test(tokio::test)]
+    async fn test_sync_pins() {
+        let dir = tempdir().expect("should be able to create temp dir");
+        let path = dir.path().join("sync_pins.cbor");
+        let addr = Addr::new("peer", 4321).expect("should be able to create `Addr`");
+        let other = Addr::new("other", 4321).expect("should be able to create `Addr`");
+        let key = SyncKeyId::from(Id::random(&mut Rng));
+        let new_key = SyncKeyId::from(Id::random(&mut Rng));
+        let load = |pinning| {
+            let cfg = SyncConfig {
+                pinning,
+                ..Default::default()
+            };
+            SyncPins::load(path.clone(), &cfg, Arc::new(SystemClock))
+        };
+
+        // Unpinned peers are not checked.
+        let pins = load(PinMode::Manual).await.expect("should load");
+        pins.check(&addr, None).await.expect("should accept");
+
+        // The first key is pinned and persisted.
+        let pins = load(PinMode::Tofu).await.expect("should load");
+        pins.check(&addr, Some(key)).await.expect("should pin");
+        pins.check(&addr, Some(key)).await.expect("should accept");
+        let pins = load(PinMode::Manual).await.expect("should load");
+        assert_eq!(pins.pins().await, [SyncPin { addr, key }]);
+
+        // A new key or no key is rejected in any mode.
+        pins.check(&addr, Some(new_key))
+            .await
+            .expect_err("should reject new key");
+        pins.check(&addr, None)
+            .await
+            .expect_err("should reject missing key");
+        let got = pins
+            .violations(None)
+            .await
+            .iter()
+            .map(|v| (v.seq, v.pinned, v.presented))
+            .collect::<Vec<_>>();
+        assert_eq!(got, [(0, Some(key), Some(new_key)), (1, Some(key), None)]);
+        assert_eq!(pins.violations(Some(0)).await.len(), 1);
+
+        // The operator accepts the new key.
+        pins.pin(addr, new_key).await.expect("should pin");
+        pins.check(&addr, Some(new_key))
+            .await
+            .expect("should accept new key");
+
+        // Unpinned peers are rejected.
+        let pins = load(PinMode::Strict).await.expect("should load");
+        pins.check(&other, Some(key))
+            .await
+            .expect_err("should reject unpinned peer");
+        assert!(pins.unpin(&addr).await.expect("should unpin"));
+        assert!(!pins.unpin(&addr).await.expect("should unpin"));
+        assert_eq!(pins.pins().await, []);
+    }
+}
@@ -0,0 +1,306 @@
+//! Policy bundles that the daemon can switch between at runtime.
+//!
+//! Bundles are stored in the daemon's working directory as
+//! `<version>.md`. Version 0 is the policy built into the
+//! daemon, so it is always available.
+//!
+//! The team decides which version it uses with the
+//! `UpgradePolicy` command. Rolling back is just an upgrade to
+//! an older version. A daemon can be pinned to a version, in
+//! which case it ignores upgrades to any other version.
+//!
+//! The command carries the SHA-256 digest of the bundle, so
+//! a version number alone cannot make two devices evaluate the
+//! graph under different policies: a bundle whose digest does
+//! not match the team's is neither stored nor activated.
+//!
+//! NB: the daemon's [`policy`][crate::policy] interface is
+//! generated from the built-in policy, so every bundle must
+//! provide the same actions and effects.
+
+use std::{borrow::Cow, io, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use aranya_crypto::{hash::Hash, rust::Sha256};
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::vm_policy::{self, TEST_POLICY_1};
+
+/// The version of the policy built into the daemon.
+pub(crate) const BUILTIN_VERSION: u32 = 0;
+
+/// The file that records the version the daemon is using.
+const ACTIVE: &str = "active";
+
+/// The policy bundles available to the daemon.
+#[derive(Debug)]
+pub(crate) struct Policies {
+    /// Where the bundles are stored.
+    dir: PathBuf,
+    /// The version the daemon is using.
+    active: u32,
+    /// The version the daemon is pinned to, if any.
+    pinned: Option<u32>,
+    /// The version the team most recently upgraded to and the
+    /// digest of its bundle, if any.
+    team: Option<(u32, Vec<u8>)>,
+}
+
+/// Returns the SHA-256 digest of the bundle `doc`.
+pub(crate) fn digest(doc: &str) -> Vec<u8> {
+    Sha256::hash(doc.as_bytes())[..].to_vec()
+}
+
+impl Policies {
+    /// Opens the bundles in `dir`.
+    ///
+    /// The daemon starts with `pinned`, if set, or else the
+    /// version it was last using.
+    pub async fn open(dir: PathBuf, pinned: Option<u32>) -> Result<Self> {
+        aranya_util::create_dir_all(&dir)
+            .await
+            .context("unable to create policy directory")?;
+        let active = match pinned {
+            Some(version) => version,
+            None => match fs::read_to_string(dir.join(ACTIVE)).await {
+                Ok(s) => s.trim().parse().context("invalid active policy version")?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => BUILTIN_VERSION,
+                Err(err) => return Err(err).context("unable to read active policy version"),
+            },
+        };
+        Ok(Self {
+            dir,
+            active,
+            pinned,
+            team: None,
+        })
+    }
+
+    /// Returns the version the daemon is using.
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    /// Returns the version the daemon is pinned to, if any.
+    pub fn pinned(&self) -> Option<u32> {
+        self.pinned
+    }
+
+    fn path(&self, version: u32) -> PathBuf {
+        self.dir.join(format!("{version}.md"))
+    }
+
+    /// Reports whether the bundle for `version` is available.
+    async fn contains(&self, version: u32) -> bool {
+        version == BUILTIN_VERSION || fs::try_exists(self.path(version)).await.unwrap_or(false)
+    }
+
+    /// Returns the policy document for `version`.
+    pub async fn read(&self, version: u32) -> Result<Cow<'static, str>> {
+        if version == BUILTIN_VERSION {
+            return Ok(Cow::Borrowed(TEST_POLICY_1));
+        }
+        let doc = fs::read_to_string(self.path(version))
+            .await
+            .with_context(|| format!("unable to read policy version {version}"))?;
+        Ok(Cow::Owned(doc))
+    }
+
+    /// Returns the SHA-256 digest of the bundle for `version`.
+    pub async fn digest(&self, version: u32) -> Result<Vec<u8>> {
+        Ok(digest(&self.read(version).await?))
+    }
+
+    /// Checks that `doc` is the team's bundle if the team
+    /// upgraded to `version`.
+    pub fn check(&self, version: u32, doc: &str) -> Result<()> {
+        match &self.team {
+            Some((v, want)) if *v == version && digest(doc) != *want => {
+                bail!("policy version {version} does not match the team's bundle")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the available versions in ascending order.
+    pub async fn versions(&self) -> Result<Vec<u32>> {
+        let mut versions = vec![BUILTIN_VERSION];
+        let mut entries = fs::read_dir(&self.dir)
+            .await
+            .context("unable to read policy directory")?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "md") {
+                if let Some(v) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse().ok())
+                {
+                    versions.push(v);
+                }
+            }
+        }
+        versions.sort_unstable();
+        versions.dedup();
+        Ok(versions)
+    }
+
+    /// Validates and stores the bundle for `version`.
+    ///
+    /// Bundles are immutable, so it is an error to store
+    /// a different document under an existing version, or one
+    /// that does not match the team's (see
+    /// [`check`][Self::check]).
+    ///
+    /// Returns the version the daemon should switch to, if
+    /// any.
+    pub async fn store(&mut self, version: u32, doc: &str) -> Result<Option<u32>> {
+        if version == BUILTIN_VERSION {
+            bail!("policy version {BUILTIN_VERSION} is reserved for the built-in policy");
+        }
+        self.check(version, doc)?;
+        vm_policy::compile(doc).context("invalid policy")?;
+
+        let path = self.path(version);
+        match fs::read_to_string(&path).await {
+            Ok(old) if old == doc => {}
+            Ok(_) => bail!("policy version {version} already exists"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                aranya_util::write_file_atomic(&path, doc.as_bytes())
+                    .await
+                    .context("unable to write policy bundle")?;
+                info!(version, "stored policy bundle");
+            }
+            Err(err) => return Err(err).context("unable to read policy bundle"),
+        }
+        Ok(self.target().await)
+    }
+
+    /// Pins the daemon to `version`, or unpins it if `None`.
+    ///
+    /// Returns the version the daemon should switch to, if
+    /// any.
+    pub async fn pin(&mut self, version: Option<u32>) -> Result<Option<u32>> {
+        if let Some(version) = version {
+            if !self.contains(version).await {
+                bail!("policy version {version} is not available");
+            }
+        }
+        self.pinned = version;
+        Ok(self.target().await)
+    }
+
+    /// Records that the team upgraded to `version`, whose
+    /// bundle has the digest `digest`.
+    ///
+    /// Returns the version the daemon should switch to, if
+    /// any.
+    pub async fn upgraded(&mut self, version: u32, digest: Vec<u8>) -> Option<u32> {
+        self.team = Some((version, digest));
+        if let Some(pinned) = self.pinned.filter(|&v| v != version) {
+            warn!(version, pinned, "ignoring policy upgrade: pinned");
+        }
+        self.target().await
+    }
+
+    /// Records that the daemon switched to `version`.
+    pub async fn set_active(&mut self, version: u32) -> Result<()> {
+        aranya_util::write_file_atomic(self.dir.join(ACTIVE), version.to_string().as_bytes())
+            .await
+            .context("unable to write active policy version")?;
+        self.active = version;
+        Ok(())
+    }
+
+    /// Returns the version the daemon should switch to, if
+    /// any.
+    async fn target(&self) -> Option<u32> {
+        let version = self.pinned.or(self.team.as_ref().map(|(v, _)| *v))?;
+        if version == self.active {
+            return None;
+        }
+        if !self.contains(version).await {
+            warn!(version, "missing policy bundle, load it to switch");
+            return None;
+        }
+        let checked = match self.read(version).await {
+            Ok(doc) => self.check(version, &doc),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = checked {
+            warn!(version, %err, "not switching policy");
+            return None;
+        }
+        Some(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::expect_used, clippy::panic, clippy::indexing_slicing)]
+
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn test_policies_upgrade_and_pin() {
+        let dir = tempdir().expect("should be able to create temp dir");
+        let mut policies = Policies::open(dir.path().into(), None)
+            .await
+            .expect("should be able to open policies");
+        assert_eq!(policies.active(), BUILTIN_VERSION);
+
+        // The team upgrades before we have the bundle, which
+        // must be the team's.
+        assert_eq!(policies.upgraded(1, digest(TEST_POLICY_1)).await, None);
+        policies
+            .store(1, &format!("{TEST_POLICY_1}\n"))
+            .await
+            .expect_err("should not be able to store another bundle");
+        let target = policies
+            .store(1, TEST_POLICY_1)
+            .await
+            .expect("should be able to store bundle");
+        assert_eq!(target, Some(1));
+        policies
+            .set_active(1)
+            .await
+            .expect("should be able to set active version");
+
+        // Bundles are immutable.
+        policies
+            .store(1, &format!("{TEST_POLICY_1}\n"))
+            .await
+            .expect_err("should not be able to replace bundle");
+        policies
+            .store(2, "not a policy")
+            .await
+            .expect_err("should not be able to store invalid bundle");
+        assert_eq!(policies.versions().await.expect("versions"), [0, 1]);
+
+        // Pinning to the built-in policy rolls back and ignores
+        // upgrades.
+        assert_eq!(policies.pin(Some(0)).await.expect("pin"), Some(0));
+        policies.set_active(0).await.expect("set active");
+        assert_eq!(policies.upgraded(1, digest(TEST_POLICY_1)).await, None);
+        assert_eq!(policies.pin(None).await.expect("unpin"), Some(1));
+
+        // A bundle that does not match the team's is not
+        // activated.
+        policies
+            .store(2, &format!("{TEST_POLICY_1}\nVersion 2.\n"))
+            .await
+            .expect("should be able to store bundle");
+        assert_eq!(policies.upgraded(2, digest(TEST_POLICY_1)).await, None);
+        policies.pin(Some(3)).await.expect_err("missing bundle");
+
+        // The active version survives restarts.
+        let policies = Policies::open(dir.path().into(), None)
+            .await
+            .expect("should be able to reopen policies");
+        assert_eq!(policies.active(), 0);
+    }
+}
@@ -37,6 +37,7 @@ pub enum ChanOp {
 pub enum Effect {
     TeamCreated(TeamCreated),
     TeamTerminated(TeamTerminated),
+    PolicyUpgraded(PolicyUpgraded),
     MemberAdded(MemberAdded),
     MemberRemoved(MemberRemoved),
     OwnerAssigned(OwnerAssigned),
@@ -51,6 +52,12 @@ pub enum Effect {
     LabelRevoked(LabelRevoked),
     NetworkNameSet(NetworkNameSet),
     NetworkNameUnset(NetworkNameUnset),
+    DeviceMetadataSet(DeviceMetadataSet),
+    DeviceMetadataUnset(DeviceMetadataUnset),
+    DeviceMetadataQueried(DeviceMetadataQueried),
+    UserQueried(UserQueried),
+    LabelQueried(LabelQueried),
+    AssignedLabelQueried(AssignedLabelQueried),
     BidiChannelCreated(BidiChannelCreated),
     BidiChannelReceived(BidiChannelReceived),
     UniChannelCreated(UniChannelCreated),
@@ -66,6 +73,13 @@ pub struct TeamCreated {
 pub struct TeamTerminated {
     pub owner_id: Id,
 }
+/// PolicyUpgraded policy effect.
+#[effect]
+pub struct PolicyUpgraded {
+    pub version: i64,
+    pub digest: Vec<u8>,
+    pub author_id: Id,
+}
 /// MemberAdded policy effect.
 #[effect]
 pub struct MemberAdded {
@@ -141,6 +155,45 @@ pub struct NetworkNameSet {
 pub struct NetworkNameUnset {
     pub user_id: Id,
 }
+/// DeviceMetadataSet policy effect.
+#[effect]
+pub struct DeviceMetadataSet {
+    pub user_id: Id,
+    pub key: String,
+    pub value: String,
+}
+/// DeviceMetadataUnset policy effect.
+#[effect]
+pub struct DeviceMetadataUnset {
+    pub user_id: Id,
+    pub key: String,
+}
+/// DeviceMetadataQueried policy effect.
+#[effect]
+pub struct DeviceMetadataQueried {
+    pub user_id: Id,
+    pub key: String,
+    pub found: bool,
+    pub value: String,
+}
+/// UserQueried policy effect.
+#[effect]
+pub struct UserQueried {
+    pub user_id: Id,
+    pub role: Role,
+}
+/// LabelQueried policy effect.
+#[effect]
+pub struct LabelQueried {
+    pub label: i64,
+}
+/// AssignedLabelQueried policy effect.
+#[effect]
+pub struct AssignedLabelQueried {
+    pub user_id: Id,
+    pub label: i64,
+    pub op: ChanOp,
+}
 /// BidiChannelCreated policy effect.
 #[effect]
 pub struct BidiChannelCreated {
@@ -196,6 +249,7 @@ pub trait ActorExt {
         nonce: Vec<u8>,
     ) -> Result<(), ClientError>;
     fn terminate_team(&mut self) -> Result<(), ClientError>;
+    fn upgrade_policy(&mut self, version: i64, digest: Vec<u8>) -> Result<(), ClientError>;
     fn add_member(&mut self, user_keys: KeyBundle) -> Result<(), ClientError>;
     fn remove_member(&mut self, user_id: Id) -> Result<(), ClientError>;
     fn assign_role(&mut self, user_id: Id, role: Role) -> Result<(), ClientError>;
@@ -215,6 +269,23 @@ pub trait ActorExt {
         net_identifier: String,
     ) -> Result<(), ClientError>;
     fn unset_network_name(&mut self, user_id: Id) -> Result<(), ClientError>;
+    fn set_device_metadata(
+        &mut self,
+        user_id: Id,
+        key: String,
+        value: String,
+    ) -> Result<(), ClientError>;
+    fn unset_device_metadata(
+        &mut self,
+        user_id: Id,
+        key: String,
+    ) -> Result<(), ClientError>;
+    fn query_device_metadata(
+        &mut self,
+        user_id: Id,
+        key: String,
+    ) -> Result<(), ClientError>;
+    fn query_team_graph(&mut self) -> Result<(), ClientError>;
     fn create_bidi_channel(
         &mut self,
         peer_id: Id,
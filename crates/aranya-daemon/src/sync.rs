@@ -5,30 +5,70 @@
 //! [`Syncer`] syncs with the next available peer from the [`DelayQueue`].
 //! [`SyncPeers`] and [`Syncer`] communicate via mpsc channels so they can run independently.
 //! This prevents the need for an `Arc<<Mutex>>` which would lock until the next peer is retrieved from the [`DelayQueue`]
+//!
+//! Each sync session runs in its own task, so a slow or stuck
+//! peer does not delay syncing with other peers. Each peer has
+//! at most one session at a time, and sessions can be listed and
+//! cancelled with [`SyncPeers::status`] and
+//! [`SyncPeers::cancel`].
+//!
+//! Sync is pull-based, so urgent changes (e.g., removing a
+//! device) are pushed by sending each peer a sync hint, which
+//! asks it to sync with its own peers for that graph right away.
+//! See [`SyncPeers::push`] and [`SyncPeers::sync_now`].
 
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use aranya_buggy::BugExt;
+use aranya_daemon_api::SyncSession;
 use aranya_runtime::storage::GraphId;
-use aranya_util::Addr;
+use aranya_util::{Addr, ShortId};
 use futures_util::StreamExt;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{self, AbortHandle, JoinError, JoinSet},
+    time::Instant,
+};
 use tokio_util::time::{delay_queue::Key, DelayQueue};
-use tracing::{error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
+    aranya::SyncProgress,
     daemon::{Client, EF},
     vm_policy::VecSink,
 };
 
 /// Message sent from [`SyncPeers`] to [`Syncer`] via mpsc.
-#[derive(Clone)]
 enum Msg {
-    AddPeer { peer: SyncPeer, interval: Duration },
-    RemovePeer { peer: SyncPeer },
+    AddPeer {
+        peer: SyncPeer,
+        interval: Duration,
+    },
+    RemovePeer {
+        peer: SyncPeer,
+    },
+    SyncNow {
+        graph_id: GraphId,
+    },
+    Push {
+        graph_id: GraphId,
+    },
+    Status {
+        reply: oneshot::Sender<Vec<SyncSession>>,
+    },
+    Cancel {
+        peer: SyncPeer,
+        reply: oneshot::Sender<bool>,
+    },
 }
 
+/// The minimum time between urgent syncs of the same graph.
+///
+/// Sync hints that arrive sooner are coalesced, so peers cannot
+/// make us sync in a tight loop.
+pub const MIN_URGENT_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 /// A sync peer.
 /// Contains the information needed to sync with a single peer:
 /// - network address
@@ -65,6 +105,58 @@ impl SyncPeers {
         Ok(())
     }
 
+    /// Syncs with every peer of `graph_id` as soon as possible
+    /// instead of waiting for their intervals.
+    ///
+    /// Urgent syncs of the same graph are at least
+    /// [`MIN_URGENT_SYNC_INTERVAL`] apart.
+    pub async fn sync_now(&self, graph_id: GraphId) -> Result<()> {
+        self.send
+            .send(Msg::SyncNow { graph_id })
+            .await
+            .context("unable to request sync")
+    }
+
+    /// Sends every peer of `graph_id` a sync hint so that they
+    /// pull our latest commands right away.
+    ///
+    /// Only peers that are online and that know us as a peer of
+    /// `graph_id` act on the hint. Others find out at their next
+    /// sync.
+    pub async fn push(&self, graph_id: GraphId) -> Result<()> {
+        self.send
+            .send(Msg::Push { graph_id })
+            .await
+            .context("unable to push to peers")
+    }
+
+    /// Returns the sync sessions that are in progress.
+    pub async fn status(&self) -> Result<Vec<SyncSession>> {
+        let (reply, recv) = oneshot::channel();
+        self.send
+            .send(Msg::Status { reply })
+            .await
+            .context("unable to get sync status")?;
+        recv.await.context("unable to get sync status")
+    }
+
+    /// Cancels the sync session with the peer, if one is in
+    /// progress.
+    ///
+    /// Returns whether a session was cancelled. The peer is
+    /// still synced with at its next interval.
+    pub async fn cancel(&self, addr: Addr, graph_id: GraphId) -> Result<bool> {
+        let (reply, recv) = oneshot::channel();
+        self.send
+            .send(Msg::Cancel {
+                peer: SyncPeer { addr, graph_id },
+                reply,
+            })
+            .await
+            .context("unable to cancel sync")?;
+        recv.await.context("unable to cancel sync")
+    }
+
     /// Remove peer from [`Syncer`].
     pub async fn remove_peer(&self, addr: Addr, graph_id: GraphId) -> Result<()> {
         if let Err(e) = self
@@ -95,7 +187,15 @@ pub struct Syncer {
     /// Delay queue for getting the next peer to sync with.
     queue: DelayQueue<SyncPeer>,
     /// Used to send effects to the API to be processed.
-    send_effects: mpsc::Sender<Vec<EF>>,
+    send_effects: mpsc::Sender<(GraphId, Vec<EF>)>,
+    /// When each graph was last synced urgently.
+    last_urgent: HashMap<GraphId, Instant>,
+    /// The sync sessions in progress.
+    sessions: HashMap<SyncPeer, Session>,
+    /// Runs the sync sessions.
+    tasks: JoinSet<(SyncPeer, Result<()>)>,
+    /// The maximum number of sessions that run at once.
+    max_sessions: usize,
 }
 
 struct PeerInfo {
@@ -105,9 +205,22 @@ struct PeerInfo {
     key: Key,
 }
 
+/// A sync session in progress.
+struct Session {
+    progress: Arc<SyncProgress>,
+    started: Instant,
+    /// Cancels the session.
+    abort: AbortHandle,
+}
+
 impl Syncer {
-    /// Creates a new `Syncer`.
-    pub fn new(client: Arc<Client>, send_effects: mpsc::Sender<Vec<EF>>) -> (Self, SyncPeers) {
+    /// Creates a new `Syncer` that runs up to `max_sessions`
+    /// sync sessions at once.
+    pub fn new(
+        client: Arc<Client>,
+        send_effects: mpsc::Sender<(GraphId, Vec<EF>)>,
+        max_sessions: usize,
+    ) -> (Self, SyncPeers) {
         let (send, recv) = mpsc::channel::<Msg>(128);
         let peers = SyncPeers::new(send);
         (
@@ -117,12 +230,19 @@ impl Syncer {
                 recv,
                 queue: DelayQueue::new(),
                 send_effects,
+                last_urgent: HashMap::new(),
+                sessions: HashMap::new(),
+                tasks: JoinSet::new(),
+                max_sessions,
             },
             peers,
         )
     }
 
-    /// Syncs with the next peer in the list.
+    /// Handles the next message, starts a sync with the next
+    /// peer in the list, or reaps a finished sync.
+    ///
+    /// Returns the error of a failed sync.
     #[instrument(skip_all)]
     pub async fn next(&mut self) -> Result<()> {
         #![allow(clippy::disallowed_macros)]
@@ -133,21 +253,104 @@ impl Syncer {
                 match msg {
                     Msg::AddPeer { peer, interval } => self.add_peer(peer, interval),
                     Msg::RemovePeer { peer } => self.remove_peer(peer),
+                    Msg::SyncNow { graph_id } => self.sync_now(graph_id),
+                    Msg::Push { graph_id } => self.push(graph_id),
+                    Msg::Status { reply } => {
+                        let _ = reply.send(self.status());
+                    }
+                    Msg::Cancel { peer, reply } => {
+                        let _ = reply.send(self.cancel(&peer));
+                    }
                 }
 
             }
+            // reap finished syncs, which makes room for new ones.
+            Some(res) = self.tasks.join_next_with_id() => {
+                self.finish(res)?;
+            }
             // get next peer from delay queue.
-            Some(expired) = self.queue.next() => {
+            Some(expired) = self.queue.next(), if self.sessions.len() < self.max_sessions => {
                 let peer = expired.into_inner();
                 let info = self.peers.get_mut(&peer).assume("peer must exist")?;
                 info.key = self.queue.insert(peer.clone(), info.interval);
                 // sync with peer.
-                self.sync(&peer.graph_id, &peer.addr).await?;
+                self.start(peer);
             }
         }
         Ok(())
     }
 
+    /// Starts syncing with `peer`, unless a sync with it is
+    /// already in progress.
+    fn start(&mut self, peer: SyncPeer) {
+        if self.sessions.contains_key(&peer) {
+            debug!(peer = %peer.addr, "previous sync still in progress");
+            return;
+        }
+        let progress = Arc::new(SyncProgress::default());
+        let abort = self.tasks.spawn({
+            let client = Arc::clone(&self.client);
+            let send_effects = self.send_effects.clone();
+            let progress = Arc::clone(&progress);
+            let peer = peer.clone();
+            async move {
+                let result = sync(&client, &send_effects, &peer, &progress).await;
+                (peer, result)
+            }
+        });
+        self.sessions.insert(
+            peer,
+            Session {
+                progress,
+                started: Instant::now(),
+                abort,
+            },
+        );
+    }
+
+    /// Removes a finished sync session.
+    fn finish(&mut self, res: Result<(task::Id, (SyncPeer, Result<()>)), JoinError>) -> Result<()> {
+        match res {
+            Ok((_, (peer, result))) => {
+                self.sessions.remove(&peer);
+                result
+            }
+            Err(err) => {
+                self.sessions.retain(|_, s| s.abort.id() != err.id());
+                if err.is_cancelled() {
+                    Ok(())
+                } else {
+                    Err(err).context("sync task failed")
+                }
+            }
+        }
+    }
+
+    /// Describes the sync sessions in progress.
+    fn status(&self) -> Vec<SyncSession> {
+        self.sessions
+            .iter()
+            .map(|(peer, session)| SyncSession {
+                addr: peer.addr.clone(),
+                team: peer.graph_id.into_id().into(),
+                stage: session.progress.stage(),
+                elapsed: session.started.elapsed(),
+                bytes_received: session.progress.bytes_received(),
+                commands: session.progress.commands(),
+            })
+            .collect()
+    }
+
+    /// Cancels the sync session with `peer`, if any.
+    fn cancel(&mut self, peer: &SyncPeer) -> bool {
+        let Some(session) = self.sessions.get(peer) else {
+            return false;
+        };
+        session.abort.abort();
+        info!(peer = %peer.addr, graph_id = %ShortId::new("graph", &peer.graph_id), "cancelled sync");
+        true
+    }
+
     /// Add a peer to the delay queue, overwriting an existing one.
     fn add_peer(&mut self, peer: SyncPeer, interval: Duration) {
         let key = self.queue.insert(peer.clone(), interval);
@@ -161,32 +364,109 @@ impl Syncer {
             .or_insert(PeerInfo { interval, key });
     }
 
-    /// Remove a peer from the delay queue.
+    /// Remove a peer from the delay queue, cancelling its sync
+    /// session if one is in progress.
     fn remove_peer(&mut self, peer: SyncPeer) {
         if let Some(info) = self.peers.remove(&peer) {
             self.queue.remove(&info.key);
         }
+        self.cancel(&peer);
     }
 
-    #[instrument(skip_all, fields(peer = %peer, graph_id = %id))]
-    async fn sync(&mut self, id: &GraphId, peer: &Addr) -> Result<()> {
-        info!("syncing with peer");
+    /// Moves the peers of `graph_id` to the front of the delay
+    /// queue, subject to [`MIN_URGENT_SYNC_INTERVAL`].
+    fn sync_now(&mut self, graph_id: GraphId) {
+        let now = Instant::now();
+        let (at, throttled) = urgent_sync_at(self.last_urgent.get(&graph_id).copied(), now);
+        // Throttled requests must not push the next urgent sync
+        // further out.
+        if !throttled {
+            self.last_urgent.insert(graph_id, now);
+        }
+        for (peer, info) in &self.peers {
+            if peer.graph_id != graph_id {
+                continue;
+            }
+            if self.queue.deadline(&info.key) > at {
+                self.queue.reset_at(&info.key, at);
+            }
+        }
+        debug!(%graph_id, throttled, "scheduled urgent sync");
+    }
 
-        let effects: Vec<EF> = {
-            let mut sink = VecSink::new();
-            self.client
-                .sync_peer(*id, &mut sink, peer)
-                .await
-                .context("sync_peer error")
-                .inspect_err(|err| error!("{err:?}"))?;
-            sink.collect()?
-        };
-        let n = effects.len();
-        self.send_effects
-            .send(effects)
+    /// Sends a sync hint for `graph_id` to each of its peers.
+    fn push(&self, graph_id: GraphId) {
+        for peer in self.peers.keys().filter(|p| p.graph_id == graph_id) {
+            let client = Arc::clone(&self.client);
+            let addr = peer.addr.clone();
+            tokio::spawn(async move {
+                match client.send_sync_hint(graph_id, &addr).await {
+                    Ok(()) => debug!(%addr, %graph_id, "sent sync hint"),
+                    Err(err) => warn!(%addr, %graph_id, ?err, "unable to send sync hint"),
+                }
+            });
+        }
+    }
+}
+
+/// Syncs with `peer`, reporting the sync's progress to
+/// `progress`.
+#[instrument(skip_all, fields(peer = %peer.addr, graph_id = %ShortId::new("graph", &peer.graph_id)))]
+async fn sync(
+    client: &Client,
+    send_effects: &mpsc::Sender<(GraphId, Vec<EF>)>,
+    peer: &SyncPeer,
+    progress: &SyncProgress,
+) -> Result<()> {
+    info!("syncing with peer");
+
+    let id = peer.graph_id;
+    let effects: Vec<EF> = {
+        let mut sink = VecSink::new();
+        client
+            .sync_peer_with_progress(id, &mut sink, &peer.addr, progress)
             .await
-            .context("unable to send effects")?;
-        info!(?n, "completed sync");
-        Ok(())
+            .context("sync_peer error")
+            .inspect_err(|err| error!("{err:?}"))?;
+        sink.collect()?
+    };
+    let n = effects.len();
+    send_effects
+        .send((id, effects))
+        .await
+        .context("unable to send effects")?;
+    info!(?n, "completed sync");
+    Ok(())
+}
+
+/// Returns when an urgent sync requested at `now` is due, given
+/// when the `last` one was, and whether it was throttled by
+/// [`MIN_URGENT_SYNC_INTERVAL`].
+fn urgent_sync_at(last: Option<Instant>, now: Instant) -> (Instant, bool) {
+    match last.map(|last| last + MIN_URGENT_SYNC_INTERVAL) {
+        Some(next) if next > now => (next, true),
+        _ => (now, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urgent_sync_at() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(urgent_sync_at(None, start), (start, false));
+
+        // Requests within the interval are throttled to the end
+        // of it, no matter how many there are.
+        let last = Some(start);
+        assert_eq!(urgent_sync_at(last, ms(1)), (ms(1000), true));
+        assert_eq!(urgent_sync_at(last, ms(999)), (ms(1000), true));
+
+        assert_eq!(urgent_sync_at(last, ms(1000)), (ms(1000), false));
+        assert_eq!(urgent_sync_at(last, ms(5000)), (ms(5000), false));
     }
 }
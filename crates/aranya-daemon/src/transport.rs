@@ -0,0 +1,227 @@
+//! Sync transports.
+//!
+//! A sync is a single exchange: the requester sends one message
+//! and the server answers with one message. Over TCP, each
+//! message is everything written before the write half is shut
+//! down. Over WebSocket, each message is a binary message, which
+//! lets syncs pass through networks that only allow outbound
+//! HTTPS (see [`SyncConfig`][crate::config::SyncConfig]).
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use aranya_util::Addr;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    client_async_tls,
+    tungstenite::{http::Uri, Message},
+    WebSocketStream,
+};
+use tracing::{debug, instrument};
+
+use crate::config::{ProxyConfig, SyncConfig, WsPeerConfig};
+
+/// The most bytes that a proxy's response to `CONNECT` may
+/// have.
+const MAX_PROXY_RESPONSE: usize = 8 * 1024;
+
+/// Chooses how to reach each sync peer.
+#[derive(Clone, Debug, Default)]
+pub struct SyncTransports {
+    /// Peers reached over WebSocket, by sync address.
+    ws: HashMap<Addr, WsPeerConfig>,
+}
+
+impl SyncTransports {
+    /// Creates the transports from the daemon's config.
+    pub fn new(cfg: &SyncConfig) -> Self {
+        let ws = cfg
+            .peers
+            .iter()
+            .map(|peer| (peer.addr, peer.clone()))
+            .collect();
+        Self { ws }
+    }
+
+    /// Sends `req` to the sync server at `addr` and returns its
+    /// response.
+    #[instrument(skip_all, fields(%addr))]
+    pub async fn exchange(&self, addr: &Addr, req: &[u8]) -> Result<Vec<u8>> {
+        match self.ws.get(addr) {
+            Some(peer) => ws_exchange(peer, req).await,
+            None => tcp_exchange(addr, req).await,
+        }
+    }
+}
+
+/// Sends `req` over a new TCP stream and reads the response.
+async fn tcp_exchange(addr: &Addr, req: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr.to_socket_addrs()).await?;
+    let peer = stream.peer_addr()?;
+    stream
+        .write_all(req)
+        .await
+        .context("failed to write sync request")?;
+    stream.shutdown().await?;
+    debug!(%peer, "sent sync request over TCP");
+
+    let mut resp = Vec::new();
+    stream
+        .read_to_end(&mut resp)
+        .await
+        .context("failed to read sync response")?;
+    Ok(resp)
+}
+
+/// Sends `req` as a WebSocket message and reads the response.
+async fn ws_exchange(peer: &WsPeerConfig, req: &[u8]) -> Result<Vec<u8>> {
+    let uri: Uri = peer.url.parse().context("invalid WebSocket URL")?;
+    let host = uri.host().context("WebSocket URL has no host")?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("wss")) => 443,
+        (None, Some("ws")) => 80,
+        (None, _) => bail!("unsupported WebSocket URL scheme"),
+    };
+    let stream = match &peer.proxy {
+        Some(proxy) => connect_via_proxy(proxy, host, port).await?,
+        None => TcpStream::connect((host, port)).await?,
+    };
+    let (mut ws, _) = client_async_tls(peer.url.as_str(), stream)
+        .await
+        .context("WebSocket handshake failed")?;
+    ws.send(Message::Binary(req.to_vec()))
+        .await
+        .context("failed to write sync request")?;
+    debug!(url = peer.url, "sent sync request over WebSocket");
+
+    let resp = recv_binary(&mut ws)
+        .await
+        .context("failed to read sync response")?;
+    // The exchange is done either way.
+    let _ = ws.close(None).await;
+    Ok(resp)
+}
+
+/// Opens a tunnel to `host:port` through an HTTP proxy.
+#[instrument(skip_all, fields(proxy = %proxy.addr))]
+async fn connect_via_proxy(proxy: &ProxyConfig, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy.addr.to_socket_addrs())
+        .await
+        .context("unable to connect to proxy")?;
+
+    let mut req = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if let Some(auth) = &proxy.auth {
+        req.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(auth)
+        ));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // Read the response headers one line at a time so that
+    // nothing after them is consumed.
+    let mut reader = BufReader::with_capacity(1, &mut stream);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+    let mut n = status.len();
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).await?;
+        n = n.saturating_add(read);
+        if n > MAX_PROXY_RESPONSE {
+            bail!("proxy response is too large");
+        }
+        if read == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    let code = status.split_whitespace().nth(1);
+    if code != Some("200") {
+        bail!("proxy refused tunnel: {}", status.trim_end());
+    }
+    debug!(%host, port, "opened proxy tunnel");
+    Ok(stream)
+}
+
+/// Reads the next binary message from `ws`.
+pub(crate) async fn recv_binary<S>(ws: &mut WebSocketStream<S>) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(msg) = ws.next().await {
+        match msg? {
+            Message::Binary(data) => return Ok(data),
+            Message::Close(_) => break,
+            // Pings are answered by `tungstenite`.
+            _ => continue,
+        }
+    }
+    bail!("stream closed without a message")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::copy_bidirectional, net::TcpListener};
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+
+    /// A sync request reaches the server over WebSocket through
+    /// a proxy.
+    #[tokio::test]
+    async fn test_ws_exchange_via_proxy() -> Result<()> {
+        // Echoes each request, reversed.
+        let server = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let mut ws = accept_async(stream).await?;
+            let mut req = recv_binary(&mut ws).await?;
+            req.reverse();
+            ws.send(Message::Binary(req)).await?;
+            anyhow::Ok(())
+        });
+
+        // Checks the credentials, then tunnels to the server.
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+        tokio::spawn(async move {
+            let (mut client, _) = proxy.accept().await?;
+            let mut buf = vec![0u8; MAX_PROXY_RESPONSE];
+            let n = client.read(&mut buf).await?;
+            let req = String::from_utf8_lossy(buf.get(..n).unwrap_or_default()).into_owned();
+            assert!(req.starts_with(&format!("CONNECT {server_addr} HTTP/1.1\r\n")));
+            assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            let mut upstream = TcpStream::connect(server_addr).await?;
+            copy_bidirectional(&mut client, &mut upstream).await?;
+            anyhow::Ok(())
+        });
+
+        let addr = Addr::new("peer", 4321)?;
+        let transports = SyncTransports::new(&SyncConfig {
+            ws_addr: None,
+            peers: vec![WsPeerConfig {
+                addr,
+                url: format!("ws://{server_addr}/aranya"),
+                proxy: Some(ProxyConfig {
+                    addr: Addr::new(proxy_addr.ip().to_string(), proxy_addr.port())?,
+                    auth: Some("user:pass".into()),
+                }),
+            }],
+            ..Default::default()
+        });
+        let resp = transports.exchange(&addr, b"abc").await?;
+        assert_eq!(resp, b"cba");
+        Ok(())
+    }
+}
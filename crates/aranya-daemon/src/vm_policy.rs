@@ -59,20 +59,7 @@ where
 {
     /// Creates a `PolicyEngine` from a policy document.
     pub fn new(policy_doc: &str, eng: E, store: Store, user_id: UserId) -> Result<Self> {
-        // compile the policy.
-        let ast = parse_policy_document(policy_doc).context("unable to parse policy document")?;
-        let module = Compiler::new(&ast)
-            .ffi_modules(&[
-                AfcFfi::<Store>::SCHEMA,
-                CryptoFfi::<Store>::SCHEMA,
-                DeviceFfi::SCHEMA,
-                EnvelopeFfi::SCHEMA,
-                IdamFfi::<Store>::SCHEMA,
-                PerspectiveFfi::SCHEMA,
-            ])
-            .compile()
-            .context("should be able to compile policy")?;
-        let machine = Machine::from_module(module).context("should be able to create machine")?;
+        let machine = compile(policy_doc)?;
 
         // select which FFI moddules to use.
         let ffis: Vec<Box<dyn FfiCallable<E> + Send + 'static>> = vec![
@@ -113,6 +100,23 @@ where
     }
 }
 
+/// Compiles a policy document.
+pub fn compile(policy_doc: &str) -> Result<Machine> {
+    let ast = parse_policy_document(policy_doc).context("unable to parse policy document")?;
+    let module = Compiler::new(&ast)
+        .ffi_modules(&[
+            AfcFfi::<Store>::SCHEMA,
+            CryptoFfi::<Store>::SCHEMA,
+            DeviceFfi::SCHEMA,
+            EnvelopeFfi::SCHEMA,
+            IdamFfi::<Store>::SCHEMA,
+            PerspectiveFfi::SCHEMA,
+        ])
+        .compile()
+        .context("should be able to compile policy")?;
+    Machine::from_module(module).context("should be able to create machine")
+}
+
 /// Converts policy [`Role`] to string.
 impl fmt::Display for Role {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
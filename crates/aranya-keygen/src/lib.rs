@@ -3,5 +3,6 @@
 #![warn(clippy::wildcard_imports, missing_docs)]
 
 mod keygen;
+pub mod provision;
 
 pub use keygen::*;
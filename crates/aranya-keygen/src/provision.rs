@@ -0,0 +1,300 @@
+//! Batch key generation for factory provisioning.
+//!
+//! [`generate_batch`] generates a [`KeyBundle`] for each device
+//! and [`Manifest`] describes the result in a form that can be
+//! loaded by provisioning tools.
+//!
+//! # Wrapped Keys
+//!
+//! Private keys are always wrapped by the [`Engine`] that
+//! generated them. When provisioning devices, construct the
+//! engine from a provisioning key encryption key (KEK) and
+//! install the KEK on each device separately. The KEK itself is
+//! never written by this module.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use aranya_base58::ToBase58;
+use aranya_crypto::{keystore::fs_keystore::Store, CipherSuite, Engine, KeyStore};
+use serde::{Deserialize, Serialize};
+
+use crate::{KeyBundle, PublicKeys};
+
+/// The name of the key bundle file in a device's output
+/// directory.
+///
+/// It matches the name used by the daemon.
+pub const KEY_BUNDLE_FILE: &str = "key_bundle";
+
+/// The name of the keystore directory in a device's output
+/// directory.
+///
+/// It matches the name used by the daemon.
+pub const KEYSTORE_DIR: &str = "keystore";
+
+/// Where [`generate_batch`] stores wrapped private keys.
+#[derive(Debug)]
+pub enum Output<'a, S> {
+    /// Store every device's keys in one keystore.
+    Store(&'a mut S),
+    /// Create a directory for each device under this path.
+    ///
+    /// Each directory is named after the device and contains
+    /// a keystore ([`KEYSTORE_DIR`]) and the CBOR encoded
+    /// [`KeyBundle`] ([`KEY_BUNDLE_FILE`]). It is an error if
+    /// the directory already exists.
+    Dirs(&'a Path),
+}
+
+/// A device generated by [`generate_batch`].
+#[derive(Debug)]
+pub struct Device<CS: CipherSuite> {
+    /// The device's name.
+    pub name: String,
+    /// The device's key bundle.
+    pub bundle: KeyBundle,
+    /// The device's public keys.
+    pub public_keys: PublicKeys<CS>,
+    /// The device's output directory, if any.
+    pub dir: Option<PathBuf>,
+}
+
+/// Generates a key bundle for each device in `names`.
+///
+/// Names must be unique and non-empty. When writing to
+/// [`Output::Dirs`], they must also be valid file names.
+pub fn generate_batch<E, S, I>(
+    eng: &mut E,
+    out: Output<'_, S>,
+    names: I,
+) -> Result<Vec<Device<E::CS>>>
+where
+    E: Engine,
+    S: KeyStore,
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    let names = names.into_iter().map(Into::into).collect::<Vec<String>>();
+    for (i, name) in names.iter().enumerate() {
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            bail!("invalid device name: {name:?}");
+        }
+        if names[..i].contains(name) {
+            bail!("duplicate device name: {name:?}");
+        }
+    }
+
+    let mut devices = Vec::with_capacity(names.len());
+    match out {
+        Output::Store(store) => {
+            for name in names {
+                let bundle = KeyBundle::generate(eng, store)
+                    .with_context(|| format!("unable to generate keys for {name:?}"))?;
+                let public_keys = bundle.public_keys(eng, store)?;
+                devices.push(Device {
+                    name,
+                    bundle,
+                    public_keys,
+                    dir: None,
+                });
+            }
+        }
+        Output::Dirs(root) => {
+            fs::create_dir_all(root)
+                .with_context(|| format!("unable to create {}", root.display()))?;
+            for name in names {
+                let dir = root.join(&name);
+                fs::create_dir(&dir)
+                    .with_context(|| format!("unable to create {}", dir.display()))?;
+                let keystore = dir.join(KEYSTORE_DIR);
+                fs::create_dir(&keystore)
+                    .with_context(|| format!("unable to create {}", keystore.display()))?;
+                let mut store = Store::open(&keystore).context("unable to open keystore")?;
+
+                let bundle = KeyBundle::generate(eng, &mut store)
+                    .with_context(|| format!("unable to generate keys for {name:?}"))?;
+                let public_keys = bundle.public_keys(eng, &store)?;
+
+                let mut buf = Vec::new();
+                ciborium::into_writer(&bundle, &mut buf).context("unable to encode key bundle")?;
+                fs::write(dir.join(KEY_BUNDLE_FILE), buf)
+                    .with_context(|| format!("unable to write key bundle for {name:?}"))?;
+
+                devices.push(Device {
+                    name,
+                    bundle,
+                    public_keys,
+                    dir: Some(dir),
+                });
+            }
+        }
+    }
+    Ok(devices)
+}
+
+/// Describes a batch of devices.
+///
+/// It only contains public information.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The devices, in the order they were generated.
+    pub devices: Vec<ManifestEntry>,
+}
+
+/// A device in a [`Manifest`].
+///
+/// Public keys are postcard encoded, then base58 encoded.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The device's name.
+    pub name: String,
+    /// The device's user ID.
+    pub user_id: String,
+    /// A short form of the user ID for humans to compare.
+    pub fingerprint: String,
+    /// The public identity key.
+    pub ident_pk: String,
+    /// The public encryption key.
+    pub enc_pk: String,
+    /// The public signing key.
+    pub sign_pk: String,
+}
+
+impl Manifest {
+    /// The CSV header written by [`to_csv`][Self::to_csv].
+    pub const CSV_HEADER: &'static str = "name,user_id,fingerprint,ident_pk,enc_pk,sign_pk";
+
+    /// Creates a manifest for `devices`.
+    pub fn new<CS: CipherSuite>(devices: &[Device<CS>]) -> Result<Self> {
+        let devices = devices
+            .iter()
+            .map(|device| {
+                let pk = &device.public_keys;
+                let user_id = device.bundle.user_id.to_string();
+                Ok(ManifestEntry {
+                    name: device.name.clone(),
+                    fingerprint: fingerprint(&user_id),
+                    user_id,
+                    ident_pk: postcard::to_allocvec(&pk.ident_pk)?.to_base58(),
+                    enc_pk: postcard::to_allocvec(&pk.enc_pk)?.to_base58(),
+                    sign_pk: postcard::to_allocvec(&pk.sign_pk)?.to_base58(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { devices })
+    }
+
+    /// Encodes the manifest as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Encodes the manifest as CSV with a header row.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::new();
+        out.push_str(Self::CSV_HEADER);
+        out.push('\n');
+        for d in &self.devices {
+            // Only the name can contain special characters.
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                csv_field(&d.name),
+                d.user_id,
+                d.fingerprint,
+                d.ident_pk,
+                d.enc_pk,
+                d.sign_pk,
+            );
+        }
+        out
+    }
+}
+
+/// Returns the first 16 characters of `user_id` in groups of
+/// four.
+fn fingerprint(user_id: &str) -> String {
+    user_id
+        .chars()
+        .take(16)
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|c| c.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Quotes `s` if it contains characters that are special in
+/// CSV.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::panic)]
+
+    use aranya_crypto::default::{DefaultEngine, Rng};
+
+    use super::*;
+
+    #[test]
+    fn test_generate_batch_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut eng, _) = DefaultEngine::<Rng>::from_entropy(Rng);
+
+        let devices =
+            generate_batch::<_, Store, _>(&mut eng, Output::Dirs(tmp.path()), ["a", "b"]).unwrap();
+        assert_eq!(devices.len(), 2);
+        for device in &devices {
+            let dir = device.dir.as_ref().unwrap();
+            assert!(dir.join(KEY_BUNDLE_FILE).is_file());
+            assert!(dir.join(KEYSTORE_DIR).is_dir());
+        }
+
+        // Directories are never reused.
+        generate_batch::<_, Store, _>(&mut eng, Output::Dirs(tmp.path()), ["a"]).unwrap_err();
+
+        let manifest = Manifest::new(&devices).unwrap();
+        let csv = manifest.to_csv();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.starts_with(Manifest::CSV_HEADER));
+        let json: Manifest = serde_json::from_str(&manifest.to_json().unwrap()).unwrap();
+        assert_eq!(json, manifest);
+    }
+
+    #[test]
+    fn test_generate_batch_invalid_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut eng, _) = DefaultEngine::<Rng>::from_entropy(Rng);
+        for names in [&["a", "a"][..], &[""], &["../a"]] {
+            generate_batch::<_, Store, _>(
+                &mut eng,
+                Output::Dirs(tmp.path()),
+                names.iter().copied(),
+            )
+            .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("abc"), "abc");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint("ABCDEFGHIJKLMNOPQRS"), "ABCD-EFGH-IJKL-MNOP");
+    }
+}
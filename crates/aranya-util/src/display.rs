@@ -0,0 +1,147 @@
+//! Display helpers for log output.
+//!
+//! Long IDs make log lines hard to read, so [`ShortId`] prints
+//! them as `kind:head…tail`, e.g., `afc:ab12…ef34`. The short
+//! form only depends on the ID, so it can be grepped for across
+//! the client, daemon, and C API logs. The alternate flag
+//! (`{:#}`) prints the full ID.
+
+use std::fmt::{self, Write};
+
+/// The number of leading characters kept by [`ShortId`].
+const HEAD: usize = 4;
+
+/// The number of trailing characters kept by [`ShortId`].
+const TAIL: usize = 4;
+
+/// Displays an ID as `kind:head…tail`.
+///
+/// IDs that are too short to truncate are printed in full. Use
+/// the alternate flag (`{:#}`) to always print the full ID.
+#[derive(Copy, Clone, Debug)]
+pub struct ShortId<T> {
+    kind: &'static str,
+    id: T,
+}
+
+impl<T> ShortId<T> {
+    /// Creates a `ShortId` for `id`.
+    ///
+    /// `kind` is a short tag for the type of ID, e.g., `"afc"`.
+    pub const fn new(kind: &'static str, id: T) -> Self {
+        Self { kind, id }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for ShortId<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.kind)?;
+        if f.alternate() {
+            return write!(f, "{}", self.id);
+        }
+        let mut ends = Ends::default();
+        write!(ends, "{}", self.id)?;
+        // Truncating has to save at least one character.
+        if ends.n <= HEAD + TAIL + 1 {
+            return write!(f, "{}", self.id);
+        }
+        for c in ends.head {
+            f.write_char(c)?;
+        }
+        f.write_char('…')?;
+        for i in 0..TAIL {
+            let c = ends.tail.get((ends.n + i) % TAIL).copied();
+            f.write_char(c.unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the first [`HEAD`] and last [`TAIL`] characters
+/// written to it without allocating.
+#[derive(Default)]
+struct Ends {
+    head: [char; HEAD],
+    tail: [char; TAIL],
+    /// The number of characters written.
+    n: usize,
+}
+
+impl Write for Ends {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if let Some(v) = self.head.get_mut(self.n) {
+                *v = c;
+            }
+            if let Some(v) = self.tail.get_mut(self.n % TAIL) {
+                *v = c;
+            }
+            self.n += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Displays an [`Option`]'s value, or a fallback if it is
+/// `None`.
+#[derive(Copy, Clone, Debug)]
+pub struct FmtOr<T>(pub T, pub &'static str);
+
+impl<T: fmt::Display> fmt::Display for FmtOr<Option<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(v) => v.fmt(f),
+            None => self.1.fmt(f),
+        }
+    }
+}
+
+/// Displays a [`Result`]'s value or its error.
+#[derive(Copy, Clone, Debug)]
+pub struct TryFmt<T>(pub T);
+
+impl<T, E> fmt::Display for TryFmt<Result<T, E>>
+where
+    T: fmt::Display,
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Ok(v) => v.fmt(f),
+            Err(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_id() {
+        let tests = [
+            ("", "afc:"),
+            ("abc", "afc:abc"),
+            ("abcdefghi", "afc:abcdefghi"),
+            ("abcdefghij", "afc:abcd…ghij"),
+            ("5Hx2mQ9vTzK8pL3nR7wY", "afc:5Hx2…R7wY"),
+            ("ééééxxxxxüüüü", "afc:éééé…üüüü"),
+        ];
+        for (id, want) in tests {
+            let got = ShortId::new("afc", id).to_string();
+            assert_eq!(got, want, "{id}");
+            assert_eq!(
+                format!("{:#}", ShortId::new("afc", id)),
+                format!("afc:{id}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_fmt_or() {
+        assert_eq!(FmtOr(Some(42), "none").to_string(), "42");
+        assert_eq!(FmtOr(None::<i32>, "none").to_string(), "none");
+        assert_eq!(TryFmt(Ok::<_, &str>(42)).to_string(), "42");
+        assert_eq!(TryFmt(Err::<i32, _>("oops")).to_string(), "oops");
+    }
+}
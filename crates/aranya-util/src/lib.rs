@@ -1,5 +1,7 @@
 pub mod addr;
+pub mod display;
 pub mod util;
 
 pub use addr::*;
+pub use display::*;
 pub use util::*;
@@ -1,9 +1,15 @@
 //! Utility routines.
 
-use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path, str::FromStr};
+use std::{
+    fs::{File, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+    str::FromStr,
+};
 
 use aranya_fast_channels::shm;
-use tokio::{fs, io};
+use tokio::{fs, io, task};
 use tracing::warn;
 
 /// Writes `data` to `path` using with 600 permissions.
@@ -16,6 +22,42 @@ pub async fn write_file(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// Atomically replaces `path` with a file containing `data`,
+/// with 600 permissions.
+///
+/// `data` is written to a temporary file next to `path`, which
+/// is synced and renamed over `path`, and then the directory is
+/// synced too. So a crash never leaves part of `data` behind,
+/// and `data` survives one once this returns.
+pub async fn write_file_atomic(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
+    let data = data.to_vec();
+    task::spawn_blocking(move || write_file_atomic_blocking(&path, &data))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Like [`write_file_atomic`], but blocks the calling thread,
+/// e.g., for use in `Drop`.
+pub fn write_file_atomic_blocking(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
 /// Creates all directories in `path` with 700 permissions.
 pub async fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
     fs::create_dir_all(path.as_ref()).await?;
@@ -8,6 +8,8 @@
 
 // Note: this file is formatted with `clang-format`.
 
+#include <inttypes.h>
+#include <stdatomic.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
@@ -116,12 +118,22 @@ typedef struct {
     };
 } Team;
 
+// Data received by `on_data`.
+typedef struct {
+    uint8_t buf[BUF_LEN];
+    size_t len;
+    // Set once a message has been copied into `buf`.
+    atomic_bool done;
+} Received;
+
 AranyaError init_client(Client *c, const char *name, const char *daemon_sock,
                         const char *shm_path, const char *afc_addr);
 AranyaError init_team(Team *t);
 AranyaError add_sync_peers(Team *t);
 AranyaError run(Team *t);
 AranyaError cleanup_team(Team *t);
+void on_data(void *user_data, const uint8_t *data, size_t data_len,
+             const AranyaAfcMsgInfo *info);
 
 // Initialize an Aranya client.
 AranyaError init_client(Client *c, const char *name, const char *daemon_sock,
@@ -195,22 +207,44 @@ AranyaError add_sync_peers(Team *t) {
     AranyaError err;
     AranyaDuration interval = ARANYA_DURATION_MILLISECONDS * 100;
 
+    // The owner adds its sync peers one at a time.
+    for (int j = 0; j < NUM_CLIENTS; j++) {
+        if (j == OWNER) {
+            continue; // don't add self as a sync peer.
+        }
+        printf("adding sync peer %s to %s\r\n", t->clients_arr[j].name,
+               t->clients_arr[OWNER].name);
+        err = aranya_add_sync_peer(&t->clients_arr[OWNER].client, &t->id,
+                                   sync_addrs[j], interval);
+        if (err != ARANYA_ERROR_SUCCESS) {
+            fprintf(stderr, "error adding sync peer %s to %s: %s\r\n",
+                    t->clients_arr[j].name, t->clients_arr[OWNER].name,
+                    aranya_error_to_str(err));
+            return err;
+        }
+    }
+
+    // The other clients join the team with a single call to
+    // `aranya_setup_and_join_team`.
     for (int i = 0; i < NUM_CLIENTS; i++) {
+        if (i == OWNER) {
+            continue;
+        }
+        AranyaAddr peers[NUM_CLIENTS - 1];
+        size_t peers_len = 0;
         for (int j = 0; j < NUM_CLIENTS; j++) {
-            if (i == j) {
-                continue; // don't add self as a sync peer.
-            }
-            printf("adding sync peer %s to %s\r\n", t->clients_arr[j].name,
-                   t->clients_arr[i].name);
-            err = aranya_add_sync_peer(&t->clients_arr[i].client, &t->id,
-                                       sync_addrs[j], interval);
-            if (err != ARANYA_ERROR_SUCCESS) {
-                fprintf(stderr, "error adding sync peer %s to %s: %s\r\n",
-                        t->clients_arr[j].name, t->clients_arr[i].name,
-                        aranya_error_to_str(err));
-                return err;
+            if (i != j) {
+                peers[peers_len++] = sync_addrs[j];
             }
         }
+        printf("joining team as %s\r\n", t->clients_arr[i].name);
+        err = aranya_setup_and_join_team(&t->clients_arr[i].client, &t->id,
+                                         peers, peers_len, interval);
+        if (err != ARANYA_ERROR_SUCCESS) {
+            fprintf(stderr, "error joining team as %s: %s\r\n",
+                    t->clients_arr[i].name, aranya_error_to_str(err));
+            return err;
+        }
     }
 
     return ARANYA_ERROR_SUCCESS;
@@ -373,12 +407,89 @@ AranyaError run(Team *t) {
         fprintf(stderr, "`aranya_recv_data` returned `false`\n");
         return ARANYA_ERROR_AFC;
     }
-    printf("%s received afc message from %s: len: %zu, label: %d \r\n",
+    printf("%s received afc message from %s: len: %zu, label: %d, seq: %" PRIu64
+           " \r\n",
            t->clients_arr[MEMBERB].name, t->clients_arr[MEMBERA].name, len,
-           info.label);
+           aranya_msg_label(&info), aranya_msg_seq(&info));
+
+    // Instead of polling, membera registers a callback with
+    // `aranya_afc_set_data_callback`. The client's runtime invokes it when
+    // data arrives.
+
+    // NULL callbacks are rejected.
+    err = aranya_afc_set_data_callback(&t->clients.membera.client, NULL, NULL);
+    if (err != ARANYA_ERROR_INVALID_ARGUMENT) {
+        fprintf(stderr, "`aranya_afc_set_data_callback` accepted NULL\n");
+        return ARANYA_ERROR_BUG;
+    }
+    static Received received;
+    atomic_init(&received.done, false);
+    err = aranya_afc_set_data_callback(&t->clients.membera.client, on_data,
+                                       &received);
+    EXPECT("error setting data callback", err);
+
+    // A channel can also be created and used in a single call with
+    // `aranya_open_channel_and_send`. Here, memberb replies to membera over
+    // a new channel.
+
+    // create AFC channel and send data.
+    AranyaChannelId reply_id;
+    const char *reply = "hello back";
+    err = aranya_open_channel_and_send(
+        &t->clients.memberb.client, &t->id, afc_addrs[MEMBERA], label,
+        (const uint8_t *)reply, strlen(reply), &reply_id);
+    EXPECT("error opening channel and sending data", err);
+    printf("%s sent afc message: len: %d \r\n", t->clients_arr[MEMBERB].name,
+           (int)strlen(reply));
+
+    // poll for ctrl messages. Only memberb polls: membera's messages are
+    // received by its runtime.
+    while (true) {
+        printf("polling for reply\r\n");
+        err = aranya_poll_data(&t->clients.memberb.client, timeout);
+        if (err == ARANYA_ERROR_TIMEOUT) {
+            printf("polling timed out\r\n");
+            break;
+        }
+    }
+
+    // wait for the callback.
+    for (int i = 0; i < 50 && !atomic_load(&received.done); i++) {
+        usleep(100 * 1000);
+    }
+    err = aranya_afc_clear_data_callback(&t->clients.membera.client);
+    EXPECT("error clearing data callback", err);
+    if (!atomic_load(&received.done)) {
+        fprintf(stderr, "data callback was not invoked\n");
+        return ARANYA_ERROR_AFC;
+    }
+    if (received.len != strlen(reply) ||
+        memcmp(received.buf, reply, received.len) != 0) {
+        fprintf(stderr, "received unexpected reply\n");
+        return ARANYA_ERROR_AFC;
+    }
+    printf("%s received afc message from %s: len: %zu \r\n",
+           t->clients_arr[MEMBERA].name, t->clients_arr[MEMBERB].name,
+           received.len);
     return ARANYA_ERROR_SUCCESS;
 }
 
+// Copies the first message into the `Received` pointed to by `user_data`.
+//
+// Invoked on one of membera's runtime threads.
+void on_data(void *user_data, const uint8_t *data, size_t data_len,
+             const AranyaAfcMsgInfo *info) {
+    Received *r = user_data;
+    (void)info;
+
+    if (atomic_load(&r->done) || data_len > BUF_LEN) {
+        return;
+    }
+    memcpy(r->buf, data, data_len);
+    r->len = data_len;
+    atomic_store(&r->done, true);
+}
+
 int main(void) {
     Team team;
     AranyaError err;